@@ -0,0 +1,30 @@
+#![no_main]
+
+use graymamba::kernel::api::mount::{dirpath, mountres3_ok, mountstat3, MNTPATHLEN};
+use graymamba::kernel::protocol::xdr::{deserialize_bounded, XDR};
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+
+fn decode<T: XDR>(mut value: T, data: &[u8]) {
+    if value.deserialize(&mut Cursor::new(data)).is_ok() {
+        value.serialize(&mut Vec::new()).unwrap();
+    }
+}
+
+// The first byte picks what the rest is read as: the dirpath MNT and UMNT take, or the parts of
+// the reply to MNT a client reads
+fuzz_target!(|data: &[u8]| {
+    let Some((&which, data)) = data.split_first() else {
+        return;
+    };
+    match which % 3 {
+        0 => {
+            let mut path = dirpath::new();
+            if deserialize_bounded(&mut path, MNTPATHLEN as usize, &mut Cursor::new(data)).is_ok() {
+                assert!(path.len() <= MNTPATHLEN as usize);
+            }
+        }
+        1 => decode(mountstat3::MNT3_OK, data),
+        _ => decode(mountres3_ok { fhandle: Vec::new(), auth_flavors: Vec::new() }, data),
+    }
+});
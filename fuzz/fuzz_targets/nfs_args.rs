@@ -0,0 +1,44 @@
+#![no_main]
+
+use graymamba::kernel::api::nfs;
+use graymamba::kernel::handlers::nfs::basic_ops::{sattrguard3, SETATTR3args};
+use graymamba::kernel::handlers::nfs::directory_ops::{MKDIR3args, READDIRPLUS3args};
+use graymamba::kernel::handlers::nfs::file_ops::{createmode3, READ3args, WRITE3args};
+use graymamba::kernel::handlers::nfs::link_ops::SYMLINK3args;
+use graymamba::kernel::protocol::xdr::XDR;
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+
+// Reads the arguments as T, and writes back whatever was read
+fn decode<T: XDR + Default>(data: &[u8]) {
+    let mut args = T::default();
+    if args.deserialize(&mut Cursor::new(data)).is_ok() {
+        args.serialize(&mut Vec::new()).unwrap();
+    }
+}
+
+// The first byte picks the type the rest is read as: the arguments of the NFSv3 procedures, and
+// the parts the handlers read them in
+fuzz_target!(|data: &[u8]| {
+    let Some((&which, data)) = data.split_first() else {
+        return;
+    };
+    match which % 16 {
+        0 => decode::<nfs::nfs_fh3>(data),
+        1 => decode::<nfs::diropargs3>(data),
+        2 => decode::<nfs::sattr3>(data),
+        3 => decode::<SETATTR3args>(data),
+        4 => decode::<sattrguard3>(data),
+        5 => decode::<READ3args>(data),
+        6 => decode::<WRITE3args>(data),
+        7 => decode::<createmode3>(data),
+        8 => decode::<MKDIR3args>(data),
+        9 => decode::<SYMLINK3args>(data),
+        10 => decode::<nfs::symlinkdata3>(data),
+        11 => decode::<nfs::devicedata3>(data),
+        12 => decode::<nfs::ftype3>(data),
+        13 => decode::<READDIRPLUS3args>(data),
+        14 => decode::<nfs::cookieverf3>(data),
+        _ => decode::<nfs::nfsstring>(data),
+    }
+});
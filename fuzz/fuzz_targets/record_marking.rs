@@ -0,0 +1,24 @@
+#![no_main]
+
+use graymamba::kernel::protocol::rpcwire::read_fragment;
+use libfuzzer_sys::fuzz_target;
+
+// Small, so the fuzzer reaches the limit
+const MAX_RECORD: usize = 64 * 1024;
+
+// A connection's bytes, read as records of fragments until they run out
+fuzz_target!(|data: &[u8]| {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    runtime.block_on(async {
+        let mut socket = data;
+        let mut record = Vec::new();
+        loop {
+            match read_fragment(&mut socket, &mut record, MAX_RECORD).await {
+                Ok(true) => record.clear(),
+                Ok(false) => {}
+                Err(_) => break,
+            }
+            assert!(record.len() <= MAX_RECORD);
+        }
+    });
+});
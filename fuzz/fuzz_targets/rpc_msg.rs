@@ -0,0 +1,22 @@
+#![no_main]
+
+use graymamba::kernel::protocol::rpc::{auth_signed, auth_unix, rpc_body, rpc_msg};
+use graymamba::kernel::protocol::xdr::{XdrDeserialize, XdrSerialize};
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+
+// A record, read as the header of a call and its credential, as handle_rpc reads them
+fuzz_target!(|data: &[u8]| {
+    let mut msg = rpc_msg::default();
+    if msg.deserialize(&mut Cursor::new(data)).is_err() {
+        return;
+    }
+    if let rpc_body::CALL(call) = &msg.body {
+        let mut unix = auth_unix::default();
+        let _ = unix.deserialize(&mut Cursor::new(&call.cred.body));
+        let mut signed = auth_signed::default();
+        let _ = signed.deserialize(&mut Cursor::new(&call.cred.body));
+    }
+    // Whatever was read can be written back
+    msg.serialize(&mut Vec::new()).unwrap();
+});
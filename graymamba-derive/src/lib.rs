@@ -0,0 +1,227 @@
+//! `#[derive(XdrSerialize, XdrDeserialize)]` for graymamba's XDR (RFC 4506) types.
+//!
+//! - A struct is its fields, in order.
+//! - An enum of unit variants is an enumeration: the variant's discriminant as a u32.
+//! - An enum with data is a discriminated union: the variant's discriminant, then its fields.
+//!   Discriminants are the enum's own, counting from 0 like Rust's, so a union switching on a
+//!   bool lists its FALSE arm first. The fields of an arm must implement Default to be read.
+//! - `#[xdr(max = N)]` on a field of variable length (opaque, a string or an array) bounds it
+//!   to N elements; longer ones are neither written nor read.
+//!
+//! Optional data is an `Option`, and fixed-length opaque a byte array, which the traits
+//! themselves implement.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as Tokens;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Expr, Field, Fields, Index, Variant};
+
+#[proc_macro_derive(XdrSerialize, attributes(xdr))]
+pub fn derive_xdr_serialize(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    serialize(&input).unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+#[proc_macro_derive(XdrDeserialize, attributes(xdr))]
+pub fn derive_xdr_deserialize(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    deserialize(&input).unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+fn xdr() -> Tokens {
+    quote!(::graymamba::kernel::protocol::xdr)
+}
+
+// The bound of #[xdr(max = N)], if the field has one
+fn max_len(field: &Field) -> syn::Result<Option<Expr>> {
+    let mut max = None;
+    for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("xdr")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("max") {
+                max = Some(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `max = <length>`"))
+            }
+        })?;
+    }
+    Ok(max)
+}
+
+// Writes the field at value, a reference
+fn write_field(field: &Field, value: Tokens) -> syn::Result<Tokens> {
+    let xdr = xdr();
+    Ok(match max_len(field)? {
+        Some(max) => quote!(#xdr::serialize_bounded(#value, (#max) as usize, dest)?;),
+        None => quote!(#xdr::XdrSerialize::serialize(#value, dest)?;),
+    })
+}
+
+// Reads the field into place, a mutable reference
+fn read_field(field: &Field, place: Tokens) -> syn::Result<Tokens> {
+    let xdr = xdr();
+    Ok(match max_len(field)? {
+        Some(max) => quote!(#xdr::deserialize_bounded(#place, (#max) as usize, src)?;),
+        None => quote!(#xdr::XdrDeserialize::deserialize(#place, src)?;),
+    })
+}
+
+// The names variant's fields are bound to in a match, and the pattern binding them
+fn bindings(variant: &Variant) -> (Vec<syn::Ident>, Tokens) {
+    let name = &variant.ident;
+    match &variant.fields {
+        Fields::Named(fields) => {
+            let names: Vec<_> = fields.named.iter().map(|f| f.ident.clone().unwrap()).collect();
+            (names.clone(), quote!(Self::#name { #(#names),* }))
+        }
+        Fields::Unnamed(fields) => {
+            let names: Vec<_> = (0..fields.unnamed.len()).map(|i| format_ident!("field{}", i)).collect();
+            (names.clone(), quote!(Self::#name ( #(#names),* )))
+        }
+        Fields::Unit => (Vec::new(), quote!(Self::#name)),
+    }
+}
+
+// Each variant's discriminant: its own, or one more than the previous one's
+fn discriminants(variants: &[&Variant]) -> Vec<Tokens> {
+    let mut next = quote!(0);
+    variants
+        .iter()
+        .map(|variant| {
+            let discriminant = match &variant.discriminant {
+                Some((_, value)) => quote!((#value)),
+                None => next.clone(),
+            };
+            next = quote!(#discriminant + 1);
+            discriminant
+        })
+        .collect()
+}
+
+fn variants(input: &DeriveInput) -> syn::Result<Vec<&Variant>> {
+    match &input.data {
+        Data::Enum(data) if data.variants.is_empty() => {
+            Err(syn::Error::new_spanned(input, "an XDR enum needs a variant"))
+        }
+        Data::Enum(data) => Ok(data.variants.iter().collect()),
+        _ => unreachable!(),
+    }
+}
+
+fn serialize(input: &DeriveInput) -> syn::Result<Tokens> {
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let mut writes = Vec::new();
+            for (i, field) in data.fields.iter().enumerate() {
+                let member = match &field.ident {
+                    Some(name) => quote!(#name),
+                    None => {
+                        let index = Index::from(i);
+                        quote!(#index)
+                    }
+                };
+                writes.push(write_field(field, quote!(&self.#member))?);
+            }
+            quote!(#(#writes)*)
+        }
+        Data::Enum(_) => {
+            let variants = variants(input)?;
+            let xdr = xdr();
+            let mut arms = Vec::new();
+            for (variant, discriminant) in variants.iter().zip(discriminants(&variants)) {
+                let (names, pattern) = bindings(variant);
+                let mut writes = Vec::new();
+                for (field, name) in variant.fields.iter().zip(&names) {
+                    writes.push(write_field(field, quote!(#name))?);
+                }
+                arms.push(quote! {
+                    #pattern => {
+                        let discriminant: u32 = #discriminant;
+                        #xdr::XdrSerialize::serialize(&discriminant, dest)?;
+                        #(#writes)*
+                    }
+                });
+            }
+            quote!(match self { #(#arms)* })
+        }
+        Data::Union(_) => return Err(syn::Error::new_spanned(input, "XDR unions are enums with data")),
+    };
+
+    let xdr = xdr();
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics #xdr::XdrSerialize for #name #type_generics #where_clause {
+            fn serialize<W: ::std::io::Write>(&self, dest: &mut W) -> ::std::io::Result<()> {
+                #body
+                Ok(())
+            }
+        }
+    })
+}
+
+fn deserialize(input: &DeriveInput) -> syn::Result<Tokens> {
+    let name = &input.ident;
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let mut reads = Vec::new();
+            for (i, field) in data.fields.iter().enumerate() {
+                let member = match &field.ident {
+                    Some(name) => quote!(#name),
+                    None => {
+                        let index = Index::from(i);
+                        quote!(#index)
+                    }
+                };
+                reads.push(read_field(field, quote!(&mut self.#member))?);
+            }
+            quote!(#(#reads)*)
+        }
+        Data::Enum(_) => {
+            let variants = variants(input)?;
+            let xdr = xdr();
+            let mut arms = Vec::new();
+            for (variant, discriminant) in variants.iter().zip(discriminants(&variants)) {
+                let (names, pattern) = bindings(variant);
+                let mut reads = Vec::new();
+                for (field, name) in variant.fields.iter().zip(&names) {
+                    let ty = &field.ty;
+                    let read = read_field(field, quote!(&mut #name))?;
+                    reads.push(quote! {
+                        let mut #name: #ty = ::std::default::Default::default();
+                        #read
+                    });
+                }
+                arms.push(quote! {
+                    discriminant if discriminant == #discriminant => {
+                        #(#reads)*
+                        #pattern
+                    }
+                });
+            }
+            let invalid = format!("Invalid value for {}", name);
+            quote! {
+                let mut discriminant: u32 = 0;
+                #xdr::XdrDeserialize::deserialize(&mut discriminant, src)?;
+                *self = match discriminant {
+                    #(#arms)*
+                    _ => {
+                        return Err(::std::io::Error::new(::std::io::ErrorKind::InvalidData, #invalid));
+                    }
+                };
+            }
+        }
+        Data::Union(_) => return Err(syn::Error::new_spanned(input, "XDR unions are enums with data")),
+    };
+
+    let xdr = xdr();
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics #xdr::XdrDeserialize for #name #type_generics #where_clause {
+            fn deserialize<R: ::std::io::Read>(&mut self, src: &mut R) -> ::std::io::Result<()> {
+                #body
+                Ok(())
+            }
+        }
+    })
+}
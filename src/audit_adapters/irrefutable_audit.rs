@@ -40,6 +40,8 @@ use std::error::Error;
 use tokio::sync::mpsc as tokio_mpsc;
 use async_trait::async_trait;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
 
 use tracing::debug;
 
@@ -67,6 +69,20 @@ pub trait IrrefutableAudit: Send + Sync {
     async fn process_event(&self, event: AuditEvent) -> Result<(), Box<dyn Error>>;
     fn shutdown(&self) -> Result<(), Box<dyn Error>>;
 
+    /// Waits, up to timeout, for the event handler to take the events sent so far, so that
+    /// shutdown does not lose them. Whether it took them all.
+    async fn flush(&self, timeout: Duration) -> bool {
+        let sender = self.get_sender();
+        let deadline = Instant::now() + timeout;
+        while sender.capacity() < sender.max_capacity() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        true
+    }
+
     /// Trigger a new audit event
     async fn trigger_event(&self, event: AuditEvent) -> Result<(), Box<dyn Error>> {
         debug!("Triggering event about to get_sender etc");
@@ -111,4 +127,7 @@ impl Error for AuditError {}
 pub mod event_types {
     pub const DISASSEMBLED: &str = "disassembled";
     pub const REASSEMBLED: &str = "reassembled";
+    pub const WRITE_DENIED: &str = "write_denied";
+    pub const LOCK_ACQUIRED: &str = "lock_acquired";
+    pub const LOCK_RELEASED: &str = "lock_released";
 }
\ No newline at end of file
@@ -0,0 +1,358 @@
+use async_trait::async_trait;
+use lru::LruCache;
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::backingstore::data_store::{DataStore, DataStoreError};
+use crate::sharesfs::namespace::Namespace;
+
+#[cfg(feature = "metrics")]
+use crate::kernel::metrics::{METADATA_CACHE_HITS, METADATA_CACHE_MISSES};
+
+use tracing::debug;
+
+// Values larger than this (typically a hash carrying a file's "data" shares) are passed
+// through uncached so the cache only ever holds metadata-sized entries
+const MAX_CACHED_VALUE_BYTES: usize = 16 * 1024;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum CacheKey {
+    Hash(String),
+    Field(String, String),
+}
+
+#[derive(Clone)]
+enum CachedValue {
+    Hash(Vec<(String, String)>),
+    Field(String),
+}
+
+struct CacheEntry {
+    value: CachedValue,
+    expires_at: Instant,
+}
+
+struct CacheState {
+    entries: LruCache<CacheKey, CacheEntry>,
+    // Which fields of each key are cached individually, so a whole key can be invalidated
+    fields_by_key: HashMap<String, HashSet<String>>,
+    // Bumped on every invalidation; a read only populates the cache if no write raced with it
+    generation: u64,
+}
+
+impl CacheState {
+    fn insert(&mut self, cache_key: CacheKey, entry: CacheEntry) {
+        if let CacheKey::Field(key, field) = &cache_key {
+            self.fields_by_key.entry(key.clone()).or_default().insert(field.clone());
+        }
+        if let Some((evicted, _)) = self.entries.push(cache_key.clone(), entry) {
+            if evicted != cache_key {
+                self.forget_field(&evicted);
+            }
+        }
+    }
+
+    fn remove(&mut self, cache_key: &CacheKey) {
+        self.entries.pop(cache_key);
+        self.forget_field(cache_key);
+    }
+
+    fn forget_field(&mut self, cache_key: &CacheKey) {
+        if let CacheKey::Field(key, field) = cache_key {
+            if let Some(fields) = self.fields_by_key.get_mut(key) {
+                fields.remove(field);
+                if fields.is_empty() {
+                    self.fields_by_key.remove(key);
+                }
+            }
+        }
+    }
+
+    fn invalidate_field(&mut self, key: &str, field: &str) {
+        self.generation += 1;
+        self.entries.pop(&CacheKey::Hash(key.to_string()));
+        self.remove(&CacheKey::Field(key.to_string(), field.to_string()));
+    }
+
+    fn invalidate_key(&mut self, key: &str) {
+        self.generation += 1;
+        self.entries.pop(&CacheKey::Hash(key.to_string()));
+        if let Some(fields) = self.fields_by_key.remove(key) {
+            for field in fields {
+                self.entries.pop(&CacheKey::Field(key.to_string(), field));
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.generation += 1;
+        self.entries.clear();
+        self.fields_by_key.clear();
+    }
+}
+
+// Read-through cache of hash reads (hget/hgetall) in front of another DataStore.
+//
+// getattr is by far the most frequent NFS call and each one costs an id_to_path hget and an
+// hgetall of the metadata hash. Every mutation made through this wrapper invalidates exactly
+// the affected keys, so within one server the cache is never stale; the TTL bounds staleness
+// when other processes write to the same backing store (e.g. a shared Redis cluster).
+// Strings, counters and sorted sets are not cached.
+pub struct CachingDataStore {
+    inner: Arc<dyn DataStore>,
+    ttl: Duration,
+    state: Mutex<CacheState>,
+}
+
+impl CachingDataStore {
+    pub fn new(inner: Arc<dyn DataStore>, capacity: usize, ttl: Duration) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        CachingDataStore {
+            inner,
+            ttl,
+            state: Mutex::new(CacheState {
+                entries: LruCache::new(capacity),
+                fields_by_key: HashMap::new(),
+                generation: 0,
+            }),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Returns the first of the candidate entries that is present and fresh, otherwise the
+    // generation to hand back to store(). Counts one hit or miss per call
+    fn lookup(&self, candidates: &[CacheKey]) -> Result<CachedValue, u64> {
+        let mut state = self.state.lock();
+        let now = Instant::now();
+        for cache_key in candidates {
+            let fresh = match state.entries.get(cache_key) {
+                Some(entry) if entry.expires_at > now => Some(entry.value.clone()),
+                Some(_) => None,
+                None => continue,
+            };
+            match fresh {
+                Some(value) => {
+                    #[cfg(feature = "metrics")]
+                    METADATA_CACHE_HITS.inc();
+                    return Ok(value);
+                }
+                None => state.remove(cache_key),
+            }
+        }
+        #[cfg(feature = "metrics")]
+        METADATA_CACHE_MISSES.inc();
+        Err(state.generation)
+    }
+
+    fn store(&self, cache_key: CacheKey, value: CachedValue, generation: u64) {
+        let size = match &value {
+            CachedValue::Hash(fields) => fields.iter().map(|(f, v)| f.len() + v.len()).sum(),
+            CachedValue::Field(value) => value.len(),
+        };
+        if size > MAX_CACHED_VALUE_BYTES {
+            return;
+        }
+        let mut state = self.state.lock();
+        if state.generation != generation {
+            debug!("metadata cache: skipping fill raced by a write");
+            return;
+        }
+        state.insert(cache_key, CacheEntry { value, expires_at: Instant::now() + self.ttl });
+    }
+}
+
+#[async_trait]
+impl DataStore for CachingDataStore {
+    async fn get(&self, key: &str) -> Result<String, DataStoreError> {
+        self.inner.get(key).await
+    }
+
+    async fn set(&self, key: &str, value: &str) -> Result<(), DataStoreError> {
+        let result = self.inner.set(key, value).await;
+        self.state.lock().invalidate_key(key);
+        result
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), DataStoreError> {
+        let result = self.inner.delete(key).await;
+        self.state.lock().invalidate_key(key);
+        result
+    }
+
+    async fn hget(&self, key: &str, field: &str) -> Result<String, DataStoreError> {
+        // A cached whole hash answers single field reads too
+        let cache_key = CacheKey::Field(key.to_string(), field.to_string());
+        let generation = match self.lookup(&[CacheKey::Hash(key.to_string()), cache_key.clone()]) {
+            Ok(CachedValue::Field(value)) => return Ok(value),
+            Ok(CachedValue::Hash(fields)) => {
+                return fields.into_iter()
+                    .find(|(f, _)| f == field)
+                    .map(|(_, v)| v)
+                    .ok_or(DataStoreError::KeyNotFound);
+            }
+            Err(generation) => generation,
+        };
+        let value = self.inner.hget(key, field).await?;
+        self.store(cache_key, CachedValue::Field(value.clone()), generation);
+        Ok(value)
+    }
+
+    async fn hset(&self, key: &str, field: &str, value: &str) -> Result<(), DataStoreError> {
+        let result = self.inner.hset(key, field, value).await;
+        self.state.lock().invalidate_field(key, field);
+        result
+    }
+
+    async fn hdel(&self, key: &str, field: &str) -> Result<(), DataStoreError> {
+        let result = self.inner.hdel(key, field).await;
+        self.state.lock().invalidate_field(key, field);
+        result
+    }
+
+    async fn hgetall(&self, key: &str) -> Result<Vec<(String, String)>, DataStoreError> {
+        let cache_key = CacheKey::Hash(key.to_string());
+        let generation = match self.lookup(std::slice::from_ref(&cache_key)) {
+            Ok(CachedValue::Hash(fields)) => return Ok(fields),
+            Ok(CachedValue::Field(_)) => unreachable!("hash entries only hold hashes"),
+            Err(generation) => generation,
+        };
+        let fields = self.inner.hgetall(key).await?;
+        self.store(cache_key, CachedValue::Hash(fields.clone()), generation);
+        Ok(fields)
+    }
+
+    async fn incr(&self, key: &str) -> Result<i64, DataStoreError> {
+        self.inner.incr(key).await
+    }
+
+    async fn rename(&self, old_key: &str, new_key: &str) -> Result<(), DataStoreError> {
+        let result = self.inner.rename(old_key, new_key).await;
+        let mut state = self.state.lock();
+        state.invalidate_key(old_key);
+        state.invalidate_key(new_key);
+        result
+    }
+
+    async fn keys(&self, pattern: &str) -> Result<Vec<String>, DataStoreError> {
+        self.inner.keys(pattern).await
+    }
+
+    async fn zrange_withscores(&self, key: &str, start: isize, stop: isize) -> Result<Vec<(String, f64)>, DataStoreError> {
+        self.inner.zrange_withscores(key, start, stop).await
+    }
+
+    async fn zadd(&self, key: &str, member: &str, score: f64) -> Result<(), DataStoreError> {
+        self.inner.zadd(key, member, score).await
+    }
+
+    async fn zrem(&self, key: &str, member: &str) -> Result<(), DataStoreError> {
+        self.inner.zrem(key, member).await
+    }
+
+    async fn zrangebyscore(&self, key: &str, min: f64, max: f64) -> Result<Vec<String>, DataStoreError> {
+        self.inner.zrangebyscore(key, min, max).await
+    }
+
+    async fn hset_multiple(&self, key: &str, fields: &[(&str, &str)]) -> Result<(), DataStoreError> {
+        let result = self.inner.hset_multiple(key, fields).await;
+        self.state.lock().invalidate_key(key);
+        result
+    }
+
+    async fn zscan_match(&self, key: &str, pattern: &str) -> Result<Vec<String>, DataStoreError> {
+        self.inner.zscan_match(key, pattern).await
+    }
+
+    async fn zscore(&self, key: &str, member: &str) -> Result<Option<f64>, DataStoreError> {
+        self.inner.zscore(key, member).await
+    }
+
+    async fn legacy_users(&self) -> Result<Vec<String>, DataStoreError> {
+        self.inner.legacy_users().await
+    }
+
+    async fn init_user_directory(&self, namespace: &Namespace, mount_path: &str) -> Result<(), DataStoreError> {
+        // Touches several hashes the wrapper cannot name; it is rare enough to just start over
+        let result = self.inner.init_user_directory(namespace, mount_path).await;
+        self.state.lock().clear();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backingstore::test_store::TestDataStore;
+
+    fn caching_store(capacity: usize, ttl: Duration) -> (CachingDataStore, Arc<TestDataStore>) {
+        let inner = Arc::new(TestDataStore::new());
+        (CachingDataStore::new(inner.clone(), capacity, ttl), inner)
+    }
+
+    #[tokio::test]
+    async fn test_reads_are_served_from_cache() {
+        let (store, inner) = caching_store(16, Duration::from_secs(60));
+        store.hset_multiple("attrs", &[("size", "1")]).await.unwrap();
+        assert_eq!(store.hget("attrs", "size").await.unwrap(), "1");
+        assert_eq!(store.hgetall("attrs").await.unwrap(), vec![("size".to_string(), "1".to_string())]);
+
+        // A write behind the cache's back is not seen until the entry expires or is invalidated
+        inner.hset("attrs", "size", "2").await.unwrap();
+        assert_eq!(store.hget("attrs", "size").await.unwrap(), "1");
+        assert_eq!(store.hgetall("attrs").await.unwrap(), vec![("size".to_string(), "1".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn test_writes_invalidate() {
+        let (store, _inner) = caching_store(16, Duration::from_secs(60));
+        store.hset_multiple("attrs", &[("size", "1"), ("ftype", "1")]).await.unwrap();
+        store.hset("ids", "7", "/a").await.unwrap();
+        store.hgetall("attrs").await.unwrap();
+        store.hget("attrs", "size").await.unwrap();
+        store.hget("ids", "7").await.unwrap();
+
+        store.hset_multiple("attrs", &[("size", "2")]).await.unwrap();
+        assert_eq!(store.hget("attrs", "size").await.unwrap(), "2");
+        store.hset("attrs", "size", "3").await.unwrap();
+        assert_eq!(store.hgetall("attrs").await.unwrap().len(), 2);
+        assert_eq!(store.hget("attrs", "size").await.unwrap(), "3");
+
+        store.hset("ids", "7", "/b").await.unwrap();
+        assert_eq!(store.hget("ids", "7").await.unwrap(), "/b");
+        store.hdel("ids", "7").await.unwrap();
+        assert!(matches!(store.hget("ids", "7").await, Err(DataStoreError::KeyNotFound)));
+
+        store.rename("attrs", "moved").await.unwrap();
+        assert!(store.hgetall("attrs").await.unwrap().is_empty());
+        assert_eq!(store.hget("moved", "size").await.unwrap(), "3");
+
+        store.delete("moved").await.unwrap();
+        assert!(matches!(store.hget("moved", "size").await, Err(DataStoreError::KeyNotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_ttl_and_capacity() {
+        let (store, inner) = caching_store(2, Duration::from_millis(20));
+        inner.hset("a", "f", "1").await.unwrap();
+        inner.hset("b", "f", "1").await.unwrap();
+        inner.hset("c", "f", "1").await.unwrap();
+        store.hget("a", "f").await.unwrap();
+        store.hget("b", "f").await.unwrap();
+        store.hget("c", "f").await.unwrap();
+        assert_eq!(store.len(), 2, "least recently used entry evicted");
+
+        inner.hset("c", "f", "2").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        assert_eq!(store.hget("c", "f").await.unwrap(), "2", "expired entries are refetched");
+    }
+}
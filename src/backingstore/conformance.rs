@@ -0,0 +1,392 @@
+// Behaviour every DataStore backend must share. Each check takes a fresh, empty store and
+// asserts the Redis semantics SharesFS relies on; datastore_conformance_tests! instantiates
+// the full set of checks as tests for one backend given an expression that builds it.
+//
+// To cover a new backend add an invocation at the bottom of this file. The factory
+// expression must evaluate to (store, guard), where guard keeps any on-disk state alive
+// for the duration of the test.
+//
+// Redis is not covered, as the cluster is shared state and cross-slot renames fail.
+use crate::backingstore::caching_data_store::CachingDataStore;
+use crate::backingstore::data_store::{DataStore, DataStoreError};
+use crate::backingstore::rocksdb_data_store::RocksDBDataStore;
+use crate::backingstore::test_store::TestDataStore;
+use crate::backingstore::user_registry::{self, Role, UserRecord};
+use crate::kernel::vfs::mock::MockDataStore;
+use graymamba::sharesfs::namespace::Namespace;
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::{tempdir, TempDir};
+
+const TEST_COMMUNITY: &str = "orangery";
+const TEST_NAMESPACE_ID: &str = "citrus";
+
+fn sorted<T: Ord>(mut items: Vec<T>) -> Vec<T> {
+    items.sort();
+    items
+}
+
+fn owned(items: &[&str]) -> Vec<String> {
+    items.iter().map(|s| s.to_string()).collect()
+}
+
+pub async fn check_strings(store: &dyn DataStore) {
+    assert!(matches!(store.get("missing").await, Err(DataStoreError::KeyNotFound)));
+
+    store.set("string", "one").await.unwrap();
+    assert_eq!(store.get("string").await.unwrap(), "one");
+    store.set("string", "two").await.unwrap();
+    assert_eq!(store.get("string").await.unwrap(), "two", "set overwrites");
+
+    store.delete("string").await.unwrap();
+    assert!(matches!(store.get("string").await, Err(DataStoreError::KeyNotFound)));
+    store.delete("string").await.expect("deleting a missing key is not an error");
+}
+
+pub async fn check_hashes(store: &dyn DataStore) {
+    assert!(matches!(store.hget("hash", "field").await, Err(DataStoreError::KeyNotFound)));
+    assert!(store.hgetall("hash").await.unwrap().is_empty(), "hgetall of a missing key is empty");
+
+    store.hset("hash", "a", "1").await.unwrap();
+    store.hset("hash", "b", "2").await.unwrap();
+    store.hset("hash", "a", "3").await.unwrap();
+    assert_eq!(store.hget("hash", "a").await.unwrap(), "3", "hset overwrites");
+    assert!(matches!(store.hget("hash", "c").await, Err(DataStoreError::KeyNotFound)));
+    assert_eq!(
+        sorted(store.hgetall("hash").await.unwrap()),
+        vec![("a".to_string(), "3".to_string()), ("b".to_string(), "2".to_string())]
+    );
+
+    store.hdel("hash", "a").await.unwrap();
+    assert!(matches!(store.hget("hash", "a").await, Err(DataStoreError::KeyNotFound)));
+    store.hdel("hash", "a").await.expect("deleting a missing field is not an error");
+    assert_eq!(store.hgetall("hash").await.unwrap(), vec![("b".to_string(), "2".to_string())]);
+}
+
+pub async fn check_hset_multiple(store: &dyn DataStore) {
+    store.hset_multiple("attrs", &[("ftype", "1"), ("size", "10")]).await.unwrap();
+    store.hset_multiple("attrs", &[("size", "20"), ("fileid", "7")]).await.unwrap();
+    assert_eq!(
+        sorted(store.hgetall("attrs").await.unwrap()),
+        vec![
+            ("fileid".to_string(), "7".to_string()),
+            ("ftype".to_string(), "1".to_string()),
+            ("size".to_string(), "20".to_string()),
+        ],
+        "hset_multiple merges into the existing hash"
+    );
+
+    // Mixing single and multiple field writes on the same hash, as SharesFS does for file data
+    store.hset("attrs", "data", "shares").await.unwrap();
+    store.hset("attrs", "size", "30").await.unwrap();
+    assert_eq!(store.hget("attrs", "size").await.unwrap(), "30");
+    store.hset_multiple("attrs", &[("size", "40")]).await.unwrap();
+    assert_eq!(store.hget("attrs", "size").await.unwrap(), "40", "the latest write wins");
+    assert_eq!(
+        sorted(store.hgetall("attrs").await.unwrap()),
+        vec![
+            ("data".to_string(), "shares".to_string()),
+            ("fileid".to_string(), "7".to_string()),
+            ("ftype".to_string(), "1".to_string()),
+            ("size".to_string(), "40".to_string()),
+        ]
+    );
+
+    store.hdel("attrs", "ftype").await.unwrap();
+    assert!(matches!(store.hget("attrs", "ftype").await, Err(DataStoreError::KeyNotFound)));
+    assert!(!store.hgetall("attrs").await.unwrap().iter().any(|(field, _)| field == "ftype"));
+}
+
+pub async fn check_delete_any_type(store: &dyn DataStore) {
+    store.hset_multiple("hash", &[("a", "1")]).await.unwrap();
+    store.hset("hash", "data", "shares").await.unwrap();
+    store.zadd("zset", "member", 1.0).await.unwrap();
+
+    store.delete("hash").await.unwrap();
+    store.delete("zset").await.unwrap();
+    assert!(store.hgetall("hash").await.unwrap().is_empty());
+    assert!(matches!(store.hget("hash", "data").await, Err(DataStoreError::KeyNotFound)));
+    assert!(store.zrange_withscores("zset", 0, -1).await.unwrap().is_empty());
+    assert_eq!(store.zscore("zset", "member").await.unwrap(), None);
+}
+
+pub async fn check_incr(store: &dyn DataStore) {
+    assert_eq!(store.incr("counter").await.unwrap(), 1, "a missing counter starts from zero");
+    assert_eq!(store.incr("counter").await.unwrap(), 2);
+    assert_eq!(store.get("counter").await.unwrap(), "2");
+
+    store.set("counter", "41").await.unwrap();
+    assert_eq!(store.incr("counter").await.unwrap(), 42);
+
+    store.set("counter", "not a number").await.unwrap();
+    assert!(store.incr("counter").await.is_err());
+}
+
+pub async fn check_rename(store: &dyn DataStore) {
+    store.set("old", "value").await.unwrap();
+    store.rename("old", "new").await.unwrap();
+    assert!(matches!(store.get("old").await, Err(DataStoreError::KeyNotFound)));
+    assert_eq!(store.get("new").await.unwrap(), "value");
+
+    // The whole hash moves, whichever way its fields were written
+    store.hset_multiple("old_hash", &[("ftype", "1"), ("size", "5")]).await.unwrap();
+    store.hset("old_hash", "data", "shares").await.unwrap();
+    store.rename("old_hash", "new_hash").await.unwrap();
+    assert!(store.hgetall("old_hash").await.unwrap().is_empty());
+    assert_eq!(
+        sorted(store.hgetall("new_hash").await.unwrap()),
+        vec![
+            ("data".to_string(), "shares".to_string()),
+            ("ftype".to_string(), "1".to_string()),
+            ("size".to_string(), "5".to_string()),
+        ]
+    );
+
+    // Renaming onto an existing key replaces it entirely
+    store.set("source", "fresh").await.unwrap();
+    store.set("target", "stale").await.unwrap();
+    store.rename("source", "target").await.unwrap();
+    assert_eq!(store.get("target").await.unwrap(), "fresh");
+    assert!(matches!(store.get("source").await, Err(DataStoreError::KeyNotFound)));
+
+    store.hset_multiple("source_hash", &[("a", "1")]).await.unwrap();
+    store.hset_multiple("target_hash", &[("a", "old"), ("b", "old")]).await.unwrap();
+    store.hset("target_hash", "data", "old").await.unwrap();
+    store.rename("source_hash", "target_hash").await.unwrap();
+    assert_eq!(
+        store.hgetall("target_hash").await.unwrap(),
+        vec![("a".to_string(), "1".to_string())],
+        "no fields of the overwritten hash survive"
+    );
+
+    store.zadd("old_zset", "member", 2.0).await.unwrap();
+    store.rename("old_zset", "new_zset").await.unwrap();
+    assert_eq!(store.zrange_withscores("new_zset", 0, -1).await.unwrap(), vec![("member".to_string(), 2.0)]);
+    assert!(store.zrange_withscores("old_zset", 0, -1).await.unwrap().is_empty());
+
+    assert!(store.rename("missing", "anything").await.is_err(), "renaming a missing key fails");
+}
+
+pub async fn check_keys(store: &dyn DataStore) {
+    store.set("{c}:/dir", "").await.unwrap();
+    store.set("{c}:/dir/a", "").await.unwrap();
+    store.set("{c}:/dir/b", "").await.unwrap();
+    store.set("{c}:/dir/sub/c", "").await.unwrap();
+    store.set("{c}:/dirx", "").await.unwrap();
+    store.hset_multiple("{c}:/dir/h", &[("ftype", "0")]).await.unwrap();
+    store.set("{other}:/dir/a", "").await.unwrap();
+
+    // '*' matches any run of characters, '/' included, but the prefix itself must match
+    assert_eq!(
+        sorted(store.keys("{c}:/dir/*").await.unwrap()),
+        owned(&["{c}:/dir/a", "{c}:/dir/b", "{c}:/dir/h", "{c}:/dir/sub/c"])
+    );
+    assert_eq!(
+        sorted(store.keys("{c}:/dir*").await.unwrap()),
+        owned(&["{c}:/dir", "{c}:/dir/a", "{c}:/dir/b", "{c}:/dir/h", "{c}:/dir/sub/c", "{c}:/dirx"])
+    );
+    assert_eq!(
+        sorted(store.keys("{c}:/dir/?").await.unwrap()),
+        owned(&["{c}:/dir/a", "{c}:/dir/b", "{c}:/dir/h"])
+    );
+    assert_eq!(sorted(store.keys("{c}:/dir/[ab]").await.unwrap()), owned(&["{c}:/dir/a", "{c}:/dir/b"]));
+    assert_eq!(store.keys("{c}:/dirx").await.unwrap(), owned(&["{c}:/dirx"]), "no wildcard is an exact match");
+    assert!(store.keys("{c}:/nothing*").await.unwrap().is_empty());
+}
+
+pub async fn check_sorted_sets(store: &dyn DataStore) {
+    assert!(store.zrange_withscores("zset", 0, -1).await.unwrap().is_empty());
+
+    store.zadd("zset", "c", 3.0).await.unwrap();
+    store.zadd("zset", "a", 1.0).await.unwrap();
+    store.zadd("zset", "b2", 2.0).await.unwrap();
+    store.zadd("zset", "b1", 2.0).await.unwrap();
+    let all = vec![
+        ("a".to_string(), 1.0),
+        ("b1".to_string(), 2.0),
+        ("b2".to_string(), 2.0),
+        ("c".to_string(), 3.0),
+    ];
+    assert_eq!(store.zrange_withscores("zset", 0, -1).await.unwrap(), all, "ordered by score, then member");
+
+    // Redis index rules: inclusive stop, negative indices count from the end, out of range clamps
+    assert_eq!(store.zrange_withscores("zset", 1, 1).await.unwrap(), all[1..2].to_vec());
+    assert_eq!(store.zrange_withscores("zset", 1, 2).await.unwrap(), all[1..3].to_vec());
+    assert_eq!(store.zrange_withscores("zset", -2, -1).await.unwrap(), all[2..4].to_vec());
+    assert_eq!(store.zrange_withscores("zset", 0, -2).await.unwrap(), all[0..3].to_vec());
+    assert_eq!(store.zrange_withscores("zset", -100, 0).await.unwrap(), all[0..1].to_vec());
+    assert_eq!(store.zrange_withscores("zset", 0, 100).await.unwrap(), all);
+    assert!(store.zrange_withscores("zset", 2, 1).await.unwrap().is_empty());
+    assert!(store.zrange_withscores("zset", 10, 20).await.unwrap().is_empty());
+    assert!(store.zrange_withscores("zset", -1, -2).await.unwrap().is_empty());
+
+    assert_eq!(store.zrangebyscore("zset", 2.0, 3.0).await.unwrap(), owned(&["b1", "b2", "c"]));
+    assert_eq!(store.zrangebyscore("zset", 1.5, 1.9).await.unwrap(), Vec::<String>::new());
+
+    assert_eq!(store.zscore("zset", "b1").await.unwrap(), Some(2.0));
+    assert_eq!(store.zscore("zset", "missing").await.unwrap(), None);
+    assert_eq!(store.zscore("missing", "a").await.unwrap(), None);
+
+    // Re-adding a member updates its score rather than duplicating it
+    store.zadd("zset", "a", 4.0).await.unwrap();
+    assert_eq!(store.zrange_withscores("zset", -1, -1).await.unwrap(), vec![("a".to_string(), 4.0)]);
+    assert_eq!(store.zrange_withscores("zset", 0, -1).await.unwrap().len(), 4);
+
+    store.zrem("zset", "a").await.unwrap();
+    store.zrem("zset", "a").await.expect("removing a missing member is not an error");
+    assert_eq!(store.zrangebyscore("zset", 0.0, 10.0).await.unwrap(), owned(&["b1", "b2", "c"]));
+}
+
+pub async fn check_zscan_match(store: &dyn DataStore) {
+    for member in ["/a", "/a/b", "/a/b/c", "/ab", "/b"] {
+        store.zadd("nodes", member, member.matches('/').count() as f64).await.unwrap();
+    }
+    assert_eq!(sorted(store.zscan_match("nodes", "/a/*").await.unwrap()), owned(&["/a/b", "/a/b/c"]));
+    assert_eq!(sorted(store.zscan_match("nodes", "/a*").await.unwrap()), owned(&["/a", "/a/b", "/a/b/c", "/ab"]));
+    assert_eq!(store.zscan_match("nodes", "/a").await.unwrap(), owned(&["/a"]));
+    assert_eq!(store.zscan_match("nodes", "*").await.unwrap().len(), 5);
+    assert!(store.zscan_match("nodes", "/c/*").await.unwrap().is_empty());
+    assert!(store.zscan_match("missing", "*").await.unwrap().is_empty());
+}
+
+pub async fn check_users(store: &dyn DataStore) {
+    assert!(store.legacy_users().await.unwrap().is_empty());
+    assert!(user_registry::list_users(store).await.unwrap().is_empty());
+    let mut alice = UserRecord::new("alice", Role::Standard);
+    alice.uid = Some(501);
+    alice.exports = owned(&["/", "/projects"]);
+    user_registry::put_user(store, &alice).await.unwrap();
+    user_registry::put_user(store, &UserRecord::new("alice2", Role::Superuser)).await.unwrap();
+    user_registry::put_user(store, &alice).await.expect("replacing a user is not an error");
+    assert_eq!(user_registry::list_users(store).await.unwrap(), owned(&["alice", "alice2"]));
+    assert_eq!(user_registry::get_user(store, "alice").await.unwrap(), Some(alice.clone()));
+    assert_eq!(user_registry::find_by_uid(store, 501).await.unwrap(), Some(alice));
+    assert!(user_registry::get_user(store, "bob").await.unwrap().is_none());
+
+    assert!(user_registry::remove_user(store, "alice").await.unwrap());
+    assert!(user_registry::get_user(store, "alice").await.unwrap().is_none());
+    assert_eq!(user_registry::get_user(store, "alice2").await.unwrap().unwrap().role, Role::Superuser);
+}
+
+pub async fn check_init_user_directory(store: &dyn DataStore) {
+    let namespace = Namespace::new(TEST_COMMUNITY, TEST_NAMESPACE_ID);
+
+    store.init_user_directory(&namespace, "/").await.unwrap();
+    store.init_user_directory(&namespace, "/alice").await.unwrap();
+
+    let root = store.get(&namespace.root_key()).await.unwrap().parse::<u64>().unwrap();
+    let alice = store.hget(&namespace.entries_key(root), "alice").await.unwrap().parse::<u64>().unwrap();
+    assert_ne!(root, alice, "every directory gets its own fileid");
+    for (id, parent, name) in [(root, root, ""), (alice, root, "alice")] {
+        let key = namespace.inode_key(id);
+        let metadata = store.hgetall(&key).await.unwrap();
+        assert!(metadata.iter().any(|(k, v)| k == "ftype" && v == "0"), "{:?} is a directory", name);
+        assert_eq!(store.hget(&key, "fileid").await.unwrap(), id.to_string());
+        assert_eq!(store.hget(&key, "parent").await.unwrap(), parent.to_string());
+        assert_eq!(store.hget(&key, "name").await.unwrap(), name);
+    }
+
+    let next_fileid = store.get(&namespace.next_fileid_key()).await.unwrap().parse::<u64>().unwrap();
+    assert!(next_fileid >= root.max(alice), "the id counter is never behind an allocated id");
+
+    // Initialising again leaves the existing directory untouched
+    store.init_user_directory(&namespace, "/alice").await.unwrap();
+    assert_eq!(store.hget(&namespace.entries_key(root), "alice").await.unwrap(), alice.to_string());
+    assert_eq!(store.get(&namespace.next_fileid_key()).await.unwrap().parse::<u64>().unwrap(), next_fileid);
+}
+
+macro_rules! datastore_conformance_tests {
+    ($backend:ident, $factory:expr) => {
+        mod $backend {
+            use super::*;
+
+            #[tokio::test]
+            async fn strings() {
+                let (store, _guard) = $factory;
+                check_strings(&store).await;
+            }
+
+            #[tokio::test]
+            async fn hashes() {
+                let (store, _guard) = $factory;
+                check_hashes(&store).await;
+            }
+
+            #[tokio::test]
+            async fn hset_multiple() {
+                let (store, _guard) = $factory;
+                check_hset_multiple(&store).await;
+            }
+
+            #[tokio::test]
+            async fn delete_any_type() {
+                let (store, _guard) = $factory;
+                check_delete_any_type(&store).await;
+            }
+
+            #[tokio::test]
+            async fn incr() {
+                let (store, _guard) = $factory;
+                check_incr(&store).await;
+            }
+
+            #[tokio::test]
+            async fn rename() {
+                let (store, _guard) = $factory;
+                check_rename(&store).await;
+            }
+
+            #[tokio::test]
+            async fn keys() {
+                let (store, _guard) = $factory;
+                check_keys(&store).await;
+            }
+
+            #[tokio::test]
+            async fn sorted_sets() {
+                let (store, _guard) = $factory;
+                check_sorted_sets(&store).await;
+            }
+
+            #[tokio::test]
+            async fn zscan_match() {
+                let (store, _guard) = $factory;
+                check_zscan_match(&store).await;
+            }
+
+            #[tokio::test]
+            async fn users() {
+                let (store, _guard) = $factory;
+                check_users(&store).await;
+            }
+
+            #[tokio::test]
+            async fn init_user_directory() {
+                let (store, _guard) = $factory;
+                check_init_user_directory(&store).await;
+            }
+        }
+    };
+}
+
+fn rocksdb_store() -> (RocksDBDataStore, TempDir) {
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let store = RocksDBDataStore::new(temp_dir.path().to_str().unwrap()).expect("Failed to create RocksDB store");
+    (store, temp_dir)
+}
+
+#[cfg(feature = "sqlite_store")]
+fn sqlite_store() -> (crate::backingstore::sqlite_data_store::SqliteDataStore, TempDir) {
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let path = temp_dir.path().join("graymamba.db");
+    let store = crate::backingstore::sqlite_data_store::SqliteDataStore::new(path.to_str().unwrap())
+        .expect("Failed to create SQLite store");
+    (store, temp_dir)
+}
+
+datastore_conformance_tests!(rocksdb, rocksdb_store());
+datastore_conformance_tests!(test_store, (TestDataStore::new(), ()));
+datastore_conformance_tests!(mock, (MockDataStore::default(), ()));
+datastore_conformance_tests!(caching, (CachingDataStore::new(Arc::new(TestDataStore::new()), 1024, Duration::from_secs(60)), ()));
+#[cfg(feature = "sqlite_store")]
+datastore_conformance_tests!(sqlite, sqlite_store());
@@ -1,5 +1,7 @@
 use async_trait::async_trait;
 
+use crate::sharesfs::namespace::{init_directory, Namespace};
+
 #[async_trait]
 pub trait DataStore: Send + Sync {
     async fn get(&self, key: &str) -> Result<String, DataStoreError>;
@@ -19,8 +21,14 @@ pub trait DataStore: Send + Sync {
     async fn hset_multiple(&self, key: &str, fields: &[(&str, &str)]) -> Result<(), DataStoreError>;
     async fn zscan_match(&self, key: &str, pattern: &str) -> Result<Vec<String>, DataStoreError>;
     async fn zscore(&self, key: &str, member: &str) -> Result<Option<f64>, DataStoreError>;
-    async fn authenticate_user(&self, userkey: &str) -> KeyType;
-    async fn init_user_directory(&self, mount_path: &str) -> Result<(), DataStoreError>;
+    // The user keys of the per-backend sets that predate the user registry, "-su" marking a
+    // superuser. Only read, to carry them into the registry
+    async fn legacy_users(&self) -> Result<Vec<String>, DataStoreError>;
+
+    // Creates the mount's directory, and the namespace's root if it has none yet
+    async fn init_user_directory(&self, namespace: &Namespace, mount_path: &str) -> Result<(), DataStoreError> {
+        init_directory(self, namespace, mount_path).await.map(|_| ())
+    }
 }
 
 #[derive(Debug)]
@@ -33,9 +41,86 @@ pub enum DataStoreError {
 
 pub type DataStoreResult<T> = Result<T, DataStoreError>;
 
-#[derive(Debug)]
-pub enum KeyType {
-    Usual,
-    Special,
-    None,
-}
\ No newline at end of file
+// Redis-style glob matching (as used by KEYS and ZSCAN MATCH) for stores without a native equivalent.
+// Supports '*', '?', '[...]' classes with '^' negation and 'a-z' ranges, and '\' escapes.
+pub fn glob_match(pattern: &str, candidate: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let candidate: Vec<char> = candidate.chars().collect();
+    glob_match_from(&pattern, &candidate)
+}
+
+fn glob_match_from(pattern: &[char], candidate: &[char]) -> bool {
+    let (mut p, mut c) = (0, 0);
+    while p < pattern.len() {
+        match pattern[p] {
+            '*' => {
+                // Collapse runs of '*' and try every possible split point
+                while p < pattern.len() && pattern[p] == '*' {
+                    p += 1;
+                }
+                if p == pattern.len() {
+                    return true;
+                }
+                return (c..=candidate.len()).any(|start| glob_match_from(&pattern[p..], &candidate[start..]));
+            }
+            '?' => {
+                if c == candidate.len() {
+                    return false;
+                }
+            }
+            '[' => {
+                if c == candidate.len() {
+                    return false;
+                }
+                let mut i = p + 1;
+                let negate = i < pattern.len() && pattern[i] == '^';
+                if negate {
+                    i += 1;
+                }
+                let mut matched = false;
+                while i < pattern.len() && pattern[i] != ']' {
+                    if pattern[i] == '\\' && i + 1 < pattern.len() {
+                        i += 1;
+                        matched |= pattern[i] == candidate[c];
+                    } else if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' {
+                        let (low, high) = if pattern[i] <= pattern[i + 2] {
+                            (pattern[i], pattern[i + 2])
+                        } else {
+                            (pattern[i + 2], pattern[i])
+                        };
+                        matched |= low <= candidate[c] && candidate[c] <= high;
+                        i += 2;
+                    } else {
+                        matched |= pattern[i] == candidate[c];
+                    }
+                    i += 1;
+                }
+                if matched == negate {
+                    return false;
+                }
+                // i rests on the closing ']' (or the end of an unterminated class)
+                p = i.min(pattern.len() - 1);
+            }
+            '\\' if p + 1 < pattern.len() => {
+                p += 1;
+                if c == candidate.len() || pattern[p] != candidate[c] {
+                    return false;
+                }
+            }
+            literal => {
+                if c == candidate.len() || literal != candidate[c] {
+                    return false;
+                }
+            }
+        }
+        p += 1;
+        c += 1;
+    }
+    c == candidate.len()
+}
+
+// The literal text before the first glob metacharacter, for stores that can seek to a prefix
+pub fn glob_literal_prefix(pattern: &str) -> &str {
+    let end = pattern.find(['*', '?', '[', '\\']).unwrap_or(pattern.len());
+    &pattern[..end]
+}
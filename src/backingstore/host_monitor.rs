@@ -0,0 +1,71 @@
+// The client hosts holding locks, kept in the DataStore so that after a restart the server can
+// tell their status monitors it lost their locks, and they reclaim them:
+//
+//   nsm_hosts          sorted set of the names of monitored hosts
+//   nsm_host:{name}    hash of the address the host last locked from
+//   nsm_state          count of the server's restarts, its status monitor state
+use crate::backingstore::data_store::{DataStore, DataStoreError};
+
+const HOSTS_KEY: &str = "nsm_hosts";
+const STATE_KEY: &str = "nsm_state";
+
+fn host_key(name: &str) -> String {
+    format!("nsm_host:{}", name)
+}
+
+// Host names are the clients' own, so only printable ones of a sensible length are kept
+pub fn valid_host_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 255 && name.chars().all(|c| c.is_ascii_graphic())
+}
+
+pub async fn monitor<S: DataStore + ?Sized>(store: &S, name: &str, address: &str) -> Result<(), DataStoreError> {
+    if !valid_host_name(name) {
+        return Ok(());
+    }
+    store.hset(&host_key(name), "address", address).await?;
+    store.zadd(HOSTS_KEY, name, 0.0).await
+}
+
+pub async fn unmonitor<S: DataStore + ?Sized>(store: &S, name: &str) -> Result<(), DataStoreError> {
+    store.zrem(HOSTS_KEY, name).await?;
+    store.delete(&host_key(name)).await
+}
+
+// Each monitored host's name and address
+pub async fn monitored_hosts<S: DataStore + ?Sized>(store: &S) -> Result<Vec<(String, String)>, DataStoreError> {
+    let mut hosts = Vec::new();
+    for (name, _) in store.zrange_withscores(HOSTS_KEY, 0, -1).await? {
+        let fields = store.hgetall(&host_key(&name)).await?;
+        if let Some((_, address)) = fields.into_iter().find(|(field, _)| field == "address") {
+            hosts.push((name, address));
+        }
+    }
+    Ok(hosts)
+}
+
+// Counts a restart, returning the server's new state number, which is odd while it is up
+pub async fn next_state<S: DataStore + ?Sized>(store: &S) -> Result<i32, DataStoreError> {
+    let restarts = store.incr(STATE_KEY).await?;
+    Ok((restarts % (i32::MAX as i64 / 2)) as i32 * 2 + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backingstore::test_store::TestDataStore;
+
+    #[tokio::test]
+    async fn test_monitored_hosts_persist() {
+        let store = TestDataStore::new();
+        monitor(&store, "alice-laptop", "10.0.0.1").await.unwrap();
+        monitor(&store, "bob laptop", "10.0.0.2").await.unwrap();
+        monitor(&store, "alice-laptop", "10.0.0.3").await.unwrap();
+        assert_eq!(monitored_hosts(&store).await.unwrap(), vec![("alice-laptop".to_string(), "10.0.0.3".to_string())]);
+        unmonitor(&store, "alice-laptop").await.unwrap();
+        assert!(monitored_hosts(&store).await.unwrap().is_empty());
+
+        let first = next_state(&store).await.unwrap();
+        let second = next_state(&store).await.unwrap();
+        assert!(first % 2 == 1 && second % 2 == 1 && second > first);
+    }
+}
@@ -0,0 +1,548 @@
+// Moving a namespace between backing stores, or to and from a portable archive file.
+//
+// A namespace is streamed as a sequence of records: a header, one record per inode (its full
+// hash including the "data" shares), the root, the directory entries, the fileid counter, the
+// registered users, and a trailer. The archive format is exactly that sequence written as one JSON
+// object per line.
+//
+// Every inode record carries a SHA-256 digest of its hash. The importer checks the digest before
+// writing and reads the inode back afterwards to verify what landed in the destination. Import
+// is idempotent: inodes whose destination copy already has the same digest are skipped, so an
+// interrupted migration is resumed by simply running it again, and re-running against a live
+// source only copies what changed. Inodes deleted from the source are not removed from the
+// destination.
+//
+// Version 1 archives hold the path-keyed layout (node, path_to_id and id_to_path records); they
+// are restored as they were written and then upgraded in place. Version 1 and 2 archives carry
+// the backend's user keys as user records, which are restored into the user registry.
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+use crate::backingstore::data_store::{DataStore, DataStoreError};
+use crate::backingstore::user_registry::{self, UserRecord, UserRegistryError};
+use crate::kernel::api::nfs::fileid3;
+use crate::sharesfs::namespace::{self, Namespace};
+
+pub const ARCHIVE_FORMAT_VERSION: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record {
+    Header { format_version: u32, community: String, namespace_id: String },
+    Inode { id: fileid3, fields: Vec<(String, String)>, sha256: String },
+    Root { id: fileid3 },
+    Entry { dir: fileid3, name: String, id: fileid3 },
+    // Version 1 records
+    Node { path: String, score: f64, fields: Vec<(String, String)>, sha256: String },
+    PathToId { path: String, id: String },
+    IdToPath { id: String, path: String },
+    NextFileid { value: i64 },
+    RegisteredUser { name: String, fields: Vec<(String, String)> },
+    // Version 1 and 2 records
+    User { userkey: String },
+    // nodes counts the inode (or, in version 1, node) records
+    Trailer { nodes: u64, sha256: String },
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Store(DataStoreError),
+    Io(std::io::Error),
+    Format(String),
+    Verification(String),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MigrationError::Store(e) => write!(f, "Data store error: {:?}", e),
+            MigrationError::Io(e) => write!(f, "Archive I/O error: {}", e),
+            MigrationError::Format(msg) => write!(f, "Archive format error: {}", msg),
+            MigrationError::Verification(msg) => write!(f, "Verification failed: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<DataStoreError> for MigrationError {
+    fn from(e: DataStoreError) -> Self {
+        MigrationError::Store(e)
+    }
+}
+
+impl From<UserRegistryError> for MigrationError {
+    fn from(e: UserRegistryError) -> Self {
+        match e {
+            UserRegistryError::Store(e) => MigrationError::Store(e),
+            e => MigrationError::Format(e.to_string()),
+        }
+    }
+}
+
+impl From<std::io::Error> for MigrationError {
+    fn from(e: std::io::Error) -> Self {
+        MigrationError::Io(e)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MigrationStats {
+    pub inodes_copied: u64,
+    pub inodes_skipped: u64,
+    pub entries: u64,
+    pub users: u64,
+}
+
+impl fmt::Display for MigrationStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} inodes copied, {} already up to date, {} directory entries, {} users",
+            self.inodes_copied, self.inodes_skipped, self.entries, self.users)
+    }
+}
+
+// Digest of an inode's hash, independent of the order the store returns fields in
+pub fn node_digest(fields: &[(String, String)]) -> String {
+    let mut sorted: Vec<&(String, String)> = fields.iter().collect();
+    sorted.sort();
+    let mut hasher = Sha256::new();
+    for (field, value) in sorted {
+        hasher.update((field.len() as u64).to_le_bytes());
+        hasher.update(field.as_bytes());
+        hasher.update((value.len() as u64).to_le_bytes());
+        hasher.update(value.as_bytes());
+    }
+    hex::encode(hasher.finalize())
+}
+
+// Running digest over the inode digests, recorded in the trailer to detect truncated streams
+#[derive(Default)]
+struct StreamDigest {
+    hasher: Sha256,
+    nodes: u64,
+}
+
+impl StreamDigest {
+    fn add(&mut self, node_sha256: &str) {
+        self.hasher.update(node_sha256.as_bytes());
+        self.nodes += 1;
+    }
+
+    fn finish(self) -> (u64, String) {
+        (self.nodes, hex::encode(self.hasher.finalize()))
+    }
+}
+
+#[async_trait::async_trait]
+pub trait RecordSink: Send {
+    async fn write(&mut self, record: Record) -> Result<(), MigrationError>;
+}
+
+async fn ids_of_kind(store: &dyn DataStore, namespace: &Namespace, kind: &str) -> Result<Vec<fileid3>, MigrationError> {
+    let mut ids: Vec<fileid3> = store.keys(&namespace.key_pattern(kind)).await?
+        .iter()
+        .filter_map(|key| namespace.id_from_key(kind, key))
+        .collect();
+    ids.sort_unstable();
+    ids.dedup();
+    Ok(ids)
+}
+
+// Streams every record of a namespace out of a store. Inodes go before the entries that name
+// them, so a partially restored namespace never has an entry without its inode
+pub async fn export_namespace(
+    store: &dyn DataStore,
+    namespace: &Namespace,
+    sink: &mut dyn RecordSink,
+) -> Result<MigrationStats, MigrationError> {
+    if namespace::has_legacy_layout(store, namespace).await? {
+        return Err(MigrationError::Format(
+            "the namespace is still in the path-keyed layout, upgrade it first (fsck --repair)".to_string(),
+        ));
+    }
+
+    let mut stats = MigrationStats::default();
+    sink.write(Record::Header {
+        format_version: ARCHIVE_FORMAT_VERSION,
+        community: namespace.community.clone(),
+        namespace_id: namespace.namespace_id.clone(),
+    }).await?;
+
+    let mut digest = StreamDigest::default();
+    for id in ids_of_kind(store, namespace, "inode").await? {
+        let fields = store.hgetall(&namespace.inode_key(id)).await?;
+        if fields.is_empty() {
+            continue;
+        }
+        let sha256 = node_digest(&fields);
+        digest.add(&sha256);
+        debug!("export inode {} ({} fields)", id, fields.len());
+        sink.write(Record::Inode { id, fields, sha256 }).await?;
+        stats.inodes_copied += 1;
+    }
+
+    if let Some(id) = namespace::root_id(store, namespace).await? {
+        sink.write(Record::Root { id }).await?;
+    }
+    for dir in ids_of_kind(store, namespace, "entries").await? {
+        for (name, id) in namespace::entries(store, namespace, dir).await? {
+            sink.write(Record::Entry { dir, name, id }).await?;
+            stats.entries += 1;
+        }
+    }
+
+    match store.get(&namespace.next_fileid_key()).await {
+        Ok(value) => {
+            let value = value.parse::<i64>()
+                .map_err(|_| MigrationError::Format(format!("non-numeric fileid counter {:?}", value)))?;
+            sink.write(Record::NextFileid { value }).await?;
+        }
+        Err(DataStoreError::KeyNotFound) => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    // Users still only in the legacy set go out as the registry would hold them
+    let mut users = Vec::new();
+    for name in user_registry::list_users(store).await? {
+        users.extend(user_registry::get_user(store, &name).await?);
+    }
+    for userkey in store.legacy_users().await? {
+        let user = UserRecord::from_legacy_key(&userkey);
+        if user_registry::valid_name(&user.name) && !users.iter().any(|registered| registered.name == user.name) {
+            users.push(user);
+        }
+    }
+    for user in users {
+        sink.write(Record::RegisteredUser { fields: user.fields(), name: user.name }).await?;
+        stats.users += 1;
+    }
+
+    let (nodes, sha256) = digest.finish();
+    sink.write(Record::Trailer { nodes, sha256 }).await?;
+    Ok(stats)
+}
+
+// Writes records into a store, optionally under a different community/namespace than the source
+pub struct StoreImporter<'a> {
+    store: &'a dyn DataStore,
+    namespace: Namespace,
+    digest: StreamDigest,
+    format_version: Option<u32>,
+    trailer_seen: bool,
+    pub stats: MigrationStats,
+}
+
+impl<'a> StoreImporter<'a> {
+    pub fn new(store: &'a dyn DataStore, namespace: Namespace) -> Self {
+        StoreImporter {
+            store,
+            namespace,
+            digest: StreamDigest::default(),
+            format_version: None,
+            trailer_seen: false,
+            stats: MigrationStats::default(),
+        }
+    }
+
+    // Call once the stream is exhausted; a stream without its trailer was cut short
+    pub fn finish(self) -> Result<MigrationStats, MigrationError> {
+        if !self.trailer_seen {
+            return Err(MigrationError::Verification("stream ended without a trailer, it is incomplete".to_string()));
+        }
+        Ok(self.stats)
+    }
+
+    // Replaces the hash at key with fields unless it already holds them; returns whether it wrote
+    async fn import_hash(&mut self, key: &str, what: &str, fields: &[(String, String)], sha256: &str) -> Result<bool, MigrationError> {
+        if node_digest(fields) != sha256 {
+            return Err(MigrationError::Verification(format!("content of {} does not match its recorded hash", what)));
+        }
+        self.digest.add(sha256);
+
+        if node_digest(&self.store.hgetall(key).await?) == sha256 {
+            self.stats.inodes_skipped += 1;
+            return Ok(false);
+        }
+        self.store.delete(key).await?;
+        namespace::write_fields(self.store, key, fields).await?;
+        if node_digest(&self.store.hgetall(key).await?) != sha256 {
+            return Err(MigrationError::Verification(format!("{} reads back differently from the destination", what)));
+        }
+        self.stats.inodes_copied += 1;
+        Ok(true)
+    }
+
+    async fn import_field(&mut self, key: &str, field: &str, value: &str) -> Result<(), MigrationError> {
+        match self.store.hget(key, field).await {
+            Ok(existing) if existing == value => {}
+            Ok(_) | Err(DataStoreError::KeyNotFound) => self.store.hset(key, field, value).await?,
+            Err(e) => return Err(e.into()),
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl RecordSink for StoreImporter<'_> {
+    async fn write(&mut self, record: Record) -> Result<(), MigrationError> {
+        let Some(format_version) = self.format_version.or(match record {
+            Record::Header { format_version, .. } => Some(format_version),
+            _ => None,
+        }) else {
+            return Err(MigrationError::Format("stream does not start with a header".to_string()));
+        };
+        if self.trailer_seen {
+            return Err(MigrationError::Format("records after the trailer".to_string()));
+        }
+        let legacy = format_version == 1;
+        match record {
+            Record::Header { format_version, .. } => {
+                if self.format_version.is_some() {
+                    return Err(MigrationError::Format("a second header".to_string()));
+                }
+                if !(1..=ARCHIVE_FORMAT_VERSION).contains(&format_version) {
+                    return Err(MigrationError::Format(format!("unsupported archive format version {}", format_version)));
+                }
+                self.format_version = Some(format_version);
+            }
+            Record::Inode { id, fields, sha256 } if !legacy => {
+                let key = self.namespace.inode_key(id);
+                self.import_hash(&key, &format!("inode {}", id), &fields, &sha256).await?;
+            }
+            Record::Root { id } if !legacy => {
+                let key = self.namespace.root_key();
+                match self.store.get(&key).await {
+                    Ok(existing) if existing == id.to_string() => {}
+                    Ok(_) | Err(DataStoreError::KeyNotFound) => self.store.set(&key, &id.to_string()).await?,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Record::Entry { dir, name, id } if !legacy => {
+                let key = self.namespace.entries_key(dir);
+                self.import_field(&key, &name, &id.to_string()).await?;
+                self.stats.entries += 1;
+            }
+            Record::Node { path, score, fields, sha256 } if legacy => {
+                // Added to _nodes last, so a node only becomes visible once its content is in place
+                let key = self.namespace.legacy_node_key(&path);
+                self.import_hash(&key, &path, &fields, &sha256).await?;
+                self.store.zadd(&self.namespace.legacy_nodes_key(), &path, score).await?;
+            }
+            Record::PathToId { path, id } if legacy => {
+                let key = self.namespace.legacy_path_to_id_key();
+                self.import_field(&key, &path, &id).await?;
+            }
+            Record::IdToPath { id, path } if legacy => {
+                let key = self.namespace.legacy_id_to_path_key();
+                self.import_field(&key, &id, &path).await?;
+            }
+            Record::NextFileid { value } => {
+                // Never move the counter backwards, or new files would reuse existing ids
+                let key = self.namespace.next_fileid_key();
+                let current = match self.store.get(&key).await {
+                    Ok(current) => current.parse::<i64>().unwrap_or(0),
+                    Err(DataStoreError::KeyNotFound) => 0,
+                    Err(e) => return Err(e.into()),
+                };
+                if value > current {
+                    self.store.set(&key, &value.to_string()).await?;
+                }
+            }
+            Record::RegisteredUser { name, fields } if format_version >= 3 => {
+                user_registry::put_user(self.store, &UserRecord::from_fields(&name, &fields)?).await?;
+                self.stats.users += 1;
+            }
+            // Registering an older archive's users again would reset their uids and secrets
+            Record::User { userkey } if format_version < 3 => {
+                let user = UserRecord::from_legacy_key(&userkey);
+                if user_registry::get_user(self.store, &user.name).await?.is_none() {
+                    user_registry::put_user(self.store, &user).await?;
+                }
+                self.stats.users += 1;
+            }
+            Record::Trailer { nodes, sha256 } => {
+                let (seen_nodes, seen_sha256) = std::mem::take(&mut self.digest).finish();
+                if nodes != seen_nodes || sha256 != seen_sha256 {
+                    return Err(MigrationError::Verification(format!(
+                        "stream holds {} inodes but its trailer records {}, or their hashes differ", seen_nodes, nodes
+                    )));
+                }
+                if legacy {
+                    let upgrade = namespace::upgrade_layout(self.store, &self.namespace).await?;
+                    if !upgrade.left_behind.is_empty() {
+                        return Err(MigrationError::Verification(format!(
+                            "{} paths could not be upgraded: {:?}", upgrade.left_behind.len(), upgrade.left_behind
+                        )));
+                    }
+                    self.stats.entries = upgrade.upgraded;
+                }
+                self.trailer_seen = true;
+            }
+            record => {
+                return Err(MigrationError::Format(format!("unexpected record in a version {} stream: {:?}", format_version, record)));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct ArchiveWriter {
+    writer: BufWriter<File>,
+}
+
+impl ArchiveWriter {
+    pub fn create(path: &str) -> Result<Self, MigrationError> {
+        Ok(ArchiveWriter { writer: BufWriter::new(File::create(path)?) })
+    }
+
+    pub fn finish(mut self) -> Result<(), MigrationError> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl RecordSink for ArchiveWriter {
+    async fn write(&mut self, record: Record) -> Result<(), MigrationError> {
+        serde_json::to_writer(&mut self.writer, &record)
+            .map_err(|e| MigrationError::Format(e.to_string()))?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+// Replays an archive file into a sink, returning the header's namespace
+pub async fn read_archive(path: &str, sink: &mut dyn RecordSink) -> Result<Namespace, MigrationError> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut namespace = None;
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record = serde_json::from_str(&line)
+            .map_err(|e| MigrationError::Format(format!("line {}: {}", number + 1, e)))?;
+        if let Record::Header { community, namespace_id, .. } = &record {
+            namespace = Some(Namespace::new(community, namespace_id));
+        }
+        sink.write(record).await?;
+    }
+    namespace.ok_or_else(|| MigrationError::Format("archive has no header".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backingstore::test_store::TestDataStore;
+    use crate::backingstore::user_registry::Role;
+    use crate::sharesfs::namespace::{add_inode, init_directory, resolve_path};
+
+    async fn populated_store(namespace: &Namespace) -> TestDataStore {
+        let store = TestDataStore::new();
+        let alice = init_directory(&store, namespace, "/alice").await.unwrap();
+        add_inode(&store, namespace, 3, alice, "notes.txt", &[("ftype", "1"), ("size", "5")]).await.unwrap();
+        store.hset(&namespace.inode_key(3), "data", "c2hhcmVz").await.unwrap();
+        store.set(&namespace.next_fileid_key(), "3").await.unwrap();
+        let mut alice = UserRecord::new("alice", Role::Standard);
+        alice.uid = Some(501);
+        user_registry::put_user(&store, &alice).await.unwrap();
+        store.add_legacy_user("bob-su").await;
+        store
+    }
+
+    async fn write_archive(store: &TestDataStore, namespace: &Namespace, archive: &str) {
+        let mut writer = ArchiveWriter::create(archive).unwrap();
+        export_namespace(store, namespace, &mut writer).await.unwrap();
+        writer.finish().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_archive_round_trip_and_resume() {
+        let namespace = Namespace::new("zoo", "aqautics");
+        let source = populated_store(&namespace).await;
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("backup.jsonl");
+        let archive = archive.to_str().unwrap();
+        write_archive(&source, &namespace, archive).await;
+
+        let destination = TestDataStore::new();
+        let mut importer = StoreImporter::new(&destination, namespace.clone());
+        read_archive(archive, &mut importer).await.unwrap();
+        let stats = importer.finish().unwrap();
+        assert_eq!((stats.inodes_copied, stats.inodes_skipped, stats.entries, stats.users), (3, 0, 2, 2));
+
+        let key = namespace.inode_key(3);
+        assert_eq!(destination.hget(&key, "data").await.unwrap(), "c2hhcmVz");
+        assert_eq!(resolve_path(&destination, &namespace, "/alice/notes.txt").await.unwrap(), Some(3));
+        assert_eq!(destination.get(&namespace.next_fileid_key()).await.unwrap(), "3");
+        assert_eq!(user_registry::find_by_uid(&destination, 501).await.unwrap().unwrap().name, "alice");
+        assert_eq!(user_registry::get_user(&destination, "bob").await.unwrap().unwrap().role, Role::Superuser);
+
+        // Running again only copies what changed
+        source.hset(&key, "size", "6").await.unwrap();
+        let mut importer = StoreImporter::new(&destination, namespace.clone());
+        export_namespace(&source, &namespace, &mut importer).await.unwrap();
+        let stats = importer.finish().unwrap();
+        assert_eq!((stats.inodes_copied, stats.inodes_skipped), (1, 2));
+        assert_eq!(destination.hget(&key, "size").await.unwrap(), "6");
+    }
+
+    #[tokio::test]
+    async fn test_tampered_or_truncated_archive_is_rejected() {
+        let namespace = Namespace::new("zoo", "aqautics");
+        let source = populated_store(&namespace).await;
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("backup.jsonl");
+        let archive = archive.to_str().unwrap();
+        write_archive(&source, &namespace, archive).await;
+        let lines: Vec<String> = std::fs::read_to_string(archive).unwrap().lines().map(String::from).collect();
+
+        let tampered = lines.iter()
+            .map(|line| line.replace("c2hhcmVz", "dGFtcGVy"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        std::fs::write(archive, tampered).unwrap();
+        let destination = TestDataStore::new();
+        let mut importer = StoreImporter::new(&destination, namespace.clone());
+        assert!(matches!(read_archive(archive, &mut importer).await, Err(MigrationError::Verification(_))));
+
+        std::fs::write(archive, lines[..lines.len() - 1].join("\n")).unwrap();
+        let mut importer = StoreImporter::new(&destination, namespace.clone());
+        read_archive(archive, &mut importer).await.unwrap();
+        assert!(matches!(importer.finish(), Err(MigrationError::Verification(_))));
+    }
+
+    #[tokio::test]
+    async fn test_version_1_archive_is_upgraded_on_restore() {
+        let namespace = Namespace::new("zoo", "aqautics");
+        let mut records = vec![Record::Header { format_version: 1, community: "zoo".into(), namespace_id: "aqautics".into() }];
+        let mut digest = StreamDigest::default();
+        for (path, id, score) in [("/", "1", 1.0), ("/alice", "2", 2.0)] {
+            let fields = vec![("ftype".to_string(), "0".to_string()), ("fileid".to_string(), id.to_string())];
+            let sha256 = node_digest(&fields);
+            digest.add(&sha256);
+            records.push(Record::Node { path: path.into(), score, fields, sha256 });
+            records.push(Record::PathToId { path: path.into(), id: id.into() });
+        }
+        let (nodes, sha256) = digest.finish();
+        records.push(Record::NextFileid { value: 2 });
+        records.push(Record::User { userkey: "carol-su".into() });
+        records.push(Record::Trailer { nodes, sha256 });
+
+        let destination = TestDataStore::new();
+        let mut importer = StoreImporter::new(&destination, namespace.clone());
+        for record in records {
+            importer.write(record).await.unwrap();
+        }
+        importer.finish().unwrap();
+        assert!(!namespace::has_legacy_layout(&destination, &namespace).await.unwrap());
+        assert_eq!(resolve_path(&destination, &namespace, "/alice").await.unwrap(), Some(2));
+        assert_eq!(user_registry::get_user(&destination, "carol").await.unwrap().unwrap().role, Role::Superuser);
+    }
+}
@@ -1,10 +1,24 @@
 pub mod data_store;
 
+pub mod caching_data_store;
+
 pub mod redis_data_store;
 
 pub mod rocksdb_data_store;
 
+#[cfg(feature = "sqlite_store")]
+pub mod sqlite_data_store;
+
+pub mod migration;
+
+pub mod user_registry;
+
+pub mod host_monitor;
+
 pub mod test_store; //a template for a new backing store
 
 #[cfg(test)]
-mod tests;
\ No newline at end of file
+mod tests;
+
+#[cfg(test)]
+mod conformance;
@@ -6,16 +6,8 @@ use async_trait::async_trait;
 use crate::backingstore::data_store::{DataStore, DataStoreError};
 use config::{Config, File as ConfigFile, ConfigError};
 
-use std::time::SystemTime;
-use std::time::UNIX_EPOCH;
-
 use r2d2_redis_cluster2::{r2d2, RedisClusterConnectionManager};
 
-use tracing::warn;
-use crate::backingstore::data_store::KeyType;
-
-use graymamba::sharesfs::SharesFS;
-
 pub fn get_redis_cluster_pool() -> Result<Pool<RedisClusterConnectionManager>, Box<dyn StdError>> {
     RedisClusterPool::get_redis_cluster_pool()
 }
@@ -79,27 +71,9 @@ impl RedisDataStore {
 
 #[async_trait]
 impl DataStore for RedisDataStore {
-    async fn authenticate_user(&self, userkey: &str) -> KeyType {
-        let mut conn = self.pool.get().unwrap();
-
-        let user_exists: Result<bool, _> = conn.sismember("GRAYMAMBAWALLETS", userkey);
-        if let Ok(exists) = user_exists {
-            if exists {
-                warn!("Wallet exists: {}", userkey);
-                return KeyType::Usual;
-            }
-        }
-
-        // Check if userkey variant exists for special access
-        let special_key = format!("{}-su", userkey);
-        let special_exists: Result<bool, _> = conn.sismember("GRAYMAMBAWALLETS", &special_key);
-        if let Ok(exists) = special_exists {
-            if exists {
-                return KeyType::Special;
-            }
-        }
-
-        KeyType::None
+    async fn legacy_users(&self) -> Result<Vec<String>, DataStoreError> {
+        let mut conn = self.pool.get().map_err(|_| DataStoreError::ConnectionError)?;
+        conn.smembers("GRAYMAMBAWALLETS").map_err(|_| DataStoreError::OperationFailed)
     }
 
     async fn get(&self, key: &str) -> Result<String, DataStoreError> {
@@ -189,93 +163,4 @@ impl DataStore for RedisDataStore {
         let mut conn = self.pool.get().map_err(|_| DataStoreError::ConnectionError)?;
         conn.zscore(key, member).map_err(|_| DataStoreError::OperationFailed)
     }
-
-    async fn init_user_directory(&self, mount_path: &str) -> Result<(), DataStoreError> {
-        let mut conn = self.pool.get().map_err(|_| DataStoreError::ConnectionError)?;
-        let (namespace_id, community) = SharesFS::get_namespace_id_and_community().await;
-        let path = format!("/{}", namespace_id);
-        let key = format!("{}{}", community, mount_path);
-        let exists_response: bool = conn.exists(&key).map_err(|_| DataStoreError::OperationFailed)?;
-    
-        if exists_response {
-            return Ok(());
-        }
-    
-        let node_type = "0";
-        let size = 0;
-        let permissions = 777;
-        let score = if mount_path == "/" { 1.0 } else { 2.0 };
-    
-        let nodes = format!("{}/{}_nodes", community, namespace_id);
-        let key_exists: bool = conn.exists(&nodes).map_err(|_| DataStoreError::OperationFailed)?;
-    
-        let fileid: u64 = if key_exists {
-            conn.incr(format!("{}/{}_next_fileid", community, namespace_id), 1)
-                .map_err(|_| DataStoreError::OperationFailed)?
-        } else {
-            1
-        };
-    
-        let system_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-        let epoch_seconds = system_time.as_secs();
-        let epoch_nseconds = system_time.subsec_nanos();
-    
-        // Instead of using pipeline, execute commands individually
-        let _: () = conn.zadd(
-            format!("{}/{}_nodes", community, namespace_id),
-            mount_path,
-            score
-        ).map_err(|_| DataStoreError::OperationFailed)?;
-    
-        let size_str = size.to_string();
-        let permissions_str = permissions.to_string();
-        let epoch_seconds_str = epoch_seconds.to_string();
-        let epoch_nseconds_str = epoch_nseconds.to_string();
-        let fileid_str = fileid.to_string();
-    
-        // Now create the vector with references to our stored strings
-        let hash_fields = vec![
-            ("ftype", node_type),
-            ("size", &size_str),
-            ("permissions", &permissions_str),
-            ("change_time_secs", &epoch_seconds_str),
-            ("change_time_nsecs", &epoch_nseconds_str),
-            ("modification_time_secs", &epoch_seconds_str),
-            ("modification_time_nsecs", &epoch_nseconds_str),
-            ("access_time_secs", &epoch_seconds_str),
-            ("access_time_nsecs", &epoch_nseconds_str),
-            ("birth_time_secs", &epoch_seconds_str),
-            ("birth_time_nsecs", &epoch_nseconds_str),
-            ("fileid", &fileid_str),
-        ];
-
-        // In the init_user_directory function, modify the hset_multiple call:
-        let _: () = conn.hset_multiple(
-            format!("{}{}", community, mount_path),
-            &hash_fields
-        ).map_err(|_| DataStoreError::OperationFailed)?;
-    
-        // Set path to id mapping
-        let _: () = conn.hset(
-            format!("{}{}_path_to_id", community, path),
-            mount_path,
-            fileid
-        ).map_err(|_| DataStoreError::OperationFailed)?;
-    
-        // Set id to path mapping
-        let _: () = conn.hset(
-            format!("{}{}_id_to_path", community, path),
-            fileid.to_string(),
-            mount_path
-        ).map_err(|_| DataStoreError::OperationFailed)?;
-    
-        if fileid == 1 {
-            let _: () = conn.set(
-                format!("{}{}_next_fileid", community, path),
-                1
-            ).map_err(|_| DataStoreError::OperationFailed)?;
-        }
-    
-        Ok(())
-    }
 }
\ No newline at end of file
@@ -1,19 +1,14 @@
 use rocksdb::{DB, Options};
 use async_trait::async_trait;
-use crate::backingstore::data_store::{DataStore, DataStoreError};
-
-use crate::backingstore::data_store::KeyType;
+use crate::backingstore::data_store::{glob_literal_prefix, glob_match, DataStore, DataStoreError};
 
 use std::fmt;
-use std::time::{SystemTime, UNIX_EPOCH};
 
 use tracing::debug;
 
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
 
-use graymamba::sharesfs::SharesFS;
-
 impl fmt::Display for RocksDBDataStore {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "RocksDBDataStore")
@@ -53,105 +48,45 @@ impl RocksDBDataStore {
             }
         }
     }
-}
 
-#[async_trait]
-impl DataStore for RocksDBDataStore {
-    async fn authenticate_user(&self, username: &str) -> KeyType {
-        // Create a key with the format "user:{username}"
-        let user_key = format!("user:{}", username);
-
-        // Check if the user exists in the database
-        match self.db.get(user_key.as_bytes()) {
-            Ok(Some(_)) => KeyType::Usual,  // User exists
-            Ok(None) => KeyType::Usual,  // User does not exist - force existence!!!!
-            Err(_) => KeyType::None,  // Operation failed
+    // All entries whose key starts with prefix. Without a prefix extractor configured the
+    // prefix iterator runs on to the end of the keyspace, so stop at the first key outside it
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>, DataStoreError> {
+        let mut entries = Vec::new();
+        for item in self.db.prefix_iterator(prefix.as_bytes()) {
+            let (key_bytes, value_bytes) = item.map_err(|_| DataStoreError::OperationFailed)?;
+            if !key_bytes.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            let key = String::from_utf8(key_bytes.to_vec())
+                .map_err(|_| DataStoreError::OperationFailed)?;
+            let value = String::from_utf8(value_bytes.to_vec())
+                .map_err(|_| DataStoreError::OperationFailed)?;
+            entries.push((key, value));
         }
+        Ok(entries)
     }
 
-    async fn init_user_directory(&self, mount_path: &str) -> Result<(), DataStoreError> {
-        let (namespace_id, community) = SharesFS::get_namespace_id_and_community().await;
-        debug!("namespace_id: {:?}", namespace_id);
-        debug!("community: {:?}", community);
-        let path = format!("/{}", namespace_id);
-        let key = format!("{}{}", community, mount_path);
-        debug!("===============rocksdb init_user_directory({})", key);       
-
-        // Check if the directory already exists
-        if let Ok(Some(_)) = self.db.get(key.as_bytes()) {
-            debug!("rocksdb init_user_directory({}) already exists", key);
-            return Ok(());
-        }
-        let node_type = "0";
-        let size = 0;
-        let permissions = 777;
-        let score = if mount_path == "/" { 1.0 } else { 2.0 };
-
-        let nodes = format!("{}/{}_nodes", community, namespace_id);
-        debug!("===============rocksdb init_user_directory({}) nodes", nodes);
-        let key_exists: bool = self.db.get(&nodes).map_err(|_| DataStoreError::OperationFailed)?.is_some();
-        debug!("===============rocksdb init_user_directory({}) key_exists?", key_exists);
-
-        let next_fileid_key = format!("{}/{}_next_fileid", community, namespace_id);
-        let fileid = self.incr(&next_fileid_key).await?;
-
-        let system_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-        let epoch_seconds = system_time.as_secs();
-        let epoch_nseconds = system_time.subsec_nanos();
-
-        // Add to sorted set (equivalent to Redis ZADD)
-        let nodes_key = format!("{}/{}_nodes:{}", community, namespace_id, mount_path);
-        self.db.put(nodes_key.as_bytes(), score.to_string().as_bytes())
-            .map_err(|_| DataStoreError::OperationFailed)?;
-
-        let size_str = size.to_string();
-        let permissions_str = permissions.to_string();
-        let epoch_seconds_str = epoch_seconds.to_string();
-        let epoch_nseconds_str = epoch_nseconds.to_string();
-        let fileid_str = fileid.to_string();
-
-        // Now create the vector with references to our stored strings
-        let hash_fields = vec![
-            ("ftype", node_type),
-            ("size", &size_str),
-            ("permissions", &permissions_str),
-            ("change_time_secs", &epoch_seconds_str),
-            ("change_time_nsecs", &epoch_nseconds_str),
-            ("modification_time_secs", &epoch_seconds_str),
-            ("modification_time_nsecs", &epoch_nseconds_str),
-            ("access_time_secs", &epoch_seconds_str),
-            ("access_time_nsecs", &epoch_nseconds_str),
-            ("birth_time_secs", &epoch_seconds_str),
-            ("birth_time_nsecs", &epoch_nseconds_str),
-            ("fileid", &fileid_str),
-        ];
-
-        // Use hset_multiple instead of individual puts
-        let key = format!("{}{}", community, mount_path);
-        self.hset_multiple(&key, &hash_fields).await?;
-
-        // Set path to id mapping
-        let path_to_id_key = format!("{}{}_path_to_id", community, path);
-        self.db.put(
-            format!("{}:{}", path_to_id_key, mount_path).as_bytes(),
-            fileid_str.as_bytes()
-        ).map_err(|_| DataStoreError::OperationFailed)?;
-
-        // Set id to path mapping
-        let id_to_path_key = format!("{}{}_id_to_path", community, path);
-        self.db.put(
-            format!("{}:{}", id_to_path_key, fileid_str).as_bytes(),
-            mount_path.as_bytes()
-        ).map_err(|_| DataStoreError::OperationFailed)?;
-
-        if fileid == 1 {
-            self.db.put(
-                format!("{}{}_next_fileid", community, path).as_bytes(),
-                b"1"
-            ).map_err(|_| DataStoreError::OperationFailed)?;
+    // The fields written together by hset_multiple, stored as JSON at the key itself
+    fn attribute_fields(&self, key: &str) -> Result<Option<HashMap<String, String>>, DataStoreError> {
+        match self.db.get(key.as_bytes()) {
+            Ok(Some(bytes)) => {
+                let value = String::from_utf8(bytes).map_err(|_| DataStoreError::OperationFailed)?;
+                Ok(serde_json::from_str::<AttributeFields>(&value).ok().map(|attributes| attributes.fields))
+            },
+            Ok(None) => Ok(None),
+            Err(_) => Err(DataStoreError::OperationFailed),
         }
+    }
+}
 
-        Ok(())
+#[async_trait]
+impl DataStore for RocksDBDataStore {
+    async fn legacy_users(&self) -> Result<Vec<String>, DataStoreError> {
+        Ok(self.scan_prefix("user:")?
+            .into_iter()
+            .map(|(key, _)| key["user:".len()..].to_string())
+            .collect())
     }
 
     async fn get(&self, key: &str) -> Result<String, DataStoreError> {
@@ -173,10 +108,12 @@ impl DataStore for RocksDBDataStore {
         self.db.delete(key.as_bytes())
             .map_err(|_| DataStoreError::OperationFailed)?;
         
-        // Also delete the data entry if it exists
-        let data_key = format!("{}:data", key);
-        debug!("rocksdb delete data({})", data_key);
-        let _ = self.db.delete(data_key.as_bytes());
+        // Also delete the entries stored flat under the key: hset fields (including data) and sorted set members
+        for (entry_key, _) in self.scan_prefix(&format!("{}:", key))? {
+            debug!("rocksdb delete entry({})", entry_key);
+            self.db.delete(entry_key.as_bytes())
+                .map_err(|_| DataStoreError::OperationFailed)?;
+        }
         
         Ok(())
     }
@@ -215,7 +152,24 @@ impl DataStore for RocksDBDataStore {
 
     async fn hdel(&self, key: &str, field: &str) -> Result<(), DataStoreError> {
         let full_key = format!("{}:{}", key, field);
-        self.delete(&full_key).await
+        self.db.delete(full_key.as_bytes())
+            .map_err(|_| DataStoreError::OperationFailed)?;
+
+        // The field may also have been written by hset_multiple into the JSON attributes
+        if let Some(mut fields) = self.attribute_fields(key)? {
+            if fields.remove(field).is_some() {
+                if fields.is_empty() {
+                    self.db.delete(key.as_bytes())
+                        .map_err(|_| DataStoreError::OperationFailed)?;
+                } else {
+                    let serialized = serde_json::to_string(&AttributeFields { fields })
+                        .map_err(|_| DataStoreError::OperationFailed)?;
+                    self.db.put(key.as_bytes(), serialized.as_bytes())
+                        .map_err(|_| DataStoreError::OperationFailed)?;
+                }
+            }
+        }
+        Ok(())
     }
 
     async fn incr(&self, key: &str) -> Result<i64, DataStoreError> {
@@ -250,76 +204,48 @@ impl DataStore for RocksDBDataStore {
         }
     }
 
+    // Like Redis RENAME: the key moves together with everything stored flat beneath it
+    // (hset fields, data, sorted set members) and whatever new_key held before is overwritten
     async fn rename(&self, old_key: &str, new_key: &str) -> Result<(), DataStoreError> {
         debug!("rocksdb rename({}) = {}", old_key, new_key);
-        
-        let old_data_key = format!("{}:data", old_key);
-        let new_data_key = format!("{}:data", new_key);
-        
-        debug!("BOWIE rocksdb rename data({}) = {}", old_data_key, new_data_key);
-        if let Ok(data_value) = self.get(&old_data_key).await {
-            debug!("BOWIE rocksdb rename data value found");
-            self.set(&new_data_key, &data_value).await?;
-            debug!("BOWIE rocksdb delete data({})", old_data_key);
-            self.delete(&old_data_key).await?;
+        if old_key == new_key {
+            return Ok(());
         }
 
-        // Handle the main key
-        if let Ok(value) = self.get(old_key).await {
-            self.set(new_key, &value).await?;
-            self.delete(old_key).await?;
+        let old_value = self.db.get(old_key.as_bytes())
+            .map_err(|_| DataStoreError::OperationFailed)?;
+        let old_entries = self.scan_prefix(&format!("{}:", old_key))?;
+        if old_value.is_none() && old_entries.is_empty() {
+            return Err(DataStoreError::KeyNotFound);
+        }
+
+        self.delete(new_key).await?;
+        if let Some(value) = old_value {
+            self.db.put(new_key.as_bytes(), &value)
+                .map_err(|_| DataStoreError::OperationFailed)?;
+            self.db.delete(old_key.as_bytes())
+                .map_err(|_| DataStoreError::OperationFailed)?;
+        }
+        for (entry_key, value) in old_entries {
+            let moved_key = format!("{}{}", new_key, &entry_key[old_key.len()..]);
+            debug!("rocksdb rename entry({}) = {}", entry_key, moved_key);
+            self.db.put(moved_key.as_bytes(), value.as_bytes())
+                .map_err(|_| DataStoreError::OperationFailed)?;
+            self.db.delete(entry_key.as_bytes())
+                .map_err(|_| DataStoreError::OperationFailed)?;
         }
         
         Ok(())
     }
 
+    // Redis glob semantics. Hash fields and sorted set members are stored flat as key:field,
+    // so they are listed as keys of their own when the pattern reaches them
     async fn keys(&self, pattern: &str) -> Result<Vec<String>, DataStoreError> {
-        //debug!("rocksdb keys pattern matching for: {}", pattern);
-        let mut results = Vec::new();
-        
-        // Convert pattern to parts for matching, handling both / and : separators
-        let pattern_without_wildcard = if pattern.ends_with('*') {
-            let base = pattern.trim_end_matches('*');
-            base.trim_end_matches('/') // Remove trailing slash if present
-        } else {
-            pattern
-        };
-        
-        //debug!("Using pattern prefix: {}", pattern_without_wildcard);
-        let iter = self.db.iterator(rocksdb::IteratorMode::Start);
-
-        for item in iter {
-            let (key, _) = item.map_err(|_| DataStoreError::OperationFailed)?;
-            let key_str = String::from_utf8(key.to_vec())
-                .map_err(|_| DataStoreError::OperationFailed)?;
-            
-            //debug!("Checking key: {} against pattern prefix: {}", key_str, pattern_without_wildcard);
-            
-            if pattern.ends_with('*') {
-                // For wildcard patterns, match if the key starts with the pattern prefix
-                // and the next character (if any) is either '/' or ':'
-                let matches = key_str.starts_with(pattern_without_wildcard) && 
-                    key_str[pattern_without_wildcard.len()..]
-                        .chars()
-                        .next()
-                        .map(|c| c == '/' || c == ':')
-                        .unwrap_or(true);
-                
-                //debug!("Wildcard match result: {} for key: {}", matches, key_str);
-                if matches {
-                    results.push(key_str);
-                }
-            } else {
-                // For exact patterns, match the entire string
-                let matches = key_str == pattern;
-                //debug!("Exact match result: {} for key: {}", matches, key_str);
-                if matches {
-                    results.push(key_str);
-                }
-            }
-        }
-
-        //debug!("Found {} matching keys: {:?}", results.len(), results);
+        let results = self.scan_prefix(glob_literal_prefix(pattern))?
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| glob_match(pattern, key))
+            .collect();
         Ok(results)
     }
 
@@ -363,6 +289,9 @@ impl DataStore for RocksDBDataStore {
         let len = results.len() as isize;
         let start = if _start < 0 { (len + _start).max(0) } else { _start.min(len) } as usize;
         let stop = if _stop < 0 { (len + _stop + 1).max(0) } else { (_stop + 1).min(len) } as usize;
+        if start >= stop {
+            return Ok(Vec::new());
+        }
 
         Ok(results[start..stop].to_vec())
     }
@@ -437,12 +366,14 @@ This implementation allows us to remove a member from a sorted set in RocksDB.
 
                 // Check if the score is within the specified range
                 if score >= min && score <= max {
-                    results.push(member.to_string());
+                    results.push((member.to_string(), score));
                 }
             }
         }
+        // Sort results by score (to match Redis behavior)
+        results.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
 
-        Ok(results)
+        Ok(results.into_iter().map(|(member, _)| member).collect())
     }
 
     async fn hset_multiple(&self, key: &str, fields: &[(&str, &str)]) -> Result<(), DataStoreError> {
@@ -469,40 +400,30 @@ This implementation allows us to remove a member from a sorted set in RocksDB.
             .map_err(|_| DataStoreError::OperationFailed)?;
         
         self.db.put(key.as_bytes(), serialized.as_bytes())
-            .map_err(|_| DataStoreError::OperationFailed)
-    }
-
-    async fn hgetall(&self, key: &str) -> Result<Vec<(String, String)>, DataStoreError> {
-        let mut results = Vec::new();
-        let prefix = format!("{}:", key);
+            .map_err(|_| DataStoreError::OperationFailed)?;
 
-        // First check for exact key match (for backward compatibility)
-        if let Ok(Some(value)) = self.db.get(key.as_bytes()) {
-            if let Ok(value_str) = String::from_utf8(value) {
-                if let Ok(hash_fields) = serde_json::from_str::<AttributeFields>(&value_str) {
-                    return Ok(hash_fields.fields.into_iter().collect());
-                }
-            }
+        // hget looks at the flat key:field entry first, so drop any older value written by hset
+        for (field, _) in fields {
+            self.db.delete(format!("{}:{}", key, field).as_bytes())
+                .map_err(|_| DataStoreError::OperationFailed)?;
         }
+        Ok(())
+    }
 
-        // Then check for prefix matches
-        let iter = self.db.prefix_iterator(prefix.as_bytes());
-        for item in iter {
-            let (key_bytes, value_bytes) = item.map_err(|_| DataStoreError::OperationFailed)?;
-            
-            // Convert key and value to strings
-            let full_key = String::from_utf8(key_bytes.to_vec())
-                .map_err(|_| DataStoreError::OperationFailed)?;
-            let value = String::from_utf8(value_bytes.to_vec())
-                .map_err(|_| DataStoreError::OperationFailed)?;
+    // A hash may be split between the JSON attributes written by hset_multiple and flat
+    // key:field entries written by hset; the flat entries are the more recent
+    async fn hgetall(&self, key: &str) -> Result<Vec<(String, String)>, DataStoreError> {
+        let mut fields = self.attribute_fields(key)?.unwrap_or_default();
 
+        let prefix = format!("{}:", key);
+        for (full_key, value) in self.scan_prefix(&prefix)? {
             // Extract field name from the key (remove prefix)
             if let Some(field) = full_key.strip_prefix(&prefix) {
-                results.push((field.to_string(), value));
+                fields.insert(field.to_string(), value);
             }
         }
 
-        Ok(results)
+        Ok(fields.into_iter().collect())
     }
 
     // This function is intended to scan through a sorted set and return members that match a specific pattern. 
@@ -526,7 +447,7 @@ This implementation allows us to remove a member from a sorted set in RocksDB.
             // Extract member from the key (remove prefix)
             if let Some(member) = full_key.strip_prefix(&prefix) {
                 // Check if the member matches the pattern
-                if member.contains(pattern) {
+                if glob_match(pattern, member) {
                     results.push(member.to_string());
                 }
             }
@@ -0,0 +1,288 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use async_trait::async_trait;
+use crate::backingstore::data_store::{DataStore, DataStoreError};
+
+use std::fmt;
+use std::sync::Mutex;
+
+use tracing::debug;
+
+impl fmt::Display for SqliteDataStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SqliteDataStore")
+    }
+}
+
+// Single-file embedded store. Unlike the RocksDB store, which flattens everything into one
+// keyspace, the Redis data types each get their own table so the file can be inspected
+// directly with the sqlite3 shell:
+//   kv(key, value)                  plain strings and counters
+//   hashes(key, field, value)       metadata hashes, path_to_id / id_to_path
+//   zsets(key, member, score)       the _nodes sorted sets
+//   users(userkey)                  user keys from before the user registry, only read
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = NORMAL;
+    CREATE TABLE IF NOT EXISTS kv (
+        key   TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS hashes (
+        key   TEXT NOT NULL,
+        field TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (key, field)
+    );
+    CREATE TABLE IF NOT EXISTS zsets (
+        key    TEXT NOT NULL,
+        member TEXT NOT NULL,
+        score  REAL NOT NULL,
+        PRIMARY KEY (key, member)
+    );
+    CREATE INDEX IF NOT EXISTS zsets_by_score ON zsets (key, score, member);
+    CREATE TABLE IF NOT EXISTS users (
+        userkey TEXT PRIMARY KEY
+    );
+";
+
+pub struct SqliteDataStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteDataStore {
+    pub fn new(path: &str) -> Result<Self, DataStoreError> {
+        debug!("Attempting to open SQLite database at path: {}", path);
+
+        let conn = Connection::open(path).map_err(|e| {
+            debug!("Failed to open SQLite database at path: {}. Error: {:?}", path, e);
+            DataStoreError::ConnectionError
+        })?;
+        conn.execute_batch(SCHEMA).map_err(|e| {
+            debug!("Failed to create SQLite schema at path: {}. Error: {:?}", path, e);
+            DataStoreError::InitializationFailed
+        })?;
+
+        debug!("Successfully opened SQLite database at path: {}", path);
+        Ok(SqliteDataStore { conn: Mutex::new(conn) })
+    }
+
+    fn key_exists(conn: &Connection, key: &str) -> Result<bool, DataStoreError> {
+        conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM kv WHERE key = ?1)
+                 OR EXISTS (SELECT 1 FROM hashes WHERE key = ?1)
+                 OR EXISTS (SELECT 1 FROM zsets WHERE key = ?1)",
+            params![key],
+            |row| row.get(0),
+        ).map_err(|_| DataStoreError::OperationFailed)
+    }
+}
+
+#[async_trait]
+impl DataStore for SqliteDataStore {
+    async fn legacy_users(&self) -> Result<Vec<String>, DataStoreError> {
+        let conn = self.conn.lock().map_err(|_| DataStoreError::OperationFailed)?;
+        let mut stmt = conn.prepare_cached("SELECT userkey FROM users ORDER BY userkey")
+            .map_err(|_| DataStoreError::OperationFailed)?;
+        let rows = stmt.query_map([], |row| row.get(0))
+            .map_err(|_| DataStoreError::OperationFailed)?;
+        rows.collect::<Result<Vec<String>, _>>()
+            .map_err(|_| DataStoreError::OperationFailed)
+    }
+
+    async fn get(&self, key: &str) -> Result<String, DataStoreError> {
+        let conn = self.conn.lock().map_err(|_| DataStoreError::OperationFailed)?;
+        conn.query_row("SELECT value FROM kv WHERE key = ?1", params![key], |row| row.get(0))
+            .optional()
+            .map_err(|_| DataStoreError::OperationFailed)?
+            .ok_or(DataStoreError::KeyNotFound)
+    }
+
+    async fn set(&self, key: &str, value: &str) -> Result<(), DataStoreError> {
+        let conn = self.conn.lock().map_err(|_| DataStoreError::OperationFailed)?;
+        conn.execute("INSERT OR REPLACE INTO kv (key, value) VALUES (?1, ?2)", params![key, value])
+            .map_err(|_| DataStoreError::OperationFailed)?;
+        Ok(())
+    }
+
+    // Like Redis DEL, removes the key whatever type it holds
+    async fn delete(&self, key: &str) -> Result<(), DataStoreError> {
+        debug!("sqlite delete({})", key);
+        let mut conn = self.conn.lock().map_err(|_| DataStoreError::OperationFailed)?;
+        let tx = conn.transaction().map_err(|_| DataStoreError::OperationFailed)?;
+        for table in ["kv", "hashes", "zsets"] {
+            tx.execute(&format!("DELETE FROM {} WHERE key = ?1", table), params![key])
+                .map_err(|_| DataStoreError::OperationFailed)?;
+        }
+        tx.commit().map_err(|_| DataStoreError::OperationFailed)
+    }
+
+    async fn hget(&self, key: &str, field: &str) -> Result<String, DataStoreError> {
+        let conn = self.conn.lock().map_err(|_| DataStoreError::OperationFailed)?;
+        conn.query_row(
+            "SELECT value FROM hashes WHERE key = ?1 AND field = ?2",
+            params![key, field],
+            |row| row.get(0),
+        ).optional()
+            .map_err(|_| DataStoreError::OperationFailed)?
+            .ok_or(DataStoreError::KeyNotFound)
+    }
+
+    async fn hset(&self, key: &str, field: &str, value: &str) -> Result<(), DataStoreError> {
+        let conn = self.conn.lock().map_err(|_| DataStoreError::OperationFailed)?;
+        conn.execute(
+            "INSERT OR REPLACE INTO hashes (key, field, value) VALUES (?1, ?2, ?3)",
+            params![key, field, value],
+        ).map_err(|_| DataStoreError::OperationFailed)?;
+        Ok(())
+    }
+
+    async fn hdel(&self, key: &str, field: &str) -> Result<(), DataStoreError> {
+        let conn = self.conn.lock().map_err(|_| DataStoreError::OperationFailed)?;
+        conn.execute("DELETE FROM hashes WHERE key = ?1 AND field = ?2", params![key, field])
+            .map_err(|_| DataStoreError::OperationFailed)?;
+        Ok(())
+    }
+
+    async fn hgetall(&self, key: &str) -> Result<Vec<(String, String)>, DataStoreError> {
+        let conn = self.conn.lock().map_err(|_| DataStoreError::OperationFailed)?;
+        let mut stmt = conn.prepare_cached("SELECT field, value FROM hashes WHERE key = ?1")
+            .map_err(|_| DataStoreError::OperationFailed)?;
+        let rows = stmt.query_map(params![key], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|_| DataStoreError::OperationFailed)?;
+        rows.collect::<Result<Vec<(String, String)>, _>>()
+            .map_err(|_| DataStoreError::OperationFailed)
+    }
+
+    async fn incr(&self, key: &str) -> Result<i64, DataStoreError> {
+        let mut conn = self.conn.lock().map_err(|_| DataStoreError::OperationFailed)?;
+        let tx = conn.transaction().map_err(|_| DataStoreError::OperationFailed)?;
+        let current: Option<String> = tx.query_row(
+            "SELECT value FROM kv WHERE key = ?1", params![key], |row| row.get(0)
+        ).optional().map_err(|_| DataStoreError::OperationFailed)?;
+        let value = current.as_deref().unwrap_or("0")
+            .parse::<i64>()
+            .map_err(|_| DataStoreError::OperationFailed)?;
+        let new_value = value.checked_add(1).ok_or(DataStoreError::OperationFailed)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO kv (key, value) VALUES (?1, ?2)",
+            params![key, new_value.to_string()],
+        ).map_err(|_| DataStoreError::OperationFailed)?;
+        tx.commit().map_err(|_| DataStoreError::OperationFailed)?;
+        debug!("sqlite incr({}) = {}", key, new_value);
+        Ok(new_value)
+    }
+
+    // Like Redis RENAME: moves the key whatever type it holds and overwrites any existing new_key
+    async fn rename(&self, old_key: &str, new_key: &str) -> Result<(), DataStoreError> {
+        debug!("sqlite rename({}) = {}", old_key, new_key);
+        if old_key == new_key {
+            return Ok(());
+        }
+        let mut conn = self.conn.lock().map_err(|_| DataStoreError::OperationFailed)?;
+        let tx = conn.transaction().map_err(|_| DataStoreError::OperationFailed)?;
+        if !Self::key_exists(&tx, old_key)? {
+            return Err(DataStoreError::KeyNotFound);
+        }
+        for table in ["kv", "hashes", "zsets"] {
+            tx.execute(&format!("DELETE FROM {} WHERE key = ?1", table), params![new_key])
+                .map_err(|_| DataStoreError::OperationFailed)?;
+            tx.execute(&format!("UPDATE {} SET key = ?2 WHERE key = ?1", table), params![old_key, new_key])
+                .map_err(|_| DataStoreError::OperationFailed)?;
+        }
+        tx.commit().map_err(|_| DataStoreError::OperationFailed)
+    }
+
+    // Redis glob patterns (*, ?, [...]) map directly onto SQLite GLOB
+    async fn keys(&self, pattern: &str) -> Result<Vec<String>, DataStoreError> {
+        let conn = self.conn.lock().map_err(|_| DataStoreError::OperationFailed)?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT key FROM kv WHERE key GLOB ?1
+             UNION SELECT key FROM hashes WHERE key GLOB ?1
+             UNION SELECT key FROM zsets WHERE key GLOB ?1"
+        ).map_err(|_| DataStoreError::OperationFailed)?;
+        let rows = stmt.query_map(params![pattern], |row| row.get(0))
+            .map_err(|_| DataStoreError::OperationFailed)?;
+        rows.collect::<Result<Vec<String>, _>>()
+            .map_err(|_| DataStoreError::OperationFailed)
+    }
+
+    async fn zrange_withscores(&self, key: &str, start: isize, stop: isize) -> Result<Vec<(String, f64)>, DataStoreError> {
+        let conn = self.conn.lock().map_err(|_| DataStoreError::OperationFailed)?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT member, score FROM zsets WHERE key = ?1 ORDER BY score, member"
+        ).map_err(|_| DataStoreError::OperationFailed)?;
+        let rows = stmt.query_map(params![key], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|_| DataStoreError::OperationFailed)?;
+        let results = rows.collect::<Result<Vec<(String, f64)>, _>>()
+            .map_err(|_| DataStoreError::OperationFailed)?;
+
+        // Resolve negative indices from the end, as Redis does
+        let len = results.len() as isize;
+        let start = if start < 0 { (len + start).max(0) } else { start.min(len) } as usize;
+        let stop = if stop < 0 { (len + stop + 1).max(0) } else { (stop + 1).min(len) } as usize;
+        if start >= stop {
+            return Ok(Vec::new());
+        }
+        Ok(results[start..stop].to_vec())
+    }
+
+    async fn zadd(&self, key: &str, member: &str, score: f64) -> Result<(), DataStoreError> {
+        let conn = self.conn.lock().map_err(|_| DataStoreError::OperationFailed)?;
+        conn.execute(
+            "INSERT OR REPLACE INTO zsets (key, member, score) VALUES (?1, ?2, ?3)",
+            params![key, member, score],
+        ).map_err(|_| DataStoreError::OperationFailed)?;
+        Ok(())
+    }
+
+    async fn zrem(&self, key: &str, member: &str) -> Result<(), DataStoreError> {
+        let conn = self.conn.lock().map_err(|_| DataStoreError::OperationFailed)?;
+        conn.execute("DELETE FROM zsets WHERE key = ?1 AND member = ?2", params![key, member])
+            .map_err(|_| DataStoreError::OperationFailed)?;
+        Ok(())
+    }
+
+    async fn zrangebyscore(&self, key: &str, min: f64, max: f64) -> Result<Vec<String>, DataStoreError> {
+        let conn = self.conn.lock().map_err(|_| DataStoreError::OperationFailed)?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT member FROM zsets WHERE key = ?1 AND score >= ?2 AND score <= ?3 ORDER BY score, member"
+        ).map_err(|_| DataStoreError::OperationFailed)?;
+        let rows = stmt.query_map(params![key, min, max], |row| row.get(0))
+            .map_err(|_| DataStoreError::OperationFailed)?;
+        rows.collect::<Result<Vec<String>, _>>()
+            .map_err(|_| DataStoreError::OperationFailed)
+    }
+
+    async fn hset_multiple(&self, key: &str, fields: &[(&str, &str)]) -> Result<(), DataStoreError> {
+        let mut conn = self.conn.lock().map_err(|_| DataStoreError::OperationFailed)?;
+        let tx = conn.transaction().map_err(|_| DataStoreError::OperationFailed)?;
+        for (field, value) in fields {
+            tx.execute(
+                "INSERT OR REPLACE INTO hashes (key, field, value) VALUES (?1, ?2, ?3)",
+                params![key, field, value],
+            ).map_err(|_| DataStoreError::OperationFailed)?;
+        }
+        tx.commit().map_err(|_| DataStoreError::OperationFailed)
+    }
+
+    async fn zscan_match(&self, key: &str, pattern: &str) -> Result<Vec<String>, DataStoreError> {
+        let conn = self.conn.lock().map_err(|_| DataStoreError::OperationFailed)?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT member FROM zsets WHERE key = ?1 AND member GLOB ?2 ORDER BY score, member"
+        ).map_err(|_| DataStoreError::OperationFailed)?;
+        let rows = stmt.query_map(params![key, pattern], |row| row.get(0))
+            .map_err(|_| DataStoreError::OperationFailed)?;
+        rows.collect::<Result<Vec<String>, _>>()
+            .map_err(|_| DataStoreError::OperationFailed)
+    }
+
+    async fn zscore(&self, key: &str, member: &str) -> Result<Option<f64>, DataStoreError> {
+        let conn = self.conn.lock().map_err(|_| DataStoreError::OperationFailed)?;
+        conn.query_row(
+            "SELECT score FROM zsets WHERE key = ?1 AND member = ?2",
+            params![key, member],
+            |row| row.get(0),
+        ).optional()
+            .map_err(|_| DataStoreError::OperationFailed)
+    }
+}
@@ -1,23 +1,20 @@
 use async_trait::async_trait;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use crate::backingstore::data_store::{DataStore, DataStoreError, DataStoreResult};
-use crate::backingstore::data_store::KeyType;
+use crate::backingstore::data_store::{glob_match, DataStore, DataStoreError, DataStoreResult};
 
 pub struct TestDataStore {
     data: Arc<RwLock<HashMap<String, String>>>,
-    sets: Arc<RwLock<HashMap<String, HashMap<String, f64>>>>
+    hashes: Arc<RwLock<HashMap<String, HashMap<String, String>>>>,
+    sets: Arc<RwLock<HashMap<String, HashMap<String, f64>>>>,
+    users: Arc<RwLock<BTreeSet<String>>>
 }
 
 #[async_trait]
 impl DataStore for TestDataStore {
-    async fn authenticate_user(&self, userkey: &str) -> KeyType {
-        if userkey.ends_with("-su") {
-            KeyType::Special
-        } else {
-            KeyType::Usual
-        }
+    async fn legacy_users(&self) -> DataStoreResult<Vec<String>> {
+        Ok(self.users.read().await.iter().cloned().collect())
     }
 
     async fn get(&self, key: &str) -> DataStoreResult<String> {
@@ -32,75 +29,112 @@ impl DataStore for TestDataStore {
     }
 
     async fn delete(&self, key: &str) -> DataStoreResult<()> {
-        let mut data = self.data.write().await;
-        data.remove(key);
+        self.data.write().await.remove(key);
+        self.hashes.write().await.remove(key);
+        self.sets.write().await.remove(key);
         Ok(())
     }
 
     async fn hset(&self, key: &str, field: &str, value: &str) -> DataStoreResult<()> {
-        let mut data = self.data.write().await;
-        data.insert(format!("{}:{}", key, field), value.to_string());
+        let mut hashes = self.hashes.write().await;
+        hashes.entry(key.to_string())
+            .or_default()
+            .insert(field.to_string(), value.to_string());
         Ok(())
     }
 
     async fn hget(&self, key: &str, field: &str) -> DataStoreResult<String> {
-        let data = self.data.read().await;
-        data.get(&format!("{}:{}", key, field))
+        let hashes = self.hashes.read().await;
+        hashes.get(key)
+            .and_then(|hash| hash.get(field))
             .cloned()
             .ok_or(DataStoreError::KeyNotFound)
     }
 
     async fn hdel(&self, key: &str, field: &str) -> DataStoreResult<()> {
-        let mut data = self.data.write().await;
-        data.remove(&format!("{}:{}", key, field));
+        let mut hashes = self.hashes.write().await;
+        if let Some(hash) = hashes.get_mut(key) {
+            hash.remove(field);
+            if hash.is_empty() {
+                hashes.remove(key);
+            }
+        }
         Ok(())
     }
 
     async fn hgetall(&self, key: &str) -> DataStoreResult<Vec<(String, String)>> {
-        let data = self.data.read().await;
-        let prefix = format!("{}:", key);
-        let result: Vec<(String, String)> = data.iter()
-            .filter(|(k, _)| k.starts_with(&prefix))
-            .map(|(k, v)| (k[prefix.len()..].to_string(), v.clone()))
-            .collect();
-        Ok(result)
+        let hashes = self.hashes.read().await;
+        Ok(hashes.get(key)
+            .map(|hash| hash.iter()
+                .map(|(field, value)| (field.clone(), value.clone()))
+                .collect())
+            .unwrap_or_default())
     }
 
     async fn incr(&self, key: &str) -> DataStoreResult<i64> {
         let mut data = self.data.write().await;
-        let current = data.get(key)
-            .and_then(|v| v.parse::<i64>().ok())
-            .unwrap_or(0);
+        let current = match data.get(key) {
+            Some(value) => value.parse::<i64>().map_err(|_| DataStoreError::OperationFailed)?,
+            None => 0,
+        };
         let new_value = current + 1;
         data.insert(key.to_string(), new_value.to_string());
         Ok(new_value)
     }
 
     async fn rename(&self, old_key: &str, new_key: &str) -> DataStoreResult<()> {
+        if old_key == new_key {
+            return Ok(());
+        }
         let mut data = self.data.write().await;
+        let mut hashes = self.hashes.write().await;
+        let mut sets = self.sets.write().await;
+        if !data.contains_key(old_key) && !hashes.contains_key(old_key) && !sets.contains_key(old_key) {
+            return Err(DataStoreError::KeyNotFound);
+        }
+        // Whatever new_key held before is overwritten
+        data.remove(new_key);
+        hashes.remove(new_key);
+        sets.remove(new_key);
         if let Some(value) = data.remove(old_key) {
             data.insert(new_key.to_string(), value);
         }
+        if let Some(hash) = hashes.remove(old_key) {
+            hashes.insert(new_key.to_string(), hash);
+        }
+        if let Some(set) = sets.remove(old_key) {
+            sets.insert(new_key.to_string(), set);
+        }
         Ok(())
     }
 
     async fn keys(&self, pattern: &str) -> DataStoreResult<Vec<String>> {
         let data = self.data.read().await;
-        Ok(data.keys()
-            .filter(|k| k.contains(pattern))
+        let hashes = self.hashes.read().await;
+        let sets = self.sets.read().await;
+        let mut keys: Vec<String> = data.keys()
+            .chain(hashes.keys())
+            .chain(sets.keys())
+            .filter(|k| glob_match(pattern, k))
             .cloned()
-            .collect())
+            .collect();
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
     }
 
     async fn zrange_withscores(&self, key: &str, start: isize, stop: isize) -> DataStoreResult<Vec<(String, f64)>> {
         let sets = self.sets.read().await;
-        Ok(sets.get(key)
-            .map(|set| set.iter()
-                .skip(start as usize)
-                .take((stop - start) as usize)
-                .map(|(member, score)| (member.clone(), *score))
-                .collect())
-            .unwrap_or_default())
+        let members = sets.get(key).map(sorted_members).unwrap_or_default();
+
+        // Resolve negative indices from the end, as Redis does
+        let len = members.len() as isize;
+        let start = if start < 0 { (len + start).max(0) } else { start.min(len) } as usize;
+        let stop = if stop < 0 { (len + stop + 1).max(0) } else { (stop + 1).min(len) } as usize;
+        if start >= stop {
+            return Ok(Vec::new());
+        }
+        Ok(members[start..stop].to_vec())
     }
 
     async fn zadd(&self, key: &str, member: &str, score: f64) -> DataStoreResult<()> {
@@ -115,6 +149,9 @@ impl DataStore for TestDataStore {
         let mut sets = self.sets.write().await;
         if let Some(set) = sets.get_mut(key) {
             set.remove(member);
+            if set.is_empty() {
+                sets.remove(key);
+            }
         }
         Ok(())
     }
@@ -122,17 +159,18 @@ impl DataStore for TestDataStore {
     async fn zrangebyscore(&self, key: &str, min: f64, max: f64) -> DataStoreResult<Vec<String>> {
         let sets = self.sets.read().await;
         Ok(sets.get(key)
-            .map(|set| set.iter()
-                .filter(|(_, score)| **score >= min && **score <= max)
-                .map(|(member, _)| member.clone())
+            .map(|set| sorted_members(set).into_iter()
+                .filter(|(_, score)| *score >= min && *score <= max)
+                .map(|(member, _)| member)
                 .collect())
             .unwrap_or_default())
     }
 
     async fn hset_multiple(&self, key: &str, fields: &[(&str, &str)]) -> DataStoreResult<()> {
-        let mut data = self.data.write().await;
+        let mut hashes = self.hashes.write().await;
+        let hash = hashes.entry(key.to_string()).or_default();
         for (field, value) in fields {
-            data.insert(format!("{}:{}", key, field), value.to_string());
+            hash.insert(field.to_string(), value.to_string());
         }
         Ok(())
     }
@@ -140,9 +178,9 @@ impl DataStore for TestDataStore {
     async fn zscan_match(&self, key: &str, pattern: &str) -> DataStoreResult<Vec<String>> {
         let sets = self.sets.read().await;
         Ok(sets.get(key)
-            .map(|set| set.iter()
-                .filter(|(member, _)| member.contains(pattern))
-                .map(|(member, _)| member.clone())
+            .map(|set| sorted_members(set).into_iter()
+                .filter(|(member, _)| glob_match(pattern, member))
+                .map(|(member, _)| member)
                 .collect())
             .unwrap_or_default())
     }
@@ -152,15 +190,17 @@ impl DataStore for TestDataStore {
         Ok(sets.get(key)
             .and_then(|set| set.get(member).copied()))
     }
+}
 
-    async fn init_user_directory(&self, mount_path: &str) -> DataStoreResult<()> {
-        let mut data = self.data.write().await;
-        if data.contains_key(mount_path) {
-            return Ok(());
-        }
-        data.insert(mount_path.to_string(), String::new());
-        Ok(())
-    }
+// Members ordered as Redis orders a sorted set: by score, ties broken by member
+fn sorted_members(set: &HashMap<String, f64>) -> Vec<(String, f64)> {
+    let mut members: Vec<(String, f64)> = set.iter()
+        .map(|(member, score)| (member.clone(), *score))
+        .collect();
+    members.sort_by(|a, b| a.1.partial_cmp(&b.1)
+        .unwrap_or(std::cmp::Ordering::Equal)
+        .then_with(|| a.0.cmp(&b.0)));
+    members
 }
 
 impl Default for TestDataStore {
@@ -173,7 +213,14 @@ impl TestDataStore {
     pub fn new() -> Self {
         TestDataStore {
             data: Arc::new(RwLock::new(HashMap::new())),
-            sets: Arc::new(RwLock::new(HashMap::new()))
+            hashes: Arc::new(RwLock::new(HashMap::new())),
+            sets: Arc::new(RwLock::new(HashMap::new())),
+            users: Arc::new(RwLock::new(BTreeSet::new()))
         }
     }
-}
\ No newline at end of file
+
+    // Stands in for a user set written before the user registry
+    pub async fn add_legacy_user(&self, userkey: &str) {
+        self.users.write().await.insert(userkey.to_string());
+    }
+}
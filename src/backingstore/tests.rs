@@ -1,8 +1,10 @@
 use crate::backingstore::data_store::DataStore;
 use crate::backingstore::redis_data_store::RedisDataStore;
 use crate::backingstore::rocksdb_data_store::RocksDBDataStore;
+#[cfg(feature = "sqlite_store")]
+use crate::backingstore::sqlite_data_store::SqliteDataStore;
 use tempfile::tempdir;
-use graymamba::sharesfs::SharesFS;
+use graymamba::sharesfs::namespace::Namespace;
 
 const TEST_COMMUNITY: &str = "orangery";
 const TEST_NAMESPACE_ID: &str = "citrus";
@@ -15,6 +17,14 @@ async fn setup_rocksdb() -> RocksDBDataStore {
     RocksDBDataStore::new(temp_dir.path().to_str().unwrap()).expect("Failed to create RocksDB store")
 }
 
+#[cfg(feature = "sqlite_store")]
+async fn setup_sqlite() -> (SqliteDataStore, tempfile::TempDir) {
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let path = temp_dir.path().join("graymamba.db");
+    let store = SqliteDataStore::new(path.to_str().unwrap()).expect("Failed to create SQLite store");
+    (store, temp_dir)
+}
+
 fn check_redis_running() -> bool {
     // Attempt to connect to Redis and return true if successful
     let client = redis::Client::open("redis://127.0.0.1:6380"); //one of our expected cluster nodes
@@ -33,16 +43,23 @@ fn check_redis_running() -> bool {
 #[tokio::test]
 async fn test_init_user_directory_structure() {
     let rocks = setup_rocksdb().await;
-    let stores: Vec<(&str, &dyn DataStore)> = vec![/*("redis", &redis),*/ ("rocks", &rocks)];
+    #[allow(unused_mut)]
+    let mut stores: Vec<(&str, &dyn DataStore)> = vec![/*("redis", &redis),*/ ("rocks", &rocks)];
+    #[cfg(feature = "sqlite_store")]
+    let (sqlite, _sqlite_dir) = setup_sqlite().await;
+    #[cfg(feature = "sqlite_store")]
+    stores.push(("sqlite", &sqlite));
 
     for (name, store) in stores {
-        SharesFS::set_namespace_id_and_community(TEST_NAMESPACE_ID, TEST_COMMUNITY).await;
+        let namespace = Namespace::new(TEST_COMMUNITY, TEST_NAMESPACE_ID);
         // Test 1: Initialize root directory
-        store.init_user_directory("/").await.expect(&format!("{} root init failed", name));
+        store.init_user_directory(&namespace, "/").await.expect(&format!("{} root init failed", name));
 
         // Test 2: Verify root directory structure
-        let root_key = &format!("{{{}}}:/", TEST_COMMUNITY);
-        let root_metadata = store.hgetall(root_key).await
+        let root_id = store.get(&namespace.root_key()).await
+            .expect(&format!("{} failed to get root id", name));
+        let root_id: u64 = root_id.parse().expect(&format!("{} root id is not numeric", name));
+        let root_metadata = store.hgetall(&namespace.inode_key(root_id)).await
             .expect(&format!("{} failed to get root metadata", name));
         
         assert!(root_metadata.iter().any(|(k, _)| k == "fileid"), 
@@ -50,45 +67,34 @@ async fn test_init_user_directory_structure() {
         assert!(root_metadata.iter().any(|(k, _)| k == "ftype"), 
             "{} root missing ftype", name);
 
-        // Test 3: Check nodes structure
-        let nodes_key = &format!("{{{}}}:/{}_nodes", TEST_COMMUNITY, TEST_NAMESPACE_ID);
-        let nodes = store.zrange_withscores(nodes_key, 0, -1).await
-            .expect(&format!("{} failed to get nodes", name));
-        
-        assert!(!nodes.is_empty(), "{} nodes should not be empty", name);
-        assert_eq!(nodes[0].1, 1.0, "{} root score should be 1.0", name);
+        // Test 3: The root is its own parent
+        let parent = store.hget(&namespace.inode_key(root_id), "parent").await
+            .expect(&format!("{} failed to get root parent", name));
+        assert_eq!(parent, root_id.to_string(), "{} root should be its own parent", name);
 
         // Test 4: Initialize subdirectory
-        store.init_user_directory("/test").await
+        store.init_user_directory(&namespace, "/test").await
             .expect(&format!("{} subdir init failed", name));
 
-        // Test 5: Verify subdirectory structure
-        let subdir_key =  &format!("{{{}}}:/test", TEST_COMMUNITY);
-        let subdir_metadata = store.hgetall(subdir_key).await
+        // Test 5: Check the root's entry for it
+        let id = store.hget(&namespace.entries_key(root_id), "test").await
+            .expect(&format!("{} failed to get the entry for /test", name));
+        let id: u64 = id.parse().expect(&format!("{} entry id is not numeric", name));
+
+        // Test 6: Verify subdirectory structure and its back-pointer
+        let subdir_key = namespace.inode_key(id);
+        let subdir_metadata = store.hgetall(&subdir_key).await
             .expect(&format!("{} failed to get subdir metadata", name));
         
         assert!(subdir_metadata.iter().any(|(k, _)| k == "fileid"), 
             "{} subdir missing fileid", name);
+        assert_eq!(store.hget(&subdir_key, "parent").await.unwrap(), root_id.to_string(),
+            "{} subdir parent mismatch", name);
+        assert_eq!(store.hget(&subdir_key, "name").await.unwrap(), "test",
+            "{} subdir name mismatch", name);
 
-        // Test 6: Check path_to_id mapping
-        let path_to_id_key = &format!("{{{}}}:/{}_path_to_id", TEST_COMMUNITY, TEST_NAMESPACE_ID);
-        let path_id = store.hget(path_to_id_key, "/test").await
-            .expect(&format!("{} failed to get path_to_id", name));
-        
-        assert!(!path_id.is_empty(), "{} path_to_id should exist", name);
-
-        // Test 7: Check id_to_path mapping
-        let id_to_path_key = &format!("{{{}}}:/{}_id_to_path", TEST_COMMUNITY, TEST_NAMESPACE_ID);
-        let id = store.hget(path_to_id_key, "/test").await
-            .expect(&format!("{} failed to get id", name));
-        let path = store.hget(id_to_path_key, &id).await
-            .expect(&format!("{} failed to get id_to_path", name));
-        
-        assert_eq!(path, "/test", "{} path mismatch in id_to_path", name);
-
-        // Test 8: Verify next_fileid
-        let next_fileid_key = &format!("{{{}}}:/{}_next_fileid", TEST_COMMUNITY, TEST_NAMESPACE_ID);
-        let next_fileid = store.get(next_fileid_key).await
+        // Test 7: Verify next_fileid
+        let next_fileid = store.get(&namespace.next_fileid_key()).await
             .expect(&format!("{} failed to get next_fileid", name));
         
         assert!(!next_fileid.is_empty(), "{} next_fileid should exist", name);
@@ -105,18 +111,27 @@ async fn test_directory_idempotency() {
 
     let redis = setup_redis().await;
     let rocks = setup_rocksdb().await;
-    let stores: Vec<(&str, &dyn DataStore)> = vec![("redis", &redis), ("rocks", &rocks)];
+    #[allow(unused_mut)]
+    let mut stores: Vec<(&str, &dyn DataStore)> = vec![("redis", &redis), ("rocks", &rocks)];
+    #[cfg(feature = "sqlite_store")]
+    let (sqlite, _sqlite_dir) = setup_sqlite().await;
+    #[cfg(feature = "sqlite_store")]
+    stores.push(("sqlite", &sqlite));
 
     for (name, store) in stores {
         // Test 1: Initialize directory twice
-        store.init_user_directory("/test2").await
+        let namespace = Namespace::new(TEST_COMMUNITY, TEST_NAMESPACE_ID);
+        store.init_user_directory(&namespace, "/test2").await
             .expect(&format!("{} first init failed", name));
-        let first_id = store.hget(&format!("{{{}}}:/test2", TEST_COMMUNITY), "fileid").await
+        let root_id = store.get(&namespace.root_key()).await
+            .expect(&format!("{} failed to get root id", name));
+        let root_key = namespace.entries_key(root_id.parse().unwrap());
+        let first_id = store.hget(&root_key, "test2").await
             .expect(&format!("{} failed to get first fileid", name));
         
-        store.init_user_directory("/test2").await
+        store.init_user_directory(&namespace, "/test2").await
             .expect(&format!("{} second init failed", name));
-        let second_id = store.hget(&format!("{{{}}}:/test2", TEST_COMMUNITY), "fileid").await
+        let second_id = store.hget(&root_key, "test2").await
             .expect(&format!("{} failed to get second fileid", name));
         
         assert_eq!(first_id, second_id, 
@@ -133,7 +148,12 @@ async fn test_sorted_set_operations() {
 
     let redis = setup_redis().await;
     let rocks = setup_rocksdb().await;
-    let stores: Vec<(&str, &dyn DataStore)> = vec![("redis", &redis), ("rocks", &rocks)];
+    #[allow(unused_mut)]
+    let mut stores: Vec<(&str, &dyn DataStore)> = vec![("redis", &redis), ("rocks", &rocks)];
+    #[cfg(feature = "sqlite_store")]
+    let (sqlite, _sqlite_dir) = setup_sqlite().await;
+    #[cfg(feature = "sqlite_store")]
+    stores.push(("sqlite", &sqlite));
 
     for (name, store) in stores {
         // Test 1: Add items to sorted set
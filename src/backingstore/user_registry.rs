@@ -0,0 +1,345 @@
+// The users allowed to mount, kept in the DataStore through its generic operations so every
+// backend stores them the same way:
+//
+//   user_record:{name}   hash of role, enabled, uid, signing_key, public_key, exports
+//   user_index           sorted set of every registered name
+//   user_uids            hash of each bound uid to the name of its user
+//
+// A mount is made as the user who signed the call (see AUTH_SIGNED in kernel/protocol/rpc.rs) or
+// else the user registered with the caller's AUTH_UNIX uid. role is "standard" or "superuser"; a
+// superuser mounts the whole export. signing_key is the user's random HMAC key, in hex, which
+// HMAC needs the server to hold, so whoever can read the store can sign as the users who have
+// one; public_key is a hex Ed25519 key, which keeps nothing secret on the server. exports
+// lists the export paths the user may mount, every export when empty.
+use std::fmt;
+
+use rand::RngCore;
+
+use crate::backingstore::data_store::{DataStore, DataStoreError};
+
+const INDEX_KEY: &str = "user_index";
+const UID_INDEX_KEY: &str = "user_uids";
+
+fn record_key(name: &str) -> String {
+    format!("user_record:{}", name)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Standard,
+    Superuser,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Standard => "standard",
+            Role::Superuser => "superuser",
+        }
+    }
+
+    pub fn parse(role: &str) -> Option<Role> {
+        match role {
+            "standard" => Some(Role::Standard),
+            "superuser" => Some(Role::Superuser),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserRecord {
+    pub name: String,
+    pub role: Role,
+    pub enabled: bool,
+    pub uid: Option<u32>,
+    pub signing_key: Option<String>,
+    pub public_key: Option<String>,
+    pub exports: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum UserRegistryError {
+    InvalidName(String),
+    Corrupt(String),
+    Store(DataStoreError),
+}
+
+impl fmt::Display for UserRegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UserRegistryError::InvalidName(name) => write!(f, "Invalid user name: {:?}", name),
+            UserRegistryError::Corrupt(msg) => write!(f, "Corrupt user record: {}", msg),
+            UserRegistryError::Store(e) => write!(f, "Data store error: {:?}", e),
+        }
+    }
+}
+
+impl std::error::Error for UserRegistryError {}
+
+impl From<DataStoreError> for UserRegistryError {
+    fn from(e: DataStoreError) -> Self {
+        UserRegistryError::Store(e)
+    }
+}
+
+// Names become key components and drive directories, so they are kept to a safe alphabet
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+        && name != "."
+        && name != ".."
+}
+
+impl UserRecord {
+    pub fn new(name: &str, role: Role) -> UserRecord {
+        UserRecord {
+            name: name.to_string(),
+            role,
+            enabled: true,
+            uid: None,
+            signing_key: None,
+            public_key: None,
+            exports: Vec::new(),
+        }
+    }
+
+    // The keys of the old per-backend user sets, where a "-su" suffix marked a superuser
+    pub fn from_legacy_key(userkey: &str) -> UserRecord {
+        match userkey.strip_suffix("-su") {
+            Some(name) => UserRecord::new(name, Role::Superuser),
+            None => UserRecord::new(userkey, Role::Standard),
+        }
+    }
+
+    pub fn may_mount(&self, export_path: &str) -> bool {
+        self.exports.is_empty() || self.exports.iter().any(|export| export == export_path)
+    }
+
+    // Replaces the user's HMAC signing key with a new random one, returned to hand to them
+    pub fn issue_signing_key(&mut self) -> Vec<u8> {
+        let mut key = vec![0u8; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        self.signing_key = Some(hex::encode(&key));
+        key
+    }
+
+    // The key AUTH_SIGNED HMAC-SHA256 credentials are signed with, handed out by the users tool
+    pub fn hmac_key(&self) -> Option<Vec<u8>> {
+        hex::decode(self.signing_key.as_deref()?).ok()
+    }
+
+    pub fn fields(&self) -> Vec<(String, String)> {
+        let mut fields = vec![
+            ("role".to_string(), self.role.as_str().to_string()),
+            ("enabled".to_string(), self.enabled.to_string()),
+            ("exports".to_string(), self.exports.join(",")),
+        ];
+        if let Some(uid) = self.uid {
+            fields.push(("uid".to_string(), uid.to_string()));
+        }
+        if let Some(signing_key) = &self.signing_key {
+            fields.push(("signing_key".to_string(), signing_key.clone()));
+        }
+        if let Some(public_key) = &self.public_key {
+            fields.push(("public_key".to_string(), public_key.clone()));
+        }
+        fields
+    }
+
+    pub fn from_fields(name: &str, fields: &[(String, String)]) -> Result<UserRecord, UserRegistryError> {
+        let field = |field: &str| fields.iter().find(|(key, _)| key == field).map(|(_, value)| value.as_str());
+        let corrupt = |what: &str| UserRegistryError::Corrupt(format!("{} of {}", what, name));
+        Ok(UserRecord {
+            name: name.to_string(),
+            role: field("role").and_then(Role::parse).ok_or_else(|| corrupt("role"))?,
+            enabled: field("enabled").and_then(|enabled| enabled.parse().ok()).ok_or_else(|| corrupt("enabled"))?,
+            uid: field("uid").map(|uid| uid.parse().map_err(|_| corrupt("uid"))).transpose()?,
+            signing_key: field("signing_key").map(str::to_string),
+            public_key: field("public_key").map(str::to_string),
+            exports: field("exports")
+                .map(|exports| exports.split(',').filter(|export| !export.is_empty()).map(str::to_string).collect())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+pub async fn get_user<S: DataStore + ?Sized>(store: &S, name: &str) -> Result<Option<UserRecord>, UserRegistryError> {
+    if !valid_name(name) || store.zscore(INDEX_KEY, name).await?.is_none() {
+        return Ok(None);
+    }
+    let fields = store.hgetall(&record_key(name)).await?;
+    UserRecord::from_fields(name, &fields).map(Some)
+}
+
+// Adds the user or replaces its record
+pub async fn put_user<S: DataStore + ?Sized>(store: &S, user: &UserRecord) -> Result<(), UserRegistryError> {
+    if !valid_name(&user.name) {
+        return Err(UserRegistryError::InvalidName(user.name.clone()));
+    }
+    let key = record_key(&user.name);
+    // A uid the user no longer has stops naming them, unless it has since been bound to another
+    if let Ok(old_uid) = store.hget(&key, "uid").await {
+        if user.uid.map(|uid| uid.to_string()) != Some(old_uid.clone())
+            && store.hget(UID_INDEX_KEY, &old_uid).await.ok().as_deref() == Some(user.name.as_str()) {
+            store.hdel(UID_INDEX_KEY, &old_uid).await?;
+        }
+    }
+    // Cleared fields must not linger
+    store.delete(&key).await?;
+    let fields = user.fields();
+    let fields: Vec<(&str, &str)> = fields.iter().map(|(field, value)| (field.as_str(), value.as_str())).collect();
+    store.hset_multiple(&key, &fields).await?;
+    if let Some(uid) = user.uid {
+        store.hset(UID_INDEX_KEY, &uid.to_string(), &user.name).await?;
+    }
+    // Indexed last, so a user only exists once its record is complete
+    store.zadd(INDEX_KEY, &user.name, 0.0).await?;
+    Ok(())
+}
+
+pub async fn remove_user<S: DataStore + ?Sized>(store: &S, name: &str) -> Result<bool, UserRegistryError> {
+    if !valid_name(name) || store.zscore(INDEX_KEY, name).await?.is_none() {
+        return Ok(false);
+    }
+    store.zrem(INDEX_KEY, name).await?;
+    if let Ok(uid) = store.hget(&record_key(name), "uid").await {
+        if store.hget(UID_INDEX_KEY, &uid).await.ok().as_deref() == Some(name) {
+            store.hdel(UID_INDEX_KEY, &uid).await?;
+        }
+    }
+    store.delete(&record_key(name)).await?;
+    Ok(true)
+}
+
+pub async fn list_users<S: DataStore + ?Sized>(store: &S) -> Result<Vec<String>, UserRegistryError> {
+    let mut names: Vec<String> = store.zrange_withscores(INDEX_KEY, 0, -1).await?
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+// The user a uid is registered to. Looked up on every call made with AUTH_UNIX, so through
+// user_uids rather than by reading every record
+pub async fn find_by_uid<S: DataStore + ?Sized>(store: &S, uid: u32) -> Result<Option<UserRecord>, UserRegistryError> {
+    let name = match store.hget(UID_INDEX_KEY, &uid.to_string()).await {
+        Ok(name) => name,
+        Err(DataStoreError::KeyNotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(get_user(store, &name).await?.filter(|user| user.uid == Some(uid)))
+}
+
+// Rebuilds user_uids from the records, for registries written before it was kept. Returns the
+// number of uids indexed
+pub async fn index_uids<S: DataStore + ?Sized>(store: &S) -> Result<usize, UserRegistryError> {
+    let mut indexed = 0;
+    for name in list_users(store).await? {
+        if let Some(uid) = get_user(store, &name).await?.and_then(|user| user.uid) {
+            store.hset(UID_INDEX_KEY, &uid.to_string(), &name).await?;
+            indexed += 1;
+        }
+    }
+    Ok(indexed)
+}
+
+// Registers the users of the backend's legacy user set that the registry does not yet hold.
+// They come without a uid, so cannot mount until one is bound to them
+pub async fn import_legacy_users<S: DataStore + ?Sized>(store: &S) -> Result<Vec<String>, UserRegistryError> {
+    let mut imported = Vec::new();
+    for userkey in store.legacy_users().await? {
+        let user = UserRecord::from_legacy_key(&userkey);
+        if !valid_name(&user.name) || get_user(store, &user.name).await?.is_some() {
+            continue;
+        }
+        put_user(store, &user).await?;
+        imported.push(user.name);
+    }
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backingstore::test_store::TestDataStore;
+
+    #[tokio::test]
+    async fn test_records_round_trip_and_are_found_by_uid() {
+        let store = TestDataStore::new();
+        let mut alice = UserRecord::new("alice", Role::Standard);
+        alice.uid = Some(501);
+        alice.exports = vec!["/".to_string(), "/projects".to_string()];
+        alice.public_key = Some("ab".repeat(32));
+        put_user(&store, &alice).await.unwrap();
+        let mut root = UserRecord::new("root", Role::Superuser);
+        root.uid = Some(0);
+        root.enabled = false;
+        put_user(&store, &root).await.unwrap();
+
+        assert_eq!(get_user(&store, "alice").await.unwrap(), Some(alice.clone()));
+        assert_eq!(list_users(&store).await.unwrap(), vec!["alice".to_string(), "root".to_string()]);
+        assert_eq!(find_by_uid(&store, 0).await.unwrap(), Some(root));
+        assert_eq!(find_by_uid(&store, 1000).await.unwrap(), None);
+        assert!(alice.may_mount("/projects") && !alice.may_mount("/archive"));
+
+        // Replacing a record drops what it no longer has
+        alice.public_key = None;
+        put_user(&store, &alice).await.unwrap();
+        assert_eq!(get_user(&store, "alice").await.unwrap().unwrap().public_key, None);
+
+        // Rebinding a uid moves it in the index
+        alice.uid = Some(502);
+        put_user(&store, &alice).await.unwrap();
+        assert_eq!(find_by_uid(&store, 501).await.unwrap(), None);
+        assert_eq!(find_by_uid(&store, 502).await.unwrap(), Some(alice.clone()));
+
+        assert!(remove_user(&store, "alice").await.unwrap());
+        assert!(!remove_user(&store, "alice").await.unwrap());
+        assert_eq!(find_by_uid(&store, 502).await.unwrap(), None);
+        assert_eq!(get_user(&store, "alice").await.unwrap(), None);
+        assert!(matches!(put_user(&store, &UserRecord::new("a:b", Role::Standard)).await, Err(UserRegistryError::InvalidName(_))));
+    }
+
+    #[tokio::test]
+    async fn test_uids_of_registries_without_the_index_are_indexed() {
+        let store = TestDataStore::new();
+        let mut alice = UserRecord::new("alice", Role::Standard);
+        alice.uid = Some(501);
+        put_user(&store, &alice).await.unwrap();
+        put_user(&store, &UserRecord::new("bob", Role::Standard)).await.unwrap();
+        store.delete(UID_INDEX_KEY).await.unwrap();
+        assert_eq!(find_by_uid(&store, 501).await.unwrap(), None);
+
+        assert_eq!(index_uids(&store).await.unwrap(), 1);
+        assert_eq!(find_by_uid(&store, 501).await.unwrap(), Some(alice));
+    }
+
+    #[test]
+    fn test_signing_keys_are_random() {
+        let mut alice = UserRecord::new("alice", Role::Standard);
+        assert_eq!(alice.hmac_key(), None);
+        let key = alice.issue_signing_key();
+        assert_eq!(alice.hmac_key(), Some(key.clone()));
+        assert_eq!(key.len(), 32);
+        assert_ne!(UserRecord::new("bob", Role::Standard).issue_signing_key(), key);
+        assert_ne!(alice.issue_signing_key(), key);
+    }
+
+    #[tokio::test]
+    async fn test_legacy_users_are_imported_once() {
+        let store = TestDataStore::new();
+        store.add_legacy_user("carol-su").await;
+        store.add_legacy_user("dave").await;
+        let mut dave = UserRecord::new("dave", Role::Standard);
+        dave.uid = Some(1001);
+        put_user(&store, &dave).await.unwrap();
+
+        assert_eq!(import_legacy_users(&store).await.unwrap(), vec!["carol".to_string()]);
+        assert_eq!(get_user(&store, "carol").await.unwrap(), Some(UserRecord::new("carol", Role::Superuser)));
+        assert_eq!(get_user(&store, "dave").await.unwrap(), Some(dave));
+        assert!(import_legacy_users(&store).await.unwrap().is_empty());
+    }
+}
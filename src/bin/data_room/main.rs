@@ -23,11 +23,13 @@ use std::sync::Arc;
 use tokio::sync::Mutex;
 
 use graymamba::nfsclient::{
-    mount::{self, MountReply},
+    auth::{self, SignedCredential},
+    mount::{self, MountCredential, MountReply},
     null,
     readdirplus::{self, ReaddirplusReply},
     send_rpc_message,
     receive_rpc_reply,
+    tls::{self, NfsStream},
 };
 
 use tokio::net::TcpStream;
@@ -43,7 +45,7 @@ struct LoginState {
 
 #[derive(Debug, Clone)]
 struct NfsSession {
-    stream: Arc<Mutex<TcpStream>>,
+    stream: Arc<Mutex<NfsStream>>,
     fs_handle: [u8; 16],
     dir_file_handles: Vec<([u8; 16], String, u64)>, // (handle, name, size)
 }
@@ -634,11 +636,43 @@ impl DataRoom {
             .get_str("nfs.data_room_address")
             .unwrap_or_else(|_| "127.0.0.1:2049".to_string());
         debug!("===============nfs_addr({})", nfs_addr);
+        // The uid the server's user registry binds to this user, and the key they sign with if
+        // the server issued one
+        let uid = settings
+            .get_int("nfs.data_room_uid")
+            .map(|uid| uid as u32)
+            .unwrap_or(auth::DEFAULT_UID);
+        let credential = match settings.get_str("nfs.data_room_signing_key") {
+            Ok(spec) => MountCredential::Signed(Box::new(SignedCredential {
+                user: username.to_string(),
+                uid,
+                gid: auth::DEFAULT_GID,
+                key: auth::SigningKey::parse(&spec).map_err(NfsError::ProtocolError)?,
+            })),
+            Err(_) => MountCredential::Unix(uid),
+        };
 
         let addr: SocketAddr = nfs_addr.parse()
             .map_err(|e: std::net::AddrParseError| NfsError::NetworkError(e.to_string()))?;
         
-        let mut stream = TcpStream::connect(addr).await?;
+        let stream = TcpStream::connect(addr).await?;
+
+        // RPC-over-TLS when a CA to verify the server by is configured, presenting the user's
+        // client certificate if they have one
+        let mut stream = match settings.get_str("nfs.data_room_tls_ca") {
+            Ok(ca) => {
+                let cert = settings.get_str("nfs.data_room_tls_cert").ok();
+                let key = settings.get_str("nfs.data_room_tls_key").ok();
+                let config = tls::client_config(&ca, cert.as_deref().zip(key.as_deref()))
+                    .map_err(|e| NfsError::NetworkError(e.to_string()))?;
+                let server_name = settings
+                    .get_str("nfs.data_room_tls_server_name")
+                    .unwrap_or_else(|_| addr.ip().to_string());
+                tls::start_tls(stream, &server_name, config).await
+                    .map_err(|e| NfsError::NetworkError(e.to_string()))?
+            }
+            Err(_) => NfsStream::Plain(stream),
+        };
 
         // NULL call
         let null_call = null::build_null_call(1);
@@ -648,7 +682,7 @@ impl DataRoom {
             .map_err(|e| NfsError::ProtocolError(e.to_string()))?;
 
         // MOUNT call
-        let mount_call = mount::build_mount_call(2, username);
+        let mount_call = mount::build_mount_call(2, username, &credential);
         send_rpc_message(&mut stream, &mount_call).await
             .map_err(|e| NfsError::ProtocolError(e.to_string()))?;
         
@@ -0,0 +1,87 @@
+// Offline consistency checker for a SharesFS namespace. Prints a JSON report on stdout.
+//
+//   fsck --store rocksdb:../RocksDBs/graymamba
+//   fsck --store redis --repair
+//
+// Exit status: 0 when the namespace is consistent (or every finding was repaired), 1 when
+// findings remain, 2 when the check could not run. Stop the server before running it.
+use std::sync::Arc;
+
+use config::{Config, File as ConfigFile};
+use graymamba::backingstore::data_store::DataStore;
+use graymamba::sharesfs::namespace::Namespace;
+use graymamba::backingstore::redis_data_store::RedisDataStore;
+use graymamba::backingstore::rocksdb_data_store::RocksDBDataStore;
+#[cfg(feature = "sqlite_store")]
+use graymamba::backingstore::sqlite_data_store::SqliteDataStore;
+use graymamba::secret_sharing::SecretSharingService;
+use graymamba::sharesfs::fsck::check_namespace;
+
+const USAGE: &str = "usage: fsck --store <store> [--community <name>] [--namespace <id>] [--repair] [--skip-content]
+stores: rocksdb:<path> | redis | sqlite:<path>";
+
+fn open_store(spec: &str) -> Result<Arc<dyn DataStore>, String> {
+    let (kind, location) = spec.split_once(':').unwrap_or((spec, ""));
+    match kind {
+        "rocksdb" if !location.is_empty() => RocksDBDataStore::new(location)
+            .map(|store| Arc::new(store) as Arc<dyn DataStore>)
+            .map_err(|e| format!("cannot open RocksDB at {}: {:?}", location, e)),
+        "redis" => RedisDataStore::new()
+            .map(|store| Arc::new(store) as Arc<dyn DataStore>)
+            .map_err(|e| format!("cannot connect to Redis: {}", e)),
+        #[cfg(feature = "sqlite_store")]
+        "sqlite" if !location.is_empty() => SqliteDataStore::new(location)
+            .map(|store| Arc::new(store) as Arc<dyn DataStore>)
+            .map_err(|e| format!("cannot open SQLite at {}: {:?}", location, e)),
+        _ => Err(format!("unsupported store {:?}\n{}", spec, USAGE)),
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(2);
+}
+
+#[tokio::main]
+async fn main() {
+    let mut settings = Config::default();
+    settings.merge(ConfigFile::with_name("config/settings.toml")).ok();
+
+    let mut store = None;
+    let mut community = settings.get_str("storage.community").ok();
+    let mut namespace_id = settings.get_str("storage.namespace_id").ok();
+    let mut repair = false;
+    let mut check_content = true;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--store" => store = args.next(),
+            "--community" => community = args.next(),
+            "--namespace" => namespace_id = args.next(),
+            "--repair" => repair = true,
+            "--skip-content" => check_content = false,
+            _ => fail(USAGE),
+        }
+    }
+    let (Some(store), Some(community), Some(namespace_id)) = (store, community, namespace_id) else {
+        fail(USAGE);
+    };
+
+    let store = open_store(&store).unwrap_or_else(|e| fail(&e));
+    let secret_sharing = if check_content {
+        Some(SecretSharingService::new().unwrap_or_else(|e| fail(&format!("cannot load secret sharing settings: {}", e))))
+    } else {
+        None
+    };
+
+    let namespace = Namespace::new(&community, &namespace_id);
+    let report = check_namespace(store.as_ref(), &namespace, secret_sharing.as_ref(), repair)
+        .await
+        .unwrap_or_else(|e| fail(&format!("check failed: {:?}", e)));
+
+    println!("{}", serde_json::to_string_pretty(&report).expect("report serializes"));
+    if report.unrepaired() > 0 {
+        std::process::exit(1);
+    }
+}
@@ -14,11 +14,12 @@ fn main() {
         // Check store features
         let redis_enabled = std::env::var("CARGO_FEATURE_REDIS_STORE").is_ok();
         let rocksdb_enabled = std::env::var("CARGO_FEATURE_ROCKSDB_STORE").is_ok();
+        let sqlite_enabled = std::env::var("CARGO_FEATURE_SQLITE_STORE").is_ok();
 
-        match (redis_enabled, rocksdb_enabled) {
-            (false, false) => panic!("Either 'redis_store' or 'rocksdb_store' feature must be enabled for graymamba"),
-            (true, true) => panic!("Only one store feature can be enabled at a time for graymamba"),
-            _ => {}
+        match [redis_enabled, rocksdb_enabled, sqlite_enabled].iter().filter(|enabled| **enabled).count() {
+            0 => panic!("One of 'redis_store', 'rocksdb_store' or 'sqlite_store' features must be enabled for graymamba"),
+            1 => {}
+            _ => panic!("Only one store feature can be enabled at a time for graymamba"),
         }
     }
 }
\ No newline at end of file
@@ -1,8 +1,31 @@
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use graymamba::kernel::protocol::connections::{ConnectionLimits, Connections};
+use graymamba::kernel::protocol::context::ListenerPorts;
+use graymamba::kernel::protocol::drc::{self, DuplicateRequestCache};
+use graymamba::kernel::protocol::rpc::NonceCache;
+use graymamba::kernel::protocol::rpcbind::RpcbindRegistry;
+use graymamba::kernel::protocol::rpcwire::RecordLimits;
 use graymamba::kernel::protocol::tcp::{NFSTcp, NFSTcpListener};
-use graymamba::sharesfs::SharesFS;
+use graymamba::kernel::protocol::tls;
+use graymamba::kernel::protocol::udp::{self, NFSUdpListener};
+use graymamba::kernel::api::{nlm, nsm, portmap};
+use graymamba::kernel::vfs::api::NFSFileSystem;
+use graymamba::kernel::vfs::exports::{Export, ExportTable};
+use graymamba::kernel::vfs::locks::{LockManager, DEFAULT_GRACE_PERIOD};
+use graymamba::sharesfs::namespace;
+use graymamba::sharesfs::export::{exports_from_settings, AuditBackend, StoreSpec};
+use graymamba::sharesfs::changes::HeldInodes;
+use graymamba::sharesfs::quota::quotas_from_settings;
+use graymamba::sharesfs::tenant::tenants_from_settings;
+use graymamba::backingstore::data_store::DataStore;
+use graymamba::backingstore::{host_monitor, user_registry};
+use graymamba::backingstore::caching_data_store::CachingDataStore;
+use graymamba::nfsclient;
+use std::time::Duration;
 
 use graymamba::audit_adapters::irrefutable_audit::IrrefutableAudit;
+use graymamba::audit_adapters::audit_system::AuditSystem;
 #[cfg(feature = "merkle_audit")]
 use graymamba::audit_adapters::merkle_audit::MerkleBasedAuditSystem;
 
@@ -28,7 +51,7 @@ use graymamba::kernel::metrics;
 
 //use tracing::{info, error};
 
-const HOSTPORT: u32 = 2049;
+const HOSTPORT: u16 = 2049;
 
 #[cfg(feature = "metrics")]
 async fn metrics_handler(req: Request<Body>) -> Result<Response<Body>, Infallible> {
@@ -88,6 +111,51 @@ async fn start_metrics_server() -> tokio::task::JoinHandle<()> {
     })
 }
 
+// A backing store named by an export's store setting
+fn open_store(spec: &StoreSpec) -> Result<Arc<dyn DataStore>, String> {
+    match spec {
+        StoreSpec::Default => Err("the default store is set up from [storage]".to_string()),
+        StoreSpec::RocksDB(path) => {
+            use graymamba::backingstore::rocksdb_data_store::RocksDBDataStore;
+            Ok(Arc::new(RocksDBDataStore::new(path).map_err(|e| format!("{:?}", e))?))
+        }
+        #[cfg(feature = "sqlite_store")]
+        StoreSpec::Sqlite(path) => {
+            use graymamba::backingstore::sqlite_data_store::SqliteDataStore;
+            Ok(Arc::new(SqliteDataStore::new(path).map_err(|e| format!("{:?}", e))?))
+        }
+        #[cfg(not(feature = "sqlite_store"))]
+        StoreSpec::Sqlite(_) => Err("built without the 'sqlite_store' feature".to_string()),
+        StoreSpec::Redis => {
+            use graymamba::backingstore::redis_data_store::RedisDataStore;
+            Ok(Arc::new(RedisDataStore::new().map_err(|e| e.to_string())?))
+        }
+    }
+}
+
+fn fatal_bind(address: &str, e: std::io::Error) -> ! {
+    eprintln!("❌ Fatal Error: cannot listen on {}: {}", address, e);
+    std::process::exit(1);
+}
+
+// The name client status monitors know the server by, unless [locking] gives one
+fn local_host_name() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .ok()
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+// Ctrl-C, or the SIGTERM of a service manager
+async fn shutdown_signal() -> std::io::Result<()> {
+    let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+        interrupted = signal::ctrl_c() => interrupted,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize logging first
@@ -134,9 +202,29 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Print enabled features
     println!("Enabled features:");
 
-    SharesFS::set_namespace_id_and_community(settings.get_str("storage.namespace_id").unwrap().as_str(), settings.get_str("storage.community").unwrap().as_str()).await;
+    let tenants = match tenants_from_settings(&settings, HOSTPORT) {
+        Ok(tenants) => tenants,
+        Err(e) => {
+            eprintln!("❌ Fatal Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let exports = match exports_from_settings(&settings, &tenants) {
+        Ok(exports) => exports,
+        Err(e) => {
+            eprintln!("❌ Fatal Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let quotas = match quotas_from_settings(&settings) {
+        Ok(quotas) => Arc::new(quotas),
+        Err(e) => {
+            eprintln!("❌ Fatal Error: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    let data_store = {
+    let data_store: Arc<dyn DataStore> = {
         #[cfg(feature = "redis_store")]
         {
             use graymamba::backingstore::redis_data_store::RedisDataStore;
@@ -154,8 +242,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             ).expect("Failed to create RocksDB data store"))
         }
 
-        #[cfg(not(any(feature = "redis_store", feature = "rocksdb_store")))]
-        compile_error!("Either 'redis_store' or 'rocksdb_store' feature must be enabled");
+        #[cfg(feature = "sqlite_store")]
+        {
+            use graymamba::backingstore::sqlite_data_store::SqliteDataStore;
+            Arc::new(SqliteDataStore::new(
+                settings.get_str("storage.sqlite_path")
+                    .expect("Failed to get sqlite_path from settings")
+                    .as_str()
+            ).expect("Failed to create SQLite data store"))
+        }
+
+        #[cfg(not(any(feature = "redis_store", feature = "rocksdb_store", feature = "sqlite_store")))]
+        compile_error!("One of 'redis_store', 'rocksdb_store' or 'sqlite_store' features must be enabled");
     };
     
 
@@ -192,11 +290,190 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         compile_error!("Either 'merkle_audit' or 'az_audit' feature must be enabled");
     };
 
-    let shares_fs = SharesFS::new(data_store, audit_system.clone());
-    let shares_fs_clone = shares_fs.clone();
-    tokio::spawn(async move {
-        shares_fs_clone.start_monitoring().await;
-    });
+    // Exports with audit = "none" share an audit system that only logs their events
+    let unaudited: Option<Arc<dyn IrrefutableAudit>> = if exports.iter().any(|export| export.audit == AuditBackend::None) {
+        match AuditSystem::new().await {
+            Ok(audit) => Some(Arc::new(audit)),
+            Err(e) => {
+                eprintln!("❌ Fatal Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    // Optional read-through cache of metadata reads in front of each backing store
+    let cache_enabled = settings.get::<bool>("metadata_cache.enabled").unwrap_or(false);
+    let capacity: usize = settings.get("metadata_cache.capacity").unwrap_or(10000);
+    let ttl_ms: u64 = settings.get("metadata_cache.ttl_ms").unwrap_or(2000);
+    if cache_enabled {
+        println!("Metadata cache enabled: {} entries, {}ms TTL", capacity, ttl_ms);
+    }
+    let with_cache = |data_store: Arc<dyn DataStore>| -> Arc<dyn DataStore> {
+        if cache_enabled {
+            Arc::new(CachingDataStore::new(data_store, capacity, Duration::from_millis(ttl_ms)))
+        } else {
+            data_store
+        }
+    };
+
+    // One of each store the exports use, shared between them
+    let mut stores: HashMap<StoreSpec, Arc<dyn DataStore>> = HashMap::new();
+    stores.insert(StoreSpec::Default, with_cache(data_store));
+    for export in &exports {
+        if !stores.contains_key(&export.store) {
+            match open_store(&export.store) {
+                Ok(data_store) => {
+                    stores.insert(export.store.clone(), with_cache(data_store));
+                }
+                Err(e) => {
+                    eprintln!("❌ Fatal Error: cannot open store {:?}: {}", export.store, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
+    // Namespaces written before inode keying are converted in place, once
+    let mut checked = HashSet::new();
+    for export in &exports {
+        if !checked.insert((export.store.clone(), export.tenant)) {
+            continue;
+        }
+        let data_store = &stores[&export.store];
+        let namespace = tenants[export.tenant].namespace();
+        if matches!(namespace::has_legacy_layout(data_store.as_ref(), &namespace).await, Ok(true)) {
+            match namespace::upgrade_layout(data_store.as_ref(), &namespace).await {
+                Ok(stats) if stats.left_behind.is_empty() => println!("Upgraded {} paths of {:?} to the inode layout", stats.upgraded, namespace),
+                Ok(stats) => eprintln!("⚠️ Upgraded {} paths of {:?} to the inode layout, {} left behind (run fsck): {:?}",
+                    stats.upgraded, namespace, stats.left_behind.len(), stats.left_behind),
+                Err(e) => {
+                    eprintln!("❌ Fatal Error: cannot upgrade the layout of {:?}: {:?}", namespace, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
+    // Users of the old per-backend user sets are carried into the registry, without a uid, and
+    // the uids of registries written before their index was kept are indexed
+    for (spec, data_store) in &stores {
+        if let Err(e) = user_registry::index_uids(data_store.as_ref()).await {
+            eprintln!("❌ Fatal Error: cannot index the user uids of store {:?}: {}", spec, e);
+            std::process::exit(1);
+        }
+        match user_registry::import_legacy_users(data_store.as_ref()).await {
+            Ok(imported) if imported.is_empty() => {}
+            Ok(imported) => eprintln!("⚠️ Registered legacy users {:?} of store {:?}; bind a uid to each with the users tool before they mount",
+                imported, spec),
+            Err(e) => {
+                eprintln!("❌ Fatal Error: cannot import the legacy users of store {:?}: {}", spec, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // RPC-over-TLS on every listener, when [tls] names the server's certificate and key
+    let tls_config = match (settings.get_str("tls.cert"), settings.get_str("tls.key")) {
+        (Ok(cert), Ok(key)) => {
+            let client_ca = settings.get_str("tls.client_ca").ok();
+            let require_client_cert = settings.get::<bool>("tls.require_client_cert").unwrap_or(false);
+            match tls::server_config(&cert, &key, client_ca.as_deref(), require_client_cert) {
+                Ok(config) => Some(config),
+                Err(e) => {
+                    eprintln!("❌ Fatal Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => None,
+    };
+    if tls_config.is_none() {
+        if let Some(export) = exports.iter().find(|export| export.require_tls) {
+            eprintln!("❌ Fatal Error: {} is only served over TLS, but [tls] has no cert and key", export.path);
+            std::process::exit(1);
+        }
+    }
+
+    // NFS over UDP on each tenant's port as well, for clients that cannot use TCP
+    let udp_enabled = settings.get::<bool>("udp.enabled").unwrap_or(false);
+    let max_datagram: usize = settings.get("udp.max_datagram").unwrap_or(udp::MAX_DATAGRAM);
+
+    // Bounds on each TCP connection's records and on the calls it may have pending
+    let default_limits = RecordLimits::default();
+    let record_limits = RecordLimits {
+        max_fragment: settings.get("tcp.max_fragment").unwrap_or(default_limits.max_fragment),
+        max_record: settings.get("tcp.max_record").unwrap_or(default_limits.max_record),
+        max_pending: settings.get("tcp.max_pending").unwrap_or(default_limits.max_pending),
+    };
+
+    // Connections of all the listeners together, and how long a shutdown waits for them
+    let connections = Arc::new(Connections::new(ConnectionLimits {
+        max_connections: settings.get("connections.max_connections").ok(),
+        max_per_client: settings.get("connections.max_per_client").ok(),
+        idle_timeout: settings.get::<u64>("connections.idle_timeout_secs").ok().map(Duration::from_secs),
+    }));
+    let drain_timeout = Duration::from_secs(settings.get("connections.drain_timeout_secs").unwrap_or(30));
+
+    // An embedded portmapper on the standard port, which usually needs root to bind
+    let rpcbind_port = settings
+        .get::<bool>("rpcbind.enabled")
+        .unwrap_or(false)
+        .then(|| settings.get::<u16>("rpcbind.port").unwrap_or(111));
+
+    // Replies of the latest non-idempotent calls of each client, for their retransmissions.
+    // UDP always uses the cache; TCP clients only retransmit after a reconnect, and may do without.
+    let drc_enabled = settings.get::<bool>("duplicate_request_cache.enabled").unwrap_or(true);
+    let drc_clients: usize = settings.get("duplicate_request_cache.clients").unwrap_or(drc::DEFAULT_CLIENTS);
+    let drc_calls: usize = settings.get("duplicate_request_cache.calls_per_client").unwrap_or(drc::DEFAULT_CALLS_PER_CLIENT);
+
+    // Advisory file locking for clients that lock with fcntl, found through [rpcbind]
+    let locking_enabled = settings.get::<bool>("locking.enabled").unwrap_or(true);
+    let grace_period = settings
+        .get::<u64>("locking.grace_period_secs")
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_GRACE_PERIOD);
+    let server_name = settings.get_str("locking.server_name").unwrap_or_else(|_| local_host_name());
+
+    // The clients that held locks before a restart are told the server lost them, and given the
+    // grace period to reclaim them before anyone else may lock
+    let mut lock_grace = Duration::ZERO;
+    let mut lock_state = 1;
+    if locking_enabled {
+        lock_state = match host_monitor::next_state(stores[&StoreSpec::Default].as_ref()).await {
+            Ok(state) => state,
+            Err(e) => {
+                eprintln!("❌ Fatal Error: cannot count the restart of the lock manager: {:?}", e);
+                std::process::exit(1);
+            }
+        };
+        for (spec, data_store) in &stores {
+            let hosts = host_monitor::monitored_hosts(data_store.as_ref()).await.unwrap_or_else(|e| {
+                eprintln!("⚠️ Cannot read the hosts holding locks in store {:?}: {:?}", spec, e);
+                Vec::new()
+            });
+            for (host, address) in hosts {
+                lock_grace = grace_period;
+                let (data_store, server_name) = (data_store.clone(), server_name.clone());
+                tokio::spawn(async move {
+                    let notified = match address.parse() {
+                        Ok(ip) => nfsclient::nsm::notify(ip, &server_name, lock_state).await,
+                        Err(e) => Err(e.into()),
+                    };
+                    match notified {
+                        Ok(()) => {
+                            let _ = host_monitor::unmonitor(data_store.as_ref(), &host).await;
+                        }
+                        Err(e) => eprintln!("⚠️ Cannot tell {} at {} to reclaim its locks: {}", host, address, e),
+                    }
+                });
+            }
+        }
+        if !lock_grace.is_zero() {
+            println!("Clients have {}s to reclaim their locks", lock_grace.as_secs());
+        }
+    }
 
     println!("🚀 graymamba launched");
     
@@ -206,32 +483,170 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         start_metrics_server().await
     };
 
-    // Start NFS server
-    let listener = NFSTcpListener::bind(&format!("0.0.0.0:{HOSTPORT}"), shares_fs)
-        .await
-        .unwrap();
-    
-    let nfs_handle = tokio::spawn(async move {
-        listener.handle_forever().await
-    });
+    // Start an NFS server per tenant, serving each of its exports
+    let mut nfs_handles = Vec::new();
+    let mut filesystems = Vec::new();
+    for (index, tenant) in tenants.iter().enumerate() {
+        let mut table = Vec::new();
+        let mut first_fs = None;
+        // The tenant's exports may overlap, so their changes hold inodes in one place
+        let held = Arc::new(HeldInodes::default());
+        for export in exports.iter().filter(|export| export.tenant == index) {
+            let audit = match export.audit {
+                AuditBackend::Default => audit_system.clone(),
+                AuditBackend::None => unaudited.clone().expect("unaudited exports have an audit system"),
+            };
+            let mut shares_fs = export.filesystem(tenant, stores[&export.store].clone(), audit, table.len() as u32);
+            shares_fs.quotas = quotas.clone();
+            shares_fs.held = held.clone();
+            filesystems.push(shares_fs.clone());
+            let shares_fs_clone = shares_fs.clone();
+            tokio::spawn(async move {
+                shares_fs_clone.start_monitoring().await;
+            });
+            println!("Exporting {} of {:?}{}", export.path, tenant.namespace(), if export.read_only { " read-only" } else { "" });
+
+            // Read-only users of a writable export mount a read-only view of it
+            let read_only_vfs: Option<Arc<dyn NFSFileSystem + Send + Sync>> = if export.read_only || export.read_only_users.is_empty() {
+                None
+            } else {
+                Some(Arc::new(shares_fs.read_only_view()))
+            };
+
+            first_fs.get_or_insert_with(|| shares_fs.clone());
+            table.push(Export {
+                path: export.path.clone(),
+                clients: export.clients.clone(),
+                read_only: export.read_only,
+                read_only_users: export.read_only_users.iter().cloned().collect(),
+                root_squash: export.root_squash,
+                require_signed_auth: export.require_signed_auth,
+                require_tls: export.require_tls,
+                vfs: Arc::new(shares_fs),
+                read_only_vfs,
+            });
+        }
+
+        let first_fs = first_fs.expect("every tenant has an export");
+        let table = Arc::new(ExportTable::new(table));
+        let mut listener = NFSTcpListener::bind(&format!("0.0.0.0:{}", tenant.port), first_fs.clone())
+            .await
+            .unwrap();
+        listener.set_exports(table.clone());
+        listener.set_record_limits(record_limits);
+        listener.set_connections(connections.clone());
+        let drc = Arc::new(DuplicateRequestCache::new(drc_clients, drc_calls));
+        listener.set_duplicate_request_cache(drc_enabled.then(|| drc.clone()));
+        if let Some(tls_config) = &tls_config {
+            listener.set_tls(tls_config.clone());
+        }
+        let registry = Arc::new(RpcbindRegistry::for_server(ListenerPorts {
+            tcp: Some(tenant.port),
+            udp: udp_enabled.then_some(tenant.port),
+        }));
+        if !locking_enabled {
+            registry.withdraw(nlm::PROGRAM);
+            registry.withdraw(nsm::PROGRAM);
+        }
+        listener.set_rpcbind(registry.clone());
+        let locks = Arc::new(LockManager::new(lock_grace));
+        locks.set_state(lock_state);
+        listener.set_lock_manager(locks.clone());
+        let nonces = Arc::new(NonceCache::default());
+        listener.set_nonces(nonces.clone());
+
+        // The first tenant's programs are also found through the standard portmapper port, for
+        // clients that mount without port and mountport options
+        if let Some(rpcbind_port) = rpcbind_port.filter(|_| index == 0) {
+            let ports = ListenerPorts { tcp: Some(rpcbind_port), udp: udp_enabled.then_some(rpcbind_port) };
+            for vers in portmap::VERSION..=portmap::RPCBVERS4 {
+                registry.register(portmap::PROGRAM, vers, ports);
+            }
+            let address = format!("0.0.0.0:{}", rpcbind_port);
+            let mut portmapper = NFSTcpListener::bind(&address, first_fs.clone())
+                .await
+                .unwrap_or_else(|e| fatal_bind(&address, e));
+            portmapper.set_exports(table.clone());
+            portmapper.set_rpcbind(registry.clone());
+            portmapper.set_lock_manager(locks.clone());
+            portmapper.set_nonces(nonces.clone());
+            portmapper.set_connections(connections.clone());
+            nfs_handles.push(tokio::spawn(async move {
+                portmapper.handle_forever().await
+            }));
+            if udp_enabled {
+                let mut portmapper = NFSUdpListener::bind(&address, first_fs.clone())
+                    .await
+                    .unwrap_or_else(|e| fatal_bind(&address, e));
+                portmapper.set_exports(table.clone());
+                portmapper.set_rpcbind(registry.clone());
+                portmapper.set_lock_manager(locks.clone());
+                portmapper.set_nonces(nonces.clone());
+                portmapper.set_connections(connections.clone());
+                nfs_handles.push(tokio::spawn(async move {
+                    portmapper.handle_forever().await
+                }));
+            }
+            println!("Answering portmap and rpcbind calls for {:?} on port {}", tenant.namespace(), rpcbind_port);
+        }
+
+        if udp_enabled {
+            let mut udp_listener = NFSUdpListener::bind(&format!("0.0.0.0:{}", tenant.port), first_fs)
+                .await
+                .unwrap();
+            udp_listener.set_exports(table);
+            udp_listener.set_max_datagram(max_datagram);
+            udp_listener.set_duplicate_request_cache(drc);
+            udp_listener.set_rpcbind(registry);
+            udp_listener.set_lock_manager(locks);
+            udp_listener.set_nonces(nonces);
+            udp_listener.set_connections(connections.clone());
+            println!("Serving {:?} over UDP on port {}", tenant.namespace(), tenant.port);
+            nfs_handles.push(tokio::spawn(async move {
+                udp_listener.handle_forever().await
+            }));
+        }
+        println!("Serving {:?} on port {}", tenant.namespace(), tenant.port);
+        nfs_handles.push(tokio::spawn(async move {
+            listener.handle_forever().await
+        }));
+    }
 
     // Wait for shutdown signal
-    match signal::ctrl_c().await {
+    match shutdown_signal().await {
         Ok(()) => {
-            println!("Received shutdown signal");
-            
-            // Cleanup
-            audit_system.shutdown().unwrap();
+            println!("Received shutdown signal, draining connections");
+
+            // Stop accepting, and let the calls in flight finish
+            if !connections.drain(drain_timeout).await {
+                eprintln!("⚠️ {} connections still open after {}s", connections.open_now(), drain_timeout.as_secs());
+            }
+
+            // Commit what clients wrote and is still buffered, then the audit events of it all
+            for shares_fs in &filesystems {
+                let failed = shares_fs.commit_all_writes().await;
+                if !failed.is_empty() {
+                    eprintln!("⚠️ Could not commit the writes of files {:?}", failed);
+                }
+            }
+            for audit in std::iter::once(&audit_system).chain(&unaudited) {
+                if !audit.flush(drain_timeout).await {
+                    eprintln!("⚠️ Audit events still queued after {}s", drain_timeout.as_secs());
+                }
+                audit.shutdown().unwrap();
+            }
             
-            // Abort both server tasks
-            nfs_handle.abort();
+            // Abort the server tasks
+            for nfs_handle in &nfs_handles {
+                nfs_handle.abort();
+            }
             #[cfg(feature = "metrics")]
             metrics_handle.abort();
             
             std::io::stdout().flush().unwrap();
         }
         Err(err) => {
-            eprintln!("Error handling ctrl-c: {}", err);
+            eprintln!("Error handling shutdown signals: {}", err);
             std::io::stderr().flush().unwrap();
         }
     }
@@ -0,0 +1,127 @@
+// Copies a namespace between backing stores, or backs it up to and restores it from an archive.
+//
+//   migrate --from rocksdb:../RocksDBs/graymamba --to redis
+//   migrate --from rocksdb:../RocksDBs/graymamba --to archive:zoo-backup.jsonl
+//   migrate --from archive:zoo-backup.jsonl --to sqlite:../SQLiteDBs/graymamba.db
+//
+// Re-running an interrupted migration resumes it; inodes already present are skipped.
+use std::sync::Arc;
+
+use config::{Config, File as ConfigFile};
+use graymamba::backingstore::data_store::DataStore;
+use graymamba::backingstore::migration::{
+    export_namespace, read_archive, ArchiveWriter, MigrationError, MigrationStats, StoreImporter,
+};
+use graymamba::backingstore::redis_data_store::RedisDataStore;
+use graymamba::backingstore::rocksdb_data_store::RocksDBDataStore;
+#[cfg(feature = "sqlite_store")]
+use graymamba::backingstore::sqlite_data_store::SqliteDataStore;
+use graymamba::sharesfs::namespace::Namespace;
+
+const USAGE: &str = "usage: migrate --from <endpoint> --to <endpoint> \
+[--community <name>] [--namespace <id>] [--to-community <name>] [--to-namespace <id>]
+endpoints: rocksdb:<path> | redis | sqlite:<path> | archive:<file>";
+
+enum Endpoint {
+    Store(Arc<dyn DataStore>),
+    Archive(String),
+}
+
+fn open_endpoint(spec: &str) -> Result<Endpoint, String> {
+    let (kind, location) = spec.split_once(':').unwrap_or((spec, ""));
+    match kind {
+        "rocksdb" if !location.is_empty() => RocksDBDataStore::new(location)
+            .map(|store| Endpoint::Store(Arc::new(store)))
+            .map_err(|e| format!("cannot open RocksDB at {}: {:?}", location, e)),
+        "redis" => RedisDataStore::new()
+            .map(|store| Endpoint::Store(Arc::new(store)))
+            .map_err(|e| format!("cannot connect to Redis: {}", e)),
+        #[cfg(feature = "sqlite_store")]
+        "sqlite" if !location.is_empty() => SqliteDataStore::new(location)
+            .map(|store| Endpoint::Store(Arc::new(store)))
+            .map_err(|e| format!("cannot open SQLite at {}: {:?}", location, e)),
+        "archive" if !location.is_empty() => Ok(Endpoint::Archive(location.to_string())),
+        _ => Err(format!("unsupported endpoint {:?}\n{}", spec, USAGE)),
+    }
+}
+
+async fn migrate(from: Endpoint, to: Endpoint, source: Namespace, destination: Namespace) -> Result<MigrationStats, MigrationError> {
+    match (from, to) {
+        (Endpoint::Store(from), Endpoint::Store(to)) => {
+            let mut importer = StoreImporter::new(to.as_ref(), destination);
+            export_namespace(from.as_ref(), &source, &mut importer).await?;
+            importer.finish()
+        }
+        (Endpoint::Store(from), Endpoint::Archive(path)) => {
+            let mut writer = ArchiveWriter::create(&path)?;
+            let stats = export_namespace(from.as_ref(), &source, &mut writer).await?;
+            writer.finish()?;
+            Ok(stats)
+        }
+        (Endpoint::Archive(path), Endpoint::Store(to)) => {
+            let mut importer = StoreImporter::new(to.as_ref(), destination);
+            read_archive(&path, &mut importer).await?;
+            importer.finish()
+        }
+        (Endpoint::Archive(_), Endpoint::Archive(_)) => {
+            Err(MigrationError::Format("copying an archive to an archive is a file copy".to_string()))
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let mut settings = Config::default();
+    settings.merge(ConfigFile::with_name("config/settings.toml")).ok();
+
+    let mut from = None;
+    let mut to = None;
+    let mut community = settings.get_str("storage.community").ok();
+    let mut namespace_id = settings.get_str("storage.namespace_id").ok();
+    let mut to_community = None;
+    let mut to_namespace_id = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let slot = match arg.as_str() {
+            "--from" => &mut from,
+            "--to" => &mut to,
+            "--community" => &mut community,
+            "--namespace" => &mut namespace_id,
+            "--to-community" => &mut to_community,
+            "--to-namespace" => &mut to_namespace_id,
+            _ => {
+                eprintln!("{}", USAGE);
+                std::process::exit(2);
+            }
+        };
+        *slot = args.next();
+    }
+
+    let (Some(from), Some(to), Some(community), Some(namespace_id)) = (from, to, community, namespace_id) else {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    };
+    let source = Namespace::new(&community, &namespace_id);
+    let destination = Namespace::new(
+        to_community.as_deref().unwrap_or(&community),
+        to_namespace_id.as_deref().unwrap_or(&namespace_id),
+    );
+
+    let endpoints = open_endpoint(&from).and_then(|from| Ok((from, open_endpoint(&to)?)));
+    let (from, to) = match endpoints {
+        Ok(endpoints) => endpoints,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            std::process::exit(2);
+        }
+    };
+
+    match migrate(from, to, source, destination).await {
+        Ok(stats) => println!("✅ Migration complete: {}", stats),
+        Err(e) => {
+            eprintln!("❌ Migration failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,191 @@
+// Manages the user registry of a store.
+//
+//   users --store rocksdb:../RocksDBs/graymamba add alice --uid 501 --exports /,/projects
+//   users --store rocksdb:../RocksDBs/graymamba add root --role superuser --uid 0
+//   users --store redis list
+//   users --store redis secret alice        prints a new HMAC signing key for alice's AUTH_SIGNED calls
+//   users --store redis keygen alice        prints a new Ed25519 private key for them, keeping the public key
+//
+// Changes take effect on the next mount; the server may keep running, except on RocksDB,
+// which only one process can open.
+use std::sync::Arc;
+
+use ed25519_dalek::SigningKey;
+use rand::rngs::OsRng;
+
+use graymamba::backingstore::data_store::DataStore;
+use graymamba::backingstore::redis_data_store::RedisDataStore;
+use graymamba::backingstore::rocksdb_data_store::RocksDBDataStore;
+#[cfg(feature = "sqlite_store")]
+use graymamba::backingstore::sqlite_data_store::SqliteDataStore;
+use graymamba::backingstore::user_registry::{self, Role, UserRecord};
+
+const USAGE: &str = "usage: users --store <store> <command>
+commands:
+  list
+  show <name>
+  add <name> [--role standard|superuser] [--uid <uid>] [--exports <path>,...]
+  set <name> [--role standard|superuser] [--uid <uid>|none] [--exports <path>,...|all]
+  enable <name> | disable <name>
+  secret <name>                              prints a new HMAC-SHA256 signing key
+  keygen <name>                              prints a new Ed25519 private key
+  key <name> <hex ed25519 public key>|none
+  remove <name>
+stores: rocksdb:<path> | redis | sqlite:<path>";
+
+fn open_store(spec: &str) -> Result<Arc<dyn DataStore>, String> {
+    let (kind, location) = spec.split_once(':').unwrap_or((spec, ""));
+    match kind {
+        "rocksdb" if !location.is_empty() => RocksDBDataStore::new(location)
+            .map(|store| Arc::new(store) as Arc<dyn DataStore>)
+            .map_err(|e| format!("cannot open RocksDB at {}: {:?}", location, e)),
+        "redis" => RedisDataStore::new()
+            .map(|store| Arc::new(store) as Arc<dyn DataStore>)
+            .map_err(|e| format!("cannot connect to Redis: {}", e)),
+        #[cfg(feature = "sqlite_store")]
+        "sqlite" if !location.is_empty() => SqliteDataStore::new(location)
+            .map(|store| Arc::new(store) as Arc<dyn DataStore>)
+            .map_err(|e| format!("cannot open SQLite at {}: {:?}", location, e)),
+        _ => Err(format!("unsupported store {:?}\n{}", spec, USAGE)),
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(2);
+}
+
+// Applies the --role, --uid and --exports options to a record
+fn apply_options(user: &mut UserRecord, options: &[String]) {
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let value = options.next().unwrap_or_else(|| fail(USAGE));
+        match option.as_str() {
+            "--role" => user.role = Role::parse(value).unwrap_or_else(|| fail(&format!("unknown role {:?}", value))),
+            "--uid" if value == "none" => user.uid = None,
+            "--uid" => user.uid = Some(value.parse().unwrap_or_else(|_| fail(&format!("{:?} is not a uid", value)))),
+            "--exports" if value == "all" => user.exports.clear(),
+            "--exports" => user.exports = value.split(',').filter(|path| !path.is_empty()).map(str::to_string).collect(),
+            _ => fail(USAGE),
+        }
+    }
+}
+
+fn describe(user: &UserRecord) -> serde_json::Value {
+    serde_json::json!({
+        "name": user.name,
+        "role": user.role.as_str(),
+        "enabled": user.enabled,
+        "uid": user.uid,
+        "signing_key": user.signing_key.is_some(),
+        "public_key": user.public_key,
+        "exports": if user.exports.is_empty() { vec!["all".to_string()] } else { user.exports.clone() },
+    })
+}
+
+async fn existing(store: &dyn DataStore, name: &str) -> UserRecord {
+    match user_registry::get_user(store, name).await {
+        Ok(Some(user)) => user,
+        Ok(None) => fail(&format!("no user {:?}", name)),
+        Err(e) => fail(&e.to_string()),
+    }
+}
+
+async fn save(store: &dyn DataStore, user: &UserRecord) {
+    // A uid identifies one user only, checked through the uid index, which registries written
+    // before it was kept lack until indexed
+    if let Some(uid) = user.uid {
+        user_registry::index_uids(store).await.unwrap_or_else(|e| fail(&e.to_string()));
+        match user_registry::find_by_uid(store, uid).await {
+            Ok(Some(other)) if other.name != user.name => fail(&format!("uid {} is already bound to {}", uid, other.name)),
+            Ok(_) => {}
+            Err(e) => fail(&e.to_string()),
+        }
+    }
+    user_registry::put_user(store, user).await.unwrap_or_else(|e| fail(&e.to_string()));
+}
+
+#[tokio::main]
+async fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() < 3 || args[0] != "--store" {
+        fail(USAGE);
+    }
+    let store = open_store(&args[1]).unwrap_or_else(|e| fail(&e));
+    let store = store.as_ref();
+    let rest = args.split_off(2);
+    let command = rest[0].as_str();
+    let name = rest.get(1).map(String::as_str);
+
+    match (command, name) {
+        ("list", None) => {
+            let names = user_registry::list_users(store).await.unwrap_or_else(|e| fail(&e.to_string()));
+            let mut users = Vec::new();
+            for name in names {
+                users.push(describe(&existing(store, &name).await));
+            }
+            println!("{}", serde_json::to_string_pretty(&users).expect("users serialize"));
+        }
+        ("show", Some(name)) => {
+            println!("{}", serde_json::to_string_pretty(&describe(&existing(store, name).await)).expect("user serializes"));
+        }
+        ("add", Some(name)) => {
+            if !user_registry::valid_name(name) {
+                fail(&format!("{:?} is not a valid user name", name));
+            }
+            if matches!(user_registry::get_user(store, name).await, Ok(Some(_))) {
+                fail(&format!("user {:?} already exists", name));
+            }
+            let mut user = UserRecord::new(name, Role::Standard);
+            apply_options(&mut user, &rest[2..]);
+            save(store, &user).await;
+            println!("Added {}", name);
+        }
+        ("set", Some(name)) => {
+            let mut user = existing(store, name).await;
+            apply_options(&mut user, &rest[2..]);
+            save(store, &user).await;
+            println!("Updated {}", name);
+        }
+        ("enable" | "disable", Some(name)) if rest.len() == 2 => {
+            let mut user = existing(store, name).await;
+            user.enabled = command == "enable";
+            save(store, &user).await;
+            println!("{} {}d", name, command);
+        }
+        ("secret", Some(name)) if rest.len() == 2 => {
+            let mut user = existing(store, name).await;
+            let signing_key = user.issue_signing_key();
+            save(store, &user).await;
+            // The signing key of the user's AUTH_SIGNED HMAC-SHA256 credentials, the secret they
+            // share with the server
+            println!("{}", hex::encode(signing_key));
+        }
+        ("keygen", Some(name)) if rest.len() == 2 => {
+            let mut user = existing(store, name).await;
+            let signing_key = SigningKey::generate(&mut OsRng);
+            user.public_key = Some(hex::encode(signing_key.verifying_key().to_bytes()));
+            save(store, &user).await;
+            // The private key of the user's AUTH_SIGNED Ed25519 credentials; only the public key is kept
+            println!("{}", hex::encode(signing_key.to_bytes()));
+        }
+        ("key", Some(name)) if rest.len() == 3 => {
+            let mut user = existing(store, name).await;
+            user.public_key = match rest[2].as_str() {
+                "none" => None,
+                key if hex::decode(key).map(|key| key.len() == 32).unwrap_or(false) => Some(key.to_lowercase()),
+                key => fail(&format!("{:?} is not a hex Ed25519 public key", key)),
+            };
+            save(store, &user).await;
+            println!("Updated the public key of {}", name);
+        }
+        ("remove", Some(name)) if rest.len() == 2 => {
+            match user_registry::remove_user(store, name).await {
+                Ok(true) => println!("Removed {}", name),
+                Ok(false) => fail(&format!("no user {:?}", name)),
+                Err(e) => fail(&e.to_string()),
+            }
+        }
+        _ => fail(USAGE),
+    }
+}
@@ -10,7 +10,7 @@ use crate::kernel::api::nfs::fattr3;
 pub struct FileMetadata {
 
     // Common metadata fields
-    pub ftype: u8,      // 0 for directory, 1 for file, 2 for Sybolic link
+    pub ftype: u8,      // 0 for directory, 1 for file, 2 for Sybolic link, 3 to 6 for the special files MKNOD makes
     pub size: u64,
     pub permissions: u32,
     pub access_time_secs: u32,
@@ -20,10 +20,38 @@ pub struct FileMetadata {
     pub modification_time_secs: u32,
     pub modification_time_nsecs: u32,
     pub fileid: fileid3,
+    // The major and minor numbers of a block or character device
+    pub rdev: specdata3,
 
 }
 
 impl FileMetadata {
+    // The file type an ftype code of the metadata stands for
+    pub fn ftype_of(code: u8) -> Option<ftype3> {
+        match code {
+            0 => Some(ftype3::NF3DIR),
+            1 => Some(ftype3::NF3REG),
+            2 => Some(ftype3::NF3LNK),
+            3 => Some(ftype3::NF3BLK),
+            4 => Some(ftype3::NF3CHR),
+            5 => Some(ftype3::NF3SOCK),
+            6 => Some(ftype3::NF3FIFO),
+            _ => None,
+        }
+    }
+
+    pub fn ftype_code(ftype: ftype3) -> u8 {
+        match ftype {
+            ftype3::NF3DIR => 0,
+            ftype3::NF3REG => 1,
+            ftype3::NF3LNK => 2,
+            ftype3::NF3BLK => 3,
+            ftype3::NF3CHR => 4,
+            ftype3::NF3SOCK => 5,
+            ftype3::NF3FIFO => 6,
+        }
+    }
+
     async fn mode_unmask(mode: u32) -> u32 {
         let mode = mode | 0x80;
         let permissions = std::fs::Permissions::from_mode(mode);
@@ -32,12 +60,7 @@ impl FileMetadata {
     pub async fn metadata_to_fattr3(fid: fileid3, metadata: &FileMetadata) -> Result<fattr3, nfsstat3> {
         let size = metadata.size;
         let file_mode = Self::mode_unmask(metadata.permissions);
-        let ftype = match metadata.ftype {
-            0 => ftype3::NF3DIR,
-            1 => ftype3::NF3REG,
-            2 => ftype3::NF3LNK,
-            _ => return Err(nfsstat3::NFS3ERR_INVAL),
-        };
+        let ftype = Self::ftype_of(metadata.ftype).ok_or(nfsstat3::NFS3ERR_INVAL)?;
         
         Ok(fattr3 {
             ftype,
@@ -49,7 +72,7 @@ impl FileMetadata {
             used: size,
             fsid: 0,
             fileid: fid,
-            rdev: specdata3::default(),
+            rdev: metadata.rdev,
             atime: nfstime3 {
                 seconds: metadata.access_time_secs,
                 nseconds: metadata.access_time_nsecs,
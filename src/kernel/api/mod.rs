@@ -2,4 +2,10 @@ pub mod mount;
 
 pub mod nfs;
 
+pub mod nfs4;
+
+pub mod nlm;
+
+pub mod nsm;
+
 pub mod portmap;
\ No newline at end of file
@@ -4,11 +4,7 @@
 #![allow(non_camel_case_types)]
 
 use crate::kernel::protocol::xdr::*;
-use byteorder::{ReadBytesExt, WriteBytesExt};
 use num_derive::{FromPrimitive, ToPrimitive};
-use num_traits::cast::FromPrimitive;
-use std::io::{Read, Write};
-pub use crate::kernel::handlers::mount_handlers::mountres3_ok;
 // Transcribed from RFC 1057 Appendix A
 
 pub const PROGRAM: u32 = 100005;
@@ -23,7 +19,7 @@ pub type dirpath = Vec<u8>;
 pub type name = Vec<u8>;
 
 #[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug, FromPrimitive, ToPrimitive)]
+#[derive(Copy, Clone, Debug, FromPrimitive, ToPrimitive, XdrSerialize, XdrDeserialize)]
 #[repr(u32)]
 pub enum mountstat3 {
     MNT3_OK = 0,                 /* no error */
@@ -37,4 +33,11 @@ pub enum mountstat3 {
     MNT3ERR_NOTSUPP = 10004,     /* Operation not supported */
     MNT3ERR_SERVERFAULT = 10006, /* A failure on the server */
 }
-XDREnumSerde!(mountstat3);
+
+#[allow(non_camel_case_types)]
+#[derive(Clone, Debug, XdrSerialize, XdrDeserialize)]
+pub struct mountres3_ok {
+    #[xdr(max = FHSIZE3)]
+    pub fhandle: fhandle3,
+    pub auth_flavors: Vec<u32>,
+}
@@ -4,12 +4,9 @@
 #![allow(non_camel_case_types)]
 
 use crate::kernel::protocol::xdr::*;
-use byteorder::{ReadBytesExt, WriteBytesExt};
 use filetime;
 use num_derive::{FromPrimitive, ToPrimitive};
-use num_traits::cast::FromPrimitive;
 use std::fmt;
-use std::io::{Read, Write};
 
 // Transcribed from RFC 1813.
 
@@ -38,7 +35,7 @@ pub const NFS3_WRITEVERFSIZE: u32 = 8;
 
 // Section 2.5 Basic Data Types
 #[allow(non_camel_case_types)]
-#[derive(Default, Clone)]
+#[derive(Default, Clone, XdrSerialize, XdrDeserialize)]
 pub struct nfsstring(pub Vec<u8>);
 impl nfsstring {
     pub fn len(&self) -> usize {
@@ -97,7 +94,7 @@ pub type mode3 = u32;
 pub type count3 = u32;
 
 #[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug, FromPrimitive, ToPrimitive)]
+#[derive(Copy, Clone, Debug, FromPrimitive, ToPrimitive, XdrSerialize, XdrDeserialize)]
 #[repr(u32)]
 pub enum nfsstat3 {
     /// Indicates the call completed successfully.
@@ -195,11 +192,9 @@ pub enum nfsstat3 {
     NFS3ERR_JUKEBOX = 10008,
 }
 
-XDREnumSerde!(nfsstat3);
-
 /// File Type
 #[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug, Default, FromPrimitive, ToPrimitive)]
+#[derive(Copy, Clone, Debug, Default, FromPrimitive, ToPrimitive, XdrSerialize, XdrDeserialize)]
 #[repr(u32)]
 pub enum ftype3 {
     /// Regular File
@@ -218,24 +213,22 @@ pub enum ftype3 {
     /// Named Pipe
     NF3FIFO = 7,
 }
-XDREnumSerde!(ftype3);
 /// Device Number information. Ex: Major / Minor device
 #[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug, Default)]
+#[derive(Copy, Clone, Debug, Default, XdrSerialize, XdrDeserialize)]
 #[repr(C)]
 pub struct specdata3 {
     pub specdata1: u32,
     pub specdata2: u32,
 }
-XDRStruct!(specdata3, specdata1, specdata2);
 
 /// File Handle information
 #[allow(non_camel_case_types)]
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, XdrSerialize, XdrDeserialize)]
 pub struct nfs_fh3 {
+    #[xdr(max = NFS3_FHSIZE)]
     pub data: Vec<u8>,
 }
-XDRStruct!(nfs_fh3, data);
 #[allow(clippy::derivable_impls)]
 impl Default for nfs_fh3 {
     fn default() -> nfs_fh3 {
@@ -244,13 +237,12 @@ impl Default for nfs_fh3 {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug, Default)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, XdrSerialize, XdrDeserialize)]
 #[repr(C)]
 pub struct nfstime3 {
     pub seconds: u32,
     pub nseconds: u32,
 }
-XDRStruct!(nfstime3, seconds, nseconds);
 
 impl From<nfstime3> for filetime::FileTime {
     fn from(time: nfstime3) -> Self {
@@ -259,7 +251,7 @@ impl From<nfstime3> for filetime::FileTime {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug, Default)]
+#[derive(Copy, Clone, Debug, Default, XdrSerialize, XdrDeserialize)]
 pub struct fattr3 {
     pub ftype: ftype3,
     pub mode: mode3,
@@ -275,9 +267,6 @@ pub struct fattr3 {
     pub mtime: nfstime3,
     pub ctime: nfstime3,
 }
-XDRStruct!(
-    fattr3, ftype, mode, nlink, uid, gid, size, used, rdev, fsid, fileid, atime, mtime, ctime
-);
 
 // Section 3.3.19. Procedure 19: FSINFO - Get static file system Information
 // The following constants are used in fsinfo to construct the bitmask 'properties',
@@ -303,7 +292,7 @@ pub const FSF_HOMOGENEOUS: u32 = 0x0008;
 pub const FSF_CANSETTIME: u32 = 0x0010;
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Default)]
+#[derive(Debug, Default, XdrSerialize, XdrDeserialize)]
 pub struct fsinfo3 {
     pub obj_attributes: post_op_attr,
     pub rtmax: u32,
@@ -317,70 +306,63 @@ pub struct fsinfo3 {
     pub time_delta: nfstime3,
     pub properties: u32,
 }
-XDRStruct!(
-    fsinfo3,
-    obj_attributes,
-    rtmax,
-    rtpref,
-    rtmult,
-    wtmax,
-    wtpref,
-    wtmult,
-    dtpref,
-    maxfilesize,
-    time_delta,
-    properties
-);
 
 #[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug, Default)]
+#[derive(Copy, Clone, Debug, Default, XdrSerialize, XdrDeserialize)]
 pub struct wcc_attr {
     pub size: size3,
     pub mtime: nfstime3,
     pub ctime: nfstime3,
 }
-XDRStruct!(wcc_attr, size, mtime, ctime);
 
 #[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug, Default)]
+#[derive(Copy, Clone, Debug, Default, XdrSerialize, XdrDeserialize)]
 #[repr(u32)]
 pub enum pre_op_attr {
     #[default]
     Void,
     attributes(wcc_attr),
 }
-XDRBoolUnion!(pre_op_attr, attributes, wcc_attr);
 
 #[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug, Default)]
+#[derive(Copy, Clone, Debug, Default, XdrSerialize, XdrDeserialize)]
 #[repr(u32)]
 pub enum post_op_attr {
     #[default]
     Void,
     attributes(fattr3),
 }
-XDRBoolUnion!(post_op_attr, attributes, fattr3);
 
 #[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug, Default)]
+#[derive(Copy, Clone, Debug, Default, XdrSerialize, XdrDeserialize)]
 pub struct wcc_data {
     pub before: pre_op_attr,
     pub after: post_op_attr,
 }
-XDRStruct!(wcc_data, before, after);
+
+impl wcc_data {
+    // From the attributes an object had before a change and has after it, each when known
+    pub fn new(before: Option<fattr3>, after: Option<fattr3>) -> wcc_data {
+        wcc_data {
+            before: before.map_or(pre_op_attr::Void, |attr| {
+                pre_op_attr::attributes(wcc_attr { size: attr.size, mtime: attr.mtime, ctime: attr.ctime })
+            }),
+            after: after.map_or(post_op_attr::Void, post_op_attr::attributes),
+        }
+    }
+}
 
 #[allow(non_camel_case_types)]
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, XdrSerialize, XdrDeserialize)]
 #[repr(u32)]
 pub enum post_op_fh3 {
     #[default]
     Void,
     handle(nfs_fh3),
 }
-XDRBoolUnion!(post_op_fh3, handle, nfs_fh3);
 
 #[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug, FromPrimitive, ToPrimitive)]
+#[derive(Copy, Clone, Debug, FromPrimitive, ToPrimitive, XdrSerialize, XdrDeserialize)]
 #[repr(u32)]
 /// This enum is only used as a discriminant for set_atime / set_mtime
 /// and should not be used directly.
@@ -389,46 +371,41 @@ pub enum _time_how {
     SET_TO_SERVER_TIME = 1,
     SET_TO_CLIENT_TIME = 2,
 }
-XDREnumSerde!(_time_how);
 
 #[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, XdrSerialize, XdrDeserialize)]
 #[repr(u32)]
 pub enum set_mode3 {
     Void,
     mode(mode3),
 }
-XDRBoolUnion!(set_mode3, mode, mode3);
 
 #[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, XdrSerialize, XdrDeserialize)]
 #[repr(u32)]
 pub enum set_uid3 {
     Void,
     uid(uid3),
 }
-XDRBoolUnion!(set_uid3, uid, uid3);
 
 #[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, XdrSerialize, XdrDeserialize)]
 #[repr(u32)]
 pub enum set_gid3 {
     Void,
     gid(gid3),
 }
-XDRBoolUnion!(set_gid3, gid, gid3);
 
 #[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, XdrSerialize, XdrDeserialize)]
 #[repr(u32)]
 pub enum set_size3 {
     Void,
     size(size3),
 }
-XDRBoolUnion!(set_size3, size, size3);
 
 #[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, XdrSerialize, XdrDeserialize)]
 #[repr(u32)]
 /// discriminant is time_how
 pub enum set_atime {
@@ -436,45 +413,9 @@ pub enum set_atime {
     SET_TO_SERVER_TIME,
     SET_TO_CLIENT_TIME(nfstime3),
 }
-impl XDR for set_atime {
-    fn serialize<R: Write>(&self, dest: &mut R) -> std::io::Result<()> {
-        match self {
-            set_atime::DONT_CHANGE => {
-                0_u32.serialize(dest)?;
-            }
-            set_atime::SET_TO_SERVER_TIME => {
-                1_u32.serialize(dest)?;
-            }
-            set_atime::SET_TO_CLIENT_TIME(v) => {
-                2_u32.serialize(dest)?;
-                v.serialize(dest)?;
-            }
-        }
-        Ok(())
-    }
-    fn deserialize<R: Read>(&mut self, src: &mut R) -> std::io::Result<()> {
-        let mut c: u32 = 0;
-        c.deserialize(src)?;
-        if c == 0 {
-            *self = set_atime::DONT_CHANGE;
-        } else if c == 1 {
-            *self = set_atime::SET_TO_SERVER_TIME;
-        } else if c == 2 {
-            let mut r = nfstime3::default();
-            r.deserialize(src)?;
-            *self = set_atime::SET_TO_CLIENT_TIME(r);
-        } else {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Invalid value for set_atime",
-            ));
-        }
-        Ok(())
-    }
-}
 
 #[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, XdrSerialize, XdrDeserialize)]
 #[repr(u32)]
 /// discriminant is time_how
 pub enum set_mtime {
@@ -483,45 +424,8 @@ pub enum set_mtime {
     SET_TO_CLIENT_TIME(nfstime3),
 }
 
-impl XDR for set_mtime {
-    fn serialize<R: Write>(&self, dest: &mut R) -> std::io::Result<()> {
-        match self {
-            set_mtime::DONT_CHANGE => {
-                0_u32.serialize(dest)?;
-            }
-            set_mtime::SET_TO_SERVER_TIME => {
-                1_u32.serialize(dest)?;
-            }
-            set_mtime::SET_TO_CLIENT_TIME(v) => {
-                2_u32.serialize(dest)?;
-                v.serialize(dest)?;
-            }
-        }
-        Ok(())
-    }
-    fn deserialize<R: Read>(&mut self, src: &mut R) -> std::io::Result<()> {
-        let mut c: u32 = 0;
-        c.deserialize(src)?;
-        if c == 0 {
-            *self = set_mtime::DONT_CHANGE;
-        } else if c == 1 {
-            *self = set_mtime::SET_TO_SERVER_TIME;
-        } else if c == 2 {
-            let mut r = nfstime3::default();
-            r.deserialize(src)?;
-            *self = set_mtime::SET_TO_CLIENT_TIME(r);
-        } else {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Invalid value for set_mtime",
-            ));
-        }
-        Ok(())
-    }
-}
-
 #[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, XdrSerialize, XdrDeserialize)]
 pub struct sattr3 {
     pub mode: set_mode3,
     pub uid: set_uid3,
@@ -530,7 +434,6 @@ pub struct sattr3 {
     pub atime: set_atime,
     pub mtime: set_mtime,
 }
-XDRStruct!(sattr3, mode, uid, gid, size, atime, mtime);
 impl Default for sattr3 {
     fn default() -> sattr3 {
         sattr3 {
@@ -545,20 +448,25 @@ impl Default for sattr3 {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, XdrSerialize, XdrDeserialize)]
 pub struct diropargs3 {
     pub dir: nfs_fh3,
     pub name: filename3,
 }
-XDRStruct!(diropargs3, dir, name);
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Default)]
+#[derive(Debug, Default, XdrSerialize, XdrDeserialize)]
 pub struct symlinkdata3 {
     pub symlink_attributes: sattr3,
     pub symlink_data: nfspath3,
 }
-XDRStruct!(symlinkdata3, symlink_attributes, symlink_data);
+
+#[allow(non_camel_case_types)]
+#[derive(Clone, Debug, Default, XdrSerialize, XdrDeserialize)]
+pub struct devicedata3 {
+    pub dev_attributes: sattr3,
+    pub spec: specdata3,
+}
 
 /// We define the root handle here
 pub fn get_root_mount_handle() -> Vec<u8> {
@@ -0,0 +1,548 @@
+// this is just a complete enumeration of everything in the RFC
+#![allow(dead_code)]
+// And its nice to keep the original RFC names and case
+#![allow(non_camel_case_types)]
+
+use crate::kernel::protocol::xdr::*;
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use num_derive::{FromPrimitive, ToPrimitive};
+use num_traits::cast::FromPrimitive;
+use std::io::{Read, Write};
+
+// Transcribed from RFC 5661 (NFS version 4 minor version 1), section 3 and the XDR of its
+// companion RFC 5662. The unions of operation arguments and results are decoded where they
+// are handled.
+
+/// NFS version 4 is the same program as version 3
+pub const PROGRAM: u32 = 100003;
+pub const VERSION: u32 = 4;
+pub const MINOR_VERSION: u32 = 1;
+
+pub const NFS4_FHSIZE: usize = 128;
+pub const NFS4_VERIFIER_SIZE: usize = 8;
+pub const NFS4_OTHER_SIZE: usize = 12;
+pub const NFS4_SESSIONID_SIZE: usize = 16;
+pub const NFS4_UINT32_MAX: u32 = u32::MAX;
+pub const NFS4_UINT64_MAX: u64 = u64::MAX;
+
+pub type verifier4 = [u8; NFS4_VERIFIER_SIZE];
+pub type sessionid4 = [u8; NFS4_SESSIONID_SIZE];
+pub type clientid4 = u64;
+pub type sequenceid4 = u32;
+pub type slotid4 = u32;
+pub type seqid4 = u32;
+pub type bitmap4 = Vec<u32>;
+pub type nfs_fh4 = Vec<u8>;
+pub type component4 = Vec<u8>;
+pub type utf8str_cs = Vec<u8>;
+pub type changeid4 = u64;
+pub type count4 = u32;
+pub type length4 = u64;
+pub type offset4 = u64;
+pub type nfs_cookie4 = u64;
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default, FromPrimitive, ToPrimitive)]
+#[repr(u32)]
+pub enum nfsstat4 {
+    #[default]
+    NFS4_OK = 0,
+    NFS4ERR_PERM = 1,
+    NFS4ERR_NOENT = 2,
+    NFS4ERR_IO = 5,
+    NFS4ERR_NXIO = 6,
+    NFS4ERR_ACCESS = 13,
+    NFS4ERR_EXIST = 17,
+    NFS4ERR_XDEV = 18,
+    NFS4ERR_NOTDIR = 20,
+    NFS4ERR_ISDIR = 21,
+    NFS4ERR_INVAL = 22,
+    NFS4ERR_FBIG = 27,
+    NFS4ERR_NOSPC = 28,
+    NFS4ERR_ROFS = 30,
+    NFS4ERR_MLINK = 31,
+    NFS4ERR_NAMETOOLONG = 63,
+    NFS4ERR_NOTEMPTY = 66,
+    NFS4ERR_DQUOT = 69,
+    NFS4ERR_STALE = 70,
+    NFS4ERR_BADHANDLE = 10001,
+    NFS4ERR_BAD_COOKIE = 10003,
+    NFS4ERR_NOTSUPP = 10004,
+    NFS4ERR_TOOSMALL = 10005,
+    NFS4ERR_SERVERFAULT = 10006,
+    NFS4ERR_BADTYPE = 10007,
+    NFS4ERR_DELAY = 10008,
+    NFS4ERR_SAME = 10009,
+    NFS4ERR_DENIED = 10010,
+    NFS4ERR_EXPIRED = 10011,
+    NFS4ERR_LOCKED = 10012,
+    NFS4ERR_GRACE = 10013,
+    NFS4ERR_FHEXPIRED = 10014,
+    NFS4ERR_SHARE_DENIED = 10015,
+    NFS4ERR_WRONGSEC = 10016,
+    NFS4ERR_CLID_INUSE = 10017,
+    NFS4ERR_RESOURCE = 10018,
+    NFS4ERR_MOVED = 10019,
+    NFS4ERR_NOFILEHANDLE = 10020,
+    NFS4ERR_MINOR_VERS_MISMATCH = 10021,
+    NFS4ERR_STALE_CLIENTID = 10022,
+    NFS4ERR_STALE_STATEID = 10023,
+    NFS4ERR_OLD_STATEID = 10024,
+    NFS4ERR_BAD_STATEID = 10025,
+    NFS4ERR_BAD_SEQID = 10026,
+    NFS4ERR_NOT_SAME = 10027,
+    NFS4ERR_LOCK_RANGE = 10028,
+    NFS4ERR_SYMLINK = 10029,
+    NFS4ERR_RESTOREFH = 10030,
+    NFS4ERR_LEASE_MOVED = 10031,
+    NFS4ERR_ATTRNOTSUPP = 10032,
+    NFS4ERR_NO_GRACE = 10033,
+    NFS4ERR_RECLAIM_BAD = 10034,
+    NFS4ERR_RECLAIM_CONFLICT = 10035,
+    NFS4ERR_BADXDR = 10036,
+    NFS4ERR_LOCKS_HELD = 10037,
+    NFS4ERR_OPENMODE = 10038,
+    NFS4ERR_BADOWNER = 10039,
+    NFS4ERR_BADCHAR = 10040,
+    NFS4ERR_BADNAME = 10041,
+    NFS4ERR_BAD_RANGE = 10042,
+    NFS4ERR_LOCK_NOTSUPP = 10043,
+    NFS4ERR_OP_ILLEGAL = 10044,
+    NFS4ERR_DEADLOCK = 10045,
+    NFS4ERR_FILE_OPEN = 10046,
+    NFS4ERR_ADMIN_REVOKED = 10047,
+    NFS4ERR_CB_PATH_DOWN = 10048,
+    NFS4ERR_BADIOMODE = 10049,
+    NFS4ERR_BADLAYOUT = 10050,
+    NFS4ERR_BAD_SESSION_DIGEST = 10051,
+    NFS4ERR_BADSESSION = 10052,
+    NFS4ERR_BADSLOT = 10053,
+    NFS4ERR_COMPLETE_ALREADY = 10054,
+    NFS4ERR_CONN_NOT_BOUND_TO_SESSION = 10055,
+    NFS4ERR_DELEG_ALREADY_WANTED = 10056,
+    NFS4ERR_BACK_CHAN_BUSY = 10057,
+    NFS4ERR_LAYOUTTRYLATER = 10058,
+    NFS4ERR_LAYOUTUNAVAILABLE = 10059,
+    NFS4ERR_NOMATCHING_LAYOUT = 10060,
+    NFS4ERR_RECALLCONFLICT = 10061,
+    NFS4ERR_UNKNOWN_LAYOUTTYPE = 10062,
+    NFS4ERR_SEQ_MISORDERED = 10063,
+    NFS4ERR_SEQUENCE_POS = 10064,
+    NFS4ERR_REQ_TOO_BIG = 10065,
+    NFS4ERR_REP_TOO_BIG = 10066,
+    NFS4ERR_REP_TOO_BIG_TO_CACHE = 10067,
+    NFS4ERR_RETRY_UNCACHED_REP = 10068,
+    NFS4ERR_UNSAFE_COMPOUND = 10069,
+    NFS4ERR_TOO_MANY_OPS = 10070,
+    NFS4ERR_OP_NOT_IN_SESSION = 10071,
+    NFS4ERR_HASH_ALG_UNSUPP = 10072,
+    NFS4ERR_CLIENTID_BUSY = 10074,
+    NFS4ERR_PNFS_IO_HOLE = 10075,
+    NFS4ERR_SEQ_FALSE_RETRY = 10076,
+    NFS4ERR_BAD_HIGH_SLOT = 10077,
+    NFS4ERR_DEADSESSION = 10078,
+    NFS4ERR_ENCR_ALG_UNSUPP = 10079,
+    NFS4ERR_PNFS_NO_LAYOUT = 10080,
+    NFS4ERR_NOT_ONLY_OP = 10081,
+    NFS4ERR_WRONG_CRED = 10082,
+    NFS4ERR_WRONG_TYPE = 10083,
+    NFS4ERR_DIRDELEG_UNAVAIL = 10084,
+    NFS4ERR_REJECT_DELEG = 10085,
+    NFS4ERR_RETURNCONFLICT = 10086,
+    NFS4ERR_DELEG_REVOKED = 10087,
+}
+XDREnumSerde!(nfsstat4);
+
+#[allow(non_camel_case_types)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, ToPrimitive)]
+#[repr(u32)]
+pub enum nfs_opnum4 {
+    OP_ACCESS = 3,
+    OP_CLOSE = 4,
+    OP_COMMIT = 5,
+    OP_CREATE = 6,
+    OP_DELEGPURGE = 7,
+    OP_DELEGRETURN = 8,
+    OP_GETATTR = 9,
+    OP_GETFH = 10,
+    OP_LINK = 11,
+    OP_LOCK = 12,
+    OP_LOCKT = 13,
+    OP_LOCKU = 14,
+    OP_LOOKUP = 15,
+    OP_LOOKUPP = 16,
+    OP_NVERIFY = 17,
+    OP_OPEN = 18,
+    OP_OPENATTR = 19,
+    OP_OPEN_CONFIRM = 20,
+    OP_OPEN_DOWNGRADE = 21,
+    OP_PUTFH = 22,
+    OP_PUTPUBFH = 23,
+    OP_PUTROOTFH = 24,
+    OP_READ = 25,
+    OP_READDIR = 26,
+    OP_READLINK = 27,
+    OP_REMOVE = 28,
+    OP_RENAME = 29,
+    OP_RENEW = 30,
+    OP_RESTOREFH = 31,
+    OP_SAVEFH = 32,
+    OP_SECINFO = 33,
+    OP_SETATTR = 34,
+    OP_SETCLIENTID = 35,
+    OP_SETCLIENTID_CONFIRM = 36,
+    OP_VERIFY = 37,
+    OP_WRITE = 38,
+    OP_RELEASE_LOCKOWNER = 39,
+    OP_BACKCHANNEL_CTL = 40,
+    OP_BIND_CONN_TO_SESSION = 41,
+    OP_EXCHANGE_ID = 42,
+    OP_CREATE_SESSION = 43,
+    OP_DESTROY_SESSION = 44,
+    OP_FREE_STATEID = 45,
+    OP_GET_DIR_DELEGATION = 46,
+    OP_GETDEVICEINFO = 47,
+    OP_GETDEVICELIST = 48,
+    OP_LAYOUTCOMMIT = 49,
+    OP_LAYOUTGET = 50,
+    OP_LAYOUTRETURN = 51,
+    OP_SECINFO_NO_NAME = 52,
+    OP_SEQUENCE = 53,
+    OP_SET_SSV = 54,
+    OP_TEST_STATEID = 55,
+    OP_WANT_DELEGATION = 56,
+    OP_DESTROY_CLIENTID = 57,
+    OP_RECLAIM_COMPLETE = 58,
+    OP_ILLEGAL = 10044,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default, FromPrimitive, ToPrimitive)]
+#[repr(u32)]
+pub enum nfs_ftype4 {
+    #[default]
+    NF4REG = 1,
+    NF4DIR = 2,
+    NF4BLK = 3,
+    NF4CHR = 4,
+    NF4LNK = 5,
+    NF4SOCK = 6,
+    NF4FIFO = 7,
+    NF4ATTRDIR = 8,
+    NF4NAMEDATTR = 9,
+}
+XDREnumSerde!(nfs_ftype4);
+
+// Section 3.3.1
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct nfstime4 {
+    pub seconds: i64,
+    pub nseconds: u32,
+}
+XDRStruct!(nfstime4, seconds, nseconds);
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct fsid4 {
+    pub major: u64,
+    pub minor: u64,
+}
+XDRStruct!(fsid4, major, minor);
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct specdata4 {
+    pub specdata1: u32,
+    pub specdata2: u32,
+}
+XDRStruct!(specdata4, specdata1, specdata2);
+
+/// The attributes of a bitmap, encoded in bitmap order
+#[derive(Clone, Debug, Default)]
+pub struct fattr4 {
+    pub attrmask: bitmap4,
+    pub attr_vals: Vec<u8>,
+}
+XDRStruct!(fattr4, attrmask, attr_vals);
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct change_info4 {
+    pub atomic: bool,
+    pub before: changeid4,
+    pub after: changeid4,
+}
+XDRStruct!(change_info4, atomic, before, after);
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct stateid4 {
+    pub seqid: u32,
+    pub other: [u8; NFS4_OTHER_SIZE],
+}
+XDRStruct!(stateid4, seqid, other);
+
+impl stateid4 {
+    /// The anonymous stateid, of reads and writes without an open
+    pub fn is_anonymous(&self) -> bool {
+        self.seqid == 0 && self.other == [0; NFS4_OTHER_SIZE]
+    }
+    /// The READ bypass stateid
+    pub fn is_bypass(&self) -> bool {
+        self.seqid == u32::MAX && self.other == [0xff; NFS4_OTHER_SIZE]
+    }
+    /// The current stateid, the one the previous operation of the compound produced
+    pub fn is_current(&self) -> bool {
+        self.seqid == 1 && self.other == [0; NFS4_OTHER_SIZE]
+    }
+    /// What CLOSE returns, since the stateid it closed is no more
+    pub fn invalid() -> stateid4 {
+        stateid4 { seqid: NFS4_UINT32_MAX, other: [0; NFS4_OTHER_SIZE] }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct client_owner4 {
+    pub co_verifier: verifier4,
+    pub co_ownerid: Vec<u8>,
+}
+XDRStruct!(client_owner4, co_verifier, co_ownerid);
+
+#[derive(Clone, Debug, Default)]
+pub struct server_owner4 {
+    pub so_minor_id: u64,
+    pub so_major_id: Vec<u8>,
+}
+XDRStruct!(server_owner4, so_minor_id, so_major_id);
+
+#[derive(Clone, Debug, Default)]
+pub struct nfs_impl_id4 {
+    pub nii_domain: utf8str_cs,
+    pub nii_name: utf8str_cs,
+    pub nii_date: nfstime4,
+}
+XDRStruct!(nfs_impl_id4, nii_domain, nii_name, nii_date);
+
+/// The owner of opens and locks: a client and the name it gives a process or file description
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct state_owner4 {
+    pub clientid: clientid4,
+    pub owner: Vec<u8>,
+}
+XDRStruct!(state_owner4, clientid, owner);
+pub type open_owner4 = state_owner4;
+pub type lock_owner4 = state_owner4;
+
+// Section 18.36
+#[derive(Clone, Debug, Default)]
+pub struct channel_attrs4 {
+    pub ca_headerpadsize: count4,
+    pub ca_maxrequestsize: count4,
+    pub ca_maxresponsesize: count4,
+    pub ca_maxresponsesize_cached: count4,
+    pub ca_maxoperations: count4,
+    pub ca_maxrequests: count4,
+    // At most one, for RDMA
+    pub ca_rdma_ird: Vec<u32>,
+}
+XDRStruct!(
+    channel_attrs4,
+    ca_headerpadsize,
+    ca_maxrequestsize,
+    ca_maxresponsesize,
+    ca_maxresponsesize_cached,
+    ca_maxoperations,
+    ca_maxrequests,
+    ca_rdma_ird
+);
+
+#[derive(Clone, Debug, Default)]
+pub struct SEQUENCE4args {
+    pub sa_sessionid: sessionid4,
+    pub sa_sequenceid: sequenceid4,
+    pub sa_slotid: slotid4,
+    pub sa_highest_slotid: slotid4,
+    pub sa_cachethis: bool,
+}
+XDRStruct!(SEQUENCE4args, sa_sessionid, sa_sequenceid, sa_slotid, sa_highest_slotid, sa_cachethis);
+
+#[derive(Clone, Debug, Default)]
+pub struct SEQUENCE4resok {
+    pub sr_sessionid: sessionid4,
+    pub sr_sequenceid: sequenceid4,
+    pub sr_slotid: slotid4,
+    pub sr_highest_slotid: slotid4,
+    pub sr_target_highest_slotid: slotid4,
+    pub sr_status_flags: u32,
+}
+XDRStruct!(
+    SEQUENCE4resok,
+    sr_sessionid,
+    sr_sequenceid,
+    sr_slotid,
+    sr_highest_slotid,
+    sr_target_highest_slotid,
+    sr_status_flags
+);
+
+#[derive(Clone, Debug, Default)]
+pub struct READ4args {
+    pub stateid: stateid4,
+    pub offset: offset4,
+    pub count: count4,
+}
+XDRStruct!(READ4args, stateid, offset, count);
+
+#[derive(Clone, Debug, Default)]
+pub struct WRITE4args {
+    pub stateid: stateid4,
+    pub offset: offset4,
+    pub stable: u32,
+    pub data: Vec<u8>,
+}
+XDRStruct!(WRITE4args, stateid, offset, stable, data);
+
+#[derive(Clone, Debug, Default)]
+pub struct READDIR4args {
+    pub cookie: nfs_cookie4,
+    pub cookieverf: verifier4,
+    pub dircount: count4,
+    pub maxcount: count4,
+    pub attr_request: bitmap4,
+}
+XDRStruct!(READDIR4args, cookie, cookieverf, dircount, maxcount, attr_request);
+
+#[derive(Clone, Debug, Default)]
+pub struct LOCK4denied {
+    pub offset: offset4,
+    pub length: length4,
+    pub locktype: u32,
+    pub owner: lock_owner4,
+}
+XDRStruct!(LOCK4denied, offset, length, locktype, owner);
+
+// Section 5.6: attribute numbers, in the order they are encoded
+pub const FATTR4_SUPPORTED_ATTRS: u32 = 0;
+pub const FATTR4_TYPE: u32 = 1;
+pub const FATTR4_FH_EXPIRE_TYPE: u32 = 2;
+pub const FATTR4_CHANGE: u32 = 3;
+pub const FATTR4_SIZE: u32 = 4;
+pub const FATTR4_LINK_SUPPORT: u32 = 5;
+pub const FATTR4_SYMLINK_SUPPORT: u32 = 6;
+pub const FATTR4_NAMED_ATTR: u32 = 7;
+pub const FATTR4_FSID: u32 = 8;
+pub const FATTR4_UNIQUE_HANDLES: u32 = 9;
+pub const FATTR4_LEASE_TIME: u32 = 10;
+pub const FATTR4_RDATTR_ERROR: u32 = 11;
+pub const FATTR4_ACL: u32 = 12;
+pub const FATTR4_ACLSUPPORT: u32 = 13;
+pub const FATTR4_ARCHIVE: u32 = 14;
+pub const FATTR4_CANSETTIME: u32 = 15;
+pub const FATTR4_CASE_INSENSITIVE: u32 = 16;
+pub const FATTR4_CASE_PRESERVING: u32 = 17;
+pub const FATTR4_CHOWN_RESTRICTED: u32 = 18;
+pub const FATTR4_FILEHANDLE: u32 = 19;
+pub const FATTR4_FILEID: u32 = 20;
+pub const FATTR4_FILES_AVAIL: u32 = 21;
+pub const FATTR4_FILES_FREE: u32 = 22;
+pub const FATTR4_FILES_TOTAL: u32 = 23;
+pub const FATTR4_FS_LOCATIONS: u32 = 24;
+pub const FATTR4_HIDDEN: u32 = 25;
+pub const FATTR4_HOMOGENEOUS: u32 = 26;
+pub const FATTR4_MAXFILESIZE: u32 = 27;
+pub const FATTR4_MAXLINK: u32 = 28;
+pub const FATTR4_MAXNAME: u32 = 29;
+pub const FATTR4_MAXREAD: u32 = 30;
+pub const FATTR4_MAXWRITE: u32 = 31;
+pub const FATTR4_MIMETYPE: u32 = 32;
+pub const FATTR4_MODE: u32 = 33;
+pub const FATTR4_NO_TRUNC: u32 = 34;
+pub const FATTR4_NUMLINKS: u32 = 35;
+pub const FATTR4_OWNER: u32 = 36;
+pub const FATTR4_OWNER_GROUP: u32 = 37;
+pub const FATTR4_QUOTA_AVAIL_HARD: u32 = 38;
+pub const FATTR4_QUOTA_AVAIL_SOFT: u32 = 39;
+pub const FATTR4_QUOTA_USED: u32 = 40;
+pub const FATTR4_RAWDEV: u32 = 41;
+pub const FATTR4_SPACE_AVAIL: u32 = 42;
+pub const FATTR4_SPACE_FREE: u32 = 43;
+pub const FATTR4_SPACE_TOTAL: u32 = 44;
+pub const FATTR4_SPACE_USED: u32 = 45;
+pub const FATTR4_SYSTEM: u32 = 46;
+pub const FATTR4_TIME_ACCESS: u32 = 47;
+pub const FATTR4_TIME_ACCESS_SET: u32 = 48;
+pub const FATTR4_TIME_BACKUP: u32 = 49;
+pub const FATTR4_TIME_CREATE: u32 = 50;
+pub const FATTR4_TIME_DELTA: u32 = 51;
+pub const FATTR4_TIME_METADATA: u32 = 52;
+pub const FATTR4_TIME_MODIFY: u32 = 53;
+pub const FATTR4_TIME_MODIFY_SET: u32 = 54;
+pub const FATTR4_MOUNTED_ON_FILEID: u32 = 55;
+pub const FATTR4_SUPPATTR_EXCLCREAT: u32 = 75;
+
+pub const FH4_PERSISTENT: u32 = 0x00;
+pub const SET_TO_SERVER_TIME4: u32 = 0;
+pub const SET_TO_CLIENT_TIME4: u32 = 1;
+
+// Section 18.1: ACCESS
+pub const ACCESS4_READ: u32 = 0x00000001;
+pub const ACCESS4_LOOKUP: u32 = 0x00000002;
+pub const ACCESS4_MODIFY: u32 = 0x00000004;
+pub const ACCESS4_EXTEND: u32 = 0x00000008;
+pub const ACCESS4_DELETE: u32 = 0x00000010;
+pub const ACCESS4_EXECUTE: u32 = 0x00000020;
+
+// Section 18.16: OPEN
+pub const OPEN4_SHARE_ACCESS_READ: u32 = 0x00000001;
+pub const OPEN4_SHARE_ACCESS_WRITE: u32 = 0x00000002;
+pub const OPEN4_SHARE_ACCESS_BOTH: u32 = 0x00000003;
+pub const OPEN4_SHARE_ACCESS_WANT_DELEG_MASK: u32 = 0xFF00;
+pub const OPEN4_SHARE_DENY_NONE: u32 = 0x00000000;
+pub const OPEN4_SHARE_DENY_BOTH: u32 = 0x00000003;
+pub const OPEN4_NOCREATE: u32 = 0;
+pub const OPEN4_CREATE: u32 = 1;
+pub const UNCHECKED4: u32 = 0;
+pub const GUARDED4: u32 = 1;
+pub const EXCLUSIVE4: u32 = 2;
+pub const EXCLUSIVE4_1: u32 = 3;
+pub const CLAIM_NULL: u32 = 0;
+pub const CLAIM_PREVIOUS: u32 = 1;
+pub const CLAIM_DELEGATE_CUR: u32 = 2;
+pub const CLAIM_DELEGATE_PREV: u32 = 3;
+pub const CLAIM_FH: u32 = 4;
+pub const CLAIM_DELEG_CUR_FH: u32 = 5;
+pub const CLAIM_DELEG_PREV_FH: u32 = 6;
+pub const OPEN4_RESULT_LOCKTYPE_POSIX: u32 = 0x00000004;
+pub const OPEN_DELEGATE_NONE: u32 = 0;
+
+// Section 18.10: LOCK
+pub const READ_LT: u32 = 1;
+pub const WRITE_LT: u32 = 2;
+pub const READW_LT: u32 = 3;
+pub const WRITEW_LT: u32 = 4;
+
+// Section 18.32: WRITE
+pub const UNSTABLE4: u32 = 0;
+pub const DATA_SYNC4: u32 = 1;
+pub const FILE_SYNC4: u32 = 2;
+
+// Section 18.35: EXCHANGE_ID
+pub const EXCHGID4_FLAG_SUPP_MOVED_REFER: u32 = 0x00000001;
+pub const EXCHGID4_FLAG_SUPP_MOVED_MIGR: u32 = 0x00000002;
+pub const EXCHGID4_FLAG_BIND_PRINC_STATEID: u32 = 0x00000100;
+pub const EXCHGID4_FLAG_USE_NON_PNFS: u32 = 0x00010000;
+pub const EXCHGID4_FLAG_USE_PNFS_MDS: u32 = 0x00020000;
+pub const EXCHGID4_FLAG_USE_PNFS_DS: u32 = 0x00040000;
+pub const EXCHGID4_FLAG_MASK_PNFS: u32 = 0x00070000;
+pub const EXCHGID4_FLAG_UPD_CONFIRMED_REC_A: u32 = 0x40000000;
+pub const EXCHGID4_FLAG_CONFIRMED_R: u32 = 0x80000000;
+pub const SP4_NONE: u32 = 0;
+
+// Section 18.36: CREATE_SESSION
+pub const CREATE_SESSION4_FLAG_PERSIST: u32 = 0x00000001;
+pub const CREATE_SESSION4_FLAG_CONN_BACK_CHAN: u32 = 0x00000002;
+
+// Section 18.34: BIND_CONN_TO_SESSION
+pub const CDFC4_FORE: u32 = 0x1;
+pub const CDFS4_FORE: u32 = 0x1;
+
+// Section 18.45: SECINFO
+pub const SECINFO_STYLE4_CURRENT_FH: u32 = 0;
+pub const SECINFO_STYLE4_PARENT: u32 = 1;
@@ -0,0 +1,53 @@
+// this is just a complete enumeration of everything in the specification
+#![allow(dead_code)]
+// And its nice to keep the original names and case
+#![allow(non_camel_case_types)]
+
+use crate::kernel::protocol::xdr::*;
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use num_derive::{FromPrimitive, ToPrimitive};
+use num_traits::cast::FromPrimitive;
+use std::io::{Read, Write};
+// Transcribed from the Network Status Monitor protocol (X/Open XNFS, chapter 11)
+
+pub const PROGRAM: u32 = 100024;
+pub const VERSION: u32 = 1;
+
+pub const SM_MAXSTRLEN: u32 = 1024;
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default, FromPrimitive, ToPrimitive)]
+#[repr(u32)]
+pub enum res {
+    #[default]
+    STAT_SUCC = 0,
+    STAT_FAIL = 1,
+}
+XDREnumSerde!(res);
+
+#[derive(Clone, Debug, Default)]
+pub struct sm_name {
+    pub mon_name: Vec<u8>,
+}
+XDRStruct!(sm_name, mon_name);
+
+#[derive(Clone, Debug, Default)]
+pub struct sm_stat_res {
+    pub res_stat: res,
+    pub state: i32,
+}
+XDRStruct!(sm_stat_res, res_stat, state);
+
+#[derive(Clone, Debug, Default)]
+pub struct sm_stat {
+    pub state: i32,
+}
+XDRStruct!(sm_stat, state);
+
+/// A host's new state, sent to those monitoring it when it restarts
+#[derive(Clone, Debug, Default)]
+pub struct stat_chge {
+    pub mon_name: Vec<u8>,
+    pub state: i32,
+}
+XDRStruct!(stat_chge, mon_name, state);
@@ -22,3 +22,44 @@ pub const IPPROTO_TCP: u32 = 6; /* protocol number for TCP/IP */
 pub const IPPROTO_UDP: u32 = 17; /* protocol number for UDP/IP */
 pub const PROGRAM: u32 = 100000;
 pub const VERSION: u32 = 2;
+// rpcbind, transcribed from RFC 1833
+pub const RPCBVERS: u32 = 3;
+pub const RPCBVERS4: u32 = 4;
+
+#[allow(non_camel_case_types)]
+#[derive(Clone, Debug, Default)]
+pub struct rpcb {
+    pub r_prog: u32,
+    pub r_vers: u32,
+    pub r_netid: Vec<u8>,
+    pub r_addr: Vec<u8>,
+    pub r_owner: Vec<u8>,
+}
+XDRStruct!(rpcb, r_prog, r_vers, r_netid, r_addr, r_owner);
+
+/// Arguments of PMAPPROC_CALLIT, and of RPCBPROC_CALLIT as rpcb_rmtcallargs
+#[allow(non_camel_case_types)]
+#[derive(Clone, Debug, Default)]
+pub struct call_args {
+    pub prog: u32,
+    pub vers: u32,
+    pub proc: u32,
+    pub args: Vec<u8>,
+}
+XDRStruct!(call_args, prog, vers, proc, args);
+
+#[allow(non_camel_case_types)]
+#[derive(Clone, Debug, Default)]
+pub struct call_result {
+    pub port: u32,
+    pub res: Vec<u8>,
+}
+XDRStruct!(call_result, port, res);
+
+#[allow(non_camel_case_types)]
+#[derive(Clone, Debug, Default)]
+pub struct rpcb_rmtcallres {
+    pub addr: Vec<u8>,
+    pub results: Vec<u8>,
+}
+XDRStruct!(rpcb_rmtcallres, addr, results);
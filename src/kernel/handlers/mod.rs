@@ -2,4 +2,10 @@ pub mod mount_handlers;
 
 pub mod portmap_handlers;
 
-pub mod nfs;
\ No newline at end of file
+pub mod nlm_handlers;
+
+pub mod nsm_handlers;
+
+pub mod nfs;
+
+pub mod nfs4;
\ No newline at end of file
@@ -5,11 +5,13 @@ use crate::kernel::protocol::xdr::*;
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::cast::{FromPrimitive, ToPrimitive};
 use std::io::{Read, Write};
-use tracing::debug;
+use tracing::{debug, info, warn};
 
 use anyhow::Result;
 
-use crate::backingstore::data_store::KeyType;
+use crate::backingstore::user_registry::{self, Role};
+use crate::kernel::vfs::api::{NFSFileSystem, VFSCapabilities};
+use std::sync::Arc;
 
 #[allow(non_camel_case_types)]
 #[allow(clippy::upper_case_acronyms)]
@@ -36,11 +38,12 @@ pub async fn handle_mount(
     match prog {
         MountProgram::MOUNTPROC3_NULL => mountproc3_null(xid, input, output)?,
         MountProgram::MOUNTPROC3_MNT => mountproc3_mnt(xid, input, output, context).await?,
+        MountProgram::MOUNTPROC3_DUMP => mountproc3_dump(xid, input, output, context)?,
         MountProgram::MOUNTPROC3_UMNT => mountproc3_umnt(xid, input, output, context).await?,
         MountProgram::MOUNTPROC3_UMNTALL => {
             mountproc3_umnt_all(xid, input, output, context).await?
         }
-        MountProgram::MOUNTPROC3_EXPORT => mountproc3_export(xid, input, output)?,
+        MountProgram::MOUNTPROC3_EXPORT => mountproc3_export(xid, input, output, context)?,
         _ => {
             proc_unavail_reply_message(xid).serialize(output)?;
         }
@@ -61,13 +64,93 @@ pub fn mountproc3_null(
     Ok(())
 }
 
-#[allow(non_camel_case_types)]
-#[derive(Clone, Debug)]
-pub struct mountres3_ok {
-    pub fhandle: fhandle3,
-    pub auth_flavors: Vec<u32>,
+// Why a caller may not mount an export
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MountRefusal {
+    // The client, or the caller, is not allowed it
+    Access,
+    // The export wants TLS or an authenticated user the call does not have
+    TooWeak,
+    // The registered users could not be read
+    ServerFault,
+    // The directory to mount could not be made
+    NoDirectory,
+}
+
+// What a caller mounts of an export: the file system serving it and the directory mounted
+pub(crate) struct MountPoint {
+    pub vfs: Arc<dyn NFSFileSystem + Send + Sync>,
+    pub path: String,
+}
+
+// Decides what the caller of a call mounts of an export, and with a drive, whose drive, making
+// its directory if need be. MNT and NFSv4 clients crossing into an export both go through it
+pub(crate) async fn mount_point(
+    context: &RPCContext,
+    index: u32,
+    drive: Option<String>,
+) -> Result<MountPoint, MountRefusal> {
+    let export = &context.exports.exports()[index as usize];
+    if !export.admits_client(&context.client_addr) {
+        debug!("{} may not mount {}", context.client_addr, export.path);
+        return Err(MountRefusal::Access);
+    }
+
+    if export.require_tls && !context.over_tls() {
+        debug!("{} is only served over TLS", export.path);
+        return Err(MountRefusal::TooWeak);
+    }
+    if export.require_signed_auth && context.caller.is_none() {
+        debug!("{} only takes calls of authenticated users", export.path);
+        return Err(MountRefusal::TooWeak);
+    }
+
+    // The caller is the user who signed the call or holds the connection's client certificate,
+    // else the registered user bound to the credential's uid, as the export's store has them
+    let caller = match (&context.caller, &context.auth) {
+        (Some(signer), _) => user_registry::get_user(export.vfs.data_store(), &signer.name).await,
+        (None, Some(auth)) => user_registry::find_by_uid(export.vfs.data_store(), auth.uid).await,
+        (None, None) => Ok(None),
+    };
+    let user = match caller {
+        Ok(Some(user)) if user.enabled && user.may_mount(&export.path) => user,
+        Ok(_) => {
+            debug!("No enabled user of {} for {:?}", export.path, context.auth);
+            return Err(MountRefusal::Access);
+        }
+        Err(e) => {
+            warn!("User lookup failed: {}", e);
+            return Err(MountRefusal::ServerFault);
+        }
+    };
+    let vfs = export.vfs_for_user(&user.name);
+    if !vfs.admits_user(&user.name) {
+        debug!("{} is not a user of {:?}", user.name, vfs.namespace());
+        return Err(MountRefusal::Access);
+    }
+
+    // Users mount their own drive; a superuser, unless squashed, may mount anyone's or the
+    // whole export
+    let superuser = user.role == Role::Superuser && !export.root_squash;
+    let path = match drive {
+        Some(drive) if drive != user.name => {
+            if !superuser || !user_registry::valid_name(&drive) {
+                debug!("{} may not mount {}'s drive", user.name, drive);
+                return Err(MountRefusal::Access);
+            }
+            export.user_path(&drive)
+        }
+        _ if superuser => export.path.clone(),
+        _ => export.user_path(&user.name),
+    };
+    info!("{} mounts {} as a {} user", user.name, path, user.role.as_str());
+
+    // A read-only mount only sees what is already there
+    if matches!(vfs.capabilities(), VFSCapabilities::ReadWrite) {
+        vfs.data_store().init_user_directory(vfs.namespace(), &path).await.map_err(|_| MountRefusal::NoDirectory)?;
+    }
+    Ok(MountPoint { vfs: vfs.clone(), path })
 }
-XDRStruct!(mountres3_ok, fhandle, auth_flavors);
 
 pub async fn mountproc3_mnt(
     xid: u32,
@@ -79,56 +162,62 @@ pub async fn mountproc3_mnt(
     debug!("=== XID: {} ===", xid);
     
     let mut path = dirpath::new();
-    path.deserialize(input)?;
+    deserialize_bounded(&mut path, MNTPATHLEN as usize, input)?;
     
     let path_str = std::str::from_utf8(&path).unwrap_or_default();
     debug!("=== Mount path received: {} ===", path_str);
     
-    let mut user_key = None;
-    let options: Vec<&str> = path_str.split('/').collect();
-
-    for option in options {
-        if option.ends_with("'s drive") {
-            user_key = Some(option.trim_end_matches("'s drive").to_string());
-        }
+    // The path names an export and, with a "<user>'s drive" component, the drive to mount
+    let Some((index, drive)) = context.exports.resolve_mount(path_str) else {
+        debug!("{:?} --> MNT3ERR_NOENT, {} is not exported", xid, path_str);
+        make_success_reply(xid).serialize(output)?;
+        mountstat3::MNT3ERR_NOENT.serialize(output)?;
+        return Ok(());
+    };
+    let export = &context.exports.exports()[index as usize];
+    let mount = mount_point(context, index, drive).await;
+    if let Err(refusal) = &mount {
+        debug!("{:?} --> refused {}: {:?}", xid, path_str, refusal);
     }
-
-    let utf8path: String = if let Some(ref user_key) = user_key {
-        match context.vfs.data_store().authenticate_user(user_key).await {
-            KeyType::Usual => {
-                println!("Authenticated as a standard user: {}", user_key);
-                format!("/{}", user_key)
-            }
-            KeyType::Special => {
-                println!("Authenticated as a superuser: {}", user_key);
-                String::from("/")
-            }
-            KeyType::None => {
-                make_failure_reply(xid).serialize(output)?;
-                return Err(anyhow::anyhow!("Authentication failed"));
-            }
+    let (vfs, utf8path) = match mount {
+        Ok(mount) => (mount.vfs, mount.path),
+        Err(MountRefusal::Access) => {
+            make_success_reply(xid).serialize(output)?;
+            mountstat3::MNT3ERR_ACCES.serialize(output)?;
+            return Ok(());
+        }
+        Err(MountRefusal::TooWeak) => {
+            auth_error_reply_message(xid, auth_stat::AUTH_TOOWEAK).serialize(output)?;
+            return Ok(());
+        }
+        Err(MountRefusal::ServerFault) => {
+            make_success_reply(xid).serialize(output)?;
+            mountstat3::MNT3ERR_SERVERFAULT.serialize(output)?;
+            return Ok(());
+        }
+        Err(MountRefusal::NoDirectory) => {
+            make_failure_reply(xid).serialize(output)?;
+            return Err(anyhow::anyhow!("Failed to initialize user directory"));
         }
-    } else {
-        make_failure_reply(xid).serialize(output)?;
-        return Err(anyhow::anyhow!("User key not provided"));
     };
 
-    context.vfs.data_store().init_user_directory(&utf8path).await.map_err(|_| {
-        let _ = make_failure_reply(xid).serialize(output);
-        anyhow::anyhow!("Failed to initialize user directory")
-    })?;
-
     debug!("mountproc3_mnt({:?},{:?}) ", xid, utf8path);
-    if let Ok(fileid) = context.vfs.get_id_from_path(&utf8path, context.vfs.data_store()).await {
+    if let Ok(fileid) = vfs.get_id_from_path(&utf8path, vfs.data_store()).await {
         let response = mountres3_ok {
-            fhandle: context.vfs.id_to_fh(fileid).data,
-            auth_flavors: vec![
-                auth_flavor::AUTH_NULL.to_u32().unwrap(),
-                auth_flavor::AUTH_UNIX.to_u32().unwrap(),
-            ],
+            fhandle: vfs.id_to_fh(fileid).data,
+            auth_flavors: if export.require_signed_auth {
+                vec![auth_flavor::AUTH_SIGNED.to_u32().unwrap()]
+            } else {
+                vec![
+                    auth_flavor::AUTH_NULL.to_u32().unwrap(),
+                    auth_flavor::AUTH_UNIX.to_u32().unwrap(),
+                    auth_flavor::AUTH_SIGNED.to_u32().unwrap(),
+                ]
+            },
         };
         debug!("{:?} --> {:?}", xid, response);
 
+        context.exports.record_mount(&context.client_addr, path_str);
         if let Some(ref chan) = context.mount_signal {
             let _ = chan.send(true).await;
         }
@@ -166,19 +255,56 @@ pub fn mountproc3_export(
     xid: u32,
     _: &mut impl Read,
     output: &mut impl Write,
+    context: &RPCContext,
 ) -> Result<(), anyhow::Error> {
     debug!("mountproc3_export({:?}) ", xid);
     make_success_reply(xid).serialize(output)?;
-    true.serialize(output)?;
-    // dirpath
-    "/".as_bytes().to_vec().serialize(output)?;
-    // groups
-    false.serialize(output)?;
+    for export in context.exports.exports() {
+        true.serialize(output)?;
+        // dirpath
+        export.path.as_bytes().to_vec().serialize(output)?;
+        // groups; none means any client
+        for network in &export.clients {
+            true.serialize(output)?;
+            network.to_string().into_bytes().serialize(output)?;
+        }
+        false.serialize(output)?;
+    }
     // next exports
     false.serialize(output)?;
     Ok(())
 }
 
+/*
+
+DESCRIPTION
+
+  Procedure DUMP returns the list of remotely mounted file
+  systems. The mountlist contains one entry for each client
+  host name and directory pair.
+
+ */
+
+pub fn mountproc3_dump(
+    xid: u32,
+    _: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    debug!("mountproc3_dump({:?}) ", xid);
+    make_success_reply(xid).serialize(output)?;
+    for mount in context.exports.mounts() {
+        true.serialize(output)?;
+        // hostname
+        mount.client.into_bytes().serialize(output)?;
+        // directory
+        mount.path.into_bytes().serialize(output)?;
+    }
+    // next mount
+    false.serialize(output)?;
+    Ok(())
+}
+
 pub async fn mountproc3_umnt(
     xid: u32,
     input: &mut impl Read,
@@ -186,9 +312,10 @@ pub async fn mountproc3_umnt(
     context: &RPCContext,
 ) -> Result<(), anyhow::Error> {
     let mut path = dirpath::new();
-    path.deserialize(input)?;
+    deserialize_bounded(&mut path, MNTPATHLEN as usize, input)?;
     let utf8path = std::str::from_utf8(&path).unwrap_or_default();
     debug!("mountproc3_umnt({:?},{:?}) ", xid, utf8path);
+    context.exports.remove_mount(&context.client_addr, utf8path);
     if let Some(ref chan) = context.mount_signal {
         let _ = chan.send(false).await;
     }
@@ -204,6 +331,7 @@ pub async fn mountproc3_umnt_all(
     context: &RPCContext,
 ) -> Result<(), anyhow::Error> {
     debug!("mountproc3_umnt_all({:?}) ", xid);
+    context.exports.remove_client(&context.client_addr);
     if let Some(ref chan) = context.mount_signal {
         let _ = chan.send(false).await;
     }
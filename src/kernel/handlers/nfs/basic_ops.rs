@@ -29,14 +29,15 @@ pub async fn nfsproc3_getattr(
     handle.deserialize(input)?;
     debug!("nfsproc3_getattr({:?},{:?}) ", xid, handle);
 
-    let id = context.vfs.fh_to_id(&handle);
     // fail if unable to convert file handle
-    if let Err(stat) = id {
-        make_success_reply(xid).serialize(output)?;
-        stat.serialize(output)?;
-        return Ok(());
-    }
-    let id = id.unwrap();
+    let id = match context.vfs.fh_to_id(&handle).await {
+        Ok(id) => id,
+        Err(stat) => {
+            make_success_reply(xid).serialize(output)?;
+            stat.serialize(output)?;
+            return Ok(());
+        }
+    };
     match context.vfs.getattr(id).await {
         Ok(fh) => {
             debug!("nfsproc3_getattr ({:?} --> {:?})", xid, fh);
@@ -66,10 +67,10 @@ XDRBoolUnion!(sattrguard3, obj_ctime, nfs::nfstime3);
 
 #[allow(non_camel_case_types)]
 #[derive(Clone, Debug, Default)]
-struct SETATTR3args {
-    object: nfs::nfs_fh3,
-    new_attribute: nfs::sattr3,
-    guard: sattrguard3,
+pub struct SETATTR3args {
+    pub object: nfs::nfs_fh3,
+    pub new_attribute: nfs::sattr3,
+    pub guard: sattrguard3,
 }
 XDRStruct!(SETATTR3args, object, new_attribute, guard);
 
@@ -85,27 +86,9 @@ pub async fn nfsproc3_setattr(
     args.deserialize(input)?;
     debug!("nfsproc3_setattr({:?},{:?}) ", xid, args);
 
-    let id = context.vfs.fh_to_id(&args.object);
     // fail if unable to convert file handle
-    if let Err(stat) = id {
-        make_success_reply(xid).serialize(output)?;
-        stat.serialize(output)?;
-        return Ok(());
-    }
-    let id = id.unwrap();
-
-    let ctime;
-
-    let pre_op_attr = match context.vfs.getattr(id).await {
-        Ok(v) => {
-            let wccattr = nfs::wcc_attr {
-                size: v.size,
-                mtime: v.mtime,
-                ctime: v.ctime,
-            };
-            ctime = v.ctime;
-            nfs::pre_op_attr::attributes(wccattr)
-        }
+    let id = match context.vfs.fh_to_id(&args.object).await {
+        Ok(id) => id,
         Err(stat) => {
             make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
@@ -113,25 +96,16 @@ pub async fn nfsproc3_setattr(
             return Ok(());
         }
     };
-    // handle the guard
-    match args.guard {
-        sattrguard3::Void => {}
-        sattrguard3::obj_ctime(c) => {
-            if c.seconds != ctime.seconds || c.nseconds != ctime.nseconds {
-                make_success_reply(xid).serialize(output)?;
-                nfs::nfsstat3::NFS3ERR_NOT_SYNC.serialize(output)?;
-                nfs::wcc_data::default().serialize(output)?;
-            }
-        }
-    }
 
-    match context.vfs.setattr(id, args.new_attribute).await {
+    // The guard is checked against the ctime in the same change that sets the attributes
+    let guard = match args.guard {
+        sattrguard3::Void => None,
+        sattrguard3::obj_ctime(ctime) => Some(ctime),
+    };
+    let (res, wcc_res) = context.vfs.setattr_wcc(id, args.new_attribute, guard).await;
+    match res {
         Ok(post_op_attr) => {
             debug!(" setattr success {:?} --> {:?}", xid, post_op_attr);
-            let wcc_res = nfs::wcc_data {
-                before: pre_op_attr,
-                after: nfs::post_op_attr::attributes(post_op_attr),
-            };
             make_success_reply(xid).serialize(output)?;
             nfs::nfsstat3::NFS3_OK.serialize(output)?;
             wcc_res.serialize(output)?;
@@ -140,7 +114,7 @@ pub async fn nfsproc3_setattr(
             error!("setattr error {:?} --> {:?}", xid, stat);
             make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
-            nfs::wcc_data::default().serialize(output)?;
+            wcc_res.serialize(output)?;
         }
     }
     Ok(())
@@ -157,15 +131,16 @@ pub async fn nfsproc3_lookup(
     dirops.deserialize(input)?;
     debug!("nfsproc3_lookup({:?},{:?}) ", xid, dirops);
 
-    let dirid = context.vfs.fh_to_id(&dirops.dir);
     // fail if unable to convert file handle
-    if let Err(stat) = dirid {
-        make_success_reply(xid).serialize(output)?;
-        stat.serialize(output)?;
-        nfs::post_op_attr::Void.serialize(output)?;
-        return Ok(());
-    }
-    let dirid = dirid.unwrap();
+    let dirid = match context.vfs.fh_to_id(&dirops.dir).await {
+        Ok(dirid) => dirid,
+        Err(stat) => {
+            make_success_reply(xid).serialize(output)?;
+            stat.serialize(output)?;
+            nfs::post_op_attr::Void.serialize(output)?;
+            return Ok(());
+        }
+    };
     let dir_attr = match context.vfs.getattr(dirid).await {
         Ok(v) => nfs::post_op_attr::attributes(v),
         Err(_) => nfs::post_op_attr::Void,
@@ -10,12 +10,12 @@ use tracing::{debug, error, trace};
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Default)]
-struct READDIRPLUS3args {
-    dir: nfs::nfs_fh3,
-    cookie: nfs::cookie3,
-    cookieverf: nfs::cookieverf3,
-    dircount: nfs::count3,
-    maxcount: nfs::count3,
+pub struct READDIRPLUS3args {
+    pub dir: nfs::nfs_fh3,
+    pub cookie: nfs::cookie3,
+    pub cookieverf: nfs::cookieverf3,
+    pub dircount: nfs::count3,
+    pub maxcount: nfs::count3,
 }
 XDRStruct!(
     READDIRPLUS3args,
@@ -28,12 +28,12 @@ XDRStruct!(
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Default)]
-struct entryplus3 {
-    fileid: nfs::fileid3,
-    name: nfs::filename3,
-    cookie: nfs::cookie3,
-    name_attributes: nfs::post_op_attr,
-    name_handle: nfs::post_op_fh3,
+pub struct entryplus3 {
+    pub fileid: nfs::fileid3,
+    pub name: nfs::filename3,
+    pub cookie: nfs::cookie3,
+    pub name_attributes: nfs::post_op_attr,
+    pub name_handle: nfs::post_op_fh3,
 }
 XDRStruct!(
     entryplus3,
@@ -54,15 +54,16 @@ pub async fn nfsproc3_readdirplus(
     args.deserialize(input)?;
     debug!("nfsproc3_readdirplus({:?},{:?}) ", xid, args);
 
-    let dirid = context.vfs.fh_to_id(&args.dir);
     // fail if unable to convert file handle
-    if let Err(stat) = dirid {
-        make_success_reply(xid).serialize(output)?;
-        stat.serialize(output)?;
-        nfs::post_op_attr::Void.serialize(output)?;
-        return Ok(());
-    }
-    let dirid = dirid.unwrap();
+    let dirid = match context.vfs.fh_to_id(&args.dir).await {
+        Ok(dirid) => dirid,
+        Err(stat) => {
+            make_success_reply(xid).serialize(output)?;
+            stat.serialize(output)?;
+            nfs::post_op_attr::Void.serialize(output)?;
+            return Ok(());
+        }
+    };
     let dir_attr_maybe = context.vfs.getattr(dirid).await;
 
     let dir_attr = match dir_attr_maybe {
@@ -177,9 +178,9 @@ pub async fn nfsproc3_readdirplus(
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Default)]
-struct MKDIR3args {
-    dirops: nfs::diropargs3,
-    attributes: nfs::sattr3,
+pub struct MKDIR3args {
+    pub dirops: nfs::diropargs3,
+    pub attributes: nfs::sattr3,
 }
 XDRStruct!(MKDIR3args, dirops, attributes);
 
@@ -197,48 +198,19 @@ pub async fn nfsproc3_mkdir(
 
     // find the directory we are supposed to create the
     // new file in
-    let dirid = context.vfs.fh_to_id(&args.dirops.dir);
-    if let Err(stat) = dirid {
-        // directory does not exist
-        make_success_reply(xid).serialize(output)?;
-        stat.serialize(output)?;
-        nfs::wcc_data::default().serialize(output)?;
-        error!("Directory does not exist");
-        return Ok(());
-    }
-    // found the directory, get the attributes
-    let dirid = dirid.unwrap();
-
-    // get the object attributes before the write
-    let pre_dir_attr = match context.vfs.getattr(dirid).await {
-        Ok(v) => {
-            let wccattr = nfs::wcc_attr {
-                size: v.size,
-                mtime: v.mtime,
-                ctime: v.ctime,
-            };
-            nfs::pre_op_attr::attributes(wccattr)
-        }
+    let dirid = match context.vfs.fh_to_id(&args.dirops.dir).await {
+        Ok(dirid) => dirid,
         Err(stat) => {
-            error!("Cannot stat directory");
+            // directory does not exist
             make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
             nfs::wcc_data::default().serialize(output)?;
+            error!("Directory does not exist");
             return Ok(());
         }
     };
 
-    let res = context.vfs.mkdir(dirid, &args.dirops.name).await;
-
-    // Re-read dir attributes for post op attr
-    let post_dir_attr = match context.vfs.getattr(dirid).await {
-        Ok(v) => nfs::post_op_attr::attributes(v),
-        Err(_) => nfs::post_op_attr::Void,
-    };
-    let wcc_res = nfs::wcc_data {
-        before: pre_dir_attr,
-        after: post_dir_attr,
-    };
+    let (res, wcc_res) = context.vfs.mkdir_wcc(dirid, &args.dirops.name).await;
 
     match res {
         Ok((fid, fattr)) => {
@@ -8,24 +8,24 @@ use byteorder::{ReadBytesExt, WriteBytesExt};
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::cast::FromPrimitive;
 use std::io::{Read, Write};
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Default)]
-struct READ3args {
-    file: nfs::nfs_fh3,
-    offset: nfs::offset3,
-    count: nfs::count3,
+pub struct READ3args {
+    pub file: nfs::nfs_fh3,
+    pub offset: nfs::offset3,
+    pub count: nfs::count3,
 }
 XDRStruct!(READ3args, file, offset, count);
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Default)]
-struct READ3resok {
-    file_attributes: nfs::post_op_attr,
-    count: nfs::count3,
-    eof: bool,
-    data: Vec<u8>,
+pub struct READ3resok {
+    pub file_attributes: nfs::post_op_attr,
+    pub count: nfs::count3,
+    pub eof: bool,
+    pub data: Vec<u8>,
 }
 XDRStruct!(READ3resok, file_attributes, count, eof, data);
 
@@ -39,14 +39,15 @@ pub async fn nfsproc3_read(
     args.deserialize(input)?;
     debug!("nfsproc3_read({:?},{:?}) ", xid, args);
 
-    let id = context.vfs.fh_to_id(&args.file);
-    if let Err(stat) = id {
-        make_success_reply(xid).serialize(output)?;
-        stat.serialize(output)?;
-        nfs::post_op_attr::Void.serialize(output)?;
-        return Ok(());
-    }
-    let id = id.unwrap();
+    let id = match context.vfs.fh_to_id(&args.file).await {
+        Ok(id) => id,
+        Err(stat) => {
+            make_success_reply(xid).serialize(output)?;
+            stat.serialize(output)?;
+            nfs::post_op_attr::Void.serialize(output)?;
+            return Ok(());
+        }
+    };
 
     let obj_attr = match context.vfs.getattr(id).await {
         Ok(v) => nfs::post_op_attr::attributes(v),
@@ -87,22 +88,22 @@ XDREnumSerde!(stable_how);
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Default)]
-struct WRITE3args {
-    file: nfs::nfs_fh3,
-    offset: nfs::offset3,
-    count: nfs::count3,
-    stable: u32,
-    data: Vec<u8>,
+pub struct WRITE3args {
+    pub file: nfs::nfs_fh3,
+    pub offset: nfs::offset3,
+    pub count: nfs::count3,
+    pub stable: u32,
+    pub data: Vec<u8>,
 }
 XDRStruct!(WRITE3args, file, offset, count, stable, data);
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Default)]
-struct WRITE3resok {
-    file_wcc: nfs::wcc_data,
-    count: nfs::count3,
-    committed: stable_how,
-    verf: nfs::writeverf3,
+pub struct WRITE3resok {
+    pub file_wcc: nfs::wcc_data,
+    pub count: nfs::count3,
+    pub committed: stable_how,
+    pub verf: nfs::writeverf3,
 }
 XDRStruct!(WRITE3resok, file_wcc, count, committed, verf);
 
@@ -122,36 +123,31 @@ pub async fn nfsproc3_write(
         return Ok(());
     }
 
-    let id = context.vfs.fh_to_id(&args.file);
-    if let Err(stat) = id {
+    let id = match context.vfs.fh_to_id(&args.file).await {
+        Ok(id) => id,
+        Err(stat) => {
+            make_success_reply(xid).serialize(output)?;
+            stat.serialize(output)?;
+            nfs::wcc_data::default().serialize(output)?;
+            return Ok(());
+        }
+    };
+
+    // A write ending past the largest offset there is cannot be made
+    if args.offset.checked_add(args.count as u64).is_none() {
+        warn!("write {:?} --> offset {} beyond the largest file", xid, args.offset);
         make_success_reply(xid).serialize(output)?;
-        stat.serialize(output)?;
+        nfs::nfsstat3::NFS3ERR_FBIG.serialize(output)?;
         nfs::wcc_data::default().serialize(output)?;
         return Ok(());
     }
-    let id = id.unwrap();
-
-    // get the object attributes before the write
-    let pre_obj_attr = match context.vfs.getattr(id).await {
-        Ok(v) => {
-            let wccattr = nfs::wcc_attr {
-                size: v.size,
-                mtime: v.mtime,
-                ctime: v.ctime,
-            };
-            nfs::pre_op_attr::attributes(wccattr)
-        }
-        Err(_) => nfs::pre_op_attr::Void,
-    };
 
-    match context.vfs.write(id, args.offset, &args.data).await {
+    let (res, file_wcc) = context.vfs.write_wcc(id, args.offset, &args.data).await;
+    match res {
         Ok(fattr) => {
             debug!("write success {:?} --> {:?}", xid, fattr);
             let res = WRITE3resok {
-                file_wcc: nfs::wcc_data {
-                    before: pre_obj_attr,
-                    after: nfs::post_op_attr::attributes(fattr),
-                },
+                file_wcc,
                 count: args.count,
                 committed: stable_how::FILE_SYNC,
                 verf: context.vfs.serverid(),
@@ -164,7 +160,7 @@ pub async fn nfsproc3_write(
             error!("write error {:?} --> {:?}", xid, stat);
             make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
-            nfs::wcc_data::default().serialize(output)?;
+            file_wcc.serialize(output)?;
         }
     }
     Ok(())
@@ -199,36 +195,18 @@ pub async fn nfsproc3_create(
 
     // find the directory we are supposed to create the
     // new file in
-    let dirid = context.vfs.fh_to_id(&dirops.dir);
-    if let Err(stat) = dirid {
-        // directory does not exist
-        make_success_reply(xid).serialize(output)?;
-        stat.serialize(output)?;
-        nfs::wcc_data::default().serialize(output)?;
-        error!("Directory does not exist");
-        return Ok(());
-    }
-    // found the directory, get the attributes
-    let dirid = dirid.unwrap();
-
-    // get the object attributes before the write
-    let pre_dir_attr = match context.vfs.getattr(dirid).await {
-        Ok(v) => {
-            let wccattr = nfs::wcc_attr {
-                size: v.size,
-                mtime: v.mtime,
-                ctime: v.ctime,
-            };
-            nfs::pre_op_attr::attributes(wccattr)
-        }
+    let dirid = match context.vfs.fh_to_id(&dirops.dir).await {
+        Ok(dirid) => dirid,
         Err(stat) => {
-            error!("Cannot stat directory");
+            // directory does not exist
             make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
             nfs::wcc_data::default().serialize(output)?;
+            error!("Directory does not exist");
             return Ok(());
         }
     };
+
     let mut target_attributes = nfs::sattr3::default();
 
     match createhow {
@@ -237,26 +215,9 @@ pub async fn nfsproc3_create(
             debug!("create unchecked {:?}", target_attributes);
         }
         createmode3::GUARDED => {
+            // A name that exists is refused by create itself, in the same change
             target_attributes.deserialize(input)?;
             debug!("create guarded {:?}", target_attributes);
-            if context.vfs.lookup(dirid, &dirops.name).await.is_ok() {
-                // file exists. Fail with NFS3ERR_EXIST.
-                // Re-read dir attributes
-                // for post op attr
-                let post_dir_attr = match context.vfs.getattr(dirid).await {
-                    Ok(v) => nfs::post_op_attr::attributes(v),
-                    Err(_) => nfs::post_op_attr::Void,
-                };
-
-                make_success_reply(xid).serialize(output)?;
-                nfs::nfsstat3::NFS3ERR_EXIST.serialize(output)?;
-                nfs::wcc_data {
-                    before: pre_dir_attr,
-                    after: post_dir_attr,
-                }
-                .serialize(output)?;
-                return Ok(());
-            }
         }
         createmode3::EXCLUSIVE => {
             debug!("create exclusive");
@@ -265,17 +226,18 @@ pub async fn nfsproc3_create(
 
     let fid: Result<nfs::fileid3, nfs::nfsstat3>;
     let postopattr: nfs::post_op_attr;
+    let wcc_res: nfs::wcc_data;
     // fill in the fid and post op attr here
     if matches!(createhow, createmode3::EXCLUSIVE) {
         // the API for exclusive is very slightly different
         // We are not returning a post op attribute
-        fid = context.vfs.create_exclusive(dirid, &dirops.name).await;
+        (fid, wcc_res) = context.vfs.create_exclusive_wcc(dirid, &dirops.name).await;
         postopattr = nfs::post_op_attr::Void;
     } else {
         // create!
-        let res = context
+        let (res, wcc) = context
             .vfs
-            .create(dirid, &dirops.name, target_attributes)
+            .create_wcc(dirid, &dirops.name, target_attributes)
             .await;
         fid = res.map(|x| x.0);
         postopattr = if let Ok((_, fattr)) = res {
@@ -283,18 +245,9 @@ pub async fn nfsproc3_create(
         } else {
             nfs::post_op_attr::Void
         };
+        wcc_res = wcc;
     }
 
-    // Re-read dir attributes for post op attr
-    let post_dir_attr = match context.vfs.getattr(dirid).await {
-        Ok(v) => nfs::post_op_attr::attributes(v),
-        Err(_) => nfs::post_op_attr::Void,
-    };
-    let wcc_res = nfs::wcc_data {
-        before: pre_dir_attr,
-        after: post_dir_attr,
-    };
-
     match fid {
         Ok(fid) => {
             debug!("create success --> {:?}, {:?}", fid, postopattr);
@@ -332,48 +285,20 @@ pub async fn nfsproc3_remove(
     debug!("nfsproc3_remove({:?}, {:?}) ", xid, dirops);
 
     // find the directory with the file
-    let dirid = context.vfs.fh_to_id(&dirops.dir);
-    if let Err(stat) = dirid {
-        // directory does not exist
-        make_success_reply(xid).serialize(output)?;
-        stat.serialize(output)?;
-        nfs::wcc_data::default().serialize(output)?;
-        error!("Directory does not exist");
-        return Ok(());
-    }
-    let dirid = dirid.unwrap();
-
-    // get the object attributes before the write
-    let pre_dir_attr = match context.vfs.getattr(dirid).await {
-        Ok(v) => {
-            let wccattr = nfs::wcc_attr {
-                size: v.size,
-                mtime: v.mtime,
-                ctime: v.ctime,
-            };
-            nfs::pre_op_attr::attributes(wccattr)
-        }
+    let dirid = match context.vfs.fh_to_id(&dirops.dir).await {
+        Ok(dirid) => dirid,
         Err(stat) => {
-            error!("Cannot stat directory");
+            // directory does not exist
             make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
             nfs::wcc_data::default().serialize(output)?;
+            error!("Directory does not exist");
             return Ok(());
         }
     };
 
     // delete!
-    let res = context.vfs.remove(dirid, &dirops.name).await;
-
-    // Re-read dir attributes for post op attr
-    let post_dir_attr = match context.vfs.getattr(dirid).await {
-        Ok(v) => nfs::post_op_attr::attributes(v),
-        Err(_) => nfs::post_op_attr::Void,
-    };
-    let wcc_res = nfs::wcc_data {
-        before: pre_dir_attr,
-        after: post_dir_attr,
-    };
+    let (res, wcc_res) = context.vfs.remove_wcc(dirid, &dirops.name).await;
 
     match res {
         Ok(()) => {
@@ -415,94 +340,38 @@ pub async fn nfsproc3_rename(
     );
 
     // find the from directory
-    let from_dirid = context.vfs.fh_to_id(&fromdirops.dir);
-    if let Err(stat) = from_dirid {
-        // directory does not exist
-        make_success_reply(xid).serialize(output)?;
-        stat.serialize(output)?;
-        nfs::wcc_data::default().serialize(output)?;
-        error!("Directory does not exist");
-        return Ok(());
-    }
-
-    // find the to directory
-    let to_dirid = context.vfs.fh_to_id(&todirops.dir);
-    if let Err(stat) = to_dirid {
-        // directory does not exist
-        make_success_reply(xid).serialize(output)?;
-        stat.serialize(output)?;
-        nfs::wcc_data::default().serialize(output)?;
-        error!("Directory does not exist");
-        return Ok(());
-    }
-
-    // found the directory, get the attributes
-    let from_dirid = from_dirid.unwrap();
-    let to_dirid = to_dirid.unwrap();
-
-    // get the object attributes before the write
-    let pre_from_dir_attr = match context.vfs.getattr(from_dirid).await {
-        Ok(v) => {
-            let wccattr = nfs::wcc_attr {
-                size: v.size,
-                mtime: v.mtime,
-                ctime: v.ctime,
-            };
-            nfs::pre_op_attr::attributes(wccattr)
-        }
+    let from_dirid = match context.vfs.fh_to_id(&fromdirops.dir).await {
+        Ok(from_dirid) => from_dirid,
         Err(stat) => {
-            error!("Cannot stat directory");
+            // directory does not exist
             make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
             nfs::wcc_data::default().serialize(output)?;
+            error!("Directory does not exist");
             return Ok(());
         }
     };
 
-    // get the object attributes before the write
-    let pre_to_dir_attr = match context.vfs.getattr(to_dirid).await {
-        Ok(v) => {
-            let wccattr = nfs::wcc_attr {
-                size: v.size,
-                mtime: v.mtime,
-                ctime: v.ctime,
-            };
-            nfs::pre_op_attr::attributes(wccattr)
-        }
+    // find the to directory
+    let to_dirid = match context.vfs.fh_to_id(&todirops.dir).await {
+        Ok(to_dirid) => to_dirid,
         Err(stat) => {
-            error!("Cannot stat directory");
+            // directory does not exist
             make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
             nfs::wcc_data::default().serialize(output)?;
+            error!("Directory does not exist");
             return Ok(());
         }
     };
 
+
     // rename!
-    let res = context
+    let (res, from_wcc_res, to_wcc_res) = context
         .vfs
-        .rename(from_dirid, &fromdirops.name, to_dirid, &todirops.name)
+        .rename_wcc(from_dirid, &fromdirops.name, to_dirid, &todirops.name)
         .await;
 
-    // Re-read dir attributes for post op attr
-    let post_from_dir_attr = match context.vfs.getattr(from_dirid).await {
-        Ok(v) => nfs::post_op_attr::attributes(v),
-        Err(_) => nfs::post_op_attr::Void,
-    };
-    let post_to_dir_attr = match context.vfs.getattr(to_dirid).await {
-        Ok(v) => nfs::post_op_attr::attributes(v),
-        Err(_) => nfs::post_op_attr::Void,
-    };
-    let from_wcc_res = nfs::wcc_data {
-        before: pre_from_dir_attr,
-        after: post_from_dir_attr,
-    };
-
-    let to_wcc_res = nfs::wcc_data {
-        before: pre_to_dir_attr,
-        after: post_to_dir_attr,
-    };
-
     match res {
         Ok(()) => {
             debug!("rename success");
@@ -522,4 +391,77 @@ pub async fn nfsproc3_rename(
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+pub async fn nfsproc3_mknod(
+    xid: u32,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let mut dirops = nfs::diropargs3::default();
+    dirops.deserialize(input)?;
+    let mut ftype = nfs::ftype3::default();
+    ftype.deserialize(input)?;
+
+    // Devices carry their numbers as well as attributes, sockets and pipes only attributes,
+    // and the types MKNOD does not make nothing
+    let mut attributes = nfs::sattr3::default();
+    let mut rdev = nfs::specdata3::default();
+    match ftype {
+        nfs::ftype3::NF3CHR | nfs::ftype3::NF3BLK => {
+            let mut device = nfs::devicedata3::default();
+            device.deserialize(input)?;
+            attributes = device.dev_attributes;
+            rdev = device.spec;
+        }
+        nfs::ftype3::NF3SOCK | nfs::ftype3::NF3FIFO => attributes.deserialize(input)?,
+        _ => {}
+    }
+
+    debug!("nfsproc3_mknod({:?}, {:?}, {:?}, {:?}) ", xid, dirops, ftype, rdev);
+
+    let dirid = match context.vfs.fh_to_id(&dirops.dir).await {
+        Ok(dirid) => dirid,
+        Err(stat) => {
+            make_success_reply(xid).serialize(output)?;
+            stat.serialize(output)?;
+            nfs::wcc_data::default().serialize(output)?;
+            error!("Directory does not exist");
+            return Ok(());
+        }
+    };
+
+    let (res, wcc_res) = match ftype {
+        nfs::ftype3::NF3CHR | nfs::ftype3::NF3BLK | nfs::ftype3::NF3SOCK | nfs::ftype3::NF3FIFO => {
+            context.vfs.mknod_wcc(dirid, &dirops.name, ftype, &attributes, rdev).await
+        }
+        _ => {
+            // Nothing changes, the directory is as it was
+            let dir_attr = context.vfs.getattr(dirid).await.ok();
+            (Err(nfs::nfsstat3::NFS3ERR_BADTYPE), nfs::wcc_data::new(dir_attr, dir_attr))
+        }
+    };
+
+    match res {
+        Ok((fid, fattr)) => {
+            debug!("mknod success --> {:?}, {:?}", fid, fattr);
+            make_success_reply(xid).serialize(output)?;
+            nfs::nfsstat3::NFS3_OK.serialize(output)?;
+            // serialize MKNOD3resok
+            let fh = context.vfs.id_to_fh(fid);
+            nfs::post_op_fh3::handle(fh).serialize(output)?;
+            nfs::post_op_attr::attributes(fattr).serialize(output)?;
+            wcc_res.serialize(output)?;
+        }
+        Err(e) => {
+            debug!("mknod error --> {:?}", e);
+            // serialize MKNOD3resfail
+            make_success_reply(xid).serialize(output)?;
+            e.serialize(output)?;
+            wcc_res.serialize(output)?;
+        }
+    }
+
+    Ok(())
+}
@@ -3,8 +3,9 @@
 use crate::kernel::protocol::context::RPCContext;
 use crate::kernel::api::nfs;
 use crate::kernel::protocol::rpc::*;
-use crate::kernel::vfs::api::VFSCapabilities;
+use crate::kernel::vfs::api::{FsStat, VFSCapabilities};
 use crate::kernel::protocol::xdr::*;
+use crate::kernel::protocol::udp::transfer_size;
 use std::io::{Read, Write};
 use tracing::debug;
 
@@ -53,31 +54,43 @@ pub async fn nfsproc3_fsinfo(
     handle.deserialize(input)?;
     debug!("nfsproc3_fsinfo({:?},{:?}) ", xid, handle);
 
-    let id = context.vfs.fh_to_id(&handle);
     // fail if unable to convert file handle
-    if let Err(stat) = id {
-        make_success_reply(xid).serialize(output)?;
-        stat.serialize(output)?;
-        nfs::post_op_attr::Void.serialize(output)?;
-        return Ok(());
-    }
-    let id = id.unwrap();
+    let id = match context.vfs.fh_to_id(&handle).await {
+        Ok(id) => id,
+        Err(stat) => {
+            make_success_reply(xid).serialize(output)?;
+            stat.serialize(output)?;
+            nfs::post_op_attr::Void.serialize(output)?;
+            return Ok(());
+        }
+    };
     //println!("nfsproc3_fsinfo-before");
     let dir_attr = match context.vfs.getattr(id).await {
         Ok(v) => nfs::post_op_attr::attributes(v),
         Err(_) => nfs::post_op_attr::Void,
     };
+    // The largest file is as large as the caller's quota allows
+    let user = context.user_name(context.vfs.data_store()).await;
+    let max_file_size = match context.vfs.fsstat(id, user.as_deref()).await {
+        Ok(stat) => stat.max_file_size,
+        Err(_) => FsStat::default().max_file_size,
+    };
 
+    // Over UDP every READ reply and WRITE call has to fit in a datagram
+    let (max, pref) = match context.max_reply {
+        Some(max_datagram) => (transfer_size(max_datagram), transfer_size(max_datagram)),
+        None => (1024 * 1024, 1024 * 124),
+    };
     let res = FSINFO3resok {
         obj_attributes: dir_attr,
-        rtmax: 1024 * 1024,
-        rtpref: 1024 * 124,
-        rtmult: 1024 * 1024,
-        wtmax: 1024 * 1024,
-        wtpref: 1024 * 1024,
-        wtmult: 1024 * 1024,
-        dtpref: 1024 * 1024,
-        maxfilesize: 128 * 1024 * 1024 * 1024,
+        rtmax: max,
+        rtpref: pref,
+        rtmult: max,
+        wtmax: max,
+        wtpref: max,
+        wtmult: max,
+        dtpref: max,
+        maxfilesize: max_file_size,
         time_delta: nfs::nfstime3 {
             seconds: 0,
             nseconds: 1000000,
@@ -127,23 +140,24 @@ pub async fn nfsproc3_access(
 
     debug!("ACCESS: Processing request with handle:{:?}, access:{:#x}", handle, access);
 
-    let id = context.vfs.fh_to_id(&handle);
     // fail if unable to convert file handle
-    if let Err(stat) = id {
-        make_success_reply(xid).serialize(output)?;
-        stat.serialize(output)?;
-        nfs::post_op_attr::Void.serialize(output)?;
-        return Ok(());
-    }
-    let id = id.unwrap();
+    let id = match context.vfs.fh_to_id(&handle).await {
+        Ok(id) => id,
+        Err(stat) => {
+            make_success_reply(xid).serialize(output)?;
+            stat.serialize(output)?;
+            nfs::post_op_attr::Void.serialize(output)?;
+            return Ok(());
+        }
+    };
 
     let obj_attr = match context.vfs.getattr(id).await {
         Ok(v) => nfs::post_op_attr::attributes(v),
         Err(_) => nfs::post_op_attr::Void,
     };
-    // TODO better checks here
+    // A read-only file system grants nothing that would change it
     if !matches!(context.vfs.capabilities(), VFSCapabilities::ReadWrite) {
-        access &= ACCESS3_READ | ACCESS3_LOOKUP;
+        access &= ACCESS3_READ | ACCESS3_LOOKUP | ACCESS3_EXECUTE;
     }
     debug!(" {:?} ---> {:?}", xid, access);
     make_success_reply(xid).serialize(output)?;
@@ -155,14 +169,14 @@ pub async fn nfsproc3_access(
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Default)]
-struct PATHCONF3resok {
-    obj_attributes: nfs::post_op_attr,
-    linkmax: u32,
-    name_max: u32,
-    no_trunc: bool,
-    chown_restricted: bool,
-    case_insensitive: bool,
-    case_preserving: bool,
+pub struct PATHCONF3resok {
+    pub obj_attributes: nfs::post_op_attr,
+    pub linkmax: u32,
+    pub name_max: u32,
+    pub no_trunc: bool,
+    pub chown_restricted: bool,
+    pub case_insensitive: bool,
+    pub case_preserving: bool,
 }
 XDRStruct!(
     PATHCONF3resok,
@@ -185,15 +199,16 @@ pub async fn nfsproc3_pathconf(
     handle.deserialize(input)?;
     debug!("nfsproc3_pathconf({:?},{:?})", xid, handle);
 
-    let id = context.vfs.fh_to_id(&handle);
     // fail if unable to convert file handle
-    if let Err(stat) = id {
-        make_success_reply(xid).serialize(output)?;
-        stat.serialize(output)?;
-        nfs::post_op_attr::Void.serialize(output)?;
-        return Ok(());
-    }
-    let id = id.unwrap();
+    let id = match context.vfs.fh_to_id(&handle).await {
+        Ok(id) => id,
+        Err(stat) => {
+            make_success_reply(xid).serialize(output)?;
+            stat.serialize(output)?;
+            nfs::post_op_attr::Void.serialize(output)?;
+            return Ok(());
+        }
+    };
 
     let obj_attr = match context.vfs.getattr(id).await {
         Ok(v) => nfs::post_op_attr::attributes(v),
@@ -217,15 +232,15 @@ pub async fn nfsproc3_pathconf(
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Default)]
-struct FSSTAT3resok {
-    obj_attributes: nfs::post_op_attr,
-    tbytes: nfs::size3,
-    fbytes: nfs::size3,
-    abytes: nfs::size3,
-    tfiles: nfs::size3,
-    ffiles: nfs::size3,
-    afiles: nfs::size3,
-    invarsec: u32,
+pub struct FSSTAT3resok {
+    pub obj_attributes: nfs::post_op_attr,
+    pub tbytes: nfs::size3,
+    pub fbytes: nfs::size3,
+    pub abytes: nfs::size3,
+    pub tfiles: nfs::size3,
+    pub ffiles: nfs::size3,
+    pub afiles: nfs::size3,
+    pub invarsec: u32,
 }
 XDRStruct!(
     FSSTAT3resok,
@@ -249,29 +264,42 @@ pub async fn nfsproc3_fsstat(
     let mut handle = nfs::nfs_fh3::default();
     handle.deserialize(input)?;
     debug!("nfsproc3_fsstat({:?},{:?}) ", xid, handle);
-    let id = context.vfs.fh_to_id(&handle);
     // fail if unable to convert file handle
-    if let Err(stat) = id {
-        make_success_reply(xid).serialize(output)?;
-        stat.serialize(output)?;
-        nfs::post_op_attr::Void.serialize(output)?;
-        return Ok(());
-    }
-    let id = id.unwrap();
+    let id = match context.vfs.fh_to_id(&handle).await {
+        Ok(id) => id,
+        Err(stat) => {
+            make_success_reply(xid).serialize(output)?;
+            stat.serialize(output)?;
+            nfs::post_op_attr::Void.serialize(output)?;
+            return Ok(());
+        }
+    };
 
     let obj_attr = match context.vfs.getattr(id).await {
         Ok(v) => nfs::post_op_attr::attributes(v),
         Err(_) => nfs::post_op_attr::Void,
     };
+    // Figures of the caller's quota, or of the whole file system for callers it cannot name
+    let user = context.user_name(context.vfs.data_store()).await;
+    let stat = match context.vfs.fsstat(id, user.as_deref()).await {
+        Ok(stat) => stat,
+        Err(stat) => {
+            make_success_reply(xid).serialize(output)?;
+            stat.serialize(output)?;
+            obj_attr.serialize(output)?;
+            return Ok(());
+        }
+    };
     let res = FSSTAT3resok {
         obj_attributes: obj_attr,
-        tbytes: 1024 * 1024 * 1024 * 1024,
-        fbytes: 1024 * 1024 * 1024 * 1024,
-        abytes: 1024 * 1024 * 1024 * 1024,
-        tfiles: 1024 * 1024 * 1024,
-        ffiles: 1024 * 1024 * 1024,
-        afiles: 1024 * 1024 * 1024,
-        invarsec: u32::MAX,
+        tbytes: stat.total_bytes,
+        fbytes: stat.free_bytes,
+        abytes: stat.avail_bytes,
+        tfiles: stat.total_files,
+        ffiles: stat.free_files,
+        afiles: stat.avail_files,
+        // Usage changes with every write
+        invarsec: 0,
     };
     make_success_reply(xid).serialize(output)?;
     nfs::nfsstat3::NFS3_OK.serialize(output)?;
@@ -7,9 +7,9 @@ use crate::kernel::protocol::xdr::*;
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Default)]
-struct SYMLINK3args {
-    dirops: nfs::diropargs3,
-    symlink: nfs::symlinkdata3,
+pub struct SYMLINK3args {
+    pub dirops: nfs::diropargs3,
+    pub symlink: nfs::symlinkdata3,
 }
 XDRStruct!(SYMLINK3args, dirops, symlink);
 
@@ -27,40 +27,21 @@ pub async fn nfsproc3_symlink(
 
     // find the directory we are supposed to create the
     // new file in
-    let dirid = context.vfs.fh_to_id(&args.dirops.dir);
-    if let Err(stat) = dirid {
-        // directory does not exist
-        make_success_reply(xid).serialize(output)?;
-        stat.serialize(output)?;
-        nfs::wcc_data::default().serialize(output)?;
-        error!("Directory does not exist");
-        return Ok(());
-    }
-    // found the directory, get the attributes
-    let dirid = dirid.unwrap();
-
-    // get the object attributes before the write
-    let pre_dir_attr = match context.vfs.getattr(dirid).await {
-        Ok(v) => {
-            let wccattr = nfs::wcc_attr {
-                size: v.size,
-                mtime: v.mtime,
-                ctime: v.ctime,
-            };
-            nfs::pre_op_attr::attributes(wccattr)
-        }
+    let dirid = match context.vfs.fh_to_id(&args.dirops.dir).await {
+        Ok(dirid) => dirid,
         Err(stat) => {
-            error!("Cannot stat directory");
+            // directory does not exist
             make_success_reply(xid).serialize(output)?;
             stat.serialize(output)?;
             nfs::wcc_data::default().serialize(output)?;
+            error!("Directory does not exist");
             return Ok(());
         }
     };
 
-    let res = context
+    let (res, wcc_res) = context
         .vfs
-        .symlink(
+        .symlink_wcc(
             dirid,
             &args.dirops.name,
             &args.symlink.symlink_data,
@@ -68,16 +49,6 @@ pub async fn nfsproc3_symlink(
         )
         .await;
 
-    // Re-read dir attributes for post op attr
-    let post_dir_attr = match context.vfs.getattr(dirid).await {
-        Ok(v) => nfs::post_op_attr::attributes(v),
-        Err(_) => nfs::post_op_attr::Void,
-    };
-    let wcc_res = nfs::wcc_data {
-        before: pre_dir_attr,
-        after: post_dir_attr,
-    };
-
     match res {
         Ok((fid, fattr)) => {
             debug!("symlink success --> {:?}, {:?}", fid, fattr);
@@ -112,14 +83,15 @@ pub async fn nfsproc3_readlink(
     handle.deserialize(input)?;
     debug!("nfsproc3_readlink({:?},{:?}) ", xid, handle);
 
-    let id = context.vfs.fh_to_id(&handle);
     // fail if unable to convert file handle
-    if let Err(stat) = id {
-        make_success_reply(xid).serialize(output)?;
-        stat.serialize(output)?;
-        return Ok(());
-    }
-    let id = id.unwrap();
+    let id = match context.vfs.fh_to_id(&handle).await {
+        Ok(id) => id,
+        Err(stat) => {
+            make_success_reply(xid).serialize(output)?;
+            stat.serialize(output)?;
+            return Ok(());
+        }
+    };
     // if the id does not exist, we fail
     let symlink_attr = match context.vfs.getattr(id).await {
         Ok(v) => nfs::post_op_attr::attributes(v),
@@ -1,6 +1,9 @@
 use crate::kernel::protocol::context::RPCContext;
+use crate::kernel::protocol::rpcbind::RpcbindRegistry;
 use crate::kernel::vfs::mock::MockNFSFileSystem;
-use crate::kernel::protocol::rpc::auth_unix;
+use crate::kernel::vfs::exports::ExportTable;
+use crate::kernel::vfs::locks::LockManager;
+use crate::kernel::protocol::rpc::NonceCache;
 use std::sync::Arc;
 
 //use std::io::Cursor;
@@ -12,12 +15,21 @@ async fn test_nfsproc3_create_readonly() {
     //let mut output = Cursor::new(Vec::new());
     
     // Create mock filesystem with readonly capability
-    let mock_fs = MockNFSFileSystem::new_readonly();
+    let mock_fs = Arc::new(MockNFSFileSystem::new_readonly());
     let _context = RPCContext {
         local_port: 2049,
         client_addr: "127.0.0.1".to_string(),
-        auth: auth_unix::default(),
-        vfs: Arc::new(mock_fs),
+        auth: None,
+        caller: None,
+        nonces: Arc::new(NonceCache::default()),
+        tls: None,
+        rpcbind: Arc::new(RpcbindRegistry::default()),
+        drc: None,
+        max_reply: None,
+        locks: Arc::new(LockManager::default()),
+        nfs4: None,
+        vfs: mock_fs.clone(),
+        exports: Arc::new(ExportTable::single(mock_fs)),
         mount_signal: None
     };
 
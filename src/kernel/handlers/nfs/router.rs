@@ -2,6 +2,7 @@
 #![allow(dead_code)]
 use crate::kernel::protocol::context::RPCContext;
 use crate::kernel::api::nfs;
+use crate::kernel::api::nfs4;
 use crate::kernel::protocol::rpc::*;
 use crate::kernel::protocol::xdr::*;
 use num_derive::{FromPrimitive, ToPrimitive};
@@ -60,7 +61,10 @@ pub async fn handle_nfs(
             call.vers,
             nfs::VERSION
         );
-        prog_mismatch_reply_message(xid, nfs::VERSION).serialize(output)?;
+        match context.nfs4 {
+            Some(_) => prog_versions_mismatch_reply_message(xid, nfs::VERSION, nfs4::VERSION).serialize(output)?,
+            None => prog_mismatch_reply_message(xid, nfs::VERSION).serialize(output)?,
+        }
         return Ok(());
     }
     let prog = NFSProgram::from_u32(call.proc).unwrap_or(NFSProgram::INVALID);
@@ -74,11 +78,25 @@ pub async fn handle_nfs(
         NFSProgram::NFSPROC3_RMDIR |
         NFSProgram::NFSPROC3_RENAME |
         NFSProgram::NFSPROC3_MKDIR |
-        NFSProgram::NFSPROC3_SYMLINK => {
+        NFSProgram::NFSPROC3_SYMLINK |
+        NFSProgram::NFSPROC3_MKNOD => {
             if !matches!(context.vfs.capabilities(), VFSCapabilities::ReadWrite) {
+                // Each of these starts with the handle of the object or directory it changes
+                let mut handle = nfs::nfs_fh3::default();
+                let id = match handle.deserialize(input) {
+                    Ok(()) => context.vfs.fh_to_id(&handle).await.ok(),
+                    Err(_) => None,
+                };
+                let operation = format!("{:?}", prog).trim_start_matches("NFSPROC3_").to_lowercase();
+                context.vfs.write_denied(id, &operation).await;
+
                 make_success_reply(xid).serialize(output)?;
                 nfs::nfsstat3::NFS3ERR_ROFS.serialize(output)?;
                 nfs::wcc_data::default().serialize(output)?;
+                if matches!(prog, NFSProgram::NFSPROC3_RENAME) {
+                    // to directory
+                    nfs::wcc_data::default().serialize(output)?;
+                }
                 return Ok(());
             }
         }
@@ -106,11 +124,11 @@ pub async fn handle_nfs(
         NFSProgram::NFSPROC3_MKDIR => nfsproc3_mkdir(xid, input, output, context).await?,
         NFSProgram::NFSPROC3_SYMLINK => nfsproc3_symlink(xid, input, output, context).await?,
         NFSProgram::NFSPROC3_READLINK => nfsproc3_readlink(xid, input, output, context).await?,
+        NFSProgram::NFSPROC3_MKNOD => nfsproc3_mknod(xid, input, output, context).await?,
         _ => {
             //warn!("Unimplemented message {:?}", prog);
             proc_unavail_reply_message(xid).serialize(output)?;
         } /*
-          NFSPROC3_MKNOD,
           NFSPROC3_LINK,
           NFSPROC3_READDIR,
           NFSPROC3_COMMIT,
@@ -0,0 +1,327 @@
+// NFSv4 attributes, which clients ask for by bitmap and which come back encoded one after the
+// other in the order of their numbers. Those the server supports are made from the NFSv3
+// attributes of a file, and from the limits NFSv3 reports by FSINFO and FSSTAT.
+use super::compound::{Compound, put};
+use super::pseudo::{self, Fh};
+use crate::kernel::api::nfs::*;
+use crate::kernel::api::nfs4::*;
+use crate::kernel::protocol::udp::transfer_size;
+use crate::kernel::protocol::xdr::*;
+use crate::kernel::vfs::api::FsStat;
+use std::io::Cursor;
+
+// The attributes GETATTR and READDIR report
+const SUPPORTED: &[u32] = &[
+    FATTR4_SUPPORTED_ATTRS,
+    FATTR4_TYPE,
+    FATTR4_FH_EXPIRE_TYPE,
+    FATTR4_CHANGE,
+    FATTR4_SIZE,
+    FATTR4_LINK_SUPPORT,
+    FATTR4_SYMLINK_SUPPORT,
+    FATTR4_NAMED_ATTR,
+    FATTR4_FSID,
+    FATTR4_UNIQUE_HANDLES,
+    FATTR4_LEASE_TIME,
+    FATTR4_RDATTR_ERROR,
+    FATTR4_CANSETTIME,
+    FATTR4_CASE_INSENSITIVE,
+    FATTR4_CASE_PRESERVING,
+    FATTR4_CHOWN_RESTRICTED,
+    FATTR4_FILEHANDLE,
+    FATTR4_FILEID,
+    FATTR4_FILES_AVAIL,
+    FATTR4_FILES_FREE,
+    FATTR4_FILES_TOTAL,
+    FATTR4_HOMOGENEOUS,
+    FATTR4_MAXFILESIZE,
+    FATTR4_MAXLINK,
+    FATTR4_MAXNAME,
+    FATTR4_MAXREAD,
+    FATTR4_MAXWRITE,
+    FATTR4_MODE,
+    FATTR4_NO_TRUNC,
+    FATTR4_NUMLINKS,
+    FATTR4_OWNER,
+    FATTR4_OWNER_GROUP,
+    FATTR4_RAWDEV,
+    FATTR4_SPACE_AVAIL,
+    FATTR4_SPACE_FREE,
+    FATTR4_SPACE_TOTAL,
+    FATTR4_SPACE_USED,
+    FATTR4_TIME_ACCESS,
+    FATTR4_TIME_ACCESS_SET,
+    FATTR4_TIME_DELTA,
+    FATTR4_TIME_METADATA,
+    FATTR4_TIME_MODIFY,
+    FATTR4_TIME_MODIFY_SET,
+    FATTR4_MOUNTED_ON_FILEID,
+    FATTR4_SUPPATTR_EXCLCREAT,
+];
+
+// The attributes made from what FSSTAT gives NFSv3 clients, which take the file system's usage
+const SPACE_ATTRS: &[u32] = &[
+    FATTR4_FILES_AVAIL,
+    FATTR4_FILES_FREE,
+    FATTR4_FILES_TOTAL,
+    FATTR4_MAXFILESIZE,
+    FATTR4_SPACE_AVAIL,
+    FATTR4_SPACE_FREE,
+    FATTR4_SPACE_TOTAL,
+];
+
+pub(super) fn bitmap(attrs: &[u32]) -> bitmap4 {
+    let mut bitmap = Vec::new();
+    for &attr in attrs {
+        let word = (attr / 32) as usize;
+        if bitmap.len() <= word {
+            bitmap.resize(word + 1, 0);
+        }
+        bitmap[word] |= 1 << (attr % 32);
+    }
+    bitmap
+}
+
+// The attributes of a bitmap, in order
+pub(super) fn attrs_of(bitmap: &bitmap4) -> Vec<u32> {
+    let mut attrs = Vec::new();
+    for (word, bits) in bitmap.iter().enumerate() {
+        for bit in 0..32 {
+            if bits & (1 << bit) != 0 {
+                attrs.push(word as u32 * 32 + bit);
+            }
+        }
+    }
+    attrs
+}
+
+// What the attributes of a file are made from
+pub(super) struct FileAttrs {
+    pub attr: fattr3,
+    pub fsid: fsid4,
+    pub fh: nfs_fh4,
+    // The fileid of the directory an export's root is mounted on, else the file's own
+    pub mounted_on: fileid3,
+    // The space and files of the file system, as the caller sees them
+    pub space: FsStat,
+}
+
+impl FileAttrs {
+    // The file's attributes, with the caller's figures of its file system when requested asks
+    // for any
+    pub async fn of(c: &Compound<'_>, fh: &Fh, requested: &bitmap4) -> Result<FileAttrs, nfsstat4> {
+        match fh {
+            Fh::Pseudo(path) => Ok(FileAttrs::pseudo(path)),
+            Fh::Export { vfs, id, .. } => {
+                let attr = vfs.getattr(*id).await.map_err(pseudo::status)?;
+                let mut file = FileAttrs::entry(c, fh, attr);
+                if attrs_of(requested).iter().any(|attr| SPACE_ATTRS.contains(attr)) {
+                    let user = c.context.user_name(vfs.data_store()).await;
+                    file.space = vfs.fsstat(*id, user.as_deref()).await.map_err(pseudo::status)?;
+                }
+                Ok(file)
+            }
+        }
+    }
+
+    // A pseudo directory: read-only, owned by root and never changing
+    pub fn pseudo(path: &str) -> FileAttrs {
+        let fileid = pseudo::pseudo_fileid(path);
+        let attr = fattr3 { ftype: ftype3::NF3DIR, mode: 0o555, nlink: 2, fileid, ..Default::default() };
+        FileAttrs { attr, fsid: fsid4::default(), fh: Fh::Pseudo(path.to_string()).to_bytes(), mounted_on: fileid, space: FsStat::default() }
+    }
+
+    // A file of an export with the attributes its file system gave
+    pub fn entry(c: &Compound<'_>, fh: &Fh, attr: fattr3) -> FileAttrs {
+        let (export, export_root) = match fh {
+            Fh::Export { export, export_root, .. } => (*export, *export_root),
+            Fh::Pseudo(_) => (0, 0),
+        };
+        let mounted_on = match c.context.exports.get(export) {
+            Some(junction) if attr.fileid == export_root => pseudo::pseudo_fileid(&junction.path),
+            _ => attr.fileid,
+        };
+        // Exports of one store share its fsid, so the export's index tells them apart
+        let fsid = fsid4 { major: attr.fsid, minor: export as u64 + 1 };
+        FileAttrs { attr, fsid, fh: fh.to_bytes(), mounted_on, space: FsStat::default() }
+    }
+}
+
+fn ftype(ftype: ftype3) -> nfs_ftype4 {
+    match ftype {
+        ftype3::NF3REG => nfs_ftype4::NF4REG,
+        ftype3::NF3DIR => nfs_ftype4::NF4DIR,
+        ftype3::NF3BLK => nfs_ftype4::NF4BLK,
+        ftype3::NF3CHR => nfs_ftype4::NF4CHR,
+        ftype3::NF3LNK => nfs_ftype4::NF4LNK,
+        ftype3::NF3SOCK => nfs_ftype4::NF4SOCK,
+        ftype3::NF3FIFO => nfs_ftype4::NF4FIFO,
+    }
+}
+
+pub(super) fn is_dir(attr: &fattr3) -> bool {
+    matches!(attr.ftype, ftype3::NF3DIR)
+}
+
+fn time(time: nfstime3) -> nfstime4 {
+    nfstime4 { seconds: time.seconds as i64, nseconds: time.nseconds }
+}
+
+// The change attribute: the later of the file's modify and change times
+pub(super) fn change_id(attr: &fattr3) -> changeid4 {
+    let latest = std::cmp::max((attr.mtime.seconds, attr.mtime.nseconds), (attr.ctime.seconds, attr.ctime.nseconds));
+    ((latest.0 as u64) << 32) | latest.1 as u64
+}
+
+// Encodes the attributes of a bitmap the server supports
+pub(super) fn encode(c: &Compound<'_>, file: &FileAttrs, requested: &bitmap4) -> fattr4 {
+    let max_transfer = c.context.max_reply.map(transfer_size).unwrap_or(1024 * 1024) as u64;
+    let attr = &file.attr;
+    let mut vals = Vec::new();
+    let mut encoded = Vec::new();
+    for requested in attrs_of(requested) {
+        if !SUPPORTED.contains(&requested) {
+            continue;
+        }
+        encoded.push(requested);
+        match requested {
+            FATTR4_SUPPORTED_ATTRS => put(&mut vals, &bitmap(SUPPORTED)),
+            FATTR4_TYPE => put(&mut vals, &ftype(attr.ftype)),
+            FATTR4_FH_EXPIRE_TYPE => put(&mut vals, &FH4_PERSISTENT),
+            FATTR4_CHANGE => put(&mut vals, &change_id(attr)),
+            FATTR4_SIZE => put(&mut vals, &attr.size),
+            FATTR4_LINK_SUPPORT | FATTR4_NAMED_ATTR | FATTR4_CASE_INSENSITIVE => put(&mut vals, &false),
+            FATTR4_SYMLINK_SUPPORT | FATTR4_UNIQUE_HANDLES | FATTR4_CANSETTIME | FATTR4_CASE_PRESERVING
+            | FATTR4_CHOWN_RESTRICTED | FATTR4_HOMOGENEOUS | FATTR4_NO_TRUNC => put(&mut vals, &true),
+            FATTR4_FSID => put(&mut vals, &file.fsid),
+            FATTR4_LEASE_TIME => put(&mut vals, &(c.state.lease_time().as_secs() as u32)),
+            FATTR4_RDATTR_ERROR => put(&mut vals, &nfsstat4::NFS4_OK),
+            FATTR4_FILEHANDLE => put(&mut vals, &file.fh),
+            FATTR4_FILEID => put(&mut vals, &attr.fileid),
+            FATTR4_FILES_AVAIL => put(&mut vals, &file.space.avail_files),
+            FATTR4_FILES_FREE => put(&mut vals, &file.space.free_files),
+            FATTR4_FILES_TOTAL => put(&mut vals, &file.space.total_files),
+            FATTR4_MAXFILESIZE => put(&mut vals, &file.space.max_file_size),
+            FATTR4_MAXLINK => put(&mut vals, &1_u32),
+            FATTR4_MAXNAME => put(&mut vals, &255_u32),
+            FATTR4_MAXREAD | FATTR4_MAXWRITE => put(&mut vals, &max_transfer),
+            FATTR4_MODE => put(&mut vals, &(attr.mode & 0o7777)),
+            FATTR4_NUMLINKS => put(&mut vals, &attr.nlink),
+            // Owners go by number, as clients without an id mapping send them
+            FATTR4_OWNER => put(&mut vals, &attr.uid.to_string().into_bytes()),
+            FATTR4_OWNER_GROUP => put(&mut vals, &attr.gid.to_string().into_bytes()),
+            FATTR4_RAWDEV => put(&mut vals, &specdata4 { specdata1: attr.rdev.specdata1, specdata2: attr.rdev.specdata2 }),
+            FATTR4_SPACE_AVAIL => put(&mut vals, &file.space.avail_bytes),
+            FATTR4_SPACE_FREE => put(&mut vals, &file.space.free_bytes),
+            FATTR4_SPACE_TOTAL => put(&mut vals, &file.space.total_bytes),
+            FATTR4_SPACE_USED => put(&mut vals, &attr.used),
+            FATTR4_TIME_ACCESS => put(&mut vals, &time(attr.atime)),
+            FATTR4_TIME_DELTA => put(&mut vals, &nfstime4 { seconds: 0, nseconds: 1_000_000 }),
+            FATTR4_TIME_METADATA => put(&mut vals, &time(attr.ctime)),
+            FATTR4_TIME_MODIFY => put(&mut vals, &time(attr.mtime)),
+            FATTR4_MOUNTED_ON_FILEID => put(&mut vals, &file.mounted_on),
+            // Exclusive creates set no attributes along with the file
+            FATTR4_SUPPATTR_EXCLCREAT => put(&mut vals, &bitmap4::new()),
+            // Write-only, so never reported
+            _ => {
+                encoded.pop();
+            }
+        }
+    }
+    fattr4 { attrmask: bitmap(&encoded), attr_vals: vals }
+}
+
+fn owner_id(owner: &[u8]) -> Result<u32, nfsstat4> {
+    let owner = std::str::from_utf8(owner).map_err(|_| nfsstat4::NFS4ERR_BADOWNER)?;
+    let id = owner.split_once('@').map_or(owner, |(id, _)| id);
+    id.parse().map_err(|_| nfsstat4::NFS4ERR_BADOWNER)
+}
+
+fn settime(vals: &mut Cursor<&[u8]>) -> Result<Option<nfstime3>, nfsstat4> {
+    let mut how = 0_u32;
+    how.deserialize(vals).map_err(|_| nfsstat4::NFS4ERR_BADXDR)?;
+    match how {
+        SET_TO_SERVER_TIME4 => Ok(None),
+        SET_TO_CLIENT_TIME4 => {
+            let mut time = nfstime4::default();
+            time.deserialize(vals).map_err(|_| nfsstat4::NFS4ERR_BADXDR)?;
+            Ok(Some(nfstime3 { seconds: time.seconds as u32, nseconds: time.nseconds }))
+        }
+        _ => Err(nfsstat4::NFS4ERR_BADXDR),
+    }
+}
+
+// Decodes attributes to set into the NFSv3 attributes file systems take, with the bitmap of those
+// it sets
+pub(super) fn decode(attrs: &fattr4) -> Result<(sattr3, bitmap4), nfsstat4> {
+    let mut sattr = sattr3::default();
+    let mut vals = Cursor::new(attrs.attr_vals.as_slice());
+    let bad = |_| nfsstat4::NFS4ERR_BADXDR;
+    let requested = attrs_of(&attrs.attrmask);
+    for &attr in &requested {
+        match attr {
+            FATTR4_SIZE => {
+                let mut size = 0_u64;
+                size.deserialize(&mut vals).map_err(bad)?;
+                sattr.size = set_size3::size(size);
+            }
+            FATTR4_MODE => {
+                let mut mode = 0_u32;
+                mode.deserialize(&mut vals).map_err(bad)?;
+                sattr.mode = set_mode3::mode(mode & 0o7777);
+            }
+            FATTR4_OWNER | FATTR4_OWNER_GROUP => {
+                let mut owner = Vec::<u8>::new();
+                owner.deserialize(&mut vals).map_err(bad)?;
+                let id = owner_id(&owner)?;
+                if attr == FATTR4_OWNER {
+                    sattr.uid = set_uid3::uid(id);
+                } else {
+                    sattr.gid = set_gid3::gid(id);
+                }
+            }
+            FATTR4_TIME_ACCESS_SET => {
+                sattr.atime = match settime(&mut vals)? {
+                    Some(time) => set_atime::SET_TO_CLIENT_TIME(time),
+                    None => set_atime::SET_TO_SERVER_TIME,
+                };
+            }
+            FATTR4_TIME_MODIFY_SET => {
+                sattr.mtime = match settime(&mut vals)? {
+                    Some(time) => set_mtime::SET_TO_CLIENT_TIME(time),
+                    None => set_mtime::SET_TO_SERVER_TIME,
+                };
+            }
+            // Attributes that are only read
+            attr if SUPPORTED.contains(&attr) => return Err(nfsstat4::NFS4ERR_INVAL),
+            _ => return Err(nfsstat4::NFS4ERR_ATTRNOTSUPP),
+        }
+    }
+    Ok((sattr, bitmap(&requested)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settable_attributes_decode_in_bitmap_order() {
+        let mut vals = Vec::new();
+        put(&mut vals, &4096_u64);
+        put(&mut vals, &0o640_u32);
+        put(&mut vals, &b"1000@example.com".to_vec());
+        let attrs = fattr4 { attrmask: bitmap(&[FATTR4_OWNER, FATTR4_MODE, FATTR4_SIZE]), attr_vals: vals };
+        let (sattr, set) = decode(&attrs).unwrap();
+        assert!(matches!(sattr.size, set_size3::size(4096)));
+        assert!(matches!(sattr.mode, set_mode3::mode(0o640)));
+        assert!(matches!(sattr.uid, set_uid3::uid(1000)));
+        assert_eq!(attrs_of(&set), vec![FATTR4_SIZE, FATTR4_MODE, FATTR4_OWNER]);
+
+        let mut vals = Vec::new();
+        put(&mut vals, &b"alice".to_vec());
+        let attrs = fattr4 { attrmask: bitmap(&[FATTR4_OWNER]), attr_vals: vals };
+        assert_eq!(decode(&attrs).unwrap_err(), nfsstat4::NFS4ERR_BADOWNER);
+        let attrs = fattr4 { attrmask: bitmap(&[FATTR4_FILEID]), attr_vals: Vec::new() };
+        assert_eq!(decode(&attrs).unwrap_err(), nfsstat4::NFS4ERR_INVAL);
+    }
+}
@@ -0,0 +1,485 @@
+use super::pseudo::Fh;
+use super::state::{self, Nfs4State, SequenceStart};
+use super::{fh_ops, open_ops};
+use crate::kernel::api::nfs4::*;
+use crate::kernel::protocol::context::RPCContext;
+use crate::kernel::protocol::rpc::*;
+use crate::kernel::protocol::xdr::*;
+use num_derive::{FromPrimitive, ToPrimitive};
+use num_traits::cast::FromPrimitive;
+use std::io::{Read, Write};
+use tracing::{debug, warn};
+
+/*
+ NFSv4.1 has two procedures, and all the work is done by COMPOUND: a list of operations run in
+ order, on a current (and a saved) file handle they pass along, until one fails. Every compound
+ but the few that set up a client and its sessions starts with SEQUENCE, which names the session
+ and the slot the request takes and answers retransmissions from the slot's cached reply.
+*/
+
+#[allow(non_camel_case_types)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, FromPrimitive, ToPrimitive)]
+enum Nfs4Program {
+    NFSPROC4_NULL = 0,
+    NFSPROC4_COMPOUND = 1,
+    INVALID,
+}
+
+// The server's name for itself in EXCHANGE_ID, which clients use to tell servers apart
+const SERVER_OWNER: &[u8] = b"graymamba";
+
+// What the operations of a compound share
+pub(super) struct Compound<'a> {
+    pub context: &'a RPCContext,
+    pub state: &'a Nfs4State,
+    pub current: Option<Fh>,
+    pub saved: Option<Fh>,
+    // The stateid the last operation that made one left, for the special current stateid
+    pub current_stateid: Option<stateid4>,
+    // The client of the session, once SEQUENCE named it
+    pub clientid: Option<clientid4>,
+}
+
+impl Compound<'_> {
+    pub fn current(&self) -> Result<&Fh, nfsstat4> {
+        self.current.as_ref().ok_or(nfsstat4::NFS4ERR_NOFILEHANDLE)
+    }
+
+    pub fn set_current(&mut self, fh: Fh) {
+        self.current = Some(fh);
+        self.current_stateid = None;
+    }
+
+    // A stateid of the arguments, with the current stateid standing for the one last made
+    pub fn stateid(&self, stateid: stateid4) -> Result<stateid4, nfsstat4> {
+        if stateid.is_current() {
+            return self.current_stateid.ok_or(nfsstat4::NFS4ERR_BAD_STATEID);
+        }
+        Ok(stateid)
+    }
+}
+
+// Results go to a buffer, which writing to cannot fail
+pub(super) fn put(res: &mut Vec<u8>, value: &impl XDR) {
+    value.serialize(res).expect("serializing to a Vec");
+}
+
+pub(super) fn args<T: XDR + Default>(input: &mut impl Read) -> Result<T, nfsstat4> {
+    let mut args = T::default();
+    args.deserialize(input).map_err(|_| nfsstat4::NFS4ERR_BADXDR)?;
+    Ok(args)
+}
+
+pub async fn handle_nfs4(
+    xid: u32,
+    call: call_body,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let Some(state) = context.nfs4.as_deref() else {
+        warn!("NFSv4 is not served on port {}", context.local_port);
+        prog_mismatch_reply_message(xid, crate::kernel::api::nfs::VERSION).serialize(output)?;
+        return Ok(());
+    };
+    match Nfs4Program::from_u32(call.proc).unwrap_or(Nfs4Program::INVALID) {
+        Nfs4Program::NFSPROC4_NULL => {
+            debug!("nfsproc4_null({:?}) ", xid);
+            make_success_reply(xid).serialize(output)?;
+        }
+        Nfs4Program::NFSPROC4_COMPOUND => nfsproc4_compound(xid, input, output, context, state).await?,
+        _ => {
+            proc_unavail_reply_message(xid).serialize(output)?;
+        }
+    }
+    Ok(())
+}
+
+// The operations that may start a compound without a SEQUENCE, as its only operation
+fn is_sessionless(op: nfs_opnum4) -> bool {
+    matches!(
+        op,
+        nfs_opnum4::OP_EXCHANGE_ID
+            | nfs_opnum4::OP_CREATE_SESSION
+            | nfs_opnum4::OP_DESTROY_SESSION
+            | nfs_opnum4::OP_BIND_CONN_TO_SESSION
+            | nfs_opnum4::OP_DESTROY_CLIENTID
+    )
+}
+
+pub async fn nfsproc4_compound(
+    xid: u32,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+    state: &Nfs4State,
+) -> Result<(), anyhow::Error> {
+    let mut tag = Vec::<u8>::new();
+    let mut minorversion = 0_u32;
+    let mut count = 0_u32;
+    tag.deserialize(input)?;
+    minorversion.deserialize(input)?;
+    count.deserialize(input)?;
+    debug!("nfsproc4_compound({:?}, {:?}, {} operations) ", xid, String::from_utf8_lossy(&tag), count);
+
+    for clientid in state.expire_clients() {
+        warn!("The lease of NFSv4 client {:x} expired", clientid);
+        release_client_locks(context, clientid).await;
+    }
+
+    let mut c = Compound { context, state, current: None, saved: None, current_stateid: None, clientid: None };
+    let mut results = Vec::new();
+    let mut done = 0_u32;
+    let mut status = nfsstat4::NFS4_OK;
+    let mut slot = None;
+    if minorversion != MINOR_VERSION {
+        status = nfsstat4::NFS4ERR_MINOR_VERS_MISMATCH;
+    }
+
+    while status == nfsstat4::NFS4_OK && done < count {
+        let mut opnum = 0_u32;
+        if opnum.deserialize(input).is_err() {
+            status = nfsstat4::NFS4ERR_BADXDR;
+            break;
+        }
+        let op = nfs_opnum4::from_u32(opnum).filter(|op| *op != nfs_opnum4::OP_ILLEGAL);
+        let mut res = Vec::new();
+        let result = match op {
+            None => Err(nfsstat4::NFS4ERR_OP_ILLEGAL),
+            Some(nfs_opnum4::OP_SEQUENCE) if done > 0 => Err(nfsstat4::NFS4ERR_SEQUENCE_POS),
+            Some(nfs_opnum4::OP_SEQUENCE) => {
+                match sequence(&mut c, input, count) {
+                    Ok((args, SequenceStart::New { clientid, resok })) => {
+                        c.clientid = Some(clientid);
+                        slot = Some(args);
+                        put(&mut res, &resok);
+                        Ok(())
+                    }
+                    // A retransmission gets the reply the request got, whole
+                    Ok((_, SequenceStart::Replay(reply))) => {
+                        debug!("{:?} --> replayed the reply of the slot", xid);
+                        make_success_reply(xid).serialize(output)?;
+                        output.write_all(&reply)?;
+                        return Ok(());
+                    }
+                    Err(stat) => Err(stat),
+                }
+            }
+            Some(op) if done == 0 && !is_sessionless(op) => Err(nfsstat4::NFS4ERR_OP_NOT_IN_SESSION),
+            Some(_) if done == 0 && count > 1 => Err(nfsstat4::NFS4ERR_NOT_ONLY_OP),
+            Some(op) => operation(&mut c, op, input, &mut res).await,
+        };
+        done += 1;
+        put(&mut results, &op.map_or(nfs_opnum4::OP_ILLEGAL as u32, |op| op as u32));
+        let stat = result.err().unwrap_or(nfsstat4::NFS4_OK);
+        debug!("{:?} --> {:?} {:?}", xid, op, stat);
+        put(&mut results, &stat);
+        results.extend_from_slice(&res);
+        status = stat;
+    }
+
+    let mut reply = Vec::new();
+    put(&mut reply, &status);
+    put(&mut reply, &tag);
+    put(&mut reply, &done);
+    reply.extend_from_slice(&results);
+    if let Some(args) = slot {
+        let cached = args.sa_cachethis.then(|| reply.clone());
+        state.finish_sequence(&args.sa_sessionid, args.sa_slotid, cached);
+    }
+    make_success_reply(xid).serialize(output)?;
+    output.write_all(&reply)?;
+    Ok(())
+}
+
+async fn operation(c: &mut Compound<'_>, op: nfs_opnum4, input: &mut impl Read, res: &mut Vec<u8>) -> Result<(), nfsstat4> {
+    use nfs_opnum4::*;
+    match op {
+        OP_EXCHANGE_ID => exchange_id(c, input, res).await,
+        OP_CREATE_SESSION => create_session(c, input, res),
+        OP_DESTROY_SESSION => destroy_session(c, input),
+        OP_BIND_CONN_TO_SESSION => bind_conn_to_session(c, input, res),
+        OP_DESTROY_CLIENTID => destroy_clientid(c, input).await,
+        OP_RECLAIM_COMPLETE => args::<bool>(input).map(|_| ()),
+        OP_PUTFH => fh_ops::putfh(c, input).await,
+        OP_PUTROOTFH | OP_PUTPUBFH => fh_ops::putrootfh(c).await,
+        OP_GETFH => fh_ops::getfh(c, res),
+        OP_SAVEFH => fh_ops::savefh(c),
+        OP_RESTOREFH => fh_ops::restorefh(c),
+        OP_LOOKUP => fh_ops::lookup(c, input).await,
+        OP_LOOKUPP => fh_ops::lookupp(c).await,
+        OP_GETATTR => fh_ops::getattr(c, input, res).await,
+        OP_VERIFY => fh_ops::verify(c, input, true).await,
+        OP_NVERIFY => fh_ops::verify(c, input, false).await,
+        OP_ACCESS => fh_ops::access(c, input, res).await,
+        OP_READDIR => fh_ops::readdir(c, input, res).await,
+        OP_READLINK => fh_ops::readlink(c, res).await,
+        OP_SECINFO => fh_ops::secinfo(c, input, res, true),
+        OP_SECINFO_NO_NAME => fh_ops::secinfo(c, input, res, false),
+        OP_OPEN => open_ops::open(c, input, res).await,
+        OP_CLOSE => open_ops::close(c, input, res).await,
+        OP_OPEN_DOWNGRADE => open_ops::open_downgrade(c, input, res),
+        OP_READ => open_ops::read(c, input, res).await,
+        OP_WRITE => open_ops::write(c, input, res).await,
+        OP_COMMIT => open_ops::commit(c, input, res).await,
+        OP_CREATE => open_ops::create(c, input, res).await,
+        OP_REMOVE => open_ops::remove(c, input, res).await,
+        OP_RENAME => open_ops::rename(c, input, res).await,
+        OP_SETATTR => open_ops::setattr(c, input, res).await,
+        OP_LOCK => open_ops::lock(c, input, res).await,
+        OP_LOCKT => open_ops::lockt(c, input, res),
+        OP_LOCKU => open_ops::locku(c, input, res).await,
+        OP_TEST_STATEID => open_ops::test_stateid(c, input, res),
+        OP_FREE_STATEID => open_ops::free_stateid(c, input),
+        // Delegations, layouts, named attributes and hard links are not offered, and NFSv4.0's
+        // client and open confirmation is replaced by sessions
+        _ => Err(nfsstat4::NFS4ERR_NOTSUPP),
+    }
+}
+
+fn sequence(c: &mut Compound<'_>, input: &mut impl Read, count: u32) -> Result<(SEQUENCE4args, SequenceStart), nfsstat4> {
+    let args = args::<SEQUENCE4args>(input)?;
+    let start = c.state.sequence(&args, count)?;
+    Ok((args, start))
+}
+
+// Releases the locks a client that is gone held
+pub(super) async fn release_client_locks(context: &RPCContext, clientid: clientid4) {
+    let host = state::lock_host(clientid);
+    for file in context.locks.release_host(&host) {
+        if let Some(vfs) = context.exports.filesystem(file.export) {
+            vfs.lock_changed(file.fileid, false, &host).await;
+        }
+    }
+}
+
+async fn exchange_id(c: &mut Compound<'_>, input: &mut impl Read, res: &mut Vec<u8>) -> Result<(), nfsstat4> {
+    let owner = args::<client_owner4>(input)?;
+    let _flags = args::<u32>(input)?;
+    // Only clients that need no state protection beyond their RPC credential
+    if args::<u32>(input)? != SP4_NONE {
+        return Err(nfsstat4::NFS4ERR_NOTSUPP);
+    }
+    let impl_ids = args::<u32>(input)?;
+    for _ in 0..impl_ids.min(1) {
+        args::<nfs_impl_id4>(input)?;
+    }
+
+    let exchanged = c.state.exchange_id(&owner);
+    debug!("{:?} is NFSv4 client {:x}", String::from_utf8_lossy(&owner.co_ownerid), exchanged.clientid);
+    if let Some(replaced) = exchanged.replaced {
+        release_client_locks(c.context, replaced).await;
+    }
+    let mut flags = EXCHGID4_FLAG_USE_NON_PNFS;
+    if exchanged.confirmed {
+        flags |= EXCHGID4_FLAG_CONFIRMED_R;
+    }
+    put(res, &exchanged.clientid);
+    put(res, &exchanged.sequenceid);
+    put(res, &flags);
+    put(res, &SP4_NONE);
+    put(res, &server_owner4 { so_minor_id: 0, so_major_id: SERVER_OWNER.to_vec() });
+    put(res, &SERVER_OWNER.to_vec());
+    put(res, &1_u32);
+    put(res, &nfs_impl_id4 {
+        nii_domain: b"graymamba".to_vec(),
+        nii_name: format!("graymamba {}", env!("CARGO_PKG_VERSION")).into_bytes(),
+        nii_date: nfstime4::default(),
+    });
+    Ok(())
+}
+
+fn create_session(c: &mut Compound<'_>, input: &mut impl Read, res: &mut Vec<u8>) -> Result<(), nfsstat4> {
+    let clientid = args::<clientid4>(input)?;
+    let sequence = args::<sequenceid4>(input)?;
+    let _flags = args::<u32>(input)?;
+    let fore = args::<channel_attrs4>(input)?;
+    let back = args::<channel_attrs4>(input)?;
+    let _cb_program = args::<u32>(input)?;
+    // The callback credentials, of no use without a back channel
+    for _ in 0..args::<u32>(input)? {
+        match args::<u32>(input)? {
+            0 => {}
+            1 => {
+                args::<auth_unix>(input)?;
+            }
+            _ => {
+                args::<u32>(input)?;
+                args::<Vec<u8>>(input)?;
+                args::<Vec<u8>>(input)?;
+            }
+        }
+    }
+
+    let (sessionid, sequence, fore) = c.state.create_session(clientid, sequence, &fore)?;
+    debug!("NFSv4 client {:x} has session {:?}", clientid, sessionid);
+    put(res, &sessionid);
+    put(res, &sequence);
+    // Neither persistent nor with a back channel, as the server never calls clients back
+    put(res, &0_u32);
+    put(res, &fore);
+    put(res, &back);
+    Ok(())
+}
+
+fn destroy_session(c: &mut Compound<'_>, input: &mut impl Read) -> Result<(), nfsstat4> {
+    let sessionid = args::<sessionid4>(input)?;
+    c.state.destroy_session(&sessionid)
+}
+
+fn bind_conn_to_session(c: &mut Compound<'_>, input: &mut impl Read, res: &mut Vec<u8>) -> Result<(), nfsstat4> {
+    let sessionid = args::<sessionid4>(input)?;
+    let _dir = args::<u32>(input)?;
+    let _use_rdma = args::<bool>(input)?;
+    if !c.state.session_exists(&sessionid) {
+        return Err(nfsstat4::NFS4ERR_BADSESSION);
+    }
+    put(res, &sessionid);
+    put(res, &CDFS4_FORE);
+    put(res, &false);
+    Ok(())
+}
+
+async fn destroy_clientid(c: &mut Compound<'_>, input: &mut impl Read) -> Result<(), nfsstat4> {
+    let clientid = args::<clientid4>(input)?;
+    c.state.destroy_clientid(clientid)?;
+    release_client_locks(c.context, clientid).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::protocol::rpc::NonceCache;
+    use crate::kernel::protocol::rpcbind::RpcbindRegistry;
+    use crate::kernel::vfs::api::NFSFileSystem;
+    use crate::kernel::vfs::exports::{Export, ExportTable};
+    use crate::kernel::vfs::locks::LockManager;
+    use crate::kernel::vfs::mock::MockNFSFileSystem;
+    use std::collections::HashSet;
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    fn context() -> RPCContext {
+        let fs: Arc<dyn NFSFileSystem + Send + Sync> = Arc::new(MockNFSFileSystem::new_readwrite());
+        let export = Export {
+            path: "/projects".to_string(),
+            clients: Vec::new(),
+            read_only: false,
+            read_only_users: HashSet::new(),
+            root_squash: false,
+            require_signed_auth: false,
+            require_tls: false,
+            vfs: fs.clone(),
+            read_only_vfs: None,
+        };
+        RPCContext {
+            local_port: 2049,
+            client_addr: "127.0.0.1:700".to_string(),
+            auth: None,
+            caller: None,
+            nonces: Arc::new(NonceCache::default()),
+            tls: None,
+            rpcbind: Arc::new(RpcbindRegistry::default()),
+            drc: None,
+            max_reply: None,
+            locks: Arc::new(LockManager::default()),
+            nfs4: Some(Arc::new(Nfs4State::default())),
+            vfs: fs,
+            exports: Arc::new(ExportTable::new(vec![export])),
+            mount_signal: None,
+        }
+    }
+
+    // Runs a compound of operations already encoded, returning its reply after the RPC header
+    async fn compound(context: &RPCContext, count: u32, ops: &[u8]) -> Vec<u8> {
+        let mut input = Vec::new();
+        put(&mut input, &Vec::<u8>::new());
+        put(&mut input, &MINOR_VERSION);
+        put(&mut input, &count);
+        input.extend_from_slice(ops);
+        let call = call_body { rpcvers: 2, prog: PROGRAM, vers: VERSION, proc: 1, ..Default::default() };
+        let mut output = Cursor::new(Vec::new());
+        handle_nfs4(1, call, &mut Cursor::new(input), &mut output, context).await.unwrap();
+        output.into_inner()[24..].to_vec()
+    }
+
+    fn read<T: XDR + Default>(reply: &mut Cursor<Vec<u8>>) -> T {
+        let mut value = T::default();
+        value.deserialize(reply).unwrap();
+        value
+    }
+
+    // The number and status of the next operation's result
+    fn op(reply: &mut Cursor<Vec<u8>>) -> (u32, u32) {
+        (read(reply), read(reply))
+    }
+
+    #[tokio::test]
+    async fn test_compounds_run_in_sessions_from_the_pseudo_root() {
+        let context = context();
+
+        // Compounds must start with SEQUENCE, save for those setting up a client
+        let mut ops = Vec::new();
+        put(&mut ops, &(nfs_opnum4::OP_PUTROOTFH as u32));
+        let mut reply = Cursor::new(compound(&context, 1, &ops).await);
+        assert_eq!(read::<u32>(&mut reply), nfsstat4::NFS4ERR_OP_NOT_IN_SESSION as u32);
+
+        let mut ops = Vec::new();
+        put(&mut ops, &(nfs_opnum4::OP_EXCHANGE_ID as u32));
+        put(&mut ops, &client_owner4 { co_verifier: [1; 8], co_ownerid: b"alice-laptop".to_vec() });
+        put(&mut ops, &0_u32);
+        put(&mut ops, &SP4_NONE);
+        put(&mut ops, &0_u32);
+        let mut reply = Cursor::new(compound(&context, 1, &ops).await);
+        assert_eq!(read::<u32>(&mut reply), 0);
+        read::<Vec<u8>>(&mut reply);
+        assert_eq!(read::<u32>(&mut reply), 1);
+        assert_eq!(op(&mut reply), (nfs_opnum4::OP_EXCHANGE_ID as u32, 0));
+        let clientid = read::<clientid4>(&mut reply);
+        let sequenceid = read::<sequenceid4>(&mut reply);
+
+        let mut ops = Vec::new();
+        put(&mut ops, &(nfs_opnum4::OP_CREATE_SESSION as u32));
+        put(&mut ops, &clientid);
+        put(&mut ops, &sequenceid);
+        put(&mut ops, &0_u32);
+        put(&mut ops, &channel_attrs4 { ca_maxoperations: 8, ca_maxrequests: 4, ..Default::default() });
+        put(&mut ops, &channel_attrs4::default());
+        put(&mut ops, &0_u32);
+        put(&mut ops, &1_u32);
+        put(&mut ops, &0_u32);
+        let mut reply = Cursor::new(compound(&context, 1, &ops).await);
+        assert_eq!(read::<u32>(&mut reply), 0);
+        read::<Vec<u8>>(&mut reply);
+        assert_eq!(read::<u32>(&mut reply), 1);
+        assert_eq!(op(&mut reply), (nfs_opnum4::OP_CREATE_SESSION as u32, 0));
+        let sessionid = read::<sessionid4>(&mut reply);
+
+        // The root is a pseudo directory above the export
+        let mut ops = Vec::new();
+        put(&mut ops, &(nfs_opnum4::OP_SEQUENCE as u32));
+        put(&mut ops, &SEQUENCE4args { sa_sessionid: sessionid, sa_sequenceid: 1, sa_slotid: 0, sa_highest_slotid: 0, sa_cachethis: true });
+        put(&mut ops, &(nfs_opnum4::OP_PUTROOTFH as u32));
+        put(&mut ops, &(nfs_opnum4::OP_GETFH as u32));
+        put(&mut ops, &(nfs_opnum4::OP_GETATTR as u32));
+        put(&mut ops, &super::super::attrs::bitmap(&[FATTR4_TYPE]));
+        let first = compound(&context, 4, &ops).await;
+        let mut reply = Cursor::new(first.clone());
+        assert_eq!(read::<u32>(&mut reply), 0);
+        read::<Vec<u8>>(&mut reply);
+        assert_eq!(read::<u32>(&mut reply), 4);
+        assert_eq!(op(&mut reply), (nfs_opnum4::OP_SEQUENCE as u32, 0));
+        read::<SEQUENCE4resok>(&mut reply);
+        assert_eq!(op(&mut reply), (nfs_opnum4::OP_PUTROOTFH as u32, 0));
+        assert_eq!(op(&mut reply), (nfs_opnum4::OP_GETFH as u32, 0));
+        assert_eq!(read::<Vec<u8>>(&mut reply), b"gm-pseudo:/".to_vec());
+        assert_eq!(op(&mut reply), (nfs_opnum4::OP_GETATTR as u32, 0));
+        let attrs = read::<fattr4>(&mut reply);
+        assert_eq!(attrs.attr_vals, (nfs_ftype4::NF4DIR as u32).to_be_bytes().to_vec());
+
+        // A retransmission is answered from the slot's cached reply
+        assert_eq!(compound(&context, 4, &ops).await, first);
+    }
+}
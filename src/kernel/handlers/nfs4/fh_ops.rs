@@ -0,0 +1,292 @@
+// The operations that move the current file handle around and read what it names
+use super::attrs::{self, FileAttrs};
+use super::compound::{args, put, Compound};
+use super::pseudo::{self, status, Fh};
+use crate::kernel::api::nfs::nfsstring;
+use crate::kernel::api::nfs4::*;
+use crate::kernel::protocol::rpc::auth_flavor;
+use crate::kernel::vfs::api::VFSCapabilities;
+use graymamba::sharesfs::namespace;
+use num_traits::cast::ToPrimitive;
+use std::io::Read;
+use tracing::debug;
+
+// The first cookie of a directory's entries; 1 and 2 are reserved for "." and ".."
+const FIRST_COOKIE: u64 = 3;
+
+// A name of a directory entry, as NFSv4 allows them
+pub(super) fn name(component: &[u8]) -> Result<String, nfsstat4> {
+    if component.is_empty() {
+        return Err(nfsstat4::NFS4ERR_INVAL);
+    }
+    if component.len() > 255 {
+        return Err(nfsstat4::NFS4ERR_NAMETOOLONG);
+    }
+    let name = std::str::from_utf8(component).map_err(|_| nfsstat4::NFS4ERR_INVAL)?;
+    if name == "." || name == ".." {
+        return Err(nfsstat4::NFS4ERR_BADNAME);
+    }
+    if name.contains(['/', '\0']) {
+        return Err(nfsstat4::NFS4ERR_BADCHAR);
+    }
+    Ok(name.to_string())
+}
+
+pub(super) fn filename(name: &str) -> nfsstring {
+    nfsstring(name.as_bytes().to_vec())
+}
+
+// Fails unless the current file handle is a directory
+pub(super) async fn current_dir(c: &Compound<'_>) -> Result<(), nfsstat4> {
+    if let Fh::Export { vfs, id, .. } = c.current()? {
+        let attr = vfs.getattr(*id).await.map_err(status)?;
+        if !attrs::is_dir(&attr) {
+            return Err(match attr.ftype {
+                crate::kernel::api::nfs::ftype3::NF3LNK => nfsstat4::NFS4ERR_SYMLINK,
+                _ => nfsstat4::NFS4ERR_NOTDIR,
+            });
+        }
+    }
+    Ok(())
+}
+
+pub async fn putfh(c: &mut Compound<'_>, input: &mut impl Read) -> Result<(), nfsstat4> {
+    let fh = args::<nfs_fh4>(input)?;
+    if fh.len() > NFS4_FHSIZE {
+        return Err(nfsstat4::NFS4ERR_BADHANDLE);
+    }
+    let fh = Fh::from_bytes(c.context, &fh).await?;
+    c.set_current(fh);
+    Ok(())
+}
+
+pub async fn putrootfh(c: &mut Compound<'_>) -> Result<(), nfsstat4> {
+    let root = pseudo::root(c.context).await?;
+    c.set_current(root);
+    Ok(())
+}
+
+pub fn getfh(c: &mut Compound<'_>, res: &mut Vec<u8>) -> Result<(), nfsstat4> {
+    put(res, &c.current()?.to_bytes());
+    Ok(())
+}
+
+pub fn savefh(c: &mut Compound<'_>) -> Result<(), nfsstat4> {
+    c.saved = Some(c.current()?.clone());
+    Ok(())
+}
+
+pub fn restorefh(c: &mut Compound<'_>) -> Result<(), nfsstat4> {
+    let saved = c.saved.clone().ok_or(nfsstat4::NFS4ERR_RESTOREFH)?;
+    c.set_current(saved);
+    Ok(())
+}
+
+pub async fn lookup(c: &mut Compound<'_>, input: &mut impl Read) -> Result<(), nfsstat4> {
+    let name = name(&args::<component4>(input)?)?;
+    current_dir(c).await?;
+    let fh = match c.current()? {
+        Fh::Pseudo(path) => pseudo::lookup(c.context, path, &name).await?,
+        Fh::Export { export, vfs, id, export_root } => {
+            let id = vfs.lookup(*id, &filename(&name)).await.map_err(status)?;
+            Fh::Export { export: *export, vfs: vfs.clone(), id, export_root: *export_root }
+        }
+    };
+    c.set_current(fh);
+    Ok(())
+}
+
+// Climbs to the parent directory, out of an export at its root
+pub async fn lookupp(c: &mut Compound<'_>) -> Result<(), nfsstat4> {
+    current_dir(c).await?;
+    let fh = match c.current()? {
+        Fh::Pseudo(path) if path == "/" => return Err(nfsstat4::NFS4ERR_NOENT),
+        Fh::Pseudo(path) => Fh::Pseudo(pseudo::parent_path(path)),
+        Fh::Export { export, id, export_root, .. } if id == export_root => {
+            let junction = &c.context.exports.get(*export).ok_or(nfsstat4::NFS4ERR_STALE)?.path;
+            if junction == "/" {
+                return Err(nfsstat4::NFS4ERR_NOENT);
+            }
+            let parent = pseudo::parent_path(junction);
+            match pseudo::export_at(&c.context.exports, &parent) {
+                Some(index) => pseudo::enter(c.context, index).await?,
+                None => Fh::Pseudo(parent),
+            }
+        }
+        Fh::Export { export, vfs, id, export_root } => {
+            let (parent, _) = namespace::parent_of(vfs.data_store(), vfs.namespace(), *id)
+                .await
+                .map_err(|_| nfsstat4::NFS4ERR_IO)?;
+            Fh::Export { export: *export, vfs: vfs.clone(), id: parent, export_root: *export_root }
+        }
+    };
+    c.set_current(fh);
+    Ok(())
+}
+
+pub async fn getattr(c: &mut Compound<'_>, input: &mut impl Read, res: &mut Vec<u8>) -> Result<(), nfsstat4> {
+    let requested = args::<bitmap4>(input)?;
+    let file = FileAttrs::of(c, c.current()?, &requested).await?;
+    put(res, &attrs::encode(c, &file, &requested));
+    Ok(())
+}
+
+// VERIFY goes on when the attributes are the ones given, NVERIFY when they are not
+pub async fn verify(c: &mut Compound<'_>, input: &mut impl Read, same: bool) -> Result<(), nfsstat4> {
+    let given = args::<fattr4>(input)?;
+    let requested = attrs::attrs_of(&given.attrmask);
+    if requested.contains(&FATTR4_RDATTR_ERROR) {
+        return Err(nfsstat4::NFS4ERR_INVAL);
+    }
+    let file = FileAttrs::of(c, c.current()?, &given.attrmask).await?;
+    let actual = attrs::encode(c, &file, &given.attrmask);
+    if attrs::attrs_of(&actual.attrmask) != requested {
+        return Err(nfsstat4::NFS4ERR_ATTRNOTSUPP);
+    }
+    match (actual.attr_vals == given.attr_vals, same) {
+        (true, true) | (false, false) => Ok(()),
+        (false, true) => Err(nfsstat4::NFS4ERR_NOT_SAME),
+        (true, false) => Err(nfsstat4::NFS4ERR_SAME),
+    }
+}
+
+pub async fn access(c: &mut Compound<'_>, input: &mut impl Read, res: &mut Vec<u8>) -> Result<(), nfsstat4> {
+    let requested = args::<u32>(input)?;
+    // Read-only file systems, and the pseudo file system, grant nothing that would change them
+    let read_only = ACCESS4_READ | ACCESS4_LOOKUP | ACCESS4_EXECUTE;
+    let granted = match c.current()? {
+        Fh::Pseudo(_) => requested & read_only,
+        Fh::Export { vfs, id, .. } => {
+            vfs.getattr(*id).await.map_err(status)?;
+            match vfs.capabilities() {
+                VFSCapabilities::ReadWrite => requested,
+                VFSCapabilities::ReadOnly => requested & read_only,
+            }
+        }
+    };
+    put(res, &requested);
+    put(res, &granted);
+    Ok(())
+}
+
+pub async fn readdir(c: &mut Compound<'_>, input: &mut impl Read, res: &mut Vec<u8>) -> Result<(), nfsstat4> {
+    let args = args::<READDIR4args>(input)?;
+    if args.cookie == 1 || args.cookie == 2 {
+        return Err(nfsstat4::NFS4ERR_BAD_COOKIE);
+    }
+    current_dir(c).await?;
+    let current = c.current()?.clone();
+
+    // The entries past the cookie, with their cookies, names and attributes
+    let (entries, end, verifier) = match &current {
+        Fh::Pseudo(path) => {
+            let entries = pseudo::children(&c.context.exports, path)
+                .into_iter()
+                .enumerate()
+                .map(|(index, name)| {
+                    let file = FileAttrs::pseudo(&pseudo::child_path(path, &name));
+                    (index as u64 + FIRST_COOKIE, name, file)
+                })
+                .filter(|(cookie, _, _)| *cookie > args.cookie)
+                .collect::<Vec<_>>();
+            (entries, true, c.state.boot_verifier())
+        }
+        Fh::Export { export, vfs, id, export_root } => {
+            let verifier = vfs.serverid();
+            if args.cookie != 0 && args.cookieverf != verifier {
+                return Err(nfsstat4::NFS4ERR_NOT_SAME);
+            }
+            // Cookies are the fileid of the entry, past the reserved ones
+            let start_after = args.cookie.saturating_sub(FIRST_COOKIE - 1);
+            let max_entries = (args.maxcount as usize / 32).clamp(1, 1024);
+            let listing = vfs.readdir(*id, start_after, max_entries).await.map_err(status)?;
+            let entries = listing
+                .entries
+                .into_iter()
+                .filter(|entry| entry.name.0 != b"." && entry.name.0 != b"..")
+                .map(|entry| {
+                    let fh = Fh::Export { export: *export, vfs: vfs.clone(), id: entry.fileid, export_root: *export_root };
+                    let file = FileAttrs::entry(c, &fh, entry.attr);
+                    (entry.fileid + FIRST_COOKIE - 1, String::from_utf8_lossy(&entry.name.0).into_owned(), file)
+                })
+                .collect::<Vec<_>>();
+            (entries, listing.end, verifier)
+        }
+    };
+
+    // The reply holds as many entries as fit in maxcount, past the verifier, list end and eof
+    let mut budget = (args.maxcount as usize).saturating_sub(16);
+    let mut list = Vec::new();
+    let mut included = 0;
+    for (cookie, name, file) in &entries {
+        let mut entry = Vec::new();
+        put(&mut entry, &true);
+        put(&mut entry, cookie);
+        put(&mut entry, &name.as_bytes().to_vec());
+        put(&mut entry, &attrs::encode(c, file, &args.attr_request));
+        if entry.len() > budget {
+            break;
+        }
+        budget -= entry.len();
+        list.extend_from_slice(&entry);
+        included += 1;
+    }
+    if included == 0 && !entries.is_empty() {
+        return Err(nfsstat4::NFS4ERR_TOOSMALL);
+    }
+    debug!("readdir returns {} of {} entries", included, entries.len());
+    put(res, &verifier);
+    res.extend_from_slice(&list);
+    put(res, &false);
+    put(res, &(end && included == entries.len()));
+    Ok(())
+}
+
+pub async fn readlink(c: &mut Compound<'_>, res: &mut Vec<u8>) -> Result<(), nfsstat4> {
+    match c.current()? {
+        Fh::Pseudo(_) => Err(nfsstat4::NFS4ERR_INVAL),
+        Fh::Export { vfs, id, .. } => {
+            let target = vfs.readlink(*id).await.map_err(status)?;
+            put(res, &target.0);
+            Ok(())
+        }
+    }
+}
+
+// The flavors a file may be reached with, of a name in the current directory or of the current
+// file handle itself; either way the current file handle is used up
+pub fn secinfo(c: &mut Compound<'_>, input: &mut impl Read, res: &mut Vec<u8>, named: bool) -> Result<(), nfsstat4> {
+    let export = match (c.current()?, named) {
+        (Fh::Pseudo(path), true) => {
+            let path = pseudo::child_path(path, &name(&args::<component4>(input)?)?);
+            if pseudo::export_at(&c.context.exports, &path).is_none() && !pseudo::is_pseudo_dir(&c.context.exports, &path) {
+                return Err(nfsstat4::NFS4ERR_NOENT);
+            }
+            pseudo::export_at(&c.context.exports, &path)
+        }
+        (Fh::Pseudo(_), false) => {
+            args::<u32>(input)?;
+            None
+        }
+        (Fh::Export { export, .. }, true) => {
+            name(&args::<component4>(input)?)?;
+            Some(*export)
+        }
+        (Fh::Export { export, .. }, false) => {
+            args::<u32>(input)?;
+            Some(*export)
+        }
+    };
+    let signed_only = export.and_then(|index| c.context.exports.get(index)).is_some_and(|export| export.require_signed_auth);
+    let flavors = if signed_only {
+        vec![auth_flavor::AUTH_SIGNED]
+    } else {
+        vec![auth_flavor::AUTH_UNIX, auth_flavor::AUTH_SIGNED, auth_flavor::AUTH_NULL]
+    };
+    put(res, &(flavors.len() as u32));
+    for flavor in flavors {
+        put(res, &flavor.to_u32().unwrap());
+    }
+    c.current = None;
+    Ok(())
+}
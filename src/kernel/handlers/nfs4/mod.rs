@@ -0,0 +1,9 @@
+mod attrs;  // Attribute bitmaps and fattr4
+mod compound;  // COMPOUND and the session operations
+mod fh_ops;  // PUTFH, LOOKUP, GETATTR, READDIR, etc.
+mod open_ops;  // OPEN, READ, WRITE, LOCK, etc.
+mod pseudo;  // The pseudo file system above the exports
+pub mod state;  // Clients, sessions and stateids
+
+pub use compound::handle_nfs4;
+pub use state::Nfs4State;
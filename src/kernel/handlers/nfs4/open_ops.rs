@@ -0,0 +1,490 @@
+// Opens and the state hanging off them, the reads, writes and locks done through it, and the
+// operations that change directories and attributes
+use super::attrs;
+use super::compound::{args, put, Compound};
+use super::fh_ops::{current_dir, filename, name};
+use super::pseudo::{self, status, Fh};
+use super::state::{lock_host, StateKind};
+use crate::kernel::api::nfs::{fileid3, ftype3, nfsstring, set_size3, sattr3, specdata3};
+use num_traits::FromPrimitive;
+use crate::kernel::api::nfs4::*;
+use crate::kernel::protocol::udp::transfer_size;
+use crate::kernel::vfs::api::{NFSFileSystem, VFSCapabilities};
+use crate::kernel::vfs::locks::{FileKey, FileLock, LockOwner};
+use std::io::Read;
+use std::sync::Arc;
+use tracing::debug;
+
+type Vfs = Arc<dyn NFSFileSystem + Send + Sync>;
+
+// The file of an export the current file handle names; pseudo directories are not files
+fn current_file(c: &Compound<'_>, pseudo: nfsstat4) -> Result<(Vfs, fileid3, FileKey), nfsstat4> {
+    let fh = c.current()?;
+    match (fh, fh.file_key()) {
+        (Fh::Export { vfs, id, .. }, Some(file)) => Ok((vfs.clone(), *id, file)),
+        _ => Err(pseudo),
+    }
+}
+
+// Fails a change to a read-only file system, which hears of it
+async fn writable(vfs: &Vfs, id: fileid3, operation: &str) -> Result<(), nfsstat4> {
+    match vfs.capabilities() {
+        VFSCapabilities::ReadWrite => Ok(()),
+        VFSCapabilities::ReadOnly => {
+            vfs.write_denied(Some(id), operation).await;
+            Err(nfsstat4::NFS4ERR_ROFS)
+        }
+    }
+}
+
+async fn dir_change(vfs: &Vfs, dir: fileid3) -> changeid4 {
+    vfs.getattr(dir).await.map(|attr| attrs::change_id(&attr)).unwrap_or(0)
+}
+
+// Fails unless a file is a regular one, as opens and reads need
+async fn regular(vfs: &Vfs, id: fileid3) -> Result<(), nfsstat4> {
+    match vfs.getattr(id).await.map_err(status)?.ftype {
+        ftype3::NF3REG => Ok(()),
+        ftype3::NF3DIR => Err(nfsstat4::NFS4ERR_ISDIR),
+        ftype3::NF3LNK => Err(nfsstat4::NFS4ERR_SYMLINK),
+        _ => Err(nfsstat4::NFS4ERR_WRONG_TYPE),
+    }
+}
+
+fn lock_owner(owner: &lock_owner4) -> LockOwner {
+    LockOwner { host: lock_host(owner.clientid), svid: 0, oh: owner.owner.clone() }
+}
+
+// The range of an offset and length, a length of all ones reaching the end of the file
+fn range(offset: offset4, length: length4) -> Result<(u64, u64), nfsstat4> {
+    match length {
+        0 => Err(nfsstat4::NFS4ERR_INVAL),
+        NFS4_UINT64_MAX => Ok((offset, u64::MAX)),
+        length => Ok((offset, offset.checked_add(length).ok_or(nfsstat4::NFS4ERR_INVAL)?)),
+    }
+}
+
+fn denied(conflict: &FileLock) -> LOCK4denied {
+    let clientid = conflict.owner.host.strip_prefix("nfs4:").and_then(|id| u64::from_str_radix(id, 16).ok());
+    LOCK4denied {
+        offset: conflict.start,
+        length: if conflict.end == u64::MAX { NFS4_UINT64_MAX } else { conflict.end - conflict.start },
+        locktype: if conflict.exclusive { WRITE_LT } else { READ_LT },
+        owner: lock_owner4 { clientid: clientid.unwrap_or(0), owner: conflict.owner.oh.clone() },
+    }
+}
+
+enum CreateHow {
+    Unchecked(fattr4),
+    Guarded(fattr4),
+    Exclusive(fattr4),
+}
+
+fn create_how(input: &mut impl Read) -> Result<CreateHow, nfsstat4> {
+    match args::<u32>(input)? {
+        UNCHECKED4 => Ok(CreateHow::Unchecked(args(input)?)),
+        GUARDED4 => Ok(CreateHow::Guarded(args(input)?)),
+        // The file system keeps no verifier, so exclusive creates are told apart by name alone
+        EXCLUSIVE4 => {
+            args::<verifier4>(input)?;
+            Ok(CreateHow::Exclusive(fattr4::default()))
+        }
+        EXCLUSIVE4_1 => {
+            args::<verifier4>(input)?;
+            Ok(CreateHow::Exclusive(args(input)?))
+        }
+        _ => Err(nfsstat4::NFS4ERR_BADXDR),
+    }
+}
+
+pub async fn open(c: &mut Compound<'_>, input: &mut impl Read, res: &mut Vec<u8>) -> Result<(), nfsstat4> {
+    let _seqid = args::<seqid4>(input)?;
+    let share_access = args::<u32>(input)? & !OPEN4_SHARE_ACCESS_WANT_DELEG_MASK;
+    let share_deny = args::<u32>(input)?;
+    let owner = args::<open_owner4>(input)?;
+    let how = match args::<u32>(input)? {
+        OPEN4_CREATE => Some(create_how(input)?),
+        _ => None,
+    };
+    let name = match args::<u32>(input)? {
+        CLAIM_NULL => Some(name(&args::<component4>(input)?)?),
+        CLAIM_FH => None,
+        // Opens held before a restart are opened again, as the server kept none of them
+        CLAIM_PREVIOUS => {
+            args::<u32>(input)?;
+            None
+        }
+        _ => return Err(nfsstat4::NFS4ERR_NOTSUPP),
+    };
+    if share_access == 0 || share_access > OPEN4_SHARE_ACCESS_BOTH || share_deny > OPEN4_SHARE_DENY_BOTH {
+        return Err(nfsstat4::NFS4ERR_INVAL);
+    }
+
+    let (export, vfs, current, export_root) = match c.current()? {
+        Fh::Pseudo(path) => {
+            return Err(match (&name, &how) {
+                (Some(_), Some(_)) => nfsstat4::NFS4ERR_ROFS,
+                (Some(name), None) if pseudo::lookup(c.context, path, name).await.is_err() => nfsstat4::NFS4ERR_NOENT,
+                _ => nfsstat4::NFS4ERR_ISDIR,
+            });
+        }
+        Fh::Export { export, vfs, id, export_root } => (*export, vfs.clone(), *id, *export_root),
+    };
+    let mut cinfo = change_info4::default();
+    let mut attrset = bitmap4::new();
+    let id = match name {
+        None => current,
+        Some(name) => {
+            current_dir(c).await?;
+            if how.is_some() {
+                writable(&vfs, current, "create").await?;
+            }
+            let name = filename(&name);
+            let before = dir_change(&vfs, current).await;
+            let existing = match vfs.lookup(current, &name).await {
+                Ok(id) => Some(id),
+                Err(crate::kernel::api::nfs::nfsstat3::NFS3ERR_NOENT) => None,
+                Err(stat) => return Err(status(stat)),
+            };
+            let id = match (how, existing) {
+                (None, Some(id)) => id,
+                (None, None) => return Err(nfsstat4::NFS4ERR_NOENT),
+                (Some(CreateHow::Guarded(_)), Some(_)) => return Err(nfsstat4::NFS4ERR_EXIST),
+                // Opening an existing file to create it only truncates it, if asked to
+                (Some(CreateHow::Unchecked(createattrs)), Some(id)) => {
+                    let (sattr, _) = attrs::decode(&createattrs)?;
+                    if let set_size3::size(size) = sattr.size {
+                        let truncate = sattr3 { size: set_size3::size(size), ..Default::default() };
+                        vfs.setattr(id, truncate).await.map_err(status)?;
+                        attrset = attrs::bitmap(&[FATTR4_SIZE]);
+                    }
+                    id
+                }
+                (Some(CreateHow::Unchecked(createattrs) | CreateHow::Guarded(createattrs)), None) => {
+                    let (sattr, set) = attrs::decode(&createattrs)?;
+                    let (id, _) = vfs.create(current, &name, sattr).await.map_err(status)?;
+                    attrset = set;
+                    id
+                }
+                (Some(CreateHow::Exclusive(createattrs)), existing) => {
+                    let (sattr, set) = attrs::decode(&createattrs)?;
+                    let id = vfs.create_exclusive(current, &name).await.map_err(status)?;
+                    if existing.is_none() && !set.is_empty() {
+                        vfs.setattr(id, sattr).await.map_err(status)?;
+                        attrset = set;
+                    }
+                    id
+                }
+            };
+            cinfo = change_info4 { atomic: false, before, after: dir_change(&vfs, current).await };
+            id
+        }
+    };
+    regular(&vfs, id).await?;
+    if share_access & OPEN4_SHARE_ACCESS_WRITE != 0 {
+        writable(&vfs, id, "open").await?;
+    }
+
+    let stateid = c.state.open(&owner, FileKey { export, fileid: id }, share_access, share_deny)?;
+    debug!("open of {} by {:?} --> {:?}", id, String::from_utf8_lossy(&owner.owner), stateid);
+    c.set_current(Fh::Export { export, vfs, id, export_root });
+    c.current_stateid = Some(stateid);
+    put(res, &stateid);
+    put(res, &cinfo);
+    put(res, &OPEN4_RESULT_LOCKTYPE_POSIX);
+    put(res, &attrset);
+    put(res, &OPEN_DELEGATE_NONE);
+    Ok(())
+}
+
+// Closes an open, releasing the locks taken through it and committing what was written
+pub async fn close(c: &mut Compound<'_>, input: &mut impl Read, res: &mut Vec<u8>) -> Result<(), nfsstat4> {
+    let _seqid = args::<seqid4>(input)?;
+    let stateid = c.stateid(args(input)?)?;
+    let (vfs, id, file) = current_file(c, nfsstat4::NFS4ERR_ISDIR)?;
+    let closed = c.state.close(&stateid, file)?;
+    for owner in closed.lock_owners {
+        let owner = lock_owner(&owner);
+        if c.context.locks.unlock(file, &owner, 0, u64::MAX) {
+            vfs.lock_changed(id, false, &owner.host).await;
+        }
+    }
+    if closed.access & OPEN4_SHARE_ACCESS_WRITE != 0 {
+        vfs.commit(id).await.map_err(status)?;
+    }
+    c.current_stateid = None;
+    put(res, &stateid4::invalid());
+    Ok(())
+}
+
+pub fn open_downgrade(c: &mut Compound<'_>, input: &mut impl Read, res: &mut Vec<u8>) -> Result<(), nfsstat4> {
+    let stateid = c.stateid(args(input)?)?;
+    let _seqid = args::<seqid4>(input)?;
+    let share_access = args::<u32>(input)? & !OPEN4_SHARE_ACCESS_WANT_DELEG_MASK;
+    let share_deny = args::<u32>(input)?;
+    let (_, _, file) = current_file(c, nfsstat4::NFS4ERR_ISDIR)?;
+    let stateid = c.state.downgrade(&stateid, file, share_access, share_deny)?;
+    c.current_stateid = Some(stateid);
+    put(res, &stateid);
+    Ok(())
+}
+
+pub async fn read(c: &mut Compound<'_>, input: &mut impl Read, res: &mut Vec<u8>) -> Result<(), nfsstat4> {
+    let args = args::<READ4args>(input)?;
+    let stateid = c.stateid(args.stateid)?;
+    let (vfs, id, file) = current_file(c, nfsstat4::NFS4ERR_ISDIR)?;
+    regular(&vfs, id).await?;
+    c.state.check_io(&stateid, file, false)?;
+    let max = c.context.max_reply.map(transfer_size).unwrap_or(1024 * 1024);
+    let (data, eof) = vfs.read(id, args.offset, args.count.min(max)).await.map_err(status)?;
+    put(res, &eof);
+    put(res, &data);
+    Ok(())
+}
+
+// Writes are committed before the reply unless the client asked for them unstable, to commit
+// them itself with COMMIT or CLOSE
+pub async fn write(c: &mut Compound<'_>, input: &mut impl Read, res: &mut Vec<u8>) -> Result<(), nfsstat4> {
+    let args = args::<WRITE4args>(input)?;
+    let stateid = c.stateid(args.stateid)?;
+    let (vfs, id, file) = current_file(c, nfsstat4::NFS4ERR_ISDIR)?;
+    writable(&vfs, id, "write").await?;
+    regular(&vfs, id).await?;
+    c.state.check_io(&stateid, file, true)?;
+    vfs.write(id, args.offset, &args.data).await.map_err(status)?;
+    let committed = match args.stable {
+        UNSTABLE4 => UNSTABLE4,
+        _ => {
+            vfs.commit(id).await.map_err(status)?;
+            FILE_SYNC4
+        }
+    };
+    put(res, &(args.data.len() as u32));
+    put(res, &committed);
+    put(res, &vfs.serverid());
+    Ok(())
+}
+
+pub async fn commit(c: &mut Compound<'_>, input: &mut impl Read, res: &mut Vec<u8>) -> Result<(), nfsstat4> {
+    let _offset = args::<offset4>(input)?;
+    let _count = args::<count4>(input)?;
+    let (vfs, id, _) = current_file(c, nfsstat4::NFS4ERR_ISDIR)?;
+    regular(&vfs, id).await?;
+    vfs.commit(id).await.map_err(status)?;
+    put(res, &vfs.serverid());
+    Ok(())
+}
+
+// The object CREATE makes, with what its type carries
+enum NewObject {
+    Directory,
+    Symlink(Vec<u8>),
+    Special(ftype3, specdata3),
+}
+
+// Makes directories, symbolic links and special files; regular files are made by OPEN
+pub async fn create(c: &mut Compound<'_>, input: &mut impl Read, res: &mut Vec<u8>) -> Result<(), nfsstat4> {
+    let objtype = nfs_ftype4::from_u32(args::<u32>(input)?).ok_or(nfsstat4::NFS4ERR_BADTYPE)?;
+    let object = match objtype {
+        nfs_ftype4::NF4DIR => NewObject::Directory,
+        nfs_ftype4::NF4LNK => NewObject::Symlink(args(input)?),
+        nfs_ftype4::NF4BLK | nfs_ftype4::NF4CHR => {
+            let rdev = args::<specdata4>(input)?;
+            let ftype = if objtype == nfs_ftype4::NF4BLK { ftype3::NF3BLK } else { ftype3::NF3CHR };
+            NewObject::Special(ftype, specdata3 { specdata1: rdev.specdata1, specdata2: rdev.specdata2 })
+        }
+        nfs_ftype4::NF4SOCK => NewObject::Special(ftype3::NF3SOCK, specdata3::default()),
+        nfs_ftype4::NF4FIFO => NewObject::Special(ftype3::NF3FIFO, specdata3::default()),
+        _ => return Err(nfsstat4::NFS4ERR_BADTYPE),
+    };
+    let name = name(&args::<component4>(input)?)?;
+    let createattrs = args::<fattr4>(input)?;
+    current_dir(c).await?;
+    let Fh::Export { export, vfs, id: dir, export_root } = c.current()?.clone() else {
+        return Err(nfsstat4::NFS4ERR_ROFS);
+    };
+    writable(&vfs, dir, "create").await?;
+    let (sattr, attrset) = attrs::decode(&createattrs)?;
+    let before = dir_change(&vfs, dir).await;
+    let id = match object {
+        NewObject::Directory => {
+            let (id, _) = vfs.mkdir(dir, &filename(&name)).await.map_err(status)?;
+            if !attrset.is_empty() {
+                vfs.setattr(id, sattr).await.map_err(status)?;
+            }
+            id
+        }
+        NewObject::Symlink(target) => vfs.symlink(dir, &filename(&name), &nfsstring(target), &sattr).await.map_err(status)?.0,
+        NewObject::Special(ftype, rdev) => vfs.mknod(dir, &filename(&name), ftype, &sattr, rdev).await.map_err(status)?.0,
+    };
+    let cinfo = change_info4 { atomic: false, before, after: dir_change(&vfs, dir).await };
+    c.set_current(Fh::Export { export, vfs, id, export_root });
+    put(res, &cinfo);
+    put(res, &attrset);
+    Ok(())
+}
+
+pub async fn remove(c: &mut Compound<'_>, input: &mut impl Read, res: &mut Vec<u8>) -> Result<(), nfsstat4> {
+    let name = name(&args::<component4>(input)?)?;
+    current_dir(c).await?;
+    let (vfs, dir, _) = current_file(c, nfsstat4::NFS4ERR_ROFS)?;
+    writable(&vfs, dir, "remove").await?;
+    let before = dir_change(&vfs, dir).await;
+    vfs.remove(dir, &filename(&name)).await.map_err(status)?;
+    put(res, &change_info4 { atomic: false, before, after: dir_change(&vfs, dir).await });
+    Ok(())
+}
+
+// Renames an entry of the saved directory to one of the current directory
+pub async fn rename(c: &mut Compound<'_>, input: &mut impl Read, res: &mut Vec<u8>) -> Result<(), nfsstat4> {
+    let oldname = name(&args::<component4>(input)?)?;
+    let newname = name(&args::<component4>(input)?)?;
+    let saved = c.saved.clone().ok_or(nfsstat4::NFS4ERR_NOFILEHANDLE)?;
+    current_dir(c).await?;
+    let (vfs, from, to) = match (&saved, c.current()?) {
+        (Fh::Export { export: a, vfs, id: from, .. }, Fh::Export { export: b, id: to, .. }) if a == b => {
+            (vfs.clone(), *from, *to)
+        }
+        (Fh::Pseudo(_), Fh::Pseudo(_)) => return Err(nfsstat4::NFS4ERR_ROFS),
+        _ => return Err(nfsstat4::NFS4ERR_XDEV),
+    };
+    writable(&vfs, from, "rename").await?;
+    let source_before = dir_change(&vfs, from).await;
+    let target_before = dir_change(&vfs, to).await;
+    vfs.rename(from, &filename(&oldname), to, &filename(&newname)).await.map_err(status)?;
+    put(res, &change_info4 { atomic: false, before: source_before, after: dir_change(&vfs, from).await });
+    put(res, &change_info4 { atomic: false, before: target_before, after: dir_change(&vfs, to).await });
+    Ok(())
+}
+
+// The attributes set are reported whether or not SETATTR succeeds
+pub async fn setattr(c: &mut Compound<'_>, input: &mut impl Read, res: &mut Vec<u8>) -> Result<(), nfsstat4> {
+    let mut attrset = bitmap4::new();
+    let result = set_attributes(c, input, &mut attrset).await;
+    put(res, &attrset);
+    result
+}
+
+async fn set_attributes(c: &mut Compound<'_>, input: &mut impl Read, attrset: &mut bitmap4) -> Result<(), nfsstat4> {
+    let stateid = c.stateid(args(input)?)?;
+    let attributes = args::<fattr4>(input)?;
+    let (vfs, id, file) = current_file(c, nfsstat4::NFS4ERR_ROFS)?;
+    let (sattr, set) = attrs::decode(&attributes)?;
+    writable(&vfs, id, "setattr").await?;
+    // Truncating or extending a file is a write, which the stateid has to allow
+    if matches!(sattr.size, set_size3::size(_)) {
+        c.state.check_io(&stateid, file, true)?;
+    }
+    vfs.setattr(id, sattr).await.map_err(status)?;
+    *attrset = set;
+    Ok(())
+}
+
+pub async fn lock(c: &mut Compound<'_>, input: &mut impl Read, res: &mut Vec<u8>) -> Result<(), nfsstat4> {
+    let locktype = args::<u32>(input)?;
+    let reclaim = args::<bool>(input)?;
+    let (start, end) = range(args(input)?, args(input)?)?;
+    let new_lock_owner = args::<bool>(input)?;
+    let (vfs, id, file) = current_file(c, nfsstat4::NFS4ERR_ISDIR)?;
+    let stateid = if new_lock_owner {
+        let _open_seqid = args::<seqid4>(input)?;
+        let open_stateid = c.stateid(args(input)?)?;
+        let _lock_seqid = args::<seqid4>(input)?;
+        let owner = args::<lock_owner4>(input)?;
+        c.state.lock_state_for_open(&open_stateid, &owner, file)?
+    } else {
+        let stateid = c.stateid(args(input)?)?;
+        let _lock_seqid = args::<seqid4>(input)?;
+        stateid
+    };
+    if !(READ_LT..=WRITEW_LT).contains(&locktype) {
+        return Err(nfsstat4::NFS4ERR_INVAL);
+    }
+    let locks = &c.context.locks;
+    match (locks.in_grace_period(), reclaim) {
+        (true, false) => return Err(nfsstat4::NFS4ERR_GRACE),
+        (false, true) => return Err(nfsstat4::NFS4ERR_NO_GRACE),
+        _ => {}
+    }
+    let state = c.state.state(&stateid, file)?;
+    if !matches!(state.kind, StateKind::Lock { .. }) {
+        return Err(nfsstat4::NFS4ERR_BAD_STATEID);
+    }
+    let exclusive = locktype == WRITE_LT || locktype == WRITEW_LT;
+    c.state.check_io(&stateid, file, exclusive)?;
+
+    let owner = lock_owner(&state.owner);
+    if let Err(conflict) = locks.lock(file, owner.clone(), exclusive, start, end) {
+        debug!("lock of {} by {:?} --> denied by {:?}", id, owner, conflict.owner);
+        put(res, &denied(&conflict));
+        return Err(nfsstat4::NFS4ERR_DENIED);
+    }
+    vfs.lock_changed(id, true, &owner.host).await;
+    let stateid = c.state.bump(&stateid);
+    c.current_stateid = Some(stateid);
+    put(res, &stateid);
+    Ok(())
+}
+
+pub fn lockt(c: &mut Compound<'_>, input: &mut impl Read, res: &mut Vec<u8>) -> Result<(), nfsstat4> {
+    let locktype = args::<u32>(input)?;
+    let (start, end) = range(args(input)?, args(input)?)?;
+    let owner = args::<lock_owner4>(input)?;
+    let (_, _, file) = current_file(c, nfsstat4::NFS4ERR_ISDIR)?;
+    if c.context.locks.in_grace_period() {
+        return Err(nfsstat4::NFS4ERR_GRACE);
+    }
+    let exclusive = locktype == WRITE_LT || locktype == WRITEW_LT;
+    if let Some(conflict) = c.context.locks.test(file, &lock_owner(&owner), exclusive, start, end) {
+        put(res, &denied(&conflict));
+        return Err(nfsstat4::NFS4ERR_DENIED);
+    }
+    Ok(())
+}
+
+pub async fn locku(c: &mut Compound<'_>, input: &mut impl Read, res: &mut Vec<u8>) -> Result<(), nfsstat4> {
+    let _locktype = args::<u32>(input)?;
+    let _seqid = args::<seqid4>(input)?;
+    let stateid = c.stateid(args(input)?)?;
+    let (start, end) = range(args(input)?, args(input)?)?;
+    let (vfs, id, file) = current_file(c, nfsstat4::NFS4ERR_ISDIR)?;
+    let state = c.state.state(&stateid, file)?;
+    if !matches!(state.kind, StateKind::Lock { .. }) {
+        return Err(nfsstat4::NFS4ERR_BAD_STATEID);
+    }
+    let owner = lock_owner(&state.owner);
+    if c.context.locks.unlock(file, &owner, start, end) {
+        vfs.lock_changed(id, false, &owner.host).await;
+    }
+    let stateid = c.state.bump(&stateid);
+    c.current_stateid = Some(stateid);
+    put(res, &stateid);
+    Ok(())
+}
+
+pub fn test_stateid(c: &mut Compound<'_>, input: &mut impl Read, res: &mut Vec<u8>) -> Result<(), nfsstat4> {
+    let count = args::<u32>(input)?;
+    let mut statuses = Vec::new();
+    for _ in 0..count {
+        statuses.push(c.state.test_stateid(&args(input)?));
+    }
+    put(res, &count);
+    for stat in statuses {
+        put(res, &stat);
+    }
+    Ok(())
+}
+
+// Frees a lock stateid whose owner has unlocked everything
+pub fn free_stateid(c: &mut Compound<'_>, input: &mut impl Read) -> Result<(), nfsstat4> {
+    let stateid = c.stateid(args(input)?)?;
+    let locks = &c.context.locks;
+    let holds_locks = |state: &super::state::State| {
+        let owner = lock_owner(&state.owner);
+        locks.locks(state.file).iter().any(|lock| lock.owner == owner)
+    };
+    match c.state.free_stateid(&stateid, holds_locks) {
+        nfsstat4::NFS4_OK => Ok(()),
+        stat => Err(stat),
+    }
+}
@@ -0,0 +1,253 @@
+// NFSv4 clients mount the server's root and walk down to the exports, so the directories above
+// them make up a pseudo file system: read-only, made of the components of the export paths, and
+// with the exports as its leaves. Crossing into an export mounts it, as MNT would, for the caller.
+//
+// Pseudo directories have handles of their path. Handles of an export's files are its NFSv3 handles,
+// and LOOKUPP climbs back out of the export at its root, which the server finds from its path
+// rather than from anything the client sends.
+use crate::kernel::api::nfs::{fileid3, nfs_fh3, nfsstat3};
+use crate::kernel::api::nfs4::*;
+use crate::kernel::handlers::mount_handlers::{self, MountRefusal};
+use crate::kernel::protocol::context::RPCContext;
+use crate::kernel::vfs::api::{fh_export_index, NFSFileSystem};
+use crate::kernel::vfs::exports::{ExportTable, READ_ONLY_VIEW};
+use crate::kernel::vfs::locks::FileKey;
+use std::sync::Arc;
+
+const PSEUDO_PREFIX: &[u8] = b"gm-pseudo:";
+const V3_FH_SIZE: usize = 20;
+
+#[derive(Clone)]
+pub(super) enum Fh {
+    // A directory of the pseudo file system, by its path
+    Pseudo(String),
+    // A file of an export, served by the file system the caller mounted it with
+    Export {
+        export: u32,
+        vfs: Arc<dyn NFSFileSystem + Send + Sync>,
+        id: fileid3,
+        export_root: fileid3,
+    },
+}
+
+impl Fh {
+    pub fn to_bytes(&self) -> nfs_fh4 {
+        match self {
+            Fh::Pseudo(path) => [PSEUDO_PREFIX, path.as_bytes()].concat(),
+            Fh::Export { vfs, id, .. } => vfs.id_to_fh(*id).data,
+        }
+    }
+
+    // The handle a client puts, checked against the export's requirements of the call and served
+    // by the caller's view of the export, which a handle cannot widen. Its file must lie in the
+    // export, which the file system's fh_to_id makes sure of
+    pub async fn from_bytes(context: &RPCContext, fh: &[u8]) -> Result<Fh, nfsstat4> {
+        if let Some(path) = fh.strip_prefix(PSEUDO_PREFIX) {
+            let path = std::str::from_utf8(path).map_err(|_| nfsstat4::NFS4ERR_BADHANDLE)?;
+            return match is_pseudo_dir(&context.exports, path) {
+                true => Ok(Fh::Pseudo(path.to_string())),
+                false => Err(nfsstat4::NFS4ERR_STALE),
+            };
+        }
+        if fh.len() != V3_FH_SIZE {
+            return Err(nfsstat4::NFS4ERR_BADHANDLE);
+        }
+        let handle = nfs_fh3 { data: fh.to_vec() };
+        let index = fh_export_index(&handle).ok_or(nfsstat4::NFS4ERR_BADHANDLE)?;
+        let export = index & !READ_ONLY_VIEW;
+        admit(context, export)?;
+        let vfs = context.filesystem_for(index).await.ok_or(nfsstat4::NFS4ERR_STALE)?;
+        let id = vfs.fh_to_id(&handle).await.map_err(status)?;
+        let export_root = export_root(context, &vfs, export).await?;
+        Ok(Fh::Export { export, vfs, id, export_root })
+    }
+
+    pub fn file_key(&self) -> Option<FileKey> {
+        match self {
+            Fh::Pseudo(_) => None,
+            Fh::Export { export, id, .. } => Some(FileKey { export: *export, fileid: *id }),
+        }
+    }
+}
+
+// The fileid of the directory an export serves
+async fn export_root(context: &RPCContext, vfs: &Arc<dyn NFSFileSystem + Send + Sync>, index: u32) -> Result<fileid3, nfsstat4> {
+    let path = &context.exports.get(index).ok_or(nfsstat4::NFS4ERR_STALE)?.path;
+    vfs.get_id_from_path(path, vfs.data_store()).await.map_err(status)
+}
+
+// Whether the call may use the files of an export, as its client, transport and credential go
+fn admit(context: &RPCContext, index: u32) -> Result<(), nfsstat4> {
+    let export = context.exports.get(index).ok_or(nfsstat4::NFS4ERR_STALE)?;
+    if !export.admits_client(&context.client_addr) {
+        return Err(nfsstat4::NFS4ERR_ACCESS);
+    }
+    if (export.require_tls && !context.over_tls()) || (export.require_signed_auth && context.caller.is_none()) {
+        return Err(nfsstat4::NFS4ERR_WRONGSEC);
+    }
+    Ok(())
+}
+
+// The errors of the file systems, most of which NFSv4 numbers the same
+pub(super) fn status(stat: nfsstat3) -> nfsstat4 {
+    use num_traits::FromPrimitive;
+    match stat {
+        nfsstat3::NFS3ERR_NOT_SYNC => nfsstat4::NFS4ERR_INVAL,
+        nfsstat3::NFS3ERR_JUKEBOX => nfsstat4::NFS4ERR_DELAY,
+        stat => nfsstat4::from_u32(stat as u32).unwrap_or(nfsstat4::NFS4ERR_SERVERFAULT),
+    }
+}
+
+// Whether a path is a directory of the pseudo file system, above some export
+pub(super) fn is_pseudo_dir(exports: &ExportTable, path: &str) -> bool {
+    export_at(exports, path).is_none() && exports.exports().iter().any(|export| is_above(path, &export.path))
+}
+
+fn is_above(path: &str, export_path: &str) -> bool {
+    path == "/" || export_path.strip_prefix(path).is_some_and(|rest| rest.starts_with('/'))
+}
+
+pub(super) fn export_at(exports: &ExportTable, path: &str) -> Option<u32> {
+    exports.exports().iter().position(|export| export.path == path).map(|index| index as u32)
+}
+
+pub(super) fn child_path(path: &str, name: &str) -> String {
+    format!("{}/{}", path.trim_end_matches('/'), name)
+}
+
+pub(super) fn parent_path(path: &str) -> String {
+    match path.rsplit_once('/') {
+        Some(("", _)) | None => "/".to_string(),
+        Some((parent, _)) => parent.to_string(),
+    }
+}
+
+// The names in a pseudo directory: the next component of each export path below it
+pub(super) fn children(exports: &ExportTable, path: &str) -> Vec<String> {
+    let mut names: Vec<String> = exports
+        .exports()
+        .iter()
+        .filter(|export| is_above(path, &export.path))
+        .filter_map(|export| export.path[path.trim_end_matches('/').len() + 1..].split('/').next().map(str::to_string))
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+// Pseudo directories have fileids of a hash of their path
+pub(super) fn pseudo_fileid(path: &str) -> fileid3 {
+    let hash = path.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+    hash.max(1)
+}
+
+// The handle of the root: a pseudo directory, unless "/" is exported
+pub(super) async fn root(context: &RPCContext) -> Result<Fh, nfsstat4> {
+    match export_at(&context.exports, "/") {
+        Some(index) => enter(context, index).await,
+        None => Ok(Fh::Pseudo("/".to_string())),
+    }
+}
+
+// Crosses into an export, at the directory of it the caller mounts
+pub(super) async fn enter(context: &RPCContext, index: u32) -> Result<Fh, nfsstat4> {
+    let mount = mount_handlers::mount_point(context, index, None).await.map_err(|refusal| match refusal {
+        MountRefusal::Access => nfsstat4::NFS4ERR_ACCESS,
+        MountRefusal::TooWeak => nfsstat4::NFS4ERR_WRONGSEC,
+        MountRefusal::ServerFault => nfsstat4::NFS4ERR_SERVERFAULT,
+        MountRefusal::NoDirectory => nfsstat4::NFS4ERR_IO,
+    })?;
+    let id = mount.vfs.get_id_from_path(&mount.path, mount.vfs.data_store()).await.map_err(status)?;
+    let export_root = export_root(context, &mount.vfs, index).await?;
+    context.exports.record_mount(&context.client_addr, &mount.path);
+    Ok(Fh::Export { export: index, vfs: mount.vfs, id, export_root })
+}
+
+// Looks up a name in a pseudo directory
+pub(super) async fn lookup(context: &RPCContext, path: &str, name: &str) -> Result<Fh, nfsstat4> {
+    let path = child_path(path, name);
+    if let Some(index) = export_at(&context.exports, &path) {
+        return enter(context, index).await;
+    }
+    match is_pseudo_dir(&context.exports, &path) {
+        true => Ok(Fh::Pseudo(path)),
+        false => Err(nfsstat4::NFS4ERR_NOENT),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::protocol::rpc::NonceCache;
+    use crate::kernel::protocol::rpcbind::RpcbindRegistry;
+    use crate::kernel::vfs::exports::Export;
+    use crate::kernel::vfs::locks::LockManager;
+    use crate::kernel::vfs::mock::MockNFSFileSystem;
+    use crate::sharesfs::namespace;
+    use std::collections::HashSet;
+
+    fn exports(paths: &[&str]) -> ExportTable {
+        let vfs: Arc<dyn NFSFileSystem + Send + Sync> = Arc::new(MockNFSFileSystem::new_readwrite());
+        ExportTable::new(paths.iter().map(|path| Export {
+            path: path.to_string(),
+            clients: Vec::new(),
+            read_only: false,
+            read_only_users: HashSet::new(),
+            root_squash: false,
+            require_signed_auth: false,
+            require_tls: false,
+            vfs: vfs.clone(),
+            read_only_vfs: None,
+        }).collect())
+    }
+
+    #[test]
+    fn test_pseudo_directories_lead_to_the_exports() {
+        let exports = exports(&["/projects/alpha", "/projects/beta", "/home"]);
+        assert!(is_pseudo_dir(&exports, "/"));
+        assert!(is_pseudo_dir(&exports, "/projects"));
+        assert!(!is_pseudo_dir(&exports, "/home"));
+        assert!(!is_pseudo_dir(&exports, "/proj"));
+        assert_eq!(children(&exports, "/"), vec!["home", "projects"]);
+        assert_eq!(children(&exports, "/projects"), vec!["alpha", "beta"]);
+        assert_eq!(parent_path("/projects/alpha"), "/projects");
+        assert_eq!(parent_path("/projects"), "/");
+        assert_eq!(export_at(&exports, "/projects/beta"), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_handles_climb_out_at_the_export_root_they_are_served_from() {
+        let exports = exports(&["/projects/alpha", "/home"]);
+        let vfs = exports.get(0).unwrap().vfs.clone();
+        namespace::init_directory(vfs.data_store(), vfs.namespace(), "/projects/alpha/docs").await.unwrap();
+        let alpha = vfs.get_id_from_path("/projects/alpha", vfs.data_store()).await.unwrap();
+        let docs = vfs.get_id_from_path("/projects/alpha/docs", vfs.data_store()).await.unwrap();
+        let context = RPCContext {
+            local_port: 2049,
+            client_addr: "127.0.0.1:700".to_string(),
+            auth: None,
+            caller: None,
+            nonces: Arc::new(NonceCache::default()),
+            tls: None,
+            rpcbind: Arc::new(RpcbindRegistry::default()),
+            drc: None,
+            max_reply: None,
+            locks: Arc::new(LockManager::default()),
+            nfs4: None,
+            vfs: vfs.clone(),
+            exports: Arc::new(exports),
+            mount_signal: None,
+        };
+
+        // The handle is the file's alone; the root comes from the export's path
+        let handle = Fh::Export { export: 0, vfs: vfs.clone(), id: docs, export_root: docs }.to_bytes();
+        assert_eq!(handle, vfs.id_to_fh(docs).data);
+        match Fh::from_bytes(&context, &handle).await {
+            Ok(Fh::Export { id, export_root, .. }) => assert_eq!((id, export_root), (docs, alpha)),
+            _ => panic!("the handle of docs is refused"),
+        }
+        // A root appended by the client is not taken
+        let forged = [handle, 1u64.to_le_bytes().to_vec()].concat();
+        assert!(matches!(Fh::from_bytes(&context, &forged).await, Err(nfsstat4::NFS4ERR_BADHANDLE)));
+    }
+}
@@ -0,0 +1,531 @@
+// The state NFSv4.1 clients hold on the server: the client records EXCHANGE_ID makes, their
+// sessions and the slots of each session's reply cache, and the stateids of opens and of the
+// lock owners locking through them. Everything is in memory and lost on a restart; client ids,
+// session ids and stateids all carry the boot verifier, so those of before the restart are
+// recognised as stale. A client that stops renewing its lease loses its state.
+use crate::kernel::api::nfs4::*;
+use crate::kernel::vfs::locks::FileKey;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// How long a client's state lasts without a SEQUENCE renewing it
+pub const DEFAULT_LEASE_TIME: Duration = Duration::from_secs(90);
+
+// The fore channel the server offers a session at most
+pub const MAX_SLOTS: u32 = 64;
+pub const MAX_OPERATIONS: u32 = 64;
+pub const MAX_REQUEST_SIZE: u32 = 1024 * 1024 + 64 * 1024;
+pub const MAX_RESPONSE_SIZE_CACHED: u32 = 64 * 1024;
+
+#[derive(Debug)]
+struct Client {
+    ownerid: Vec<u8>,
+    verifier: verifier4,
+    // Whether a session was ever created for it
+    confirmed: bool,
+    // The sequence id the next CREATE_SESSION is to carry, and the session the last one created
+    create_session_seq: sequenceid4,
+    last_session: Option<(sessionid4, channel_attrs4)>,
+    renewed: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Slot {
+    seqid: sequenceid4,
+    in_progress: bool,
+    // The reply to the slot's last request, when it asked for it to be cached
+    reply: Option<Vec<u8>>,
+}
+
+#[derive(Debug)]
+struct Session {
+    clientid: clientid4,
+    fore: channel_attrs4,
+    slots: Vec<Slot>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StateKind {
+    // The share access and deny of an open owner's opens of a file
+    Open { access: u32, deny: u32 },
+    // A lock owner's locks of a file, taken through an open
+    Lock { open: [u8; NFS4_OTHER_SIZE] },
+}
+
+#[derive(Clone, Debug)]
+pub struct State {
+    pub kind: StateKind,
+    pub seqid: u32,
+    pub owner: state_owner4,
+    pub file: FileKey,
+}
+
+// How a SEQUENCE is to be answered
+#[derive(Debug)]
+pub enum SequenceStart {
+    // A new request of the client
+    New { clientid: clientid4, resok: SEQUENCE4resok },
+    // A retransmission, answered with the reply the request got
+    Replay(Vec<u8>),
+}
+
+#[derive(Debug)]
+pub struct ExchangeResult {
+    pub clientid: clientid4,
+    pub sequenceid: sequenceid4,
+    pub confirmed: bool,
+    // The record of an earlier incarnation of the client, whose state is gone
+    pub replaced: Option<clientid4>,
+}
+
+// What CLOSE leaves behind: the access the open had and the owners that locked through it
+#[derive(Debug)]
+pub struct ClosedOpen {
+    pub access: u32,
+    pub lock_owners: Vec<state_owner4>,
+}
+
+#[derive(Debug, Default)]
+struct Tables {
+    next_id: u64,
+    clients: HashMap<clientid4, Client>,
+    sessions: HashMap<sessionid4, Session>,
+    states: HashMap<[u8; NFS4_OTHER_SIZE], State>,
+}
+
+#[derive(Debug)]
+pub struct Nfs4State {
+    boot: u32,
+    lease: Duration,
+    tables: Mutex<Tables>,
+}
+
+impl Default for Nfs4State {
+    fn default() -> Self {
+        Nfs4State::new(DEFAULT_LEASE_TIME)
+    }
+}
+
+// The host name locks of a v4 client are held by, so the lock manager tells them from NLM hosts
+pub fn lock_host(clientid: clientid4) -> String {
+    format!("nfs4:{:016x}", clientid)
+}
+
+impl Nfs4State {
+    pub fn new(lease: Duration) -> Nfs4State {
+        Nfs4State { boot: rand::random(), lease, tables: Mutex::new(Tables::default()) }
+    }
+
+    pub fn lease_time(&self) -> Duration {
+        self.lease
+    }
+
+    // The verifier of this incarnation of the server
+    pub fn boot_verifier(&self) -> verifier4 {
+        let mut verifier = [0; NFS4_VERIFIER_SIZE];
+        verifier[..4].copy_from_slice(&self.boot.to_be_bytes());
+        verifier
+    }
+
+    fn next_id(tables: &mut Tables) -> u64 {
+        tables.next_id += 1;
+        tables.next_id
+    }
+
+    fn new_other(&self, tables: &mut Tables) -> [u8; NFS4_OTHER_SIZE] {
+        let mut other = [0; NFS4_OTHER_SIZE];
+        other[..4].copy_from_slice(&self.boot.to_be_bytes());
+        other[4..].copy_from_slice(&Self::next_id(tables).to_be_bytes());
+        other
+    }
+
+    fn is_stale_other(&self, other: &[u8; NFS4_OTHER_SIZE]) -> bool {
+        other[..4] != self.boot.to_be_bytes()
+    }
+
+    fn check_clientid(&self, tables: &Tables, clientid: clientid4) -> Result<(), nfsstat4> {
+        if (clientid >> 32) as u32 != self.boot || !tables.clients.contains_key(&clientid) {
+            return Err(nfsstat4::NFS4ERR_STALE_CLIENTID);
+        }
+        Ok(())
+    }
+
+    // Drops a client and everything it holds
+    fn remove_client(tables: &mut Tables, clientid: clientid4) {
+        tables.clients.remove(&clientid);
+        tables.sessions.retain(|_, session| session.clientid != clientid);
+        tables.states.retain(|_, state| state.owner.clientid != clientid);
+    }
+
+    pub fn exchange_id(&self, owner: &client_owner4) -> ExchangeResult {
+        let mut tables = self.tables.lock();
+        let existing = tables.clients.iter().find(|(_, client)| client.ownerid == owner.co_ownerid).map(|(&id, _)| id);
+        if let Some(clientid) = existing {
+            let client = &tables.clients[&clientid];
+            if client.verifier == owner.co_verifier {
+                return ExchangeResult {
+                    clientid,
+                    sequenceid: client.create_session_seq,
+                    confirmed: client.confirmed,
+                    replaced: None,
+                };
+            }
+            // The client restarted, and its earlier state is gone with it
+            Self::remove_client(&mut tables, clientid);
+        }
+        let clientid = ((self.boot as u64) << 32) | (Self::next_id(&mut tables) & 0xffff_ffff);
+        tables.clients.insert(clientid, Client {
+            ownerid: owner.co_ownerid.clone(),
+            verifier: owner.co_verifier,
+            confirmed: false,
+            create_session_seq: 1,
+            last_session: None,
+            renewed: Instant::now(),
+        });
+        ExchangeResult { clientid, sequenceid: 1, confirmed: false, replaced: existing }
+    }
+
+    // Creates a session with a fore channel no larger than the server offers
+    pub fn create_session(
+        &self,
+        clientid: clientid4,
+        sequence: sequenceid4,
+        fore: &channel_attrs4,
+    ) -> Result<(sessionid4, sequenceid4, channel_attrs4), nfsstat4> {
+        let mut tables = self.tables.lock();
+        self.check_clientid(&tables, clientid)?;
+        let client = &tables.clients[&clientid];
+        if sequence.wrapping_add(1) == client.create_session_seq {
+            return match &client.last_session {
+                Some((sessionid, fore)) => Ok((*sessionid, sequence, fore.clone())),
+                None => Err(nfsstat4::NFS4ERR_SEQ_MISORDERED),
+            };
+        }
+        if sequence != client.create_session_seq {
+            return Err(nfsstat4::NFS4ERR_SEQ_MISORDERED);
+        }
+        let fore = channel_attrs4 {
+            ca_headerpadsize: 0,
+            ca_maxrequestsize: fore.ca_maxrequestsize.min(MAX_REQUEST_SIZE),
+            ca_maxresponsesize: fore.ca_maxresponsesize.min(MAX_REQUEST_SIZE),
+            ca_maxresponsesize_cached: fore.ca_maxresponsesize_cached.min(MAX_RESPONSE_SIZE_CACHED),
+            ca_maxoperations: fore.ca_maxoperations.clamp(1, MAX_OPERATIONS),
+            ca_maxrequests: fore.ca_maxrequests.clamp(1, MAX_SLOTS),
+            ca_rdma_ird: Vec::new(),
+        };
+        let mut sessionid = [0; NFS4_SESSIONID_SIZE];
+        sessionid[..4].copy_from_slice(&self.boot.to_be_bytes());
+        sessionid[4..12].copy_from_slice(&Self::next_id(&mut tables).to_be_bytes());
+        let slots = (0..fore.ca_maxrequests).map(|_| Slot::default()).collect();
+        tables.sessions.insert(sessionid, Session { clientid, fore: fore.clone(), slots });
+        let client = tables.clients.get_mut(&clientid).unwrap();
+        client.confirmed = true;
+        client.create_session_seq = sequence.wrapping_add(1);
+        client.last_session = Some((sessionid, fore.clone()));
+        client.renewed = Instant::now();
+        Ok((sessionid, sequence, fore))
+    }
+
+    pub fn destroy_session(&self, sessionid: &sessionid4) -> Result<(), nfsstat4> {
+        match self.tables.lock().sessions.remove(sessionid) {
+            Some(_) => Ok(()),
+            None => Err(nfsstat4::NFS4ERR_BADSESSION),
+        }
+    }
+
+    pub fn session_exists(&self, sessionid: &sessionid4) -> bool {
+        self.tables.lock().sessions.contains_key(sessionid)
+    }
+
+    pub fn destroy_clientid(&self, clientid: clientid4) -> Result<(), nfsstat4> {
+        let mut tables = self.tables.lock();
+        self.check_clientid(&tables, clientid)?;
+        if tables.sessions.values().any(|session| session.clientid == clientid) {
+            return Err(nfsstat4::NFS4ERR_CLIENTID_BUSY);
+        }
+        Self::remove_client(&mut tables, clientid);
+        Ok(())
+    }
+
+    // Starts a request of some operations on a slot of a session, renewing its client's lease
+    pub fn sequence(&self, args: &SEQUENCE4args, operations: u32) -> Result<SequenceStart, nfsstat4> {
+        let mut tables = self.tables.lock();
+        let session = tables.sessions.get_mut(&args.sa_sessionid).ok_or(nfsstat4::NFS4ERR_BADSESSION)?;
+        if operations > session.fore.ca_maxoperations {
+            return Err(nfsstat4::NFS4ERR_TOO_MANY_OPS);
+        }
+        let highest_slotid = session.slots.len() as u32 - 1;
+        let slot = session.slots.get_mut(args.sa_slotid as usize).ok_or(nfsstat4::NFS4ERR_BADSLOT)?;
+        if args.sa_sequenceid == slot.seqid {
+            if slot.in_progress {
+                return Err(nfsstat4::NFS4ERR_DELAY);
+            }
+            return match &slot.reply {
+                Some(reply) => Ok(SequenceStart::Replay(reply.clone())),
+                None => Err(nfsstat4::NFS4ERR_RETRY_UNCACHED_REP),
+            };
+        }
+        if args.sa_sequenceid != slot.seqid.wrapping_add(1) {
+            return Err(nfsstat4::NFS4ERR_SEQ_MISORDERED);
+        }
+        slot.seqid = args.sa_sequenceid;
+        slot.in_progress = true;
+        slot.reply = None;
+        let clientid = session.clientid;
+        if let Some(client) = tables.clients.get_mut(&clientid) {
+            client.renewed = Instant::now();
+        }
+        Ok(SequenceStart::New {
+            clientid,
+            resok: SEQUENCE4resok {
+                sr_sessionid: args.sa_sessionid,
+                sr_sequenceid: args.sa_sequenceid,
+                sr_slotid: args.sa_slotid,
+                sr_highest_slotid: highest_slotid,
+                sr_target_highest_slotid: highest_slotid,
+                sr_status_flags: 0,
+            },
+        })
+    }
+
+    // Ends the request of a slot, keeping its reply for retransmissions
+    pub fn finish_sequence(&self, sessionid: &sessionid4, slotid: slotid4, reply: Option<Vec<u8>>) {
+        let mut tables = self.tables.lock();
+        if let Some(slot) = tables.sessions.get_mut(sessionid).and_then(|session| session.slots.get_mut(slotid as usize)) {
+            slot.in_progress = false;
+            slot.reply = reply;
+        }
+    }
+
+    // Drops the clients whose lease ran out, returning them so their locks can be released
+    pub fn expire_clients(&self) -> Vec<clientid4> {
+        let mut tables = self.tables.lock();
+        let expired: Vec<clientid4> = tables
+            .clients
+            .iter()
+            .filter(|(_, client)| client.renewed.elapsed() > self.lease)
+            .map(|(&id, _)| id)
+            .collect();
+        for &clientid in &expired {
+            Self::remove_client(&mut tables, clientid);
+        }
+        expired
+    }
+
+    // Opens a file for an open owner, or adds to the share access and deny of its open of it
+    pub fn open(&self, owner: &open_owner4, file: FileKey, access: u32, deny: u32) -> Result<stateid4, nfsstat4> {
+        let mut tables = self.tables.lock();
+        self.check_clientid(&tables, owner.clientid)?;
+        let mut existing = None;
+        for (other, state) in tables.states.iter().filter(|(_, state)| state.file == file) {
+            let StateKind::Open { access: held, deny: denied } = state.kind else { continue };
+            if state.owner == *owner {
+                existing = Some(*other);
+            } else if access & denied != 0 || deny & held != 0 {
+                return Err(nfsstat4::NFS4ERR_SHARE_DENIED);
+            }
+        }
+        if let Some(other) = existing {
+            let state = tables.states.get_mut(&other).unwrap();
+            if let StateKind::Open { access: held, deny: denied } = state.kind {
+                state.kind = StateKind::Open { access: held | access, deny: denied | deny };
+            }
+            state.seqid = state.seqid.wrapping_add(1);
+            return Ok(stateid4 { seqid: state.seqid, other });
+        }
+        let other = self.new_other(&mut tables);
+        tables.states.insert(other, State { kind: StateKind::Open { access, deny }, seqid: 1, owner: owner.clone(), file });
+        Ok(stateid4 { seqid: 1, other })
+    }
+
+    // The state of a stateid of a file, checking its seqid unless it is 0
+    pub fn state(&self, stateid: &stateid4, file: FileKey) -> Result<State, nfsstat4> {
+        let tables = self.tables.lock();
+        Self::lookup(self, &tables, stateid, file).cloned()
+    }
+
+    fn lookup<'a>(&self, tables: &'a Tables, stateid: &stateid4, file: FileKey) -> Result<&'a State, nfsstat4> {
+        if self.is_stale_other(&stateid.other) {
+            return Err(nfsstat4::NFS4ERR_STALE_STATEID);
+        }
+        let state = tables.states.get(&stateid.other).ok_or(nfsstat4::NFS4ERR_BAD_STATEID)?;
+        if state.file != file {
+            return Err(nfsstat4::NFS4ERR_BAD_STATEID);
+        }
+        if stateid.seqid != 0 && stateid.seqid < state.seqid {
+            return Err(nfsstat4::NFS4ERR_OLD_STATEID);
+        }
+        if stateid.seqid > state.seqid {
+            return Err(nfsstat4::NFS4ERR_BAD_STATEID);
+        }
+        Ok(state)
+    }
+
+    // Whether a stateid allows reading, or writing, a file: anonymous ones do unless another
+    // owner's open denies it
+    pub fn check_io(&self, stateid: &stateid4, file: FileKey, write: bool) -> Result<(), nfsstat4> {
+        let tables = self.tables.lock();
+        let wanted = if write { OPEN4_SHARE_ACCESS_WRITE } else { OPEN4_SHARE_ACCESS_READ };
+        if stateid.is_anonymous() || stateid.is_bypass() {
+            let denied = tables.states.values().any(|state| {
+                state.file == file && matches!(state.kind, StateKind::Open { deny, .. } if deny & wanted != 0)
+            });
+            // The READ bypass stateid reads past deny modes
+            let bypassed = stateid.is_bypass() && !write;
+            return if denied && !bypassed { Err(nfsstat4::NFS4ERR_LOCKED) } else { Ok(()) };
+        }
+        let state = self.lookup(&tables, stateid, file)?;
+        let open = match state.kind {
+            StateKind::Open { .. } => state,
+            StateKind::Lock { open } => tables.states.get(&open).ok_or(nfsstat4::NFS4ERR_BAD_STATEID)?,
+        };
+        match open.kind {
+            StateKind::Open { access, .. } if access & wanted != 0 => Ok(()),
+            // Reads through opens for writing are allowed, as clients read to fill their caches
+            StateKind::Open { access, .. } if !write && access & OPEN4_SHARE_ACCESS_WRITE != 0 => Ok(()),
+            _ => Err(nfsstat4::NFS4ERR_OPENMODE),
+        }
+    }
+
+    pub fn close(&self, stateid: &stateid4, file: FileKey) -> Result<ClosedOpen, nfsstat4> {
+        let mut tables = self.tables.lock();
+        let state = self.lookup(&tables, stateid, file)?;
+        let StateKind::Open { access, .. } = state.kind else { return Err(nfsstat4::NFS4ERR_BAD_STATEID) };
+        let other = stateid.other;
+        let mut lock_owners = Vec::new();
+        tables.states.retain(|key, state| match state.kind {
+            StateKind::Lock { open } if open == other => {
+                lock_owners.push(state.owner.clone());
+                false
+            }
+            _ => *key != other,
+        });
+        Ok(ClosedOpen { access, lock_owners })
+    }
+
+    // Narrows an open to share access and deny it already has
+    pub fn downgrade(&self, stateid: &stateid4, file: FileKey, access: u32, deny: u32) -> Result<stateid4, nfsstat4> {
+        let mut tables = self.tables.lock();
+        self.lookup(&tables, stateid, file)?;
+        let state = tables.states.get_mut(&stateid.other).unwrap();
+        let StateKind::Open { access: held, deny: denied } = state.kind else { return Err(nfsstat4::NFS4ERR_BAD_STATEID) };
+        if access == 0 || access & !held != 0 || deny & !denied != 0 {
+            return Err(nfsstat4::NFS4ERR_INVAL);
+        }
+        state.kind = StateKind::Open { access, deny };
+        state.seqid = state.seqid.wrapping_add(1);
+        Ok(stateid4 { seqid: state.seqid, other: stateid.other })
+    }
+
+    // The lock stateid of a lock owner locking a file through an open, made on its first lock
+    pub fn lock_state_for_open(&self, open_stateid: &stateid4, owner: &lock_owner4, file: FileKey) -> Result<stateid4, nfsstat4> {
+        let mut tables = self.tables.lock();
+        let open = self.lookup(&tables, open_stateid, file)?;
+        if !matches!(open.kind, StateKind::Open { .. }) || open.owner.clientid != owner.clientid {
+            return Err(nfsstat4::NFS4ERR_BAD_STATEID);
+        }
+        let existing = tables.states.iter().find(|(_, state)| {
+            state.owner == *owner && state.file == file && state.kind == StateKind::Lock { open: open_stateid.other }
+        });
+        if let Some((other, state)) = existing {
+            return Ok(stateid4 { seqid: state.seqid, other: *other });
+        }
+        let other = self.new_other(&mut tables);
+        let kind = StateKind::Lock { open: open_stateid.other };
+        tables.states.insert(other, State { kind, seqid: 0, owner: owner.clone(), file });
+        Ok(stateid4 { seqid: 0, other })
+    }
+
+    // Moves a stateid on, after a change of the state it stands for
+    pub fn bump(&self, stateid: &stateid4) -> stateid4 {
+        let mut tables = self.tables.lock();
+        match tables.states.get_mut(&stateid.other) {
+            Some(state) => {
+                state.seqid = state.seqid.wrapping_add(1);
+                stateid4 { seqid: state.seqid, other: stateid.other }
+            }
+            None => *stateid,
+        }
+    }
+
+    pub fn test_stateid(&self, stateid: &stateid4) -> nfsstat4 {
+        let tables = self.tables.lock();
+        match tables.states.get(&stateid.other) {
+            Some(state) => self.lookup(&tables, stateid, state.file).err().unwrap_or(nfsstat4::NFS4_OK),
+            None if self.is_stale_other(&stateid.other) => nfsstat4::NFS4ERR_STALE_STATEID,
+            None => nfsstat4::NFS4ERR_BAD_STATEID,
+        }
+    }
+
+    // Frees a lock stateid whose owner holds no locks any more
+    pub fn free_stateid(&self, stateid: &stateid4, holds_locks: impl Fn(&State) -> bool) -> nfsstat4 {
+        let mut tables = self.tables.lock();
+        let Some(state) = tables.states.get(&stateid.other) else { return self.test_stateid_locked(stateid) };
+        match state.kind {
+            StateKind::Open { .. } => nfsstat4::NFS4ERR_LOCKS_HELD,
+            StateKind::Lock { .. } if holds_locks(state) => nfsstat4::NFS4ERR_LOCKS_HELD,
+            StateKind::Lock { .. } => {
+                tables.states.remove(&stateid.other);
+                nfsstat4::NFS4_OK
+            }
+        }
+    }
+
+    fn test_stateid_locked(&self, stateid: &stateid4) -> nfsstat4 {
+        if self.is_stale_other(&stateid.other) { nfsstat4::NFS4ERR_STALE_STATEID } else { nfsstat4::NFS4ERR_BAD_STATEID }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(state: &Nfs4State, name: &str, verifier: u8) -> ExchangeResult {
+        state.exchange_id(&client_owner4 { co_verifier: [verifier; 8], co_ownerid: name.as_bytes().to_vec() })
+    }
+
+    #[test]
+    fn test_sessions_replay_and_opens_conflict() {
+        let state = Nfs4State::default();
+        let alice = client(&state, "alice-laptop", 1);
+        assert_eq!(client(&state, "alice-laptop", 1).clientid, alice.clientid);
+        let fore = channel_attrs4 { ca_maxoperations: 16, ca_maxrequests: 1000, ..Default::default() };
+        let (sessionid, _, fore) = state.create_session(alice.clientid, alice.sequenceid, &fore).unwrap();
+        assert_eq!(fore.ca_maxrequests, MAX_SLOTS);
+        assert_eq!(state.create_session(alice.clientid, alice.sequenceid, &fore).unwrap().0, sessionid);
+
+        // A slot runs each sequence id once, replaying the cached reply of a retransmission
+        let args = SEQUENCE4args { sa_sessionid: sessionid, sa_sequenceid: 1, sa_slotid: 0, ..Default::default() };
+        assert!(matches!(state.sequence(&args, 1), Ok(SequenceStart::New { .. })));
+        assert_eq!(state.sequence(&args, 1).unwrap_err(), nfsstat4::NFS4ERR_DELAY);
+        state.finish_sequence(&sessionid, 0, Some(b"reply".to_vec()));
+        assert!(matches!(state.sequence(&args, 1), Ok(SequenceStart::Replay(reply)) if reply == b"reply"));
+        let skipped = SEQUENCE4args { sa_sequenceid: 3, ..args.clone() };
+        assert_eq!(state.sequence(&skipped, 1).unwrap_err(), nfsstat4::NFS4ERR_SEQ_MISORDERED);
+
+        // Opens denying writes keep out other owners' writers, but not the same owner's
+        let file = FileKey { export: 0, fileid: 9 };
+        let owner = state_owner4 { clientid: alice.clientid, owner: b"one".to_vec() };
+        let other = state_owner4 { owner: b"two".to_vec(), ..owner.clone() };
+        let open = state.open(&owner, file, OPEN4_SHARE_ACCESS_READ, OPEN4_SHARE_ACCESS_WRITE).unwrap();
+        assert_eq!(state.open(&other, file, OPEN4_SHARE_ACCESS_WRITE, 0).unwrap_err(), nfsstat4::NFS4ERR_SHARE_DENIED);
+        let upgraded = state.open(&owner, file, OPEN4_SHARE_ACCESS_WRITE, 0).unwrap();
+        assert_eq!((upgraded.other, upgraded.seqid), (open.other, 2));
+        assert_eq!(state.check_io(&open, file, true).unwrap_err(), nfsstat4::NFS4ERR_OLD_STATEID);
+        assert!(state.check_io(&upgraded, file, true).is_ok());
+        assert_eq!(state.check_io(&stateid4::default(), file, true).unwrap_err(), nfsstat4::NFS4ERR_LOCKED);
+
+        // Closing drops the lock stateids taken through the open
+        let lock = state.lock_state_for_open(&upgraded, &other, file).unwrap();
+        let closed = state.close(&upgraded, file).unwrap();
+        assert_eq!(closed.lock_owners, vec![other]);
+        assert_eq!(state.test_stateid(&lock), nfsstat4::NFS4ERR_BAD_STATEID);
+
+        // A client that restarts loses its sessions
+        let restarted = client(&state, "alice-laptop", 2);
+        assert_eq!(restarted.replaced, Some(alice.clientid));
+        assert!(!state.session_exists(&sessionid));
+        assert_eq!(state.destroy_clientid(alice.clientid).unwrap_err(), nfsstat4::NFS4ERR_STALE_CLIENTID);
+    }
+}
@@ -0,0 +1,345 @@
+use crate::kernel::protocol::context::RPCContext;
+use crate::kernel::api::nfs;
+use crate::kernel::api::nlm::*;
+use crate::kernel::protocol::rpc::*;
+use crate::kernel::protocol::xdr::*;
+use crate::kernel::vfs::api::{fh_export_index, NFSFileSystem, VFSCapabilities};
+use crate::kernel::vfs::exports::READ_ONLY_VIEW;
+use crate::kernel::vfs::locks::{range_end, range_len, FileKey, LockOwner};
+use crate::backingstore::host_monitor;
+use num_derive::{FromPrimitive, ToPrimitive};
+use num_traits::cast::FromPrimitive;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::{debug, error, warn};
+
+/*
+ The synchronous procedures of NLM version 4. Blocked locks are not queued and no GRANTED
+ callbacks are made: a client told NLM4_BLOCKED retries its lock itself, as Linux clients do
+ every 30 seconds. The asynchronous _MSG and _RES procedures and DOS file sharing are not
+ offered.
+*/
+
+#[allow(non_camel_case_types)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, FromPrimitive, ToPrimitive)]
+enum NlmProgram {
+    NLMPROC4_NULL = 0,
+    NLMPROC4_TEST = 1,
+    NLMPROC4_LOCK = 2,
+    NLMPROC4_CANCEL = 3,
+    NLMPROC4_UNLOCK = 4,
+    NLMPROC4_GRANTED = 5,
+    NLMPROC4_SHARE = 20,
+    NLMPROC4_UNSHARE = 21,
+    NLMPROC4_NM_LOCK = 22,
+    NLMPROC4_FREE_ALL = 23,
+    INVALID,
+}
+
+pub async fn handle_nlm(
+    xid: u32,
+    call: call_body,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    if call.vers != VERSION {
+        warn!("Invalid NLM Version number {} != {}", call.vers, VERSION);
+        prog_mismatch_reply_message(xid, VERSION).serialize(output)?;
+        return Ok(());
+    }
+    let prog = NlmProgram::from_u32(call.proc).unwrap_or(NlmProgram::INVALID);
+
+    match prog {
+        NlmProgram::NLMPROC4_NULL => {
+            debug!("nlmproc4_null({:?}) ", xid);
+            make_success_reply(xid).serialize(output)?;
+        }
+        NlmProgram::NLMPROC4_TEST => nlmproc4_test(xid, input, output, context).await?,
+        NlmProgram::NLMPROC4_LOCK => nlmproc4_lock(xid, input, output, context, true).await?,
+        NlmProgram::NLMPROC4_CANCEL => nlmproc4_cancel(xid, input, output)?,
+        NlmProgram::NLMPROC4_UNLOCK => nlmproc4_unlock(xid, input, output, context).await?,
+        // Monitoring is for hosts that will notify of their restarts; NM_LOCK is for those that won't
+        NlmProgram::NLMPROC4_NM_LOCK => nlmproc4_lock(xid, input, output, context, false).await?,
+        NlmProgram::NLMPROC4_FREE_ALL => nlmproc4_free_all(xid, input, output, context).await?,
+        _ => {
+            proc_unavail_reply_message(xid).serialize(output)?;
+        }
+    }
+    Ok(())
+}
+
+// The file a lock is for, as the same file whichever view of its export the handle is of
+async fn resolve(
+    context: &RPCContext,
+    fh: &netobj,
+) -> Result<(FileKey, Arc<dyn NFSFileSystem + Send + Sync>), nlm4_stats> {
+    let handle = nfs::nfs_fh3 { data: fh.clone() };
+    let index = fh_export_index(&handle).ok_or(nlm4_stats::NLM4_STALE_FH)?;
+    let vfs = context.exports.filesystem(index).ok_or(nlm4_stats::NLM4_STALE_FH)?.clone();
+    let fileid = vfs.fh_to_id(&handle).await.map_err(|_| nlm4_stats::NLM4_STALE_FH)?;
+    Ok((FileKey { export: index & !READ_ONLY_VIEW, fileid }, vfs))
+}
+
+fn lock_owner(lock: &nlm4_lock) -> LockOwner {
+    LockOwner {
+        host: String::from_utf8_lossy(&lock.caller_name).into_owned(),
+        svid: lock.svid,
+        oh: lock.oh.clone(),
+    }
+}
+
+// The IP address of the client, without its port
+fn client_ip(context: &RPCContext) -> String {
+    context
+        .client_addr
+        .parse::<SocketAddr>()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|_| context.client_addr.clone())
+}
+
+pub async fn nlmproc4_test(
+    xid: u32,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let mut args = nlm4_testargs::default();
+    args.deserialize(input)?;
+    debug!("nlmproc4_test({:?}, {:?}) ", xid, args);
+    let lock = &args.alock;
+    let test_stat = if context.locks.in_grace_period() {
+        nlm4_testrply::Stat(nlm4_stats::NLM4_DENIED_GRACE_PERIOD)
+    } else {
+        match resolve(context, &lock.fh).await {
+            Err(stat) => nlm4_testrply::Stat(stat),
+            Ok((file, _)) => {
+                let end = range_end(lock.l_offset, lock.l_len);
+                match context.locks.test(file, &lock_owner(lock), args.exclusive, lock.l_offset, end) {
+                    Some(holder) => nlm4_testrply::Denied(nlm4_holder {
+                        exclusive: holder.exclusive,
+                        svid: holder.owner.svid,
+                        oh: holder.owner.oh,
+                        l_offset: holder.start,
+                        l_len: range_len(holder.start, holder.end),
+                    }),
+                    None => nlm4_testrply::Stat(nlm4_stats::NLM4_GRANTED),
+                }
+            }
+        }
+    };
+    debug!("\t{:?} --> {:?}", xid, test_stat);
+    make_success_reply(xid).serialize(output)?;
+    nlm4_testres { cookie: args.cookie, test_stat }.serialize(output)?;
+    Ok(())
+}
+
+pub async fn nlmproc4_lock(
+    xid: u32,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+    monitor: bool,
+) -> Result<(), anyhow::Error> {
+    let mut args = nlm4_lockargs::default();
+    args.deserialize(input)?;
+    debug!("nlmproc4_lock({:?}, {:?}) ", xid, args);
+    let stat = lock(&args, context, monitor).await;
+    debug!("\t{:?} --> {:?}", xid, stat);
+    make_success_reply(xid).serialize(output)?;
+    nlm4_res { cookie: args.cookie, stat }.serialize(output)?;
+    Ok(())
+}
+
+async fn lock(args: &nlm4_lockargs, context: &RPCContext, monitor: bool) -> nlm4_stats {
+    // Until the grace period is over only locks held before the restart may be taken again
+    if context.locks.in_grace_period() && !args.reclaim {
+        return nlm4_stats::NLM4_DENIED_GRACE_PERIOD;
+    }
+    let (file, vfs) = match resolve(context, &args.alock.fh).await {
+        Ok(resolved) => resolved,
+        Err(stat) => return stat,
+    };
+    if args.exclusive && !matches!(vfs.capabilities(), VFSCapabilities::ReadWrite) {
+        return nlm4_stats::NLM4_ROFS;
+    }
+    let owner = lock_owner(&args.alock);
+    let end = range_end(args.alock.l_offset, args.alock.l_len);
+    if let Err(conflict) = context.locks.lock(file, owner.clone(), args.exclusive, args.alock.l_offset, end) {
+        debug!("Lock of {:?} by {:?} conflicts with {:?}", file, owner, conflict);
+        return if args.block { nlm4_stats::NLM4_BLOCKED } else { nlm4_stats::NLM4_DENIED };
+    }
+    vfs.lock_changed(file.fileid, true, &owner.host).await;
+
+    // Recorded, so the host is told to reclaim its locks should the server restart
+    let address = client_ip(context);
+    if context.locks.note_host(&owner.host, &address) && monitor {
+        if let Err(e) = host_monitor::monitor(vfs.data_store(), &owner.host, &address).await {
+            error!("Cannot record {:?} as holding locks: {:?}", owner.host, e);
+        }
+    }
+    nlm4_stats::NLM4_GRANTED
+}
+
+// Blocked locks are not queued, so there is never one to cancel
+pub fn nlmproc4_cancel(
+    xid: u32,
+    input: &mut impl Read,
+    output: &mut impl Write,
+) -> Result<(), anyhow::Error> {
+    let mut args = nlm4_cancargs::default();
+    args.deserialize(input)?;
+    debug!("nlmproc4_cancel({:?}, {:?}) ", xid, args);
+    make_success_reply(xid).serialize(output)?;
+    nlm4_res { cookie: args.cookie, stat: nlm4_stats::NLM4_GRANTED }.serialize(output)?;
+    Ok(())
+}
+
+pub async fn nlmproc4_unlock(
+    xid: u32,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let mut args = nlm4_unlockargs::default();
+    args.deserialize(input)?;
+    debug!("nlmproc4_unlock({:?}, {:?}) ", xid, args);
+    let stat = match resolve(context, &args.alock.fh).await {
+        Ok((file, vfs)) => {
+            let owner = lock_owner(&args.alock);
+            let end = range_end(args.alock.l_offset, args.alock.l_len);
+            if context.locks.unlock(file, &owner, args.alock.l_offset, end) {
+                vfs.lock_changed(file.fileid, false, &owner.host).await;
+            }
+            nlm4_stats::NLM4_GRANTED
+        }
+        Err(stat) => stat,
+    };
+    make_success_reply(xid).serialize(output)?;
+    nlm4_res { cookie: args.cookie, stat }.serialize(output)?;
+    Ok(())
+}
+
+pub async fn nlmproc4_free_all(
+    xid: u32,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let mut args = nlm4_notify::default();
+    args.deserialize(input)?;
+    let name = String::from_utf8_lossy(&args.name);
+    debug!("nlmproc4_free_all({:?}, {:?}) ", xid, name);
+    release_restarted_host(&name, context).await;
+    make_success_reply(xid).serialize(output)?;
+    Ok(())
+}
+
+// Releases the locks of a host that restarted, when told so by the host itself or from the
+// server's own host; a host is known by the address it locked from
+pub async fn release_restarted_host(name: &str, context: &RPCContext) {
+    let address = client_ip(context);
+    let local = context.client_addr.parse::<SocketAddr>().is_ok_and(|addr| addr.ip().is_loopback());
+    let mut hosts = context.locks.hosts_at(&address);
+    if local && !hosts.iter().any(|host| host == name) {
+        hosts.push(name.to_string());
+    }
+    for host in hosts {
+        for file in context.locks.release_host(&host) {
+            warn!("Released the locks of restarted host {:?} of {:?}", host, file);
+            if let Some(vfs) = context.exports.filesystem(file.export) {
+                vfs.lock_changed(file.fileid, false, &host).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::protocol::rpc::NonceCache;
+    use crate::kernel::protocol::rpcbind::RpcbindRegistry;
+    use crate::kernel::vfs::exports::ExportTable;
+    use crate::kernel::vfs::locks::LockManager;
+    use crate::kernel::vfs::mock::MockNFSFileSystem;
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    fn context(client_addr: &str, locks: Arc<LockManager>) -> RPCContext {
+        let fs = Arc::new(MockNFSFileSystem::new_readwrite());
+        RPCContext {
+            local_port: 2049,
+            client_addr: client_addr.to_string(),
+            auth: None,
+            caller: None,
+            nonces: Arc::new(NonceCache::default()),
+            tls: None,
+            rpcbind: Arc::new(RpcbindRegistry::default()),
+            drc: None,
+            max_reply: None,
+            locks,
+            nfs4: None,
+            vfs: fs.clone(),
+            exports: Arc::new(ExportTable::single(fs)),
+            mount_signal: None,
+        }
+    }
+
+    // Makes a call and returns its results, after the reply header
+    async fn call(context: &RPCContext, proc: u32, args: &impl XDR) -> Vec<u8> {
+        let mut input = Vec::new();
+        args.serialize(&mut input).unwrap();
+        let call = call_body { rpcvers: 2, prog: PROGRAM, vers: VERSION, proc, ..Default::default() };
+        let mut output = Cursor::new(Vec::new());
+        handle_nlm(1, call, &mut Cursor::new(input), &mut output, context).await.unwrap();
+        output.into_inner()[24..].to_vec()
+    }
+
+    async fn lock(context: &RPCContext, host: &str, exclusive: bool, reclaim: bool) -> nlm4_stats {
+        let alock = nlm4_lock {
+            caller_name: host.as_bytes().to_vec(),
+            fh: context.vfs.id_to_fh(1).data,
+            oh: b"owner".to_vec(),
+            svid: 7,
+            l_offset: 0,
+            l_len: 0,
+        };
+        let args = nlm4_lockargs { block: true, exclusive, alock, reclaim, ..Default::default() };
+        let mut res = nlm4_res::default();
+        res.deserialize(&mut Cursor::new(call(context, 2, &args).await)).unwrap();
+        res.stat
+    }
+
+    #[tokio::test]
+    async fn test_locks_conflict_until_their_host_restarts() {
+        let locks = Arc::new(LockManager::default());
+        let alice = context("10.0.0.1:800", locks.clone());
+        let bob = context("10.0.0.2:800", locks.clone());
+        assert_eq!(lock(&alice, "alice-laptop", true, false).await, nlm4_stats::NLM4_GRANTED);
+        assert_eq!(lock(&bob, "bob-laptop", false, false).await, nlm4_stats::NLM4_BLOCKED);
+
+        // TEST names the holder of the conflicting lock
+        let args = nlm4_testargs {
+            exclusive: false,
+            alock: nlm4_lock { caller_name: b"bob-laptop".to_vec(), fh: bob.vfs.id_to_fh(1).data, svid: 3, ..Default::default() },
+            ..Default::default()
+        };
+        let mut res = nlm4_testres::default();
+        res.deserialize(&mut Cursor::new(call(&bob, 1, &args).await)).unwrap();
+        assert!(matches!(res.test_stat, nlm4_testrply::Denied(holder) if holder.svid == 7 && holder.exclusive));
+
+        // Alice's host restarting, said from its own address, frees the file
+        let notify = nlm4_notify { name: b"alice-laptop".to_vec(), state: 3 };
+        call(&bob, 23, &notify).await;
+        assert_eq!(lock(&bob, "bob-laptop", false, false).await, nlm4_stats::NLM4_BLOCKED);
+        call(&alice, 23, &notify).await;
+        assert_eq!(lock(&bob, "bob-laptop", false, false).await, nlm4_stats::NLM4_GRANTED);
+
+        // After a restart only reclaims are granted until the grace period is over
+        let restarted = context("10.0.0.1:800", Arc::new(LockManager::new(Duration::from_secs(60))));
+        assert_eq!(lock(&restarted, "alice-laptop", true, false).await, nlm4_stats::NLM4_DENIED_GRACE_PERIOD);
+        assert_eq!(lock(&restarted, "alice-laptop", true, true).await, nlm4_stats::NLM4_GRANTED);
+    }
+}
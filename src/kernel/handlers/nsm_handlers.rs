@@ -0,0 +1,88 @@
+use crate::kernel::protocol::context::RPCContext;
+use crate::kernel::api::nsm::*;
+use crate::kernel::handlers::nlm_handlers;
+use crate::kernel::protocol::rpc::*;
+use crate::kernel::protocol::xdr::*;
+use num_derive::{FromPrimitive, ToPrimitive};
+use num_traits::cast::FromPrimitive;
+use std::io::{Read, Write};
+use tracing::{debug, warn};
+
+/*
+ The status monitor only as far as the lock manager needs one: it reports the server's state
+ and hears from client hosts that restarted. Monitoring for other programs is refused, since
+ the server's lock manager records the hosts holding locks itself.
+*/
+
+#[allow(non_camel_case_types)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, FromPrimitive, ToPrimitive)]
+enum NsmProgram {
+    SM_NULL = 0,
+    SM_STAT = 1,
+    SM_MON = 2,
+    SM_UNMON = 3,
+    SM_UNMON_ALL = 4,
+    SM_SIMU_CRASH = 5,
+    SM_NOTIFY = 6,
+    INVALID,
+}
+
+pub async fn handle_nsm(
+    xid: u32,
+    call: call_body,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    if call.vers != VERSION {
+        warn!("Invalid NSM Version number {} != {}", call.vers, VERSION);
+        prog_mismatch_reply_message(xid, VERSION).serialize(output)?;
+        return Ok(());
+    }
+    let prog = NsmProgram::from_u32(call.proc).unwrap_or(NsmProgram::INVALID);
+    let state = context.locks.state();
+
+    match prog {
+        NsmProgram::SM_NULL => {
+            debug!("sm_null({:?}) ", xid);
+            make_success_reply(xid).serialize(output)?;
+        }
+        NsmProgram::SM_STAT => {
+            debug!("sm_stat({:?}) ", xid);
+            make_success_reply(xid).serialize(output)?;
+            sm_stat_res { res_stat: res::STAT_SUCC, state }.serialize(output)?;
+        }
+        NsmProgram::SM_MON => {
+            debug!("sm_mon({:?}) refused", xid);
+            make_success_reply(xid).serialize(output)?;
+            sm_stat_res { res_stat: res::STAT_FAIL, state }.serialize(output)?;
+        }
+        NsmProgram::SM_UNMON | NsmProgram::SM_UNMON_ALL => {
+            debug!("sm_unmon({:?}) ", xid);
+            make_success_reply(xid).serialize(output)?;
+            sm_stat { state }.serialize(output)?;
+        }
+        NsmProgram::SM_NOTIFY => sm_notify(xid, input, output, context).await?,
+        _ => {
+            proc_unavail_reply_message(xid).serialize(output)?;
+        }
+    }
+    Ok(())
+}
+
+// A client host announcing it restarted, so the locks it held are gone
+pub async fn sm_notify(
+    xid: u32,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let mut args = stat_chge::default();
+    args.deserialize(input)?;
+    let name = String::from_utf8_lossy(&args.mon_name);
+    debug!("sm_notify({:?}, {:?}, {:?}) ", xid, name, args.state);
+    nlm_handlers::release_restarted_host(&name, context).await;
+    make_success_reply(xid).serialize(output)?;
+    Ok(())
+}
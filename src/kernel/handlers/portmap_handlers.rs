@@ -1,10 +1,14 @@
 use crate::kernel::protocol::context::RPCContext;
 use crate::kernel::api::portmap;
 use crate::kernel::protocol::rpc::*;
+use crate::kernel::protocol::rpcbind::{self, merge_address, netid_protocol, protocol_netid, universal_address};
+use crate::kernel::protocol::rpcwire::call_in_process;
 use crate::kernel::protocol::xdr::*;
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::cast::FromPrimitive;
 use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tracing::{debug, error};
 
 /*
@@ -35,27 +39,74 @@ enum PortmapProgram {
     INVALID,
 }
 
-pub fn handle_portmap(
+/*
+ From RFC 1833, versions 3 and 4 of the same program. Version 4 renames CALLIT to BCAST and
+ adds GETVERSADDR, INDIRECT, GETADDRLIST and GETSTAT.
+*/
+
+#[allow(non_camel_case_types)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, FromPrimitive, ToPrimitive)]
+enum RpcbindProgram {
+    RPCBPROC_NULL = 0,
+    RPCBPROC_SET = 1,
+    RPCBPROC_UNSET = 2,
+    RPCBPROC_GETADDR = 3,
+    RPCBPROC_DUMP = 4,
+    RPCBPROC_CALLIT = 5,
+    RPCBPROC_GETTIME = 6,
+    RPCBPROC_UADDR2TADDR = 7,
+    RPCBPROC_TADDR2UADDR = 8,
+    RPCBPROC_GETVERSADDR = 9,
+    RPCBPROC_INDIRECT = 10,
+    RPCBPROC_GETADDRLIST = 11,
+    RPCBPROC_GETSTAT = 12,
+    INVALID,
+}
+
+pub async fn handle_portmap(
     xid: u32,
     call: call_body,
     input: &mut impl Read,
     output: &mut impl Write,
     context: &RPCContext,
 ) -> Result<(), anyhow::Error> {
-    if call.vers != portmap::VERSION {
+    if call.vers == portmap::VERSION {
+        let prog = PortmapProgram::from_u32(call.proc).unwrap_or(PortmapProgram::INVALID);
+        match prog {
+            PortmapProgram::PMAPPROC_NULL => pmapproc_null(xid, input, output)?,
+            PortmapProgram::PMAPPROC_SET => pmapproc_set(xid, input, output, context)?,
+            PortmapProgram::PMAPPROC_UNSET => pmapproc_unset(xid, input, output, context)?,
+            PortmapProgram::PMAPPROC_GETPORT => pmapproc_getport(xid, input, output, context)?,
+            PortmapProgram::PMAPPROC_DUMP => pmapproc_dump(xid, input, output, context)?,
+            PortmapProgram::PMAPPROC_CALLIT => pmapproc_callit(xid, &call, input, output, context).await?,
+            _ => {
+                proc_unavail_reply_message(xid).serialize(output)?;
+            }
+        }
+        return Ok(());
+    }
+    if call.vers != portmap::RPCBVERS && call.vers != portmap::RPCBVERS4 {
         error!(
-            "Invalid Portmap Version number {} != {}",
+            "Invalid Portmap Version number {} not in {}..={}",
             call.vers,
-            portmap::VERSION
+            portmap::VERSION,
+            portmap::RPCBVERS4
         );
-        prog_mismatch_reply_message(xid, portmap::VERSION).serialize(output)?;
+        prog_versions_mismatch_reply_message(xid, portmap::VERSION, portmap::RPCBVERS4).serialize(output)?;
         return Ok(());
     }
-    let prog = PortmapProgram::from_u32(call.proc).unwrap_or(PortmapProgram::INVALID);
-
+    let v4 = call.vers == portmap::RPCBVERS4;
+    let prog = RpcbindProgram::from_u32(call.proc).unwrap_or(RpcbindProgram::INVALID);
     match prog {
-        PortmapProgram::PMAPPROC_NULL => pmapproc_null(xid, input, output)?,
-        PortmapProgram::PMAPPROC_GETPORT => pmapproc_getport(xid, input, output, context)?,
+        RpcbindProgram::RPCBPROC_NULL => pmapproc_null(xid, input, output)?,
+        RpcbindProgram::RPCBPROC_SET => rpcbproc_set(xid, input, output, context)?,
+        RpcbindProgram::RPCBPROC_UNSET => rpcbproc_unset(xid, input, output, context)?,
+        RpcbindProgram::RPCBPROC_GETADDR => rpcbproc_getaddr(xid, input, output, context, false)?,
+        RpcbindProgram::RPCBPROC_GETVERSADDR if v4 => rpcbproc_getaddr(xid, input, output, context, true)?,
+        RpcbindProgram::RPCBPROC_DUMP => rpcbproc_dump(xid, input, output, context)?,
+        RpcbindProgram::RPCBPROC_CALLIT => rpcbproc_callit(xid, &call, input, output, context).await?,
+        RpcbindProgram::RPCBPROC_GETTIME => rpcbproc_gettime(xid, input, output)?,
         _ => {
             proc_unavail_reply_message(xid).serialize(output)?;
         }
@@ -76,8 +127,54 @@ pub fn pmapproc_null(
     Ok(())
 }
 
+// As with rpcbind, only programs on the server's own host may change its mappings
+fn from_loopback(context: &RPCContext) -> bool {
+    context.client_addr.parse::<SocketAddr>().is_ok_and(|addr| match addr.ip() {
+        IpAddr::V6(v6) => v6.is_loopback() || v6.to_ipv4_mapped().is_some_and(|v4| v4.is_loopback()),
+        ip => ip.is_loopback(),
+    })
+}
+
+pub fn pmapproc_set(
+    xid: u32,
+    read: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let mut mapping = portmap::mapping::default();
+    mapping.deserialize(read)?;
+    debug!("pmapproc_set({:?}, {:?}) ", xid, mapping);
+    let set = match (protocol_netid(mapping.prot), u16::try_from(mapping.port)) {
+        (Some(netid), Ok(port)) if from_loopback(context) => {
+            let addr = universal_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
+            context.rpcbind.set(mapping.prog, mapping.vers, netid, &addr, "")
+        }
+        _ => false,
+    };
+    make_success_reply(xid).serialize(output)?;
+    set.serialize(output)?;
+    Ok(())
+}
+
+pub fn pmapproc_unset(
+    xid: u32,
+    read: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let mut mapping = portmap::mapping::default();
+    mapping.deserialize(read)?;
+    debug!("pmapproc_unset({:?}, {:?}) ", xid, mapping);
+    // Version 2 UNSET ignores the protocol and port
+    let unset = from_loopback(context) && context.rpcbind.unset(mapping.prog, mapping.vers, None);
+    make_success_reply(xid).serialize(output)?;
+    unset.serialize(output)?;
+    Ok(())
+}
+
 /*
- * We fake a portmapper here. And always direct back to the same host port
+ * The port of a program's version over the transport asked for, or of another of its
+ * versions, or 0 when it is not registered
  */
 pub fn pmapproc_getport(
     xid: u32,
@@ -89,8 +186,169 @@ pub fn pmapproc_getport(
     mapping.deserialize(read)?;
     debug!("pmapproc_getport({:?}, {:?}) ", xid, mapping);
     make_success_reply(xid).serialize(output)?;
-    let port = context.local_port as u32;
+    let port = context.rpcbind.port(mapping.prog, mapping.vers, mapping.prot);
     debug!("\t{:?} --> {:?}", xid, port);
     port.serialize(output)?;
     Ok(())
 }
+
+pub fn pmapproc_dump(
+    xid: u32,
+    _: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    debug!("pmapproc_dump({:?}) ", xid);
+    make_success_reply(xid).serialize(output)?;
+    // Version 2 only knows of TCP and UDP over IPv4
+    for entry in context.rpcbind.dump() {
+        let (Some(prot), Some(port)) = (netid_protocol(&entry.netid), rpcbind::address_port(&entry.addr)) else {
+            continue;
+        };
+        true.serialize(output)?;
+        portmap::mapping { prog: entry.prog, vers: entry.vers, prot, port: port as u32 }.serialize(output)?;
+    }
+    // next mapping
+    false.serialize(output)?;
+    Ok(())
+}
+
+/*
+ * Runs a procedure of a program this server serves, as if called directly. Nothing is sent
+ * back when the program is not served here or the procedure fails, so broadcasts are only
+ * answered by servers that have something to say.
+ */
+pub async fn pmapproc_callit(
+    xid: u32,
+    call: &call_body,
+    read: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let mut args = portmap::call_args::default();
+    args.deserialize(read)?;
+    debug!("pmapproc_callit({:?}, {}, {}, {}) ", xid, args.prog, args.vers, args.proc);
+    if let Some(res) = call_in_process(xid, call, &args, context).await {
+        make_success_reply(xid).serialize(output)?;
+        portmap::call_result { port: context.local_port as u32, res }.serialize(output)?;
+    }
+    Ok(())
+}
+
+pub fn rpcbproc_set(
+    xid: u32,
+    read: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let mut args = portmap::rpcb::default();
+    args.deserialize(read)?;
+    debug!("rpcbproc_set({:?}, {:?}) ", xid, args);
+    let set = match (std::str::from_utf8(&args.r_netid), std::str::from_utf8(&args.r_addr)) {
+        (Ok(netid), Ok(addr)) if from_loopback(context) && rpcbind::address_port(addr).is_some() => {
+            let owner = String::from_utf8_lossy(&args.r_owner);
+            context.rpcbind.set(args.r_prog, args.r_vers, netid, addr, &owner)
+        }
+        _ => false,
+    };
+    make_success_reply(xid).serialize(output)?;
+    set.serialize(output)?;
+    Ok(())
+}
+
+pub fn rpcbproc_unset(
+    xid: u32,
+    read: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let mut args = portmap::rpcb::default();
+    args.deserialize(read)?;
+    debug!("rpcbproc_unset({:?}, {:?}) ", xid, args);
+    // An empty netid unsets the program's version on every transport
+    let netid = String::from_utf8_lossy(&args.r_netid);
+    let netid = (!netid.is_empty()).then_some(netid.as_ref());
+    let unset = from_loopback(context) && context.rpcbind.unset(args.r_prog, args.r_vers, netid);
+    make_success_reply(xid).serialize(output)?;
+    unset.serialize(output)?;
+    Ok(())
+}
+
+/*
+ * The universal address of a program on the netid asked for, the empty string when it is not
+ * registered. GETVERSADDR only answers for the exact version.
+ */
+pub fn rpcbproc_getaddr(
+    xid: u32,
+    read: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+    exact: bool,
+) -> Result<(), anyhow::Error> {
+    let mut args = portmap::rpcb::default();
+    args.deserialize(read)?;
+    debug!("rpcbproc_getaddr({:?}, {:?}) ", xid, args);
+    let addr = context
+        .rpcbind
+        .lookup(args.r_prog, args.r_vers, &String::from_utf8_lossy(&args.r_netid), exact)
+        .map(|entry| merge_address(&entry.addr, &context.client_addr))
+        .unwrap_or_default();
+    debug!("\t{:?} --> {:?}", xid, addr);
+    make_success_reply(xid).serialize(output)?;
+    addr.into_bytes().serialize(output)?;
+    Ok(())
+}
+
+pub fn rpcbproc_dump(
+    xid: u32,
+    _: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    debug!("rpcbproc_dump({:?}) ", xid);
+    make_success_reply(xid).serialize(output)?;
+    for entry in context.rpcbind.dump() {
+        true.serialize(output)?;
+        portmap::rpcb {
+            r_prog: entry.prog,
+            r_vers: entry.vers,
+            r_netid: entry.netid.into_bytes(),
+            r_addr: entry.addr.into_bytes(),
+            r_owner: entry.owner.into_bytes(),
+        }
+        .serialize(output)?;
+    }
+    // next rpcb
+    false.serialize(output)?;
+    Ok(())
+}
+
+pub async fn rpcbproc_callit(
+    xid: u32,
+    call: &call_body,
+    read: &mut impl Read,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let mut args = portmap::call_args::default();
+    args.deserialize(read)?;
+    debug!("rpcbproc_callit({:?}, {}, {}, {}) ", xid, args.prog, args.vers, args.proc);
+    if let Some(results) = call_in_process(xid, call, &args, context).await {
+        let addr = universal_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED), context.local_port);
+        make_success_reply(xid).serialize(output)?;
+        portmap::rpcb_rmtcallres { addr: merge_address(&addr, &context.client_addr).into_bytes(), results }.serialize(output)?;
+    }
+    Ok(())
+}
+
+pub fn rpcbproc_gettime(
+    xid: u32,
+    _: &mut impl Read,
+    output: &mut impl Write,
+) -> Result<(), anyhow::Error> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs() as u32);
+    debug!("rpcbproc_gettime({:?}) --> {:?}", xid, now);
+    make_success_reply(xid).serialize(output)?;
+    now.serialize(output)?;
+    Ok(())
+}
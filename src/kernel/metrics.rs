@@ -52,6 +52,30 @@ lazy_static! {
         "Total number of bytes sent",
         REGISTRY
     ).unwrap();
+
+    pub static ref METADATA_CACHE_HITS: IntCounter = register_int_counter_with_registry!(
+        "graymamba_metadata_cache_hits_total",
+        "Total number of metadata reads served from the cache",
+        REGISTRY
+    ).unwrap();
+
+    pub static ref METADATA_CACHE_MISSES: IntCounter = register_int_counter_with_registry!(
+        "graymamba_metadata_cache_misses_total",
+        "Total number of metadata reads passed through to the backing store",
+        REGISTRY
+    ).unwrap();
+
+    pub static ref DUPLICATE_REQUEST_HITS: IntCounter = register_int_counter_with_registry!(
+        "graymamba_duplicate_request_hits_total",
+        "Total number of retransmitted calls answered with their cached reply",
+        REGISTRY
+    ).unwrap();
+
+    pub static ref DUPLICATE_REQUESTS_DROPPED: IntCounter = register_int_counter_with_registry!(
+        "graymamba_duplicate_requests_dropped_total",
+        "Total number of retransmitted calls dropped while the original was in progress",
+        REGISTRY
+    ).unwrap();
 }
 
 pub fn init() {
@@ -64,5 +88,9 @@ pub fn init() {
     lazy_static::initialize(&FRAGMENTS_PROCESSED);
     lazy_static::initialize(&BYTES_RECEIVED);
     lazy_static::initialize(&BYTES_SENT);
+    lazy_static::initialize(&METADATA_CACHE_HITS);
+    lazy_static::initialize(&METADATA_CACHE_MISSES);
+    lazy_static::initialize(&DUPLICATE_REQUEST_HITS);
+    lazy_static::initialize(&DUPLICATE_REQUESTS_DROPPED);
     info!("Metrics initialization complete");
 }
\ No newline at end of file
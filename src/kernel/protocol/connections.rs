@@ -0,0 +1,195 @@
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
+
+// Limits on the connections of all the listeners that share them; None is unlimited
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectionLimits {
+    pub max_connections: Option<usize>,
+    pub max_per_client: Option<usize>,
+    // A connection with no call in flight that sends nothing for this long is closed
+    pub idle_timeout: Option<Duration>,
+}
+
+#[derive(Debug, Default)]
+struct Open {
+    total: usize,
+    clients: HashMap<IpAddr, usize>,
+    // UDP calls being handled, which have no connection
+    calls: usize,
+}
+
+// The connections open on the server's listeners, counted per client so the limits can be
+// held to, and drained when the server shuts down: listeners stop accepting, connections stop
+// reading calls and close once those in flight are answered.
+#[derive(Debug)]
+pub struct Connections {
+    limits: ConnectionLimits,
+    open: Mutex<Open>,
+    closed: Notify,
+    draining: watch::Sender<bool>,
+}
+
+impl Default for Connections {
+    fn default() -> Self {
+        Connections::new(ConnectionLimits::default())
+    }
+}
+
+impl Connections {
+    pub fn new(limits: ConnectionLimits) -> Connections {
+        Connections {
+            limits,
+            open: Mutex::new(Open::default()),
+            closed: Notify::new(),
+            draining: watch::Sender::new(false),
+        }
+    }
+
+    pub fn limits(&self) -> &ConnectionLimits {
+        &self.limits
+    }
+
+    // A connection from client, open until the guard is dropped, or None when the limits do
+    // not allow another or the server is draining
+    pub fn admit(self: &Arc<Self>, client: IpAddr) -> Option<Connection> {
+        // Checked under the lock, so a drain that has started counts whatever was admitted
+        let mut open = self.open.lock();
+        if self.is_draining() {
+            return None;
+        }
+        let from_client = open.clients.get(&client).copied().unwrap_or(0);
+        if self.limits.max_connections.is_some_and(|max| open.total >= max)
+            || self.limits.max_per_client.is_some_and(|max| from_client >= max)
+        {
+            return None;
+        }
+        open.total += 1;
+        open.clients.insert(client, from_client + 1);
+        Some(Connection { connections: self.clone(), client: Some(client) })
+    }
+
+    // A UDP call being handled, which a drain waits for like a connection
+    pub fn call(self: &Arc<Self>) -> Connection {
+        self.open.lock().calls += 1;
+        Connection { connections: self.clone(), client: None }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    // Resolves once the server starts draining
+    pub async fn draining(&self) {
+        let _ = self.draining.subscribe().wait_for(|draining| *draining).await;
+    }
+
+    // Starts draining and waits, up to timeout, for every connection and call to finish.
+    // Whether they all did.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.draining.send_replace(true);
+        let deadline = Instant::now() + timeout;
+        loop {
+            let closed = self.closed.notified();
+            if self.open_now() == 0 {
+                return true;
+            }
+            if tokio::time::timeout_at(deadline, closed).await.is_err() {
+                return false;
+            }
+        }
+    }
+
+    // Connections and UDP calls open now
+    pub fn open_now(&self) -> usize {
+        let open = self.open.lock();
+        open.total + open.calls
+    }
+}
+
+// An admitted connection, or a UDP call, given up when dropped
+#[derive(Debug)]
+pub struct Connection {
+    connections: Arc<Connections>,
+    client: Option<IpAddr>,
+}
+
+impl Connection {
+    pub fn connections(&self) -> &Connections {
+        &self.connections
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut open = self.connections.open.lock();
+        match self.client {
+            Some(client) => {
+                open.total -= 1;
+                if let Some(count) = open.clients.get_mut(&client) {
+                    *count -= 1;
+                    if *count == 0 {
+                        open.clients.remove(&client);
+                    }
+                }
+            }
+            None => open.calls -= 1,
+        }
+        drop(open);
+        self.connections.closed.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admits_within_the_limits() {
+        let connections = Arc::new(Connections::new(ConnectionLimits {
+            max_connections: Some(3),
+            max_per_client: Some(2),
+            idle_timeout: None,
+        }));
+        let (alice, bob, carol) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap(), "10.0.0.3".parse().unwrap());
+
+        let first = connections.admit(alice).unwrap();
+        let _second = connections.admit(alice).unwrap();
+        assert!(connections.admit(alice).is_none());
+        let _third = connections.admit(bob).unwrap();
+        assert!(connections.admit(carol).is_none());
+
+        // A closed connection makes room for another, from anyone
+        drop(first);
+        let _fourth = connections.admit(carol).unwrap();
+        assert_eq!(connections.open_now(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_drain_waits_for_connections_and_calls() {
+        let connections = Arc::new(Connections::default());
+        let connection = connections.admit("10.0.0.1".parse().unwrap()).unwrap();
+        let call = connections.call();
+
+        let draining = connections.clone();
+        let drained = tokio::spawn(async move { draining.drain(Duration::from_secs(5)).await });
+        connections.draining().await;
+        assert!(connections.admit("10.0.0.2".parse().unwrap()).is_none());
+
+        drop(connection);
+        drop(call);
+        assert!(drained.await.unwrap());
+        assert_eq!(connections.open_now(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_drain_gives_up_at_the_timeout() {
+        let connections = Arc::new(Connections::default());
+        let _connection = connections.admit("10.0.0.1".parse().unwrap()).unwrap();
+        assert!(!connections.drain(Duration::from_millis(20)).await);
+    }
+}
@@ -1,14 +1,48 @@
+use crate::backingstore::data_store::DataStore;
+use crate::backingstore::user_registry::{self, UserRecord};
+use crate::kernel::handlers::nfs4::Nfs4State;
+use crate::kernel::protocol::drc::DuplicateRequestCache;
+use crate::kernel::protocol::rpc::NonceCache;
+use crate::kernel::protocol::rpcbind::RpcbindRegistry;
+use crate::kernel::protocol::tls::TlsSession;
 use crate::kernel::vfs::api::NFSFileSystem;
+use crate::kernel::vfs::exports::{ExportTable, READ_ONLY_VIEW};
+use crate::kernel::vfs::locks::LockManager;
 use std::fmt;
 use std::sync::Arc;
 use tokio::sync::mpsc;
 
+// The ports the server listens on for each transport, which it registers its programs with
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ListenerPorts {
+    pub tcp: Option<u16>,
+    pub udp: Option<u16>,
+}
+
 #[derive(Clone)]
 pub struct RPCContext {
     pub local_port: u16,
     pub client_addr: String,
-    pub auth: crate::kernel::protocol::rpc::auth_unix,
+    // The unix ids of the call's AUTH_UNIX or AUTH_SIGNED credential, if it had one
+    pub auth: Option<crate::kernel::protocol::rpc::auth_unix>,
+    // The registered user an AUTH_SIGNED credential or the connection's client certificate
+    // was verified for
+    pub caller: Option<UserRecord>,
+    pub nonces: Arc<NonceCache>,
+    // The connection's TLS state, when its listener offers RPC-over-TLS
+    pub tls: Option<Arc<TlsSession>>,
+    // The programs the server offers, for portmap to answer with
+    pub rpcbind: Arc<RpcbindRegistry>,
+    // Replies to non-idempotent calls, when the transport answers retransmissions from them
+    pub drc: Option<Arc<DuplicateRequestCache>>,
+    // The largest reply the transport can carry, when it has a limit
+    pub max_reply: Option<usize>,
+    // The advisory locks clients hold of the files of the exports
+    pub locks: Arc<LockManager>,
+    // The NFSv4 clients, sessions and open state, when the transport serves NFSv4
+    pub nfs4: Option<Arc<Nfs4State>>,
     pub vfs: Arc<dyn NFSFileSystem + Send + Sync>,
+    pub exports: Arc<ExportTable>,
     pub mount_signal: Option<mpsc::Sender<bool>>
 }
 
@@ -18,6 +52,43 @@ impl fmt::Debug for RPCContext {
             .field("local_port", &self.local_port)
             .field("client_addr", &self.client_addr)
             .field("auth", &self.auth)
+            .field("caller", &self.caller.as_ref().map(|user| &user.name))
+            .field("tls", &self.tls)
             .finish()
     }
 }
+
+impl RPCContext {
+    // Whether the call came over a connection an AUTH_TLS probe upgraded to TLS
+    pub fn over_tls(&self) -> bool {
+        self.tls.as_ref().is_some_and(|tls| tls.is_established())
+    }
+
+    // The user making the call: the one who signed it or holds the connection's client
+    // certificate, else the registered user data_store binds to the credential's uid
+    pub async fn user_name(&self, data_store: &dyn DataStore) -> Option<String> {
+        if let Some(caller) = &self.caller {
+            return Some(caller.name.clone());
+        }
+        let uid = self.auth.as_ref()?.uid;
+        user_registry::find_by_uid(data_store, uid).await.ok().flatten().map(|user| user.name)
+    }
+
+    // The file system a handle's export index routes the call to. The caller decides the view:
+    // a read-only user of the export gets its read-only view whatever the handle asks for, and
+    // a caller no user is registered for could not have mounted it, so gets no more. A handle of
+    // the read-only view keeps it.
+    pub async fn filesystem_for(&self, index: u32) -> Option<Arc<dyn NFSFileSystem + Send + Sync>> {
+        let export = self.exports.get(index & !READ_ONLY_VIEW)?;
+        let Some(read_only_vfs) = &export.read_only_vfs else {
+            return self.exports.filesystem(index).cloned();
+        };
+        if index & READ_ONLY_VIEW != 0 {
+            return Some(read_only_vfs.clone());
+        }
+        match self.user_name(export.vfs.data_store()).await {
+            Some(user) => Some(export.vfs_for_user(&user).clone()),
+            None => Some(read_only_vfs.clone()),
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use lru::LruCache;
+use parking_lot::Mutex;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+
+use crate::kernel::protocol::rpc::opaque_auth;
+use crate::kernel::vfs::exports::client_ip;
+#[cfg(feature = "metrics")]
+use crate::kernel::metrics::{DUPLICATE_REQUESTS_DROPPED, DUPLICATE_REQUEST_HITS};
+
+// Clients, and calls of each, kept when not configured otherwise
+pub const DEFAULT_CLIENTS: usize = 1024;
+pub const DEFAULT_CALLS_PER_CLIENT: usize = 128;
+
+// NFSv3 procedures whose second execution does not do what the first did: SETATTR, CREATE,
+// MKDIR, SYMLINK, MKNOD, REMOVE, RMDIR, RENAME and LINK
+pub fn is_non_idempotent(proc: u32) -> bool {
+    matches!(proc, 2 | 8..=15)
+}
+
+// A call as a retransmission of it would repeat it, credential and all, so another caller of
+// the client cannot be answered with the reply to someone else's call
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RequestKey {
+    client: String,
+    call: CallKey,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct CallKey {
+    xid: u32,
+    proc: u32,
+    cred_hash: u64,
+    args_hash: u64,
+}
+
+impl RequestKey {
+    pub fn new(client_addr: &str, xid: u32, proc: u32, cred: &opaque_auth, args: &[u8]) -> RequestKey {
+        let mut hasher = DefaultHasher::new();
+        (cred.flavor as u32).hash(&mut hasher);
+        cred.body.hash(&mut hasher);
+        let cred_hash = hasher.finish();
+        let mut hasher = DefaultHasher::new();
+        args.hash(&mut hasher);
+        // A client retransmits from whatever port it reconnected from, so is known by its address
+        let client = client_ip(client_addr).map(|ip| ip.to_string()).unwrap_or_else(|| client_addr.to_string());
+        RequestKey { client, call: CallKey { xid, proc, cred_hash, args_hash: hasher.finish() } }
+    }
+
+    pub fn xid(&self) -> u32 {
+        self.call.xid
+    }
+}
+
+enum Entry {
+    InProgress,
+    Done(Vec<u8>),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Lookup {
+    // Not seen before; it is now in progress
+    New,
+    // Still being executed; the retransmission is dropped and the client retries later
+    InProgress,
+    // Executed; its reply is sent again
+    Replay(Vec<u8>),
+}
+
+// Duplicate request cache: the replies of each client's latest non-idempotent calls, so a
+// client that retransmits one after a timeout is answered with what it did rather than running
+// it, and auditing it, again. Each client has its own share, so a busy client cannot push out
+// the calls of the others; clients not heard from lately are forgotten first.
+pub struct DuplicateRequestCache {
+    calls_per_client: NonZeroUsize,
+    clients: Mutex<LruCache<String, LruCache<CallKey, Entry>>>,
+}
+
+impl DuplicateRequestCache {
+    pub fn new(clients: usize, calls_per_client: usize) -> Self {
+        DuplicateRequestCache {
+            calls_per_client: NonZeroUsize::new(calls_per_client).unwrap_or(NonZeroUsize::MIN),
+            clients: Mutex::new(LruCache::new(NonZeroUsize::new(clients).unwrap_or(NonZeroUsize::MIN))),
+        }
+    }
+
+    pub fn begin(&self, key: &RequestKey) -> Lookup {
+        let mut clients = self.clients.lock();
+        let calls = clients.get_or_insert_mut(key.client.clone(), || LruCache::new(self.calls_per_client));
+        match calls.get(&key.call) {
+            Some(Entry::InProgress) => {
+                #[cfg(feature = "metrics")]
+                DUPLICATE_REQUESTS_DROPPED.inc();
+                Lookup::InProgress
+            }
+            Some(Entry::Done(reply)) => {
+                #[cfg(feature = "metrics")]
+                DUPLICATE_REQUEST_HITS.inc();
+                Lookup::Replay(reply.clone())
+            }
+            None => {
+                calls.push(key.call.clone(), Entry::InProgress);
+                Lookup::New
+            }
+        }
+    }
+
+    pub fn complete(&self, key: RequestKey, reply: Vec<u8>) {
+        let mut clients = self.clients.lock();
+        clients.get_or_insert_mut(key.client, || LruCache::new(self.calls_per_client))
+            .push(key.call, Entry::Done(reply));
+    }
+
+    // Forgets a call that failed without a reply, so a retransmission runs it again
+    pub fn abandon(&self, key: &RequestKey) {
+        if let Some(calls) = self.clients.lock().peek_mut(&key.client) {
+            calls.pop(&key.call);
+        }
+    }
+
+    // The calls kept, over all clients
+    pub fn len(&self) -> usize {
+        self.clients.lock().iter().map(|(_, calls)| calls.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::protocol::rpc::auth_flavor;
+
+    fn unix(body: &[u8]) -> opaque_auth {
+        opaque_auth { flavor: auth_flavor::AUTH_UNIX, body: body.to_vec() }
+    }
+
+    #[test]
+    fn test_retransmissions_replay_the_reply() {
+        let drc = DuplicateRequestCache::new(8, 8);
+        let create = RequestKey::new("10.0.0.1:700", 7, 8, &unix(b"alice"), b"dir handle and name");
+        assert_eq!(drc.begin(&create), Lookup::New);
+        assert_eq!(drc.begin(&create), Lookup::InProgress);
+        drc.complete(create.clone(), b"reply".to_vec());
+        assert_eq!(drc.begin(&create), Lookup::Replay(b"reply".to_vec()));
+        // from another port of the client too
+        let reconnected = RequestKey::new("10.0.0.1:701", 7, 8, &unix(b"alice"), b"dir handle and name");
+        assert_eq!(drc.begin(&reconnected), Lookup::Replay(b"reply".to_vec()));
+
+        // Another client, xid or argument list is another call
+        assert_eq!(drc.begin(&RequestKey::new("10.0.0.2:700", 7, 8, &unix(b"alice"), b"dir handle and name")), Lookup::New);
+        assert_eq!(drc.begin(&RequestKey::new("10.0.0.1:700", 8, 8, &unix(b"alice"), b"dir handle and name")), Lookup::New);
+        assert_eq!(drc.begin(&RequestKey::new("10.0.0.1:700", 7, 8, &unix(b"alice"), b"other name")), Lookup::New);
+        // and so is another caller's
+        assert_eq!(drc.begin(&RequestKey::new("10.0.0.1:700", 7, 8, &unix(b"bob"), b"dir handle and name")), Lookup::New);
+
+        let remove = RequestKey::new("10.0.0.1:700", 9, 12, &unix(b"alice"), b"name");
+        assert_eq!(drc.begin(&remove), Lookup::New);
+        drc.abandon(&remove);
+        assert_eq!(drc.begin(&remove), Lookup::New);
+    }
+
+    #[test]
+    fn test_keeps_the_latest_calls_of_each_client() {
+        let drc = DuplicateRequestCache::new(2, 2);
+        let complete = |client: &str, xid: u32| {
+            let key = RequestKey::new(client, xid, 12, &unix(b"alice"), b"name");
+            assert_eq!(drc.begin(&key), Lookup::New);
+            drc.complete(key, vec![xid as u8]);
+        };
+        for xid in 0..3 {
+            complete("10.0.0.1:700", xid);
+        }
+        complete("10.0.0.2:700", 0);
+        assert_eq!(drc.len(), 3);
+        assert_eq!(drc.begin(&RequestKey::new("10.0.0.1:700", 0, 12, &unix(b"alice"), b"name")), Lookup::New);
+        assert_eq!(drc.begin(&RequestKey::new("10.0.0.1:700", 2, 12, &unix(b"alice"), b"name")), Lookup::Replay(vec![2]));
+        assert_eq!(drc.begin(&RequestKey::new("10.0.0.2:700", 0, 12, &unix(b"alice"), b"name")), Lookup::Replay(vec![0]));
+
+        // A third client pushes out the one heard from least recently
+        complete("10.0.0.3:700", 0);
+        assert_eq!(drc.begin(&RequestKey::new("10.0.0.1:700", 2, 12, &unix(b"alice"), b"name")), Lookup::New);
+        assert!(is_non_idempotent(14) && is_non_idempotent(2) && !is_non_idempotent(7) && !is_non_idempotent(16));
+    }
+}
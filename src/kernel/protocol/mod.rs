@@ -7,3 +7,13 @@ pub mod xdr;
 pub mod context;
 
 pub mod tcp;
+
+pub mod tls;
+
+pub mod udp;
+
+pub mod drc;
+
+pub mod rpcbind;
+
+pub mod connections;
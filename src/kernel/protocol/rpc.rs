@@ -7,7 +7,15 @@ use crate::kernel::protocol::xdr::*;
 use byteorder::{ReadBytesExt, WriteBytesExt};
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::cast::FromPrimitive;
+use std::collections::{HashSet, VecDeque};
 use std::io::{Read, Write};
+use std::sync::Mutex;
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::backingstore::user_registry::UserRecord;
 // Transcribed from RFC 1057
 
 #[allow(non_camel_case_types)]
@@ -94,19 +102,29 @@ pub enum auth_flavor {
     AUTH_UNIX = 1,
     AUTH_SHORT = 2,
     AUTH_DES = 3, /* and more to be defined */
+    // RFC 9289: probes a server for RPC-over-TLS
+    AUTH_TLS = 7,
+    // graymamba's signed credentials, below; not a flavor IANA assigns
+    AUTH_SIGNED = 400_001,
 }
 XDREnumSerde!(auth_flavor);
 
+// RFC 5531: the largest body of an opaque_auth, and of the AUTH_UNIX (AUTH_SYS) fields
+pub const MAX_AUTH_BYTES: usize = 400;
+pub const MAX_MACHINE_NAME: usize = 255;
+pub const MAX_UNIX_GIDS: usize = 16;
+
 #[allow(non_camel_case_types)]
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, XdrSerialize, XdrDeserialize)]
 pub struct auth_unix {
-    stamp: u32,
-    machinename: Vec<u8>,
-    uid: u32,
-    gid: u32,
-    gids: Vec<u32>,
+    pub stamp: u32,
+    #[xdr(max = MAX_MACHINE_NAME)]
+    pub machinename: Vec<u8>,
+    pub uid: u32,
+    pub gid: u32,
+    #[xdr(max = MAX_UNIX_GIDS)]
+    pub gids: Vec<u32>,
 }
-XDRStruct!(auth_unix, stamp, machinename, uid, gid, gids);
 
 ///Provisions for authentication of caller to service and vice-versa are
 ///provided as a part of the RPC protocol.  The call message has two
@@ -127,12 +145,12 @@ XDRStruct!(auth_unix, stamp, machinename, uid, gid, gids);
 ///If authentication parameters were rejected, the reply message
 ///contains information stating why they were rejected.
 #[allow(non_camel_case_types)]
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, XdrSerialize, XdrDeserialize)]
 pub struct opaque_auth {
     pub flavor: auth_flavor,
+    #[xdr(max = MAX_AUTH_BYTES)]
     pub body: Vec<u8>,
 }
-XDRStruct!(opaque_auth, flavor, body);
 impl Default for opaque_auth {
     fn default() -> opaque_auth {
         opaque_auth {
@@ -172,7 +190,7 @@ impl Default for rpc_body {
         rpc_body::CALL(call_body::default())
     }
 }
-impl XDR for rpc_body {
+impl XdrSerialize for rpc_body {
     fn serialize<R: Write>(&self, dest: &mut R) -> std::io::Result<()> {
         match self {
             rpc_body::CALL(v) => {
@@ -186,6 +204,8 @@ impl XDR for rpc_body {
         }
         Ok(())
     }
+}
+impl XdrDeserialize for rpc_body {
     fn deserialize<R: Read>(&mut self, src: &mut R) -> std::io::Result<()> {
         let mut c: u32 = 0;
         c.deserialize(src)?;
@@ -197,6 +217,11 @@ impl XDR for rpc_body {
             let mut r = reply_body::default();
             r.deserialize(src)?;
             *self = rpc_body::REPLY(r);
+        } else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Invalid value for rpc_body",
+            ));
         }
         Ok(())
     }
@@ -278,7 +303,7 @@ impl Default for reply_body {
     }
 }
 
-impl XDR for reply_body {
+impl XdrSerialize for reply_body {
     fn serialize<R: Write>(&self, dest: &mut R) -> std::io::Result<()> {
         match self {
             reply_body::MSG_ACCEPTED(v) => {
@@ -292,6 +317,8 @@ impl XDR for reply_body {
         }
         Ok(())
     }
+}
+impl XdrDeserialize for reply_body {
     fn deserialize<R: Read>(&mut self, src: &mut R) -> std::io::Result<()> {
         let mut c: u32 = 0;
         c.deserialize(src)?;
@@ -303,6 +330,11 @@ impl XDR for reply_body {
             let mut r = rejected_reply::default();
             r.deserialize(src)?;
             *self = reply_body::MSG_DENIED(r);
+        } else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Invalid value for reply_body",
+            ));
         }
         Ok(())
     }
@@ -350,7 +382,7 @@ pub enum accept_body {
     /// procedure can't decode params
     GARBAGE_ARGS,
 }
-impl XDR for accept_body {
+impl XdrSerialize for accept_body {
     fn serialize<R: Write>(&self, dest: &mut R) -> std::io::Result<()> {
         match self {
             accept_body::SUCCESS => {
@@ -372,6 +404,8 @@ impl XDR for accept_body {
         }
         Ok(())
     }
+}
+impl XdrDeserialize for accept_body {
     fn deserialize<R: Read>(&mut self, src: &mut R) -> std::io::Result<()> {
         let mut c: u32 = 0;
         c.deserialize(src)?;
@@ -385,8 +419,13 @@ impl XDR for accept_body {
             *self = accept_body::PROG_MISMATCH(r);
         } else if c == 3 {
             *self = accept_body::PROC_UNAVAIL;
-        } else {
+        } else if c == 4 {
             *self = accept_body::GARBAGE_ARGS;
+        } else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Invalid value for accept_body",
+            ));
         }
         Ok(())
     }
@@ -415,7 +454,7 @@ impl Default for rejected_reply {
     }
 }
 
-impl XDR for rejected_reply {
+impl XdrSerialize for rejected_reply {
     fn serialize<R: Write>(&self, dest: &mut R) -> std::io::Result<()> {
         match self {
             rejected_reply::RPC_MISMATCH(v) => {
@@ -429,6 +468,8 @@ impl XDR for rejected_reply {
         }
         Ok(())
     }
+}
+impl XdrDeserialize for rejected_reply {
     fn deserialize<R: Read>(&mut self, src: &mut R) -> std::io::Result<()> {
         let mut c: u32 = 0;
         c.deserialize(src)?;
@@ -440,6 +481,11 @@ impl XDR for rejected_reply {
             let mut r = auth_stat::default();
             r.deserialize(src)?;
             *self = rejected_reply::AUTH_ERROR(r);
+        } else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Invalid value for rejected_reply",
+            ));
         }
         Ok(())
     }
@@ -478,6 +524,16 @@ pub fn prog_mismatch_reply_message(xid: u32, accepted_ver: u32) -> rpc_msg {
         body: rpc_body::REPLY(reply),
     }
 }
+pub fn prog_versions_mismatch_reply_message(xid: u32, low: u32, high: u32) -> rpc_msg {
+    let reply = reply_body::MSG_ACCEPTED(accepted_reply {
+        verf: opaque_auth::default(),
+        reply_data: accept_body::PROG_MISMATCH(mismatch_info { low, high }),
+    });
+    rpc_msg {
+        xid,
+        body: rpc_body::REPLY(reply),
+    }
+}
 pub fn garbage_args_reply_message(xid: u32) -> rpc_msg {
     let reply = reply_body::MSG_ACCEPTED(accepted_reply {
         verf: opaque_auth::default(),
@@ -510,6 +566,21 @@ pub fn make_success_reply(xid: u32) -> rpc_msg {
 
 
 
+// RFC 9289: accepts an AUTH_TLS probe; the client starts a TLS handshake once it has this reply
+pub fn starttls_reply(xid: u32) -> rpc_msg {
+    let reply = reply_body::MSG_ACCEPTED(accepted_reply {
+        verf: opaque_auth {
+            flavor: auth_flavor::AUTH_NULL,
+            body: b"STARTTLS".to_vec(),
+        },
+        reply_data: accept_body::SUCCESS,
+    });
+    rpc_msg {
+        xid,
+        body: rpc_body::REPLY(reply),
+    }
+}
+
 pub fn make_failure_reply(xid: u32) -> rpc_msg {
     let reply = reply_body::MSG_DENIED(rejected_reply::AUTH_ERROR(auth_stat::default()));
     rpc_msg {
@@ -517,3 +588,252 @@ pub fn make_failure_reply(xid: u32) -> rpc_msg {
         body: rpc_body::REPLY(reply),
     }
 }
+
+pub fn auth_error_reply_message(xid: u32, stat: auth_stat) -> rpc_msg {
+    let reply = reply_body::MSG_DENIED(rejected_reply::AUTH_ERROR(stat));
+    rpc_msg {
+        xid,
+        body: rpc_body::REPLY(reply),
+    }
+}
+
+// AUTH_SIGNED: a credential naming a registered user, with the unix ids the call is made as, a
+// timestamp and a nonce. The verifier is a signature, by the user's HMAC-SHA256 signing key or
+// Ed25519 key, over signed_payload: the call header up to and including the credential. The
+// arguments are not signed, so the calls are as open to tampering on the wire as AUTH_UNIX ones,
+// unless the connection runs over TLS; what is gained is that no one can claim to be a user
+// without their key.
+pub const SIGNED_HMAC_SHA256: u32 = 1;
+pub const SIGNED_ED25519: u32 = 2;
+
+// How far a credential's timestamp may be from the server's clock, in seconds
+pub const SIGNED_MAX_SKEW: u64 = 300;
+
+#[derive(Clone, Debug, Default, XdrSerialize, XdrDeserialize)]
+pub struct auth_signed {
+    pub user: Vec<u8>,
+    pub uid: u32,
+    pub gid: u32,
+    // as many as the AUTH_UNIX credential the call is made with can carry
+    #[xdr(max = MAX_UNIX_GIDS)]
+    pub gids: Vec<u32>,
+    pub scheme: u32,
+    // Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub nonce: Vec<u8>,
+}
+
+impl auth_signed {
+    // The ids the call is made as, as AUTH_UNIX would carry them
+    pub fn unix(&self) -> auth_unix {
+        auth_unix {
+            stamp: self.timestamp as u32,
+            machinename: Vec::new(),
+            uid: self.uid,
+            gid: self.gid,
+            gids: self.gids.clone(),
+        }
+    }
+}
+
+pub fn signed_payload(xid: u32, call: &call_body) -> Vec<u8> {
+    let mut payload = Vec::new();
+    // Writing to a Vec cannot fail
+    for word in [xid, call.rpcvers, call.prog, call.vers, call.proc] {
+        let _ = word.serialize(&mut payload);
+    }
+    let _ = call.cred.serialize(&mut payload);
+    payload
+}
+
+// A user and a nonce they have used
+type UserNonce = (Vec<u8>, Vec<u8>);
+
+// The nonces of credentials accepted within the allowed skew, so none is accepted twice
+#[derive(Default)]
+pub struct NonceCache {
+    seen: Mutex<SeenNonces>,
+}
+
+#[derive(Default)]
+struct SeenNonces {
+    nonces: HashSet<UserNonce>,
+    // When each can be forgotten, in the order they were seen, so the earliest come first
+    expiries: VecDeque<(u64, UserNonce)>,
+}
+
+impl NonceCache {
+    // False when the user has used the nonce already
+    pub fn insert(&self, user: &[u8], nonce: &[u8], now: u64) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        // A credential accepted now has a timestamp at most the skew ahead, so is outside the
+        // skew, and cannot come back, twice the skew from now
+        while seen.expiries.front().is_some_and(|(expiry, _)| *expiry < now) {
+            if let Some((_, expired)) = seen.expiries.pop_front() {
+                seen.nonces.remove(&expired);
+            }
+        }
+        let user_nonce = (user.to_vec(), nonce.to_vec());
+        if seen.nonces.contains(&user_nonce) {
+            return false;
+        }
+        seen.nonces.insert(user_nonce.clone());
+        seen.expiries.push_back((now.saturating_add(2 * SIGNED_MAX_SKEW), user_nonce));
+        true
+    }
+}
+
+// Checks a signed credential of the user it names, returning why it is refused. The nonce of a
+// retransmission, a call the duplicate request cache holds, has been seen, and is let through
+// for the cache to answer
+pub fn verify_signed(
+    user: &UserRecord,
+    cred: &auth_signed,
+    payload: &[u8],
+    signature: &[u8],
+    now: u64,
+    nonces: &NonceCache,
+    retransmission: bool,
+) -> Result<(), auth_stat> {
+    if !user.enabled {
+        return Err(auth_stat::AUTH_REJECTEDCRED);
+    }
+    // A user's calls are made as their uid, if they have one
+    if user.uid.is_some_and(|uid| uid != cred.uid) || cred.nonce.len() < 8 {
+        return Err(auth_stat::AUTH_BADCRED);
+    }
+    if now.abs_diff(cred.timestamp) > SIGNED_MAX_SKEW {
+        return Err(auth_stat::AUTH_REJECTEDVERF);
+    }
+    match cred.scheme {
+        SIGNED_HMAC_SHA256 => {
+            let key = user.hmac_key().ok_or(auth_stat::AUTH_BADCRED)?;
+            let mut mac = Hmac::<Sha256>::new_from_slice(&key).map_err(|_| auth_stat::AUTH_BADCRED)?;
+            mac.update(payload);
+            mac.verify_slice(signature).map_err(|_| auth_stat::AUTH_BADVERF)?;
+        }
+        SIGNED_ED25519 => {
+            let key = user.public_key.as_deref()
+                .and_then(|key| hex::decode(key).ok())
+                .and_then(|key| <[u8; 32]>::try_from(key).ok())
+                .and_then(|key| VerifyingKey::from_bytes(&key).ok())
+                .ok_or(auth_stat::AUTH_BADCRED)?;
+            let signature = Signature::from_slice(signature).map_err(|_| auth_stat::AUTH_BADVERF)?;
+            key.verify_strict(payload, &signature).map_err(|_| auth_stat::AUTH_BADVERF)?;
+        }
+        _ => return Err(auth_stat::AUTH_BADCRED),
+    }
+    // Only checked once the signature holds, so no one else can use up a user's nonces
+    if !nonces.insert(&cred.user, &cred.nonce, now) && !retransmission {
+        return Err(auth_stat::AUTH_REJECTEDVERF);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backingstore::user_registry::Role;
+    use crate::nfsclient::auth::{SignedCredential, SigningKey};
+    use std::io::Cursor;
+
+    // A MOUNT call signed the way nfsclient signs, read back as the server reads it
+    fn signed_call(user: &str, uid: u32, key: SigningKey) -> (u32, call_body, auth_signed) {
+        let mut call = Vec::new();
+        for word in [7u32, 0, 2, 100005, 3, 1] {
+            call.extend_from_slice(&word.to_be_bytes());
+        }
+        SignedCredential { user: user.to_string(), uid, gid: 20, key }.write_to_vec(&mut call);
+        let mut msg = rpc_msg::default();
+        msg.deserialize(&mut Cursor::new(call)).unwrap();
+        let rpc_body::CALL(body) = msg.body else { panic!("not a call") };
+        let mut cred = auth_signed::default();
+        cred.deserialize(&mut Cursor::new(&body.cred.body)).unwrap();
+        (msg.xid, body, cred)
+    }
+
+    fn verify(user: &UserRecord, (xid, call, cred): &(u32, call_body, auth_signed), nonces: &NonceCache) -> Result<(), auth_stat> {
+        verify_signed(user, cred, &signed_payload(*xid, call), &call.verf.body, cred.timestamp, nonces, false)
+    }
+
+    #[test]
+    fn test_hmac_credentials_verify_once() {
+        let mut alice = UserRecord::new("alice", Role::Standard);
+        alice.uid = Some(501);
+        let key = alice.issue_signing_key();
+        let nonces = NonceCache::default();
+
+        let call = signed_call("alice", 501, SigningKey::HmacSha256(key.clone()));
+        assert_eq!(call.2.unix().uid, 501);
+        assert!(verify(&alice, &call, &nonces).is_ok());
+        assert!(matches!(verify(&alice, &call, &nonces), Err(auth_stat::AUTH_REJECTEDVERF)));
+
+        // Another key, another uid, a tampered header or a stale timestamp are refused
+        let forged = signed_call("alice", 501, SigningKey::HmacSha256(vec![0; 32]));
+        assert!(matches!(verify(&alice, &forged, &nonces), Err(auth_stat::AUTH_BADVERF)));
+        let other_uid = signed_call("alice", 0, SigningKey::HmacSha256(key.clone()));
+        assert!(matches!(verify(&alice, &other_uid, &nonces), Err(auth_stat::AUTH_BADCRED)));
+        let (xid, mut call, cred) = signed_call("alice", 501, SigningKey::HmacSha256(key.clone()));
+        call.proc = 3;
+        assert!(matches!(verify(&alice, &(xid, call, cred), &nonces), Err(auth_stat::AUTH_BADVERF)));
+        let (xid, call, cred) = signed_call("alice", 501, SigningKey::HmacSha256(key));
+        let late = cred.timestamp + SIGNED_MAX_SKEW + 1;
+        assert!(matches!(
+            verify_signed(&alice, &cred, &signed_payload(xid, &call), &call.verf.body, late, &nonces, false),
+            Err(auth_stat::AUTH_REJECTEDVERF)
+        ));
+    }
+
+    #[test]
+    fn test_nonces_are_forgotten_once_outside_the_skew() {
+        let nonces = NonceCache::default();
+        assert!(nonces.insert(b"alice", b"nonce one", 1000));
+        assert!(nonces.insert(b"bob", b"nonce one", 1100));
+        assert!(!nonces.insert(b"alice", b"nonce one", 1100));
+        assert!(!nonces.insert(b"alice", b"nonce one", 1000 + 2 * SIGNED_MAX_SKEW));
+        // alice's has expired, while bob's, seen later, has not
+        assert!(nonces.insert(b"alice", b"nonce one", 1001 + 2 * SIGNED_MAX_SKEW));
+        assert!(!nonces.insert(b"bob", b"nonce one", 1001 + 2 * SIGNED_MAX_SKEW));
+        assert_eq!(nonces.seen.lock().unwrap().expiries.len(), 2);
+    }
+
+    #[test]
+    fn test_ed25519_credentials_verify() {
+        let key = ed25519_dalek::SigningKey::from_bytes(&[9; 32]);
+        let mut bob = UserRecord::new("bob", Role::Superuser);
+        bob.public_key = Some(hex::encode(key.verifying_key().to_bytes()));
+        let nonces = NonceCache::default();
+
+        let call = signed_call("bob", 1000, SigningKey::Ed25519(key.clone()));
+        assert!(verify(&bob, &call, &nonces).is_ok());
+        let forged = signed_call("bob", 1000, SigningKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(&[8; 32])));
+        assert!(matches!(verify(&bob, &forged, &nonces), Err(auth_stat::AUTH_BADVERF)));
+        // HMAC calls need a secret bob does not have
+        let hmac = signed_call("bob", 1000, SigningKey::HmacSha256(vec![1; 32]));
+        assert!(matches!(verify(&bob, &hmac, &nonces), Err(auth_stat::AUTH_BADCRED)));
+
+        bob.enabled = false;
+        let call = signed_call("bob", 1000, SigningKey::Ed25519(key));
+        assert!(matches!(verify(&bob, &call, &nonces), Err(auth_stat::AUTH_REJECTEDCRED)));
+    }
+
+    #[test]
+    fn test_refuses_malformed_headers() {
+        let header = |words: &[u32]| words.iter().flat_map(|word| word.to_be_bytes()).collect::<Vec<u8>>();
+        let read = |bytes: Vec<u8>| rpc_msg::default().deserialize(&mut Cursor::new(bytes));
+
+        // A message that is neither a call nor a reply, and replies of no known kind
+        assert!(read(header(&[7, 2])).is_err());
+        assert!(read(header(&[7, 1, 2])).is_err());
+        assert!(read(header(&[7, 1, 0, 0, 0, 5])).is_err());
+        assert!(read(header(&[7, 1, 1, 2])).is_err());
+
+        // A credential longer than RFC 5531 allows is refused before its body is read
+        let call = header(&[7, 0, 2, 100003, 3, 0, 1, MAX_AUTH_BYTES as u32 + 1]);
+        assert!(read(call).is_err());
+        let mut unix = auth_unix::default();
+        assert!(unix.deserialize(&mut Cursor::new(header(&[0, 0, 0, 0, MAX_UNIX_GIDS as u32 + 1]))).is_err());
+        let mut signed = auth_signed::default();
+        assert!(signed.deserialize(&mut Cursor::new(header(&[0, 0, 0, MAX_UNIX_GIDS as u32 + 1]))).is_err());
+    }
+}
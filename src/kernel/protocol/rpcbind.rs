@@ -0,0 +1,282 @@
+// The embedded portmapper's table of the RPC programs a server offers, answering portmap v2
+// GETPORT and rpcbind v3/v4 GETADDR. The server registers the programs it serves itself, with
+// the ports it actually listens on; other programs on the host may SET and UNSET their own
+// mappings from the loopback interface, as they would with rpcbind.
+use crate::kernel::api::{mount, nfs, nfs4, nlm, nsm, portmap};
+use crate::kernel::protocol::context::ListenerPorts;
+use parking_lot::RwLock;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+
+// The netids of the transports over IPv4, which portmap v2 protocols map to
+pub const NETID_TCP: &str = "tcp";
+pub const NETID_UDP: &str = "udp";
+
+// The owner rpcbind v3/v4 DUMP reports for the server's own programs
+pub const SERVER_OWNER: &str = "graymamba";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Registration {
+    pub prog: u32,
+    pub vers: u32,
+    pub netid: String,
+    // Universal address, "h1.h2.h3.h4.p1.p2" for IPv4
+    pub addr: String,
+    pub owner: String,
+    // Served by this server; never unset over RPC
+    builtin: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct RpcbindRegistry {
+    entries: RwLock<Vec<Registration>>,
+}
+
+impl RpcbindRegistry {
+    // A table of the programs a server serves on these ports: portmap v2 to v4, NFS v3 and MOUNT v3
+    pub fn for_server(ports: ListenerPorts) -> RpcbindRegistry {
+        let registry = RpcbindRegistry::default();
+        for vers in portmap::VERSION..=portmap::RPCBVERS4 {
+            registry.register(portmap::PROGRAM, vers, ports);
+        }
+        registry.register(nfs::PROGRAM, nfs::VERSION, ports);
+        // NFSv4 is only served over TCP
+        registry.register(nfs::PROGRAM, nfs4::VERSION, ListenerPorts { tcp: ports.tcp, udp: None });
+        registry.register(mount::PROGRAM, mount::VERSION, ports);
+        registry.register(nlm::PROGRAM, nlm::VERSION, ports);
+        registry.register(nsm::PROGRAM, nsm::VERSION, ports);
+        registry
+    }
+
+    // Registers a program the server serves itself, replacing any mapping of it
+    pub fn register(&self, prog: u32, vers: u32, ports: ListenerPorts) {
+        let mut entries = self.entries.write();
+        for (netid, port) in [(NETID_TCP, ports.tcp), (NETID_UDP, ports.udp)] {
+            let Some(port) = port else { continue };
+            entries.retain(|entry| !(entry.prog == prog && entry.vers == vers && entry.netid == netid));
+            entries.push(Registration {
+                prog,
+                vers,
+                netid: netid.to_string(),
+                addr: universal_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port),
+                owner: SERVER_OWNER.to_string(),
+                builtin: true,
+            });
+        }
+    }
+
+    // SET: adds a mapping of another program, unless its program, version and netid are mapped
+    pub fn set(&self, prog: u32, vers: u32, netid: &str, addr: &str, owner: &str) -> bool {
+        let mut entries = self.entries.write();
+        if netid.is_empty() || entries.iter().any(|entry| entry.prog == prog && entry.vers == vers && entry.netid == netid) {
+            return false;
+        }
+        entries.push(Registration {
+            prog,
+            vers,
+            netid: netid.to_string(),
+            addr: addr.to_string(),
+            owner: owner.to_string(),
+            builtin: false,
+        });
+        true
+    }
+
+    // UNSET: removes the mappings of a program and version set over RPC, on one netid or all
+    pub fn unset(&self, prog: u32, vers: u32, netid: Option<&str>) -> bool {
+        let mut entries = self.entries.write();
+        let before = entries.len();
+        entries.retain(|entry| {
+            entry.builtin || entry.prog != prog || entry.vers != vers || netid.is_some_and(|netid| entry.netid != netid)
+        });
+        entries.len() != before
+    }
+
+    // Stops offering one of the server's own programs, every version at every port
+    pub fn withdraw(&self, prog: u32) {
+        self.entries.write().retain(|entry| !entry.builtin || entry.prog != prog);
+    }
+
+    // The mapping of a program on a netid: of its version, or unless exact of any other
+    pub fn lookup(&self, prog: u32, vers: u32, netid: &str, exact: bool) -> Option<Registration> {
+        let entries = self.entries.read();
+        let on_netid = || entries.iter().filter(|entry| entry.prog == prog && entry.netid == netid);
+        on_netid()
+            .find(|entry| entry.vers == vers)
+            .or_else(|| if exact { None } else { on_netid().next() })
+            .cloned()
+    }
+
+    // GETPORT: the port of a program's version over a protocol, or 0
+    pub fn port(&self, prog: u32, vers: u32, prot: u32) -> u32 {
+        protocol_netid(prot)
+            .and_then(|netid| self.lookup(prog, vers, netid, false))
+            .and_then(|entry| address_port(&entry.addr))
+            .map_or(0, u32::from)
+    }
+
+    pub fn dump(&self) -> Vec<Registration> {
+        self.entries.read().clone()
+    }
+
+    // Whether the server serves a program on this port
+    pub fn serves(&self, prog: u32, port: u16) -> bool {
+        self.entries
+            .read()
+            .iter()
+            .any(|entry| entry.builtin && entry.prog == prog && address_port(&entry.addr) == Some(port))
+    }
+}
+
+pub fn protocol_netid(prot: u32) -> Option<&'static str> {
+    match prot {
+        portmap::IPPROTO_TCP => Some(NETID_TCP),
+        portmap::IPPROTO_UDP => Some(NETID_UDP),
+        _ => None,
+    }
+}
+
+pub fn netid_protocol(netid: &str) -> Option<u32> {
+    match netid {
+        NETID_TCP => Some(portmap::IPPROTO_TCP),
+        NETID_UDP => Some(portmap::IPPROTO_UDP),
+        _ => None,
+    }
+}
+
+// RFC 5665 universal address of an IP address and port
+pub fn universal_address(ip: IpAddr, port: u16) -> String {
+    format!("{}.{}.{}", ip, port >> 8, port & 0xff)
+}
+
+// The port of a universal address, and its IP address
+fn split_address(addr: &str) -> Option<(&str, u16)> {
+    let (rest, low) = addr.rsplit_once('.')?;
+    let (ip, high) = rest.rsplit_once('.')?;
+    let port = high.parse::<u8>().ok()? as u16 * 256 + low.parse::<u8>().ok()? as u16;
+    Some((ip, port))
+}
+
+pub fn address_port(addr: &str) -> Option<u16> {
+    split_address(addr).map(|(_, port)| port)
+}
+
+// A mapping's address as the client should use it: a wildcard address is replaced by the
+// server's address on the route to the client, as rpcbind merges addresses
+pub fn merge_address(addr: &str, client_addr: &str) -> String {
+    let Some((ip, port)) = split_address(addr) else { return addr.to_string() };
+    if !ip.parse::<IpAddr>().is_ok_and(|ip| ip.is_unspecified()) {
+        return addr.to_string();
+    }
+    match client_addr.parse::<SocketAddr>().ok().and_then(local_ip_towards) {
+        Some(local) => universal_address(local, port),
+        None => addr.to_string(),
+    }
+}
+
+// Connecting a UDP socket sends nothing, but picks the local address of the route
+fn local_ip_towards(client: SocketAddr) -> Option<IpAddr> {
+    let wildcard: SocketAddr = match client {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(wildcard).ok()?;
+    socket.connect(client).ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    // A client on IPv4 over an IPv6 socket is given its IPv4 address
+    Some(match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        _ => ip,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::protocol::udp::{NFSUdpListener, MAX_DATAGRAM};
+    use crate::kernel::protocol::xdr::{XdrDeserialize, XdrSerialize};
+    use crate::kernel::vfs::mock::MockNFSFileSystem;
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    // A portmap call with AUTH_NULL, and the results of its reply if it got one
+    async fn call(socket: &tokio::net::UdpSocket, vers: u32, proc: u32, args: &[u8]) -> Option<Vec<u8>> {
+        let mut message = Vec::new();
+        for word in [proc, 0, 2, portmap::PROGRAM, vers, proc, 0, 0, 0, 0] {
+            message.extend_from_slice(&word.to_be_bytes());
+        }
+        message.extend_from_slice(args);
+        socket.send(&message).await.unwrap();
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let len = tokio::time::timeout(Duration::from_millis(500), socket.recv(&mut buf)).await.ok()?.unwrap();
+        // xid, REPLY, MSG_ACCEPTED, AUTH_NULL verifier, SUCCESS
+        assert_eq!(&buf[20..24], &0u32.to_be_bytes());
+        Some(buf[24..len].to_vec())
+    }
+
+    fn xdr(value: &impl XdrSerialize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        value.serialize(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[tokio::test]
+    async fn test_answers_portmap_and_rpcbind_calls() {
+        let mut listener = NFSUdpListener::bind("127.0.0.1:0", MockNFSFileSystem::new_readwrite()).await.unwrap();
+        let port = listener.get_listen_port();
+        listener.set_rpcbind(std::sync::Arc::new(RpcbindRegistry::for_server(ListenerPorts { tcp: Some(2049), udp: Some(port) })));
+        tokio::spawn(async move { listener.handle_forever().await });
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(("127.0.0.1", port)).await.unwrap();
+
+        // GETADDR gives the server's address towards the client
+        let nfs_udp = portmap::rpcb { r_prog: nfs::PROGRAM, r_vers: 3, r_netid: b"udp".to_vec(), ..Default::default() };
+        let mut addr = Vec::new();
+        addr.deserialize(&mut Cursor::new(call(&socket, 3, 3, &xdr(&nfs_udp)).await.unwrap())).unwrap();
+        assert_eq!(String::from_utf8(addr).unwrap(), universal_address("127.0.0.1".parse().unwrap(), port));
+
+        // A program set from the loopback interface is found by GETPORT
+        let acl = portmap::mapping { prog: 100227, vers: 3, prot: portmap::IPPROTO_TCP, port: 4045 };
+        assert_eq!(call(&socket, 2, 1, &xdr(&acl)).await.unwrap(), 1u32.to_be_bytes());
+        assert_eq!(call(&socket, 2, 3, &xdr(&acl)).await.unwrap(), 4045u32.to_be_bytes());
+
+        // CALLIT runs the programs served here, and stays silent about the others
+        let null = portmap::call_args { prog: nfs::PROGRAM, vers: 3, proc: 0, args: Vec::new() };
+        let mut result = portmap::call_result::default();
+        result.deserialize(&mut Cursor::new(call(&socket, 2, 5, &xdr(&null)).await.unwrap())).unwrap();
+        assert_eq!((result.port, result.res.len()), (port as u32, 0));
+        let elsewhere = portmap::call_args { prog: 100227, ..null };
+        assert_eq!(call(&socket, 2, 5, &xdr(&elsewhere)).await, None);
+    }
+
+    #[test]
+    fn test_registrations_and_addresses() {
+        let registry = RpcbindRegistry::for_server(ListenerPorts { tcp: Some(2049), udp: Some(2050) });
+        assert_eq!(registry.port(nfs::PROGRAM, 3, portmap::IPPROTO_TCP), 2049);
+        assert_eq!(registry.port(nfs::PROGRAM, 3, portmap::IPPROTO_UDP), 2050);
+        // Another version of a program is answered with the one served, unless asked exactly
+        assert_eq!(registry.port(mount::PROGRAM, 1, portmap::IPPROTO_TCP), 2049);
+        assert!(registry.lookup(mount::PROGRAM, 1, NETID_TCP, true).is_none());
+        assert!(registry.serves(nfs::PROGRAM, 2050) && !registry.serves(nfs::PROGRAM, 111));
+
+        // Programs of others can be set and unset, the server's own neither
+        assert!(registry.set(100227, 3, NETID_UDP, "0.0.0.0.3.241", "nfs_acl"));
+        assert!(!registry.set(100227, 3, NETID_UDP, "0.0.0.0.3.242", "nfs_acl"));
+        assert_eq!(registry.port(100227, 3, portmap::IPPROTO_UDP), 1009);
+        assert!(!registry.set(nfs::PROGRAM, 3, NETID_TCP, "0.0.0.0.0.111", "other"));
+        assert!(!registry.unset(nfs::PROGRAM, 3, None));
+        assert!(registry.unset(100227, 3, None));
+        assert_eq!(registry.port(100227, 3, portmap::IPPROTO_UDP), 0);
+        assert!(!registry.serves(100227, 1009));
+
+        // The lock manager is offered unless withdrawn
+        assert!(registry.serves(nlm::PROGRAM, 2049) && registry.serves(nsm::PROGRAM, 2050));
+        registry.withdraw(nlm::PROGRAM);
+        assert_eq!(registry.port(nlm::PROGRAM, 4, portmap::IPPROTO_TCP), 0);
+
+        assert_eq!(universal_address("10.1.2.3".parse().unwrap(), 2049), "10.1.2.3.8.1");
+        assert_eq!(address_port("10.1.2.3.8.1"), Some(2049));
+        assert_eq!(address_port("10.1.2.3.8.256"), None);
+        assert_eq!(merge_address("0.0.0.0.8.1", "127.0.0.1:700"), "127.0.0.1.8.1");
+        assert_eq!(merge_address("10.1.2.3.8.1", "127.0.0.1:700"), "10.1.2.3.8.1");
+    }
+}
@@ -1,36 +1,54 @@
 use anyhow::anyhow;
 use std::io::Cursor;
+use std::future::Future;
 use std::io::{Read, Write};
+use std::pin::Pin;
 use tracing::{error, trace, warn};
 
 use crate::kernel::protocol::context::RPCContext;
+use crate::kernel::protocol::drc::{self, DuplicateRequestCache, Lookup, RequestKey};
 use crate::kernel::protocol::rpc::*;
 use crate::kernel::protocol::xdr::*;
 
 use crate::kernel::api::mount;
 use crate::kernel::api::nfs;
+use crate::kernel::api::nfs4;
+use crate::kernel::api::nlm;
+use crate::kernel::api::nsm;
 use crate::kernel::api::portmap;
 
 use crate::kernel::handlers::nfs::router::handle_nfs;
+use crate::kernel::handlers::nfs4::handle_nfs4;
+use crate::kernel::vfs::api::fh_export_index;
+use crate::kernel::vfs::exports::READ_ONLY_VIEW;
+use crate::backingstore::user_registry::{self, UserRecord};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::kernel::handlers::mount_handlers;
 
 use crate::kernel::handlers::portmap_handlers;
+use crate::kernel::handlers::nlm_handlers;
+use crate::kernel::handlers::nsm_handlers;
+use tokio::io::AsyncRead;
 use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWrite;
 use tokio::io::AsyncWriteExt;
 use tokio::io::DuplexStream;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, Semaphore};
+use std::sync::Arc;
 use tracing::debug;
 #[cfg(feature = "metrics")]
 use graymamba::kernel::metrics::*;
 // Information from RFC 5531
 // https://datatracker.ietf.org/doc/html/rfc5531
 
+// Handles one RPC message, returning whether the connection is to switch to TLS once the reply
+// is sent
 async fn handle_rpc(
     input: &mut impl Read,
     output: &mut impl Write,
     mut context: RPCContext,
-) -> Result<(), anyhow::Error> {
+) -> Result<bool, anyhow::Error> {
     debug!("Starting RPC message deserialization");
     let mut recv = rpc_msg::default();
     match recv.deserialize(input) {
@@ -42,37 +60,317 @@ async fn handle_rpc(
     }
     let xid = recv.xid;
     if let rpc_body::CALL(call) = recv.body {
-        if let auth_flavor::AUTH_UNIX = call.cred.flavor {
-            let mut auth = auth_unix::default();
-            auth.deserialize(&mut Cursor::new(&call.cred.body))?;
-            context.auth = auth;
+        let mut signed = None;
+        match call.cred.flavor {
+            auth_flavor::AUTH_UNIX => {
+                let mut auth = auth_unix::default();
+                if auth.deserialize(&mut Cursor::new(&call.cred.body)).is_err() {
+                    auth_error_reply_message(xid, auth_stat::AUTH_BADCRED).serialize(output)?;
+                    return Ok(false);
+                }
+                context.auth = Some(auth);
+            }
+            auth_flavor::AUTH_SIGNED => {
+                let mut cred = auth_signed::default();
+                if cred.deserialize(&mut Cursor::new(&call.cred.body)).is_err() {
+                    auth_error_reply_message(xid, auth_stat::AUTH_BADCRED).serialize(output)?;
+                    return Ok(false);
+                }
+                context.auth = Some(cred.unix());
+                signed = Some(cred);
+            }
+            auth_flavor::AUTH_TLS => {
+                // RFC 9289: only a NULL call may probe, once per connection of a listener with TLS
+                let offered = context.tls.as_ref().is_some_and(|tls| !tls.is_established());
+                if call.proc == 0 && call.rpcvers == 2 && offered {
+                    debug!("{:?} --> STARTTLS", xid);
+                    starttls_reply(xid).serialize(output)?;
+                    return Ok(true);
+                }
+                auth_error_reply_message(xid, auth_stat::AUTH_BADCRED).serialize(output)?;
+                return Ok(false);
+            }
+            _ => {}
         }
         if call.rpcvers != 2 {
             warn!("Invalid RPC version {} != 2", call.rpcvers);
             rpc_vers_mismatch(xid).serialize(output)?;
-            return Ok(());
+            return Ok(false);
+        }
+
+        // Every listener answers portmap, but only serves the other programs registered with its port
+        if call.prog != portmap::PROGRAM && !context.rpcbind.serves(call.prog, context.local_port) {
+            warn!("Program {} is not served on port {}", call.prog, context.local_port);
+            prog_unavail_reply_message(xid).serialize(output)?;
+            return Ok(false);
+        }
+
+        // Every NFSv3 procedure but NULL starts with a file handle, which names the export it is for.
+        // NFSv4 compounds name theirs with PUTFH, and check them as they go.
+        let mut nfs_args = Vec::new();
+        let mut export_index = None;
+        let mut requires_signed_auth = false;
+        let mut requires_tls = false;
+        let mut admits_client = true;
+        if call.prog == nfs::PROGRAM && call.vers == nfs::VERSION {
+            input.read_to_end(&mut nfs_args)?;
+            let mut fh = nfs::nfs_fh3::default();
+            if call.proc != 0 && fh.deserialize(&mut Cursor::new(&nfs_args)).is_ok() {
+                if let Some(index) = fh_export_index(&fh) {
+                    if let Some(export) = context.exports.get(index & !READ_ONLY_VIEW) {
+                        // the export's store, which callers are authenticated against
+                        context.vfs = export.vfs.clone();
+                        requires_signed_auth = export.require_signed_auth;
+                        requires_tls = export.require_tls;
+                        admits_client = export.admits_client(&context.client_addr);
+                        export_index = Some(index);
+                    }
+                }
+            }
         }
-        if call.prog == nfs::PROGRAM {
-            handle_nfs(xid, call, input, output, &context).await
+
+        // Non-idempotent NFSv3 calls go through the duplicate request cache
+        let once = context.drc.clone()
+            .filter(|_| call.prog == nfs::PROGRAM && call.vers == nfs::VERSION && drc::is_non_idempotent(call.proc))
+            .map(|cache| {
+                let key = RequestKey::new(&context.client_addr, xid, call.proc, &call.cred, &nfs_args);
+                (cache, key)
+            });
+        // A signed retransmission reuses the nonce of its call, so whether it is one is looked up
+        // before the nonce is checked, in the same step that begins the call if it is not: a call
+        // pushed out of the cache in between would otherwise run again without its nonce checked
+        let lookup = match (&signed, &once) {
+            (Some(_), Some((cache, key))) => Some(cache.begin(key)),
+            _ => None,
+        };
+
+        let authorized = 'authorize: {
+            // Signed calls are checked against the users of the store of the file system they are for
+            if let Some(cred) = &signed {
+                let retransmission = matches!(lookup, Some(Lookup::InProgress | Lookup::Replay(_)));
+                match authenticate_signed(xid, &call, cred, retransmission, &context).await {
+                    Ok(user) => context.caller = Some(user),
+                    Err(stat) => {
+                        warn!("Refused the AUTH_SIGNED call {} of {:?}: {:?}", xid, String::from_utf8_lossy(&cred.user), stat);
+                        break 'authorize Err(stat);
+                    }
+                }
+            }
+            // A verified client certificate names the caller of every call on its connection
+            if let Some(name) = context.tls.as_ref().and_then(|tls| tls.client_user()) {
+                match authenticate_certificate(name, &context).await {
+                    Ok(user) => context.caller = Some(user),
+                    Err(stat) => {
+                        warn!("Refused the call {} over the TLS connection of {:?}: {:?}", xid, name, stat);
+                        break 'authorize Err(stat);
+                    }
+                }
+            }
+            // A client outside the export's networks could not have mounted it, so cannot use its handles
+            if !admits_client {
+                warn!("Refused the call {} of {} outside the networks of its export", xid, context.client_addr);
+                break 'authorize Err(auth_stat::AUTH_TOOWEAK);
+            }
+            if (requires_tls && !context.over_tls()) || (requires_signed_auth && context.caller.is_none()) {
+                break 'authorize Err(auth_stat::AUTH_TOOWEAK);
+            }
+            Ok(())
+        };
+        if let Err(stat) = authorized {
+            // A refused call is not run, so its retransmission is looked up afresh
+            if let (Some((cache, key)), Some(Lookup::New)) = (&once, &lookup) {
+                cache.abandon(key);
+            }
+            auth_error_reply_message(xid, stat).serialize(output)?;
+            return Ok(false);
+        }
+        // The view of the export is the caller's, not the handle's: one of a read-only user's
+        // handles with READ_ONLY_VIEW cleared is still served read-only, so its changes are
+        // refused with NFS3ERR_ROFS and the rest with NFS3ERR_BADHANDLE
+        if let Some(index) = export_index {
+            if let Some(vfs) = context.filesystem_for(index).await {
+                context.vfs = vfs;
+            }
+        }
+
+        // Replies are built aside, so a call whose arguments turn out not to decode can still
+        // be answered with GARBAGE_ARGS alone
+        let mut reply = Vec::new();
+        let handled = if call.prog == nfs::PROGRAM && call.vers == nfs4::VERSION {
+            handle_nfs4(xid, call, input, &mut reply, &context).await
+        } else if call.prog == nfs::PROGRAM {
+            match once {
+                Some((cache, key)) => {
+                    let lookup = lookup.unwrap_or_else(|| cache.begin(&key));
+                    handle_nfs_once(&cache, key, lookup, call, nfs_args, &mut reply, &context).await
+                }
+                None => handle_nfs(xid, call, &mut Cursor::new(nfs_args), &mut reply, &context).await,
+            }
         } else if call.prog == portmap::PROGRAM {
-            portmap_handlers::handle_portmap(xid, call, input, output, &context)
+            portmap_handlers::handle_portmap(xid, call, input, &mut reply, &context).await
         } else if call.prog == mount::PROGRAM {
-            mount_handlers::handle_mount(xid, call, input, output, &context).await
+            mount_handlers::handle_mount(xid, call, input, &mut reply, &context).await
+        } else if call.prog == nlm::PROGRAM {
+            nlm_handlers::handle_nlm(xid, call, input, &mut reply, &context).await
+        } else if call.prog == nsm::PROGRAM {
+            nsm_handlers::handle_nsm(xid, call, input, &mut reply, &context).await
         } else {
             warn!(
                 "Unknown RPC Program number {} != {}",
                 call.prog,
                 nfs::PROGRAM
             );
-            prog_unavail_reply_message(xid).serialize(output)?;
-            Ok(())
+            prog_unavail_reply_message(xid).serialize(&mut reply).map_err(Into::into)
+        };
+        match handled {
+            Ok(()) => output.write_all(&reply)?,
+            Err(e) if is_undecodable(&e) => {
+                warn!("Could not decode the arguments of call {}: {}", xid, e);
+                garbage_args_reply_message(xid).serialize(output)?;
+            }
+            Err(e) => return Err(e),
         }
+        Ok(false)
     } else {
         error!("Unexpectedly received a Reply instead of a Call");
         Err(anyhow!("Bad RPC Call format"))
     }
 }
 
+// Whether a handler failed because its arguments were short or malformed
+fn is_undecodable(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>().is_some_and(|e| {
+        matches!(e.kind(), std::io::ErrorKind::InvalidData | std::io::ErrorKind::UnexpectedEof)
+    })
+}
+
+// Runs a non-idempotent NFS call once, answering its retransmissions with the reply it got.
+// lookup is what the cache said of the call when it was begun
+async fn handle_nfs_once(
+    cache: &DuplicateRequestCache,
+    key: RequestKey,
+    lookup: Lookup,
+    call: call_body,
+    args: Vec<u8>,
+    output: &mut impl Write,
+    context: &RPCContext,
+) -> Result<(), anyhow::Error> {
+    let xid = key.xid();
+    match lookup {
+        Lookup::Replay(reply) => {
+            debug!("{:?} --> replayed the reply to a retransmission", xid);
+            output.write_all(&reply)?;
+        }
+        Lookup::InProgress => debug!("{:?} --> dropped a retransmission of a call in progress", xid),
+        Lookup::New => {
+            let mut reply = Vec::new();
+            match handle_nfs(xid, call, &mut Cursor::new(args), &mut reply, context).await {
+                Ok(()) => {
+                    output.write_all(&reply)?;
+                    cache.complete(key, reply);
+                }
+                Err(e) => {
+                    cache.abandon(&key);
+                    return Err(e);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Runs a portmap CALLIT of a program this listener serves as a call of its own, with the
+// credential of the CALLIT if it is AUTH_UNIX, returning the procedure's results if it succeeded
+pub fn call_in_process<'a>(
+    xid: u32,
+    callit: &'a call_body,
+    args: &'a portmap::call_args,
+    context: &'a RPCContext,
+) -> Pin<Box<dyn Future<Output = Option<Vec<u8>>> + Send + 'a>> {
+    Box::pin(async move {
+        if args.prog == portmap::PROGRAM || !context.rpcbind.serves(args.prog, context.local_port) {
+            return None;
+        }
+        let cred = match callit.cred.flavor {
+            auth_flavor::AUTH_UNIX => callit.cred.clone(),
+            _ => opaque_auth::default(),
+        };
+        let call = call_body {
+            rpcvers: 2,
+            prog: args.prog,
+            vers: args.vers,
+            proc: args.proc,
+            cred,
+            verf: opaque_auth::default(),
+        };
+        let mut message = Vec::new();
+        rpc_msg { xid, body: rpc_body::CALL(call) }.serialize(&mut message).ok()?;
+        message.extend_from_slice(&args.args);
+
+        let mut reply = Vec::new();
+        handle_rpc(&mut Cursor::new(message), &mut reply, context.clone()).await.ok()?;
+        let mut reply = Cursor::new(reply);
+        let mut header = rpc_msg::default();
+        header.deserialize(&mut reply).ok()?;
+        match header.body {
+            rpc_body::REPLY(reply_body::MSG_ACCEPTED(accepted_reply { reply_data: accept_body::SUCCESS, .. })) => {
+                let start = reply.position() as usize;
+                Some(reply.into_inner().split_off(start))
+            }
+            _ => None,
+        }
+    })
+}
+
+async fn authenticate_signed(
+    xid: u32,
+    call: &call_body,
+    cred: &auth_signed,
+    retransmission: bool,
+    context: &RPCContext,
+) -> Result<UserRecord, auth_stat> {
+    if !matches!(call.verf.flavor, auth_flavor::AUTH_SIGNED) {
+        return Err(auth_stat::AUTH_BADVERF);
+    }
+    let name = std::str::from_utf8(&cred.user).map_err(|_| auth_stat::AUTH_BADCRED)?;
+    let user = match user_registry::get_user(context.vfs.data_store(), name).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Err(auth_stat::AUTH_BADCRED),
+        Err(e) => {
+            error!("Cannot look up {:?}: {}", name, e);
+            return Err(auth_stat::AUTH_REJECTEDCRED);
+        }
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+    verify_signed(&user, cred, &signed_payload(xid, call), &call.verf.body, now, &context.nonces, retransmission)?;
+    Ok(user)
+}
+
+// The user of the connection's client certificate, who must also be the signer and the uid of
+// the call's credential, if it has them
+async fn authenticate_certificate(name: &str, context: &RPCContext) -> Result<UserRecord, auth_stat> {
+    if context.caller.as_ref().is_some_and(|signer| signer.name != name) {
+        return Err(auth_stat::AUTH_BADCRED);
+    }
+    let user = match user_registry::get_user(context.vfs.data_store(), name).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Err(auth_stat::AUTH_BADCRED),
+        Err(e) => {
+            error!("Cannot look up {:?}: {}", name, e);
+            return Err(auth_stat::AUTH_REJECTEDCRED);
+        }
+    };
+    if !user.enabled {
+        return Err(auth_stat::AUTH_REJECTEDCRED);
+    }
+    if let (Some(uid), Some(auth)) = (user.uid, &context.auth) {
+        if auth.uid != uid {
+            return Err(auth_stat::AUTH_BADCRED);
+        }
+    }
+    Ok(user)
+}
+
 /// RFC 1057 Section 10
 /// When RPC messages are passed on top of a byte stream transport
 /// protocol (like TCP), it is necessary to delimit one message from
@@ -91,9 +389,10 @@ async fn handle_rpc(
 /// length in bytes of the fragment's data.  The boolean value is the
 /// highest-order bit of the header; the length is the 31 low-order bits.
 /// (Note that this record specification is NOT in XDR standard form!)
-async fn read_fragment(
-    socket: &mut DuplexStream,
+pub async fn read_fragment(
+    socket: &mut (impl AsyncRead + Unpin),
     append_to: &mut Vec<u8>,
+    max_record: usize,
 ) -> Result<bool, anyhow::Error> {
     let mut header_buf = [0_u8; 4];
     debug!("Attempting to read fragment header...");
@@ -103,28 +402,15 @@ async fn read_fragment(
     let is_last = (fragment_header & (1 << 31)) > 0;
     let length = (fragment_header & ((1 << 31) - 1)) as usize;
     debug!("Reading fragment length:{}, last:{}", length, is_last);
-    
-    let start_offset = append_to.len();
-    debug!("Current buffer size: {}, extending to: {}", start_offset, start_offset + length);
-    append_to.resize(append_to.len() + length, 0);
-    
-    // Read in chunks to handle partial reads
-    let mut bytes_read = 0;
-    while bytes_read < length {
-        let remaining = length - bytes_read;
-        debug!("Reading chunk: {} bytes read of {}, remaining: {}", bytes_read, length, remaining);
-        
-        let read_result = socket
-            .read(&mut append_to[start_offset + bytes_read..start_offset + length])
-            .await?;
-        
-        if read_result == 0 {
-            debug!("Connection closed after reading {} of {} bytes", bytes_read, length);
-            return Err(anyhow!("Connection closed before complete fragment was read"));
-        }
-        
-        bytes_read += read_result;
-        debug!("Chunk read complete: {} bytes in this chunk", read_result);
+    if append_to.len() + length > max_record {
+        return Err(anyhow!("Record longer than {} bytes", max_record));
+    }
+
+    // The buffer grows as the fragment arrives, not by the length its header claims
+    let bytes_read = socket.take(length as u64).read_to_end(append_to).await?;
+    if bytes_read < length {
+        debug!("Connection closed after reading {} of {} bytes", bytes_read, length);
+        return Err(anyhow!("Connection closed before complete fragment was read"));
     }
 
     debug!(
@@ -137,50 +423,131 @@ async fn read_fragment(
     Ok(is_last)
 }
 
-pub async fn write_fragment(
-    socket: &mut tokio::net::TcpStream,
+/// Writes buf as one record, in fragments of at most max_fragment bytes
+pub async fn write_record(
+    socket: &mut (impl AsyncWrite + Unpin),
     buf: &[u8],
+    max_fragment: usize,
 ) -> Result<(), anyhow::Error> {
-    // TODO: split into many fragments
-    assert!(buf.len() < (1 << 31));
-    // set the last flag
-    let fragment_header = buf.len() as u32 + (1 << 31);
-    let header_buf = u32::to_be_bytes(fragment_header);
-    socket.write_all(&header_buf).await?;
-    trace!("Writing fragment length:{}", buf.len());
-    socket.write_all(buf).await?;
+    let max_fragment = max_fragment.clamp(1, MAX_FRAGMENT_LENGTH);
+    let mut start = 0;
+    loop {
+        let end = buf.len().min(start + max_fragment);
+        let is_last = end == buf.len();
+        // the last flag is the high bit of the length
+        let fragment_header = (end - start) as u32 | if is_last { 1 << 31 } else { 0 };
+        socket.write_all(&fragment_header.to_be_bytes()).await?;
+        trace!("Writing fragment length:{}, last:{}", end - start, is_last);
+        socket.write_all(&buf[start..end]).await?;
+        if is_last {
+            break;
+        }
+        start = end;
+    }
+    // A TLS stream holds on to what is written until flushed
+    socket.flush().await?;
     Ok(())
 }
 
-pub type SocketMessageType = Result<Vec<u8>, anyhow::Error>;
+// A reply to send on the socket, after which the socket is upgraded to TLS if start_tls
+#[derive(Debug)]
+pub struct SocketReply {
+    pub message: Vec<u8>,
+    pub start_tls: bool,
+}
+
+pub type SocketMessageType = Result<SocketReply, anyhow::Error>;
+
+/// Handles one RPC message, whichever transport it came by. The reply is empty when there is
+/// none to send.
+pub async fn handle_message(message: Vec<u8>, context: RPCContext) -> SocketMessageType {
+    let mut write_buf: Vec<u8> = Vec::new();
+    let mut write_cursor = Cursor::new(&mut write_buf);
+    debug!("Starting RPC handler with fragment size: {}", message.len());
+    let maybe_reply = handle_rpc(&mut Cursor::new(message), &mut write_cursor, context).await;
+    match maybe_reply {
+        Err(e) => {
+            error!("RPC Error: {:?}", e);
+            Err(e)
+        }
+        Ok(start_tls) => {
+            let _ = std::io::Write::flush(&mut write_cursor);
+            debug!("RPC handler completed successfully, response size: {}", write_buf.len());
+            Ok(SocketReply { message: write_buf, start_tls })
+        }
+    }
+}
+
+/// The longest fragment record marking can describe
+pub const MAX_FRAGMENT_LENGTH: usize = (1 << 31) - 1;
+
+/// What one TCP connection may send and have buffered
+#[derive(Debug, Clone, Copy)]
+pub struct RecordLimits {
+    /// Replies longer than this are split into fragments of at most this many bytes
+    pub max_fragment: usize,
+    /// A call whose record grows past this many bytes closes the connection
+    pub max_record: usize,
+    /// Calls being handled, and replies waiting for the socket, before the connection's next
+    /// call is waited for
+    pub max_pending: usize,
+}
+
+impl Default for RecordLimits {
+    // Enough for a WRITE of the 1 MiB FSINFO offers, with room for its header
+    fn default() -> Self {
+        RecordLimits { max_fragment: 1 << 20, max_record: 4 << 20, max_pending: 16 }
+    }
+}
 
 /// The Socket Message Handler reads from a TcpStream and spawns off
 /// subtasks to handle each message. replies are queued into the
-/// reply_send_channel.
+/// reply_send_channel, which holds at most max_pending of them.
 #[derive(Debug)]
 pub struct SocketMessageHandler {
     cur_fragment: Vec<u8>,
     socket_receive_channel: DuplexStream,
-    reply_send_channel: mpsc::UnboundedSender<SocketMessageType>,
+    reply_send_channel: mpsc::Sender<SocketMessageType>,
+    in_flight: Arc<Semaphore>,
+    max_pending: usize,
+    max_record: usize,
     context: RPCContext,
 }
 
+/// The calls of one connection that are being handled
+#[derive(Debug, Clone)]
+pub struct CallsInFlight {
+    permits: Arc<Semaphore>,
+    max_pending: usize,
+}
+
+impl CallsInFlight {
+    pub fn is_empty(&self) -> bool {
+        self.permits.available_permits() == self.max_pending
+    }
+}
+
 impl SocketMessageHandler {
     /// Creates a new SocketMessageHandler with the receiver for queued message replies
     pub fn new(
         context: &RPCContext,
+        limits: RecordLimits,
     ) -> (
         Self,
         DuplexStream,
-        mpsc::UnboundedReceiver<SocketMessageType>,
+        mpsc::Receiver<SocketMessageType>,
     ) {
+        let max_pending = limits.max_pending.max(1);
         let (socksend, sockrecv) = tokio::io::duplex(256000);
-        let (msgsend, msgrecv) = mpsc::unbounded_channel();
+        let (msgsend, msgrecv) = mpsc::channel(max_pending);
         (
             Self {
                 cur_fragment: Vec::new(),
                 socket_receive_channel: sockrecv,
                 reply_send_channel: msgsend,
+                in_flight: Arc::new(Semaphore::new(max_pending)),
+                max_pending,
+                max_record: limits.max_record,
                 context: context.clone(),
             },
             socksend,
@@ -188,10 +555,15 @@ impl SocketMessageHandler {
         )
     }
 
+    /// The calls read by this handler that are still being handled
+    pub fn calls_in_flight(&self) -> CallsInFlight {
+        CallsInFlight { permits: self.in_flight.clone(), max_pending: self.max_pending }
+    }
+
     /// Reads a fragment from the socket. This should be looped.
     pub async fn read(&mut self) -> Result<(), anyhow::Error> {
         debug!("Starting to read new fragment");
-        let is_last = match read_fragment(&mut self.socket_receive_channel, &mut self.cur_fragment).await {
+        let is_last = match read_fragment(&mut self.socket_receive_channel, &mut self.cur_fragment, self.max_record).await {
             Ok(last) => {
                 debug!("Successfully read fragment, is_last: {}", last);
                 last
@@ -214,27 +586,228 @@ impl SocketMessageHandler {
             let fragment = std::mem::take(&mut self.cur_fragment);
             let context = self.context.clone();
             let send = self.reply_send_channel.clone();
-            
+
+            // Nothing more is read until a call in flight has its reply queued, so the calls of
+            // a client that does not take its replies back up in its socket rather than here
+            let permit = self.in_flight.clone().acquire_owned().await?;
             tokio::spawn(async move {
-                let mut write_buf: Vec<u8> = Vec::new();
-                let mut write_cursor = Cursor::new(&mut write_buf);
-                debug!("Starting RPC handler with fragment size: {}", fragment.len());
-                let maybe_reply =
-                    handle_rpc(&mut Cursor::new(fragment), &mut write_cursor, context).await;
-                match maybe_reply {
-                    Err(e) => {
-                        error!("RPC Error: {:?}", e);
-                        let _ = send.send(Err(e));
-                    }
-                    Ok(_) => {
-                        let _ = std::io::Write::flush(&mut write_cursor);
-                        drop(write_cursor);
-                        debug!("RPC handler completed successfully, response size: {}", write_buf.len());
-                        let _ = send.send(Ok(write_buf));
-                    }
-                }
+                let _ = send.send(handle_message(fragment, context).await).await;
+                drop(permit);
             });
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backingstore::user_registry::Role;
+    use crate::kernel::handlers::nfs::basic_ops::SETATTR3args;
+    use crate::kernel::handlers::nfs::file_ops::WRITE3args;
+    use crate::kernel::handlers::nfs4::Nfs4State;
+    use crate::kernel::protocol::context::ListenerPorts;
+    use crate::kernel::protocol::rpcbind::RpcbindRegistry;
+    use crate::kernel::vfs::api::NFSFileSystem;
+    use crate::kernel::vfs::exports::{ClientNetwork, Export, ExportTable};
+    use crate::kernel::vfs::locks::LockManager;
+    use crate::kernel::vfs::mock::MockNFSFileSystem;
+    use crate::nfsclient::auth::{SignedCredential, SigningKey};
+    use std::collections::HashSet;
+
+    fn export(vfs: Arc<dyn NFSFileSystem + Send + Sync>) -> Export {
+        Export {
+            path: "/".to_string(),
+            clients: Vec::new(),
+            read_only: false,
+            read_only_users: HashSet::new(),
+            root_squash: false,
+            require_signed_auth: false,
+            require_tls: false,
+            vfs,
+            read_only_vfs: None,
+        }
+    }
+
+    fn context(export: Export) -> RPCContext {
+        RPCContext {
+            local_port: 2049,
+            client_addr: "127.0.0.1:700".to_string(),
+            auth: None,
+            caller: None,
+            nonces: Arc::new(NonceCache::default()),
+            tls: None,
+            rpcbind: Arc::new(RpcbindRegistry::for_server(ListenerPorts { tcp: Some(2049), udp: None })),
+            drc: None,
+            max_reply: None,
+            locks: Arc::new(LockManager::default()),
+            nfs4: Some(Arc::new(Nfs4State::default())),
+            vfs: export.vfs.clone(),
+            exports: Arc::new(ExportTable::new(vec![export])),
+            mount_signal: None,
+        }
+    }
+
+    // Makes an NFSv3 call as uid, returning its reply
+    async fn nfs_call(context: &RPCContext, proc: u32, uid: u32, args: &impl XdrSerialize) -> Vec<u8> {
+        let mut cred = Vec::new();
+        auth_unix { uid, gid: uid, ..auth_unix::default() }.serialize(&mut cred).unwrap();
+        let call = call_body {
+            rpcvers: 2,
+            prog: nfs::PROGRAM,
+            vers: nfs::VERSION,
+            proc,
+            cred: opaque_auth { flavor: auth_flavor::AUTH_UNIX, body: cred },
+            verf: opaque_auth::default(),
+        };
+        let mut message = Vec::new();
+        rpc_msg { xid: 1, body: rpc_body::CALL(call) }.serialize(&mut message).unwrap();
+        args.serialize(&mut message).unwrap();
+        let mut reply = Vec::new();
+        handle_rpc(&mut Cursor::new(message), &mut reply, context.clone()).await.unwrap();
+        reply
+    }
+
+    // Makes an NFSv3 call as uid, returning the status it is answered with
+    async fn nfs_status(context: &RPCContext, proc: u32, uid: u32, args: &impl XdrSerialize) -> u32 {
+        let reply = nfs_call(context, proc, uid, args).await;
+        // after the xid, REPLY, MSG_ACCEPTED, an empty verifier and SUCCESS
+        u32::from_be_bytes(reply[24..28].try_into().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_signed_retransmissions_are_answered_from_the_cache() {
+        let vfs: Arc<dyn NFSFileSystem + Send + Sync> = Arc::new(MockNFSFileSystem::new_readwrite());
+        let mut alice = UserRecord::new("alice", Role::Standard);
+        alice.uid = Some(1001);
+        let key = alice.issue_signing_key();
+        user_registry::put_user(vfs.data_store(), &alice).await.unwrap();
+        let mut context = context(export(vfs.clone()));
+        // which keeps one call of each client
+        context.drc = Some(Arc::new(DuplicateRequestCache::new(8, 1)));
+
+        let mut header = Vec::new();
+        for word in [9u32, 0, 2, nfs::PROGRAM, nfs::VERSION, 2] {
+            header.extend_from_slice(&word.to_be_bytes());
+        }
+        SignedCredential { user: "alice".to_string(), uid: 1001, gid: 1001, key: SigningKey::HmacSha256(key) }
+            .write_to_vec(&mut header);
+        let call = |args: SETATTR3args, context: RPCContext| {
+            let mut message = header.clone();
+            args.serialize(&mut message).unwrap();
+            async move {
+                let mut reply = Vec::new();
+                handle_rpc(&mut Cursor::new(message), &mut reply, context).await.unwrap();
+                reply
+            }
+        };
+        let setattr = SETATTR3args { object: vfs.id_to_fh(1), ..SETATTR3args::default() };
+
+        let reply = call(setattr.clone(), context.clone()).await;
+        assert_eq!(reply[8..12], [0, 0, 0, 0]);
+        // Retransmitted with its nonce, from the port the client reconnected from
+        context.client_addr = "127.0.0.1:701".to_string();
+        assert_eq!(call(setattr.clone(), context.clone()).await, reply);
+
+        // The nonce of another call is still refused
+        let other = SETATTR3args { object: vfs.id_to_fh(2), ..SETATTR3args::default() };
+        let refused = |reply: Vec<u8>| reply.chunks(4).map(|word| u32::from_be_bytes(word.try_into().unwrap())).collect::<Vec<u32>>()[1..]
+            == [1, 1, 1, auth_stat::AUTH_REJECTEDVERF as u32];
+        assert!(refused(call(other.clone(), context.clone()).await));
+        // and once another call of the client has pushed the call out of the cache, so is its own
+        assert_eq!(nfs_status(&context, 2, 1001, &other).await, nfs::nfsstat3::NFS3ERR_NOENT as u32);
+        assert!(refused(call(setattr, context).await));
+    }
+
+    #[tokio::test]
+    async fn test_refuses_clients_outside_the_export_networks() {
+        let vfs: Arc<dyn NFSFileSystem + Send + Sync> = Arc::new(MockNFSFileSystem::new_readwrite());
+        let mut restricted = export(vfs.clone());
+        restricted.clients = vec![ClientNetwork::parse("10.1.0.0/16").unwrap()];
+        let mut context = context(restricted);
+        let getattr = vfs.id_to_fh(1);
+
+        // 127.0.0.1 is refused with MSG_DENIED, AUTH_ERROR and AUTH_TOOWEAK
+        let reply = nfs_call(&context, 1, 1001, &getattr).await;
+        let words: Vec<u32> = reply.chunks(4).map(|word| u32::from_be_bytes(word.try_into().unwrap())).collect();
+        assert_eq!(words[1..], [1, 1, 1, auth_stat::AUTH_TOOWEAK as u32]);
+
+        context.client_addr = "10.1.0.5:700".to_string();
+        assert_eq!(nfs_status(&context, 1, 1001, &getattr).await, nfs::nfsstat3::NFS3ERR_NOENT as u32);
+    }
+
+    #[tokio::test]
+    async fn test_read_only_users_cannot_widen_their_handles() {
+        let vfs: Arc<dyn NFSFileSystem + Send + Sync> = Arc::new(MockNFSFileSystem::new_readwrite());
+        for (name, uid) in [("alice", 1001), ("reviewer", 1002)] {
+            let mut user = UserRecord::new(name, Role::Standard);
+            user.uid = Some(uid);
+            user_registry::put_user(vfs.data_store(), &user).await.unwrap();
+        }
+        let mut reviewed = export(vfs.clone());
+        reviewed.read_only_users.insert("reviewer".to_string());
+        reviewed.read_only_vfs = Some(Arc::new(MockNFSFileSystem::new_readonly()));
+        let context = context(reviewed);
+
+        // A handle of the read-write view, as the reviewer's are with READ_ONLY_VIEW cleared
+        let handle = vfs.id_to_fh(1);
+        assert_eq!(fh_export_index(&handle).unwrap() & READ_ONLY_VIEW, 0);
+        let setattr = SETATTR3args { object: handle, ..SETATTR3args::default() };
+        assert_eq!(nfs_status(&context, 2, 1002, &setattr).await, nfs::nfsstat3::NFS3ERR_ROFS as u32);
+        // while a read-write user reaches the mock file system, which holds no such file
+        assert_eq!(nfs_status(&context, 2, 1001, &setattr).await, nfs::nfsstat3::NFS3ERR_NOENT as u32);
+        // Nor can a caller no user is registered for
+        assert_eq!(nfs_status(&context, 2, 4242, &setattr).await, nfs::nfsstat3::NFS3ERR_ROFS as u32);
+    }
+
+    #[tokio::test]
+    async fn test_records_are_split_into_fragments_and_reassembled() {
+        let (mut send, mut recv) = tokio::io::duplex(4096);
+        let record: Vec<u8> = (0..250u8).collect();
+        write_record(&mut send, &record, 100).await.unwrap();
+
+        let mut read = Vec::new();
+        let mut lengths = Vec::new();
+        loop {
+            let before = read.len();
+            let is_last = read_fragment(&mut recv, &mut read, 1000).await.unwrap();
+            lengths.push(read.len() - before);
+            if is_last {
+                break;
+            }
+        }
+        assert_eq!(lengths, vec![100, 100, 50]);
+        assert_eq!(read, record);
+    }
+
+    #[tokio::test]
+    async fn test_refuses_records_over_the_limit() {
+        let (mut send, mut recv) = tokio::io::duplex(4096);
+        write_record(&mut send, &[0; 300], 200).await.unwrap();
+
+        let mut read = Vec::new();
+        assert!(!read_fragment(&mut recv, &mut read, 250).await.unwrap());
+        // The second fragment takes the record past the limit before any of it is read
+        assert!(read_fragment(&mut recv, &mut read, 250).await.is_err());
+        assert_eq!(read.len(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_fragments_cut_short_are_refused() {
+        // A header claiming a megabyte, then the connection closing after three bytes
+        let mut socket = &[0x80, 0x10, 0x00, 0x00, 1, 2, 3][..];
+        let mut read = Vec::new();
+        assert!(read_fragment(&mut socket, &mut read, 4 << 20).await.is_err());
+        assert_eq!(read, [1, 2, 3]);
+        assert!(read.capacity() < 1 << 20);
+    }
+
+    #[tokio::test]
+    async fn test_writes_past_the_largest_offset_are_refused() {
+        let vfs: Arc<dyn NFSFileSystem + Send + Sync> = Arc::new(MockNFSFileSystem::new_readwrite());
+        let context = context(export(vfs.clone()));
+        // The mock file system cannot write, so this is answered before reaching it
+        let write = WRITE3args { file: vfs.id_to_fh(1), offset: u64::MAX - 2, count: 4, stable: 2, data: vec![1, 2, 3, 4] };
+        assert_eq!(nfs_status(&context, 7, 1001, &write).await, nfs::nfsstat3::NFS3ERR_FBIG as u32);
+    }
+}
@@ -1,15 +1,25 @@
-use crate::kernel::protocol::context::RPCContext;
+use crate::kernel::handlers::nfs4::Nfs4State;
+use crate::kernel::protocol::connections::{Connection, Connections};
+use crate::kernel::protocol::context::{ListenerPorts, RPCContext};
+use crate::kernel::protocol::drc::{self, DuplicateRequestCache};
 use crate::kernel::protocol::rpcwire::*;
 use crate::kernel::vfs::api::NFSFileSystem;
+use crate::kernel::protocol::rpc::NonceCache;
+use crate::kernel::protocol::rpcbind::RpcbindRegistry;
+use crate::kernel::protocol::tls::{certificate_user, TlsSession};
+use crate::kernel::vfs::exports::ExportTable;
+use crate::kernel::vfs::locks::LockManager;
 use anyhow;
 use async_trait::async_trait;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::{io, net::IpAddr};
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
 use tokio::net::TcpListener;
+use tokio_rustls::rustls::ServerConfig;
 use tokio::sync::mpsc;
-use tracing::{debug, error, info};
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn};
 #[cfg(feature = "metrics")]
 use graymamba::kernel::metrics::*;
 
@@ -18,7 +28,16 @@ pub struct NFSTcpListener<T: NFSFileSystem + Send + Sync + 'static> {
     listener: TcpListener,
     port: u16,
     arcfs: Arc<T>,
+    exports: Arc<ExportTable>,
+    nonces: Arc<NonceCache>,
+    tls: Option<Arc<ServerConfig>>,
+    rpcbind: Arc<RpcbindRegistry>,
+    locks: Arc<LockManager>,
+    nfs4: Arc<Nfs4State>,
+    drc: Option<Arc<DuplicateRequestCache>>,
     mount_signal: Option<mpsc::Sender<bool>>,
+    limits: RecordLimits,
+    connections: Arc<Connections>,
 }
 
 pub fn generate_host_ip(hostnum: u16) -> String {
@@ -31,11 +50,14 @@ pub fn generate_host_ip(hostnum: u16) -> String {
 
 /// processes an established socket
 async fn process_socket(
-    mut socket: tokio::net::TcpStream,
+    socket: tokio::net::TcpStream,
     context: RPCContext,
+    limits: RecordLimits,
+    connection: Connection,
 ) -> Result<(), anyhow::Error> {
     debug!("=== Processing socket ===");
-    let (mut message_handler, mut socksend, mut msgrecvchan) = SocketMessageHandler::new(&context);
+    let (mut message_handler, socksend, msgrecvchan) = SocketMessageHandler::new(&context, limits);
+    let calls = message_handler.calls_in_flight();
     let _ = socket.set_nodelay(true);
 
     debug!("=== Setting mount listener ===");
@@ -48,42 +70,114 @@ async fn process_socket(
             }
         }
     });
-    loop {
-        tokio::select! {
-            _ = socket.readable() => {
-                let mut buf = [0; 128000];
-
-                match socket.try_read(&mut buf) {
-                    Ok(0) => {
-                        return Ok(());
-                    }
-                    Ok(n) => {
-                        let _ = socksend.write_all(&buf[..n]).await;
-                    }
-                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                        continue;
-                    }
-                    Err(e) => {
-                        info!("Message handling closed : {:?}", e);
-                        return Err(e.into());
+    let mut stream = Stream { socksend, msgrecvchan, calls, connection, max_fragment: limits.max_fragment };
+    let Some(socket) = stream.serve(socket).await? else {
+        return Ok(());
+    };
+
+    // The client asked for TLS with an AUTH_TLS probe, which is only accepted when the listener
+    // offers it, and starts the handshake once it has the reply
+    let tls = context.tls.as_ref().ok_or_else(|| anyhow::anyhow!("STARTTLS without TLS"))?;
+    let tls_stream = tls.acceptor().accept(socket).await?;
+    let client_user = tls_stream.get_ref().1.peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(certificate_user);
+    info!("TLS established with {} for {:?}", context.client_addr, client_user);
+    tls.establish(client_user);
+    stream.serve(tls_stream).await?;
+    Ok(())
+}
+
+// The message handler's side of a connection, which outlives an upgrade to TLS
+struct Stream {
+    socksend: DuplexStream,
+    msgrecvchan: mpsc::Receiver<SocketMessageType>,
+    calls: CallsInFlight,
+    connection: Connection,
+    max_fragment: usize,
+}
+
+impl Stream {
+    /// Passes what arrives on socket to the message handler and sends its replies, until the
+    /// connection closes or, returning the socket, a reply asks for it to be upgraded to TLS.
+    /// When the server drains, calls are no longer read, and the connection closes once those
+    /// in flight are answered.
+    async fn serve<S: AsyncRead + AsyncWrite + Unpin>(&mut self, mut socket: S) -> Result<Option<S>, anyhow::Error> {
+        let mut buf = vec![0; 128000];
+        // What was read and is not yet taken by the message handler. The socket is read again once
+        // it is all taken, so while the handler is busy the client waits, but replies still go out.
+        let mut unread: Vec<u8> = Vec::new();
+        let idle_timeout = self.connection.connections().limits().idle_timeout;
+        let mut last_active = Instant::now();
+        let mut draining = self.connection.connections().is_draining();
+        loop {
+            tokio::select! {
+                read = socket.read(&mut buf), if unread.is_empty() && !draining => {
+                    match read {
+                        Ok(0) => {
+                            return Ok(None);
+                        }
+                        Ok(n) => {
+                            unread.extend_from_slice(&buf[..n]);
+                            last_active = Instant::now();
+                        }
+                        Err(e) => {
+                            info!("Message handling closed : {:?}", e);
+                            return Err(e.into());
+                        }
                     }
-                }
 
-            },
-            reply = msgrecvchan.recv() => {
-                match reply {
-                    Some(Err(e)) => {
-                        info!("Message handling closed : {:?}", e);
-                        return Err(e);
+                },
+                taken = self.socksend.write(&unread), if !unread.is_empty() && !draining => {
+                    match taken {
+                        Ok(n) => {
+                            unread.drain(..n);
+                        }
+                        Err(e) => {
+                            info!("Message handling closed : {:?}", e);
+                            return Err(e.into());
+                        }
                     }
-                    Some(Ok(msg)) => {
-                        if let Err(e) = write_fragment(&mut socket, &msg).await {
-                            error!("Write error {:?}", e);
+                },
+                reply = self.msgrecvchan.recv() => {
+                    last_active = Instant::now();
+                    match reply {
+                        Some(Err(e)) => {
+                            info!("Message handling closed : {:?}", e);
+                            return Err(e);
+                        }
+                        Some(Ok(reply)) => {
+                            if reply.message.is_empty() {
+                                continue;
+                            }
+                            if let Err(e) = write_record(&mut socket, &reply.message, self.max_fragment).await {
+                                error!("Write error {:?}", e);
+                            }
+                            if reply.start_tls {
+                                return Ok(Some(socket));
+                            }
+                        }
+                        // The handler only stops once the drain ended its input
+                        None if draining => {
+                            return Ok(None);
+                        }
+                        None => {
+                            return Err(anyhow::anyhow!("Unexpected socket context termination"));
                         }
                     }
-                    None => {
-                        return Err(anyhow::anyhow!("Unexpected socket context termination"));
+                },
+                _ = self.connection.connections().draining(), if !draining => {
+                    // The end of its input stops the message handler once it has read what it
+                    // was given; a call only partly read is dropped, for the client to send again
+                    draining = true;
+                    let _ = self.socksend.shutdown().await;
+                },
+                _ = tokio::time::sleep_until(last_active + idle_timeout.unwrap_or_default()), if idle_timeout.is_some() && !draining => {
+                    if self.calls.is_empty() && self.msgrecvchan.is_empty() && unread.is_empty() {
+                        info!("Closing idle connection");
+                        return Ok(None);
                     }
+                    last_active = Instant::now();
                 }
             }
         }
@@ -102,7 +196,7 @@ pub trait NFSTcp: Send + Sync {
     /// and a "false" will be sent on an unmount
     fn set_mount_listener(&mut self, signal: mpsc::Sender<bool>);
 
-    /// Loops forever and never returns handling all incoming connections.
+    /// Handles all incoming connections, returning once its connections drain.
     async fn handle_forever(&self) -> io::Result<()>;
 }
 
@@ -164,13 +258,69 @@ impl<T: NFSFileSystem + Send + Sync + 'static> NFSTcpListener<T> {
             SocketAddr::V4(s) => s.port(),
             SocketAddr::V6(s) => s.port(),
         };
+        let exports = Arc::new(ExportTable::single(arcfs.clone()));
         Ok(NFSTcpListener {
             listener,
             port,
             arcfs,
+            exports,
+            nonces: Arc::new(NonceCache::default()),
+            tls: None,
+            rpcbind: Arc::new(RpcbindRegistry::for_server(ListenerPorts { tcp: Some(port), udp: None })),
+            locks: Arc::new(LockManager::default()),
+            nfs4: Arc::new(Nfs4State::default()),
+            drc: Some(Arc::new(DuplicateRequestCache::new(drc::DEFAULT_CLIENTS, drc::DEFAULT_CALLS_PER_CLIENT))),
             mount_signal: None,
+            limits: RecordLimits::default(),
+            connections: Arc::new(Connections::default()),
         })
     }
+
+    /// Serves these exports instead of all of fs. Each export's file system
+    /// must report its index in the table as its export_index.
+    pub fn set_exports(&mut self, exports: impl Into<Arc<ExportTable>>) {
+        self.exports = exports.into();
+    }
+
+    /// Shares the programs registered with the server's other listeners, which portmap answers
+    /// with. The listener only serves the programs registered with its port.
+    pub fn set_rpcbind(&mut self, rpcbind: Arc<RpcbindRegistry>) {
+        self.rpcbind = rpcbind;
+    }
+
+    /// Shares the advisory locks with the server's other listeners, which must all see the
+    /// same locks of the same files
+    pub fn set_lock_manager(&mut self, locks: Arc<LockManager>) {
+        self.locks = locks;
+    }
+
+    /// Shares the nonces of AUTH_SIGNED calls already seen with the server's other listeners, so
+    /// a signed call taken from one transport cannot be replayed over another
+    pub fn set_nonces(&mut self, nonces: Arc<NonceCache>) {
+        self.nonces = nonces;
+    }
+
+    /// Answers retransmitted non-idempotent calls from this cache, which may be shared with
+    /// the server's other transports, or runs them again with None
+    pub fn set_duplicate_request_cache(&mut self, drc: Option<Arc<DuplicateRequestCache>>) {
+        self.drc = drc;
+    }
+
+    /// Offers RPC-over-TLS (RFC 9289) to clients that probe for it with AUTH_TLS
+    pub fn set_tls(&mut self, config: Arc<ServerConfig>) {
+        self.tls = Some(config);
+    }
+
+    /// Bounds the records each connection sends and receives, and the calls it has pending
+    pub fn set_record_limits(&mut self, limits: RecordLimits) {
+        self.limits = limits;
+    }
+
+    /// Admits connections within the limits of these, which may be shared with the server's
+    /// other listeners, and stops accepting when they drain
+    pub fn set_connections(&mut self, connections: Arc<Connections>) {
+        self.connections = connections;
+    }
 }
 
 #[async_trait]
@@ -193,11 +343,22 @@ impl<T: NFSFileSystem + Send + Sync + 'static> NFSTcp for NFSTcpListener<T> {
         self.mount_signal = Some(signal);
     }
 
-    /// Loops forever and never returns handling all incoming connections.
+    /// Handles all incoming connections, returning once its connections drain.
     async fn handle_forever(&self) -> io::Result<()> {
         loop {
-            let (socket, addr) = self.listener.accept().await?;
-            
+            let (socket, addr) = tokio::select! {
+                accepted = self.listener.accept() => accepted?,
+                _ = self.connections.draining() => {
+                    info!("No longer accepting connections on port {}", self.port);
+                    return Ok(());
+                }
+            };
+            // Dropping the socket closes it
+            let Some(connection) = self.connections.admit(addr.ip()) else {
+                warn!("Refused a connection from {:?}: too many connections", addr);
+                continue;
+            };
+
             #[cfg(feature = "metrics")]
             {
                 ACTIVE_CONNECTIONS.inc();
@@ -209,15 +370,25 @@ impl<T: NFSFileSystem + Send + Sync + 'static> NFSTcp for NFSTcpListener<T> {
             let context = RPCContext {
                 local_port: self.port,
                 client_addr: socket.peer_addr().unwrap().to_string(),
-                auth: crate::kernel::protocol::rpc::auth_unix::default(),
+                auth: None,
+                caller: None,
+                nonces: self.nonces.clone(),
+                tls: self.tls.clone().map(|config| Arc::new(TlsSession::new(config))),
+                rpcbind: self.rpcbind.clone(),
+                locks: self.locks.clone(),
+                nfs4: Some(self.nfs4.clone()),
+                drc: self.drc.clone(),
+                max_reply: None,
                 vfs: self.arcfs.clone(),
+                exports: self.exports.clone(),
                 mount_signal: self.mount_signal.clone(),
             };
             
             info!("Accepting socket {:?} {:?}", socket, context);
             
+            let limits = self.limits;
             tokio::spawn(async move {
-                let result = process_socket(socket, context).await;
+                let result = process_socket(socket, context, limits, connection).await;
                 #[cfg(feature = "metrics")]
                 ACTIVE_CONNECTIONS.dec();
                 result
@@ -225,3 +396,56 @@ impl<T: NFSFileSystem + Send + Sync + 'static> NFSTcp for NFSTcpListener<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::protocol::connections::ConnectionLimits;
+    use crate::kernel::vfs::mock::MockNFSFileSystem;
+    use crate::nfsclient::{null, receive_rpc_reply, send_rpc_message};
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+
+    async fn serve(limits: ConnectionLimits) -> (u16, Arc<Connections>, tokio::task::JoinHandle<io::Result<()>>) {
+        let mut listener = NFSTcpListener::bind("127.0.0.1:0", MockNFSFileSystem::new_readwrite()).await.unwrap();
+        let connections = Arc::new(Connections::new(limits));
+        listener.set_connections(connections.clone());
+        let port = listener.get_listen_port();
+        (port, connections, tokio::spawn(async move { listener.handle_forever().await }))
+    }
+
+    async fn is_closed(stream: &mut TcpStream) -> bool {
+        let mut byte = [0; 1];
+        matches!(tokio::time::timeout(Duration::from_secs(5), stream.read(&mut byte)).await, Ok(Ok(0) | Err(_)))
+    }
+
+    #[tokio::test]
+    async fn test_refuses_connections_over_the_client_limit_and_drains() {
+        let limits = ConnectionLimits { max_per_client: Some(1), ..ConnectionLimits::default() };
+        let (port, connections, listener) = serve(limits).await;
+
+        let mut first = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        send_rpc_message(&mut first, &null::build_null_call(1)).await.unwrap();
+        assert!(receive_rpc_reply(&mut first).await.is_ok());
+        let mut second = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        assert!(is_closed(&mut second).await);
+
+        // Draining stops the listener and closes the connection, which has nothing in flight
+        assert!(connections.drain(Duration::from_secs(5)).await);
+        assert!(listener.await.unwrap().is_ok());
+        assert!(is_closed(&mut first).await);
+    }
+
+    #[tokio::test]
+    async fn test_closes_idle_connections() {
+        let limits = ConnectionLimits { idle_timeout: Some(Duration::from_millis(100)), ..ConnectionLimits::default() };
+        let (port, connections, _listener) = serve(limits).await;
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        send_rpc_message(&mut stream, &null::build_null_call(1)).await.unwrap();
+        assert!(receive_rpc_reply(&mut stream).await.is_ok());
+        assert!(is_closed(&mut stream).await);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(connections.open_now(), 0);
+    }
+}
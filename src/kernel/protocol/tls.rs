@@ -0,0 +1,264 @@
+// RPC-over-TLS (RFC 9289). A client probes with a NULL call carrying an AUTH_TLS credential;
+// once the server's STARTTLS reply is on the wire, both ends run a TLS 1.3 handshake on the same
+// connection, which carries the rest of its RPC records. A client certificate, when the server
+// verifies them, names the registered user the connection's calls are made by.
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::{Arc, OnceLock};
+
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{self, RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::backingstore::user_registry;
+
+// The ALPN protocol both ends of an RPC-over-TLS connection offer
+pub const ALPN_SUNRPC: &[u8] = b"sunrpc";
+
+#[derive(Debug)]
+pub enum TlsError {
+    Io(String, std::io::Error),
+    NoCertificate(String),
+    NoKey(String),
+    Config(String),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::Io(path, e) => write!(f, "Cannot read {}: {}", path, e),
+            TlsError::NoCertificate(path) => write!(f, "No PEM certificate in {}", path),
+            TlsError::NoKey(path) => write!(f, "No PEM private key in {}", path),
+            TlsError::Config(msg) => write!(f, "Invalid TLS configuration: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+impl From<rustls::Error> for TlsError {
+    fn from(e: rustls::Error) -> Self {
+        TlsError::Config(e.to_string())
+    }
+}
+
+fn open(path: &str) -> Result<BufReader<File>, TlsError> {
+    File::open(path).map(BufReader::new).map_err(|e| TlsError::Io(path.to_string(), e))
+}
+
+// The PEM certificates of a file, leaf first
+pub fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| TlsError::Io(path.to_string(), e))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate(path.to_string()));
+    }
+    Ok(certs)
+}
+
+// The first PEM private key of a file
+pub fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, TlsError> {
+    rustls_pemfile::private_key(&mut open(path)?)
+        .map_err(|e| TlsError::Io(path.to_string(), e))?
+        .ok_or_else(|| TlsError::NoKey(path.to_string()))
+}
+
+// The PEM certificates of a file, as trust anchors
+pub fn load_roots(path: &str) -> Result<RootCertStore, TlsError> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+// The listener's side of its connections. With client_ca, client certificates issued by it are
+// verified; clients without one are served anonymously unless require_client_cert.
+pub fn server_config(
+    cert_path: &str,
+    key_path: &str,
+    client_ca_path: Option<&str>,
+    require_client_cert: bool,
+) -> Result<Arc<ServerConfig>, TlsError> {
+    let builder = ServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13]);
+    let builder = match client_ca_path {
+        Some(path) => {
+            let verifier = WebPkiClientVerifier::builder(Arc::new(load_roots(path)?));
+            let verifier = if require_client_cert { verifier } else { verifier.allow_unauthenticated() };
+            builder.with_client_cert_verifier(verifier.build().map_err(|e| TlsError::Config(e.to_string()))?)
+        }
+        None if require_client_cert => {
+            return Err(TlsError::Config("client certificates are required but no client CA is set".to_string()));
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_single_cert(load_certs(cert_path)?, load_key(key_path)?)?;
+    config.alpn_protocols = vec![ALPN_SUNRPC.to_vec()];
+    Ok(Arc::new(config))
+}
+
+// The user a client certificate is for: the common name of its subject, if that is a user name
+pub fn certificate_user(cert: &CertificateDer) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(cert.as_ref()).ok()?;
+    let name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+    user_registry::valid_name(name).then(|| name.to_string())
+}
+
+// The TLS state of one connection of a listener that offers TLS
+pub struct TlsSession {
+    acceptor: TlsAcceptor,
+    // Set once the handshake is done, to the user the client certificate is for
+    established: OnceLock<Option<String>>,
+}
+
+impl TlsSession {
+    pub fn new(config: Arc<ServerConfig>) -> TlsSession {
+        TlsSession { acceptor: TlsAcceptor::from(config), established: OnceLock::new() }
+    }
+
+    pub fn acceptor(&self) -> &TlsAcceptor {
+        &self.acceptor
+    }
+
+    pub fn is_established(&self) -> bool {
+        self.established.get().is_some()
+    }
+
+    pub fn establish(&self, client_user: Option<String>) {
+        let _ = self.established.set(client_user);
+    }
+
+    pub fn client_user(&self) -> Option<&str> {
+        self.established.get().and_then(|user| user.as_deref())
+    }
+}
+
+impl fmt::Debug for TlsSession {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsSession")
+            .field("established", &self.established.get())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backingstore::user_registry::{Role, UserRecord};
+    use crate::kernel::protocol::tcp::{NFSTcp, NFSTcpListener};
+    use crate::kernel::vfs::api::NFSFileSystem;
+    use crate::kernel::vfs::mock::MockNFSFileSystem;
+    use crate::nfsclient::auth::AuthUnix;
+    use crate::nfsclient::tls::{client_config, start_tls};
+    use crate::nfsclient::{null, receive_rpc_reply, send_rpc_message};
+    use rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use std::path::Path;
+    use tokio::net::TcpStream;
+
+    // Writes a CA, a certificate for localhost issued by it and one for the user alice
+    fn write_certificates(dir: &Path) -> String {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, "graymamba test CA");
+        let ca = params.self_signed(&ca_key).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+        for (name, common_name, purpose) in [
+            ("server", "localhost", ExtendedKeyUsagePurpose::ServerAuth),
+            ("alice", "alice", ExtendedKeyUsagePurpose::ClientAuth),
+        ] {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![common_name.to_string()]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, common_name);
+            params.extended_key_usages = vec![purpose];
+            let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+            std::fs::write(dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+            std::fs::write(dir.join(format!("{}.key", name)), key.serialize_pem()).unwrap();
+        }
+        dir.to_str().unwrap().to_string()
+    }
+
+    // A NULL call as uid
+    fn null_call(xid: u32, uid: u32) -> Vec<u8> {
+        let mut call = null::build_null_call(xid);
+        call.truncate(24);
+        AuthUnix { uid, ..AuthUnix::default() }.write_to_vec(&mut call);
+        call
+    }
+
+    async fn serve(config: Option<Arc<ServerConfig>>) -> u16 {
+        let fs = MockNFSFileSystem::new_readwrite();
+        let mut alice = UserRecord::new("alice", Role::Standard);
+        alice.uid = Some(1000);
+        user_registry::put_user(fs.data_store(), &alice).await.unwrap();
+        let mut listener = NFSTcpListener::bind("127.0.0.1:0", fs).await.unwrap();
+        if let Some(config) = config {
+            listener.set_tls(config);
+        }
+        let port = listener.get_listen_port();
+        tokio::spawn(async move { listener.handle_forever().await });
+        port
+    }
+
+    #[test]
+    fn test_certificate_user_is_the_common_name() {
+        let key = KeyPair::generate().unwrap();
+        let cert_for = |common_name: &str| {
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.distinguished_name.push(DnType::CommonName, common_name);
+            params.self_signed(&key).unwrap().der().clone()
+        };
+        assert_eq!(certificate_user(&cert_for("alice")), Some("alice".to_string()));
+        assert_eq!(certificate_user(&cert_for("alice smith")), None);
+        assert_eq!(certificate_user(&cert_for("..")), None);
+    }
+
+    #[tokio::test]
+    async fn test_starttls_makes_the_client_certificate_user_the_caller() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = write_certificates(dir.path());
+        let path = |name: &str| format!("{}/{}", dir, name);
+        let config = server_config(&path("server.pem"), &path("server.key"), Some(&path("ca.pem")), true).unwrap();
+        let port = serve(Some(config)).await;
+
+        let client = client_config(&path("ca.pem"), Some((&path("alice.pem"), &path("alice.key")))).unwrap();
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let mut stream = start_tls(stream, "localhost", client).await.unwrap();
+
+        // Accepted as alice's uid, refused as anyone else's
+        let reply_stat = |reply: &[u8]| u32::from_be_bytes(reply[8..12].try_into().unwrap());
+        send_rpc_message(&mut stream, &null_call(1, 1000)).await.unwrap();
+        assert_eq!(reply_stat(&receive_rpc_reply(&mut stream).await.unwrap()), 0);
+        send_rpc_message(&mut stream, &null_call(2, 0)).await.unwrap();
+        assert_eq!(reply_stat(&receive_rpc_reply(&mut stream).await.unwrap()), 1);
+
+        // Without a client certificate the handshake fails
+        let anonymous = client_config(&path("ca.pem"), None).unwrap();
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let failed = match start_tls(stream, "localhost", anonymous).await {
+            Err(_) => true,
+            // TLS 1.3 clients learn of a refused certificate on their first read
+            Ok(mut stream) => {
+                send_rpc_message(&mut stream, &null_call(1, 1000)).await.is_err()
+                    || receive_rpc_reply(&mut stream).await.is_err()
+            }
+        };
+        assert!(failed);
+    }
+
+    #[tokio::test]
+    async fn test_probe_is_refused_without_tls() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = write_certificates(dir.path());
+        let client = client_config(&format!("{}/ca.pem", dir), None).unwrap();
+        let port = serve(None).await;
+
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        assert!(start_tls(stream, "localhost", client).await.is_err());
+    }
+}
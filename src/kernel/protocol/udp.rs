@@ -0,0 +1,260 @@
+use crate::kernel::protocol::connections::Connections;
+use crate::kernel::protocol::context::{ListenerPorts, RPCContext};
+use crate::kernel::protocol::drc::{self, DuplicateRequestCache};
+use crate::kernel::protocol::rpc::NonceCache;
+use crate::kernel::protocol::rpcbind::RpcbindRegistry;
+use crate::kernel::protocol::rpcwire::handle_message;
+use crate::kernel::vfs::api::NFSFileSystem;
+use crate::kernel::vfs::exports::ExportTable;
+use crate::kernel::vfs::locks::LockManager;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
+
+/// The largest UDP payload over IPv4
+pub const MAX_DATAGRAM: usize = 65507;
+
+/// A NFS Udp Handler. Each datagram carries one RPC message, without the record marking of
+/// TCP, and is handled by the same dispatch as the TCP listener's. Non-idempotent calls go
+/// through a duplicate request cache, since UDP clients retransmit whatever is not answered
+/// in time.
+pub struct NFSUdpListener<T: NFSFileSystem + Send + Sync + 'static> {
+    socket: Arc<UdpSocket>,
+    port: u16,
+    arcfs: Arc<T>,
+    exports: Arc<ExportTable>,
+    nonces: Arc<NonceCache>,
+    drc: Arc<DuplicateRequestCache>,
+    rpcbind: Arc<RpcbindRegistry>,
+    locks: Arc<LockManager>,
+    max_datagram: usize,
+    mount_signal: Option<mpsc::Sender<bool>>,
+    connections: Arc<Connections>,
+}
+
+impl<T: NFSFileSystem + Send + Sync + 'static> NFSUdpListener<T> {
+    /// Binds to a ipstr of the form [ip address]:port. For instance
+    /// "127.0.0.1:12000". fs is an instance of an implementation
+    /// of NFSFileSystem.
+    pub async fn bind(ipstr: &str, fs: T) -> io::Result<NFSUdpListener<T>> {
+        let socket = UdpSocket::bind(ipstr).await?;
+        info!("Listening for datagrams on {:?}", ipstr);
+        let port = socket.local_addr()?.port();
+        let arcfs = Arc::new(fs);
+        Ok(NFSUdpListener {
+            socket: Arc::new(socket),
+            port,
+            exports: Arc::new(ExportTable::single(arcfs.clone())),
+            arcfs,
+            nonces: Arc::new(NonceCache::default()),
+            drc: Arc::new(DuplicateRequestCache::new(drc::DEFAULT_CLIENTS, drc::DEFAULT_CALLS_PER_CLIENT)),
+            rpcbind: Arc::new(RpcbindRegistry::for_server(ListenerPorts { tcp: None, udp: Some(port) })),
+            locks: Arc::new(LockManager::default()),
+            max_datagram: MAX_DATAGRAM,
+            mount_signal: None,
+            connections: Arc::new(Connections::default()),
+        })
+    }
+
+    /// Serves these exports instead of all of fs. Each export's file system
+    /// must report its index in the table as its export_index.
+    pub fn set_exports(&mut self, exports: impl Into<Arc<ExportTable>>) {
+        self.exports = exports.into();
+    }
+
+    /// Shares the programs registered with the server's other listeners, which portmap answers
+    /// with. The listener only serves the programs registered with its port.
+    pub fn set_rpcbind(&mut self, rpcbind: Arc<RpcbindRegistry>) {
+        self.rpcbind = rpcbind;
+    }
+
+    /// Shares the advisory locks with the server's other listeners, which must all see the
+    /// same locks of the same files
+    pub fn set_lock_manager(&mut self, locks: Arc<LockManager>) {
+        self.locks = locks;
+    }
+
+    /// Shares the nonces of AUTH_SIGNED calls already seen with the server's other listeners, so
+    /// a signed call taken from one transport cannot be replayed over another
+    pub fn set_nonces(&mut self, nonces: Arc<NonceCache>) {
+        self.nonces = nonces;
+    }
+
+    /// Drops calls, and replies, larger than this many bytes
+    pub fn set_max_datagram(&mut self, max_datagram: usize) {
+        self.max_datagram = max_datagram.min(MAX_DATAGRAM);
+    }
+
+    /// Answers retransmitted non-idempotent calls from this cache, which may be shared with
+    /// the server's other transports
+    pub fn set_duplicate_request_cache(&mut self, drc: Arc<DuplicateRequestCache>) {
+        self.drc = drc;
+    }
+
+    /// Stops receiving when these connections, which may be shared with the server's other
+    /// listeners, drain; a drain waits for the calls being handled
+    pub fn set_connections(&mut self, connections: Arc<Connections>) {
+        self.connections = connections;
+    }
+
+    /// Gets the true listening port. Useful if the bound port number is 0
+    pub fn get_listen_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Sets a mount listener. A "true" signal will be sent on a mount
+    /// and a "false" will be sent on an unmount
+    pub fn set_mount_listener(&mut self, signal: mpsc::Sender<bool>) {
+        self.mount_signal = Some(signal);
+    }
+
+    /// Handles all incoming datagrams, returning once its connections drain.
+    pub async fn handle_forever(&self) -> io::Result<()> {
+        loop {
+            // One byte more than the limit tells a datagram over it from one at it
+            let mut buf = vec![0; self.max_datagram + 1];
+            let received = tokio::select! {
+                received = self.socket.recv_from(&mut buf) => received,
+                _ = self.connections.draining() => {
+                    info!("No longer receiving datagrams on port {}", self.port);
+                    return Ok(());
+                }
+            };
+            let (len, addr) = match received {
+                Ok(received) => received,
+                Err(e) => {
+                    // An ICMP error of an earlier reply can surface here; the socket still works
+                    debug!("Datagram receive error {:?}", e);
+                    continue;
+                }
+            };
+            if len > self.max_datagram {
+                warn!("Dropped a datagram of more than {} bytes from {}", self.max_datagram, addr);
+                continue;
+            }
+            buf.truncate(len);
+
+            let context = RPCContext {
+                local_port: self.port,
+                client_addr: addr.to_string(),
+                auth: None,
+                caller: None,
+                nonces: self.nonces.clone(),
+                tls: None,
+                rpcbind: self.rpcbind.clone(),
+                locks: self.locks.clone(),
+                // NFSv4 is only served over TCP
+                nfs4: None,
+                drc: Some(self.drc.clone()),
+                max_reply: Some(self.max_datagram),
+                vfs: self.arcfs.clone(),
+                exports: self.exports.clone(),
+                mount_signal: self.mount_signal.clone(),
+            };
+            let socket = self.socket.clone();
+            let max_datagram = self.max_datagram;
+            let call = self.connections.call();
+            tokio::spawn(async move {
+                reply(&socket, addr, handle_message(buf, context).await, max_datagram).await;
+                drop(call);
+            });
+        }
+    }
+}
+
+async fn reply(
+    socket: &UdpSocket,
+    addr: SocketAddr,
+    reply: crate::kernel::protocol::rpcwire::SocketMessageType,
+    max_datagram: usize,
+) {
+    match reply {
+        Ok(reply) if reply.message.is_empty() => {}
+        Ok(reply) if reply.message.len() > max_datagram => {
+            warn!("Dropped a reply of {} bytes to {}, over the datagram limit", reply.message.len(), addr);
+        }
+        Ok(reply) => {
+            if let Err(e) = socket.send_to(&reply.message, addr).await {
+                error!("Datagram send error to {}: {:?}", addr, e);
+            }
+        }
+        // Nothing can be sent for a message that is not a call
+        Err(e) => debug!("Dropped a datagram from {}: {:?}", addr, e),
+    }
+}
+
+// The largest READ and WRITE a client over UDP may make, leaving room in the datagram for
+// the RPC and NFS headers around the data
+pub fn transfer_size(max_datagram: usize) -> u32 {
+    let room = max_datagram.saturating_sub(1024).max(512);
+    1 << (usize::BITS - 1 - room.leading_zeros())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::vfs::mock::MockNFSFileSystem;
+    use crate::nfsclient::null;
+    use std::time::Duration;
+
+    async fn call(socket: &UdpSocket, message: &[u8]) -> Option<Vec<u8>> {
+        socket.send(message).await.unwrap();
+        let mut buf = vec![0; MAX_DATAGRAM];
+        match tokio::time::timeout(Duration::from_millis(500), socket.recv(&mut buf)).await {
+            Ok(len) => Some(buf[..len.unwrap()].to_vec()),
+            Err(_) => None,
+        }
+    }
+
+    // GETPORT of the NFS program over a transport
+    fn getport_call(xid: u32, protocol: u32) -> Vec<u8> {
+        let mut message = Vec::new();
+        for word in [xid, 0, 2, 100000, 2, 3, 0, 0, 0, 0, 100003, 3, protocol, 0] {
+            message.extend_from_slice(&u32::to_be_bytes(word));
+        }
+        message
+    }
+
+    #[tokio::test]
+    async fn test_serves_calls_in_datagrams() {
+        let mut listener = NFSUdpListener::bind("127.0.0.1:0", MockNFSFileSystem::new_readwrite()).await.unwrap();
+        let port = listener.get_listen_port();
+        listener.set_rpcbind(Arc::new(RpcbindRegistry::for_server(ListenerPorts { tcp: Some(2049), udp: Some(port) })));
+        listener.set_max_datagram(1024);
+        tokio::spawn(async move { listener.handle_forever().await });
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(("127.0.0.1", port)).await.unwrap();
+        let reply = call(&socket, &null::build_null_call(7)).await.unwrap();
+        assert_eq!(&reply[..4], &7u32.to_be_bytes());
+        assert_eq!(&reply[8..12], &0u32.to_be_bytes());
+
+        // Portmap answers with the port of each transport
+        let port_of = |reply: Vec<u8>| u32::from_be_bytes(reply[24..28].try_into().unwrap());
+        assert_eq!(port_of(call(&socket, &getport_call(8, 6)).await.unwrap()), 2049);
+        assert_eq!(port_of(call(&socket, &getport_call(9, 17)).await.unwrap()), port as u32);
+
+        // Arguments that do not decode, here a handle cut short, get GARBAGE_ARGS
+        let mut getattr = Vec::new();
+        for word in [11, 0, 2, 100003, 3, 1, 0, 0, 0, 0, 20, 1] {
+            getattr.extend_from_slice(&u32::to_be_bytes(word));
+        }
+        let reply = call(&socket, &getattr).await.unwrap();
+        assert_eq!(&reply[20..24], &4u32.to_be_bytes());
+
+        // Datagrams over the limit are dropped
+        let mut oversized = null::build_null_call(10);
+        oversized.resize(2048, 0);
+        assert_eq!(call(&socket, &oversized).await, None);
+    }
+
+    #[test]
+    fn test_transfer_size_fits_a_datagram() {
+        assert_eq!(transfer_size(MAX_DATAGRAM), 32768);
+        assert_eq!(transfer_size(9000), 4096);
+        assert_eq!(transfer_size(0), 512);
+    }
+}
@@ -4,23 +4,36 @@ use std::io::{Read, Write};
 pub type XDREndian = BigEndian;
 use crate::kernel::api::nfs::nfsstring;
 
+/// Derives the traits for structs, enumerations and discriminated unions; see graymamba-derive
+pub use graymamba_derive::{XdrDeserialize, XdrSerialize};
+
 /// See https://datatracker.ietf.org/doc/html/rfc1014
-#[allow(clippy::upper_case_acronyms)]
-pub trait XDR {
+pub trait XdrSerialize {
     fn serialize<R: Write>(&self, dest: &mut R) -> std::io::Result<()>;
+}
+
+pub trait XdrDeserialize {
     fn deserialize<R: Read>(&mut self, src: &mut R) -> std::io::Result<()>;
 }
 
+/// A type that is both written and read
+#[allow(clippy::upper_case_acronyms)]
+pub trait XDR: XdrSerialize + XdrDeserialize {}
+
+impl<T: XdrSerialize + XdrDeserialize> XDR for T {}
+
 /// Serializes a basic enumeration.
 /// Casts everything as u32 BigEndian
 #[allow(non_camel_case_types)]
 #[macro_export]
 macro_rules! XDREnumSerde {
     ($t:ident) => {
-        impl XDR for $t {
+        impl XdrSerialize for $t {
             fn serialize<R: Write>(&self, dest: &mut R) -> std::io::Result<()> {
                 dest.write_u32::<XDREndian>(*self as u32)
             }
+        }
+        impl XdrDeserialize for $t {
             fn deserialize<R: Read>(&mut self, src: &mut R) -> std::io::Result<()> {
                 let r: u32 = src.read_u32::<XDREndian>()?;
                 if let Some(p) = FromPrimitive::from_u32(r) {
@@ -38,11 +51,13 @@ macro_rules! XDREnumSerde {
 }
 
 /// Serializes a bool as a 4 byte big endian integer.
-impl XDR for bool {
+impl XdrSerialize for bool {
     fn serialize<R: Write>(&self, dest: &mut R) -> std::io::Result<()> {
         let val: u32 = *self as u32;
         dest.write_u32::<XDREndian>(val)
     }
+}
+impl XdrDeserialize for bool {
     fn deserialize<R: Read>(&mut self, src: &mut R) -> std::io::Result<()> {
         let val: u32 = src.read_u32::<XDREndian>()?;
         *self = val > 0;
@@ -51,10 +66,12 @@ impl XDR for bool {
 }
 
 /// Serializes a i32 as a 4 byte big endian integer.
-impl XDR for i32 {
+impl XdrSerialize for i32 {
     fn serialize<R: Write>(&self, dest: &mut R) -> std::io::Result<()> {
         dest.write_i32::<XDREndian>(*self)
     }
+}
+impl XdrDeserialize for i32 {
     fn deserialize<R: Read>(&mut self, src: &mut R) -> std::io::Result<()> {
         *self = src.read_i32::<XDREndian>()?;
         Ok(())
@@ -62,10 +79,12 @@ impl XDR for i32 {
 }
 
 /// Serializes a i64 as a 8 byte big endian integer.
-impl XDR for i64 {
+impl XdrSerialize for i64 {
     fn serialize<R: Write>(&self, dest: &mut R) -> std::io::Result<()> {
         dest.write_i64::<XDREndian>(*self)
     }
+}
+impl XdrDeserialize for i64 {
     fn deserialize<R: Read>(&mut self, src: &mut R) -> std::io::Result<()> {
         *self = src.read_i64::<XDREndian>()?;
         Ok(())
@@ -73,10 +92,12 @@ impl XDR for i64 {
 }
 
 /// Serializes a u32 as a 4 byte big endian integer.
-impl XDR for u32 {
+impl XdrSerialize for u32 {
     fn serialize<R: Write>(&self, dest: &mut R) -> std::io::Result<()> {
         dest.write_u32::<XDREndian>(*self)
     }
+}
+impl XdrDeserialize for u32 {
     fn deserialize<R: Read>(&mut self, src: &mut R) -> std::io::Result<()> {
         *self = src.read_u32::<XDREndian>()?;
         Ok(())
@@ -84,26 +105,30 @@ impl XDR for u32 {
 }
 
 /// Serializes a u64 as a 8 byte big endian integer.
-impl XDR for u64 {
+impl XdrSerialize for u64 {
     fn serialize<R: Write>(&self, dest: &mut R) -> std::io::Result<()> {
         dest.write_u64::<XDREndian>(*self)
     }
+}
+impl XdrDeserialize for u64 {
     fn deserialize<R: Read>(&mut self, src: &mut R) -> std::io::Result<()> {
         *self = src.read_u64::<XDREndian>()?;
         Ok(())
     }
 }
 
-impl<const N: usize> XDR for [u8; N] {
+impl<const N: usize> XdrSerialize for [u8; N] {
     fn serialize<R: Write>(&self, dest: &mut R) -> std::io::Result<()> {
         dest.write_all(self)
     }
+}
+impl<const N: usize> XdrDeserialize for [u8; N] {
     fn deserialize<R: Read>(&mut self, src: &mut R) -> std::io::Result<()> {
         src.read_exact(self)
     }
 }
 
-impl XDR for Vec<u8> {
+impl XdrSerialize for Vec<u8> {
     fn serialize<R: Write>(&self, dest: &mut R) -> std::io::Result<()> {
         assert!(self.len() < u32::MAX as usize);
         let length = self.len() as u32;
@@ -117,11 +142,20 @@ impl XDR for Vec<u8> {
         }
         Ok(())
     }
+}
+impl XdrDeserialize for Vec<u8> {
     fn deserialize<R: Read>(&mut self, src: &mut R) -> std::io::Result<()> {
         let mut length: u32 = 0;
         length.deserialize(src)?;
-        self.resize(length as usize, 0);
-        src.read_exact(self)?;
+        // Grown as the bytes arrive, so a length from the wire cannot reserve more than was sent
+        self.clear();
+        src.take(length as u64).read_to_end(self)?;
+        if self.len() != length as usize {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("{} bytes of opaque data where {} were promised", self.len(), length),
+            ));
+        }
         // read padding
         let pad = ((4 - length % 4) % 4) as usize;
         let mut zeros: [u8; 4] = [0, 0, 0, 0];
@@ -130,16 +164,7 @@ impl XDR for Vec<u8> {
     }
 }
 
-impl XDR for nfsstring {
-    fn serialize<R: Write>(&self, dest: &mut R) -> std::io::Result<()> {
-        self.0.serialize(dest)
-    }
-    fn deserialize<R: Read>(&mut self, src: &mut R) -> std::io::Result<()> {
-        self.0.deserialize(src)
-    }
-}
-
-impl XDR for Vec<u32> {
+impl XdrSerialize for Vec<u32> {
     fn serialize<R: Write>(&self, dest: &mut R) -> std::io::Result<()> {
         assert!(self.len() < u32::MAX as usize);
         let length = self.len() as u32;
@@ -149,17 +174,94 @@ impl XDR for Vec<u32> {
         }
         Ok(())
     }
+}
+impl XdrDeserialize for Vec<u32> {
     fn deserialize<R: Read>(&mut self, src: &mut R) -> std::io::Result<()> {
         let mut length: u32 = 0;
         length.deserialize(src)?;
-        self.resize(length as usize, 0);
-        for i in self {
+        // Grown as the elements arrive, like opaque data
+        self.clear();
+        for _ in 0..length {
+            let mut i: u32 = 0;
             i.deserialize(src)?;
+            self.push(i);
         }
         Ok(())
     }
 }
 
+/// Optional data (`*type` in RFC 4506) is a bool telling whether the value follows
+impl<T: XdrSerialize> XdrSerialize for Option<T> {
+    fn serialize<R: Write>(&self, dest: &mut R) -> std::io::Result<()> {
+        self.is_some().serialize(dest)?;
+        if let Some(value) = self {
+            value.serialize(dest)?;
+        }
+        Ok(())
+    }
+}
+impl<T: XdrDeserialize + Default> XdrDeserialize for Option<T> {
+    fn deserialize<R: Read>(&mut self, src: &mut R) -> std::io::Result<()> {
+        let mut follows = false;
+        follows.deserialize(src)?;
+        *self = None;
+        if follows {
+            let mut value = T::default();
+            value.deserialize(src)?;
+            *self = Some(value);
+        }
+        Ok(())
+    }
+}
+
+/// Variable-length data, whose length is written before it: opaque, strings and arrays
+pub trait XdrVariable: XDR {
+    fn xdr_len(&self) -> usize;
+}
+
+impl XdrVariable for Vec<u8> {
+    fn xdr_len(&self) -> usize {
+        self.len()
+    }
+}
+
+impl XdrVariable for Vec<u32> {
+    fn xdr_len(&self) -> usize {
+        self.len()
+    }
+}
+
+impl XdrVariable for nfsstring {
+    fn xdr_len(&self) -> usize {
+        self.len()
+    }
+}
+
+/// Writes value, which must have at most max elements, as `#[xdr(max = ...)]` fields are
+pub fn serialize_bounded<T: XdrVariable, R: Write>(value: &T, max: usize, dest: &mut R) -> std::io::Result<()> {
+    if value.xdr_len() > max {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} elements where at most {} are allowed", value.xdr_len(), max),
+        ));
+    }
+    value.serialize(dest)
+}
+
+/// Reads value, refusing a length over max before reading, or making room for, what follows it
+pub fn deserialize_bounded<T: XdrVariable, R: Read>(value: &mut T, max: usize, src: &mut R) -> std::io::Result<()> {
+    let mut length: u32 = 0;
+    length.deserialize(src)?;
+    if length as usize > max {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{} elements where at most {} are allowed", length, max),
+        ));
+    }
+    value.deserialize(&mut Read::chain(&length.to_be_bytes()[..], src))
+}
+
+/// Superseded by #[derive(XdrSerialize, XdrDeserialize)], which new types use
 #[allow(non_camel_case_types)]
 #[macro_export]
 macro_rules! XDRStruct {
@@ -167,11 +269,13 @@ macro_rules! XDRStruct {
         $t:ident,
         $($element:ident),*
     ) => {
-        impl XDR for $t {
+        impl XdrSerialize for $t {
             fn serialize<R: Write>(&self, dest: &mut R) -> std::io::Result<()> {
                 $(self.$element.serialize(dest)?;)*
                 Ok(())
             }
+        }
+        impl XdrDeserialize for $t {
             fn deserialize<R: Read>(&mut self, src: &mut R) -> std::io::Result<()> {
                 $(self.$element.deserialize(src)?;)*
                 Ok(())
@@ -193,14 +297,15 @@ macro_rules! XDRStruct {
 ///          attributes(wcc_attr)
 ///       }
 /// The serde methods can be generated with XDRBoolUnion(pre_op_attr, attributes, wcc_attr)
-/// The "true" type must have the Default trait
+/// The "true" type must have the Default trait. The derives do the same for an enum whose
+/// Void variant comes first.
 #[allow(non_camel_case_types)]
 #[macro_export]
 macro_rules! XDRBoolUnion {
     (
         $t:ident, $enumcase:ident, $enumtype:ty
     ) => {
-        impl XDR for $t {
+        impl XdrSerialize for $t {
             fn serialize<R: Write>(&self, dest: &mut R) -> std::io::Result<()> {
                 match self {
                     $t::Void => {
@@ -213,6 +318,8 @@ macro_rules! XDRBoolUnion {
                 }
                 Ok(())
             }
+        }
+        impl XdrDeserialize for $t {
             fn deserialize<R: Read>(&mut self, src: &mut R) -> std::io::Result<()> {
                 let mut c: bool = false;
                 c.deserialize(src)?;
@@ -232,3 +339,98 @@ macro_rules! XDRBoolUnion {
 pub(crate) use XDRBoolUnion;
 pub(crate) use XDREnumSerde;
 pub(crate) use XDRStruct;
+
+#[cfg(test)]
+#[allow(non_camel_case_types)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[derive(Debug, Default, XdrSerialize, XdrDeserialize)]
+    struct entry {
+        name: nfsstring,
+        #[xdr(max = 4)]
+        handle: Vec<u8>,
+        cookie: Option<u64>,
+        verf: [u8; 2],
+    }
+
+    #[derive(Debug, Default, PartialEq, XdrSerialize, XdrDeserialize)]
+    #[repr(u32)]
+    enum stat {
+        #[default]
+        STAT_OK = 0,
+        STAT_NOENT = 2,
+        STAT_IO,
+    }
+
+    #[derive(Debug, Default, XdrSerialize, XdrDeserialize)]
+    #[repr(u32)]
+    enum result {
+        #[default]
+        Void,
+        entry(entry),
+        error { stat: stat, detail: u32 } = 5,
+    }
+
+    fn bytes(value: &impl XdrSerialize) -> Vec<u8> {
+        let mut out = Vec::new();
+        value.serialize(&mut out).unwrap();
+        out
+    }
+
+    fn read<T: XdrDeserialize + Default>(mut input: &[u8]) -> std::io::Result<T> {
+        let mut value = T::default();
+        value.deserialize(&mut input)?;
+        Ok(value)
+    }
+
+    #[test]
+    fn test_derived_structs_and_unions_round_trip() {
+        let entry = entry { name: b"abcde"[..].into(), handle: vec![1, 2], cookie: Some(7), verf: [8, 9] };
+        let encoded = bytes(&result::entry(entry));
+        #[rustfmt::skip]
+        assert_eq!(encoded, [
+            0, 0, 0, 1,
+            0, 0, 0, 5, b'a', b'b', b'c', b'd', b'e', 0, 0, 0,
+            0, 0, 0, 2, 1, 2, 0, 0,
+            0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 7,
+            8, 9,
+        ]);
+        let decoded: result = read(&encoded).unwrap();
+        assert!(matches!(&decoded, result::entry(e) if e.name.0 == b"abcde" && e.cookie == Some(7)));
+
+        let error = result::error { stat: stat::STAT_IO, detail: 4 };
+        assert_eq!(bytes(&error), [0, 0, 0, 5, 0, 0, 0, 3, 0, 0, 0, 4]);
+        assert!(matches!(read(&bytes(&error)).unwrap(), result::error { stat: stat::STAT_IO, detail: 4 }));
+        assert!(matches!(read(&bytes(&result::Void)).unwrap(), result::Void));
+    }
+
+    #[test]
+    fn test_refuses_unknown_discriminants_and_overlong_fields() {
+        assert!(read::<stat>(&[0, 0, 0, 1]).is_err());
+        assert!(read::<result>(&[0, 0, 0, 2]).is_err());
+
+        // Five bytes of a handle of at most four, refused whether written or read
+        let overlong = entry { handle: vec![0; 5], ..entry::default() };
+        assert!(overlong.serialize(&mut Vec::new()).is_err());
+        let mut encoded = bytes(&entry::default());
+        encoded[7] = 5;
+        let mut input = Cursor::new(&encoded);
+        assert!(entry::default().deserialize(&mut input).is_err());
+        assert_eq!(input.position(), 8);
+    }
+
+    #[test]
+    fn test_lengths_from_the_wire_reserve_only_what_arrives() {
+        // The largest length there is, followed by three bytes
+        let opaque: std::io::Result<Vec<u8>> = read(&[0xff, 0xff, 0xff, 0xff, 1, 2, 3]);
+        assert_eq!(opaque.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+        let array: std::io::Result<Vec<u32>> = read(&[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 1]);
+        assert_eq!(array.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+
+        let mut value = vec![9u8; 3];
+        value.deserialize(&mut &[0, 0, 0, 2, 1, 2, 0, 0][..]).unwrap();
+        assert_eq!(value, [1, 2]);
+    }
+}
@@ -7,7 +7,7 @@ use std::time::SystemTime;
 
 use crate::backingstore::data_store::DataStore;
 
-use graymamba::sharesfs::SharesFS;
+use graymamba::sharesfs::namespace::{self, Namespace};
 
 #[derive(Default, Debug)]
 pub struct DirEntry {
@@ -21,6 +21,34 @@ pub struct ReadDirResult {
     pub end: bool,
 }
 
+// The space and file counts FSSTAT and FSINFO report: what there is in all, what is free and
+// what the caller may still use, and the largest file they may write
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FsStat {
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+    pub avail_bytes: u64,
+    pub total_files: u64,
+    pub free_files: u64,
+    pub avail_files: u64,
+    pub max_file_size: u64,
+}
+
+// What a file system that keeps no usage reports
+impl Default for FsStat {
+    fn default() -> Self {
+        FsStat {
+            total_bytes: 1024 * 1024 * 1024 * 1024,
+            free_bytes: 1024 * 1024 * 1024 * 1024,
+            avail_bytes: 1024 * 1024 * 1024 * 1024,
+            total_files: 1024 * 1024 * 1024,
+            free_files: 1024 * 1024 * 1024,
+            avail_files: 1024 * 1024 * 1024,
+            max_file_size: 128 * 1024 * 1024 * 1024,
+        }
+    }
+}
+
 static mut GENERATION_NUMBER: u64 = 0;
 static GENERATION_NUMBER_INIT: Once = Once::new();
 
@@ -36,6 +64,14 @@ fn get_generation_number() -> u64 {
     }
 }
 
+/// The export a file handle from the default id_to_fh belongs to
+pub fn fh_export_index(fh: &nfs_fh3) -> Option<u32> {
+    if fh.data.len() != 20 {
+        return None;
+    }
+    Some(u32::from_le_bytes(fh.data[16..20].try_into().unwrap()))
+}
+
 /// What capabilities are supported
 #[derive(Clone)]
 pub enum VFSCapabilities {
@@ -52,6 +88,7 @@ pub enum VFSCapabilities {
 ///  - A 64-bit generation number derived from the server startup time
 ///   (i.e. so the opaque file handle expires when the NFS server restarts)
 ///  - The 64-bit file id
+///  - The 32-bit index of the export the file system serves, so calls are routed back to it
 //
 /// readdir pagination
 /// ------------------
@@ -74,6 +111,12 @@ pub enum VFSCapabilities {
 #[async_trait]
 pub trait NFSFileSystem: Sync {
     fn data_store(&self) -> &dyn DataStore;
+    /// The tenant namespace every fileid of this file system lives in
+    fn namespace(&self) -> &Namespace;
+    /// Whether user may mount this file system. All users may unless the tenant lists them
+    fn admits_user(&self, _user: &str) -> bool {
+        true
+    }
     /// Returns the set of capabilities supported
     fn capabilities(&self) -> VFSCapabilities;
     /// Returns the ID the of the root directory "/"
@@ -189,22 +232,193 @@ pub trait NFSFileSystem: Sync {
         attr: &sattr3,
     ) -> Result<(fileid3, fattr3), nfsstat3>;
 
+    /// Makes a special file: a block or character device with its device numbers, a socket
+    /// or a named pipe. File systems that cannot hold them return Err(nfsstat3::NFS3ERR_NOTSUPP)
+    async fn mknod(
+        &self,
+        _dirid: fileid3,
+        _filename: &filename3,
+        _ftype: ftype3,
+        _attr: &sattr3,
+        _rdev: specdata3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        Err(nfsstat3::NFS3ERR_NOTSUPP)
+    }
+
+    /// Sets the attributes of an id as setattr does, with the weak cache consistency data of
+    /// the change. When guard is given and is not the ctime the id has, nothing is set and
+    /// Err(nfsstat3::NFS3ERR_NOT_SYNC) is returned.
+    ///
+    /// The _wcc methods are what NFSv3 clients are answered from: the attributes before and
+    /// after must be those of this change alone. The defaults read them around the plain
+    /// method, so another change may fall in between; file systems that can hold off other
+    /// changes while one runs should override them.
+    async fn setattr_wcc(
+        &self,
+        id: fileid3,
+        setattr: sattr3,
+        guard: Option<nfstime3>,
+    ) -> (Result<fattr3, nfsstat3>, wcc_data) {
+        let before = match self.getattr(id).await {
+            Ok(before) => before,
+            Err(stat) => return (Err(stat), wcc_data::default()),
+        };
+        if guard.is_some_and(|ctime| ctime != before.ctime) {
+            return (Err(nfsstat3::NFS3ERR_NOT_SYNC), wcc_data::new(Some(before), Some(before)));
+        }
+        let res = self.setattr(id, setattr).await;
+        let after = self.getattr(id).await.ok();
+        (res, wcc_data::new(Some(before), after))
+    }
+
+    /// Writes to a file as write does, with the weak cache consistency data of the file
+    async fn write_wcc(&self, id: fileid3, offset: u64, data: &[u8]) -> (Result<fattr3, nfsstat3>, wcc_data) {
+        let before = self.getattr(id).await.ok();
+        let res = self.write(id, offset, data).await;
+        let after = self.getattr(id).await.ok();
+        (res, wcc_data::new(before, after))
+    }
+
+    /// Creates a file as create does, with the weak cache consistency data of the directory
+    async fn create_wcc(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+        attr: sattr3,
+    ) -> (Result<(fileid3, fattr3), nfsstat3>, wcc_data) {
+        let before = self.getattr(dirid).await.ok();
+        let res = self.create(dirid, filename, attr).await;
+        let after = self.getattr(dirid).await.ok();
+        (res, wcc_data::new(before, after))
+    }
+
+    /// Creates a file as create_exclusive does, with the weak cache consistency data of the
+    /// directory
+    async fn create_exclusive_wcc(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+    ) -> (Result<fileid3, nfsstat3>, wcc_data) {
+        let before = self.getattr(dirid).await.ok();
+        let res = self.create_exclusive(dirid, filename).await;
+        let after = self.getattr(dirid).await.ok();
+        (res, wcc_data::new(before, after))
+    }
+
+    /// Makes a directory as mkdir does, with the weak cache consistency data of its parent
+    async fn mkdir_wcc(
+        &self,
+        dirid: fileid3,
+        dirname: &filename3,
+    ) -> (Result<(fileid3, fattr3), nfsstat3>, wcc_data) {
+        let before = self.getattr(dirid).await.ok();
+        let res = self.mkdir(dirid, dirname).await;
+        let after = self.getattr(dirid).await.ok();
+        (res, wcc_data::new(before, after))
+    }
+
+    /// Makes a symlink as symlink does, with the weak cache consistency data of the directory
+    async fn symlink_wcc(
+        &self,
+        dirid: fileid3,
+        linkname: &filename3,
+        symlink: &nfspath3,
+        attr: &sattr3,
+    ) -> (Result<(fileid3, fattr3), nfsstat3>, wcc_data) {
+        let before = self.getattr(dirid).await.ok();
+        let res = self.symlink(dirid, linkname, symlink, attr).await;
+        let after = self.getattr(dirid).await.ok();
+        (res, wcc_data::new(before, after))
+    }
+
+    /// Makes a special file as mknod does, with the weak cache consistency data of the directory
+    async fn mknod_wcc(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+        ftype: ftype3,
+        attr: &sattr3,
+        rdev: specdata3,
+    ) -> (Result<(fileid3, fattr3), nfsstat3>, wcc_data) {
+        let before = self.getattr(dirid).await.ok();
+        let res = self.mknod(dirid, filename, ftype, attr, rdev).await;
+        let after = self.getattr(dirid).await.ok();
+        (res, wcc_data::new(before, after))
+    }
+
+    /// Removes a file as remove does, with the weak cache consistency data of the directory
+    async fn remove_wcc(&self, dirid: fileid3, filename: &filename3) -> (Result<(), nfsstat3>, wcc_data) {
+        let before = self.getattr(dirid).await.ok();
+        let res = self.remove(dirid, filename).await;
+        let after = self.getattr(dirid).await.ok();
+        (res, wcc_data::new(before, after))
+    }
+
+    /// Renames a file as rename does, with the weak cache consistency data of the directory
+    /// it leaves and of the one it enters
+    async fn rename_wcc(
+        &self,
+        from_dirid: fileid3,
+        from_filename: &filename3,
+        to_dirid: fileid3,
+        to_filename: &filename3,
+    ) -> (Result<(), nfsstat3>, wcc_data, wcc_data) {
+        let from_before = self.getattr(from_dirid).await.ok();
+        let to_before = self.getattr(to_dirid).await.ok();
+        let res = self.rename(from_dirid, from_filename, to_dirid, to_filename).await;
+        let from_after = self.getattr(from_dirid).await.ok();
+        let to_after = self.getattr(to_dirid).await.ok();
+        (res, wcc_data::new(from_before, from_after), wcc_data::new(to_before, to_after))
+    }
+
+    /// The space and files of the file system holding id, as user sees them when the caller
+    /// is a known user
+    async fn fsstat(&self, _id: fileid3, _user: Option<&str>) -> Result<FsStat, nfsstat3> {
+        Ok(FsStat::default())
+    }
+
     /// Reads a symlink
     async fn readlink(&self, id: fileid3) -> Result<nfspath3, nfsstat3>;
 
+    /// Called when a change is refused because the file system is read-only,
+    /// with the file or directory it was aimed at when known
+    async fn write_denied(&self, _id: Option<fileid3>, _operation: &str) {}
+
+    /// Called when a client host takes or releases a byte-range lock of a file
+    async fn lock_changed(&self, _id: fileid3, _acquired: bool, _host: &str) {}
+
+    /// Makes what was written to a file so far durable, as an NFSv4 client closing or
+    /// committing it asks
+    async fn commit(&self, _id: fileid3) -> Result<(), nfsstat3> {
+        Ok(())
+    }
+
+    /// The index of the export this file system serves in its listener's export table
+    fn export_index(&self) -> u32 {
+        0
+    }
+
+    /// Whether a fileid is the export's root or lies below it, the only files a handle of the
+    /// export may name. A file system serving a tree of its own serves every fileid of it
+    async fn exports_id(&self, _id: fileid3) -> bool {
+        true
+    }
+
     /// Converts the fileid to an opaque NFS file handle. Optional.
     fn id_to_fh(&self, id: fileid3) -> nfs_fh3 {
         let gennum = get_generation_number();
         let mut ret: Vec<u8> = Vec::new();
         ret.extend_from_slice(&gennum.to_le_bytes());
         ret.extend_from_slice(&id.to_le_bytes());
+        ret.extend_from_slice(&self.export_index().to_le_bytes());
         nfs_fh3 { data: ret }
     }
 
     
     /// Converts an opaque NFS file handle to a fileid.  Optional.
-    fn fh_to_id(&self, id: &nfs_fh3) -> Result<fileid3, nfsstat3> {
-        if id.data.len() != 16 {
+    /// Handles of files outside the export are refused, whatever export index they carry
+    async fn fh_to_id(&self, id: &nfs_fh3) -> Result<fileid3, nfsstat3> {
+        if fh_export_index(id) != Some(self.export_index()) {
             return Err(nfsstat3::NFS3ERR_BADHANDLE);
         }
         let gen = u64::from_le_bytes(id.data[0..8].try_into().unwrap());
@@ -213,7 +427,8 @@ pub trait NFSFileSystem: Sync {
         match gen.cmp(&gennum) {
             Ordering::Less => Err(nfsstat3::NFS3ERR_STALE),
             Ordering::Greater => Err(nfsstat3::NFS3ERR_BADHANDLE),
-            Ordering::Equal => Ok(id),
+            Ordering::Equal if self.exports_id(id).await => Ok(id),
+            Ordering::Equal => Err(nfsstat3::NFS3ERR_BADHANDLE),
         }
     }
     /// Converts a complete path to a fileid.  Optional.
@@ -232,17 +447,10 @@ pub trait NFSFileSystem: Sync {
     }
 
     async fn get_id_from_path(&self, path: &str, data_store: &dyn DataStore) -> Result<fileid3, nfsstat3> {
-        let (namespace_id, community) = SharesFS::get_namespace_id_and_community().await;
-        let key = format!("{}/{}_path_to_id", community, namespace_id);
-    
-        let id_str = data_store.hget(&key, path)
+        namespace::resolve_path(data_store, self.namespace(), path)
             .await
-            .map_err(|_| nfsstat3::NFS3ERR_IO)?;
-    
-        let id: fileid3 = id_str.parse()
-            .map_err(|_| nfsstat3::NFS3ERR_IO)?;
-    
-        Ok(id)
+            .map_err(|_| nfsstat3::NFS3ERR_IO)?
+            .ok_or(nfsstat3::NFS3ERR_NOENT)
     }
 
     fn serverid(&self) -> cookieverf3 {
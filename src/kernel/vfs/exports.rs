@@ -0,0 +1,284 @@
+// The exports one listener serves and the mounts clients hold of them.
+//
+// Each export is a directory of a file system, with the client networks allowed to mount it, and is
+// served by its own NFSFileSystem. The index of an export in its table is carried in every file
+// handle that export's file system hands out, so NFS calls are routed back to it. Users the export
+// serves read-only are handed handles of a read-only view of it, marked with READ_ONLY_VIEW.
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use crate::kernel::vfs::api::NFSFileSystem;
+
+// Set in the export index of a read-only view's file handles
+pub const READ_ONLY_VIEW: u32 = 1 << 31;
+
+// A client network in CIDR notation; a bare address is a single host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl ClientNetwork {
+    pub fn parse(spec: &str) -> Result<ClientNetwork, String> {
+        let (addr, prefix) = match spec.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (spec, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| format!("{:?} is not an IP address", addr))?;
+        let width = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse::<u8>().ok().filter(|prefix| *prefix <= width)
+                .ok_or_else(|| format!("{:?} is not a prefix length for {}", prefix, addr))?,
+            None => width,
+        };
+        Ok(ClientNetwork { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients reaching an IPv6 socket show up as mapped addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ClientNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+// The address of an RPCContext client_addr, which is usually ip:port
+pub fn client_ip(client_addr: &str) -> Option<IpAddr> {
+    client_addr.parse::<SocketAddr>().map(|addr| addr.ip()).ok()
+        .or_else(|| client_addr.parse::<IpAddr>().ok())
+}
+
+pub struct Export {
+    // Absolute and without a trailing slash, except for "/" itself
+    pub path: String,
+    // Networks allowed to mount; any client when empty
+    pub clients: Vec<ClientNetwork>,
+    pub read_only: bool,
+    // Users the export is read-only to, served by read_only_vfs
+    pub read_only_users: HashSet<String>,
+    // Superusers mount their own directory of the export rather than all of it
+    pub root_squash: bool,
+    // Only calls of an authenticated user, by AUTH_SIGNED or a client certificate, are served;
+    // others are refused with AUTH_TOOWEAK
+    pub require_signed_auth: bool,
+    // Only calls over TLS are served; others are refused with AUTH_TOOWEAK
+    pub require_tls: bool,
+    pub vfs: Arc<dyn NFSFileSystem + Send + Sync>,
+    pub read_only_vfs: Option<Arc<dyn NFSFileSystem + Send + Sync>>,
+}
+
+impl Export {
+    pub fn admits_client(&self, client_addr: &str) -> bool {
+        if self.clients.is_empty() {
+            return true;
+        }
+        match client_ip(client_addr) {
+            Some(ip) => self.clients.iter().any(|network| network.contains(ip)),
+            None => false,
+        }
+    }
+
+    // The file system a user's mount is served by
+    pub fn vfs_for_user(&self, user: &str) -> &Arc<dyn NFSFileSystem + Send + Sync> {
+        match &self.read_only_vfs {
+            Some(read_only_vfs) if self.read_only_users.contains(user) => read_only_vfs,
+            _ => &self.vfs,
+        }
+    }
+
+    // The directory a user mounts from this export
+    pub fn user_path(&self, user: &str) -> String {
+        format!("{}/{}", self.path.trim_end_matches('/'), user)
+    }
+}
+
+// A mount reported by DUMP: the client host and the path it mounted
+#[derive(Debug, Clone, PartialEq)]
+pub struct MountEntry {
+    pub client: String,
+    pub path: String,
+}
+
+pub struct ExportTable {
+    exports: Vec<Export>,
+    mounts: Mutex<Vec<MountEntry>>,
+}
+
+impl ExportTable {
+    pub fn new(exports: Vec<Export>) -> ExportTable {
+        ExportTable { exports, mounts: Mutex::new(Vec::new()) }
+    }
+
+    // All of one file system, to any client
+    pub fn single(vfs: Arc<dyn NFSFileSystem + Send + Sync>) -> ExportTable {
+        ExportTable::new(vec![Export {
+            path: "/".to_string(),
+            clients: Vec::new(),
+            read_only: false,
+            read_only_users: HashSet::new(),
+            root_squash: false,
+            require_signed_auth: false,
+            require_tls: false,
+            vfs,
+            read_only_vfs: None,
+        }])
+    }
+
+    pub fn exports(&self) -> &[Export] {
+        &self.exports
+    }
+
+    pub fn get(&self, index: u32) -> Option<&Export> {
+        self.exports.get(index as usize)
+    }
+
+    // The file system the export index of a file handle routes to
+    pub fn filesystem(&self, index: u32) -> Option<&Arc<dyn NFSFileSystem + Send + Sync>> {
+        let export = self.get(index & !READ_ONLY_VIEW)?;
+        if index & READ_ONLY_VIEW == 0 {
+            Some(&export.vfs)
+        } else {
+            export.read_only_vfs.as_ref()
+        }
+    }
+
+    // Splits a mount path such as "/projects/alice's drive" into the index of the export it names
+    // and the user of the drive, if any
+    pub fn resolve_mount(&self, mount_path: &str) -> Option<(u32, Option<String>)> {
+        let mut user = None;
+        let mut components = Vec::new();
+        for component in mount_path.split('/').filter(|component| !component.is_empty()) {
+            match component.strip_suffix("'s drive") {
+                Some(name) => user = Some(name.to_string()),
+                None => components.push(component),
+            }
+        }
+        let path = format!("/{}", components.join("/"));
+        let index = self.exports.iter().position(|export| export.path == path)?;
+        Some((index as u32, user))
+    }
+
+    pub fn record_mount(&self, client_addr: &str, path: &str) {
+        let entry = MountEntry { client: client_host(client_addr), path: path.to_string() };
+        let mut mounts = self.mounts.lock().unwrap();
+        if !mounts.contains(&entry) {
+            mounts.push(entry);
+        }
+    }
+
+    pub fn remove_mount(&self, client_addr: &str, path: &str) {
+        let client = client_host(client_addr);
+        self.mounts.lock().unwrap().retain(|entry| entry.client != client || entry.path != path);
+    }
+
+    pub fn remove_client(&self, client_addr: &str) {
+        let client = client_host(client_addr);
+        self.mounts.lock().unwrap().retain(|entry| entry.client != client);
+    }
+
+    pub fn mounts(&self) -> Vec<MountEntry> {
+        self.mounts.lock().unwrap().clone()
+    }
+}
+
+// Mounts are held by hosts, whichever port they call from
+fn client_host(client_addr: &str) -> String {
+    client_ip(client_addr).map(|ip| ip.to_string()).unwrap_or_else(|| client_addr.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::vfs::api::VFSCapabilities;
+    use crate::kernel::vfs::mock::MockNFSFileSystem;
+
+    fn export(path: &str, clients: &[&str]) -> Export {
+        Export {
+            path: path.to_string(),
+            clients: clients.iter().map(|spec| ClientNetwork::parse(spec).unwrap()).collect(),
+            read_only: false,
+            read_only_users: HashSet::new(),
+            root_squash: false,
+            require_signed_auth: false,
+            require_tls: false,
+            vfs: Arc::new(MockNFSFileSystem::new_readwrite()),
+            read_only_vfs: None,
+        }
+    }
+
+    #[test]
+    fn test_client_networks() {
+        let lan = ClientNetwork::parse("10.1.0.0/16").unwrap();
+        assert!(lan.contains("10.1.200.3".parse().unwrap()));
+        assert!(!lan.contains("10.2.0.1".parse().unwrap()));
+        assert!(lan.contains("::ffff:10.1.0.9".parse().unwrap()));
+        assert!(ClientNetwork::parse("0.0.0.0/0").unwrap().contains("192.168.1.1".parse().unwrap()));
+        assert!(ClientNetwork::parse("fd00::/8").unwrap().contains("fd12::1".parse().unwrap()));
+        assert_eq!(ClientNetwork::parse("127.0.0.1").unwrap().to_string(), "127.0.0.1/32");
+        assert!(ClientNetwork::parse("10.0.0.0/33").is_err());
+        assert!(ClientNetwork::parse("lan").is_err());
+
+        let restricted = export("/", &["10.1.0.0/16"]);
+        assert!(restricted.admits_client("10.1.0.5:871"));
+        assert!(!restricted.admits_client("127.0.0.1:871"));
+        assert!(export("/", &[]).admits_client("127.0.0.1:871"));
+    }
+
+    #[test]
+    fn test_resolves_mounts_and_tracks_them() {
+        let table = ExportTable::new(vec![export("/", &[]), export("/projects", &[])]);
+        assert_eq!(table.resolve_mount("/alice's drive"), Some((0, Some("alice".to_string()))));
+        assert_eq!(table.resolve_mount("/projects/alice's drive"), Some((1, Some("alice".to_string()))));
+        assert_eq!(table.resolve_mount("/projects/"), Some((1, None)));
+        assert_eq!(table.resolve_mount("/archive/alice's drive"), None);
+        assert_eq!(table.get(1).unwrap().user_path("alice"), "/projects/alice");
+        assert_eq!(table.get(0).unwrap().user_path("alice"), "/alice");
+
+        table.record_mount("10.0.0.1:700", "/alice's drive");
+        table.record_mount("10.0.0.1:701", "/alice's drive");
+        table.record_mount("10.0.0.1:702", "/projects/alice's drive");
+        table.record_mount("10.0.0.2:700", "/bob's drive");
+        assert_eq!(table.mounts().len(), 3);
+        table.remove_mount("10.0.0.1:900", "/alice's drive");
+        assert_eq!(table.mounts().len(), 2);
+        table.remove_client("10.0.0.1:900");
+        assert_eq!(table.mounts(), vec![MountEntry { client: "10.0.0.2".to_string(), path: "/bob's drive".to_string() }]);
+    }
+
+    #[test]
+    fn test_read_only_users_get_the_read_only_view() {
+        let mut reviewed = export("/", &[]);
+        reviewed.read_only_users.insert("reviewer".to_string());
+        reviewed.read_only_vfs = Some(Arc::new(MockNFSFileSystem::new_readonly()));
+        let table = ExportTable::new(vec![reviewed]);
+        let export = table.get(0).unwrap();
+
+        assert!(matches!(export.vfs_for_user("alice").capabilities(), VFSCapabilities::ReadWrite));
+        assert!(matches!(export.vfs_for_user("reviewer").capabilities(), VFSCapabilities::ReadOnly));
+        assert!(matches!(table.filesystem(0).unwrap().capabilities(), VFSCapabilities::ReadWrite));
+        assert!(matches!(table.filesystem(READ_ONLY_VIEW).unwrap().capabilities(), VFSCapabilities::ReadOnly));
+        assert!(table.filesystem(1).is_none());
+        assert!(ExportTable::single(Arc::new(MockNFSFileSystem::new_readwrite())).filesystem(READ_ONLY_VIEW).is_none());
+    }
+}
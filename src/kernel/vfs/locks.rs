@@ -0,0 +1,227 @@
+// Advisory byte-range locks, as NLM clients take them for fcntl, kept per file in memory. Each
+// lock belongs to an owner, a process (svid and owner handle) on a client host, and POSIX rules
+// apply: an owner's locks never conflict with each other, and locking or unlocking part of a
+// range it holds splits it. Locks do not survive a restart; for a grace period afterwards only
+// clients reclaiming the locks they held may lock.
+use crate::kernel::api::nfs::fileid3;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::{Duration, Instant};
+
+// How long after a restart clients have to reclaim their locks, as lockd gives them
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(90);
+
+// A file of an export
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FileKey {
+    pub export: u32,
+    pub fileid: fileid3,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LockOwner {
+    // The client's name for itself, which its status monitor announces restarts with
+    pub host: String,
+    pub svid: i32,
+    pub oh: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileLock {
+    pub owner: LockOwner,
+    pub exclusive: bool,
+    pub start: u64,
+    // One past the last byte, u64::MAX for a lock to the end of the file however long it grows
+    pub end: u64,
+}
+
+impl FileLock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start < end && start < self.end
+    }
+}
+
+// The end of a range of an offset and length, a length of 0 reaching the end of the file
+pub fn range_end(offset: u64, len: u64) -> u64 {
+    if len == 0 {
+        u64::MAX
+    } else {
+        offset.saturating_add(len)
+    }
+}
+
+// The length of a range as NLM gives it
+pub fn range_len(start: u64, end: u64) -> u64 {
+    if end == u64::MAX {
+        0
+    } else {
+        end - start
+    }
+}
+
+#[derive(Debug)]
+pub struct LockManager {
+    files: Mutex<HashMap<FileKey, Vec<FileLock>>>,
+    // The address each client host last locked from
+    hosts: Mutex<HashMap<String, String>>,
+    grace_until: Instant,
+    // The server's status monitor state number, odd while it is up
+    state: AtomicI32,
+}
+
+impl Default for LockManager {
+    fn default() -> Self {
+        LockManager::new(Duration::ZERO)
+    }
+}
+
+impl LockManager {
+    pub fn new(grace_period: Duration) -> LockManager {
+        LockManager {
+            files: Mutex::new(HashMap::new()),
+            hosts: Mutex::new(HashMap::new()),
+            grace_until: Instant::now() + grace_period,
+            state: AtomicI32::new(1),
+        }
+    }
+
+    pub fn in_grace_period(&self) -> bool {
+        Instant::now() < self.grace_until
+    }
+
+    pub fn state(&self) -> i32 {
+        self.state.load(Ordering::Relaxed)
+    }
+
+    pub fn set_state(&self, state: i32) {
+        self.state.store(state, Ordering::Relaxed);
+    }
+
+    // A lock of another owner that would keep this one from being taken
+    pub fn test(&self, file: FileKey, owner: &LockOwner, exclusive: bool, start: u64, end: u64) -> Option<FileLock> {
+        let files = self.files.lock();
+        files.get(&file)?.iter().find(|lock| conflicts(lock, owner, exclusive, start, end)).cloned()
+    }
+
+    // Takes a lock, replacing the owner's own locks of the range, unless another owner's conflicts
+    pub fn lock(&self, file: FileKey, owner: LockOwner, exclusive: bool, start: u64, end: u64) -> Result<(), FileLock> {
+        let mut files = self.files.lock();
+        let locks = files.entry(file).or_default();
+        if let Some(conflict) = locks.iter().find(|lock| conflicts(lock, &owner, exclusive, start, end)) {
+            return Err(conflict.clone());
+        }
+        carve(locks, &owner, start, end);
+        locks.push(FileLock { owner, exclusive, start, end });
+        Ok(())
+    }
+
+    // Releases the owner's locks of a range, returning whether it held any
+    pub fn unlock(&self, file: FileKey, owner: &LockOwner, start: u64, end: u64) -> bool {
+        let mut files = self.files.lock();
+        let Some(locks) = files.get_mut(&file) else { return false };
+        let released = carve(locks, owner, start, end);
+        if locks.is_empty() {
+            files.remove(&file);
+        }
+        released
+    }
+
+    // Releases every lock of a client host, returning the files it held locks of
+    pub fn release_host(&self, host: &str) -> Vec<FileKey> {
+        let mut released = Vec::new();
+        self.files.lock().retain(|file, locks| {
+            let before = locks.len();
+            locks.retain(|lock| lock.owner.host != host);
+            if locks.len() != before {
+                released.push(*file);
+            }
+            !locks.is_empty()
+        });
+        self.hosts.lock().remove(host);
+        released
+    }
+
+    // Records the address a host locks from, returning whether it is new or has changed
+    pub fn note_host(&self, host: &str, address: &str) -> bool {
+        self.hosts.lock().insert(host.to_string(), address.to_string()).as_deref() != Some(address)
+    }
+
+    // The hosts that locked from an address
+    pub fn hosts_at(&self, address: &str) -> Vec<String> {
+        self.hosts.lock().iter().filter(|(_, at)| at.as_str() == address).map(|(host, _)| host.clone()).collect()
+    }
+
+    pub fn locks(&self, file: FileKey) -> Vec<FileLock> {
+        self.files.lock().get(&file).cloned().unwrap_or_default()
+    }
+}
+
+fn conflicts(lock: &FileLock, owner: &LockOwner, exclusive: bool, start: u64, end: u64) -> bool {
+    lock.owner != *owner && (exclusive || lock.exclusive) && lock.overlaps(start, end)
+}
+
+// Removes a range from an owner's locks, keeping the parts of them outside it
+fn carve(locks: &mut Vec<FileLock>, owner: &LockOwner, start: u64, end: u64) -> bool {
+    let mut carved = false;
+    let mut kept = Vec::with_capacity(locks.len());
+    for lock in locks.drain(..) {
+        if lock.owner != *owner || !lock.overlaps(start, end) {
+            kept.push(lock);
+            continue;
+        }
+        carved = true;
+        if lock.start < start {
+            kept.push(FileLock { end: start, ..lock.clone() });
+        }
+        if end < lock.end {
+            kept.push(FileLock { start: end, ..lock });
+        }
+    }
+    *locks = kept;
+    carved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owner(host: &str, svid: i32) -> LockOwner {
+        LockOwner { host: host.to_string(), svid, oh: Vec::new() }
+    }
+
+    #[test]
+    fn test_posix_byte_range_locks() {
+        let locks = LockManager::default();
+        let file = FileKey { export: 0, fileid: 7 };
+        let (alice, bob) = (owner("alice-laptop", 1), owner("bob-laptop", 1));
+
+        // Shared locks coexist; an exclusive one conflicts with another owner's overlapping lock
+        locks.lock(file, alice.clone(), false, 0, 100).unwrap();
+        locks.lock(file, bob.clone(), false, 50, 150).unwrap();
+        assert_eq!(locks.lock(file, bob.clone(), true, 90, 95).unwrap_err().owner, alice);
+        assert!(locks.test(file, &bob, true, 100, 150).is_none());
+
+        // An owner's own locks are replaced, and unlocking part of one splits it
+        locks.lock(file, alice.clone(), true, 0, 40).unwrap();
+        assert!(locks.unlock(file, &alice, 10, 20));
+        let mut held: Vec<_> = locks.locks(file).into_iter().filter(|lock| lock.owner == alice).map(|lock| (lock.exclusive, lock.start, lock.end)).collect();
+        held.sort_by_key(|&(_, start, _)| start);
+        assert_eq!(held, vec![(true, 0, 10), (true, 20, 40), (false, 40, 100)]);
+
+        // A lock to the end of the file covers whatever is past it
+        assert_eq!(range_end(200, 0), u64::MAX);
+        assert!(locks.lock(file, alice.clone(), true, 1000, range_end(1000, 0)).is_ok());
+        assert!(locks.test(file, &bob, false, u64::MAX - 1, u64::MAX).is_some());
+        assert_eq!(range_len(1000, u64::MAX), 0);
+
+        // A restarted client loses its locks
+        assert!(locks.note_host("alice-laptop", "10.0.0.1"));
+        assert!(!locks.note_host("alice-laptop", "10.0.0.1"));
+        assert_eq!(locks.hosts_at("10.0.0.1"), vec!["alice-laptop".to_string()]);
+        assert_eq!(locks.release_host("alice-laptop"), vec![file]);
+        assert!(locks.lock(file, bob.clone(), true, 150, 2000).is_ok());
+        assert!(!locks.unlock(file, &alice, 0, u64::MAX));
+        assert!(!locks.in_grace_period() && LockManager::new(DEFAULT_GRACE_PERIOD).in_grace_period());
+    }
+}
@@ -1,89 +1,96 @@
 use super::api::*;
 use crate::kernel::api::nfs::*;
-use crate::backingstore::data_store::{DataStore, DataStoreError, KeyType};
+use crate::backingstore::data_store::{DataStore, DataStoreError};
+use crate::backingstore::test_store::TestDataStore;
+use crate::sharesfs::namespace::Namespace;
 use async_trait::async_trait;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
-pub struct MockDataStore {}
+// In-memory store backing the mock filesystem; delegates to TestDataStore so that it
+// behaves like the real backends (see backingstore::conformance)
+#[derive(Default)]
+pub struct MockDataStore {
+    inner: TestDataStore,
+}
 
 #[async_trait]
 impl DataStore for MockDataStore {
-    async fn get(&self, _key: &str) -> Result<String, DataStoreError> {
-        todo!("MockDataStore::get not implemented")
+    async fn get(&self, key: &str) -> Result<String, DataStoreError> {
+        self.inner.get(key).await
     }
 
-    async fn set(&self, _key: &str, _value: &str) -> Result<(), DataStoreError> {
-        todo!("MockDataStore::set not implemented")
+    async fn set(&self, key: &str, value: &str) -> Result<(), DataStoreError> {
+        self.inner.set(key, value).await
     }
 
-    async fn delete(&self, _key: &str) -> Result<(), DataStoreError> {
-        todo!("MockDataStore::delete not implemented")
+    async fn delete(&self, key: &str) -> Result<(), DataStoreError> {
+        self.inner.delete(key).await
     }
 
-    async fn hget(&self, _key: &str, _field: &str) -> Result<String, DataStoreError> {
-        todo!("MockDataStore::hget not implemented")
+    async fn hget(&self, key: &str, field: &str) -> Result<String, DataStoreError> {
+        self.inner.hget(key, field).await
     }
 
-    async fn hset(&self, _key: &str, _field: &str, _value: &str) -> Result<(), DataStoreError> {
-        todo!("MockDataStore::hset not implemented")
+    async fn hset(&self, key: &str, field: &str, value: &str) -> Result<(), DataStoreError> {
+        self.inner.hset(key, field, value).await
     }
 
-    async fn hdel(&self, _key: &str, _field: &str) -> Result<(), DataStoreError> {
-        todo!("MockDataStore::hdel not implemented")
+    async fn hdel(&self, key: &str, field: &str) -> Result<(), DataStoreError> {
+        self.inner.hdel(key, field).await
     }
 
-    async fn hgetall(&self, _key: &str) -> Result<Vec<(String, String)>, DataStoreError> {
-        todo!("MockDataStore::hgetall not implemented")
+    async fn hgetall(&self, key: &str) -> Result<Vec<(String, String)>, DataStoreError> {
+        self.inner.hgetall(key).await
     }
 
-    async fn incr(&self, _key: &str) -> Result<i64, DataStoreError> {
-        todo!("MockDataStore::incr not implemented")
+    async fn incr(&self, key: &str) -> Result<i64, DataStoreError> {
+        self.inner.incr(key).await
     }
 
-    async fn rename(&self, _old_key: &str, _new_key: &str) -> Result<(), DataStoreError> {
-        todo!("MockDataStore::rename not implemented")
+    async fn rename(&self, old_key: &str, new_key: &str) -> Result<(), DataStoreError> {
+        self.inner.rename(old_key, new_key).await
     }
 
-    async fn keys(&self, _pattern: &str) -> Result<Vec<String>, DataStoreError> {
-        todo!("MockDataStore::keys not implemented")
+    async fn keys(&self, pattern: &str) -> Result<Vec<String>, DataStoreError> {
+        self.inner.keys(pattern).await
     }
 
-    async fn zrange_withscores(&self, _key: &str, _start: isize, _stop: isize) -> Result<Vec<(String, f64)>, DataStoreError> {
-        todo!("MockDataStore::zrange_withscores not implemented")
+    async fn zrange_withscores(&self, key: &str, start: isize, stop: isize) -> Result<Vec<(String, f64)>, DataStoreError> {
+        self.inner.zrange_withscores(key, start, stop).await
     }
 
-    async fn zadd(&self, _key: &str, _member: &str, _score: f64) -> Result<(), DataStoreError> {
-        todo!("MockDataStore::zadd not implemented")
+    async fn zadd(&self, key: &str, member: &str, score: f64) -> Result<(), DataStoreError> {
+        self.inner.zadd(key, member, score).await
     }
 
-    async fn zrem(&self, _key: &str, _member: &str) -> Result<(), DataStoreError> {
-        todo!("MockDataStore::zrem not implemented")
+    async fn zrem(&self, key: &str, member: &str) -> Result<(), DataStoreError> {
+        self.inner.zrem(key, member).await
     }
 
-    async fn zrangebyscore(&self, _key: &str, _min: f64, _max: f64) -> Result<Vec<String>, DataStoreError> {
-        todo!("MockDataStore::zrangebyscore not implemented")
+    async fn zrangebyscore(&self, key: &str, min: f64, max: f64) -> Result<Vec<String>, DataStoreError> {
+        self.inner.zrangebyscore(key, min, max).await
     }
 
-    async fn hset_multiple(&self, _key: &str, _fields: &[(&str, &str)]) -> Result<(), DataStoreError> {
-        todo!("MockDataStore::hset_multiple not implemented")
+    async fn hset_multiple(&self, key: &str, fields: &[(&str, &str)]) -> Result<(), DataStoreError> {
+        self.inner.hset_multiple(key, fields).await
     }
 
-    async fn zscan_match(&self, _key: &str, _pattern: &str) -> Result<Vec<String>, DataStoreError> {
-        todo!("MockDataStore::zscan_match not implemented")
+    async fn zscan_match(&self, key: &str, pattern: &str) -> Result<Vec<String>, DataStoreError> {
+        self.inner.zscan_match(key, pattern).await
     }
 
-    async fn zscore(&self, _key: &str, _member: &str) -> Result<Option<f64>, DataStoreError> {
-        todo!("MockDataStore::zscore not implemented")
+    async fn zscore(&self, key: &str, member: &str) -> Result<Option<f64>, DataStoreError> {
+        self.inner.zscore(key, member).await
     }
 
-    async fn authenticate_user(&self, _userkey: &str) -> KeyType {
-        todo!("MockDataStore::authenticate_user not implemented")
+    async fn legacy_users(&self) -> Result<Vec<String>, DataStoreError> {
+        self.inner.legacy_users().await
     }
 
-    async fn init_user_directory(&self, _mount_path: &str) -> Result<(), DataStoreError> {
-        todo!("MockDataStore::init_user_directory not implemented")
+    async fn init_user_directory(&self, namespace: &Namespace, mount_path: &str) -> Result<(), DataStoreError> {
+        self.inner.init_user_directory(namespace, mount_path).await
     }
 }
 
@@ -91,7 +98,8 @@ pub struct MockNFSFileSystem {
     capabilities: super::api::VFSCapabilities,
     files: Arc<RwLock<HashMap<fileid3, fattr3>>>,
     next_fileid: Arc<RwLock<u64>>,
-    data_store: MockDataStore
+    data_store: MockDataStore,
+    namespace: Namespace,
 }
 
 impl MockNFSFileSystem {
@@ -100,7 +108,8 @@ impl MockNFSFileSystem {
             capabilities: super::api::VFSCapabilities::ReadOnly,
             files: Arc::new(RwLock::new(HashMap::new())),
             next_fileid: Arc::new(RwLock::new(1)),
-            data_store: MockDataStore {}
+            data_store: MockDataStore::default(),
+            namespace: Namespace::new("mock", "mock"),
         }
     }
     
@@ -109,7 +118,8 @@ impl MockNFSFileSystem {
             capabilities: super::api::VFSCapabilities::ReadWrite,
             files: Arc::new(RwLock::new(HashMap::new())),
             next_fileid: Arc::new(RwLock::new(1)),
-            data_store: MockDataStore {}
+            data_store: MockDataStore::default(),
+            namespace: Namespace::new("mock", "mock"),
         }
     }
 }
@@ -120,6 +130,10 @@ impl NFSFileSystem for MockNFSFileSystem {
         &self.data_store
     }
 
+    fn namespace(&self) -> &Namespace {
+        &self.namespace
+    }
+
     fn capabilities(&self) -> VFSCapabilities {
         self.capabilities.clone()
     }
@@ -1,3 +1,5 @@
 pub mod api;
+pub mod exports;
+pub mod locks;
 #[cfg(test)]
 pub mod mock;
\ No newline at end of file
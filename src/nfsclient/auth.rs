@@ -1,6 +1,7 @@
 // Constants for AUTH types
 pub const AUTH_UNIX: u32 = 1;
 pub const AUTH_NULL: u32 = 0;
+pub const AUTH_TLS: u32 = 7;
 
 // Standard Finder credentials
 pub const DEFAULT_UID: u32 = 501;
@@ -52,4 +53,84 @@ impl AuthUnix {
         call.extend_from_slice(&AUTH_NULL.to_be_bytes());
         call.extend_from_slice(&0u32.to_be_bytes());
     }
-}
\ No newline at end of file
+}
+// Keys an AUTH_SIGNED credential is signed with, as the server's users tool hands them out
+pub enum SigningKey {
+    HmacSha256(Vec<u8>),
+    Ed25519(ed25519_dalek::SigningKey),
+}
+
+impl SigningKey {
+    // "hmac:<hex key>" or "ed25519:<hex private key>"
+    pub fn parse(spec: &str) -> Result<SigningKey, String> {
+        let (scheme, key) = spec.split_once(':').ok_or_else(|| format!("{:?} is not scheme:key", spec))?;
+        let key = hex::decode(key).map_err(|_| format!("the {} key is not hex", scheme))?;
+        match scheme {
+            "hmac" => Ok(SigningKey::HmacSha256(key)),
+            "ed25519" => <[u8; 32]>::try_from(key)
+                .map(|key| SigningKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(&key)))
+                .map_err(|_| "an Ed25519 private key is 32 bytes".to_string()),
+            _ => Err(format!("unknown signing scheme {:?}", scheme)),
+        }
+    }
+}
+
+pub struct SignedCredential {
+    pub user: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub key: SigningKey,
+}
+
+impl SignedCredential {
+    // Appends the credential and its verifier to a call holding the header up to the procedure
+    pub fn write_to_vec(&self, call: &mut Vec<u8>) {
+        use crate::kernel::protocol::rpc::{
+            auth_flavor, auth_signed, call_body, opaque_auth, signed_payload, SIGNED_ED25519, SIGNED_HMAC_SHA256,
+        };
+        use crate::kernel::protocol::xdr::XdrSerialize;
+        use ed25519_dalek::Signer;
+        use hmac::{Hmac, Mac};
+        use rand::RngCore;
+
+        let word = |index: usize| u32::from_be_bytes(call[index * 4..index * 4 + 4].try_into().unwrap());
+        let mut nonce = vec![0u8; 16];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let cred = auth_signed {
+            user: self.user.as_bytes().to_vec(),
+            uid: self.uid,
+            gid: self.gid,
+            gids: vec![self.gid],
+            scheme: match self.key {
+                SigningKey::HmacSha256(_) => SIGNED_HMAC_SHA256,
+                SigningKey::Ed25519(_) => SIGNED_ED25519,
+            },
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|since| since.as_secs())
+                .unwrap_or(0),
+            nonce,
+        };
+        let mut body = Vec::new();
+        cred.serialize(&mut body).unwrap();
+        let header = call_body {
+            rpcvers: word(2),
+            prog: word(3),
+            vers: word(4),
+            proc: word(5),
+            cred: opaque_auth { flavor: auth_flavor::AUTH_SIGNED, body },
+            verf: opaque_auth::default(),
+        };
+        let payload = signed_payload(word(0), &header);
+        let signature = match &self.key {
+            SigningKey::HmacSha256(key) => {
+                let mut mac = Hmac::<sha2::Sha256>::new_from_slice(key).expect("HMAC takes any key length");
+                mac.update(&payload);
+                mac.finalize().into_bytes().to_vec()
+            }
+            SigningKey::Ed25519(key) => key.sign(&payload).to_bytes().to_vec(),
+        };
+        header.cred.serialize(call).unwrap();
+        opaque_auth { flavor: auth_flavor::AUTH_SIGNED, body: signature }.serialize(call).unwrap();
+    }
+}
@@ -0,0 +1,783 @@
+// An NFSv3 client over one connection, shared by as many calls as are made at once: each call
+// gets its own xid and the replies, in whatever order the server sends them, go to the call
+// with their xid. A connection the server closes is opened again by the next call.
+use super::auth::{SignedCredential, DEFAULT_GID, DEFAULT_UID};
+use super::tls::{start_tls, NfsStream};
+use crate::kernel::api::mount::{self, mountres3_ok, mountstat3};
+use crate::kernel::api::nfs::{
+    self, cookie3, cookieverf3, count3, createverf3, devicedata3, diropargs3, fattr3, filename3, fileid3, fsinfo3,
+    nfs_fh3, nfspath3, nfsstat3, nfstime3, offset3, post_op_attr, post_op_fh3, sattr3, symlinkdata3, wcc_data,
+    writeverf3,
+};
+use crate::kernel::handlers::nfs::basic_ops::{sattrguard3, SETATTR3args};
+use crate::kernel::handlers::nfs::directory_ops::{entryplus3, MKDIR3args, READDIRPLUS3args};
+use crate::kernel::handlers::nfs::file_ops::{stable_how, READ3args, READ3resok, WRITE3args, WRITE3resok};
+use crate::kernel::handlers::nfs::fs_ops::{FSSTAT3resok, PATHCONF3resok};
+use crate::kernel::handlers::nfs::link_ops::SYMLINK3args;
+use crate::kernel::protocol::rpc::{
+    accept_body, auth_flavor, auth_stat, auth_unix, opaque_auth, rejected_reply, reply_body, rpc_body, rpc_msg,
+};
+use crate::kernel::protocol::rpcwire::{read_fragment, write_record, RecordLimits};
+use crate::kernel::protocol::xdr::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Cursor, Read, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::ClientConfig;
+use tracing::debug;
+
+// How long a call waits for its reply unless set_timeout says otherwise
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum NfsError {
+    Io(io::Error),
+    Tls(String),
+    // The connection closed before the reply came
+    Closed,
+    Timeout,
+    // The reply could not be read
+    Decode(io::Error),
+    RpcMismatch { low: u32, high: u32 },
+    AuthError(auth_stat),
+    ProgUnavail,
+    ProgMismatch { low: u32, high: u32 },
+    ProcUnavail,
+    GarbageArgs,
+    Nfs(nfsstat3),
+    Mount(mountstat3),
+}
+
+impl fmt::Display for NfsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NfsError::Io(e) => write!(f, "connection failed: {}", e),
+            NfsError::Tls(e) => write!(f, "could not start TLS: {}", e),
+            NfsError::Closed => write!(f, "the connection closed before the reply came"),
+            NfsError::Timeout => write!(f, "no reply came in time"),
+            NfsError::Decode(e) => write!(f, "the reply could not be read: {}", e),
+            NfsError::RpcMismatch { low, high } => write!(f, "the server speaks RPC versions {} to {}", low, high),
+            NfsError::AuthError(stat) => write!(f, "the server refused the credential: {:?}", stat),
+            NfsError::ProgUnavail => write!(f, "the server does not offer the program"),
+            NfsError::ProgMismatch { low, high } => {
+                write!(f, "the server offers versions {} to {} of the program", low, high)
+            }
+            NfsError::ProcUnavail => write!(f, "the server does not offer the procedure"),
+            NfsError::GarbageArgs => write!(f, "the server could not read the arguments"),
+            NfsError::Nfs(stat) => write!(f, "the server answered {:?}", stat),
+            NfsError::Mount(stat) => write!(f, "the mount failed: {:?}", stat),
+        }
+    }
+}
+
+impl std::error::Error for NfsError {}
+
+impl From<io::Error> for NfsError {
+    fn from(e: io::Error) -> Self {
+        NfsError::Io(e)
+    }
+}
+
+// The credential every call is made with
+pub enum Credential {
+    None,
+    Unix(auth_unix),
+    Signed(Box<SignedCredential>),
+}
+
+impl Credential {
+    // AUTH_UNIX as uid, in gid and no other groups
+    pub fn unix(uid: u32, gid: u32) -> Credential {
+        Credential::Unix(auth_unix { uid, gid, gids: vec![gid], ..auth_unix::default() })
+    }
+
+    // Appends the credential and its verifier to a call holding the header up to the procedure
+    fn write_to_vec(&self, call: &mut Vec<u8>) -> io::Result<()> {
+        let cred = match self {
+            Credential::None => opaque_auth::default(),
+            Credential::Unix(unix) => {
+                let mut body = Vec::new();
+                unix.serialize(&mut body)?;
+                opaque_auth { flavor: auth_flavor::AUTH_UNIX, body }
+            }
+            Credential::Signed(signed) => {
+                signed.write_to_vec(call);
+                return Ok(());
+            }
+        };
+        cred.serialize(call)?;
+        opaque_auth::default().serialize(call)
+    }
+}
+
+impl Default for Credential {
+    fn default() -> Self {
+        Credential::unix(DEFAULT_UID, DEFAULT_GID)
+    }
+}
+
+// Where connections are made, and the TLS they are upgraded to if any
+struct Target {
+    addr: SocketAddr,
+    tls: Option<(String, Arc<ClientConfig>)>,
+}
+
+// The calls waiting for a reply by xid, or None once the connection has closed
+type Pending = Arc<Mutex<Option<HashMap<u32, oneshot::Sender<Vec<u8>>>>>>;
+
+struct Connection {
+    writer: WriteHalf<NfsStream>,
+    pending: Pending,
+    reader: JoinHandle<()>,
+}
+
+impl Connection {
+    fn is_closed(&self) -> bool {
+        self.pending.lock().unwrap().is_none()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+// Hands each reply to the call with its xid until the connection closes, then lets the calls
+// still waiting know
+async fn route_replies(mut reader: ReadHalf<NfsStream>, pending: Pending, max_record: usize) {
+    loop {
+        let mut record = Vec::new();
+        loop {
+            match read_fragment(&mut reader, &mut record, max_record).await {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(e) => {
+                    debug!("connection closed: {}", e);
+                    pending.lock().unwrap().take();
+                    return;
+                }
+            }
+        }
+        let Some(xid) = record.get(0..4).map(|xid| u32::from_be_bytes(xid.try_into().unwrap())) else {
+            continue;
+        };
+        let waiting = pending.lock().unwrap().as_mut().and_then(|pending| pending.remove(&xid));
+        match waiting {
+            Some(call) => {
+                let _ = call.send(record);
+            }
+            None => debug!("reply to {} which no call is waiting for", xid),
+        }
+    }
+}
+
+// The reply's results, after its header, or the error the server answered with instead
+fn accepted(reply: &[u8]) -> Result<Cursor<&[u8]>, NfsError> {
+    let mut reply = Cursor::new(reply);
+    let mut msg = rpc_msg::default();
+    msg.deserialize(&mut reply).map_err(NfsError::Decode)?;
+    match msg.body {
+        rpc_body::REPLY(reply_body::MSG_ACCEPTED(accepted)) => match accepted.reply_data {
+            accept_body::SUCCESS => Ok(reply),
+            accept_body::PROG_UNAVAIL => Err(NfsError::ProgUnavail),
+            accept_body::PROG_MISMATCH(versions) => {
+                Err(NfsError::ProgMismatch { low: versions.low, high: versions.high })
+            }
+            accept_body::PROC_UNAVAIL => Err(NfsError::ProcUnavail),
+            accept_body::GARBAGE_ARGS => Err(NfsError::GarbageArgs),
+        },
+        rpc_body::REPLY(reply_body::MSG_DENIED(rejected_reply::RPC_MISMATCH(versions))) => {
+            Err(NfsError::RpcMismatch { low: versions.low, high: versions.high })
+        }
+        rpc_body::REPLY(reply_body::MSG_DENIED(rejected_reply::AUTH_ERROR(stat))) => Err(NfsError::AuthError(stat)),
+        rpc_body::CALL(_) => Err(NfsError::Decode(io::Error::new(io::ErrorKind::InvalidData, "a call, not a reply"))),
+    }
+}
+
+// Arguments of the procedures that take none
+struct Void;
+
+impl XdrSerialize for Void {
+    fn serialize<R: Write>(&self, _dest: &mut R) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct NfsClient {
+    target: Target,
+    credential: Credential,
+    timeout: Duration,
+    max_record: usize,
+    next_xid: AtomicU32,
+    connection: tokio::sync::Mutex<Option<Connection>>,
+}
+
+impl NfsClient {
+    pub async fn connect(addr: SocketAddr, credential: Credential) -> Result<NfsClient, NfsError> {
+        NfsClient::open(Target { addr, tls: None }, credential).await
+    }
+
+    // Connects with RPC-over-TLS, trusting the server by config as server_name
+    pub async fn connect_tls(
+        addr: SocketAddr,
+        server_name: &str,
+        config: Arc<ClientConfig>,
+        credential: Credential,
+    ) -> Result<NfsClient, NfsError> {
+        NfsClient::open(Target { addr, tls: Some((server_name.to_string(), config)) }, credential).await
+    }
+
+    async fn open(target: Target, credential: Credential) -> Result<NfsClient, NfsError> {
+        let client = NfsClient {
+            target,
+            credential,
+            timeout: DEFAULT_TIMEOUT,
+            max_record: RecordLimits::default().max_record,
+            // xids carry on from wherever the last client to connect left off, as far as a
+            // server remembering replies by xid can tell
+            next_xid: AtomicU32::new(rand::random()),
+            connection: tokio::sync::Mutex::new(None),
+        };
+        *client.connection.lock().await = Some(client.new_connection().await?);
+        Ok(client)
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    // Closes the connection; calls still waiting get Closed and the next call opens another
+    pub async fn close(&self) {
+        self.connection.lock().await.take();
+    }
+
+    async fn new_connection(&self) -> Result<Connection, NfsError> {
+        let stream = TcpStream::connect(self.target.addr).await?;
+        let stream = match &self.target.tls {
+            Some((server_name, config)) => {
+                start_tls(stream, server_name, config.clone()).await.map_err(|e| NfsError::Tls(e.to_string()))?
+            }
+            None => NfsStream::Plain(stream),
+        };
+        let (reader, writer) = tokio::io::split(stream);
+        let pending: Pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        let reader = tokio::spawn(route_replies(reader, pending.clone(), self.max_record));
+        Ok(Connection { writer, pending, reader })
+    }
+
+    // Makes a call and waits for its reply
+    async fn call(&self, prog: u32, vers: u32, proc: u32, args: &impl XdrSerialize) -> Result<Vec<u8>, NfsError> {
+        let xid = self.next_xid.fetch_add(1, Ordering::Relaxed);
+        let mut call = Vec::new();
+        // the xid, CALL and RPC version 2 come before the program
+        for word in [xid, 0, 2, prog, vers, proc] {
+            call.extend_from_slice(&word.to_be_bytes());
+        }
+        self.credential.write_to_vec(&mut call)?;
+        args.serialize(&mut call)?;
+
+        let (sender, reply) = oneshot::channel();
+        let pending = {
+            let mut connection = self.connection.lock().await;
+            if connection.as_ref().is_none_or(Connection::is_closed) {
+                *connection = Some(self.new_connection().await?);
+            }
+            let open = connection.as_mut().unwrap();
+            let pending = open.pending.clone();
+            match pending.lock().unwrap().as_mut() {
+                Some(waiting) => waiting.insert(xid, sender),
+                None => return Err(NfsError::Closed),
+            };
+            if let Err(e) = write_record(&mut open.writer, &call, self.max_record).await {
+                *connection = None;
+                return Err(NfsError::Io(e.downcast().unwrap_or_else(io::Error::other)));
+            }
+            pending
+        };
+
+        match tokio::time::timeout(self.timeout, reply).await {
+            Ok(Ok(reply)) => Ok(reply),
+            Ok(Err(_)) => Err(NfsError::Closed),
+            Err(_) => {
+                if let Some(waiting) = pending.lock().unwrap().as_mut() {
+                    waiting.remove(&xid);
+                }
+                Err(NfsError::Timeout)
+            }
+        }
+    }
+
+    // Makes an NFS call and reads the results it answers NFS3_OK with
+    async fn call_nfs<T: XdrDeserialize + Default>(&self, proc: u32, args: &impl XdrSerialize) -> Result<T, NfsError> {
+        let reply = self.call(nfs::PROGRAM, nfs::VERSION, proc, args).await?;
+        let mut results = accepted(&reply)?;
+        let mut stat = nfsstat3::NFS3_OK;
+        stat.deserialize(&mut results).map_err(NfsError::Decode)?;
+        if !matches!(stat, nfsstat3::NFS3_OK) {
+            return Err(NfsError::Nfs(stat));
+        }
+        let mut resok = T::default();
+        resok.deserialize(&mut results).map_err(NfsError::Decode)?;
+        Ok(resok)
+    }
+
+    pub async fn null(&self) -> Result<(), NfsError> {
+        let reply = self.call(nfs::PROGRAM, nfs::VERSION, NFSPROC3_NULL, &Void).await?;
+        accepted(&reply).map(|_| ())
+    }
+
+    // Mounts path, which names an export and, as "<user>'s drive", a user's drive in it
+    pub async fn mount(&self, path: &str) -> Result<nfs_fh3, NfsError> {
+        let reply = self.call(mount::PROGRAM, mount::VERSION, MOUNTPROC3_MNT, &path.as_bytes().to_vec()).await?;
+        let mut results = accepted(&reply)?;
+        let mut stat = mountstat3::MNT3_OK;
+        stat.deserialize(&mut results).map_err(NfsError::Decode)?;
+        if !matches!(stat, mountstat3::MNT3_OK) {
+            return Err(NfsError::Mount(stat));
+        }
+        let mut resok = mountres3_ok { fhandle: Vec::new(), auth_flavors: Vec::new() };
+        resok.deserialize(&mut results).map_err(NfsError::Decode)?;
+        Ok(nfs_fh3 { data: resok.fhandle })
+    }
+
+    pub async fn getattr(&self, object: &nfs_fh3) -> Result<fattr3, NfsError> {
+        self.call_nfs(NFSPROC3_GETATTR, object).await
+    }
+
+    // Sets attributes, only if the object's ctime is still guard when given
+    pub async fn setattr(
+        &self,
+        object: &nfs_fh3,
+        new_attribute: sattr3,
+        guard: Option<nfstime3>,
+    ) -> Result<wcc_data, NfsError> {
+        let guard = guard.map_or(sattrguard3::Void, sattrguard3::obj_ctime);
+        let args = SETATTR3args { object: object.clone(), new_attribute, guard };
+        self.call_nfs(NFSPROC3_SETATTR, &args).await
+    }
+
+    pub async fn lookup(&self, dir: &nfs_fh3, name: &str) -> Result<LOOKUP3resok, NfsError> {
+        self.call_nfs(NFSPROC3_LOOKUP, &dirop(dir, name)).await
+    }
+
+    // The ACCESS3_* rights of access the caller has to the object
+    pub async fn access(&self, object: &nfs_fh3, access: u32) -> Result<ACCESS3resok, NfsError> {
+        self.call_nfs(NFSPROC3_ACCESS, &ACCESS3args { object: object.clone(), access }).await
+    }
+
+    pub async fn readlink(&self, symlink: &nfs_fh3) -> Result<READLINK3resok, NfsError> {
+        self.call_nfs(NFSPROC3_READLINK, symlink).await
+    }
+
+    pub async fn read(&self, file: &nfs_fh3, offset: offset3, count: count3) -> Result<READ3resok, NfsError> {
+        self.call_nfs(NFSPROC3_READ, &READ3args { file: file.clone(), offset, count }).await
+    }
+
+    pub async fn write(
+        &self,
+        file: &nfs_fh3,
+        offset: offset3,
+        data: &[u8],
+        stable: stable_how,
+    ) -> Result<WRITE3resok, NfsError> {
+        let args = WRITE3args {
+            file: file.clone(),
+            offset,
+            count: data.len() as count3,
+            stable: stable as u32,
+            data: data.to_vec(),
+        };
+        self.call_nfs(NFSPROC3_WRITE, &args).await
+    }
+
+    pub async fn create(&self, dir: &nfs_fh3, name: &str, how: createhow3) -> Result<CREATE3resok, NfsError> {
+        self.call_nfs(NFSPROC3_CREATE, &CREATE3args { dirops: dirop(dir, name), how }).await
+    }
+
+    pub async fn mkdir(&self, dir: &nfs_fh3, name: &str, attributes: sattr3) -> Result<CREATE3resok, NfsError> {
+        self.call_nfs(NFSPROC3_MKDIR, &MKDIR3args { dirops: dirop(dir, name), attributes }).await
+    }
+
+    // Makes name a symbolic link to target
+    pub async fn symlink(
+        &self,
+        dir: &nfs_fh3,
+        name: &str,
+        target: &str,
+        attributes: sattr3,
+    ) -> Result<CREATE3resok, NfsError> {
+        let symlink = symlinkdata3 { symlink_attributes: attributes, symlink_data: target.as_bytes().into() };
+        self.call_nfs(NFSPROC3_SYMLINK, &SYMLINK3args { dirops: dirop(dir, name), symlink }).await
+    }
+
+    pub async fn mknod(&self, dir: &nfs_fh3, name: &str, what: mknoddata3) -> Result<CREATE3resok, NfsError> {
+        self.call_nfs(NFSPROC3_MKNOD, &MKNOD3args { dirops: dirop(dir, name), what }).await
+    }
+
+    // The directory's attributes either side of the removal
+    pub async fn remove(&self, dir: &nfs_fh3, name: &str) -> Result<wcc_data, NfsError> {
+        self.call_nfs(NFSPROC3_REMOVE, &dirop(dir, name)).await
+    }
+
+    pub async fn rmdir(&self, dir: &nfs_fh3, name: &str) -> Result<wcc_data, NfsError> {
+        self.call_nfs(NFSPROC3_RMDIR, &dirop(dir, name)).await
+    }
+
+    pub async fn rename(
+        &self,
+        from_dir: &nfs_fh3,
+        from_name: &str,
+        to_dir: &nfs_fh3,
+        to_name: &str,
+    ) -> Result<RENAME3resok, NfsError> {
+        let args = RENAME3args { from: dirop(from_dir, from_name), to: dirop(to_dir, to_name) };
+        self.call_nfs(NFSPROC3_RENAME, &args).await
+    }
+
+    // Makes name in dir a hard link to file
+    pub async fn link(&self, file: &nfs_fh3, dir: &nfs_fh3, name: &str) -> Result<LINK3resok, NfsError> {
+        self.call_nfs(NFSPROC3_LINK, &LINK3args { file: file.clone(), link: dirop(dir, name) }).await
+    }
+
+    // The entries after cookie, 0 for the first, with the cookieverf the last page came with
+    pub async fn readdir(
+        &self,
+        dir: &nfs_fh3,
+        cookie: cookie3,
+        cookieverf: cookieverf3,
+        count: count3,
+    ) -> Result<READDIR3resok, NfsError> {
+        let args = READDIR3args { dir: dir.clone(), cookie, cookieverf, count };
+        self.call_nfs(NFSPROC3_READDIR, &args).await
+    }
+
+    pub async fn readdirplus(
+        &self,
+        dir: &nfs_fh3,
+        cookie: cookie3,
+        cookieverf: cookieverf3,
+        dircount: count3,
+        maxcount: count3,
+    ) -> Result<READDIRPLUS3resok, NfsError> {
+        let args = READDIRPLUS3args { dir: dir.clone(), cookie, cookieverf, dircount, maxcount };
+        self.call_nfs(NFSPROC3_READDIRPLUS, &args).await
+    }
+
+    pub async fn fsstat(&self, root: &nfs_fh3) -> Result<FSSTAT3resok, NfsError> {
+        self.call_nfs(NFSPROC3_FSSTAT, root).await
+    }
+
+    pub async fn fsinfo(&self, root: &nfs_fh3) -> Result<fsinfo3, NfsError> {
+        self.call_nfs(NFSPROC3_FSINFO, root).await
+    }
+
+    pub async fn pathconf(&self, object: &nfs_fh3) -> Result<PATHCONF3resok, NfsError> {
+        self.call_nfs(NFSPROC3_PATHCONF, object).await
+    }
+
+    // Commits count bytes from offset, the whole file when 0, written UNSTABLE
+    pub async fn commit(&self, file: &nfs_fh3, offset: offset3, count: count3) -> Result<COMMIT3resok, NfsError> {
+        self.call_nfs(NFSPROC3_COMMIT, &COMMIT3args { file: file.clone(), offset, count }).await
+    }
+}
+
+fn dirop(dir: &nfs_fh3, name: &str) -> diropargs3 {
+    diropargs3 { dir: dir.clone(), name: name.as_bytes().into() }
+}
+
+const NFSPROC3_NULL: u32 = 0;
+const NFSPROC3_GETATTR: u32 = 1;
+const NFSPROC3_SETATTR: u32 = 2;
+const NFSPROC3_LOOKUP: u32 = 3;
+const NFSPROC3_ACCESS: u32 = 4;
+const NFSPROC3_READLINK: u32 = 5;
+const NFSPROC3_READ: u32 = 6;
+const NFSPROC3_WRITE: u32 = 7;
+const NFSPROC3_CREATE: u32 = 8;
+const NFSPROC3_MKDIR: u32 = 9;
+const NFSPROC3_SYMLINK: u32 = 10;
+const NFSPROC3_MKNOD: u32 = 11;
+const NFSPROC3_REMOVE: u32 = 12;
+const NFSPROC3_RMDIR: u32 = 13;
+const NFSPROC3_RENAME: u32 = 14;
+const NFSPROC3_LINK: u32 = 15;
+const NFSPROC3_READDIR: u32 = 16;
+const NFSPROC3_READDIRPLUS: u32 = 17;
+const NFSPROC3_FSSTAT: u32 = 18;
+const NFSPROC3_FSINFO: u32 = 19;
+const NFSPROC3_PATHCONF: u32 = 20;
+const NFSPROC3_COMMIT: u32 = 21;
+const MOUNTPROC3_MNT: u32 = 1;
+
+// Arguments and results of the procedures the server's handlers have no types for, from RFC 1813
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, XdrSerialize)]
+struct ACCESS3args {
+    object: nfs_fh3,
+    access: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Clone, Debug, XdrSerialize)]
+#[repr(u32)]
+pub enum createhow3 {
+    UNCHECKED(sattr3),
+    // fails with NFS3ERR_EXIST if the name is taken
+    GUARDED(sattr3),
+    // creates once however often the call is retried with the same verifier
+    EXCLUSIVE(createverf3),
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, XdrSerialize)]
+struct CREATE3args {
+    dirops: diropargs3,
+    how: createhow3,
+}
+
+// The type of node MKNOD makes, with what it is made with
+#[allow(non_camel_case_types)]
+#[derive(Clone, Debug, XdrSerialize)]
+#[repr(u32)]
+pub enum mknoddata3 {
+    NF3BLK(devicedata3) = 3,
+    NF3CHR(devicedata3) = 4,
+    NF3SOCK(sattr3) = 6,
+    NF3FIFO(sattr3) = 7,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, XdrSerialize)]
+struct MKNOD3args {
+    dirops: diropargs3,
+    what: mknoddata3,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, XdrSerialize)]
+struct RENAME3args {
+    from: diropargs3,
+    to: diropargs3,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, XdrSerialize)]
+struct LINK3args {
+    file: nfs_fh3,
+    link: diropargs3,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, XdrSerialize)]
+struct READDIR3args {
+    dir: nfs_fh3,
+    cookie: cookie3,
+    cookieverf: cookieverf3,
+    count: count3,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, XdrSerialize)]
+struct COMMIT3args {
+    file: nfs_fh3,
+    offset: offset3,
+    count: count3,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Default, XdrDeserialize)]
+pub struct LOOKUP3resok {
+    pub object: nfs_fh3,
+    pub obj_attributes: post_op_attr,
+    pub dir_attributes: post_op_attr,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Default, XdrDeserialize)]
+pub struct ACCESS3resok {
+    pub obj_attributes: post_op_attr,
+    pub access: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Default, XdrDeserialize)]
+pub struct READLINK3resok {
+    pub symlink_attributes: post_op_attr,
+    pub data: nfspath3,
+}
+
+// What CREATE, MKDIR, SYMLINK and MKNOD answer with
+#[allow(non_camel_case_types)]
+#[derive(Debug, Default, XdrDeserialize)]
+pub struct CREATE3resok {
+    pub obj: post_op_fh3,
+    pub obj_attributes: post_op_attr,
+    pub dir_wcc: wcc_data,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Default, XdrDeserialize)]
+pub struct RENAME3resok {
+    pub fromdir_wcc: wcc_data,
+    pub todir_wcc: wcc_data,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Default, XdrDeserialize)]
+pub struct LINK3resok {
+    pub file_attributes: post_op_attr,
+    pub linkdir_wcc: wcc_data,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Default, XdrDeserialize)]
+pub struct entry3 {
+    pub fileid: fileid3,
+    pub name: filename3,
+    pub cookie: cookie3,
+}
+
+// Reads the entries of a READDIR or READDIRPLUS reply, each following a TRUE
+fn deserialize_entries<T: XdrDeserialize + Default>(entries: &mut Vec<T>, src: &mut impl Read) -> io::Result<()> {
+    entries.clear();
+    let mut follows = false;
+    follows.deserialize(src)?;
+    while follows {
+        let mut entry = T::default();
+        entry.deserialize(src)?;
+        entries.push(entry);
+        follows.deserialize(src)?;
+    }
+    Ok(())
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Default)]
+pub struct READDIR3resok {
+    pub dir_attributes: post_op_attr,
+    pub cookieverf: cookieverf3,
+    pub entries: Vec<entry3>,
+    pub eof: bool,
+}
+
+impl XdrDeserialize for READDIR3resok {
+    fn deserialize<R: Read>(&mut self, src: &mut R) -> io::Result<()> {
+        self.dir_attributes.deserialize(src)?;
+        self.cookieverf.deserialize(src)?;
+        deserialize_entries(&mut self.entries, src)?;
+        self.eof.deserialize(src)
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Default)]
+pub struct READDIRPLUS3resok {
+    pub dir_attributes: post_op_attr,
+    pub cookieverf: cookieverf3,
+    pub entries: Vec<entryplus3>,
+    pub eof: bool,
+}
+
+impl XdrDeserialize for READDIRPLUS3resok {
+    fn deserialize<R: Read>(&mut self, src: &mut R) -> io::Result<()> {
+        self.dir_attributes.deserialize(src)?;
+        self.cookieverf.deserialize(src)?;
+        deserialize_entries(&mut self.entries, src)?;
+        self.eof.deserialize(src)
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Default, XdrDeserialize)]
+pub struct COMMIT3resok {
+    pub file_wcc: wcc_data,
+    pub verf: writeverf3,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::protocol::rpc::make_success_reply;
+    use crate::kernel::protocol::tcp::{NFSTcpListener, NFSTcp};
+    use crate::kernel::vfs::api::NFSFileSystem;
+    use crate::kernel::vfs::mock::MockNFSFileSystem;
+    use tokio::net::TcpListener;
+
+    // Answers two GETATTRs in the opposite order to the one they came in, each with the first
+    // byte of its handle as the fileid
+    async fn answer_backwards(listener: TcpListener) {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut replies = Vec::new();
+        for _ in 0..2 {
+            let mut record = Vec::new();
+            while !read_fragment(&mut socket, &mut record, 1 << 16).await.unwrap() {}
+            let mut call = Cursor::new(&record[..]);
+            let mut msg = rpc_msg::default();
+            msg.deserialize(&mut call).unwrap();
+            let mut handle = nfs_fh3::default();
+            handle.deserialize(&mut call).unwrap();
+            let mut reply = Vec::new();
+            make_success_reply(msg.xid).serialize(&mut reply).unwrap();
+            nfsstat3::NFS3_OK.serialize(&mut reply).unwrap();
+            fattr3 { fileid: handle.data[0] as fileid3, ..fattr3::default() }.serialize(&mut reply).unwrap();
+            replies.push(reply);
+        }
+        for reply in replies.iter().rev() {
+            write_record(&mut socket, reply, 1 << 16).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_matches_replies_to_calls_by_xid() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(answer_backwards(listener));
+
+        let client = Arc::new(NfsClient::connect(addr, Credential::default()).await.unwrap());
+        let calls: Vec<_> = [1u8, 2]
+            .into_iter()
+            .map(|byte| {
+                let client = client.clone();
+                tokio::spawn(async move { client.getattr(&nfs_fh3 { data: vec![byte] }).await })
+            })
+            .collect();
+        for (byte, call) in [1, 2].into_iter().zip(calls) {
+            assert_eq!(call.await.unwrap().unwrap().fileid, byte);
+        }
+        server.await.unwrap();
+
+        // The server has gone, and with it the connection
+        assert!(matches!(client.null().await, Err(NfsError::Io(_) | NfsError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn test_calls_a_server() {
+        let vfs = MockNFSFileSystem::new_readwrite();
+        let root = vfs.id_to_fh(vfs.root_dir());
+        let listener = NFSTcpListener::bind("127.0.0.1:0", vfs).await.unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], listener.get_listen_port()));
+        tokio::spawn(async move { listener.handle_forever().await });
+
+        let mut client = NfsClient::connect(addr, Credential::default()).await.unwrap();
+        client.set_timeout(Duration::from_secs(10));
+        client.null().await.unwrap();
+        let found = client.lookup(&root, "a").await.unwrap();
+        assert!(matches!(client.getattr(&found.object).await, Err(NfsError::Nfs(nfsstat3::NFS3ERR_NOENT))));
+        assert!(client.read(&found.object, 0, 16).await.unwrap().eof);
+        // The server has no COMMIT
+        assert!(matches!(client.commit(&found.object, 0, 0).await, Err(NfsError::ProcUnavail)));
+
+        // A closed connection is opened again by the next call
+        client.close().await;
+        client.null().await.unwrap();
+    }
+}
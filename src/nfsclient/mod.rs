@@ -6,9 +6,12 @@ pub mod readdirplus;
 pub mod read;
 pub mod access;
 pub mod auth;
+pub mod tls;
+pub mod nsm;
+pub mod client;
+pub use client::{Credential, NfsClient, NfsError};
 use std::error::Error;
-use tokio::net::TcpStream;
-use tokio::io::{AsyncWriteExt, AsyncReadExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -122,7 +125,7 @@ impl RpcReply {
     }
 }
 
-pub async fn send_rpc_message(stream: &mut TcpStream, data: &[u8]) -> Result<(), Box<dyn Error>> {
+pub async fn send_rpc_message(stream: &mut (impl AsyncWrite + Unpin), data: &[u8]) -> Result<(), Box<dyn Error>> {
     let record_marker = 0x80000000u32 | (data.len() as u32);
     
     // Send record marker
@@ -133,7 +136,7 @@ pub async fn send_rpc_message(stream: &mut TcpStream, data: &[u8]) -> Result<(),
     Ok(())
 }
 
-pub async fn receive_rpc_reply(stream: &mut TcpStream) -> Result<Vec<u8>, Box<dyn Error>> {
+pub async fn receive_rpc_reply(stream: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>, Box<dyn Error>> {
     let mut complete_response = Vec::new();
     
     loop {
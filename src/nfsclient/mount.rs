@@ -1,4 +1,4 @@
-use super::auth::AUTH_NULL;
+use super::auth::{SignedCredential, AUTH_NULL};
 use super::RpcReply;
 use std::error::Error;
 
@@ -46,7 +46,14 @@ impl MountAuth {
     }
 }
 
-pub fn build_mount_call(xid: u32, name: &str) -> Vec<u8> {
+pub enum MountCredential {
+    // AUTH_UNIX as the uid the server has registered the user with
+    Unix(u32),
+    Signed(Box<SignedCredential>),
+}
+
+// Mounts name's drive
+pub fn build_mount_call(xid: u32, name: &str, credential: &MountCredential) -> Vec<u8> {
     let path = format!("{}'s drive", name);
     let path_len = path.len() as u32;
     
@@ -59,7 +66,10 @@ pub fn build_mount_call(xid: u32, name: &str) -> Vec<u8> {
     call.extend_from_slice(&MOUNT_PROC_MNT.to_be_bytes());
     
     // Add mount-specific authentication
-    MountAuth::default().write_to_vec(&mut call);
+    match credential {
+        MountCredential::Unix(uid) => MountAuth { uid: *uid, ..MountAuth::default() }.write_to_vec(&mut call),
+        MountCredential::Signed(credential) => credential.write_to_vec(&mut call),
+    }
     
     // Path
     call.extend_from_slice(&path_len.to_be_bytes());
@@ -0,0 +1,90 @@
+// Tells a host's status monitor that this server restarted (SM_NOTIFY), so the host's lock
+// manager reclaims the locks it held. The monitor's port is asked of the host's portmapper.
+use super::auth::AUTH_NULL;
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+const PORTMAP_PROGRAM: u32 = 100000;
+const PORTMAP_VERSION: u32 = 2;
+const PORTMAP_PORT: u16 = 111;
+const IPPROTO_UDP: u32 = 17;
+const NSM_PROGRAM: u32 = 100024;
+const NSM_VERSION: u32 = 1;
+const SM_NOTIFY: u32 = 6;
+
+const ATTEMPTS: usize = 3;
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+fn call_header(xid: u32, prog: u32, vers: u32, proc: u32) -> Vec<u8> {
+    let mut call = Vec::new();
+    call.extend_from_slice(&xid.to_be_bytes());
+    call.extend_from_slice(&0u32.to_be_bytes());  // call type = 0
+    call.extend_from_slice(&2u32.to_be_bytes());  // RPC version = 2
+    call.extend_from_slice(&prog.to_be_bytes());
+    call.extend_from_slice(&vers.to_be_bytes());
+    call.extend_from_slice(&proc.to_be_bytes());
+
+    // Credential and verifier (AUTH_NULL)
+    for _ in 0..2 {
+        call.extend_from_slice(&AUTH_NULL.to_be_bytes());
+        call.extend_from_slice(&0u32.to_be_bytes());
+    }
+    call
+}
+
+pub fn build_getport_call(xid: u32, prog: u32, vers: u32, prot: u32) -> Vec<u8> {
+    let mut call = call_header(xid, PORTMAP_PROGRAM, PORTMAP_VERSION, 3);
+    for word in [prog, vers, prot, 0] {
+        call.extend_from_slice(&word.to_be_bytes());
+    }
+    call
+}
+
+pub fn build_notify_call(xid: u32, mon_name: &str, state: i32) -> Vec<u8> {
+    let mut call = call_header(xid, NSM_PROGRAM, NSM_VERSION, SM_NOTIFY);
+    call.extend_from_slice(&(mon_name.len() as u32).to_be_bytes());
+    call.extend_from_slice(mon_name.as_bytes());
+    call.resize(call.len().next_multiple_of(4), 0);
+    call.extend_from_slice(&state.to_be_bytes());
+    call
+}
+
+// Sends a call, retrying on timeouts, and returns the results of its accepted, successful reply
+async fn call(socket: &UdpSocket, to: SocketAddr, message: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let mut buf = vec![0; 1024];
+    for _ in 0..ATTEMPTS {
+        socket.send_to(message, to).await?;
+        let Ok(received) = tokio::time::timeout(TIMEOUT, socket.recv_from(&mut buf)).await else {
+            continue;
+        };
+        let (len, from) = received?;
+        // xid, REPLY, MSG_ACCEPTED, AUTH_NULL verifier and SUCCESS
+        if from != to || len < 24 || buf[..4] != message[..4] {
+            continue;
+        }
+        if buf[8..12] != [0; 4] || buf[20..24] != [0; 4] {
+            return Err(format!("{} refused the call", to).into());
+        }
+        return Ok(buf[24..len].to_vec());
+    }
+    Err(format!("no reply from {}", to).into())
+}
+
+// Notifies a host's status monitor over UDP that the server known to it as mon_name is now in state
+pub async fn notify(host: IpAddr, mon_name: &str, state: i32) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let wildcard: SocketAddr = match host {
+        IpAddr::V4(_) => "0.0.0.0:0".parse()?,
+        IpAddr::V6(_) => "[::]:0".parse()?,
+    };
+    let socket = UdpSocket::bind(wildcard).await?;
+    let xid = rand::random::<u32>();
+    let reply = call(&socket, (host, PORTMAP_PORT).into(), &build_getport_call(xid, NSM_PROGRAM, NSM_VERSION, IPPROTO_UDP)).await?;
+    let port = reply.get(..4).map(|port| u32::from_be_bytes(port.try_into().unwrap())).unwrap_or(0);
+    if port == 0 || port > u16::MAX as u32 {
+        return Err(format!("{} has no status monitor", host).into());
+    }
+    call(&socket, (host, port as u16).into(), &build_notify_call(xid.wrapping_add(1), mon_name, state)).await?;
+    Ok(())
+}
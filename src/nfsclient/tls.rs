@@ -0,0 +1,114 @@
+// RPC-over-TLS (RFC 9289): a NULL call with an AUTH_TLS credential asks the server to upgrade
+// the connection, and the TLS 1.3 handshake follows its STARTTLS reply
+use super::auth::{AUTH_NULL, AUTH_TLS};
+use super::{receive_rpc_reply, send_rpc_message};
+use crate::kernel::protocol::tls::{load_certs, load_key, load_roots, ALPN_SUNRPC};
+use std::error::Error;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{self, ClientConfig};
+use tokio_rustls::TlsConnector;
+
+const NFS_PROGRAM: u32 = 100003;
+const NFS_VERSION: u32 = 3;
+
+// A connection to the server, upgraded to TLS or not
+#[derive(Debug)]
+pub enum NfsStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for NfsStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            NfsStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            NfsStream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for NfsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            NfsStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            NfsStream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            NfsStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            NfsStream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            NfsStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            NfsStream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+// Trusts servers with a certificate issued by the CA of ca_path and, given a certificate and
+// key, presents them as the client's, which the server maps to the user of their common name
+pub fn client_config(ca_path: &str, client_cert: Option<(&str, &str)>) -> Result<Arc<ClientConfig>, Box<dyn Error>> {
+    let builder = ClientConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
+        .with_root_certificates(load_roots(ca_path)?);
+    let mut config = match client_cert {
+        Some((cert_path, key_path)) => builder.with_client_auth_cert(load_certs(cert_path)?, load_key(key_path)?)?,
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = vec![ALPN_SUNRPC.to_vec()];
+    Ok(Arc::new(config))
+}
+
+pub fn build_tls_probe_call(xid: u32) -> Vec<u8> {
+    let mut call = Vec::new();
+    call.extend_from_slice(&xid.to_be_bytes());
+    call.extend_from_slice(&0u32.to_be_bytes());  // call type = 0
+    call.extend_from_slice(&2u32.to_be_bytes());  // RPC version = 2
+    call.extend_from_slice(&NFS_PROGRAM.to_be_bytes());
+    call.extend_from_slice(&NFS_VERSION.to_be_bytes());
+    call.extend_from_slice(&0u32.to_be_bytes());  // procedure = 0 (NULL)
+
+    // Credential (AUTH_TLS, empty)
+    call.extend_from_slice(&AUTH_TLS.to_be_bytes());
+    call.extend_from_slice(&0u32.to_be_bytes());
+
+    // Verifier (AUTH_NULL)
+    call.extend_from_slice(&AUTH_NULL.to_be_bytes());
+    call.extend_from_slice(&0u32.to_be_bytes());
+    call
+}
+
+// Whether a reply accepts the probe: MSG_ACCEPTED with a "STARTTLS" verifier and SUCCESS
+fn is_starttls_reply(reply: &[u8]) -> bool {
+    let word = |index: usize| reply.get(index * 4..index * 4 + 4).map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()));
+    word(1) == Some(1) && word(2) == Some(0) && word(4) == Some(8)
+        && reply.get(20..28) == Some(b"STARTTLS".as_slice()) && word(7) == Some(0)
+}
+
+// Probes the server on a fresh connection and runs the handshake, verifying the server's
+// certificate is for server_name, a DNS name or IP address
+pub async fn start_tls(
+    mut stream: TcpStream,
+    server_name: &str,
+    config: Arc<ClientConfig>,
+) -> Result<NfsStream, Box<dyn Error>> {
+    let server_name = ServerName::try_from(server_name.to_string())?;
+    send_rpc_message(&mut stream, &build_tls_probe_call(0)).await?;
+    let reply = receive_rpc_reply(&mut stream).await?;
+    if !is_starttls_reply(&reply) {
+        return Err("the server does not offer RPC-over-TLS".into());
+    }
+    let stream = TlsConnector::from(config).connect(server_name, stream).await?;
+    Ok(NfsStream::Tls(Box::new(stream)))
+}
@@ -128,27 +128,27 @@ impl SecretSharingService {
     
         let recovered_chunks: Vec<Vec<u8>> = self.pool.install(|| {
             shares.par_iter().map(|chunk_map| {
-                if let Some(chunk_shares) = chunk_map.get("shares") {
-                    if chunk_shares.len() < self.settings.threshold {
-                        return Vec::new(); // We expect at least threshold number of shares
-                    }
-                    let indices_and_shares: Vec<(usize, BigInt)> = chunk_shares
-                        .iter()
-                        .enumerate()
-                        .map(|(index, share)| {
-                            let share_bigint = BigInt::parse_bytes(share.as_bytes(), 10).expect("Invalid share format");
-                            (index + 1, share_bigint) // Indices are assumed to start from 1
-                        })
-                        .collect();
-    
-                    //let recovered_secret = self.sss.recover(&indices_and_shares[0..self.sss.threshold as usize]);
-                    let recovered_secret = self.sss.recover(&indices_and_shares[0..self.settings.threshold]);
-                    recovered_secret.to_bytes_be().1
-                } else {
-                    Vec::new()
+                let chunk_shares = chunk_map.get("shares")
+                    .ok_or_else(|| anyhow::anyhow!("chunk without shares"))?;
+                if chunk_shares.len() < self.settings.threshold {
+                    // We expect at least threshold number of shares
+                    return Err(anyhow::anyhow!("chunk has {} shares, {} needed", chunk_shares.len(), self.settings.threshold));
                 }
-            }).collect()
-        });
+                let indices_and_shares: Vec<(usize, BigInt)> = chunk_shares
+                    .iter()
+                    .enumerate()
+                    .map(|(index, share)| {
+                        let share_bigint = BigInt::parse_bytes(share.as_bytes(), 10)
+                            .ok_or_else(|| anyhow::anyhow!("invalid share format"))?;
+                        Ok((index + 1, share_bigint)) // Indices are assumed to start from 1
+                    })
+                    .collect::<Result<_, anyhow::Error>>()?;
+
+                //let recovered_secret = self.sss.recover(&indices_and_shares[0..self.sss.threshold as usize]);
+                let recovered_secret = self.sss.recover(&indices_and_shares[0..self.settings.threshold]);
+                Ok(recovered_secret.to_bytes_be().1)
+            }).collect::<Result<_, anyhow::Error>>()
+        })?;
     
         
         let recovered_chunks_flattened: Vec<u8> = recovered_chunks.into_iter().flatten().collect();
@@ -0,0 +1,149 @@
+// Changes to the same inodes, one at a time.
+//
+// A client answered with weak cache consistency data compares the attributes a file or
+// directory had before its change with those it cached: when they match, it keeps its cache
+// and takes the attributes after the change as its own. That is only sound when nothing else
+// changed the inode between the two reads, so every change SharesFS makes holds the inodes it
+// touches (the file written or set, the directories entries are made in or removed from, the
+// inodes removed or renamed) for as long as it runs, and the attributes are read while they are
+// held.
+use std::ffi::OsStr;
+use std::future::Future;
+use std::os::unix::ffi::OsStrExt;
+
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::kernel::api::nfs::{fileid3, filename3, nfsstat3, wcc_data};
+use crate::sharesfs::SharesFS;
+
+const STRIPES: usize = 64;
+
+// The inodes of a tenant changes are holding. Every export of the tenant shares them, as its
+// exports may overlap; inodes share a lock when their ids fall in the same stripe.
+pub struct HeldInodes {
+    stripes: [Mutex<()>; STRIPES],
+}
+
+impl Default for HeldInodes {
+    fn default() -> Self {
+        HeldInodes { stripes: std::array::from_fn(|_| Mutex::new(())) }
+    }
+}
+
+impl HeldInodes {
+    // Stripes are always taken in order, so two changes never deadlock
+    pub(super) async fn hold(&self, ids: &[fileid3]) -> Vec<MutexGuard<'_, ()>> {
+        let mut stripes: Vec<usize> = ids.iter().map(|id| (id % STRIPES as u64) as usize).collect();
+        stripes.sort_unstable();
+        stripes.dedup();
+        let mut held = Vec::with_capacity(stripes.len());
+        for stripe in stripes {
+            held.push(self.stripes[stripe].lock().await);
+        }
+        held
+    }
+}
+
+impl SharesFS {
+    async fn resolve_ids<const N: usize>(&self, ids: [fileid3; N]) -> Result<[fileid3; N], nfsstat3> {
+        let mut resolved = ids;
+        for id in resolved.iter_mut() {
+            *id = self.resolve_id(*id).await?;
+        }
+        Ok(resolved)
+    }
+
+    async fn resolve_entries<'a>(&self, entries: &[(fileid3, &'a filename3)]) -> Result<Vec<(fileid3, &'a filename3)>, nfsstat3> {
+        let mut resolved = Vec::with_capacity(entries.len());
+        for (dirid, name) in entries {
+            resolved.push((self.resolve_id(*dirid).await?, *name));
+        }
+        Ok(resolved)
+    }
+
+    // The inodes the entries link to, those that exist
+    async fn entry_ids(&self, entries: &[(fileid3, &filename3)]) -> Vec<fileid3> {
+        let mut ids = Vec::with_capacity(entries.len());
+        for (dirid, name) in entries {
+            let name = OsStr::from_bytes(name).to_str().unwrap_or("");
+            if let Ok(Some(id)) = self.get_child(*dirid, name).await {
+                ids.push(id);
+            }
+        }
+        ids
+    }
+
+    // Holds ids and the inodes entries, names in some of those directories, link to. The names
+    // are looked up again once held, and held anew if a change relinked them meanwhile.
+    async fn hold(&self, ids: &[fileid3], entries: &[(fileid3, &filename3)]) -> Vec<MutexGuard<'_, ()>> {
+        let mut linked = self.entry_ids(entries).await;
+        loop {
+            let held = self.held.hold(&[ids, &linked].concat()).await;
+            let relinked = self.entry_ids(entries).await;
+            if relinked == linked {
+                return held;
+            }
+            linked = relinked;
+        }
+    }
+
+    // Runs change holding ids
+    pub(super) async fn changing<T, const N: usize>(
+        &self,
+        ids: [fileid3; N],
+        change: impl Future<Output = Result<T, nfsstat3>>,
+    ) -> Result<T, nfsstat3> {
+        self.changing_entries(ids, &[], change).await
+    }
+
+    // Runs change holding ids and the inodes of entries, the names it removes or renames in
+    // directories among ids
+    pub(super) async fn changing_entries<T, const N: usize>(
+        &self,
+        ids: [fileid3; N],
+        entries: &[(fileid3, &filename3)],
+        change: impl Future<Output = Result<T, nfsstat3>>,
+    ) -> Result<T, nfsstat3> {
+        let ids = self.resolve_ids(ids).await?;
+        let entries = self.resolve_entries(entries).await?;
+        let _held = self.hold(&ids, &entries).await;
+        change.await
+    }
+
+    // Runs change holding ids, with the weak cache consistency data of each
+    pub(super) async fn changing_wcc<T, const N: usize>(
+        &self,
+        ids: [fileid3; N],
+        change: impl Future<Output = Result<T, nfsstat3>>,
+    ) -> (Result<T, nfsstat3>, [wcc_data; N]) {
+        self.changing_entries_wcc(ids, &[], change).await
+    }
+
+    // changing_entries, with the weak cache consistency data of each of ids
+    pub(super) async fn changing_entries_wcc<T, const N: usize>(
+        &self,
+        ids: [fileid3; N],
+        entries: &[(fileid3, &filename3)],
+        change: impl Future<Output = Result<T, nfsstat3>>,
+    ) -> (Result<T, nfsstat3>, [wcc_data; N]) {
+        let resolved = match self.resolve_ids(ids).await {
+            Ok(ids) => self.resolve_entries(entries).await.map(|entries| (ids, entries)),
+            Err(stat) => Err(stat),
+        };
+        let (ids, entries) = match resolved {
+            Ok(resolved) => resolved,
+            Err(stat) => return (Err(stat), [wcc_data::default(); N]),
+        };
+        let _held = self.hold(&ids, &entries).await;
+        let mut before = [None; N];
+        for (attr, id) in before.iter_mut().zip(ids) {
+            *attr = self.get_attribute(id).await.ok();
+        }
+        let res = change.await;
+        let mut wcc = [wcc_data::default(); N];
+        for ((wcc, before), id) in wcc.iter_mut().zip(before).zip(ids) {
+            *wcc = wcc_data::new(before, self.get_attribute(id).await.ok());
+        }
+        (res, wcc)
+    }
+}
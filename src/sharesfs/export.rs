@@ -0,0 +1,295 @@
+// The directories each tenant exports, configured as [[exports]] in settings.toml:
+//
+//   [[exports]]
+//   path = "/projects"
+//   tenant = "zoo/aqautics"          # community/namespace_id; the first tenant when absent
+//   clients = ["10.1.0.0/16"]        # networks allowed to mount; any client when absent
+//   read_only = false
+//   read_only_users = ["reviewer"]   # served read-only even when the export is not
+//   root_squash = true               # superusers mount their own directory, not all of the export
+//   auth = "signed"                  # only serve AUTH_SIGNED calls; "unix" (the default) also
+//                                    # takes AUTH_UNIX and AUTH_NULL ones
+//   audit = "default"                # or "none" to leave this export unaudited
+//   store = "default"                # or "rocksdb:<path>", "sqlite:<path>", "redis"
+//
+// Without an [[exports]] section every tenant exports "/" to any client, read-write, as before.
+// An export with a store of its own keeps the tenant's namespace in that store.
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+
+use config::{Config, ConfigError};
+use serde::Deserialize;
+
+use crate::audit_adapters::irrefutable_audit::IrrefutableAudit;
+use crate::backingstore::data_store::DataStore;
+use crate::kernel::vfs::exports::ClientNetwork;
+use crate::sharesfs::tenant::TenantConfig;
+use crate::sharesfs::SharesFS;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum StoreSpec {
+    // The store the server was built for, as set up in [storage]
+    Default,
+    RocksDB(String),
+    Sqlite(String),
+    Redis,
+}
+
+impl StoreSpec {
+    pub fn parse(spec: &str) -> Result<StoreSpec, ExportConfigError> {
+        let path = |path: &str| {
+            if path.is_empty() {
+                Err(ExportConfigError::Invalid(format!("store {:?} needs a path", spec)))
+            } else {
+                Ok(path.to_string())
+            }
+        };
+        match spec.split_once(':') {
+            None if spec == "default" => Ok(StoreSpec::Default),
+            None if spec == "redis" => Ok(StoreSpec::Redis),
+            Some(("rocksdb", rest)) => Ok(StoreSpec::RocksDB(path(rest)?)),
+            Some(("sqlite", rest)) => Ok(StoreSpec::Sqlite(path(rest)?)),
+            _ => Err(ExportConfigError::Invalid(format!("unknown store {:?}", spec))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AuditBackend {
+    // The audit the server was built with
+    Default,
+    None,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportConfig {
+    // Index of the exporting tenant in the tenant list
+    pub tenant: usize,
+    pub path: String,
+    pub clients: Vec<ClientNetwork>,
+    pub read_only: bool,
+    pub read_only_users: Vec<String>,
+    pub root_squash: bool,
+    pub require_signed_auth: bool,
+    pub require_tls: bool,
+    pub audit: AuditBackend,
+    pub store: StoreSpec,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportSettings {
+    path: String,
+    #[serde(default)]
+    tenant: Option<String>,
+    #[serde(default)]
+    clients: Vec<String>,
+    #[serde(default)]
+    read_only: bool,
+    #[serde(default)]
+    read_only_users: Vec<String>,
+    #[serde(default)]
+    root_squash: bool,
+    #[serde(default)]
+    auth: Option<String>,
+    #[serde(default)]
+    tls: bool,
+    #[serde(default)]
+    audit: Option<String>,
+    #[serde(default)]
+    store: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ExportConfigError {
+    UnknownTenant(String),
+    Invalid(String),
+}
+
+impl fmt::Display for ExportConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExportConfigError::UnknownTenant(tenant) => write!(f, "Export of unknown tenant: {}", tenant),
+            ExportConfigError::Invalid(msg) => write!(f, "Invalid export settings: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ExportConfigError {}
+
+impl ExportConfig {
+    // All of a tenant, to any client
+    pub fn whole_tenant(tenant: usize) -> ExportConfig {
+        ExportConfig {
+            tenant,
+            path: "/".to_string(),
+            clients: Vec::new(),
+            read_only: false,
+            read_only_users: Vec::new(),
+            root_squash: false,
+            require_signed_auth: false,
+            require_tls: false,
+            audit: AuditBackend::Default,
+            store: StoreSpec::Default,
+        }
+    }
+
+    // A SharesFS serving this export as the export_index'th of its tenant's listener
+    pub fn filesystem(
+        &self,
+        tenant: &TenantConfig,
+        data_store: Arc<dyn DataStore>,
+        irrefutable_audit: Arc<dyn IrrefutableAudit>,
+        export_index: u32,
+    ) -> SharesFS {
+        let mut shares_fs = tenant.filesystem(data_store, irrefutable_audit);
+        shares_fs.export_index = export_index;
+        shares_fs.read_only = self.read_only;
+        shares_fs.export_path = self.path.clone();
+        shares_fs
+    }
+}
+
+// "/a/b/" and "a//b" are both "/a/b"; a drive component would be taken for a user's
+fn normalise_path(path: &str) -> Result<String, ExportConfigError> {
+    let components: Vec<&str> = path.split('/').filter(|component| !component.is_empty()).collect();
+    if !path.starts_with('/') || components.iter().any(|component| *component == "." || *component == ".." || component.ends_with("'s drive")) {
+        return Err(ExportConfigError::Invalid(format!("{:?} is not an absolute directory path", path)));
+    }
+    Ok(format!("/{}", components.join("/")))
+}
+
+// The configured exports, in the order each tenant's listener numbers them
+pub fn exports_from_settings(settings: &Config, tenants: &[TenantConfig]) -> Result<Vec<ExportConfig>, ExportConfigError> {
+    let raw = match settings.get::<Vec<ExportSettings>>("exports") {
+        Ok(raw) => raw,
+        Err(ConfigError::NotFound(_)) => return Ok((0..tenants.len()).map(ExportConfig::whole_tenant).collect()),
+        Err(e) => return Err(ExportConfigError::Invalid(e.to_string())),
+    };
+
+    let mut exports = Vec::new();
+    let mut seen = HashSet::new();
+    for export in raw {
+        let tenant = match &export.tenant {
+            None => 0,
+            Some(name) => tenants.iter()
+                .position(|tenant| format!("{}/{}", tenant.community, tenant.namespace_id) == *name)
+                .ok_or_else(|| ExportConfigError::UnknownTenant(name.clone()))?,
+        };
+        let path = normalise_path(&export.path)?;
+        if !seen.insert((tenant, path.clone())) {
+            return Err(ExportConfigError::Invalid(format!("{} is exported twice by {:?}", path, tenants[tenant].namespace())));
+        }
+        let clients = export.clients.iter()
+            .map(|spec| ClientNetwork::parse(spec).map_err(ExportConfigError::Invalid))
+            .collect::<Result<Vec<_>, _>>()?;
+        let audit = match export.audit.as_deref() {
+            None | Some("default") => AuditBackend::Default,
+            Some("none") => AuditBackend::None,
+            Some(other) => return Err(ExportConfigError::Invalid(format!("unknown audit {:?}", other))),
+        };
+        let require_signed_auth = match export.auth.as_deref() {
+            None | Some("unix") => false,
+            Some("signed") => true,
+            Some(other) => return Err(ExportConfigError::Invalid(format!("unknown auth {:?}", other))),
+        };
+        let store = match export.store.as_deref() {
+            None => StoreSpec::Default,
+            Some(spec) => StoreSpec::parse(spec)?,
+        };
+        exports.push(ExportConfig {
+            tenant,
+            path,
+            clients,
+            read_only: export.read_only,
+            read_only_users: export.read_only_users,
+            root_squash: export.root_squash,
+            require_signed_auth,
+            require_tls: export.tls,
+            audit,
+            store,
+        });
+    }
+    for (index, tenant) in tenants.iter().enumerate() {
+        if !exports.iter().any(|export| export.tenant == index) {
+            return Err(ExportConfigError::Invalid(format!("{:?} has no export", tenant.namespace())));
+        }
+    }
+    Ok(exports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{File, FileFormat};
+
+    fn settings(toml: &str) -> Config {
+        let mut settings = Config::default();
+        settings.merge(File::from_str(toml, FileFormat::Toml)).unwrap();
+        settings
+    }
+
+    fn tenants() -> Vec<TenantConfig> {
+        vec![
+            TenantConfig { community: "zoo".into(), namespace_id: "aqautics".into(), port: 2049, users: None },
+            TenantConfig { community: "farm".into(), namespace_id: "barn".into(), port: 2050, users: None },
+        ]
+    }
+
+    #[test]
+    fn test_every_tenant_exports_its_root_by_default() {
+        let exports = exports_from_settings(&settings("[storage]\ncommunity = \"zoo\"\n"), &tenants()).unwrap();
+        assert_eq!(exports, vec![ExportConfig::whole_tenant(0), ExportConfig::whole_tenant(1)]);
+    }
+
+    #[test]
+    fn test_reads_exports() {
+        let exports = exports_from_settings(&settings(r#"
+            [[exports]]
+            path = "/"
+
+            [[exports]]
+            path = "/projects/"
+            clients = ["10.1.0.0/16", "127.0.0.1"]
+            read_only = true
+            read_only_users = ["reviewer"]
+            root_squash = true
+            auth = "signed"
+            tls = true
+            audit = "none"
+            store = "sqlite:../SQLiteDBs/projects.db"
+
+            [[exports]]
+            path = "/"
+            tenant = "farm/barn"
+            store = "rocksdb:../RocksDBs/barn"
+        "#), &tenants()).unwrap();
+        assert_eq!(exports.len(), 3);
+        assert_eq!(exports[0], ExportConfig::whole_tenant(0));
+        assert_eq!(exports[1].path, "/projects");
+        assert_eq!(exports[1].clients, vec![ClientNetwork::parse("10.1.0.0/16").unwrap(), ClientNetwork::parse("127.0.0.1/32").unwrap()]);
+        assert!(exports[1].read_only && exports[1].root_squash && exports[1].require_signed_auth && exports[1].require_tls);
+        assert_eq!(exports[1].read_only_users, vec!["reviewer".to_string()]);
+        assert_eq!(exports[1].audit, AuditBackend::None);
+        assert_eq!(exports[1].store, StoreSpec::Sqlite("../SQLiteDBs/projects.db".to_string()));
+        assert_eq!(exports[2].tenant, 1);
+        assert_eq!(exports[2].store, StoreSpec::RocksDB("../RocksDBs/barn".to_string()));
+    }
+
+    #[test]
+    fn test_rejects_bad_exports() {
+        let rejected = |toml: &str| exports_from_settings(&settings(toml), &tenants()).is_err();
+        let farm = "\n[[exports]]\npath = \"/\"\ntenant = \"farm/barn\"\n";
+        assert!(!rejected(&format!("[[exports]]\npath = \"/\"\n{}", farm)));
+        assert!(rejected("[[exports]]\npath = \"/\"\n"));
+        assert!(rejected(&format!("[[exports]]\npath = \"/\"\ntenant = \"zoo/reptiles\"\n{}", farm)));
+        assert!(rejected(&format!("[[exports]]\npath = \"projects\"\n{}", farm)));
+        assert!(rejected(&format!("[[exports]]\npath = \"/alice's drive\"\n{}", farm)));
+        assert!(rejected(&format!("[[exports]]\npath = \"/a\"\n[[exports]]\npath = \"/a/\"\n{}", farm)));
+        assert!(rejected(&format!("[[exports]]\npath = \"/\"\nclients = [\"lan\"]\n{}", farm)));
+        assert!(rejected(&format!("[[exports]]\npath = \"/\"\naudit = \"blockchain\"\n{}", farm)));
+        assert!(rejected(&format!("[[exports]]\npath = \"/\"\nauth = \"kerberos\"\n{}", farm)));
+        assert!(rejected(&format!("[[exports]]\npath = \"/\"\nstore = \"rocksdb:\"\n{}", farm)));
+        assert!(rejected(&format!("[[exports]]\npath = \"/\"\nstore = \"postgres\"\n{}", farm)));
+    }
+}
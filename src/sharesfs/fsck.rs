@@ -0,0 +1,523 @@
+// Offline consistency check of a SharesFS namespace.
+//
+// A namespace is a root key, one hash per inode, one entries hash per directory, the
+// _next_fileid counter and the _usage hash (see namespace.rs), and the server updates them one
+// at a time, so a
+// failure part way through an operation leaves them out of step. The checker reads all of them,
+// reports every inconsistency it finds and, when asked, repairs those that have an unambiguous
+// fix. Content that cannot be reassembled is reported but never touched.
+//
+// Run it with the server stopped; a concurrent write looks exactly like a half-finished one.
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use serde::Serialize;
+use tracing::debug;
+
+use crate::backingstore::data_store::{DataStore, DataStoreError, DataStoreResult};
+use crate::kernel::api::nfs::fileid3;
+use crate::secret_sharing::SecretSharingService;
+use crate::sharesfs::namespace::{self, Namespace};
+use crate::sharesfs::quota::Ledger;
+
+// A directory entry: dir's entries map name to an inode
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct Link {
+    pub dir: fileid3,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Issue {
+    // Paths still in the path-keyed layout; repaired by upgrading them
+    LegacyLayout { paths: usize },
+    // Inodes exist but the root key is missing; candidate is the only inode that is its own parent
+    MissingRoot { candidate: Option<fileid3> },
+    // An entry naming an inode that does not exist
+    DanglingEntry { dir: fileid3, name: String, id: fileid3 },
+    // Entries of a directory whose inode does not exist
+    OrphanedEntries { dir: fileid3, entries: usize },
+    // An inode named by more than one entry; all but kept are removed
+    ExtraLinks { id: fileid3, kept: Option<Link>, extra: Vec<Link> },
+    // The inode's parent and name disagree with the entry that links it
+    WrongBackPointer { id: fileid3, parent: Option<fileid3>, name: Option<String>, linked: Link },
+    // An inode no entry names; relinked where it says it belongs, or into the root
+    Unlinked { id: fileid3, parent: Option<fileid3>, name: Option<String> },
+    // Linked, but in a cycle that never reaches the root
+    Unreachable { id: fileid3 },
+    // The inode's fileid field disagrees with its key
+    FileidMismatch { id: fileid3, recorded: Option<String> },
+    // The counter would hand out an id that is already in use
+    NextFileidTooLow { next_fileid: u64, max_id: u64 },
+    // The stored shares do not reassemble into file content
+    UnreadableContent { id: fileid3, path: Option<String>, reason: String },
+    // The usage kept for quotas is not what the inodes add up to; repaired by recounting
+    UsageMismatch { recorded: Ledger, counted: Ledger },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Finding {
+    #[serde(flatten)]
+    pub issue: Issue,
+    pub repaired: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FsckReport {
+    pub community: String,
+    pub namespace_id: String,
+    pub inodes: usize,
+    pub content_checked: usize,
+    pub findings: Vec<Finding>,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+
+    pub fn unrepaired(&self) -> usize {
+        self.findings.iter().filter(|finding| !finding.repaired).count()
+    }
+}
+
+struct Snapshot {
+    root: Option<fileid3>,
+    inodes: BTreeMap<fileid3, HashMap<String, String>>,
+    // Directory -> name -> id, for every non-empty entries hash
+    entries: BTreeMap<fileid3, BTreeMap<String, fileid3>>,
+    next_fileid: u64,
+    usage: Ledger,
+}
+
+impl Snapshot {
+    fn parent_of(&self, id: fileid3) -> (Option<fileid3>, Option<String>) {
+        let fields = self.inodes.get(&id);
+        let parent = fields.and_then(|fields| fields.get("parent")).and_then(|parent| parent.parse().ok());
+        let name = fields.and_then(|fields| fields.get("name")).cloned();
+        (parent, name)
+    }
+
+    // What the inodes use: every inode but the root is a file, regular files count their size,
+    // and each is charged to its owner as well
+    fn usage(&self) -> Ledger {
+        let mut ledger = Ledger::default();
+        for (id, fields) in &self.inodes {
+            if self.parent_of(*id).0 == Some(*id) {
+                continue;
+            }
+            let bytes = match fields.get("ftype") {
+                Some(ftype) if ftype == "1" => fields.get("size").and_then(|size| size.parse().ok()).unwrap_or(0),
+                _ => 0,
+            };
+            let owner = fields.get("owner").filter(|owner| !owner.is_empty());
+            for usage in std::iter::once(&mut ledger.namespace).chain(owner.map(|owner| ledger.users.entry(owner.clone()).or_default())) {
+                usage.bytes += bytes;
+                usage.files += 1;
+            }
+        }
+        ledger
+    }
+
+    fn is_directory(&self, id: fileid3) -> bool {
+        self.inodes.get(&id).and_then(|fields| fields.get("ftype")).is_some_and(|ftype| ftype == "0")
+    }
+
+    fn path_of(&self, id: fileid3) -> Option<String> {
+        let root = self.root?;
+        let mut names = Vec::new();
+        let mut current = id;
+        while current != root {
+            let (parent, name) = self.parent_of(current);
+            names.push(name?);
+            current = parent.filter(|parent| *parent != current)?;
+            if names.len() > self.inodes.len() {
+                return None;
+            }
+        }
+        names.reverse();
+        Some(format!("/{}", names.join("/")))
+    }
+}
+
+async fn ids_of_kind(store: &dyn DataStore, namespace: &Namespace, kind: &str) -> DataStoreResult<BTreeSet<fileid3>> {
+    Ok(store.keys(&namespace.key_pattern(kind)).await?
+        .iter()
+        .filter_map(|key| namespace.id_from_key(kind, key))
+        .collect())
+}
+
+async fn snapshot(store: &dyn DataStore, namespace: &Namespace) -> DataStoreResult<Snapshot> {
+    let root = namespace::root_id(store, namespace).await?;
+
+    let mut inodes = BTreeMap::new();
+    for id in ids_of_kind(store, namespace, "inode").await? {
+        let fields: HashMap<String, String> = store.hgetall(&namespace.inode_key(id)).await?.into_iter().collect();
+        if !fields.is_empty() {
+            inodes.insert(id, fields);
+        }
+    }
+
+    let mut entries = BTreeMap::new();
+    for dir in ids_of_kind(store, namespace, "entries").await? {
+        let mut names = BTreeMap::new();
+        for (name, id) in store.hgetall(&namespace.entries_key(dir)).await? {
+            // An id that does not parse names no inode, so is reported as dangling
+            names.insert(name, id.parse().unwrap_or(fileid3::MAX));
+        }
+        if !names.is_empty() {
+            entries.insert(dir, names);
+        }
+    }
+
+    let next_fileid = match store.get(&namespace.next_fileid_key()).await {
+        Ok(value) => value.parse::<u64>().unwrap_or(0),
+        Err(DataStoreError::KeyNotFound) => 0,
+        Err(e) => return Err(e),
+    };
+
+    let usage = Ledger::read(store, namespace).await?;
+
+    Ok(Snapshot { root, inodes, entries, next_fileid, usage })
+}
+
+fn find_issues(snapshot: &Snapshot) -> Vec<Issue> {
+    let mut issues = Vec::new();
+
+    if snapshot.root.is_none() && !snapshot.inodes.is_empty() {
+        let roots: Vec<fileid3> = snapshot.inodes.keys()
+            .copied()
+            .filter(|id| snapshot.parent_of(*id).0 == Some(*id))
+            .collect();
+        let candidate = if roots.len() == 1 { Some(roots[0]) } else { None };
+        issues.push(Issue::MissingRoot { candidate });
+    }
+
+    // Only entries of existing inodes link anything
+    let mut links: BTreeMap<fileid3, Vec<Link>> = BTreeMap::new();
+    for (dir, names) in &snapshot.entries {
+        if !snapshot.inodes.contains_key(dir) {
+            issues.push(Issue::OrphanedEntries { dir: *dir, entries: names.len() });
+            continue;
+        }
+        for (name, id) in names {
+            if snapshot.inodes.contains_key(id) {
+                links.entry(*id).or_default().push(Link { dir: *dir, name: name.clone() });
+            } else {
+                issues.push(Issue::DanglingEntry { dir: *dir, name: name.clone(), id: *id });
+            }
+        }
+    }
+
+    let mut kept_links: BTreeMap<fileid3, Link> = BTreeMap::new();
+    let mut unlinked = Vec::new();
+    for (id, fields) in &snapshot.inodes {
+        if fields.get("fileid") != Some(&id.to_string()) {
+            issues.push(Issue::FileidMismatch { id: *id, recorded: fields.get("fileid").cloned() });
+        }
+
+        let (parent, name) = snapshot.parent_of(*id);
+        let mut linked = links.remove(id).unwrap_or_default();
+        if Some(*id) == snapshot.root {
+            // The root is linked by the root key alone and is its own parent
+            if !linked.is_empty() {
+                issues.push(Issue::ExtraLinks { id: *id, kept: None, extra: linked });
+            }
+            if parent != Some(*id) || name.as_deref() != Some("") {
+                issues.push(Issue::WrongBackPointer { id: *id, parent, name, linked: Link { dir: *id, name: String::new() } });
+            }
+            continue;
+        }
+        if linked.is_empty() {
+            unlinked.push(*id);
+            issues.push(Issue::Unlinked { id: *id, parent, name });
+            continue;
+        }
+
+        // The entry the back-pointer names wins; otherwise the first
+        let kept_at = linked.iter()
+            .position(|link| Some(link.dir) == parent && Some(&link.name) == name.as_ref())
+            .unwrap_or(0);
+        let kept = linked.remove(kept_at);
+        if Some(kept.dir) != parent || Some(&kept.name) != name.as_ref() {
+            issues.push(Issue::WrongBackPointer { id: *id, parent, name, linked: kept.clone() });
+        }
+        if !linked.is_empty() {
+            issues.push(Issue::ExtraLinks { id: *id, kept: Some(kept.clone()), extra: linked });
+        }
+        kept_links.insert(*id, kept);
+    }
+
+    // Whatever the root and the relinked inodes do not reach is cut off in a cycle
+    let mut children: BTreeMap<fileid3, Vec<fileid3>> = BTreeMap::new();
+    for (id, link) in &kept_links {
+        children.entry(link.dir).or_default().push(*id);
+    }
+    let mut reached: BTreeSet<fileid3> = BTreeSet::new();
+    let mut queue: VecDeque<fileid3> = snapshot.root.into_iter().chain(unlinked).collect();
+    while let Some(id) = queue.pop_front() {
+        if reached.insert(id) {
+            queue.extend(children.get(&id).into_iter().flatten().copied());
+        }
+    }
+    if snapshot.root.is_some() {
+        for id in kept_links.keys().filter(|id| !reached.contains(id)) {
+            issues.push(Issue::Unreachable { id: *id });
+        }
+    }
+
+    let max_id = snapshot.inodes.keys().copied().max().unwrap_or(0);
+    if snapshot.next_fileid < max_id {
+        issues.push(Issue::NextFileidTooLow { next_fileid: snapshot.next_fileid, max_id });
+    }
+
+    let counted = snapshot.usage();
+    if counted != snapshot.usage {
+        issues.push(Issue::UsageMismatch { recorded: snapshot.usage.clone(), counted });
+    }
+    issues
+}
+
+async fn check_content(snapshot: &Snapshot, secret_sharing: &SecretSharingService) -> (usize, Vec<Issue>) {
+    let mut checked = 0;
+    let mut issues = Vec::new();
+    for (id, fields) in &snapshot.inodes {
+        let Some(shares) = fields.get("data") else {
+            continue;
+        };
+        if shares.is_empty() {
+            continue;
+        }
+        checked += 1;
+        let reason = match secret_sharing.reassemble(shares).await {
+            Ok(content) => STANDARD.decode(&content).err().map(|e| format!("reassembled content is not base64: {}", e)),
+            Err(e) => Some(e),
+        };
+        if let Some(reason) = reason {
+            debug!("fsck: inode {} fails reassembly: {}", id, reason);
+            issues.push(Issue::UnreadableContent { id: *id, path: snapshot.path_of(*id), reason });
+        }
+    }
+    (checked, issues)
+}
+
+// Applies the fix for one issue; returns false for issues that need a human
+async fn repair(store: &dyn DataStore, namespace: &Namespace, snapshot: &Snapshot, issue: &Issue) -> DataStoreResult<bool> {
+    match issue {
+        // Upgraded before the snapshot was taken
+        Issue::LegacyLayout { .. } => return Ok(false),
+        Issue::MissingRoot { candidate: Some(root) } => {
+            store.set(&namespace.root_key(), &root.to_string()).await?;
+        }
+        Issue::MissingRoot { candidate: None } => return Ok(false),
+        Issue::DanglingEntry { dir, name, .. } => {
+            store.hdel(&namespace.entries_key(*dir), name).await?;
+        }
+        Issue::OrphanedEntries { dir, .. } => {
+            store.delete(&namespace.entries_key(*dir)).await?;
+        }
+        Issue::ExtraLinks { extra, .. } => {
+            for link in extra {
+                store.hdel(&namespace.entries_key(link.dir), &link.name).await?;
+            }
+        }
+        Issue::WrongBackPointer { id, linked, .. } => {
+            store.hset_multiple(&namespace.inode_key(*id), &[("parent", &linked.dir.to_string()), ("name", &linked.name)]).await?;
+        }
+        Issue::Unlinked { id, parent, name } => {
+            // Back where it says it belongs if that directory is intact and the name is free,
+            // otherwise into the root under a name that cannot clash
+            let free = |dir: fileid3, name: &str| !snapshot.entries.get(&dir).is_some_and(|names| names.contains_key(name));
+            let home = match (parent, name) {
+                (Some(parent), Some(name)) if !name.is_empty() && snapshot.is_directory(*parent) && free(*parent, name) => {
+                    Some((*parent, name.clone()))
+                }
+                _ => snapshot.root.map(|root| (root, format!("lost+found.{}", id))),
+            };
+            let Some((dir, name)) = home else {
+                return Ok(false);
+            };
+            if !free(dir, &name) {
+                return Ok(false);
+            }
+            store.hset_multiple(&namespace.inode_key(*id), &[("parent", &dir.to_string()), ("name", &name)]).await?;
+            store.hset(&namespace.entries_key(dir), &name, &id.to_string()).await?;
+        }
+        Issue::Unreachable { .. } => return Ok(false),
+        Issue::FileidMismatch { id, .. } => {
+            store.hset(&namespace.inode_key(*id), "fileid", &id.to_string()).await?;
+        }
+        Issue::NextFileidTooLow { max_id, .. } => {
+            store.set(&namespace.next_fileid_key(), &max_id.to_string()).await?;
+        }
+        Issue::UnreadableContent { .. } => return Ok(false),
+        Issue::UsageMismatch { counted, .. } => {
+            counted.write(store, namespace).await?;
+        }
+    }
+    Ok(true)
+}
+
+// Checks a namespace; content is reassembled only when a SecretSharingService is given. A
+// namespace still in the path-keyed layout is upgraded first when fixing
+pub async fn check_namespace(
+    store: &dyn DataStore,
+    namespace: &Namespace,
+    secret_sharing: Option<&SecretSharingService>,
+    fix: bool,
+) -> DataStoreResult<FsckReport> {
+    let mut findings = Vec::new();
+    if namespace::has_legacy_layout(store, namespace).await? {
+        let paths = store.zrange_withscores(&namespace.legacy_nodes_key(), 0, -1).await?.len();
+        let repaired = fix && namespace::upgrade_layout(store, namespace).await?.left_behind.is_empty();
+        findings.push(Finding { issue: Issue::LegacyLayout { paths }, repaired });
+    }
+
+    let snapshot = snapshot(store, namespace).await?;
+    let mut issues = find_issues(&snapshot);
+    let mut content_checked = 0;
+    if let Some(secret_sharing) = secret_sharing {
+        let (checked, content_issues) = check_content(&snapshot, secret_sharing).await;
+        content_checked = checked;
+        issues.extend(content_issues);
+    }
+
+    // The root goes first so inodes can be relinked into it, and dead entries go before
+    // anything is relinked so their names are free again
+    issues.sort_by_key(|issue| match issue {
+        Issue::MissingRoot { .. } => 0,
+        Issue::DanglingEntry { .. } | Issue::OrphanedEntries { .. } | Issue::ExtraLinks { .. } => 1,
+        _ => 2,
+    });
+    for issue in issues {
+        let repaired = fix && repair(store, namespace, &snapshot, &issue).await?;
+        findings.push(Finding { issue, repaired });
+    }
+
+    Ok(FsckReport {
+        community: namespace.community.clone(),
+        namespace_id: namespace.namespace_id.clone(),
+        inodes: snapshot.inodes.len(),
+        content_checked,
+        findings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backingstore::test_store::TestDataStore;
+    use crate::sharesfs::namespace::{add_inode, init_directory, resolve_path};
+    use crate::sharesfs::quota::{charge, Usage};
+
+    async fn healthy_store(namespace: &Namespace) -> TestDataStore {
+        let store = TestDataStore::new();
+        let alice = init_directory(&store, namespace, "/alice").await.unwrap();
+        add_inode(&store, namespace, 3, alice, "notes.txt", &[("ftype", "1"), ("size", "5"), ("owner", "alice")]).await.unwrap();
+        charge(&store, namespace, Some("alice"), 5, 1).await.unwrap();
+        store.set(&namespace.next_fileid_key(), "3").await.unwrap();
+        store
+    }
+
+    fn kinds(report: &FsckReport) -> Vec<Issue> {
+        report.findings.iter().map(|finding| finding.issue.clone()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_healthy_namespace_is_clean() {
+        let namespace = Namespace::new("zoo", "aqautics");
+        let store = healthy_store(&namespace).await;
+        let report = check_namespace(&store, &namespace, None, false).await.unwrap();
+        assert!(report.is_clean(), "{:?}", report.findings);
+        assert_eq!(report.inodes, 3);
+    }
+
+    #[tokio::test]
+    async fn test_detects_and_repairs_inconsistencies() {
+        let namespace = Namespace::new("zoo", "aqautics");
+        let store = healthy_store(&namespace).await;
+        // A dangling entry, a rename interrupted between its two entry writes, an inode that
+        // lost its entry, a wrong fileid and a counter that lags behind
+        store.hset(&namespace.entries_key(1), "gone", "9").await.unwrap();
+        store.hset(&namespace.entries_key(1), "notes.txt", "3").await.unwrap();
+        store.hset_multiple(&namespace.inode_key(4), &[("ftype", "1"), ("fileid", "4"), ("parent", "2"), ("name", "todo")]).await.unwrap();
+        store.hset(&namespace.inode_key(2), "fileid", "7").await.unwrap();
+        store.hset_multiple(&namespace.inode_key(10), &[("ftype", "1"), ("fileid", "10"), ("parent", "1"), ("name", "alice")]).await.unwrap();
+
+        let report = check_namespace(&store, &namespace, None, false).await.unwrap();
+        let issues = kinds(&report);
+        assert!(issues.contains(&Issue::DanglingEntry { dir: 1, name: "gone".into(), id: 9 }));
+        assert!(issues.contains(&Issue::ExtraLinks {
+            id: 3,
+            kept: Some(Link { dir: 2, name: "notes.txt".into() }),
+            extra: vec![Link { dir: 1, name: "notes.txt".into() }],
+        }));
+        assert!(issues.contains(&Issue::Unlinked { id: 4, parent: Some(2), name: Some("todo".into()) }));
+        assert!(issues.contains(&Issue::FileidMismatch { id: 2, recorded: Some("7".into()) }));
+        assert!(issues.contains(&Issue::NextFileidTooLow { next_fileid: 3, max_id: 10 }));
+        assert!(report.findings.iter().all(|finding| !finding.repaired));
+
+        let report = check_namespace(&store, &namespace, None, true).await.unwrap();
+        assert_eq!(report.unrepaired(), 0, "{:?}", report.findings);
+        let report = check_namespace(&store, &namespace, None, false).await.unwrap();
+        assert!(report.is_clean(), "{:?}", report.findings);
+        assert_eq!(resolve_path(&store, &namespace, "/alice/todo").await.unwrap(), Some(4));
+        assert_eq!(resolve_path(&store, &namespace, "/notes.txt").await.unwrap(), None);
+        // "alice" is taken in the root, so inode 10 goes to lost+found
+        assert_eq!(resolve_path(&store, &namespace, "/lost+found.10").await.unwrap(), Some(10));
+    }
+
+    #[tokio::test]
+    async fn test_upgrades_a_legacy_namespace() {
+        let namespace = Namespace::new("zoo", "aqautics");
+        let store = TestDataStore::new();
+        for (path, id) in [("/", "1"), ("/alice", "2")] {
+            store.hset_multiple(&namespace.legacy_node_key(path), &[("ftype", "0"), ("fileid", id)]).await.unwrap();
+            store.zadd(&namespace.legacy_nodes_key(), path, path.matches('/').count() as f64 + 1.0).await.unwrap();
+            store.hset(&namespace.legacy_path_to_id_key(), path, id).await.unwrap();
+            store.hset(&namespace.legacy_id_to_path_key(), id, path).await.unwrap();
+        }
+        store.set(&namespace.next_fileid_key(), "2").await.unwrap();
+
+        let report = check_namespace(&store, &namespace, None, false).await.unwrap();
+        assert_eq!(kinds(&report), vec![Issue::LegacyLayout { paths: 2 }]);
+        let report = check_namespace(&store, &namespace, None, true).await.unwrap();
+        assert_eq!(report.unrepaired(), 0, "{:?}", report.findings);
+        assert!(check_namespace(&store, &namespace, None, false).await.unwrap().is_clean());
+        assert_eq!(resolve_path(&store, &namespace, "/alice").await.unwrap(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_recounts_usage_kept_for_quotas() {
+        let namespace = Namespace::new("zoo", "aqautics");
+        let store = healthy_store(&namespace).await;
+        store.delete(&namespace.usage_key()).await.unwrap();
+        charge(&store, &namespace, Some("bob"), 40, 3).await.unwrap();
+
+        let report = check_namespace(&store, &namespace, None, true).await.unwrap();
+        let [Finding { issue: Issue::UsageMismatch { counted, .. }, repaired: true }] = report.findings.as_slice() else {
+            panic!("{:?}", report.findings);
+        };
+        assert_eq!(counted.namespace, Usage { bytes: 5, files: 2 });
+        assert_eq!(counted.user("alice"), Usage { bytes: 5, files: 1 });
+        assert_eq!(Ledger::read(&store, &namespace).await.unwrap(), *counted);
+        assert!(check_namespace(&store, &namespace, None, false).await.unwrap().is_clean());
+    }
+
+    #[tokio::test]
+    async fn test_reports_content_that_fails_reassembly() {
+        let namespace = Namespace::new("zoo", "aqautics");
+        let store = healthy_store(&namespace).await;
+        let secret_sharing = SecretSharingService::new().unwrap();
+        let shares = secret_sharing.disassemble(&STANDARD.encode("hello")).await.unwrap();
+        store.hset(&namespace.inode_key(3), "data", &shares).await.unwrap();
+        store.hset(&namespace.inode_key(2), "data", "not shares").await.unwrap();
+
+        let report = check_namespace(&store, &namespace, Some(&secret_sharing), true).await.unwrap();
+        assert_eq!(report.content_checked, 2);
+        assert_eq!(report.findings.len(), 1);
+        assert!(matches!(&report.findings[0].issue, Issue::UnreadableContent { id: 2, path: Some(path), .. } if path == "/alice"));
+        assert!(!report.findings[0].repaired);
+    }
+}
@@ -2,16 +2,22 @@ mod metadata;
 mod writing;
 mod rename;
 mod directories;
+pub mod changes;
 
 pub mod channel_buffer;
+pub mod export;
+pub mod fsck;
+pub mod namespace;
+pub mod quota;
+pub mod tenant;
 
-use std::collections::BTreeSet;
+use std::collections::BTreeMap;
 use std::ops::Bound;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use std::ffi::OsStr;
 use std::os::unix::ffi::OsStrExt;
 use crate::kernel::api::nfs::fileid3;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use tokio::sync::{Mutex, Semaphore};
 use rayon::prelude::*;
 
@@ -37,20 +43,19 @@ use async_trait::async_trait;
 
 use tracing::{debug, warn};
 
-use crate::kernel::vfs::api::{DirEntry, NFSFileSystem, ReadDirResult, VFSCapabilities};
-
-use lazy_static::lazy_static;
-lazy_static! {
-    pub static ref NAMESPACE_ID: Arc<RwLock<String>> = Arc::new(RwLock::new(String::new()));
-    pub static ref COMMUNITY: Arc<RwLock<String>> = Arc::new(RwLock::new(String::new()));
-}
+use crate::kernel::vfs::api::{DirEntry, FsStat, NFSFileSystem, ReadDirResult, VFSCapabilities};
 
 use base64::{Engine as _, engine::general_purpose::STANDARD};
 
 use crate::secret_sharing::SecretSharingService;
 
+use namespace::Namespace;
+use quota::Quotas;
+use changes::HeldInodes;
+
 use crate::audit_adapters::irrefutable_audit::{AuditEvent, IrrefutableAudit};
-use crate::audit_adapters::irrefutable_audit::event_types::{REASSEMBLED};
+use crate::audit_adapters::irrefutable_audit::event_types::{LOCK_ACQUIRED, LOCK_RELEASED, REASSEMBLED, WRITE_DENIED};
+use crate::kernel::vfs::exports::READ_ONLY_VIEW;
 
 #[derive(Clone)]
 pub struct SharesFS {
@@ -59,55 +64,42 @@ pub struct SharesFS {
     pub active_writes: Arc<Mutex<HashMap<fileid3, ActiveWrite>>>,
     pub commit_semaphore: Arc<Semaphore>,
     pub secret_sharing: Arc<SecretSharingService>,
-    
+    // The tenant this instance serves; every key it reads or writes lies in this namespace
+    pub namespace: Namespace,
+    // Users allowed to mount, when the tenant restricts them
+    pub allowed_users: Option<HashSet<String>>,
+    // The export this instance serves, as its index in the listener's export table
+    pub export_index: u32,
+    // Refuses every change, recording the attempt in the audit
+    pub read_only: bool,
+    // The directory the export serves; the directories below it are users' drives
+    pub export_path: String,
+    pub quotas: Arc<Quotas>,
+    pub held: Arc<HeldInodes>,
 }
 
 impl SharesFS {
-    pub async fn set_namespace_id_and_community(namespace: &str, pcommunity: &str) {
-        let mut namespace_id = NAMESPACE_ID.write().unwrap();
-        *namespace_id = namespace.to_string();
-
-        let mut community = COMMUNITY.write().unwrap();
-        community.clear(); // Clear the previous content
-        community.push_str(&format!("{{{}}}:", pcommunity.to_string()));
-        println!("community: {:?}", community);
-    }
-    
-    pub async fn get_namespace_id_and_community() -> (String, String) {
-        let namespace_id = NAMESPACE_ID.read().unwrap().clone();
-        let community = COMMUNITY.read().unwrap().clone();
-        (namespace_id, community)
-    }
-    
-    pub async fn create_test_entry(&self, _parent_id: u64, path: &str, id: u64) -> Result<(), nfsstat3> {
-        let (namespace_id, community) = SharesFS::get_namespace_id_and_community().await;
-        
-        // Store path to id mapping
-        self.data_store.hset(
-            &format!("{}/{}_path_to_id", community, namespace_id),
-            path,
-            &id.to_string()
-        ).await.map_err(|_| nfsstat3::NFS3ERR_IO)?;
-
-        // Store id to path mapping
-        self.data_store.hset(
-            &format!("{}/{}_id_to_path", community, namespace_id),
-            &id.to_string(),
-            path
-        ).await.map_err(|_| nfsstat3::NFS3ERR_IO)?;
+    pub async fn create_test_entry(&self, parent_id: u64, path: &str, id: u64) -> Result<(), nfsstat3> {
+        let ns = &self.namespace;
+        let id_str = id.to_string();
 
-        // Add to nodes set
-        let score = path.split("/").count() as f64;
-        self.data_store.zadd(
-            &format!("{}/{}_nodes", community, namespace_id),
-            path,
-            score
-        ).await.map_err(|_| nfsstat3::NFS3ERR_IO)?;
+        if path == "/" {
+            // The root is its own parent
+            self.data_store.hset_multiple(
+                &ns.inode_key(id),
+                &[("fileid", &id_str), ("parent", &id_str), ("name", "")]
+            ).await.map_err(|_| nfsstat3::NFS3ERR_IO)?;
+            self.data_store.set(&ns.root_key(), &id_str).await.map_err(|_| nfsstat3::NFS3ERR_IO)?;
+            return Ok(());
+        }
 
-        Ok(())
+        let name = path.rsplit('/').next().unwrap_or(path);
+        namespace::add_inode(&*self.data_store, ns, id, parent_id, name, &[])
+            .await
+            .map_err(|_| nfsstat3::NFS3ERR_IO)
     }
 
-    pub fn new(data_store: Arc<dyn DataStore>, irrefutable_audit: Arc<dyn IrrefutableAudit>) -> SharesFS {
+    pub fn new(data_store: Arc<dyn DataStore>, irrefutable_audit: Arc<dyn IrrefutableAudit>, namespace: Namespace) -> SharesFS {
         let active_writes = Arc::new(Mutex::new(HashMap::new()));
         let commit_semaphore = Arc::new(Semaphore::new(10));
         let secret_sharing = Arc::new(SecretSharingService::new().expect("Failed to initialize SecretSharingService"));
@@ -118,8 +110,31 @@ impl SharesFS {
             active_writes,
             commit_semaphore,
             secret_sharing,
+            namespace,
+            allowed_users: None,
+            export_index: 0,
+            read_only: false,
+            export_path: "/".to_string(),
+            quotas: Arc::new(Quotas::default()),
+            held: Arc::new(HeldInodes::default()),
+        }
+    }
+    // The same tree, read-only, with handles of its own so mounts of it stay read-only
+    pub fn read_only_view(&self) -> SharesFS {
+        let mut view = self.clone();
+        view.read_only = true;
+        view.export_index |= READ_ONLY_VIEW;
+        view
+    }
+
+    async fn check_writable(&self, operation: &str, id: fileid3) -> Result<(), nfsstat3> {
+        if !self.read_only {
+            return Ok(());
         }
+        self.write_denied(Some(id), operation).await;
+        Err(nfsstat3::NFS3ERR_ROFS)
     }
+
     // New method to start monitoring
     pub async fn start_monitoring(&self) {
         self.monitor_active_writes().await;
@@ -132,48 +147,42 @@ impl SharesFS {
     }
 
     pub async fn get_path_from_id(&self, id: fileid3) -> Result<String, nfsstat3> {
-
-        let (namespace_id, community) = SharesFS::get_namespace_id_and_community().await;
-
-        let key = format!("{}/{}_id_to_path", community, namespace_id);
-          
-        let path: String = {
-            self.data_store
-                .hget(&key, &id.to_string())
-                .await
-                .map_err(|_| nfsstat3::NFS3ERR_IO)?
-        };
-
-        Ok(path)
+        let id = self.resolve_id(id).await?;
+        let ns = &self.namespace;
+        namespace::path_of(&*self.data_store, ns, id)
+            .await
+            .map_err(|_| nfsstat3::NFS3ERR_IO)
     }
 
     /// Get the ID for a given file/directory path
     pub async fn get_id_from_path(&self, path: &str) -> Result<fileid3, nfsstat3> {
-       
-        let (namespace_id, community) = SharesFS::get_namespace_id_and_community().await;
-
-        let key = format!("{}/{}_path_to_id", community, namespace_id);
-
-        let id_str: String = self.data_store
-        .hget(&key, path)
-        .await
-        .map_err(|_| nfsstat3::NFS3ERR_IO)?;
-    
-        let id: fileid3 = id_str.parse()
-            .map_err(|_| nfsstat3::NFS3ERR_IO)?;
+        let ns = &self.namespace;
+        match namespace::resolve_path(&*self.data_store, ns, path).await {
+            Ok(Some(id)) => Ok(id),
+            Ok(None) => Err(nfsstat3::NFS3ERR_NOENT),
+            Err(_) => Err(nfsstat3::NFS3ERR_IO),
+        }
+    }
 
-        Ok(id)
+    // root_dir() is 0 while the mount hands out the root's own fileid; both name the root
+    pub async fn resolve_id(&self, id: fileid3) -> Result<fileid3, nfsstat3> {
+        if id != 0 {
+            return Ok(id);
+        }
+        let ns = &self.namespace;
+        namespace::root_id(&*self.data_store, ns)
+            .await
+            .map_err(|_| nfsstat3::NFS3ERR_IO)?
+            .ok_or(nfsstat3::NFS3ERR_NOENT)
     }
 
     /// Get the metadata for a given file/directory ID
     pub async fn get_metadata_from_id(&self, id: fileid3) -> Result<FileMetadata, nfsstat3> {
         //warn!("SharesFS::get_metadata_from_id");
-        let path = self.get_path_from_id(id).await?;
-        
-        let community = COMMUNITY.read().unwrap().clone();
+        let id = self.resolve_id(id).await?;
         
         // Construct the share store key for metadata
-        let metadata_key = format!("{}{}", community, path);
+        let metadata_key = self.namespace.inode_key(id);
 
         let metadata_vec = self.data_store.hgetall(&metadata_key).await
         .map_err(|_| nfsstat3::NFS3ERR_IO)?;
@@ -202,176 +211,66 @@ impl SharesFS {
            
 
             fileid: metadata.get("fileid").and_then(|s| s.parse().ok()).unwrap_or(0), // Assuming fileid is stored as integer
+            rdev: specdata3 {
+                specdata1: metadata.get("rdev_major").and_then(|s| s.parse().ok()).unwrap_or(0),
+                specdata2: metadata.get("rdev_minor").and_then(|s| s.parse().ok()).unwrap_or(0),
+            },
         };
 
         Ok(file_metadata)
         
     }
 
-    pub async fn get_direct_children(&self, path: &str) -> Result<Vec<fileid3>, nfsstat3> {
-        // Get nodes in the specified subpath
-
-        let nodes_in_subpath: Vec<String> = self.get_nodes_in_subpath(path).await?;
-
-        let mut direct_children = Vec::new();
-        for node in nodes_in_subpath {
-            if self.is_direct_child(&node, path).await {
-                let id_result = self.get_id_from_path(&node).await;
-                match id_result {
-                    Ok(id) => direct_children.push(id),
-                    Err(_) => return Err(nfsstat3::NFS3ERR_IO),
-                }
-            }
-        }
-        Ok(direct_children)
-    }
-
-    async fn get_nodes_in_subpath(&self, subpath: &str) -> Result<Vec<String>, nfsstat3> {
-        
-        let (namespace_id, community) = SharesFS::get_namespace_id_and_community().await;
-
-        let key = format!("{}/{}_nodes", community, namespace_id);
-
-        // let pool_guard = shared_pool.lock().unwrap();
-        // let mut conn = pool_guard.get_connection();
-        
-        if subpath == "/" {
-            // If the subpath is the root, return nodes with a score of ROOT_DIRECTORY_SCORE
-            // Await the async operation and then transform the Ok value
-            let nodes_result: Vec<String> = self.data_store.zrangebyscore(&key, 2.0, 2.0).await.map_err(|_| nfsstat3::NFS3ERR_IO)?;
-            Ok(nodes_result)
-            
-        } else {
-            // Calculate start and end scores based on the hierarchy level
-            let start_score = subpath.split("/").count() as f64;
-            let end_score = start_score + 1.0;
-
-            // Retrieve nodes at the specified hierarchy level
-            // Await the async operation and then transform the Ok value
-            let nodes_result: Vec<String> = self.data_store.zrangebyscore(&key, end_score, end_score).await.map_err(|_| nfsstat3::NFS3ERR_IO)?;
-            Ok(nodes_result)
-            
-        }
+    // The directory's entries as (name, fileid)
+    pub async fn get_direct_children(&self, dirid: fileid3) -> Result<Vec<(String, fileid3)>, nfsstat3> {
+        let ns = &self.namespace;
+        namespace::entries(&*self.data_store, ns, dirid)
+            .await
+            .map_err(|_| nfsstat3::NFS3ERR_IO)
     }
 
-    async fn is_direct_child(&self, node: &str, path: &str) -> bool {
-        // Check if the node is a direct child of the specified path
-        // For example, if path is "/asif", check if node is "/asif/something"
-        if path == "/" {
-            node.contains("/")
-        } else {
-            node.starts_with(&format!("{}{}", path, "/")) && !node[(path.len() + 1)..].contains("/")
-        }
+    // The fileid name refers to in the directory, if any
+    pub async fn get_child(&self, dirid: fileid3, name: &str) -> Result<Option<fileid3>, nfsstat3> {
+        let ns = &self.namespace;
+        namespace::lookup(&*self.data_store, ns, dirid, name)
+            .await
+            .map_err(|_| nfsstat3::NFS3ERR_IO)
     }
 
-    pub async fn get_last_path_element(&self, input: String) -> String {
-        let elements: Vec<&str> = input.split("/").collect();
-        
-        if elements.is_empty() {
-            return String::from("");
-        }
-        
-        let last_element = elements[elements.len() - 1];
-        if last_element == "/" {
-            String::from("/")
-        } else {
-            last_element.to_string()
-        }
+    pub async fn next_fileid(&self) -> Result<fileid3, nfsstat3> {
+        let ns = &self.namespace;
+        namespace::next_fileid(&*self.data_store, ns).await.map_err(|e| {
+            eprintln!("Error incrementing file ID: {:?}", e);
+            nfsstat3::NFS3ERR_IO
+        })
     }
 
-    pub async fn create_node(&self, node_type: &str, fileid: fileid3, path: &str) -> DataStoreResult<()> {
-        let (namespace_id, community) = SharesFS::get_namespace_id_and_community().await;
-      
-        let size = 0;
-        let permissions = 777;
-        let score: f64 = path.matches('/').count() as f64 + 1.0;  
-        
-        let system_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap();
-        let epoch_seconds = system_time.as_secs();
-        let epoch_nseconds = system_time.subsec_nanos(); // Capture nanoseconds part
-        
-        self.data_store.zadd(
-            &format!("{}/{}_nodes", community, namespace_id),
-            path,
-            score
-        ).await?;
-    
-        self.data_store.hset_multiple(&format!("{}{}", community, path), &[
-            ("ftype", node_type),
-            ("size", &size.to_string()),
-            ("permissions", &permissions.to_string()),
-            ("change_time_secs", &epoch_seconds.to_string()),
-            ("change_time_nsecs", &epoch_nseconds.to_string()),
-            ("modification_time_secs", &epoch_seconds.to_string()),
-            ("modification_time_nsecs", &epoch_nseconds.to_string()),
-            ("access_time_secs", &epoch_seconds.to_string()),
-            ("access_time_nsecs", &epoch_nseconds.to_string()),
-            ("birth_time_secs", &epoch_seconds.to_string()),
-            ("birth_time_nsecs", &epoch_nseconds.to_string()),
-            ("fileid", &fileid.to_string())
-            ]).await?;
-        
-            self.data_store.hset(&format!("{}/{}_path_to_id", community, namespace_id), path, &fileid.to_string()).await?;
-            self.data_store.hset(&format!("{}/{}_id_to_path", community, namespace_id), &fileid.to_string(), path).await?;
-            
-        Ok(())
+    pub async fn create_node(&self, node_type: &str, fileid: fileid3, parent: fileid3, name: &str, owner: Option<&str>) -> DataStoreResult<()> {
+        let ns = &self.namespace;
+        let attributes = namespace::new_attributes(node_type, "777", 0);
+        let mut fields = namespace::as_fields(&attributes);
+        fields.extend(owner.map(|owner| ("owner", owner)));
+        namespace::add_inode(&*self.data_store, ns, fileid, parent, name, &fields).await
     }
     
-    pub async fn create_file_node(&self, node_type: &str, fileid: fileid3, path: &str, setattr: sattr3,) -> DataStoreResult<()> {
-       
-        let (namespace_id, community) = SharesFS::get_namespace_id_and_community().await;
-      
-        let size = 0;
-        let score: f64 = path.matches('/').count() as f64 + 1.0;  
-        
-        let system_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap();
-        let epoch_seconds = system_time.as_secs();
-        let epoch_nseconds = system_time.subsec_nanos(); // Capture nanoseconds part
-        
-        let _ = self.data_store.zadd(
-            &format!("{}/{}_nodes", community, namespace_id),
-            path,
-            score
-        ).await.map_err(|_| nfsstat3::NFS3ERR_IO);
+    pub async fn create_file_node(&self, node_type: &str, fileid: fileid3, parent: fileid3, name: &str, setattr: sattr3, owner: Option<&str>) -> DataStoreResult<()> {
+        let ns = &self.namespace;
 
         let permissions = if let set_mode3::mode(mode) = setattr.mode {
-            debug!(" -- set permissions {:?} {:?}", path, mode);
+            debug!(" -- set permissions {:?} {:?}", name, mode);
             Self::mode_unmask_setattr(mode).to_string()
         } else {
             "777".to_string() // Default permissions if none specified
         };
 
-        let _ = self.data_store.hset_multiple(&format!("{}{}", community, path), 
-    &[
-            ("ftype", node_type),
-            ("size", &size.to_string()),
-            ("permissions", &permissions),
-            ("change_time_secs", &epoch_seconds.to_string()),
-            ("change_time_nsecs", &epoch_nseconds.to_string()),
-            ("modification_time_secs", &epoch_seconds.to_string()),
-            ("modification_time_nsecs", &epoch_nseconds.to_string()),
-            ("access_time_secs", &epoch_seconds.to_string()),
-            ("access_time_nsecs", &epoch_nseconds.to_string()),
-            ("birth_time_secs", &epoch_seconds.to_string()),
-            ("birth_time_nsecs", &epoch_nseconds.to_string()),
-            ("fileid", &fileid.to_string())
-            ]).await.map_err(|_| nfsstat3::NFS3ERR_IO);
-
-        let _ = self.data_store.hset(&format!("{}/{}_path_to_id", community, namespace_id), path, &fileid.to_string()).await.map_err(|_| nfsstat3::NFS3ERR_IO);
-        let _ = self.data_store.hset(&format!("{}/{}_id_to_path", community, namespace_id), &fileid.to_string(), path).await.map_err(|_| nfsstat3::NFS3ERR_IO);
-
-        
-        Ok(())
-            
+        let attributes = namespace::new_attributes(node_type, &permissions, 0);
+        let mut fields = namespace::as_fields(&attributes);
+        fields.extend(owner.map(|owner| ("owner", owner)));
+        namespace::add_inode(&*self.data_store, ns, fileid, parent, name, &fields).await
     }
     
-    pub async fn get_ftype(&self, path: String) -> Result<String, nfsstat3> {
-        let community = COMMUNITY.read().unwrap().clone();
-        let key = format!("{}{}", community, path.clone());
+    pub async fn get_ftype(&self, id: fileid3) -> Result<String, nfsstat3> {
+        let key = self.namespace.inode_key(id);
 
         let ftype_result = self.data_store.hget(&key, "ftype").await.map_err(|_| nfsstat3::NFS3ERR_IO);
         let ftype: String = match ftype_result {
@@ -381,32 +280,12 @@ impl SharesFS {
         Ok(ftype)
     }
 
-    async fn get_member_keys(&self, pattern: &str, sorted_set_key: &str) -> Result<bool, nfsstat3> {
-
-        match self.data_store.zscan_match(sorted_set_key, pattern).await {
-            Ok(iter) => {
-                // Process the iterator
-                // This part depends on what type your zscan_match returns
-                let matching_keys: Vec<String> = iter
-                .into_iter()
-                .collect();
-        
-                if !matching_keys.is_empty() {
-                    return Ok(true);
-                }
-            }
-            Err(_) => return Err(nfsstat3::NFS3ERR_IO),
-        }
-    
-        Ok(false)
-    }
-
-    pub async fn get_data(&self, path: &str) -> Vec<u8> {
+    pub async fn get_data(&self, id: fileid3) -> Vec<u8> {
      
-        let (_namespace_id, community) = SharesFS::get_namespace_id_and_community().await;
+        let key = self.namespace.inode_key(id);
 
         // Retrieve the current file content (Base64 encoded) from store
-        let store_value: String = (self.data_store.hget(&format!("{}{}", community, path),"data").await).unwrap_or_default();
+        let store_value: String = (self.data_store.hget(&key, "data").await).unwrap_or_default();
         if !store_value.is_empty() {
             match self.secret_sharing.reassemble(&store_value).await {
                 Ok(reconstructed_secret) => {
@@ -423,21 +302,340 @@ impl SharesFS {
     pub async fn load_existing_content(&self, id: fileid3, channel: &Arc<ChannelBuffer>) -> Result<(), nfsstat3> {
         
         if channel.is_empty().await {
+            let contents = self.get_data(id).await;
+
             
-            let path_result = self.get_path_from_id(id).await;
-            let path: String = match path_result {
-                Ok(k) => k,
-                Err(_) => return Err(nfsstat3::NFS3ERR_IO),  // Replace with appropriate nfsstat3 error
-            };
+            channel.write(0, &contents).await;
+        }
+
+        Ok(())
+    }
+
+    pub(super) async fn handle_setattr(&self, id: fileid3, setattr: sattr3) -> Result<fattr3, nfsstat3> {
+        self.check_writable("setattr", id).await?;
+        let id = self.resolve_id(id).await?;
+        // Never set attributes on an inode that is gone, that would bring back a fragment of it
+        if self.get_ftype(id).await.is_err() {
+            return Err(nfsstat3::NFS3ERR_STALE);
+        }
+        let key = self.namespace.inode_key(id);
+
+        debug!("setattr: {:?}", id);
+
+        match setattr.atime {
+            set_atime::SET_TO_SERVER_TIME => {
+                let system_time = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap();
+                let epoch_seconds = system_time.as_secs();
+                let epoch_nseconds = system_time.subsec_nanos();
+        
+                // Update the atime metadata of the file
+                let _ = self.data_store.hset_multiple(
+                    &key,
+                    &[
+                        ("access_time_secs", &epoch_seconds.to_string()),
+                        ("access_time_nsecs", &epoch_nseconds.to_string()),
+                    ]
+                ).await.map_err(|_| nfsstat3::NFS3ERR_IO);
+            }
+            set_atime::SET_TO_CLIENT_TIME(nfstime3 { seconds, nseconds }) => {
+                // Update the atime metadata of the file with client-provided time
+                let _ = self.data_store.hset_multiple(
+                    &key,
+                    &[
+                        ("access_time_secs", &seconds.to_string()),
+                        ("access_time_nsecs", &nseconds.to_string()),
+                    ]
+                ).await.map_err(|_| nfsstat3::NFS3ERR_IO);
+            }
+            _ => {}
+        };
+
+        match setattr.mtime {
+            set_mtime::SET_TO_SERVER_TIME => {
+                let system_time = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap();
+                let epoch_seconds = system_time.as_secs();
+                let epoch_nseconds = system_time.subsec_nanos();
+        
+                // Update the atime metadata of the file
+                let _ = self.data_store.hset_multiple(
+                    &key,
+                    &[
+                        ("modification_time_secs", &epoch_seconds.to_string()),
+                        ("modification_time_nsecs", &epoch_nseconds.to_string()),
+                    ],
+                ).await.map_err(|_| nfsstat3::NFS3ERR_IO);
+            }
+            set_mtime::SET_TO_CLIENT_TIME(nfstime3 { seconds, nseconds }) => {
+                // Update the atime metadata of the file with client-provided time
+                let _ = self.data_store.hset_multiple(
+                    &key,
+                    &[
+                        ("modification_time_secs", &seconds.to_string()),
+                        ("modification_time_nsecs", &nseconds.to_string()),
+                    ],
+                ).await.map_err(|_| nfsstat3::NFS3ERR_IO);
+            }
+            _ => {}
+        };
 
-            let contents = self.get_data(&path).await;
+        if let set_mode3::mode(mode) = setattr.mode {
+            debug!(" -- set permissions {:?} {:?}", id, mode);
+            let mode_value = Self::mode_unmask_setattr(mode);
 
+            // Update the permissions metadata of the file in the share store
+            let _ = self.data_store.hset_multiple(
+                &key,
+                &[
+                ("permissions",&mode_value.to_string())
+                ],
+            ).await.map_err(|_| nfsstat3::NFS3ERR_IO);
             
-            channel.write(0, &contents).await;
         }
 
+        if let set_size3::size(size3) = setattr.size {
+            debug!(" -- set size {:?} {:?}", id, size3);
+            // Only regular files are charged for their size
+            let regular = self.get_ftype(id).await.is_ok_and(|ftype| ftype == "1");
+            let (owner, size) = self.footprint(id).await;
+            let growth = if regular { size3.saturating_sub(size) } else { 0 };
+            self.reserve(owner.as_deref(), growth, 0).await?;
+    
+            // Update the size metadata of the file in the share store, its contents changed
+            let size_str = size3.to_string();
+            let times = namespace::modified_now();
+            let mut fields = namespace::as_fields(&times);
+            fields.push(("size", &size_str));
+            let hset_result = self.data_store.hset_multiple(&key, &fields).await.map_err(|_| nfsstat3::NFS3ERR_IO);
+            let hset_result = self.settle(owner.as_deref(), growth, 0, hset_result).await;
+            // Growth was charged by reserve; shrinking is charged once made
+            if regular && hset_result.is_ok() && size3 < size {
+                self.charge(owner.as_deref(), size3 as i64 - size as i64, 0).await;
+            }
+        }
+
+        // Whatever was set, the inode changed
+        let system_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        self.data_store.hset_multiple(
+            &key,
+            &[
+                ("change_time_secs", &system_time.as_secs().to_string()),
+                ("change_time_nsecs", &system_time.subsec_nanos().to_string()),
+            ],
+        ).await.map_err(|_| nfsstat3::NFS3ERR_IO)?;
+
+        let metadata = self.get_metadata_from_id(id).await?;
+
+        //FileMetadata::metadata_to_fattr3(id, &metadata)
+        let fattr = FileMetadata::metadata_to_fattr3(id, &metadata).await?;
+
+        Ok(fattr)
+    }
+
+    pub(super) async fn handle_create(&self, dirid: fileid3, filename: &filename3, setattr: sattr3) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.check_writable("create", dirid).await?;
+        let dirid = self.resolve_id(dirid).await?;
+
+        //warn!("graymamba create {:?}", dirid);
+        if self.get_ftype(dirid).await.is_err() {
+            return Err(nfsstat3::NFS3ERR_NOENT); // No such directory id exists
+        }
+
+        let objectname_osstr = OsStr::from_bytes(filename).to_os_string();
+        let name = objectname_osstr.to_str().unwrap_or("");
+
+        debug!("create: {:?} in {:?}", name, dirid);
+
+        // Check if file already exists
+        if self.get_child(dirid, name).await?.is_some() {
+            return Err(nfsstat3::NFS3ERR_EXIST);
+        }
+
+        // Create new file ID
+        let new_file_id = self.next_fileid().await?;
+
+        let owner = self.admit(dirid, name).await?;
+        let created = self.create_file_node("1", new_file_id, dirid, name, setattr, owner.as_deref())
+            .await
+            .map_err(|_| nfsstat3::NFS3ERR_IO);
+        self.settle(owner.as_deref(), 0, 1, created).await?;
+        let metadata = self.get_metadata_from_id(new_file_id).await?;
+        Ok((new_file_id, FileMetadata::metadata_to_fattr3(new_file_id, &metadata).await?))
+        
+    }
+
+    pub(super) async fn handle_create_exclusive(&self, dirid: fileid3, filename: &filename3) -> Result<fileid3, nfsstat3> {
+        self.check_writable("create", dirid).await?;
+        let dirid = self.resolve_id(dirid).await?;
+        if self.get_ftype(dirid).await.is_err() {
+            return Err(nfsstat3::NFS3ERR_IO);
+        }
+
+        let objectname_osstr = OsStr::from_bytes(filename).to_os_string();
+        let name = objectname_osstr.to_str().unwrap_or("");
+
+        if let Some(existing_id) = self.get_child(dirid, name).await? {
+            // File already exists, return the existing file ID
+            return Ok(existing_id);
+        }
+
+        // Create new file ID
+        let new_file_id = self.next_fileid().await?;
+
+        let owner = self.admit(dirid, name).await?;
+        let created = self.create_node("1", new_file_id, dirid, name, owner.as_deref())
+            .await
+            .map_err(|_| nfsstat3::NFS3ERR_IO);
+        self.settle(owner.as_deref(), 0, 1, created).await?;
+
+        Ok(new_file_id)
+    }
+
+    pub(super) async fn handle_remove(&self, dirid: fileid3, filename: &filename3) -> Result<(), nfsstat3> {
+        self.check_writable("remove", dirid).await?;
+        let dirid = self.resolve_id(dirid).await?;
+        let objectname_osstr = OsStr::from_bytes(filename).to_os_string();
+        let name = objectname_osstr.to_str().unwrap_or("");
+
+        let id = self.get_child(dirid, name).await?.ok_or(nfsstat3::NFS3ERR_NOENT)?;
+        // The path is only known while the inode is still linked
+        let path = self.get_path_from_id(id).await?;
+
+        debug!("remove: {:?}", path);
+
+        let ftype_result = self.get_ftype(id).await;
+        
+        match ftype_result {
+        Ok(ftype) => {
+            if ftype.parse().ok().and_then(FileMetadata::ftype_of).is_some() {
+                let (owner, bytes) = self.footprint(id).await;
+                self.remove_directory_file(dirid, name, id).await?;
+                self.charge(owner.as_deref(), -(bytes as i64), -1).await;
+                
+                // Trigger audit event for deletion
+                let community = self.namespace.community_prefix();
+                let event = AuditEvent {
+                    creation_time: Local::now().format("%b %d %H:%M:%S.%f %Y").to_string(),
+                    event_type: "DELETED".to_string(),
+                    file_path: path.clone(),
+                    event_key: community,
+                };
+                if let Err(e) = self.irrefutable_audit.trigger_event(event).await {
+                    warn!("Failed to trigger audit event: {}", e);
+                }
+            } else {
+                return Err(nfsstat3::NFS3ERR_IO);
+            }
+        },
+        Err(_) => return Err(nfsstat3::NFS3ERR_IO),
+        }
+            
         Ok(())
     }
+
+    pub(super) async fn handle_symlink(&self, dirid: fileid3, linkname: &filename3, symlink: &nfspath3, attr: &sattr3) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.check_writable("symlink", dirid).await?;
+        // Validate input parameters
+        if linkname.is_empty() || symlink.is_empty() {
+            return Err(nfsstat3::NFS3ERR_INVAL);
+        }
+
+        let dirid = self.resolve_id(dirid).await?;
+        if self.get_ftype(dirid).await.is_err() {
+            return Err(nfsstat3::NFS3ERR_IO);
+        }
+
+        //Convert symlink to string
+        let symlink_osstr = OsStr::from_bytes(symlink).to_os_string();
+
+        debug!("symlink: {:?}", symlink_osstr);
+        let objectname_osstr = OsStr::from_bytes(linkname).to_os_string();
+        let name = objectname_osstr.to_str().unwrap_or("");
+
+        if self.get_child(dirid, name).await?.is_some() {
+            return Err(nfsstat3::NFS3ERR_EXIST);
+        }
+
+        // Generate a new file ID for the symlink
+        let symlink_id = self.next_fileid().await?;
+        let owner = self.admit(dirid, name).await?;
+
+        // First calculate the permissions
+        let permissions = if let set_mode3::mode(mode) = attr.mode {
+            Self::mode_unmask_setattr(mode).to_string()
+        } else {
+            "777".to_string() // Default permissions if none specified
+        };
+
+        // The target is written with the attributes, so the link is never seen without it
+        let attributes = namespace::new_attributes("2", &permissions, symlink.len() as u64);
+        let mut fields = namespace::as_fields(&attributes);
+        fields.push(("symlink_target", symlink_osstr.to_str().unwrap_or_default()));
+        fields.extend(owner.as_deref().map(|owner| ("owner", owner)));
+        let ns = &self.namespace;
+        let added = namespace::add_inode(&*self.data_store, ns, symlink_id, dirid, name, &fields)
+            .await
+            .map_err(|_| nfsstat3::NFS3ERR_IO);
+        self.settle(owner.as_deref(), 0, 1, added).await?;
+
+        let metadata = self.get_metadata_from_id(symlink_id).await?;
+
+        Ok((symlink_id, FileMetadata::metadata_to_fattr3(symlink_id, &metadata).await?))
+        
+    }
+
+    pub(super) async fn handle_mknod(&self, dirid: fileid3, filename: &filename3, ftype: ftype3, attr: &sattr3, rdev: specdata3) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.check_writable("mknod", dirid).await?;
+        if filename.is_empty() {
+            return Err(nfsstat3::NFS3ERR_INVAL);
+        }
+        if !matches!(ftype, ftype3::NF3BLK | ftype3::NF3CHR | ftype3::NF3SOCK | ftype3::NF3FIFO) {
+            return Err(nfsstat3::NFS3ERR_BADTYPE);
+        }
+
+        let dirid = self.resolve_id(dirid).await?;
+        if self.get_ftype(dirid).await.is_err() {
+            return Err(nfsstat3::NFS3ERR_NOENT);
+        }
+
+        let objectname_osstr = OsStr::from_bytes(filename).to_os_string();
+        let name = objectname_osstr.to_str().unwrap_or("");
+        debug!("mknod: {:?} {:?} in {:?}", ftype, name, dirid);
+
+        if self.get_child(dirid, name).await?.is_some() {
+            return Err(nfsstat3::NFS3ERR_EXIST);
+        }
+
+        let node_id = self.next_fileid().await?;
+        let owner = self.admit(dirid, name).await?;
+        let permissions = if let set_mode3::mode(mode) = attr.mode {
+            Self::mode_unmask_setattr(mode).to_string()
+        } else {
+            "777".to_string() // Default permissions if none specified
+        };
+
+        // Special files hold no data, only their type and, for devices, the device numbers
+        let code = FileMetadata::ftype_code(ftype).to_string();
+        let attributes = namespace::new_attributes(&code, &permissions, 0);
+        let mut fields = namespace::as_fields(&attributes);
+        let (major, minor) = (rdev.specdata1.to_string(), rdev.specdata2.to_string());
+        if matches!(ftype, ftype3::NF3BLK | ftype3::NF3CHR) {
+            fields.push(("rdev_major", &major));
+            fields.push(("rdev_minor", &minor));
+        }
+        fields.extend(owner.as_deref().map(|owner| ("owner", owner)));
+        let ns = &self.namespace;
+        let added = namespace::add_inode(&*self.data_store, ns, node_id, dirid, name, &fields)
+            .await
+            .map_err(|_| nfsstat3::NFS3ERR_IO);
+        self.settle(owner.as_deref(), 0, 1, added).await?;
+
+        let metadata = self.get_metadata_from_id(node_id).await?;
+        Ok((node_id, FileMetadata::metadata_to_fattr3(node_id, &metadata).await?))
+    }
 }
 
 #[async_trait]
@@ -445,60 +643,87 @@ impl NFSFileSystem for SharesFS {
     fn data_store(&self) -> &dyn DataStore {
         &*self.data_store
     }
+    fn namespace(&self) -> &Namespace {
+        &self.namespace
+    }
+    fn admits_user(&self, user: &str) -> bool {
+        self.allowed_users.as_ref().is_none_or(|users| users.contains(user))
+    }
     fn root_dir(&self) -> fileid3 {
         0
     }
     fn capabilities(&self) -> VFSCapabilities {
-        VFSCapabilities::ReadWrite
+        if self.read_only {
+            VFSCapabilities::ReadOnly
+        } else {
+            VFSCapabilities::ReadWrite
+        }
+    }
+    fn export_index(&self) -> u32 {
+        self.export_index
+    }
+    // The exports of a tenant share its namespace, and fileids are sequential, so a handle of one
+    // export could otherwise name the files of another
+    async fn exports_id(&self, id: fileid3) -> bool {
+        if self.export_path == "/" {
+            return true;
+        }
+        let (Ok(root), Ok(id)) = (self.get_id_from_path(&self.export_path).await, self.resolve_id(id).await) else {
+            return false;
+        };
+        namespace::is_within(&*self.data_store, &self.namespace, id, root).await.unwrap_or(false)
+    }
+    async fn write_denied(&self, id: Option<fileid3>, operation: &str) {
+        let path = match id {
+            Some(id) => self.get_path_from_id(id).await.unwrap_or_else(|_| format!("<fileid {}>", id)),
+            None => "<unknown>".to_string(),
+        };
+        warn!("Denied {} of {} in read-only {:?}", operation, path, self.namespace);
+        let event = AuditEvent {
+            creation_time: Local::now().format("%b %d %H:%M:%S.%f %Y").to_string(),
+            event_type: WRITE_DENIED.to_string(),
+            file_path: path,
+            event_key: self.namespace.community_prefix(),
+        };
+        if let Err(e) = self.irrefutable_audit.trigger_event(event).await {
+            warn!("Failed to trigger audit event: {}", e);
+        }
+    }
+
+    async fn lock_changed(&self, id: fileid3, acquired: bool, host: &str) {
+        let path = self.get_path_from_id(id).await.unwrap_or_else(|_| format!("<fileid {}>", id));
+        debug!("{} {} a lock of {} in {:?}", host, if acquired { "took" } else { "released" }, path, self.namespace);
+        let event = AuditEvent {
+            creation_time: Local::now().format("%b %d %H:%M:%S.%f %Y").to_string(),
+            event_type: if acquired { LOCK_ACQUIRED } else { LOCK_RELEASED }.to_string(),
+            file_path: path,
+            event_key: self.namespace.community_prefix(),
+        };
+        if let Err(e) = self.irrefutable_audit.trigger_event(event).await {
+            warn!("Failed to trigger audit event: {}", e);
+        }
+    }
+
+    async fn commit(&self, id: fileid3) -> Result<(), nfsstat3> {
+        self.commit_write(id).await.map_err(|_| nfsstat3::NFS3ERR_IO)
     }
  
     async fn lookup(&self, dirid: fileid3, filename: &filename3) -> Result<fileid3, nfsstat3> {
         debug!("lookup: {:?}", filename);
         let filename_str = OsStr::from_bytes(filename).to_str().ok_or(nfsstat3::NFS3ERR_IO)?;
 
-        // Handle the root directory case
-        if dirid == 0 {
-            
-            let child_path = format!("/{}", filename_str);
-            
-            if let Ok(child_id) = self.get_id_from_path(&child_path).await {
-                //println!("ID--------{}", child_id);
-                return Ok(child_id);
-            }
-            return Err(nfsstat3::NFS3ERR_NOENT);
-        }
-    
-        // Handle other directories
-        let parent_path = self.get_path_from_id(dirid).await?;
-        let child_path = format!("{}/{}", parent_path, filename_str);
-    
-        if let Ok(child_id) = self.get_id_from_path(&child_path).await {
-            return Ok(child_id);
-        }
-    
-        Err(nfsstat3::NFS3ERR_NOENT)
+        // Root and other directories alike, one entry read
+        let dirid = self.resolve_id(dirid).await?;
+        self.get_child(dirid, filename_str).await?.ok_or(nfsstat3::NFS3ERR_NOENT)
     }
     
     async fn getattr(&self, id: fileid3) -> Result<fattr3, nfsstat3> {
-        self.get_attribute(id).await
-    }
-
-    async fn write(&self, id: fileid3, offset: u64, data: &[u8]) -> Result<fattr3, nfsstat3> {
-        self.handle_write(id, offset, data).await
-    }
-
-    async fn mkdir(&self, dirid: fileid3, dirname: &filename3) -> Result<(fileid3, fattr3), nfsstat3> {
-        self.handle_mkdir(dirid, dirname).await
+        self.get_attribute(id).await
     }
 
     async fn read(&self, id: fileid3, offset: u64, count: u32) -> Result<(Vec<u8>, bool), nfsstat3> {
         debug!("read: {:?}", id);
-        let (namespace_id, community) = SharesFS::get_namespace_id_and_community().await;
-    
-        let path: String = self.data_store.hget(
-            &format!("{}/{}_id_to_path", community, namespace_id),
-            &id.to_string()
-        ).await.map_err(|_| nfsstat3::NFS3ERR_IO)?;
+        let path = self.get_path_from_id(id).await?;
 
         debug!("read: {:?}", path);
         
@@ -546,7 +771,7 @@ impl NFSFileSystem for SharesFS {
             }
         }
     
-        let current_data = self.get_data(&path).await;
+        let current_data = self.get_data(id).await;
         
         if offset as usize >= current_data.len() {
             return Ok((vec![], true));
@@ -580,13 +805,13 @@ impl NFSFileSystem for SharesFS {
     }
 
     async fn readdir_sequential(&self, dirid: fileid3, start_after: fileid3, max_entries: usize) -> Result<ReadDirResult, nfsstat3> {
-        let path = self.get_path_from_id(dirid).await?;
-        //println!("path: {:?}", path);
+        let dirid = self.resolve_id(dirid).await?;
 
-        let children_vec = self.get_direct_children(&path).await?;
-        let children: BTreeSet<u64> = children_vec.into_iter().collect();
-        
-        //println!("Children: {:?}", children.iter().collect::<Vec<_>>());
+        // Ordered by fileid, which is what start_after counts in
+        let children: BTreeMap<fileid3, String> = self.get_direct_children(dirid).await?
+            .into_iter()
+            .map(|(name, id)| (id, name))
+            .collect();
         
         let mut ret = ReadDirResult {
             entries: Vec::new(),
@@ -602,13 +827,9 @@ impl NFSFileSystem for SharesFS {
         let remaining_length = children.range((range_start, Bound::Unbounded)).count();
         debug!("children len: {:?}", children.len());
         debug!("remaining_len : {:?}", remaining_length);
-        for child_id in children.range((range_start, Bound::Unbounded)) {
-            let child_path = self.get_path_from_id(*child_id).await?;
-            let child_name = self.get_last_path_element(child_path).await;
+        for (child_id, child_name) in children.range((range_start, Bound::Unbounded)) {
             let child_metadata = self.get_metadata_from_id(*child_id).await?;
 
-            //println!("\t --- {:?} {:?}", child_id, child_name);
-            
             ret.entries.push(DirEntry {
                 fileid: *child_id,
                 name: child_name.as_bytes().into(),
@@ -625,8 +846,6 @@ impl NFSFileSystem for SharesFS {
             ret.end = true;
         }
 
-        //println!("readdir_result:{:?}", ret);
-
         Ok(ret)
     }
 
@@ -636,27 +855,27 @@ impl NFSFileSystem for SharesFS {
         start_after: fileid3,
         max_entries: usize,
     ) -> Result<ReadDirResult, nfsstat3> {
+        let dirid = self.resolve_id(dirid).await?;
         let path = self.get_path_from_id(dirid).await?;
-        let children = self.get_direct_children(&path).await?;
+        let children: BTreeMap<fileid3, String> = self.get_direct_children(dirid).await?
+            .into_iter()
+            .map(|(name, id)| (id, name))
+            .collect();
 
         debug!("readdir: {:?}", path);
         
-        let children_set: BTreeSet<_> = children.into_iter().collect();
-        
         let range = if start_after > 0 {
-            children_set.range((Bound::Excluded(start_after), Bound::Unbounded))
+            children.range((Bound::Excluded(start_after), Bound::Unbounded))
         } else {
-            children_set.range(..)
+            children.range(..)
         };
 
         let entries: Vec<_> = range
         .take(max_entries)
         .collect::<Vec<_>>()
         .par_iter()
-        .filter_map(|&child_id| {
+        .filter_map(|&(child_id, name)| {
             let metadata = futures::executor::block_on(self.get_metadata_from_id(*child_id)).ok()?;
-            let path = futures::executor::block_on(self.get_path_from_id(*child_id)).ok()?;
-            let name = futures::executor::block_on(self.get_last_path_element(path));
             let attr = futures::executor::block_on(FileMetadata::metadata_to_fattr3(*child_id, &metadata)).ok()?;
             
             Some(DirEntry {
@@ -670,7 +889,7 @@ impl NFSFileSystem for SharesFS {
         let cnt = entries.len();
 
         // Trigger audit event for directory read
-        let (_namespace_id, community) = SharesFS::get_namespace_id_and_community().await;
+        let community = self.namespace.community_prefix();
         let event = AuditEvent {
             creation_time: Local::now().format("%b %d %H:%M:%S.%f %Y").to_string(),
             event_type: "DIRECTORY_READ".to_string(),
@@ -687,444 +906,112 @@ impl NFSFileSystem for SharesFS {
         })
     }
 
-    async fn setattr(&self, id: fileid3, setattr: sattr3) -> Result<fattr3, nfsstat3> {       
-        let (namespace_id, community) = SharesFS::get_namespace_id_and_community().await;
-
-        // Get file path from the share store
-        let path: String = self.data_store.hget(
-            &format!("{}/{}_id_to_path", community, namespace_id),
-            &id.to_string()
-        ).await
-            .unwrap_or_else(|_| String::new());
-
-        debug!("setattr: {:?}", path);
-
-        match setattr.atime {
-            set_atime::SET_TO_SERVER_TIME => {
-                let system_time = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap();
-                let epoch_seconds = system_time.as_secs();
-                let epoch_nseconds = system_time.subsec_nanos();
-        
-                // Update the atime metadata of the file
-                let _ = self.data_store.hset_multiple(
-                    &format!("{}{}", community, path),
-                    &[
-                        ("access_time_secs", &epoch_seconds.to_string()),
-                        ("access_time_nsecs", &epoch_nseconds.to_string()),
-                    ]
-                ).await.map_err(|_| nfsstat3::NFS3ERR_IO);
-            }
-            set_atime::SET_TO_CLIENT_TIME(nfstime3 { seconds, nseconds }) => {
-                // Update the atime metadata of the file with client-provided time
-                let _ = self.data_store.hset_multiple(
-                    &format!("{}{}", community, path),
-                    &[
-                        ("access_time_secs", &seconds.to_string()),
-                        ("access_time_nsecs", &nseconds.to_string()),
-                    ]
-                ).await.map_err(|_| nfsstat3::NFS3ERR_IO);
-            }
-            _ => {}
-        };
+    // Every change holds the inodes it touches, see changes.rs
+    async fn setattr(&self, id: fileid3, setattr: sattr3) -> Result<fattr3, nfsstat3> {
+        self.changing([id], self.handle_setattr(id, setattr)).await
+    }
 
-        match setattr.mtime {
-            set_mtime::SET_TO_SERVER_TIME => {
-                let system_time = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap();
-                let epoch_seconds = system_time.as_secs();
-                let epoch_nseconds = system_time.subsec_nanos();
-        
-                // Update the atime metadata of the file
-                let _ = self.data_store.hset_multiple(
-                    &format!("{}{}", community, path),
-                    &[
-                        ("modification_time_secs", &epoch_seconds.to_string()),
-                        ("modification_time_nsecs", &epoch_nseconds.to_string()),
-                    ],
-                ).await.map_err(|_| nfsstat3::NFS3ERR_IO);
-            }
-            set_mtime::SET_TO_CLIENT_TIME(nfstime3 { seconds, nseconds }) => {
-                // Update the atime metadata of the file with client-provided time
-                let _ = self.data_store.hset_multiple(
-                    &format!("{}{}", community, path),
-                    &[
-                        ("modification_time_secs", &seconds.to_string()),
-                        ("modification_time_nsecs", &nseconds.to_string()),
-                    ],
-                ).await.map_err(|_| nfsstat3::NFS3ERR_IO);
+    async fn setattr_wcc(&self, id: fileid3, setattr: sattr3, guard: Option<nfstime3>) -> (Result<fattr3, nfsstat3>, wcc_data) {
+        // The guard is checked while the file is held, so nothing changes it before it is set
+        let change = async {
+            if let Some(ctime) = guard {
+                if self.get_attribute(id).await?.ctime != ctime {
+                    return Err(nfsstat3::NFS3ERR_NOT_SYNC);
+                }
             }
-            _ => {}
+            self.handle_setattr(id, setattr).await
         };
+        let (res, [wcc]) = self.changing_wcc([id], change).await;
+        (res, wcc)
+    }
 
-        if let set_mode3::mode(mode) = setattr.mode {
-            debug!(" -- set permissions {:?} {:?}", path, mode);
-            let mode_value = Self::mode_unmask_setattr(mode);
-
-            // Update the permissions metadata of the file in the share store
-            let _ = self.data_store.hset_multiple(
-                &format!("{}{}", community, path),
-                &[
-                ("permissions",&mode_value.to_string())
-                ],
-            ).await.map_err(|_| nfsstat3::NFS3ERR_IO);
-            
-        }
-
-        if let set_size3::size(size3) = setattr.size {
-            debug!(" -- set size {:?} {:?}", path, size3);
-    
-            // Update the size metadata of the file in the share store
-            let _hset_result = self.data_store.hset_multiple(
-                &format!("{}{}", community, path),
-                &[
-                ("size",&size3.to_string())
-                ],
-            ).await.map_err(|_| nfsstat3::NFS3ERR_IO);
-        }
-        
-        
-        let metadata = self.get_metadata_from_id(id).await?;
-
-        //FileMetadata::metadata_to_fattr3(id, &metadata)
-        let fattr = FileMetadata::metadata_to_fattr3(id, &metadata).await?;
+    async fn write(&self, id: fileid3, offset: u64, data: &[u8]) -> Result<fattr3, nfsstat3> {
+        self.changing([id], self.handle_write(id, offset, data)).await
+    }
 
-        Ok(fattr)
+    async fn write_wcc(&self, id: fileid3, offset: u64, data: &[u8]) -> (Result<fattr3, nfsstat3>, wcc_data) {
+        let (res, [wcc]) = self.changing_wcc([id], self.handle_write(id, offset, data)).await;
+        (res, wcc)
     }
 
     async fn create(&self, dirid: fileid3, filename: &filename3, setattr: sattr3) -> Result<(fileid3, fattr3), nfsstat3> {
-                
-        let (namespace_id, community) = SharesFS::get_namespace_id_and_community().await;
-        
-        // Get parent directory path from the share store
-        let parent_path: String = self.data_store.hget(
-            &format!("{}/{}_id_to_path", community, namespace_id),
-            &dirid.to_string()
-        ).await
-            .unwrap_or_else(|_| String::new());
-        
-        //warn!("graymamba create {:?}", parent_path);
-        if parent_path.is_empty() {
-            return Err(nfsstat3::NFS3ERR_NOENT); // No such directory id exists
-        }
-
-        let objectname_osstr = OsStr::from_bytes(filename).to_os_string();
-        
-        let new_file_path: String = if parent_path == "/" {
-            format!("/{}", objectname_osstr.to_str().unwrap_or(""))
-        } else {
-            format!("{}/{}", parent_path, objectname_osstr.to_str().unwrap_or(""))
-        };
-
-        debug!("create: {:?}", new_file_path);
-
-        // Check if file already exists
-        let exists: bool = match self.data_store.zscore(
-            &format!("{}/{}_nodes", community, namespace_id),
-            &new_file_path
-        ).await {
-            Ok(Some(_)) => true,
-            Ok(None) => false,
-            Err(e) => {
-                eprintln!("Error checking if file exists: {:?}", e);
-                false
-            }
-        };
-    
-        
-        if exists {
-            return Err(nfsstat3::NFS3ERR_EXIST);
-        }
-
-        // Create new file ID
-        let new_file_id: fileid3 = match self.data_store.incr(
-            &format!("{}/{}_next_fileid", community, namespace_id)
-        ).await {
-            Ok(id) => id.try_into().unwrap(),
-            Err(e) => {
-                eprintln!("Error incrementing file ID: {:?}", e);
-                return Err(nfsstat3::NFS3ERR_IO);
-            }
-        };
+        self.changing([dirid], self.handle_create(dirid, filename, setattr)).await
+    }
 
-        let _ = self.create_file_node("1", new_file_id, &new_file_path, setattr).await;
-        let metadata = self.get_metadata_from_id(new_file_id).await?;
-        Ok((new_file_id, FileMetadata::metadata_to_fattr3(new_file_id, &metadata).await?))
-        
+    async fn create_wcc(&self, dirid: fileid3, filename: &filename3, setattr: sattr3) -> (Result<(fileid3, fattr3), nfsstat3>, wcc_data) {
+        let (res, [wcc]) = self.changing_wcc([dirid], self.handle_create(dirid, filename, setattr)).await;
+        (res, wcc)
     }
 
     async fn create_exclusive(&self, dirid: fileid3, filename: &filename3) -> Result<fileid3, nfsstat3> {
+        self.changing([dirid], self.handle_create_exclusive(dirid, filename)).await
+    }
 
+    async fn create_exclusive_wcc(&self, dirid: fileid3, filename: &filename3) -> (Result<fileid3, nfsstat3>, wcc_data) {
+        let (res, [wcc]) = self.changing_wcc([dirid], self.handle_create_exclusive(dirid, filename)).await;
+        (res, wcc)
+    }
 
-        {
-
-            //let mut conn = self.pool.get_connection();             
-
-            //let (namespace_id, community, new_file_path,new_file_id ) = {
-                
-                let (namespace_id, community) = SharesFS::get_namespace_id_and_community().await;
-                
-                // Get parent directory path from the share store
-                let parent_path: String = self.data_store.hget(
-                    &format!("{}/{}_id_to_path", community, namespace_id),
-                    &dirid.to_string()
-                ).await
-                    .map_err(|_| nfsstat3::NFS3ERR_IO)?;
-            
-                // if parent_path.is_empty() {
-                //     return Err(nfsstat3::NFS3ERR_NOENT); // No such directory id exists
-                // }
-        
-                let objectname_osstr = OsStr::from_bytes(filename).to_os_string();
-                
-                let new_file_path: String = if parent_path == "/" {
-                    format!("/{}", objectname_osstr.to_str().unwrap_or(""))
-                } else {
-                    format!("{}/{}", parent_path, objectname_osstr.to_str().unwrap_or(""))
-                };
-
-                let exists: bool = match self.data_store.zscore(
-                    &format!("{}/{}_nodes", community, namespace_id),
-                    &new_file_path
-                ).await {
-                    Ok(Some(_)) => true,
-                    Ok(None) => false,
-                    Err(e) => {
-                        eprintln!("Error checking if file exists: {:?}", e);
-                        false
-                    }
-                };
-                
-                if exists {
-
-                    let fields_result = self.data_store.hget(
-                        &format!("{}/{}_path_to_id", community, namespace_id),
-                        &new_file_path
-                    ).await;
-                    match fields_result {
-                        Ok(value) => {
-                            // File already exists, return the existing file ID
-                            return value.parse::<u64>().map_err(|_| nfsstat3::NFS3ERR_IO);
-                        }
-                        Err(_) => {
-                            // Handle the error case, e.g., return NFS3ERR_IO or any other appropriate error
-                            return Err(nfsstat3::NFS3ERR_IO);
-                        }
-                    }
-                    //return Err(nfsstat3::NFS3ERR_NOENT);
-                }
-
-                // Create new file ID
-                let new_file_id: fileid3 = match self.data_store.incr(
-                    &format!("{}/{}_next_fileid", community, namespace_id)
-                ).await {
-                    Ok(id) => id.try_into().unwrap(),
-                    Err(e) => {
-                        eprintln!("Error incrementing file ID: {:?}", e);
-                        return Err(nfsstat3::NFS3ERR_IO);
-                    }
-                };
-            
-            let _ = self.create_node("1", new_file_id, &new_file_path).await;
-
-            Ok(new_file_id)
-            
-        }
-        
+    async fn mkdir(&self, dirid: fileid3, dirname: &filename3) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.changing([dirid], self.handle_mkdir(dirid, dirname)).await
     }
 
-    async fn remove(&self, dirid: fileid3, filename: &filename3) -> Result<(), nfsstat3> {       
-        let parent_path = (self.get_path_from_id(dirid).await?).to_string();
-        let objectname_osstr = OsStr::from_bytes(filename).to_os_string();           
-        // Construct the full path of the file/directory
-        let new_dir_path: String = if parent_path == "/" {
-            format!("/{}", objectname_osstr.to_str().unwrap_or(""))
-        } else {
-            format!("{}/{}", parent_path, objectname_osstr.to_str().unwrap_or(""))
-        };
+    async fn mkdir_wcc(&self, dirid: fileid3, dirname: &filename3) -> (Result<(fileid3, fattr3), nfsstat3>, wcc_data) {
+        let (res, [wcc]) = self.changing_wcc([dirid], self.handle_mkdir(dirid, dirname)).await;
+        (res, wcc)
+    }
 
-        debug!("remove: {:?}", new_dir_path);
+    async fn remove(&self, dirid: fileid3, filename: &filename3) -> Result<(), nfsstat3> {
+        self.changing_entries([dirid], &[(dirid, filename)], self.handle_remove(dirid, filename)).await
+    }
 
-        let ftype_result = self.get_ftype(new_dir_path.clone()).await;
-        
-        match ftype_result {
-        Ok(ftype) => {
-            if ftype == "0" || ftype == "1" || ftype == "2" {
-                self.remove_directory_file(&new_dir_path).await?;
-                
-                // Trigger audit event for deletion
-                let (_namespace_id, community) = SharesFS::get_namespace_id_and_community().await;
-                let event = AuditEvent {
-                    creation_time: Local::now().format("%b %d %H:%M:%S.%f %Y").to_string(),
-                    event_type: "DELETED".to_string(),
-                    file_path: new_dir_path.clone(),
-                    event_key: community,
-                };
-                if let Err(e) = self.irrefutable_audit.trigger_event(event).await {
-                    warn!("Failed to trigger audit event: {}", e);
-                }
-            } else {
-                return Err(nfsstat3::NFS3ERR_IO);
-            }
-        },
-        Err(_) => return Err(nfsstat3::NFS3ERR_IO),
-        }
-            
-        Ok(())
+    async fn remove_wcc(&self, dirid: fileid3, filename: &filename3) -> (Result<(), nfsstat3>, wcc_data) {
+        let change = self.handle_remove(dirid, filename);
+        let (res, [wcc]) = self.changing_entries_wcc([dirid], &[(dirid, filename)], change).await;
+        (res, wcc)
     }
 
     async fn rename(&self, from_dirid: fileid3, from_filename: &filename3, to_dirid: fileid3, to_filename: &filename3) -> Result<(), nfsstat3> {
-        self.rename_helper(from_dirid, from_filename, to_dirid, to_filename).await
+        let change = self.rename_helper(from_dirid, from_filename, to_dirid, to_filename);
+        let entries = [(from_dirid, from_filename), (to_dirid, to_filename)];
+        self.changing_entries([from_dirid, to_dirid], &entries, change).await
     }
 
-    async fn symlink(&self, dirid: fileid3, linkname: &filename3, symlink: &nfspath3, attr: &sattr3) -> Result<(fileid3, fattr3), nfsstat3> {
-        // Validate input parameters
-        if linkname.is_empty() || symlink.is_empty() {
-            return Err(nfsstat3::NFS3ERR_INVAL);
-        }
-        
-        //let mut conn = self.pool.get_connection();  
-
-        let (namespace_id, community) = SharesFS::get_namespace_id_and_community().await;   
-
-        // Get the current system time for metadata timestamps
-        let system_time = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap();
-            let epoch_seconds = system_time.as_secs();
-            let epoch_nseconds = system_time.subsec_nanos(); // Capture nanoseconds part
-            
-
-        // Get the directory path from the directory ID
-        let dir_path: String = self.data_store.hget(
-            &format!("{}/{}_id_to_path", community, namespace_id),
-            &dirid.to_string()
-        ).await
-            .map_err(|_| nfsstat3::NFS3ERR_IO)?;
-
-        //Convert symlink to string
-        let symlink_osstr = OsStr::from_bytes(symlink).to_os_string();
-
-        debug!("symlink: {:?}", symlink_osstr);
-        // Construct the full symlink path
-        let objectname_osstr = OsStr::from_bytes(linkname).to_os_string();
-                    
-            let symlink_path: String = if dir_path == "/" {
-                format!("/{}", objectname_osstr.to_str().unwrap_or(""))
-            } else {
-                format!("{}/{}", dir_path, objectname_osstr.to_str().unwrap_or(""))
-            };
-
-            let symlink_exists: bool = match self.data_store.zscore(
-                &format!("{}/{}_nodes", community, namespace_id),
-                &symlink_path
-            ).await {
-                Ok(Some(_)) => true,
-                Ok(None) => false,
-                Err(e) => {
-                    eprintln!("Error checking if symlink exists: {:?}", e);
-                    false
-                }
-            };
-
-        if symlink_exists {
-            return Err(nfsstat3::NFS3ERR_EXIST);
-        }
-
-        // Generate a new file ID for the symlink
-        let symlink_id: fileid3 = match self.data_store.incr(
-            &format!("{}/{}_next_fileid", community, namespace_id)
-        ).await {
-            Ok(id) => id.try_into().unwrap(),
-            Err(e) => {
-                eprintln!("Error incrementing symlink ID: {:?}", e);
-                return Err(nfsstat3::NFS3ERR_IO);
-            }
-        };
+    async fn rename_wcc(&self, from_dirid: fileid3, from_filename: &filename3, to_dirid: fileid3, to_filename: &filename3) -> (Result<(), nfsstat3>, wcc_data, wcc_data) {
+        let change = self.rename_helper(from_dirid, from_filename, to_dirid, to_filename);
+        let entries = [(from_dirid, from_filename), (to_dirid, to_filename)];
+        let (res, [from_wcc, to_wcc]) = self.changing_entries_wcc([from_dirid, to_dirid], &entries, change).await;
+        (res, from_wcc, to_wcc)
+    }
 
-        // Begin a share store transaction to ensure atomicity
+    async fn symlink(&self, dirid: fileid3, linkname: &filename3, symlink: &nfspath3, attr: &sattr3) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.changing([dirid], self.handle_symlink(dirid, linkname, symlink, attr)).await
+    }
 
-        let score = (symlink_path.matches('/').count() as f64) + 1.0;
-        let _ = self.data_store.zadd(
-            &format!("{}/{}_nodes", community, namespace_id),
-            &symlink_path,
-            score
-        ).await.map_err(|_| nfsstat3::NFS3ERR_IO);
-        
-        // First calculate the permissions
-        let permissions = if let set_mode3::mode(mode) = attr.mode {
-            Self::mode_unmask_setattr(mode).to_string()
-        } else {
-            "777".to_string() // Default permissions if none specified
-        };
+    async fn symlink_wcc(&self, dirid: fileid3, linkname: &filename3, symlink: &nfspath3, attr: &sattr3) -> (Result<(fileid3, fattr3), nfsstat3>, wcc_data) {
+        let (res, [wcc]) = self.changing_wcc([dirid], self.handle_symlink(dirid, linkname, symlink, attr)).await;
+        (res, wcc)
+    }
 
-        // Include permissions in the initial hset_multiple
-        let _ = self.data_store.hset_multiple(
-            &format!("{}{}", community, &symlink_path),
-            &[
-                ("ftype", "2"),
-                ("size", &symlink.len().to_string()),
-                ("permissions", &permissions),
-                ("change_time_secs", &epoch_seconds.to_string()),
-                ("change_time_nsecs", &epoch_nseconds.to_string()),
-                ("modification_time_secs", &epoch_seconds.to_string()),
-                ("modification_time_nsecs", &epoch_nseconds.to_string()),
-                ("access_time_secs", &epoch_seconds.to_string()),
-                ("access_time_nsecs", &epoch_nseconds.to_string()),
-                ("birth_time_secs", &epoch_seconds.to_string()),
-                ("birth_time_nsecs", &epoch_nseconds.to_string()),
-                ("fileid", &symlink_id.to_string())
-            ]
-        ).await.map_err(|_| nfsstat3::NFS3ERR_IO);
-
-        let _ = self.data_store.hset(
-            &format!("{}{}", community, symlink_path),
-            "symlink_target",
-            symlink_osstr.to_str().unwrap_or_default()
-        ).await.map_err(|_| nfsstat3::NFS3ERR_IO);
-        
-        let _ = self.data_store.hset(
-            &format!("{}/{}_path_to_id", community, namespace_id),
-            &symlink_path,
-            &symlink_id.to_string()
-        ).await.map_err(|_| nfsstat3::NFS3ERR_IO);
-
-        let _ = self.data_store.hset(
-            &format!("{}/{}_id_to_path", community, namespace_id),
-            &symlink_id.to_string(),
-            &symlink_path
-        ).await.map_err(|_| nfsstat3::NFS3ERR_IO);
+    async fn mknod(&self, dirid: fileid3, filename: &filename3, ftype: ftype3, attr: &sattr3, rdev: specdata3) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.changing([dirid], self.handle_mknod(dirid, filename, ftype, attr, rdev)).await
+    }
 
-        let metadata = self.get_metadata_from_id(symlink_id).await?;
+    async fn mknod_wcc(&self, dirid: fileid3, filename: &filename3, ftype: ftype3, attr: &sattr3, rdev: specdata3) -> (Result<(fileid3, fattr3), nfsstat3>, wcc_data) {
+        let (res, [wcc]) = self.changing_wcc([dirid], self.handle_mknod(dirid, filename, ftype, attr, rdev)).await;
+        (res, wcc)
+    }
 
-        Ok((symlink_id, FileMetadata::metadata_to_fattr3(symlink_id, &metadata).await?))
-        
+    async fn fsstat(&self, _id: fileid3, user: Option<&str>) -> Result<FsStat, nfsstat3> {
+        self.space(user).await
     }
 
     async fn readlink(&self, id: fileid3) -> Result<nfsstring, nfsstat3> {
-        let (namespace_id, community) = SharesFS::get_namespace_id_and_community().await;
-    
-        // Retrieve the path from the file ID
-        let path: String = match self.data_store.hget(
-            &format!("{}/{}_id_to_path", community, namespace_id),
-            &id.to_string()
-        ).await {
-            Ok(path) => path,
-            Err(e) => {
-                eprintln!("Error retrieving path for ID {}: {:?}", id, e);
-                return Err(nfsstat3::NFS3ERR_STALE);
-            }
-        };
+        debug!("readlink: {:?}", id);
+        let key = self.namespace.inode_key(id);
 
-        debug!("readlink: {:?}", path);
-    
-        // Retrieve the symlink target using the path
-        let symlink_target: String = match self.data_store.hget(
-            &format!("{}{}", community, path),
-            "symlink_target"
-        ).await {
+        // Retrieve the symlink target from the inode
+        let symlink_target: String = match self.data_store.hget(&key, "symlink_target").await {
             Ok(target) => target,
             Err(e) => {
                 eprintln!("Error retrieving symlink target: {:?}", e);
@@ -1138,4 +1025,241 @@ impl NFSFileSystem for SharesFS {
             Ok(nfsstring::from(symlink_target.into_bytes()))
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+    use tokio::sync::mpsc;
+    use crate::backingstore::test_store::TestDataStore;
+
+    // Keeps the events it is sent for the test to read
+    struct RecordingAudit {
+        sender: mpsc::Sender<AuditEvent>,
+    }
+
+    #[async_trait]
+    impl IrrefutableAudit for RecordingAudit {
+        async fn new() -> Result<Self, Box<dyn Error>> {
+            Ok(RecordingAudit { sender: mpsc::channel(1).0 })
+        }
+        fn get_sender(&self) -> &mpsc::Sender<AuditEvent> {
+            &self.sender
+        }
+        fn spawn_event_handler(_: Arc<dyn IrrefutableAudit>, _: mpsc::Receiver<AuditEvent>) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+        async fn process_event(&self, _: AuditEvent) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+        fn shutdown(&self) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_only_view_refuses_changes_and_audits_them() {
+        let namespace = Namespace::new("zoo", "aqautics");
+        let store = Arc::new(TestDataStore::new());
+        namespace::init_directory(store.as_ref(), &namespace, "/alice").await.unwrap();
+        let (sender, mut events) = mpsc::channel(16);
+        let shares_fs = SharesFS::new(store, Arc::new(RecordingAudit { sender }), namespace);
+        let alice = shares_fs.get_id_from_path("/alice").await.unwrap();
+
+        let view = shares_fs.read_only_view();
+        assert!(matches!(view.capabilities(), VFSCapabilities::ReadOnly));
+        assert_eq!(view.export_index(), READ_ONLY_VIEW);
+        assert!(view.fh_to_id(&shares_fs.id_to_fh(alice)).await.is_err());
+
+        assert!(matches!(view.mkdir(alice, &b"docs"[..].into()).await, Err(nfsstat3::NFS3ERR_ROFS)));
+        assert!(matches!(view.create(alice, &b"notes"[..].into(), sattr3::default()).await, Err(nfsstat3::NFS3ERR_ROFS)));
+        assert!(matches!(view.setattr(alice, sattr3::default()).await, Err(nfsstat3::NFS3ERR_ROFS)));
+        for operation in ["mkdir", "create", "setattr"] {
+            let event = events.recv().await.unwrap();
+            assert_eq!(event.event_type, WRITE_DENIED, "{}", operation);
+            assert_eq!(event.file_path, "/alice");
+            assert_eq!(event.event_key, "{zoo}:");
+        }
+        assert!(view.get_child(alice, "docs").await.unwrap().is_none());
+
+        // The writable instance it was made from still changes the tree the view reads
+        shares_fs.mkdir(alice, &b"docs"[..].into()).await.unwrap();
+        assert!(view.lookup(alice, &b"docs"[..].into()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_handles_only_name_files_of_their_export() {
+        let namespace = Namespace::new("zoo", "aqautics");
+        let store = Arc::new(TestDataStore::new());
+        namespace::init_directory(store.as_ref(), &namespace, "/public/alice").await.unwrap();
+        namespace::init_directory(store.as_ref(), &namespace, "/secret/bob").await.unwrap();
+        let (sender, _events) = mpsc::channel(16);
+        let mut public = SharesFS::new(store, Arc::new(RecordingAudit { sender }), namespace);
+        public.export_path = "/public".to_string();
+        let alice = public.get_id_from_path("/public/alice").await.unwrap();
+        let bob = public.get_id_from_path("/secret/bob").await.unwrap();
+        let root = public.get_id_from_path("/").await.unwrap();
+
+        assert_eq!(public.fh_to_id(&public.id_to_fh(alice)).await.ok(), Some(alice));
+        // Handles carrying the export's index but naming a file outside it are refused
+        for id in [bob, root, 0] {
+            assert!(matches!(public.fh_to_id(&public.id_to_fh(id)).await, Err(nfsstat3::NFS3ERR_BADHANDLE)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_special_files_keep_their_type_and_device_numbers() {
+        let namespace = Namespace::new("zoo", "aqautics");
+        let store = Arc::new(TestDataStore::new());
+        namespace::init_directory(store.as_ref(), &namespace, "/alice").await.unwrap();
+        let (sender, _events) = mpsc::channel(16);
+        let shares_fs = SharesFS::new(store, Arc::new(RecordingAudit { sender }), namespace);
+        let alice = shares_fs.get_id_from_path("/alice").await.unwrap();
+
+        let tty = specdata3 { specdata1: 5, specdata2: 1 };
+        let (console, attr) = shares_fs.mknod(alice, &b"console"[..].into(), ftype3::NF3CHR, &sattr3::default(), tty).await.unwrap();
+        assert!(matches!(attr.ftype, ftype3::NF3CHR));
+        assert_eq!((attr.rdev.specdata1, attr.rdev.specdata2), (5, 1));
+        let (_, attr) = shares_fs.mknod(alice, &b"queue"[..].into(), ftype3::NF3FIFO, &sattr3::default(), tty).await.unwrap();
+        assert!(matches!(attr.ftype, ftype3::NF3FIFO));
+        assert_eq!(attr.rdev.specdata1, 0);
+        assert!(matches!(
+            shares_fs.mknod(alice, &b"queue"[..].into(), ftype3::NF3SOCK, &sattr3::default(), tty).await,
+            Err(nfsstat3::NFS3ERR_EXIST)
+        ));
+        assert!(matches!(
+            shares_fs.mknod(alice, &b"notes"[..].into(), ftype3::NF3REG, &sattr3::default(), tty).await,
+            Err(nfsstat3::NFS3ERR_BADTYPE)
+        ));
+
+        // Listings carry their attributes, and they are removed like files
+        let listing = shares_fs.readdir(alice, 0, 10).await.unwrap();
+        assert_eq!(listing.entries.len(), 2);
+        assert!(matches!(shares_fs.getattr(console).await.unwrap().ftype, ftype3::NF3CHR));
+        shares_fs.remove(alice, &b"console"[..].into()).await.unwrap();
+        shares_fs.remove(alice, &b"queue"[..].into()).await.unwrap();
+        assert!(shares_fs.get_direct_children(alice).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_quotas_refuse_growth_and_fsstat_reports_them() {
+        let namespace = Namespace::new("zoo", "aqautics");
+        let store = Arc::new(TestDataStore::new());
+        for drive in ["/alice", "/bob"] {
+            namespace::init_directory(store.as_ref(), &namespace, drive).await.unwrap();
+        }
+        let (sender, _events) = mpsc::channel(64);
+        let mut shares_fs = SharesFS::new(store, Arc::new(RecordingAudit { sender }), namespace);
+        let user = quota::Limits { soft_bytes: Some(6), hard_bytes: Some(10), hard_files: Some(2), ..Default::default() };
+        let total = quota::Limits { hard_bytes: Some(15), ..Default::default() };
+        shares_fs.quotas = Arc::new(Quotas { namespace: total, user, users: HashMap::new() });
+        let alice = shares_fs.get_id_from_path("/alice").await.unwrap();
+        let bob = shares_fs.get_id_from_path("/bob").await.unwrap();
+
+        let (notes, _) = shares_fs.create(alice, &b"notes"[..].into(), sattr3::default()).await.unwrap();
+        shares_fs.write(notes, 0, b"12345678").await.unwrap();
+        assert!(matches!(shares_fs.write(notes, 8, b"9ab").await, Err(nfsstat3::NFS3ERR_DQUOT)));
+        shares_fs.mkdir(alice, &b"docs"[..].into()).await.unwrap();
+        assert!(matches!(shares_fs.create(alice, &b"more"[..].into(), sattr3::default()).await, Err(nfsstat3::NFS3ERR_DQUOT)));
+
+        // Bob's own quota has room, the namespace's has not
+        let (todo, _) = shares_fs.create(bob, &b"todo"[..].into(), sattr3::default()).await.unwrap();
+        assert!(matches!(shares_fs.write(todo, 0, b"12345678").await, Err(nfsstat3::NFS3ERR_NOSPC)));
+
+        let stat = shares_fs.fsstat(alice, Some("alice")).await.unwrap();
+        assert_eq!((stat.total_bytes, stat.free_bytes, stat.avail_bytes), (10, 2, 0));
+        assert_eq!(stat.max_file_size, 10);
+        let stat = shares_fs.fsstat(alice, None).await.unwrap();
+        assert_eq!((stat.total_bytes, stat.free_bytes, stat.total_files - stat.free_files), (15, 7, 5));
+
+        // Removing and truncating give the space back
+        shares_fs.remove(alice, &b"docs"[..].into()).await.unwrap();
+        let truncate = sattr3 { size: set_size3::size(2), ..Default::default() };
+        shares_fs.setattr(notes, truncate).await.unwrap();
+        shares_fs.write(todo, 0, b"12345678").await.unwrap();
+        let (usage, alice_usage) = quota::usage(shares_fs.data_store.as_ref(), &shares_fs.namespace, Some("alice")).await.unwrap();
+        assert_eq!(usage, quota::Usage { bytes: 10, files: 4 });
+        assert_eq!(alice_usage, Some(quota::Usage { bytes: 2, files: 1 }));
+    }
+
+    #[tokio::test]
+    async fn test_creates_made_at_once_cannot_share_the_last_file_of_a_quota() {
+        let namespace = Namespace::new("zoo", "aqautics");
+        let store = Arc::new(TestDataStore::new());
+        namespace::init_directory(store.as_ref(), &namespace, "/alice").await.unwrap();
+        let (sender, _events) = mpsc::channel(64);
+        let mut shares_fs = SharesFS::new(store, Arc::new(RecordingAudit { sender }), namespace);
+        let user = quota::Limits { hard_files: Some(1), ..Default::default() };
+        shares_fs.quotas = Arc::new(Quotas { user, ..Quotas::default() });
+        let alice = shares_fs.get_id_from_path("/alice").await.unwrap();
+
+        let (one, two) = (b"one"[..].into(), b"two"[..].into());
+        let (one, two) = tokio::join!(
+            shares_fs.create(alice, &one, sattr3::default()),
+            shares_fs.create(alice, &two, sattr3::default()),
+        );
+        assert_eq!([one.is_ok(), two.is_ok()].iter().filter(|created| **created).count(), 1);
+        assert!(matches!(one.and(two), Err(nfsstat3::NFS3ERR_DQUOT)));
+        let (_, alice_usage) = quota::usage(shares_fs.data_store.as_ref(), &shares_fs.namespace, Some("alice")).await.unwrap();
+        assert_eq!(alice_usage, Some(quota::Usage { bytes: 0, files: 1 }));
+    }
+
+    #[tokio::test]
+    async fn test_changes_report_the_attributes_on_either_side() {
+        let namespace = Namespace::new("zoo", "aqautics");
+        let store = Arc::new(TestDataStore::new());
+        namespace::init_directory(store.as_ref(), &namespace, "/alice").await.unwrap();
+        let (sender, _events) = mpsc::channel(16);
+        let shares_fs = SharesFS::new(store, Arc::new(RecordingAudit { sender }), namespace);
+        let alice = shares_fs.get_id_from_path("/alice").await.unwrap();
+        let attrs = |wcc: wcc_data| match (wcc.before, wcc.after) {
+            (pre_op_attr::attributes(before), post_op_attr::attributes(after)) => (before, after),
+            _ => panic!("attributes missing from {:?}", wcc),
+        };
+
+        // Entries made in a directory modify it, and it is reported as it was and became
+        let dir = shares_fs.getattr(alice).await.unwrap();
+        let (res, wcc) = shares_fs.create_wcc(alice, &b"notes"[..].into(), sattr3::default()).await;
+        let (notes, _) = res.unwrap();
+        let (before, after) = attrs(wcc);
+        assert_eq!((before.mtime, before.ctime), (dir.mtime, dir.ctime));
+        assert_ne!(after.mtime, dir.mtime);
+        assert_eq!(after.mtime, shares_fs.getattr(alice).await.unwrap().mtime);
+
+        // A write changes the times of the file with its size
+        let (res, wcc) = shares_fs.write_wcc(notes, 0, b"hello").await;
+        let (before, after) = attrs(wcc);
+        assert_eq!((before.size, after.size, res.unwrap().size), (0, 5, 5));
+        assert_ne!(after.mtime, before.mtime);
+
+        // A guard that is not the ctime leaves the file alone, one that is lets the change through
+        let file = shares_fs.getattr(notes).await.unwrap();
+        let chmod = sattr3 { mode: set_mode3::mode(0o600), ..sattr3::default() };
+        let stale = nfstime3 { seconds: file.ctime.seconds - 1, nseconds: 0 };
+        let (res, wcc) = shares_fs.setattr_wcc(notes, chmod, Some(stale)).await;
+        assert!(matches!(res, Err(nfsstat3::NFS3ERR_NOT_SYNC)));
+        let (before, after) = attrs(wcc);
+        assert_eq!((before.ctime, after.ctime, after.mode), (file.ctime, file.ctime, file.mode));
+        let (res, wcc) = shares_fs.setattr_wcc(notes, chmod, Some(file.ctime)).await;
+        assert_ne!(res.unwrap().mode, file.mode);
+        assert_ne!(attrs(wcc).1.ctime, file.ctime);
+
+        // A rename reports both directories
+        shares_fs.mkdir(alice, &b"archive"[..].into()).await.unwrap();
+        let archive = shares_fs.lookup(alice, &b"archive"[..].into()).await.unwrap();
+        let (res, from_wcc, to_wcc) = shares_fs.rename_wcc(alice, &b"notes"[..].into(), archive, &b"notes"[..].into()).await;
+        res.unwrap();
+        assert_ne!(attrs(from_wcc).1.mtime, attrs(from_wcc).0.mtime);
+        assert_ne!(attrs(to_wcc).1.mtime, attrs(to_wcc).0.mtime);
+
+        // A removal waits for the changes of the inode it removes, not only of its directory
+        let (todo, _) = shares_fs.create(alice, &b"todo"[..].into(), sattr3::default()).await.unwrap();
+        assert_ne!(todo % 64, alice % 64);
+        let name: filename3 = b"todo"[..].into();
+        let held = shares_fs.held.hold(&[todo]).await;
+        let remove = shares_fs.remove_wcc(alice, &name);
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(50), remove).await.is_err());
+        drop(held);
+        shares_fs.remove_wcc(alice, &name).await.0.unwrap();
+    }
+}
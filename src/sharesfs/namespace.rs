@@ -0,0 +1,475 @@
+// Where a namespace's files live in the backing store.
+//
+// Every file, directory and symlink is an inode keyed by its fileid. The inode hash holds the
+// attributes, the shares ("data") and a back-pointer to the entry that links it (parent, name).
+// A directory's entries are a hash from name to child fileid. Nothing is keyed by path, so
+// renaming a directory touches two entries and one inode however much lies beneath it; paths
+// are computed by walking parent pointers, for the audit trail and the mount. Adding, removing or
+// moving an entry updates the modification and change times of the directories it lies in.
+//
+//   {c}/{ns}_root             fileid of the root directory
+//   {c}/{ns}_inode:{id}       attributes, data, parent, name
+//   {c}/{ns}_entries:{id}     name -> child fileid, for directories
+//   {c}/{ns}_next_fileid      last fileid handed out
+//   {c}/{ns}_usage            bytes and files in use, see quota.rs
+//
+// Namespaces written before this layout keep one hash per path plus the _nodes, _path_to_id and
+// _id_to_path indexes; upgrade_layout converts them in place.
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tracing::debug;
+
+use crate::backingstore::data_store::{DataStore, DataStoreError, DataStoreResult};
+use crate::kernel::api::nfs::fileid3;
+use crate::sharesfs::quota;
+
+// Guards parent walks against a cycle left behind by a damaged namespace
+const MAX_DEPTH: usize = 4096;
+
+// Community is the bare name, e.g. "zoo", not "{zoo}:"
+#[derive(Debug, Clone, PartialEq)]
+pub struct Namespace {
+    pub community: String,
+    pub namespace_id: String,
+}
+
+impl Namespace {
+    pub fn new(community: &str, namespace_id: &str) -> Self {
+        Namespace { community: community.to_string(), namespace_id: namespace_id.to_string() }
+    }
+
+    // "{zoo}:", which prefixes every key of the community and keys its audit events
+    pub fn community_prefix(&self) -> String {
+        format!("{{{}}}:", self.community)
+    }
+
+    pub fn root_key(&self) -> String {
+        format!("{}/{}_root", self.community_prefix(), self.namespace_id)
+    }
+
+    pub fn inode_key(&self, id: fileid3) -> String {
+        format!("{}/{}_inode:{}", self.community_prefix(), self.namespace_id, id)
+    }
+
+    pub fn entries_key(&self, id: fileid3) -> String {
+        format!("{}/{}_entries:{}", self.community_prefix(), self.namespace_id, id)
+    }
+
+    pub fn next_fileid_key(&self) -> String {
+        format!("{}/{}_next_fileid", self.community_prefix(), self.namespace_id)
+    }
+
+    pub fn usage_key(&self) -> String {
+        format!("{}/{}_usage", self.community_prefix(), self.namespace_id)
+    }
+
+    // Matches every key of one kind ("inode" or "entries"); see id_from_key
+    pub fn key_pattern(&self, kind: &str) -> String {
+        format!("{}/{}_{}:*", self.community_prefix(), self.namespace_id, kind)
+    }
+
+    // The fileid in an inode or entries key. Stores that keep hash fields as keys of their own
+    // list "{key}:{field}" too, so anything after the id is ignored
+    pub fn id_from_key(&self, kind: &str, key: &str) -> Option<fileid3> {
+        let rest = key.strip_prefix(&format!("{}/{}_{}:", self.community_prefix(), self.namespace_id, kind))?;
+        rest.split(':').next()?.parse().ok()
+    }
+
+    pub fn legacy_node_key(&self, path: &str) -> String {
+        format!("{}{}", self.community_prefix(), path)
+    }
+
+    pub fn legacy_nodes_key(&self) -> String {
+        format!("{}/{}_nodes", self.community_prefix(), self.namespace_id)
+    }
+
+    pub fn legacy_path_to_id_key(&self) -> String {
+        format!("{}/{}_path_to_id", self.community_prefix(), self.namespace_id)
+    }
+
+    pub fn legacy_id_to_path_key(&self) -> String {
+        format!("{}/{}_id_to_path", self.community_prefix(), self.namespace_id)
+    }
+}
+
+fn parse_id(value: &str) -> DataStoreResult<fileid3> {
+    value.parse().map_err(|_| DataStoreError::OperationFailed)
+}
+
+fn found<T>(result: DataStoreResult<T>) -> DataStoreResult<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(DataStoreError::KeyNotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Attributes of a freshly created inode, stamped with the current time
+pub fn new_attributes(ftype: &str, permissions: &str, size: u64) -> Vec<(&'static str, String)> {
+    let system_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let epoch_seconds = system_time.as_secs().to_string();
+    let epoch_nseconds = system_time.subsec_nanos().to_string();
+    vec![
+        ("ftype", ftype.to_string()),
+        ("size", size.to_string()),
+        ("permissions", permissions.to_string()),
+        ("change_time_secs", epoch_seconds.clone()),
+        ("change_time_nsecs", epoch_nseconds.clone()),
+        ("modification_time_secs", epoch_seconds.clone()),
+        ("modification_time_nsecs", epoch_nseconds.clone()),
+        ("access_time_secs", epoch_seconds.clone()),
+        ("access_time_nsecs", epoch_nseconds.clone()),
+        ("birth_time_secs", epoch_seconds),
+        ("birth_time_nsecs", epoch_nseconds),
+    ]
+}
+
+// The times of an inode changed now, its contents with it
+pub fn modified_now() -> Vec<(&'static str, String)> {
+    let system_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let epoch_seconds = system_time.as_secs().to_string();
+    let epoch_nseconds = system_time.subsec_nanos().to_string();
+    vec![
+        ("change_time_secs", epoch_seconds.clone()),
+        ("change_time_nsecs", epoch_nseconds.clone()),
+        ("modification_time_secs", epoch_seconds),
+        ("modification_time_nsecs", epoch_nseconds),
+    ]
+}
+
+// A directory whose entries changed
+async fn touch<S: DataStore + ?Sized>(store: &S, namespace: &Namespace, dir: fileid3) -> DataStoreResult<()> {
+    store.hset_multiple(&namespace.inode_key(dir), &as_fields(&modified_now())).await
+}
+
+pub fn as_fields<'a>(attributes: &'a [(&'static str, String)]) -> Vec<(&'a str, &'a str)> {
+    attributes.iter().map(|(field, value)| (*field, value.as_str())).collect()
+}
+
+// Writes a hash the way SharesFS does: the attributes together, the shares on their own
+pub async fn write_fields<S: DataStore + ?Sized>(store: &S, key: &str, fields: &[(String, String)]) -> DataStoreResult<()> {
+    let attributes: Vec<(&str, &str)> = fields.iter()
+        .filter(|(field, _)| field != "data")
+        .map(|(field, value)| (field.as_str(), value.as_str()))
+        .collect();
+    if !attributes.is_empty() {
+        store.hset_multiple(key, &attributes).await?;
+    }
+    if let Some((_, data)) = fields.iter().find(|(field, _)| field == "data") {
+        store.hset(key, "data", data).await?;
+    }
+    Ok(())
+}
+
+pub async fn root_id<S: DataStore + ?Sized>(store: &S, namespace: &Namespace) -> DataStoreResult<Option<fileid3>> {
+    match found(store.get(&namespace.root_key()).await)? {
+        Some(id) => Ok(Some(parse_id(&id)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn next_fileid<S: DataStore + ?Sized>(store: &S, namespace: &Namespace) -> DataStoreResult<fileid3> {
+    let id = store.incr(&namespace.next_fileid_key()).await?;
+    fileid3::try_from(id).map_err(|_| DataStoreError::OperationFailed)
+}
+
+pub async fn lookup<S: DataStore + ?Sized>(store: &S, namespace: &Namespace, dir: fileid3, name: &str) -> DataStoreResult<Option<fileid3>> {
+    match found(store.hget(&namespace.entries_key(dir), name).await)? {
+        Some(id) => Ok(Some(parse_id(&id)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn entries<S: DataStore + ?Sized>(store: &S, namespace: &Namespace, dir: fileid3) -> DataStoreResult<Vec<(String, fileid3)>> {
+    store.hgetall(&namespace.entries_key(dir)).await?
+        .into_iter()
+        .map(|(name, id)| Ok((name, parse_id(&id)?)))
+        .collect()
+}
+
+pub async fn resolve_path<S: DataStore + ?Sized>(store: &S, namespace: &Namespace, path: &str) -> DataStoreResult<Option<fileid3>> {
+    let Some(mut id) = root_id(store, namespace).await? else {
+        return Ok(None);
+    };
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        match lookup(store, namespace, id, name).await? {
+            Some(child) => id = child,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(id))
+}
+
+// The inode's (parent, name); the root is its own parent
+pub async fn parent_of<S: DataStore + ?Sized>(store: &S, namespace: &Namespace, id: fileid3) -> DataStoreResult<(fileid3, String)> {
+    let key = namespace.inode_key(id);
+    let parent = parse_id(&store.hget(&key, "parent").await?)?;
+    let name = store.hget(&key, "name").await?;
+    Ok((parent, name))
+}
+
+pub async fn path_of<S: DataStore + ?Sized>(store: &S, namespace: &Namespace, id: fileid3) -> DataStoreResult<String> {
+    let root = root_id(store, namespace).await?.ok_or(DataStoreError::KeyNotFound)?;
+    let mut names = Vec::new();
+    let mut current = id;
+    for _ in 0..MAX_DEPTH {
+        if current == root {
+            names.reverse();
+            return Ok(format!("/{}", names.join("/")));
+        }
+        let (parent, name) = parent_of(store, namespace, current).await?;
+        if parent == current {
+            // A second root: the inode is not linked into the tree
+            return Err(DataStoreError::KeyNotFound);
+        }
+        names.push(name);
+        current = parent;
+    }
+    Err(DataStoreError::OperationFailed)
+}
+
+// Whether id is ancestor itself or lies beneath it
+pub async fn is_within<S: DataStore + ?Sized>(store: &S, namespace: &Namespace, id: fileid3, ancestor: fileid3) -> DataStoreResult<bool> {
+    let mut current = id;
+    for _ in 0..MAX_DEPTH {
+        if current == ancestor {
+            return Ok(true);
+        }
+        let (parent, _) = parent_of(store, namespace, current).await?;
+        if parent == current {
+            return Ok(false);
+        }
+        current = parent;
+    }
+    Err(DataStoreError::OperationFailed)
+}
+
+// Writes the inode and then links it, so an entry never names a half-written inode
+pub async fn add_inode<S: DataStore + ?Sized>(
+    store: &S,
+    namespace: &Namespace,
+    id: fileid3,
+    parent: fileid3,
+    name: &str,
+    attributes: &[(&str, &str)],
+) -> DataStoreResult<()> {
+    let id_str = id.to_string();
+    let parent_str = parent.to_string();
+    let mut fields = attributes.to_vec();
+    fields.extend([("fileid", id_str.as_str()), ("parent", parent_str.as_str()), ("name", name)]);
+    store.hset_multiple(&namespace.inode_key(id), &fields).await?;
+    store.hset(&namespace.entries_key(parent), name, &id_str).await?;
+    touch(store, namespace, parent).await
+}
+
+// Unlinks first, so a failure part way leaves an unreachable inode rather than a dangling entry
+pub async fn remove_inode<S: DataStore + ?Sized>(store: &S, namespace: &Namespace, dir: fileid3, name: &str, id: fileid3) -> DataStoreResult<()> {
+    store.hdel(&namespace.entries_key(dir), name).await?;
+    store.delete(&namespace.entries_key(id)).await?;
+    store.delete(&namespace.inode_key(id)).await?;
+    touch(store, namespace, dir).await
+}
+
+// Moves id from (from_dir, from_name) to (to_dir, to_name), whatever lies beneath it. The caller
+// removes anything to_name named first. The new entry is written before the old one goes, so a
+// failure part way leaves the inode linked twice (which fsck repairs) rather than lost
+#[allow(clippy::too_many_arguments)]
+pub async fn move_inode<S: DataStore + ?Sized>(
+    store: &S,
+    namespace: &Namespace,
+    id: fileid3,
+    from_dir: fileid3,
+    from_name: &str,
+    to_dir: fileid3,
+    to_name: &str,
+    attributes: &[(&str, &str)],
+) -> DataStoreResult<()> {
+    store.hset(&namespace.entries_key(to_dir), to_name, &id.to_string()).await?;
+    if from_dir != to_dir || from_name != to_name {
+        store.hdel(&namespace.entries_key(from_dir), from_name).await?;
+    }
+    let to_dir_str = to_dir.to_string();
+    let mut fields = attributes.to_vec();
+    fields.extend([("parent", to_dir_str.as_str()), ("name", to_name)]);
+    store.hset_multiple(&namespace.inode_key(id), &fields).await?;
+    touch(store, namespace, to_dir).await?;
+    if from_dir != to_dir {
+        touch(store, namespace, from_dir).await?;
+    }
+    Ok(())
+}
+
+// Creates the root and every directory along path that does not exist yet
+pub async fn init_directory<S: DataStore + ?Sized>(store: &S, namespace: &Namespace, path: &str) -> DataStoreResult<fileid3> {
+    let attributes = new_attributes("0", "777", 0);
+    let mut dir = match root_id(store, namespace).await? {
+        Some(root) => root,
+        None => {
+            let root = next_fileid(store, namespace).await?;
+            let root_str = root.to_string();
+            let mut fields = as_fields(&attributes);
+            fields.extend([("fileid", root_str.as_str()), ("parent", root_str.as_str()), ("name", "")]);
+            store.hset_multiple(&namespace.inode_key(root), &fields).await?;
+            store.set(&namespace.root_key(), &root_str).await?;
+            root
+        }
+    };
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        dir = match lookup(store, namespace, dir, name).await? {
+            Some(child) => child,
+            None => {
+                let child = next_fileid(store, namespace).await?;
+                add_inode(store, namespace, child, dir, name, &as_fields(&attributes)).await?;
+                // Made for whoever mounts, so charged to the namespace alone
+                quota::charge(store, namespace, None, 0, 1).await?;
+                child
+            }
+        };
+    }
+    Ok(dir)
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct UpgradeStats {
+    pub upgraded: u64,
+    // Paths left in the old layout because their id or parent could not be found
+    pub left_behind: Vec<String>,
+}
+
+pub async fn has_legacy_layout<S: DataStore + ?Sized>(store: &S, namespace: &Namespace) -> DataStoreResult<bool> {
+    Ok(!store.zrange_withscores(&namespace.legacy_nodes_key(), 0, 0).await?.is_empty())
+}
+
+// Converts a namespace from the path-keyed layout. Each path is copied into its inode and linked
+// before its old keys are removed, so an interrupted upgrade is finished by running it again
+pub async fn upgrade_layout<S: DataStore + ?Sized>(store: &S, namespace: &Namespace) -> DataStoreResult<UpgradeStats> {
+    let mut stats = UpgradeStats::default();
+    let path_to_id: HashMap<String, String> = store.hgetall(&namespace.legacy_path_to_id_key()).await?.into_iter().collect();
+
+    // Parents before children, whatever scores the old _nodes held
+    let mut paths: Vec<String> = store.zrange_withscores(&namespace.legacy_nodes_key(), 0, -1).await?
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect();
+    paths.sort_by_key(|path| (path.trim_end_matches('/').matches('/').count(), path.clone()));
+
+    let mut upgraded: HashMap<String, fileid3> = HashMap::new();
+    for path in paths {
+        let fields = store.hgetall(&namespace.legacy_node_key(&path)).await?;
+        let recorded = fields.iter().find(|(field, _)| field == "fileid").map(|(_, id)| id.clone());
+        let Some(id) = path_to_id.get(&path).cloned().or(recorded).and_then(|id| id.parse::<fileid3>().ok()) else {
+            stats.left_behind.push(path);
+            continue;
+        };
+
+        let (parent, name) = if path == "/" {
+            (id, String::new())
+        } else {
+            let (parent_path, name) = path.rsplit_once('/').unwrap_or(("", &path));
+            let parent_path = if parent_path.is_empty() { "/" } else { parent_path };
+            let parent = match upgraded.get(parent_path) {
+                Some(parent) => Some(*parent),
+                None => resolve_path(store, namespace, parent_path).await?,
+            };
+            let Some(parent) = parent else {
+                stats.left_behind.push(path);
+                continue;
+            };
+            (parent, name.to_string())
+        };
+
+        let mut inode_fields: Vec<(String, String)> = fields.into_iter()
+            .filter(|(field, _)| !matches!(field.as_str(), "fileid" | "parent" | "name"))
+            .collect();
+        inode_fields.extend([
+            ("fileid".to_string(), id.to_string()),
+            ("parent".to_string(), parent.to_string()),
+            ("name".to_string(), name.clone()),
+        ]);
+        write_fields(store, &namespace.inode_key(id), &inode_fields).await?;
+        if path == "/" {
+            store.set(&namespace.root_key(), &id.to_string()).await?;
+        } else {
+            store.hset(&namespace.entries_key(parent), &name, &id.to_string()).await?;
+        }
+        debug!("upgraded {} to inode {}", path, id);
+
+        store.zrem(&namespace.legacy_nodes_key(), &path).await?;
+        store.hdel(&namespace.legacy_path_to_id_key(), &path).await?;
+        store.hdel(&namespace.legacy_id_to_path_key(), &id.to_string()).await?;
+        store.delete(&namespace.legacy_node_key(&path)).await?;
+        upgraded.insert(path, id);
+        stats.upgraded += 1;
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backingstore::test_store::TestDataStore;
+
+    #[tokio::test]
+    async fn test_rename_moves_a_subtree_with_constant_updates() {
+        let store = TestDataStore::new();
+        let namespace = Namespace::new("zoo", "aqautics");
+        let alice = init_directory(&store, &namespace, "/alice").await.unwrap();
+        let project = init_directory(&store, &namespace, "/alice/project/src").await.unwrap();
+        let src_parent = resolve_path(&store, &namespace, "/alice/project").await.unwrap().unwrap();
+        assert_eq!(path_of(&store, &namespace, project).await.unwrap(), "/alice/project/src");
+
+        let root = root_id(&store, &namespace).await.unwrap().unwrap();
+        let project_dir = lookup(&store, &namespace, alice, "project").await.unwrap().unwrap();
+        move_inode(&store, &namespace, project_dir, alice, "project", root, "archive", &[]).await.unwrap();
+
+        assert_eq!(lookup(&store, &namespace, alice, "project").await.unwrap(), None);
+        assert_eq!(resolve_path(&store, &namespace, "/archive/src").await.unwrap(), Some(project));
+        assert_eq!(path_of(&store, &namespace, project).await.unwrap(), "/archive/src");
+        assert_eq!(src_parent, project_dir);
+        assert!(is_within(&store, &namespace, project, project_dir).await.unwrap());
+        assert!(!is_within(&store, &namespace, project_dir, alice).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_init_directory_is_idempotent() {
+        let store = TestDataStore::new();
+        let namespace = Namespace::new("zoo", "aqautics");
+        let first = init_directory(&store, &namespace, "/alice").await.unwrap();
+        let counter = store.get(&namespace.next_fileid_key()).await.unwrap();
+        assert_eq!(init_directory(&store, &namespace, "/alice").await.unwrap(), first);
+        assert_eq!(store.get(&namespace.next_fileid_key()).await.unwrap(), counter);
+        assert_eq!(path_of(&store, &namespace, root_id(&store, &namespace).await.unwrap().unwrap()).await.unwrap(), "/");
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_from_path_keyed_layout() {
+        let store = TestDataStore::new();
+        let namespace = Namespace::new("zoo", "aqautics");
+        for (path, id, ftype) in [("/", "1", "0"), ("/alice", "2", "0"), ("/alice/notes.txt", "3", "1"), ("/lost/file", "4", "1")] {
+            store.hset_multiple(&namespace.legacy_node_key(path), &[("ftype", ftype), ("fileid", id)]).await.unwrap();
+            store.zadd(&namespace.legacy_nodes_key(), path, path.matches('/').count() as f64 + 1.0).await.unwrap();
+            store.hset(&namespace.legacy_path_to_id_key(), path, id).await.unwrap();
+            store.hset(&namespace.legacy_id_to_path_key(), id, path).await.unwrap();
+        }
+        store.hset(&namespace.legacy_node_key("/alice/notes.txt"), "data", "c2hhcmVz").await.unwrap();
+        store.set(&namespace.next_fileid_key(), "4").await.unwrap();
+
+        assert!(has_legacy_layout(&store, &namespace).await.unwrap());
+        let stats = upgrade_layout(&store, &namespace).await.unwrap();
+        assert_eq!(stats.upgraded, 3);
+        assert_eq!(stats.left_behind, vec!["/lost/file".to_string()]);
+
+        assert_eq!(root_id(&store, &namespace).await.unwrap(), Some(1));
+        assert_eq!(resolve_path(&store, &namespace, "/alice/notes.txt").await.unwrap(), Some(3));
+        assert_eq!(store.hget(&namespace.inode_key(3), "data").await.unwrap(), "c2hhcmVz");
+        assert_eq!(path_of(&store, &namespace, 3).await.unwrap(), "/alice/notes.txt");
+        assert!(store.hgetall(&namespace.legacy_node_key("/alice")).await.unwrap().is_empty());
+
+        // Running it again only revisits what was left behind
+        let stats = upgrade_layout(&store, &namespace).await.unwrap();
+        assert_eq!(stats.upgraded, 0);
+        assert_eq!(next_fileid(&store, &namespace).await.unwrap(), 5);
+    }
+}
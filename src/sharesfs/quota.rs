@@ -0,0 +1,419 @@
+// Usage and quotas of a SharesFS namespace.
+//
+// Every namespace keeps what its files use in one hash, kept up to date as files are created,
+// grow or shrink, and are removed: bytes are the sizes of its regular files, files count every
+// inode but the root. Each inode made through SharesFS records its owner, the user whose drive
+// it lies in (the first directory below the export), and is charged to that user as well.
+//
+//   {c}/{ns}_usage            bytes, files, bytes:<user>, files:<user>
+//
+// Limits are configured in settings.toml for every namespace alike; a soft limit only warns,
+// a hard one refuses the change, with NFS3ERR_DQUOT for a user's and NFS3ERR_NOSPC for the
+// namespace's:
+//
+//   [quotas.namespace]
+//   hard_bytes = 1099511627776
+//   [quotas.user]                # every user without limits of their own
+//   soft_bytes = 8589934592
+//   hard_bytes = 10737418240
+//   hard_files = 100000
+//   [quotas.users.alice]
+//   hard_bytes = 53687091200
+//
+// Namespaces written before usage was kept start from nothing; fsck recounts them.
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use config::{Config, ConfigError};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::backingstore::data_store::{DataStore, DataStoreError, DataStoreResult};
+use crate::kernel::api::nfs::{fileid3, nfsstat3};
+use crate::kernel::vfs::api::FsStat;
+use crate::sharesfs::namespace::Namespace;
+use crate::sharesfs::SharesFS;
+
+// Charges are read, added to and written back, so one at a time
+static CHARGES: Mutex<()> = Mutex::const_new(());
+
+// Only growth is held to a limit, so usage already over it can still shrink
+fn over(limit: Option<u64>, used: u64, more: u64) -> bool {
+    limit.is_some_and(|limit| more > 0 && used.saturating_add(more) > limit)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+pub struct Limits {
+    pub soft_bytes: Option<u64>,
+    pub hard_bytes: Option<u64>,
+    pub soft_files: Option<u64>,
+    pub hard_files: Option<u64>,
+}
+
+impl Limits {
+    fn is_unlimited(&self) -> bool {
+        *self == Limits::default()
+    }
+
+    // Whether usage grown by bytes and files would pass the hard limits
+    fn refuses(&self, usage: Usage, bytes: u64, files: u64) -> bool {
+        over(self.hard_bytes, usage.bytes, bytes) || over(self.hard_files, usage.files, files)
+    }
+
+    fn passes_soft(&self, usage: Usage, bytes: u64, files: u64) -> bool {
+        over(self.soft_bytes, usage.bytes, bytes) || over(self.soft_files, usage.files, files)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct Quotas {
+    #[serde(default)]
+    pub namespace: Limits,
+    #[serde(default)]
+    pub user: Limits,
+    #[serde(default)]
+    pub users: HashMap<String, Limits>,
+}
+
+impl Quotas {
+    pub fn limits_for(&self, user: &str) -> Limits {
+        self.users.get(user).copied().unwrap_or(self.user)
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.namespace.is_unlimited() && self.user.is_unlimited() && self.users.values().all(Limits::is_unlimited)
+    }
+}
+
+#[derive(Debug)]
+pub enum QuotaConfigError {
+    Invalid(String),
+}
+
+impl fmt::Display for QuotaConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QuotaConfigError::Invalid(msg) => write!(f, "Invalid quota settings: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for QuotaConfigError {}
+
+// The configured quotas; none when there is no [quotas] section
+pub fn quotas_from_settings(settings: &Config) -> Result<Quotas, QuotaConfigError> {
+    let quotas = match settings.get::<Quotas>("quotas") {
+        Ok(quotas) => quotas,
+        Err(ConfigError::NotFound(_)) => return Ok(Quotas::default()),
+        Err(e) => return Err(QuotaConfigError::Invalid(e.to_string())),
+    };
+    let scopes = [("namespace", &quotas.namespace), ("user", &quotas.user)].into_iter()
+        .chain(quotas.users.iter().map(|(user, limits)| (user.as_str(), limits)));
+    for (scope, limits) in scopes {
+        let above = |soft: Option<u64>, hard: Option<u64>| soft.zip(hard).is_some_and(|(soft, hard)| soft > hard);
+        if above(limits.soft_bytes, limits.hard_bytes) || above(limits.soft_files, limits.hard_files) {
+            return Err(QuotaConfigError::Invalid(format!("a soft limit of {} is above its hard limit", scope)));
+        }
+    }
+    Ok(quotas)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Usage {
+    pub bytes: u64,
+    pub files: u64,
+}
+
+// Everything a namespace's usage hash holds
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Ledger {
+    pub namespace: Usage,
+    pub users: BTreeMap<String, Usage>,
+}
+
+impl Ledger {
+    pub async fn read<S: DataStore + ?Sized>(store: &S, namespace: &Namespace) -> DataStoreResult<Ledger> {
+        let mut ledger = Ledger::default();
+        for (field, value) in store.hgetall(&namespace.usage_key()).await? {
+            let value = value.parse().unwrap_or(0);
+            let (kind, user) = match field.split_once(':') {
+                Some((kind, user)) => (kind, Some(user)),
+                None => (field.as_str(), None),
+            };
+            let usage = match user {
+                Some(user) => ledger.users.entry(user.to_string()).or_default(),
+                None => &mut ledger.namespace,
+            };
+            match kind {
+                "bytes" => usage.bytes = value,
+                "files" => usage.files = value,
+                _ => {}
+            }
+        }
+        // Users who no longer use anything are as good as absent
+        ledger.users.retain(|_, usage| *usage != Usage::default());
+        Ok(ledger)
+    }
+
+    // Replaces what the namespace's usage hash holds
+    pub async fn write<S: DataStore + ?Sized>(&self, store: &S, namespace: &Namespace) -> DataStoreResult<()> {
+        let _charging = CHARGES.lock().await;
+        let mut fields = vec![("bytes".to_string(), self.namespace.bytes.to_string()), ("files".to_string(), self.namespace.files.to_string())];
+        for (user, usage) in &self.users {
+            fields.push((format!("bytes:{}", user), usage.bytes.to_string()));
+            fields.push((format!("files:{}", user), usage.files.to_string()));
+        }
+        let fields: Vec<(&str, &str)> = fields.iter().map(|(field, value)| (field.as_str(), value.as_str())).collect();
+        store.delete(&namespace.usage_key()).await?;
+        store.hset_multiple(&namespace.usage_key(), &fields).await
+    }
+
+    pub fn user(&self, user: &str) -> Usage {
+        self.users.get(user).copied().unwrap_or_default()
+    }
+}
+
+async fn counter<S: DataStore + ?Sized>(store: &S, key: &str, field: &str) -> DataStoreResult<u64> {
+    match store.hget(key, field).await {
+        Ok(value) => Ok(value.parse().unwrap_or(0)),
+        Err(DataStoreError::KeyNotFound) => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+// What the namespace uses, and the owner when one is given
+pub async fn usage<S: DataStore + ?Sized>(store: &S, namespace: &Namespace, owner: Option<&str>) -> DataStoreResult<(Usage, Option<Usage>)> {
+    let key = namespace.usage_key();
+    let total = Usage { bytes: counter(store, &key, "bytes").await?, files: counter(store, &key, "files").await? };
+    let user = match owner {
+        Some(owner) => Some(Usage {
+            bytes: counter(store, &key, &format!("bytes:{}", owner)).await?,
+            files: counter(store, &key, &format!("files:{}", owner)).await?,
+        }),
+        None => None,
+    };
+    Ok((total, user))
+}
+
+// Adds bytes and files, either of which may be negative, to the namespace and the owner
+pub async fn charge<S: DataStore + ?Sized>(store: &S, namespace: &Namespace, owner: Option<&str>, bytes: i64, files: i64) -> DataStoreResult<()> {
+    if bytes == 0 && files == 0 {
+        return Ok(());
+    }
+    let _charging = CHARGES.lock().await;
+    add_to_ledger(store, namespace, owner, bytes, files).await
+}
+
+// charge, for a caller holding CHARGES
+async fn add_to_ledger<S: DataStore + ?Sized>(store: &S, namespace: &Namespace, owner: Option<&str>, bytes: i64, files: i64) -> DataStoreResult<()> {
+    let key = namespace.usage_key();
+    let mut fields = vec!["bytes".to_string(), "files".to_string()];
+    if let Some(owner) = owner {
+        fields.extend([format!("bytes:{}", owner), format!("files:{}", owner)]);
+    }
+    let mut values = Vec::new();
+    for (field, delta) in fields.iter().zip([bytes, files].into_iter().cycle()) {
+        let value = counter(store, &key, field).await?.saturating_add_signed(delta);
+        values.push(value.to_string());
+    }
+    let updates: Vec<(&str, &str)> = fields.iter().map(String::as_str).zip(values.iter().map(String::as_str)).collect();
+    store.hset_multiple(&key, &updates).await
+}
+
+// The user whose drive a path lies in: the first directory below the export, once the path
+// goes beneath it
+pub fn owner_of_path(export_path: &str, path: &str) -> Option<String> {
+    let relative = path.strip_prefix(export_path.trim_end_matches('/'))?;
+    if !relative.is_empty() && !relative.starts_with('/') {
+        return None;
+    }
+    let mut components = relative.split('/').filter(|component| !component.is_empty());
+    let owner = components.next()?;
+    components.next().map(|_| owner.to_string())
+}
+
+// The figures of the scope with the least room left; unlimited is what a scope without a hard
+// limit reports as its size
+fn figures(scopes: &[(Option<u64>, Option<u64>, u64)], unlimited: u64) -> (u64, u64, u64) {
+    let room = |(hard, soft, used): &(Option<u64>, Option<u64>, u64)| {
+        let total = hard.unwrap_or(unlimited);
+        (total, total.saturating_sub(*used), soft.unwrap_or(total).saturating_sub(*used))
+    };
+    let (total, free, _) = scopes.iter().map(room).min_by_key(|(_, free, _)| *free).unwrap_or((unlimited, unlimited, unlimited));
+    let avail = scopes.iter().map(|scope| room(scope).2).min().unwrap_or(free);
+    (total, free, avail.min(free))
+}
+
+impl SharesFS {
+    // The owner recorded in an inode, if it has one
+    pub(super) async fn owner_of(&self, id: fileid3) -> Option<String> {
+        self.data_store.hget(&self.namespace.inode_key(id), "owner").await.ok().filter(|owner| !owner.is_empty())
+    }
+
+    // The owner of name when it is made in a directory
+    pub(super) async fn owner_below(&self, dirid: fileid3, name: &str) -> Result<Option<String>, nfsstat3> {
+        let path = self.get_path_from_id(dirid).await?;
+        Ok(owner_of_path(&self.export_path, &format!("{}/{}", path.trim_end_matches('/'), name)))
+    }
+
+    // The owner of a new entry of a directory, once one more file is reserved for it; settle
+    // keeps or gives it back
+    pub(super) async fn admit(&self, dirid: fileid3, name: &str) -> Result<Option<String>, nfsstat3> {
+        let owner = self.owner_below(dirid, name).await?;
+        self.reserve(owner.as_deref(), 0, 1).await?;
+        Ok(owner)
+    }
+
+    // Whom an inode is charged to and the bytes it is charged for, read before it goes
+    pub(super) async fn footprint(&self, id: fileid3) -> (Option<String>, u64) {
+        let key = self.namespace.inode_key(id);
+        let bytes = match self.data_store.hget(&key, "ftype").await {
+            Ok(ftype) if ftype == "1" => self.data_store.hget(&key, "size").await.ok().and_then(|size| size.parse().ok()).unwrap_or(0),
+            _ => 0,
+        };
+        (self.owner_of(id).await, bytes)
+    }
+
+    // Charges bytes and files to the owner ahead of the change that adds them, or refuses them
+    // when a hard limit of the owner or of the namespace has no room left. The room is checked
+    // and taken under the one lock, so changes made at once cannot each be let into the same room
+    pub(super) async fn reserve(&self, owner: Option<&str>, bytes: u64, files: u64) -> Result<(), nfsstat3> {
+        if bytes == 0 && files == 0 {
+            return Ok(());
+        }
+        let _charging = CHARGES.lock().await;
+        if !self.quotas.is_unlimited() {
+            self.check_quota(owner, bytes, files).await?;
+        }
+        let (bytes, files) = (i64::try_from(bytes).unwrap_or(i64::MAX), i64::try_from(files).unwrap_or(i64::MAX));
+        if let Err(e) = add_to_ledger(&*self.data_store, &self.namespace, owner, bytes, files).await {
+            warn!("Failed to charge {} bytes and {} files to {:?} in {:?}: {:?}", bytes, files, owner, self.namespace, e);
+        }
+        Ok(())
+    }
+
+    // Gives back what reserve took when the change it was taken for failed
+    pub(super) async fn settle<T>(&self, owner: Option<&str>, bytes: u64, files: u64, made: Result<T, nfsstat3>) -> Result<T, nfsstat3> {
+        if made.is_err() {
+            self.charge(owner, -i64::try_from(bytes).unwrap_or(i64::MAX), -i64::try_from(files).unwrap_or(i64::MAX)).await;
+        }
+        made
+    }
+
+    // Refuses adding bytes and files when a hard limit of the owner or of the namespace has no
+    // room left for them. Called by reserve, under CHARGES
+    async fn check_quota(&self, owner: Option<&str>, bytes: u64, files: u64) -> Result<(), nfsstat3> {
+        let (total, user) = usage(&*self.data_store, &self.namespace, owner).await.map_err(|_| nfsstat3::NFS3ERR_IO)?;
+        if let (Some(owner), Some(user)) = (owner, user) {
+            let limits = self.quotas.limits_for(owner);
+            if limits.refuses(user, bytes, files) {
+                warn!("{} is over quota in {:?}", owner, self.namespace);
+                return Err(nfsstat3::NFS3ERR_DQUOT);
+            }
+            if limits.passes_soft(user, bytes, files) {
+                warn!("{} is over their soft quota in {:?}", owner, self.namespace);
+            }
+        }
+        if self.quotas.namespace.refuses(total, bytes, files) {
+            warn!("{:?} is full", self.namespace);
+            return Err(nfsstat3::NFS3ERR_NOSPC);
+        }
+        if self.quotas.namespace.passes_soft(total, bytes, files) {
+            warn!("{:?} is over its soft quota", self.namespace);
+        }
+        Ok(())
+    }
+
+    // A failed charge leaves usage behind until fsck recounts it, but not the change itself
+    pub(super) async fn charge(&self, owner: Option<&str>, bytes: i64, files: i64) {
+        if let Err(e) = charge(&*self.data_store, &self.namespace, owner, bytes, files).await {
+            warn!("Failed to charge {} bytes and {} files to {:?} in {:?}: {:?}", bytes, files, owner, self.namespace, e);
+        }
+    }
+
+    // What FSSTAT shows user, or the namespace as a whole
+    pub(super) async fn space(&self, user: Option<&str>) -> Result<FsStat, nfsstat3> {
+        let (total, used) = usage(&*self.data_store, &self.namespace, user).await.map_err(|_| nfsstat3::NFS3ERR_IO)?;
+        let mut scopes = vec![(self.quotas.namespace, total)];
+        if let (Some(user), Some(used)) = (user, used) {
+            scopes.push((self.quotas.limits_for(user), used));
+        }
+        let unlimited = FsStat::default();
+        let bytes: Vec<_> = scopes.iter().map(|(limits, used)| (limits.hard_bytes, limits.soft_bytes, used.bytes)).collect();
+        let files: Vec<_> = scopes.iter().map(|(limits, used)| (limits.hard_files, limits.soft_files, used.files)).collect();
+        let (total_bytes, free_bytes, avail_bytes) = figures(&bytes, unlimited.total_bytes);
+        let (total_files, free_files, avail_files) = figures(&files, unlimited.total_files);
+        let max_file_size = scopes.iter()
+            .filter_map(|(limits, _)| limits.hard_bytes)
+            .fold(unlimited.max_file_size, u64::min);
+        Ok(FsStat { total_bytes, free_bytes, avail_bytes, total_files, free_files, avail_files, max_file_size })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backingstore::test_store::TestDataStore;
+    use config::{File, FileFormat};
+
+    fn settings(toml: &str) -> Config {
+        let mut settings = Config::default();
+        settings.merge(File::from_str(toml, FileFormat::Toml)).unwrap();
+        settings
+    }
+
+    #[test]
+    fn test_reads_quotas_and_rejects_soft_limits_above_hard_ones() {
+        assert!(quotas_from_settings(&settings("")).unwrap().is_unlimited());
+
+        let quotas = quotas_from_settings(&settings(r#"
+            [quotas.namespace]
+            hard_bytes = 1000
+            [quotas.user]
+            soft_files = 5
+            hard_files = 10
+            [quotas.users.alice]
+            hard_bytes = 500
+        "#)).unwrap();
+        assert_eq!(quotas.namespace.hard_bytes, Some(1000));
+        assert_eq!(quotas.limits_for("bob"), Limits { soft_files: Some(5), hard_files: Some(10), ..Limits::default() });
+        assert_eq!(quotas.limits_for("alice"), Limits { hard_bytes: Some(500), ..Limits::default() });
+
+        let inverted = settings("[quotas.user]\nsoft_bytes = 10\nhard_bytes = 5\n");
+        assert!(matches!(quotas_from_settings(&inverted), Err(QuotaConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_owner_is_the_first_directory_below_the_export() {
+        assert_eq!(owner_of_path("/", "/alice/notes.txt"), Some("alice".to_string()));
+        assert_eq!(owner_of_path("/", "/alice"), None);
+        assert_eq!(owner_of_path("/projects", "/projects/alice/docs/a"), Some("alice".to_string()));
+        assert_eq!(owner_of_path("/projects", "/projectsx/alice/a"), None);
+        assert_eq!(owner_of_path("/projects", "/other/alice/a"), None);
+    }
+
+    #[tokio::test]
+    async fn test_charges_add_up_in_the_ledger() {
+        let namespace = Namespace::new("zoo", "aqautics");
+        let store = TestDataStore::new();
+        charge(&store, &namespace, Some("alice"), 100, 1).await.unwrap();
+        charge(&store, &namespace, None, 0, 1).await.unwrap();
+        charge(&store, &namespace, Some("alice"), -30, 0).await.unwrap();
+        charge(&store, &namespace, Some("bob"), -5, -1).await.unwrap();
+
+        let ledger = Ledger::read(&store, &namespace).await.unwrap();
+        assert_eq!(ledger.namespace, Usage { bytes: 65, files: 1 });
+        assert_eq!(ledger.user("alice"), Usage { bytes: 70, files: 1 });
+        assert_eq!(ledger.user("bob"), Usage::default());
+        assert_eq!(usage(&store, &namespace, Some("alice")).await.unwrap(), (Usage { bytes: 65, files: 1 }, Some(Usage { bytes: 70, files: 1 })));
+    }
+
+    #[test]
+    fn test_the_scope_with_least_room_is_reported() {
+        // A namespace of 1000 bytes with 900 used leaves less room than a user's 500 with 50 used
+        assert_eq!(figures(&[(Some(1000), None, 900), (Some(500), Some(400), 50)], 5000), (1000, 100, 100));
+        assert_eq!(figures(&[(None, None, 10), (Some(500), Some(400), 50)], 5000), (500, 450, 350));
+        assert_eq!(figures(&[(None, None, 10)], 5000), (5000, 4990, 4990));
+    }
+}
@@ -0,0 +1,164 @@
+// The tenants one graymamba process serves.
+//
+// Each tenant is its own namespace, so its own tree and fileid counter, is served on its own NFS
+// port by its own SharesFS, may restrict which users can mount it, and has its audit events keyed
+// by its community. They are configured as [[tenants]] in settings.toml:
+//
+//   [[tenants]]
+//   community = "zoo"
+//   namespace_id = "aqautics"
+//   port = 2049
+//   users = ["alice", "bob"]     # optional; any authenticated user when absent
+//
+// Without a [[tenants]] section the [storage] community and namespace_id are served on the
+// default port, as before.
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+use config::{Config, ConfigError};
+use serde::Deserialize;
+
+use crate::audit_adapters::irrefutable_audit::IrrefutableAudit;
+use crate::backingstore::data_store::DataStore;
+use crate::sharesfs::namespace::Namespace;
+use crate::sharesfs::SharesFS;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TenantConfig {
+    pub community: String,
+    pub namespace_id: String,
+    pub port: u16,
+    #[serde(default)]
+    pub users: Option<Vec<String>>,
+}
+
+#[derive(Debug)]
+pub enum TenantConfigError {
+    Missing(String),
+    Invalid(String),
+}
+
+impl fmt::Display for TenantConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TenantConfigError::Missing(msg) => write!(f, "Missing tenant setting: {}", msg),
+            TenantConfigError::Invalid(msg) => write!(f, "Invalid tenant settings: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for TenantConfigError {}
+
+impl TenantConfig {
+    pub fn namespace(&self) -> Namespace {
+        Namespace::new(&self.community, &self.namespace_id)
+    }
+
+    // A SharesFS serving this tenant
+    pub fn filesystem(&self, data_store: Arc<dyn DataStore>, irrefutable_audit: Arc<dyn IrrefutableAudit>) -> SharesFS {
+        let mut shares_fs = SharesFS::new(data_store, irrefutable_audit, self.namespace());
+        shares_fs.allowed_users = self.users.as_ref().map(|users| users.iter().cloned().collect::<HashSet<String>>());
+        shares_fs
+    }
+}
+
+// The configured tenants; each must have a port and a namespace of its own
+pub fn tenants_from_settings(settings: &Config, default_port: u16) -> Result<Vec<TenantConfig>, TenantConfigError> {
+    let tenants = match settings.get::<Vec<TenantConfig>>("tenants") {
+        Ok(tenants) => tenants,
+        Err(ConfigError::NotFound(_)) => {
+            let setting = |key: &str| settings.get_str(key).map_err(|_| TenantConfigError::Missing(key.to_string()));
+            vec![TenantConfig {
+                community: setting("storage.community")?,
+                namespace_id: setting("storage.namespace_id")?,
+                port: default_port,
+                users: None,
+            }]
+        }
+        Err(e) => return Err(TenantConfigError::Invalid(e.to_string())),
+    };
+    if tenants.is_empty() {
+        return Err(TenantConfigError::Invalid("[[tenants]] lists no tenant".to_string()));
+    }
+
+    let mut ports = HashMap::new();
+    let mut namespaces = HashSet::new();
+    for tenant in &tenants {
+        if tenant.community.is_empty() || tenant.namespace_id.is_empty() {
+            return Err(TenantConfigError::Invalid("every tenant needs a community and a namespace_id".to_string()));
+        }
+        if let Some(other) = ports.insert(tenant.port, tenant.namespace()) {
+            return Err(TenantConfigError::Invalid(format!(
+                "{:?} and {:?} are both on port {}", other, tenant.namespace(), tenant.port
+            )));
+        }
+        if !namespaces.insert((tenant.community.clone(), tenant.namespace_id.clone())) {
+            return Err(TenantConfigError::Invalid(format!("{:?} is configured twice", tenant.namespace())));
+        }
+    }
+    Ok(tenants)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{File, FileFormat};
+
+    fn settings(toml: &str) -> Config {
+        let mut settings = Config::default();
+        settings.merge(File::from_str(toml, FileFormat::Toml)).unwrap();
+        settings
+    }
+
+    #[test]
+    fn test_falls_back_to_the_storage_namespace() {
+        let tenants = tenants_from_settings(&settings("[storage]\ncommunity = \"zoo\"\nnamespace_id = \"aqautics\"\n"), 2049).unwrap();
+        assert_eq!(tenants, vec![TenantConfig { community: "zoo".into(), namespace_id: "aqautics".into(), port: 2049, users: None }]);
+    }
+
+    #[test]
+    fn test_reads_tenants_and_rejects_clashes() {
+        let tenants = tenants_from_settings(&settings(r#"
+            [[tenants]]
+            community = "zoo"
+            namespace_id = "aqautics"
+            port = 2049
+
+            [[tenants]]
+            community = "farm"
+            namespace_id = "barn"
+            port = 2050
+            users = ["alice"]
+        "#), 2049).unwrap();
+        assert_eq!(tenants.len(), 2);
+        assert_eq!(tenants[1].namespace(), Namespace::new("farm", "barn"));
+        assert_eq!(tenants[1].users, Some(vec!["alice".to_string()]));
+
+        let same_port = settings(r#"
+            [[tenants]]
+            community = "zoo"
+            namespace_id = "aqautics"
+            port = 2049
+
+            [[tenants]]
+            community = "farm"
+            namespace_id = "barn"
+            port = 2049
+        "#);
+        assert!(matches!(tenants_from_settings(&same_port, 2049), Err(TenantConfigError::Invalid(_))));
+
+        let same_namespace = settings(r#"
+            [[tenants]]
+            community = "zoo"
+            namespace_id = "aqautics"
+            port = 2049
+
+            [[tenants]]
+            community = "zoo"
+            namespace_id = "aqautics"
+            port = 2050
+        "#);
+        assert!(matches!(tenants_from_settings(&same_namespace, 2049), Err(TenantConfigError::Invalid(_))));
+    }
+}
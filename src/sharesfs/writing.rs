@@ -9,6 +9,7 @@ use crate::audit_adapters::irrefutable_audit::event_types::DISASSEMBLED;
 
 use crate::graymamba::file_metadata::FileMetadata;
 use super::{SharesFS, ActiveWrite};
+use super::namespace;
 
 use crate::sharesfs::ChannelBuffer;
 
@@ -26,10 +27,17 @@ impl SharesFS {
         offset: u64,
         data: &[u8]
     ) -> Result<fattr3, nfsstat3> {
-        let (_namespace_id, community) = SharesFS::get_namespace_id_and_community().await;
+        self.check_writable("write", id).await?;
         let path = self.get_path_from_id(id).await?;
+        let key = self.namespace.inode_key(id);
 
         debug!("write: {:?}", path);
+
+        // Refused before the buffer takes the data, so a refused write leaves nothing to commit
+        let (owner, size) = self.footprint(id).await;
+        let end = offset.checked_add(data.len() as u64).ok_or(nfsstat3::NFS3ERR_FBIG)?;
+        let growth = end.saturating_sub(size);
+        self.reserve(owner.as_deref(), growth, 0).await?;
     
         let channel = {
             let mut active_writes = self.active_writes.lock().await;
@@ -49,14 +57,16 @@ impl SharesFS {
         
         let total_size = channel.total_size();
         debug!("total_size: {:?}", total_size);
-        debug!("community: {:?}", community);
         debug!("path: {:?}", path);
-        self.data_store.hset_multiple(
-            &format!("{}{}", community, path),
-            &[
-                ("size",&total_size.to_string())
-            ]
-        ).await.map_err(|_| nfsstat3::NFS3ERR_IO)?;
+        // The times change with the size, not when the buffer is committed later
+        let size_str = total_size.to_string();
+        let times = namespace::modified_now();
+        let mut fields = namespace::as_fields(&times);
+        fields.push(("size", &size_str));
+        let updated = self.data_store.hset_multiple(&key, &fields).await.map_err(|_| nfsstat3::NFS3ERR_IO);
+        self.settle(owner.as_deref(), growth, 0, updated).await?;
+        // The buffer may have grown past what was reserved, by writes before this one
+        self.charge(owner.as_deref(), total_size as i64 - size as i64 - growth as i64, 0).await;
 
         debug!("hset_multiple complete");
 
@@ -99,7 +109,7 @@ impl SharesFS {
 
         let _permit = self.commit_semaphore.acquire().await.map_err(|_| DataStoreError::OperationFailed);
 
-        let (_namespace_id, community) = SharesFS::get_namespace_id_and_community().await;
+        let key = self.namespace.inode_key(id);
 
 
         let channel = {
@@ -134,15 +144,15 @@ impl SharesFS {
         match self.secret_sharing.disassemble(&base64_contents).await {
             Ok(shares) => {
                 // Attempt to write the shares to the data store
-                debug!("Writing shares to data store under key: {:?}", format!("{}{}", community, path));
+                debug!("Writing shares of {:?} to data store under key: {:?}", path, key);
                 match self
                     .data_store
-                    .hset(&format!("{}{}", community, path), "data", &shares)
+                    .hset(&key, "data", &shares)
                     .await
                 {
                     Ok(_) => {
                         // Update file metadata upon successful storage
-                        self.update_file_metadata(&path).await?;
+                        self.update_file_metadata(id).await?;
 
                         // Clear the buffer contents after a successful commit
                         channel.clear().await;
@@ -165,6 +175,20 @@ impl SharesFS {
        
     }
 
+    // Commits every buffered write, pack files included, as the server shuts down. The files
+    // whose writes could not be committed.
+    pub async fn commit_all_writes(&self) -> Vec<fileid3> {
+        let ids: Vec<fileid3> = self.active_writes.lock().await.keys().copied().collect();
+        let mut failed = Vec::new();
+        for id in ids {
+            if let Err(e) = self.commit_write(id).await {
+                warn!("Failed to commit the writes of {}: {:?}", id, e);
+                failed.push(id);
+            }
+        }
+        failed
+    }
+
     pub async fn monitor_active_writes(&self) {
         warn!("Starting active writes monitor");
         loop {
@@ -193,20 +217,17 @@ impl SharesFS {
         }
     }
 
-    async fn update_file_metadata(&self, path: &str) -> Result<(), DataStoreError> {
+    async fn update_file_metadata(&self, id: fileid3) -> Result<(), DataStoreError> {
         let system_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
         let epoch_seconds = system_time.as_secs();
         let epoch_nseconds = system_time.subsec_nanos();
-        let (_namespace_id, community) = SharesFS::get_namespace_id_and_community().await;
 
-        debug!("Updating file metadata for path: {:?}", path);
+        debug!("Updating file metadata for id: {:?}", id);
 
-        let update_result = self.data_store.hset_multiple(&format!("{}{}", community, path),
+        // Modification and change times were set by the writes themselves, so a commit
+        // leaves the attributes clients were answered with as they are
+        let update_result = self.data_store.hset_multiple(&self.namespace.inode_key(id),
             &[
-                ("change_time_secs", &epoch_seconds.to_string()),
-                ("change_time_nsecs", &epoch_nseconds.to_string()),
-                ("modification_time_secs", &epoch_seconds.to_string()),
-                ("modification_time_nsecs", &epoch_nseconds.to_string()),
                 ("access_time_secs", &epoch_seconds.to_string()),
                 ("access_time_nsecs", &epoch_nseconds.to_string()),
             ]).await;
@@ -225,15 +246,7 @@ impl SharesFS {
 
         debug!("Checking if last write for id: {:?}", id);
 
-        let (_namespace_id, community) = SharesFS::get_namespace_id_and_community().await;
-
-        let path_result = self.get_path_from_id(id).await;
-        let path: String = match path_result {
-            Ok(k) => k,
-            Err(_) => return Err(nfsstat3::NFS3ERR_IO),  // Replace with appropriate nfsstat3 error
-        };
-
-        let current_size_result = self.data_store.hget(&format!("{}{}", community, path), "size").await;
+        let current_size_result = self.data_store.hget(&self.namespace.inode_key(id), "size").await;
         let current_size: u64 = match current_size_result {
             Ok(k) => k.parse::<u64>().map_err(|_| nfsstat3::NFS3ERR_IO)?,
             Err(_) => return Err(nfsstat3::NFS3ERR_IO),  // Replace with appropriate nfsstat3 error